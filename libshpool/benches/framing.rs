@@ -0,0 +1,63 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Microbenchmarks for the chunk framing that every byte of shell
+//! output goes through on its way to the client.
+
+use std::io::Cursor;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libshpool::testing::ChunkExt;
+use shpool_protocol::{Chunk, ChunkKind};
+
+// Typical keystroke echo, a line of output, and a full read buffer.
+const SIZES: [usize; 3] = [8, 128, 16 * 1024];
+
+fn write_chunks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk_write");
+    for size in SIZES {
+        let data = vec![b'x'; size];
+        let mut out = Vec::with_capacity(size + 5);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &data, |b, data| {
+            b.iter(|| {
+                out.clear();
+                let chunk = Chunk { kind: ChunkKind::Data, buf: data };
+                chunk.write_to(&mut out).unwrap();
+                black_box(&out);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read_chunks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunk_read");
+    for size in SIZES {
+        let mut framed = vec![];
+        Chunk { kind: ChunkKind::Data, buf: &vec![b'x'; size] }.write_to(&mut framed).unwrap();
+        let mut buf = vec![0; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &framed, |b, framed| {
+            b.iter(|| {
+                let chunk = Chunk::read_into(&mut Cursor::new(framed), &mut buf).unwrap();
+                black_box(chunk.buf.len());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write_chunks, read_chunks);
+criterion_main!(benches);
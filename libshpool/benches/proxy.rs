@@ -0,0 +1,66 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end benchmarks for the path that bytes take between a shell's
+//! pty and an attached client, using the in-process daemon harness.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use libshpool::testing::{Client, Daemon, DEFAULT_CONFIG};
+use shpool_protocol::{AttachHeader, TtySize};
+
+const THROUGHPUT_BYTES: usize = 1024 * 1024;
+
+fn attach_cmd(daemon: &Daemon, name: &str, cmd: &str) -> Client {
+    daemon
+        .attach_with(AttachHeader {
+            name: String::from(name),
+            local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+            cmd: Some(String::from(cmd)),
+            client_version: String::from(shpool_protocol::VERSION),
+            ..AttachHeader::default()
+        })
+        .expect("attaching")
+}
+
+/// How fast the daemon can pump output from a shell to a client.
+fn shell_to_client_throughput(c: &mut Criterion) {
+    let daemon = Daemon::start(DEFAULT_CONFIG).expect("starting daemon");
+    let mut client = attach_cmd(&daemon, "zeros", "cat /dev/zero");
+
+    let mut group = c.benchmark_group("proxy");
+    group.throughput(Throughput::Bytes(THROUGHPUT_BYTES as u64));
+    group.bench_function("shell_to_client", |b| {
+        b.iter(|| client.discard_output(THROUGHPUT_BYTES).expect("reading output"))
+    });
+    group.finish();
+}
+
+/// How long it takes for a keystroke to make it to the shell and for
+/// the shell's response to make it back.
+fn keystroke_round_trip(c: &mut Criterion) {
+    let daemon = Daemon::start(DEFAULT_CONFIG).expect("starting daemon");
+    let mut client = attach_cmd(&daemon, "echo", "cat");
+
+    let mut group = c.benchmark_group("proxy");
+    group.bench_function("keystroke_rtt", |b| {
+        b.iter(|| {
+            client.write(b"x\n").expect("writing keystroke");
+            client.expect("x").expect("reading echo");
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, shell_to_client_throughput, keystroke_round_trip);
+criterion_main!(benches);
@@ -0,0 +1,379 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  `shpool adopt` eases moving over from tmux or screen by recreating
+  their sessions as shpool sessions. There is no way to take over the
+  ptys the other multiplexer owns, so this looks up the process at the
+  root of each tmux pane or screen window and creates a detached
+  shpool session with the same name, working directory and command.
+  Panes that are just running a shell get a plain shpool shell.
+
+  The new sessions get created with the same request that `shpool
+  import` uses, and `--dry-run` prints them in the `shpool export`
+  format instead so they can be tweaked and imported by hand.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, ImportReply, ImportRequest};
+use tracing::{info, warn};
+
+use crate::{
+    context::ClientContext,
+    export::{SessionEntry, SessionsFile},
+    platform, session_name, AdoptSource,
+};
+
+const TMUX_PANE_FORMAT: &str = "#{session_name}\t#{window_index}\t#{pane_index}\t#{pane_pid}";
+
+/// Used when /etc/shells can't be read.
+const FALLBACK_SHELLS: &[&str] = &["sh", "bash", "zsh", "fish", "dash", "ksh", "tcsh", "csh"];
+
+/// A tmux pane or screen window, before we have looked at its process.
+#[derive(Debug, PartialEq, Eq)]
+struct Window {
+    /// The name of the tmux or screen session.
+    session: String,
+    /// Identifies the window within the session.
+    index: String,
+    /// The process running in the window.
+    pid: i32,
+}
+
+pub fn run(from: Option<AdoptSource>, dry_run: bool, ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut windows = vec![];
+    if from.map(|f| matches!(f, AdoptSource::Tmux)).unwrap_or(true) {
+        windows.extend(name_windows(tmux_panes()?));
+    }
+    if from.map(|f| matches!(f, AdoptSource::Screen)).unwrap_or(true) {
+        windows.extend(name_windows(screen_windows()?));
+    }
+    if windows.is_empty() {
+        eprintln!("no tmux or screen sessions found");
+        return Ok(());
+    }
+
+    let shells = known_shells();
+    let mut sessions = vec![];
+    for (name, window) in windows.into_iter() {
+        if let Err(reason) = session_name::validate(&name) {
+            eprintln!("skipping {}: {}", name, reason);
+            continue;
+        }
+        let cmdline = match read_cmdline(window.pid) {
+            Ok(c) => c,
+            Err(e) => {
+                // the process may have exited since we listed it
+                warn!("reading cmdline of {}: {:?}", window.pid, e);
+                eprintln!("skipping {}: could not inspect its process", name);
+                continue;
+            }
+        };
+        let cwd = platform::cwd_for_pid(nix::unistd::Pid::from_raw(window.pid))
+            .map(|p| p.to_string_lossy().into_owned())
+            .ok();
+        sessions.push(SessionEntry {
+            name,
+            cmd: command(&cmdline, &shells),
+            cwd,
+            env: BTreeMap::new(),
+        });
+    }
+
+    if dry_run {
+        print!("{}", toml::to_string(&SessionsFile { sessions }).context("formatting sessions")?);
+        return Ok(());
+    }
+
+    let names: Vec<String> = sessions.iter().map(|s| s.name.clone()).collect();
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::Import(ImportRequest {
+            sessions: sessions.into_iter().map(From::from).collect(),
+        }))
+        .context("writing import request header")?;
+
+    let reply: ImportReply = client.read_reply().context("reading reply")?;
+
+    for name in names.iter() {
+        if !reply.already_exists.contains(name)
+            && !reply.forbidden.contains(name)
+            && !reply.failed.iter().any(|f| &f.name == name)
+        {
+            println!("adopted {}", name);
+        }
+    }
+    if !reply.already_exists.is_empty() {
+        eprintln!("skipped existing sessions: {}", reply.already_exists.join(" "));
+    }
+    if !reply.forbidden.is_empty() {
+        eprintln!("skipped sessions with forbidden commands: {}", reply.forbidden.join(" "));
+    }
+    for failure in reply.failed.iter() {
+        eprintln!("failed to adopt {}: {}", failure.name, failure.error);
+    }
+    if !reply.failed.is_empty() {
+        return Err(anyhow!("failed to adopt {} sessions", reply.failed.len()));
+    }
+
+    Ok(())
+}
+
+fn tmux_panes() -> anyhow::Result<Vec<Window>> {
+    let out = match Command::new("tmux").args(["list-panes", "-a", "-F", TMUX_PANE_FORMAT]).output()
+    {
+        Ok(out) => out,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("tmux is not installed");
+            return Ok(vec![]);
+        }
+        Err(e) => return Err(e).context("running tmux list-panes"),
+    };
+    if !out.status.success() {
+        // most likely there is no tmux server running
+        info!("tmux list-panes failed: {}", String::from_utf8_lossy(&out.stderr));
+        return Ok(vec![]);
+    }
+    Ok(parse_tmux_panes(&String::from_utf8_lossy(&out.stdout)))
+}
+
+fn parse_tmux_panes(out: &str) -> Vec<Window> {
+    out.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let session = fields.next()?;
+            let window = fields.next()?;
+            let pane = fields.next()?;
+            let pid = fields.next()?.parse().ok()?;
+            Some(Window {
+                session: String::from(session),
+                index: format!("{}.{}", window, pane),
+                pid,
+            })
+        })
+        .collect()
+}
+
+fn screen_windows() -> anyhow::Result<Vec<Window>> {
+    // screen -ls exits non-zero even when it lists sessions,
+    // so only the output matters.
+    let out = match Command::new("screen").arg("-ls").output() {
+        Ok(out) => out,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            info!("screen is not installed");
+            return Ok(vec![]);
+        }
+        Err(e) => return Err(e).context("running screen -ls"),
+    };
+
+    let servers = parse_screen_ls(&String::from_utf8_lossy(&out.stdout));
+    if servers.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Screen doesn't have a way to ask it about its windows from the
+    // outside, but each window is a child of the screen server with
+    // the window number in its environment.
+    let children = children_by_parent().context("scanning /proc")?;
+    let mut windows = vec![];
+    for (server_pid, session) in servers.into_iter() {
+        for pid in children.get(&server_pid).into_iter().flatten() {
+            let index = read_environ(*pid)
+                .ok()
+                .and_then(|env| env.get("WINDOW").cloned())
+                .unwrap_or_else(|| pid.to_string());
+            windows.push(Window { session: session.clone(), index, pid: *pid });
+        }
+    }
+    Ok(windows)
+}
+
+/// Pull the server pid and session name out of each session in the
+/// output of `screen -ls`, which has lines like "\t1234.work\t(Detached)".
+fn parse_screen_ls(out: &str) -> Vec<(i32, String)> {
+    out.lines()
+        .filter(|line| line.starts_with(char::is_whitespace))
+        .filter_map(|line| {
+            let id = line.split_whitespace().next()?;
+            let (pid, name) = id.split_once('.')?;
+            Some((pid.parse().ok()?, String::from(name)))
+        })
+        .collect()
+}
+
+/// Name the shpool sessions for a batch of windows. A session with just
+/// one window keeps its name, otherwise each window gets a suffix.
+fn name_windows(windows: Vec<Window>) -> Vec<(String, Window)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for w in windows.iter() {
+        *counts.entry(w.session.clone()).or_default() += 1;
+    }
+    windows
+        .into_iter()
+        .map(|w| {
+            // spaces are fine in tmux and screen names, but not ours
+            let session = w.session.split_whitespace().collect::<Vec<_>>().join("-");
+            let name =
+                if counts[&w.session] == 1 { session } else { format!("{}-{}", session, w.index) };
+            (name, w)
+        })
+        .collect()
+}
+
+/// The command to give the shpool session for a window running the
+/// given command line, which is None when it is running a plain shell
+/// so that the session gets the user's normal shell.
+fn command(cmdline: &[String], shells: &[String]) -> Option<String> {
+    let argv0 = cmdline.first()?;
+    let base = Path::new(argv0.trim_start_matches('-'))
+        .file_name()
+        .map(|b| b.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // a login shell or a shell with no args is just a shell
+    if shells.contains(&base) && (argv0.starts_with('-') || cmdline.len() == 1) {
+        return None;
+    }
+    Some(shell_words::join(cmdline))
+}
+
+fn known_shells() -> Vec<String> {
+    let shells: Vec<String> = fs::read_to_string("/etc/shells")
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| Path::new(l).file_name().map(|b| b.to_string_lossy().into_owned()))
+        .collect();
+    if shells.is_empty() {
+        FALLBACK_SHELLS.iter().map(|s| String::from(*s)).collect()
+    } else {
+        shells
+    }
+}
+
+fn read_cmdline(pid: i32) -> anyhow::Result<Vec<String>> {
+    let raw = fs::read(format!("/proc/{}/cmdline", pid)).context("reading cmdline")?;
+    Ok(raw
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect())
+}
+
+fn read_environ(pid: i32) -> anyhow::Result<HashMap<String, String>> {
+    let raw = fs::read(format!("/proc/{}/environ", pid)).context("reading environ")?;
+    Ok(raw
+        .split(|b| *b == 0)
+        .filter_map(|var| {
+            let var = String::from_utf8_lossy(var);
+            let (k, v) = var.split_once('=')?;
+            Some((String::from(k), String::from(v)))
+        })
+        .collect())
+}
+
+fn children_by_parent() -> anyhow::Result<HashMap<i32, Vec<i32>>> {
+    let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+    for entry in fs::read_dir("/proc").context("listing /proc")?.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<i32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        // processes can exit out from under us, so errors here are expected
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        // the command name can have spaces and parens in it, so
+        // skip past the last paren before splitting
+        let ppid = stat
+            .rfind(')')
+            .and_then(|i| stat[i + 1..].split_whitespace().nth(1))
+            .and_then(|p| p.parse().ok());
+        if let Some(ppid) = ppid {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+    for pids in children.values_mut() {
+        pids.sort();
+    }
+    Ok(children)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn tmux_pane_names() {
+        let out = "work\t0\t0\t100\nwork\t1\t0\t101\nwork\t1\t1\t102\nmy notes\t0\t0\t200\nbad\n";
+        let named: Vec<(String, i32)> =
+            name_windows(parse_tmux_panes(out)).into_iter().map(|(n, w)| (n, w.pid)).collect();
+        assert_eq!(
+            named,
+            vec![
+                (String::from("work-0.0"), 100),
+                (String::from("work-1.0"), 101),
+                (String::from("work-1.1"), 102),
+                (String::from("my-notes"), 200),
+            ]
+        );
+    }
+
+    #[test]
+    fn screen_ls() {
+        let out = "There are screens on:\n\
+                   \t4321.pts-0.host\t(Detached)\n\
+                   \t1234.work\t(01/02/2024 10:00:00 AM)\t(Attached)\n\
+                   2 Sockets in /run/screen/S-me.\n";
+        assert_eq!(
+            parse_screen_ls(out),
+            vec![(4321, String::from("pts-0.host")), (1234, String::from("work"))]
+        );
+    }
+
+    #[test]
+    fn commands() {
+        let shells = strings(&["bash", "zsh"]);
+        let cases = vec![
+            (vec!["-bash"], None),
+            (vec!["/bin/zsh"], None),
+            (vec!["-zsh", "-l"], None),
+            (vec!["bash", "script.sh"], Some("bash script.sh")),
+            (vec!["htop"], Some("htop")),
+            (vec!["tail", "-f", "my log"], Some("tail -f 'my log'")),
+            (vec![], None),
+        ];
+        for (cmdline, want) in cases.into_iter() {
+            assert_eq!(
+                command(&strings(&cmdline), &shells).as_deref(),
+                want,
+                "cmdline={:?}",
+                cmdline
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{AdoptPidReply, AdoptPidRequest, AdoptPidStatus, ConnectHeader};
+
+use crate::{context::ClientContext, session_name};
+
+pub fn run(pid: i32, name: String, ctx: &ClientContext) -> anyhow::Result<()> {
+    if let Err(reason) = session_name::validate(&name) {
+        eprintln!("{}", reason);
+        return Err(anyhow!("{}", reason));
+    }
+
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::AdoptPid(AdoptPidRequest { name: name.clone(), pid }))
+        .context("writing adopt-pid request header")?;
+
+    let reply: AdoptPidReply = client.read_reply().context("reading reply")?;
+
+    let msg = match reply.status {
+        AdoptPidStatus::Adopted => {
+            println!("moved pid {} into session '{}', attach to it with `shpool attach {}`", pid, name, name);
+            return Ok(());
+        }
+        AdoptPidStatus::Unsupported => String::from(
+            "the daemon can't adopt processes, it needs to be built with the adopt_pid feature on x86_64 linux",
+        ),
+        AdoptPidStatus::AlreadyExists => format!("session '{}' already exists", name),
+        AdoptPidStatus::InvalidName(reason) => reason,
+        AdoptPidStatus::Failed(reason) => format!("adopting pid {}: {}", pid, reason),
+    };
+    eprintln!("{}", msg);
+    Err(anyhow!("{}", msg))
+}
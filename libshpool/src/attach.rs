@@ -12,41 +12,128 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, fmt, io, path::PathBuf, thread, time};
+use std::{
+    env, fs, io, process,
+    sync::{Arc, Mutex},
+    thread, time,
+};
 
 use anyhow::{anyhow, bail, Context};
+use nix::sys::signal::{self, Signal};
 use shpool_protocol::{
-    AttachHeader, AttachReplyHeader, ConnectHeader, DetachReply, DetachRequest, ResizeReply,
-    ResizeRequest, SessionMessageReply, SessionMessageRequest, SessionMessageRequestPayload,
-    TtySize,
+    AttachHeader, AttachReplyHeader, ConnectHeader, DetachReply, DetachRequest, RedrawReply,
+    ResizeReply, ResizeRequest, RestartPolicy, SessionMessageReply, SessionMessageRequest,
+    SessionMessageRequestPayload, SuspendReply, SuspendRequest, TerminalCaps, TtySize,
+};
+use tracing::{error, info, instrument, warn};
+
+use super::{
+    clipboard_bridge, config,
+    context::ClientContext,
+    control,
+    daemon::{cancel, keybindings},
+    duration,
+    messages::{self, Message},
+    output, picker, protocol,
+    protocol::ClientResult,
+    session_name, terminal_probe, test_hooks, top, tty,
+    tty::TtySizeExt as _,
+    Error,
 };
-use tracing::{error, info, warn};
-
-use super::{config, duration, protocol, protocol::ClientResult, test_hooks, tty::TtySizeExt as _};
 
 const MAX_FORCE_RETRIES: usize = 20;
+/// What `shpool attach` exits with when the user quits with a locally
+/// handled quit character, the same as being killed by SIGQUIT.
+pub const QUIT_EXIT_STATUS: i32 = 128 + Signal::SIGQUIT as i32;
+const DEFAULT_CLIENT_DETACH_KEYBINDING: &str = "Ctrl-Space Ctrl-q";
+const DEFAULT_CLIENT_REDRAW_KEYBINDING: &str = "Ctrl-Space Ctrl-l";
+
+/// The on_detach_local hook for the current attach, kept where the
+/// paths that exit the process early can get at it.
+static DETACH_HOOK: Mutex<Option<LocalHook>> = Mutex::new(None);
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
-    config_manager: config::Manager,
-    name: String,
+    ctx: ClientContext,
+    name: Option<String>,
+    pane: Option<String>,
+    auto_name: bool,
     force: bool,
     ttl: Option<String>,
     cmd: Option<String>,
-    socket: PathBuf,
-) -> anyhow::Result<()> {
+    container: Option<String>,
+    udp: bool,
+    template: Option<String>,
+    cwd: Option<String>,
+    restart: Option<RestartPolicy>,
+    control: bool,
+) -> anyhow::Result<i32> {
     info!("\n\n======================== STARTING ATTACH ============================\n\n");
     test_hooks::emit("attach-startup");
 
-    if name.is_empty() {
-        eprintln!("blank session names are not allowed");
-        return Ok(());
+    let name = match name {
+        Some(name) => name,
+        // With --auto-name, the daemon picks the name, so there is
+        // nothing to check yet.
+        None if auto_name => String::new(),
+        None if picker::available() => match picker::pick(&ctx)? {
+            Some(name) => name,
+            None => return Ok(0),
+        },
+        None => bail!("no session name given, and not on a terminal to pick one from"),
+    };
+    if !auto_name {
+        let check = session_name::validate(&name).and_then(|()| match &pane {
+            Some(pane) => session_name::validate_pane(pane),
+            None => Ok(()),
+        });
+        if let Err(reason) = check {
+            output::error(&reason);
+            output::result_to_stderr("attach", "invalid-name", &[name]);
+            return Err(Error::InvalidSessionName(reason).into());
+        }
     }
-    if name.contains(char::is_whitespace) {
-        eprintln!("whitespace is not allowed in session names");
-        return Ok(());
+    // From here on a pane goes by its full address, which is what the
+    // daemon, hooks and force detach all know it as.
+    let name = match &pane {
+        Some(pane) => session_name::pane_address(&name, pane),
+        None => name,
+    };
+
+    if udp && !cfg!(feature = "udp_transport") {
+        return Err(anyhow!("shpool was built without support for the udp transport"));
     }
 
-    SignalHandler::new(name.clone(), socket.clone()).spawn()?;
+    if let Some(template) = &template {
+        let known =
+            ctx.config.get().templates.as_ref().map(|t| t.contains_key(template)).unwrap_or(false);
+        if !known {
+            bail!("no session template named '{}' in the config", template);
+        }
+    }
+
+    // New sessions start out wherever the user is attaching from, unless
+    // they asked for somewhere else.
+    let cwd = match cwd {
+        Some(cwd) => Some(
+            fs::canonicalize(&cwd)
+                .context(format!("resolving --cwd '{}'", cwd))?
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        None => match env::current_dir() {
+            Ok(dir) => Some(dir.to_string_lossy().into_owned()),
+            Err(e) => {
+                warn!("could not get current dir, new session will start in home dir: {:?}", e);
+                None
+            }
+        },
+    };
+
+    // In control mode, the front-end tells us about resizes itself.
+    if !auto_name && !control {
+        SignalHandler::new(name.clone(), ctx.clone()).spawn()?;
+    }
 
     let ttl = match &ttl {
         Some(src) => match duration::parse(src.as_str()) {
@@ -58,20 +145,65 @@ pub fn run(
         None => None,
     };
 
+    let (on_attach, on_detach) = {
+        let config = ctx.config.get();
+        (config.on_attach_local.clone(), config.on_detach_local.clone())
+    };
+    if let Some(cmd) = on_attach {
+        let hook = LocalHook { what: "on_attach_local", cmd, session_name: name.clone(), control };
+        if let Err(e) = hook.run() {
+            output::error(format!("shpool: {:#}, not attaching", e));
+            return Err(e);
+        }
+    }
+    *DETACH_HOOK.lock().unwrap() = on_detach.map(|cmd| LocalHook {
+        what: "on_detach_local",
+        cmd,
+        session_name: name.clone(),
+        control,
+    });
+    let _detach_hook = DetachHookGuard;
+
+    let terminal = if control || ctx.config.get().noprobe_terminal.unwrap_or(false) {
+        None
+    } else {
+        terminal_probe::probe()
+    };
+
     let mut detached = false;
     let mut tries = 0;
-    while let Err(err) = do_attach(&config_manager, name.as_str(), &ttl, &cmd, &socket) {
+    loop {
+        let err = match do_attach(
+            &ctx,
+            name.as_str(),
+            auto_name,
+            &ttl,
+            &cmd,
+            &container,
+            udp,
+            &template,
+            &cwd,
+            restart,
+            &terminal,
+            control,
+        ) {
+            Ok(exit_status) => return Ok(exit_status),
+            Err(err) => err,
+        };
         match err.downcast() {
-            Ok(BusyError) if !force => {
-                eprintln!("session '{}' already has a terminal attached", name);
-                return Ok(());
+            Ok(Error::SessionBusy(name)) if !force => {
+                output::error(messages::render(&ctx.config.get(), Message::Busy, &name, &[]));
+                output::result_to_stderr("attach", "busy", std::slice::from_ref(&name));
+                return Err(Error::SessionBusy(name).into());
             }
-            Ok(BusyError) => {
+            Ok(Error::SessionBusy(_)) => {
                 if !detached {
-                    let mut client = dial_client(&socket)?;
+                    let (mut client, _) = dial_client(&ctx, control)?;
                     client
                         .write_connect_header(ConnectHeader::Detach(DetachRequest {
                             sessions: vec![name.clone()],
+                            panes: false,
+                            dry_run: false,
                         }))
                         .context("writing detach request header")?;
                     let detach_reply: DetachReply = client.read_reply().context("reading reply")?;
@@ -84,38 +216,42 @@ pub fn run(
                 thread::sleep(time::Duration::from_millis(100));
 
                 if tries > MAX_FORCE_RETRIES {
-                    eprintln!(
-                        "session '{}' already has a terminal which remains attached even after attempting to detach it",
-                        name
-                    );
-                    return Err(anyhow!("could not detach session, forced attach failed"));
+                    output::error(messages::render(
+                        &ctx.config.get(),
+                        Message::ForceAttachFailed,
+                        &name,
+                        &[],
+                    ));
+                    output::result_to_stderr("attach", "busy", std::slice::from_ref(&name));
+                    return Err(Error::SessionBusy(name)).context("forced attach failed");
                 }
                 tries += 1;
             }
+            Ok(err) => return Err(err.into()),
             Err(err) => return Err(err),
         }
     }
-
-    Ok(())
-}
-
-#[derive(Debug)]
-struct BusyError;
-impl fmt::Display for BusyError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "BusyError")
-    }
 }
-impl std::error::Error for BusyError {}
 
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip_all, fields(s = name))]
 fn do_attach(
-    config: &config::Manager,
+    ctx: &ClientContext,
     name: &str,
+    auto_name: bool,
     ttl: &Option<time::Duration>,
     cmd: &Option<String>,
-    socket: &PathBuf,
-) -> anyhow::Result<()> {
-    let mut client = dial_client(socket)?;
+    container: &Option<String>,
+    udp: bool,
+    template: &Option<String>,
+    cwd: &Option<String>,
+    restart: Option<RestartPolicy>,
+    terminal: &Option<TerminalCaps>,
+    control: bool,
+) -> anyhow::Result<i32> {
+    let config = &ctx.config;
+    let (mut client, version_mismatch) = dial_client(ctx, control)?;
+    let emitter = if control { Some(control::Emitter::stdout()) } else { None };
 
     let tty_size = match TtySize::from_fd(0) {
         Ok(s) => s,
@@ -133,9 +269,11 @@ fn do_attach(
         }
     }
 
+    let (session, pane) = session_name::split_pane(name);
     client
         .write_connect_header(ConnectHeader::Attach(AttachHeader {
-            name: String::from(name),
+            name: String::from(session),
+            pane: pane.map(String::from),
             local_tty_size: tty_size,
             local_env: local_env_keys
                 .into_iter()
@@ -146,32 +284,78 @@ fn do_attach(
                 .collect::<Vec<_>>(),
             ttl_secs: ttl.map(|d| d.as_secs()),
             cmd: cmd.clone(),
+            container: container.clone(),
+            udp_transport: udp,
+            template: template.clone(),
+            cwd: cwd.clone(),
+            client_version: String::from(shpool_protocol::VERSION),
+            ssh_client: env::var("SSH_CONNECTION")
+                .ok()
+                .and_then(|conn| conn.split_whitespace().next().map(String::from)),
+            auto_name,
+            restart,
+            terminal: terminal.clone(),
+            checked_frames: true,
+            notice_chunks: true,
         }))
         .context("writing attach header")?;
 
+    if udp {
+        client.negotiate_udp().context("negotiating udp transport")?;
+    }
+
     let attach_resp: AttachReplyHeader = client.read_reply().context("reading attach reply")?;
     info!("attach_resp.status={:?}", attach_resp.status);
+    client.set_checked_frames(attach_resp.checked_frames);
 
+    let mut created = false;
     {
         use shpool_protocol::AttachStatus::*;
         match attach_resp.status {
             Busy => {
-                return Err(BusyError.into());
+                return Err(Error::SessionBusy(String::from(name)).into());
+            }
+            BeingCreated => {
+                let err = Error::SessionBeingCreated(String::from(name));
+                output::error(format!("{}, try again in a moment", err));
+                output::result_to_stderr("attach", "being-created", &[String::from(name)]);
+                return Err(err.into());
+            }
+            // The daemon only refuses a client whose version it already
+            // flagged as a mismatch for strict_version_check.
+            Forbidden(reason) if version_mismatch => {
+                let vars = [(messages::REASON_VAR, reason.as_str())];
+                output::error(messages::render(&config.get(), Message::Forbidden, name, &vars));
+                output::result_to_stderr("attach", "forbidden", &[String::from(name)]);
+                return Err(Error::VersionSkew(reason).into());
             }
             Forbidden(reason) => {
-                eprintln!("forbidden: {}", reason);
-                return Err(anyhow!("forbidden: {}", reason));
+                let vars = [(messages::REASON_VAR, reason.as_str())];
+                output::error(messages::render(&config.get(), Message::Forbidden, name, &vars));
+                output::result_to_stderr("attach", "forbidden", &[String::from(name)]);
+                return Err(Error::Forbidden(reason).into());
+            }
+            InvalidName(reason) => {
+                let vars = [(messages::REASON_VAR, reason.as_str())];
+                output::error(messages::render(&config.get(), Message::InvalidName, name, &vars));
+                output::result_to_stderr("attach", "invalid-name", &[String::from(name)]);
+                return Err(Error::InvalidSessionName(reason).into());
             }
             Attached { warnings } => {
-                for warning in warnings.into_iter() {
-                    eprintln!("shpool: warn: {}", warning);
-                }
+                print_warnings(&emitter, warnings);
                 info!("attached to an existing session: '{}'", name);
+                if ttl.is_some() && !attach_resp.ttl_applied {
+                    print_warnings(
+                        &emitter,
+                        vec![String::from(
+                            "the daemon did not apply the ttl on reattach, try restarting your daemon",
+                        )],
+                    );
+                }
             }
             Created { warnings } => {
-                for warning in warnings.into_iter() {
-                    eprintln!("shpool: warn: {}", warning);
-                }
+                print_warnings(&emitter, warnings);
+                created = true;
                 info!("created a new session: '{}'", name);
             }
             UnexpectedError(err) => {
@@ -180,16 +364,148 @@ fn do_attach(
         }
     }
 
-    match client.pipe_bytes() {
-        Ok(exit_status) => std::process::exit(exit_status),
-        Err(e) => Err(e),
+    let name = if auto_name {
+        attach_resp.name.ok_or(anyhow!("daemon did not say what it named the session"))?
+    } else {
+        String::from(name)
+    };
+
+    match emitter {
+        Some(emitter) => {
+            emitter.attached(&name, created);
+            client.pipe_control(emitter, name, ctx.clone())
+        }
+        None => {
+            // stdout belongs to the session, so the result goes to stderr
+            output::result_to_stderr(
+                "attach",
+                if created { "created" } else { "attached" },
+                std::slice::from_ref(&name),
+            );
+            let banner = ExitBanner::new(config, &name);
+            let local_signals = local_signals(&config.get());
+            let quit = Arc::new(cancel::Cancel::new()?);
+            TtySignalHandler {
+                session_name: name.clone(),
+                ctx: ctx.clone(),
+                quit: Arc::clone(&quit),
+            }
+            .spawn(local_signals)?;
+            let escape = LocalEscape::new(ctx, &name).context("building client keybindings")?;
+            let clipboard = clipboard_bridge(&config.get());
+            if auto_name {
+                output::note(format!("shpool: created session '{}'", name));
+                SignalHandler::new(name, ctx.clone()).spawn()?;
+            }
+            let status = client.pipe_bytes(escape, banner, local_signals, clipboard, &quit)?;
+            if quit.is_cancelled() {
+                output::note("shpool: quit, the session is still running");
+            }
+            Ok(status)
+        }
+    }
+}
+
+/// A command from the config for `shpool attach` to run on the
+/// client's machine.
+struct LocalHook {
+    /// The config option the command came from, for messages.
+    what: &'static str,
+    cmd: String,
+    session_name: String,
+    control: bool,
+}
+
+impl LocalHook {
+    fn run(&self) -> anyhow::Result<()> {
+        info!("running {} '{}'", self.what, self.cmd);
+        let mut cmd = process::Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg(&self.cmd)
+            .env("SHPOOL_SESSION_NAME", &self.session_name)
+            // stdout might be the session's or a control mode
+            // front-end's, so keep the hook's output off of it
+            .stdout(io::stderr());
+        if self.control {
+            cmd.stdin(process::Stdio::null());
+        }
+        let status = cmd.status().with_context(|| format!("running {}", self.what))?;
+        if !status.success() {
+            return Err(anyhow!("{} '{}' failed with {}", self.what, self.cmd, status));
+        }
+        Ok(())
+    }
+}
+
+/// Run the on_detach_local hook, unless there is none or it already
+/// ran. Called on the way out of the process, however the attach ends.
+pub fn run_detach_hook() {
+    let hook = DETACH_HOOK.lock().unwrap().take();
+    if let Some(hook) = hook {
+        if let Err(e) = hook.run() {
+            output::warning(format!("shpool: {:#}", e));
+        }
     }
 }
 
-fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
-    match protocol::Client::new(socket) {
-        Ok(ClientResult::JustClient(c)) => Ok(c),
-        Ok(ClientResult::VersionMismatch { warning, client }) => {
+/// Runs the on_detach_local hook when `attach::run` returns. The paths
+/// that exit the process without returning run it themselves.
+struct DetachHookGuard;
+
+impl Drop for DetachHookGuard {
+    fn drop(&mut self) {
+        run_detach_hook();
+    }
+}
+
+/// The clipboard bridge, if it is turned on and there is a display to
+/// reach the clipboard through.
+fn clipboard_bridge(config: &config::Config) -> Option<clipboard_bridge::Bridge> {
+    if !config.clipboard_bridge.unwrap_or(false) {
+        return None;
+    }
+    let backend = clipboard_bridge::Backend::detect();
+    if backend.is_none() {
+        info!("clipboard_bridge is on, but neither WAYLAND_DISPLAY nor DISPLAY is set");
+    }
+    backend.map(|backend| clipboard_bridge::Bridge {
+        backend,
+        paste: config.clipboard_bridge_paste.unwrap_or(false),
+    })
+}
+
+fn print_warnings(emitter: &Option<control::Emitter>, warnings: Vec<String>) {
+    for warning in warnings.into_iter() {
+        match emitter {
+            Some(emitter) => emitter.warning(&warning),
+            None => output::warning(format!("shpool: warn: {}", warning)),
+        }
+    }
+}
+
+/// Connect to the daemon, also returning whether it speaks a different
+/// version of the protocol. The user only gets asked about a mismatch
+/// the first time.
+fn dial_client(ctx: &ClientContext, control: bool) -> anyhow::Result<(protocol::Client, bool)> {
+    match ctx.dial()? {
+        ClientResult::JustClient(c) => Ok((c, false)),
+        ClientResult::VersionMismatch { warning, client }
+            if !ctx.note_version_mismatch(warning.clone()) =>
+        {
+            Ok((client, true))
+        }
+        // There is nobody to ask in control mode, so just pass the
+        // warning along to the front-end.
+        ClientResult::VersionMismatch { warning, client } if control => {
+            control::Emitter::stdout().warning(&format!("{}, try restarting your daemon", warning));
+            Ok((client, true))
+        }
+        // Scripts can't answer the prompt, so they just get the warning.
+        ClientResult::VersionMismatch { warning, client } if !output::chatty() => {
+            output::warning(format!("warning: {}, try restarting your daemon", warning));
+            Ok((client, true))
+        }
+        ClientResult::VersionMismatch { warning, client } => {
             eprintln!("warning: {}, try restarting your daemon", warning);
             eprintln!("hit enter to continue anyway or ^C to exit");
 
@@ -198,14 +514,166 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
                 .next()
                 .context("waiting for a continue through a version mismatch")?;
 
-            Ok(client)
+            Ok((client, true))
+        }
+    }
+}
+
+//
+// Local Escape
+//
+
+/// LocalEscape watches the user's keystrokes for the client detach
+/// and redraw keybindings. Unlike the keybindings in the daemon, this
+/// runs entirely inside `shpool attach`, so detaching still works when
+/// the daemon is wedged or so busy pumping output that it never gets
+/// around to reading input.
+pub struct LocalEscape {
+    bindings: keybindings::Bindings,
+    /// Bytes which might be the start of a keybinding, held back
+    /// until we know whether they should go to the shell.
+    pending: Vec<u8>,
+    /// Where to send redraw requests, and for which session.
+    redraw_target: Option<(ClientContext, String)>,
+}
+
+impl LocalEscape {
+    /// Build the escape matcher from the config, returning None if the
+    /// user has turned off both keybindings.
+    pub fn new(ctx: &ClientContext, session_name: &str) -> anyhow::Result<Option<Self>> {
+        let config = ctx.config.get();
+        let detach = config
+            .client_detach_keybinding
+            .clone()
+            .unwrap_or(String::from(DEFAULT_CLIENT_DETACH_KEYBINDING));
+        let redraw = config
+            .client_redraw_keybinding
+            .clone()
+            .unwrap_or(String::from(DEFAULT_CLIENT_REDRAW_KEYBINDING));
+        let bindings = [
+            (detach.as_str(), keybindings::Action::Detach),
+            (redraw.as_str(), keybindings::Action::Redraw),
+        ]
+        .into_iter()
+        .filter(|(binding, _)| !binding.is_empty())
+        .collect::<Vec<_>>();
+        if bindings.is_empty() {
+            return Ok(None);
         }
-        Err(err) => {
-            let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
-                eprintln!("could not connect to daemon");
+        let mut escape = Self::from_bindings(bindings)?;
+        escape.redraw_target = Some((ctx.clone(), String::from(session_name)));
+        Ok(Some(escape))
+    }
+
+    fn from_bindings<'a, B: IntoIterator<Item = (&'a str, keybindings::Action)>>(
+        bindings: B,
+    ) -> anyhow::Result<Self> {
+        let bindings =
+            keybindings::Bindings::new(bindings).context("parsing client keybindings")?;
+        Ok(LocalEscape { bindings, pending: vec![], redraw_target: None })
+    }
+
+    /// Ask the daemon for a redraw off to the side, so that a slow
+    /// daemon doesn't hold up the user's typing.
+    fn request_redraw(&self) {
+        let Some((ctx, session_name)) = self.redraw_target.clone() else {
+            return;
+        };
+        thread::spawn(move || {
+            if let Err(e) = redraw_session(&ctx, &session_name) {
+                warn!("requesting redraw: {:?}", e);
+            }
+        });
+    }
+
+    /// Scan a chunk of user input, appending the bytes which should be
+    /// forwarded to the daemon to `out`. Returns true if the detach
+    /// keybinding fired, in which case the rest of the input is dropped.
+    pub fn scan(&mut self, input: &[u8], out: &mut Vec<u8>) -> bool {
+        for byte in input.iter() {
+            use keybindings::BindingResult::*;
+            match self.bindings.transition(*byte) {
+                Partial => self.pending.push(*byte),
+                Match(keybindings::Action::Detach) => {
+                    self.pending.clear();
+                    return true;
+                }
+                Match(keybindings::Action::Redraw) => {
+                    info!("client redraw keybinding fired");
+                    self.pending.clear();
+                    self.request_redraw();
+                }
+                NoMatch | Match(_) => {
+                    out.append(&mut self.pending);
+                    out.push(*byte);
+                }
             }
-            Err(io_err).context("connecting to daemon")
+        }
+        false
+    }
+}
+
+//
+// Exit Banner
+//
+
+/// ExitBanner prints a short summary of the session when `shpool attach`
+/// is done with it, if the `exit_banner` config option is on.
+pub struct ExitBanner {
+    session_name: String,
+    attached_at: time::Instant,
+}
+
+/// What happened over the course of an attach, for the exit banner.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AttachStats {
+    /// Bytes of user input sent to the session.
+    pub bytes_in: u64,
+    /// Bytes of session output written to the terminal.
+    pub bytes_out: u64,
+    /// The exit status of the shell, if it exited rather than us
+    /// detaching from it.
+    pub exit_status: Option<i32>,
+}
+
+impl ExitBanner {
+    pub fn new(config: &config::Manager, session_name: &str) -> Option<Self> {
+        if !config.get().exit_banner.unwrap_or(false) || !output::chatty() {
+            return None;
+        }
+        Some(ExitBanner {
+            session_name: String::from(session_name),
+            attached_at: time::Instant::now(),
+        })
+    }
+
+    /// Print the banner. The tty must already be out of raw mode.
+    pub fn print(&self, stats: &AttachStats) {
+        eprint!("{}", self.render(stats, self.attached_at.elapsed()));
+    }
+
+    fn render(&self, stats: &AttachStats, attached_for: time::Duration) -> String {
+        let traffic = format!(
+            "{} in, {} out",
+            top::human_bytes(stats.bytes_in),
+            top::human_bytes(stats.bytes_out)
+        );
+        match stats.exit_status {
+            Some(status) => format!(
+                "shpool: session '{}' exited with status {} after {} attached ({})\n",
+                self.session_name,
+                status,
+                duration::format(attached_for),
+                traffic,
+            ),
+            None => format!(
+                "shpool: detached from session '{}' after {} ({})\n\
+                 shpool: reattach with: shpool attach {}\n",
+                self.session_name,
+                duration::format(attached_for),
+                traffic,
+                shell_words::quote(&self.session_name),
+            ),
         }
     }
 }
@@ -216,12 +684,12 @@ fn dial_client(socket: &PathBuf) -> anyhow::Result<protocol::Client> {
 
 struct SignalHandler {
     session_name: String,
-    socket: PathBuf,
+    ctx: ClientContext,
 }
 
 impl SignalHandler {
-    fn new(session_name: String, socket: PathBuf) -> Self {
-        SignalHandler { session_name, socket }
+    fn new(session_name: String, ctx: ClientContext) -> Self {
+        SignalHandler { session_name, ctx }
     }
 
     fn spawn(self) -> anyhow::Result<()> {
@@ -250,44 +718,287 @@ impl SignalHandler {
 
     fn handle_sigwinch(&self) -> anyhow::Result<()> {
         info!("handle_sigwinch: enter");
-        let mut client = match protocol::Client::new(&self.socket)? {
-            ClientResult::JustClient(c) => c,
-            // At this point, we've already warned the user and they
-            // chose to continue anyway, so we shouldn't bother them
-            // again.
-            ClientResult::VersionMismatch { client, .. } => client,
-        };
-
         let tty_size = TtySize::from_fd(0).context("getting tty size")?;
         info!("handle_sigwinch: tty_size={:?}", tty_size);
+        resize_session(&self.ctx, &self.session_name, tty_size)
+    }
+}
 
-        // write the request on a new, seperate connection
-        client
-            .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
-                session_name: self.session_name.clone(),
-                payload: SessionMessageRequestPayload::Resize(ResizeRequest {
-                    tty_size: tty_size.clone(),
-                }),
-            }))
-            .context("writing resize request")?;
-
-        let reply: SessionMessageReply =
-            client.read_reply().context("reading session message reply")?;
-        match reply {
-            SessionMessageReply::NotFound => {
-                warn!(
-                    "handle_sigwinch: sent resize for session '{}', but the daemon has no record of that session",
-                    self.session_name
-                );
+/// Pick out the signal characters that the config says `shpool attach`
+/// should handle itself.
+fn local_signals(config: &config::Config) -> tty::LocalSignals {
+    let is_local = |handling: Option<config::TtySignalHandling>| {
+        handling.unwrap_or_default() == config::TtySignalHandling::Local
+    };
+    match &config.tty_signals {
+        Some(sigs) => {
+            tty::LocalSignals { suspend: is_local(sigs.suspend), quit: is_local(sigs.quit) }
+        }
+        None => tty::LocalSignals::default(),
+    }
+}
+
+/// Handles suspend signals, as well as the quit signal if the client
+/// tty raises it for a locally handled quit character. Since the tty
+/// is in raw mode while attached, the default actions would leave it
+/// in a mess.
+struct TtySignalHandler {
+    session_name: String,
+    ctx: ClientContext,
+    /// Cancelled to end the attach when the user quits.
+    quit: Arc<cancel::Cancel>,
+}
+
+impl TtySignalHandler {
+    fn spawn(self, local: tty::LocalSignals) -> anyhow::Result<()> {
+        use signal_hook::{consts::*, iterator::*};
+
+        // SIGTSTP can come from somewhere other than our tty, like a
+        // `kill -TSTP`, so we always handle it.
+        let mut sigs = vec![SIGTSTP];
+        if local.quit {
+            sigs.push(SIGQUIT);
+        }
+        let mut signals = Signals::new(sigs).context("creating tty signal iterator")?;
+
+        thread::spawn(move || {
+            for signal in &mut signals {
+                let res = match signal {
+                    SIGTSTP => self.handle_sigtstp(),
+                    SIGQUIT => self.handle_sigquit(),
+                    sig => {
+                        error!("unknown signal: {}", sig);
+                        panic!("unknown signal: {}", sig);
+                    }
+                };
+                if let Err(e) = res {
+                    error!("tty signal handler error: {:?}", e);
+                }
             }
-            SessionMessageReply::Resize(ResizeReply::Ok) => {
-                info!("handle_sigwinch: resized session '{}' to {:?}", self.session_name, tty_size);
+        });
+
+        Ok(())
+    }
+
+    fn handle_sigtstp(&self) -> anyhow::Result<()> {
+        info!("handle_sigtstp: suspending");
+        // Let the daemon know first, so that it doesn't mistake us for
+        // a dead client once we stop reading.
+        if let Err(e) = suspend_session(&self.ctx, &self.session_name, true) {
+            warn!("telling daemon about suspend: {:?}", e);
+        }
+        // if we are not on a tty, there is nothing to restore
+        if let Err(e) = tty::restore_pre_attach_flags() {
+            info!("not restoring tty before suspend: {:?}", e);
+        }
+        // SIGSTOP can't be caught, so this really stops us, and we pick
+        // up from here once the shell sends a SIGCONT.
+        signal::raise(Signal::SIGSTOP).context("stopping self")?;
+        info!("handle_sigtstp: resumed");
+        if let Err(e) = tty::reapply_attach_flags() {
+            info!("not reapplying tty flags after resume: {:?}", e);
+        }
+
+        // The terminal may have changed size while we were stopped. The
+        // daemon redraws the screen when it hears that we are back, so
+        // resize first so the redraw comes out the right size.
+        match TtySize::from_fd(0) {
+            Ok(tty_size) => {
+                if let Err(e) = resize_session(&self.ctx, &self.session_name, tty_size) {
+                    warn!("resyncing size after resume: {:?}", e);
+                }
             }
-            reply => {
-                warn!("handle_sigwinch: unexpected resize reply: {:?}", reply);
+            Err(e) => info!("not resyncing size after resume: {:?}", e),
+        }
+        suspend_session(&self.ctx, &self.session_name, false)
+    }
+
+    fn handle_sigquit(&self) -> anyhow::Result<()> {
+        info!("handle_sigquit: quitting");
+        // pipe_bytes hangs up, which detaches us same as if we had been
+        // killed, and returns QUIT_EXIT_STATUS once the tty is back to
+        // normal.
+        self.quit.cancel();
+        Ok(())
+    }
+}
+
+/// Tell the daemon that the client attached to the session is being
+/// suspended, or is back.
+fn suspend_session(ctx: &ClientContext, session_name: &str, suspended: bool) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
+            session_name: String::from(session_name),
+            payload: SessionMessageRequestPayload::Suspend(SuspendRequest { suspended }),
+        }))
+        .context("writing suspend request")?;
+
+    let reply: SessionMessageReply =
+        client.read_reply().context("reading session message reply")?;
+    match reply {
+        SessionMessageReply::Suspend(SuspendReply::Ok) => Ok(()),
+        reply => Err(anyhow!("unexpected suspend reply: {:?}", reply)),
+    }
+}
+
+/// Ask the daemon to redraw the screen of the client attached to the
+/// session.
+fn redraw_session(ctx: &ClientContext, session_name: &str) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
+            session_name: String::from(session_name),
+            payload: SessionMessageRequestPayload::Redraw,
+        }))
+        .context("writing redraw request")?;
+
+    let reply: SessionMessageReply =
+        client.read_reply().context("reading session message reply")?;
+    match reply {
+        SessionMessageReply::Redraw(RedrawReply::Ok) => Ok(()),
+        reply => Err(anyhow!("unexpected redraw reply: {:?}", reply)),
+    }
+}
+
+/// Tell the daemon about a new size for the session's terminal.
+pub fn resize_session(
+    ctx: &ClientContext,
+    session_name: &str,
+    tty_size: TtySize,
+) -> anyhow::Result<()> {
+    // At this point, the user has already heard about any version
+    // mismatch, so connect won't bother them with it again.
+    let mut client = ctx.connect()?;
+
+    // write the request on a new, seperate connection
+    client
+        .write_connect_header(ConnectHeader::SessionMessage(SessionMessageRequest {
+            session_name: String::from(session_name),
+            payload: SessionMessageRequestPayload::Resize(ResizeRequest {
+                tty_size: tty_size.clone(),
+            }),
+        }))
+        .context("writing resize request")?;
+
+    let reply: SessionMessageReply =
+        client.read_reply().context("reading session message reply")?;
+    match reply {
+        SessionMessageReply::NotFound => {
+            warn!(
+                "resize_session: sent resize for session '{}', but the daemon has no record of that session",
+                session_name
+            );
+            Err(anyhow!("no session named '{}'", session_name))
+        }
+        SessionMessageReply::Resize(ResizeReply::Ok) => {
+            info!("resize_session: resized session '{}' to {:?}", session_name, tty_size);
+            Ok(())
+        }
+        reply => {
+            warn!("resize_session: unexpected resize reply: {:?}", reply);
+            Err(anyhow!("unexpected resize reply: {:?}", reply))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts;
+
+    fn hook(cmd: &str) -> LocalHook {
+        LocalHook {
+            what: "on_attach_local",
+            cmd: String::from(cmd),
+            session_name: String::from("main"),
+            control: true,
+        }
+    }
+
+    #[test]
+    fn local_hooks() {
+        hook("test \"$SHPOOL_SESSION_NAME\" = main").run().unwrap();
+        let err = hook("exit 3").run().unwrap_err();
+        assert!(format!("{:#}", err).contains("on_attach_local 'exit 3' failed"), "{:#}", err);
+    }
+
+    fn check(chunks: &[&[u8]], want_out: &[u8], want_detach: bool) {
+        let mut escape = LocalEscape::from_bindings([
+            (DEFAULT_CLIENT_DETACH_KEYBINDING, keybindings::Action::Detach),
+            (DEFAULT_CLIENT_REDRAW_KEYBINDING, keybindings::Action::Redraw),
+        ])
+        .unwrap();
+        let mut out = vec![];
+        let mut detach = false;
+        for chunk in chunks {
+            if escape.scan(chunk, &mut out) {
+                detach = true;
+                break;
             }
         }
+        assert_eq!(out, want_out, "chunks={:?}", chunks);
+        assert_eq!(detach, want_detach, "chunks={:?}", chunks);
+    }
+
+    #[test]
+    fn local_escape() {
+        check(&[b"echo hi\n"], b"echo hi\n", false);
+        check(&[b"ls", &[0, 17], b"rm -rf"], b"ls", true);
+        check(&[b"ls", &[0], &[17]], b"ls", true);
+        check(&[&[0], b"x"], &[0, b'x'], false);
+        check(&[&[0]], b"", false);
+        // the redraw binding gets swallowed without detaching
+        check(&[b"ls", &[0, 12], b" -l"], b"ls -l", false);
+        check(&[b"ls", &[0], &[12, 17]], b"ls\x11", false);
+    }
+
+    #[test]
+    fn big_paste_passes_through() {
+        // A megabyte covering every byte value, including the NUL that
+        // starts the default binding, but never the full binding.
+        let paste: Vec<u8> = (0..=255u8).cycle().take(1024 * 1024).collect();
+        for split in [1, 7, 4093, consts::BUF_SIZE] {
+            let chunks: Vec<&[u8]> = paste.chunks(split).collect();
+            check(&chunks, &paste, false);
+        }
+    }
+
+    #[test]
+    fn exit_banner() {
+        let banner =
+            ExitBanner { session_name: String::from("my sess"), attached_at: time::Instant::now() };
+        let attached_for = time::Duration::from_secs(3725);
+
+        let stats = AttachStats { bytes_in: 12, bytes_out: 3 * 1024 * 1024, exit_status: None };
+        assert_eq!(
+            banner.render(&stats, attached_for),
+            "shpool: detached from session 'my sess' after 1h2m5s (12B in, 3.0MiB out)\n\
+             shpool: reattach with: shpool attach 'my sess'\n"
+        );
+
+        let stats = AttachStats { exit_status: Some(2), ..stats };
+        assert_eq!(
+            banner.render(&stats, attached_for),
+            "shpool: session 'my sess' exited with status 2 after 1h2m5s attached \
+             (12B in, 3.0MiB out)\n"
+        );
+    }
 
+    #[test]
+    fn tty_signals_from_config() -> anyhow::Result<()> {
+        assert_eq!(local_signals(&config::Config::default()), tty::LocalSignals::default());
+
+        let config: config::Config = toml::from_str(
+            r#"
+            [tty_signals]
+            suspend = "local"
+            quit = "forward"
+            "#,
+        )?;
+        assert_eq!(local_signals(&config), tty::LocalSignals { suspend: true, quit: false });
         Ok(())
     }
 }
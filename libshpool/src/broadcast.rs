@@ -0,0 +1,81 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{BroadcastReply, BroadcastRequest, ConnectHeader};
+
+use crate::{context::ClientContext, Error};
+
+pub fn run(
+    sessions: Vec<String>,
+    input: Vec<String>,
+    panes: bool,
+    no_enter: bool,
+    dry_run: bool,
+    ctx: &ClientContext,
+) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::Broadcast(BroadcastRequest {
+            sessions,
+            panes,
+            data: input_line(&input, no_enter),
+            dry_run,
+        }))
+        .context("writing broadcast request header")?;
+
+    let reply: BroadcastReply = client.read_reply().context("reading reply")?;
+
+    if dry_run {
+        for session in reply.matched_sessions.iter() {
+            println!("{}", session);
+        }
+    }
+
+    if !reply.failed_sessions.is_empty() {
+        eprintln!("could not send to: {}", reply.failed_sessions.join(" "));
+    }
+    if !reply.not_found_sessions.is_empty() {
+        eprintln!("not found: {}", reply.not_found_sessions.join(" "));
+        return Err(Error::SessionsNotFound(reply.not_found_sessions).into());
+    }
+    if !reply.failed_sessions.is_empty() {
+        return Err(anyhow!("could not send to: {}", reply.failed_sessions.join(" ")));
+    }
+
+    Ok(())
+}
+
+/// The bytes to send for the given words, ending with the carriage
+/// return that hitting enter on a terminal sends.
+fn input_line(input: &[String], no_enter: bool) -> Vec<u8> {
+    let mut line = input.join(" ").into_bytes();
+    if !no_enter {
+        line.push(b'\r');
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn input_lines() {
+        let words = vec![String::from("git"), String::from("pull")];
+        assert_eq!(input_line(&words, false), b"git pull\r");
+        assert_eq!(input_line(&words, true), b"git pull");
+    }
+}
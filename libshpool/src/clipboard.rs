@@ -0,0 +1,80 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io,
+    io::{Read, Write},
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{
+    ConnectHeader, CopyReply, CopyRequest, CopyStatus, PasteReply, PasteRequest, PasteStatus,
+};
+
+use crate::{context::ClientContext, Error};
+
+/// Copy the given words, or stdin if there are none, into the daemon's
+/// clipboard.
+pub fn copy(input: Vec<String>, ctx: &ClientContext) -> anyhow::Result<()> {
+    let data = if input.is_empty() {
+        let mut data = vec![];
+        io::stdin().read_to_end(&mut data).context("reading stdin")?;
+        data
+    } else {
+        input.join(" ").into_bytes()
+    };
+
+    let mut client = ctx.connect()?;
+    client
+        .write_connect_header(ConnectHeader::Copy(CopyRequest { data }))
+        .context("writing copy request header")?;
+
+    let reply: CopyReply = client.read_reply().context("reading reply")?;
+    match reply.status {
+        CopyStatus::Copied => Ok(()),
+        CopyStatus::TooBig { max_bytes } => {
+            eprintln!("too big to copy, the clipboard holds at most {} bytes", max_bytes);
+            Err(anyhow!("too big to copy"))
+        }
+    }
+}
+
+/// Print the daemon's clipboard to stdout, or type it into the given
+/// session.
+pub fn paste(session: Option<String>, ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+    client
+        .write_connect_header(ConnectHeader::Paste(PasteRequest { session: session.clone() }))
+        .context("writing paste request header")?;
+
+    let reply: PasteReply = client.read_reply().context("reading reply")?;
+    match reply.status {
+        PasteStatus::Contents(data) => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data).context("writing clipboard")?;
+            stdout.flush().context("flushing clipboard")?;
+            Ok(())
+        }
+        PasteStatus::Typed => Ok(()),
+        PasteStatus::Empty => {
+            eprintln!("the clipboard is empty");
+            Err(anyhow!("the clipboard is empty"))
+        }
+        PasteStatus::NotFound => {
+            let session = session.unwrap_or_default();
+            eprintln!("not found: {}", session);
+            Err(Error::SessionsNotFound(vec![session]).into())
+        }
+    }
+}
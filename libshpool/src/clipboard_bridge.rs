@@ -0,0 +1,418 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Bridges OSC 52 clipboard sequences to the local clipboard for
+  `shpool attach`.
+
+  Programs like vim and tmux copy text by printing an OSC 52 sequence
+  with the text base64 encoded, `ESC ] 52 ; c ; <base64> BEL`, and ask
+  for the clipboard with `?` in place of the text. Plenty of terminals
+  ignore these, or only allow copying. When the clipboard_bridge config
+  option is on, the attach client takes these sequences out of the
+  session's output and handles them itself, with wl-copy and wl-paste
+  under Wayland or xclip under X11. Paste requests hand whatever is on
+  the clipboard to any program in the session that asks, so they only
+  get answered when clipboard_bridge_paste is on as well, by typing the
+  reply sequence into the session, as a terminal would.
+*/
+
+use std::{env, io::Write, process, thread};
+
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use tracing::{info, warn};
+
+/// OSC 52 payloads longer than this get passed through to the
+/// terminal rather than buffered. Leaves room for the base64 overhead
+/// on a copy the size of the daemon's clipboard.
+const MAX_PAYLOAD_LEN: usize = 2 * 1024 * 1024;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// What the session asked of the clipboard.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Put this text on the clipboard.
+    Copy { selection: Vec<u8>, data: Vec<u8> },
+    /// Send back what is on the clipboard.
+    Paste { selection: Vec<u8> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    /// Inside an OSC, reading the code to see if it is 52.
+    OscCode,
+    Osc52,
+    /// Saw an ESC inside an OSC 52, which should be the start of the
+    /// string terminator.
+    Osc52Esc,
+}
+
+/// Takes OSC 52 sequences out of a stream of output, leaving the rest
+/// of it untouched.
+#[derive(Debug, Default)]
+pub struct Osc52Filter {
+    state: State,
+    /// The bytes of a sequence that might be an OSC 52, held back
+    /// until we know whether it is one.
+    held: Vec<u8>,
+}
+
+impl Osc52Filter {
+    /// Copy `buf` to `out`, minus any OSC 52 sequences, which get
+    /// returned as requests instead. Sequences can be split across
+    /// calls.
+    pub fn filter(&mut self, buf: &[u8], out: &mut Vec<u8>) -> Vec<Request> {
+        let mut requests = vec![];
+        for byte in buf.iter() {
+            if let Some(req) = self.transition(*byte, out) {
+                requests.push(req);
+            }
+        }
+        requests
+    }
+
+    fn transition(&mut self, byte: u8, out: &mut Vec<u8>) -> Option<Request> {
+        self.state = match (self.state, byte) {
+            (State::Ground, ESC) => {
+                self.held.push(byte);
+                State::Esc
+            }
+            (State::Ground, _) => {
+                out.push(byte);
+                State::Ground
+            }
+
+            (State::Esc, b']') => {
+                self.held.push(byte);
+                State::OscCode
+            }
+            (State::Esc, _) => {
+                self.release(out);
+                self.state = State::Ground;
+                return self.transition(byte, out);
+            }
+
+            (State::OscCode, b'5') if self.held.len() == 2 => {
+                self.held.push(byte);
+                State::OscCode
+            }
+            (State::OscCode, b'2') if self.held.len() == 3 => {
+                self.held.push(byte);
+                State::OscCode
+            }
+            (State::OscCode, b';') if self.held.len() == 4 => {
+                self.held.push(byte);
+                State::Osc52
+            }
+            // Some other OSC, which is the terminal's business.
+            (State::OscCode, _) => {
+                self.release(out);
+                self.state = State::Ground;
+                return self.transition(byte, out);
+            }
+
+            (State::Osc52, BEL) => {
+                self.state = State::Ground;
+                return self.finish();
+            }
+            (State::Osc52, ESC) => {
+                self.held.push(byte);
+                State::Osc52Esc
+            }
+            (State::Osc52, CAN) | (State::Osc52, SUB) => {
+                self.release(out);
+                out.push(byte);
+                State::Ground
+            }
+            (State::Osc52, _) if self.held.len() >= MAX_PAYLOAD_LEN => {
+                warn!("OSC 52 sequence too long, passing it through");
+                self.release(out);
+                out.push(byte);
+                State::Ground
+            }
+            (State::Osc52, _) => {
+                self.held.push(byte);
+                State::Osc52
+            }
+
+            (State::Osc52Esc, b'\\') => {
+                self.state = State::Ground;
+                return self.finish();
+            }
+            // Any other escape cuts the OSC short and starts something
+            // new, so let the terminal sort it out.
+            (State::Osc52Esc, _) => {
+                self.held.pop();
+                self.release(out);
+                self.held.push(ESC);
+                self.state = State::Esc;
+                return self.transition(byte, out);
+            }
+        };
+        None
+    }
+
+    fn release(&mut self, out: &mut Vec<u8>) {
+        out.append(&mut self.held);
+    }
+
+    /// Turn the held OSC 52 sequence into a request.
+    fn finish(&mut self) -> Option<Request> {
+        let held = std::mem::take(&mut self.held);
+        // skip the ESC ] 52 ; and any ESC from the terminator
+        let payload = held[5..].strip_suffix(&[ESC]).unwrap_or(&held[5..]);
+        let (selection, data) = match payload.iter().position(|b| *b == b';') {
+            Some(i) => (&payload[..i], &payload[i + 1..]),
+            None => {
+                warn!("OSC 52 sequence with no data, dropping it");
+                return None;
+            }
+        };
+        if data == b"?" {
+            return Some(Request::Paste { selection: selection.to_vec() });
+        }
+        match STANDARD.decode(data) {
+            Ok(data) => Some(Request::Copy { selection: selection.to_vec(), data }),
+            Err(e) => {
+                warn!("OSC 52 sequence with bad base64, dropping it: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+/// The clipboard bridge for an attach.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bridge {
+    pub backend: Backend,
+    /// Whether to answer paste requests.
+    pub paste: bool,
+}
+
+impl Bridge {
+    /// Carry out a request, returning the input to send back to the
+    /// session, if any. Paste requests get dropped unless pasting is
+    /// turned on.
+    pub fn handle(&self, req: Request) -> anyhow::Result<Option<Vec<u8>>> {
+        if let (Request::Paste { .. }, false) = (&req, self.paste) {
+            info!("ignoring a clipboard paste request, clipboard_bridge_paste is off");
+            return Ok(None);
+        }
+        self.backend.handle(req)
+    }
+}
+
+/// The programs used to get at the local clipboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// wl-copy and wl-paste from wl-clipboard.
+    Wayland,
+    /// xclip.
+    X11,
+}
+
+impl Backend {
+    /// Pick a backend based on the display the client is running
+    /// under, if any.
+    pub fn detect() -> Option<Self> {
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            Some(Backend::Wayland)
+        } else if env::var_os("DISPLAY").is_some() {
+            Some(Backend::X11)
+        } else {
+            None
+        }
+    }
+
+    fn copy_cmd(&self, primary: bool) -> process::Command {
+        match self {
+            Backend::Wayland => {
+                let mut cmd = process::Command::new("wl-copy");
+                if primary {
+                    cmd.arg("--primary");
+                }
+                cmd
+            }
+            Backend::X11 => {
+                let mut cmd = process::Command::new("xclip");
+                cmd.arg("-selection").arg(if primary { "primary" } else { "clipboard" });
+                cmd
+            }
+        }
+    }
+
+    fn paste_cmd(&self, primary: bool) -> process::Command {
+        match self {
+            Backend::Wayland => {
+                let mut cmd = process::Command::new("wl-paste");
+                cmd.arg("--no-newline");
+                if primary {
+                    cmd.arg("--primary");
+                }
+                cmd
+            }
+            Backend::X11 => {
+                let mut cmd = self.copy_cmd(primary);
+                cmd.arg("-out");
+                cmd
+            }
+        }
+    }
+
+    /// Carry out a request, returning the input to send back to the
+    /// session, if any.
+    pub fn handle(&self, req: Request) -> anyhow::Result<Option<Vec<u8>>> {
+        match req {
+            Request::Copy { selection, data } => {
+                info!("copying {} bytes to the local clipboard", data.len());
+                let mut child = self
+                    .copy_cmd(is_primary(&selection))
+                    .stdin(process::Stdio::piped())
+                    .stdout(process::Stdio::null())
+                    .stderr(process::Stdio::null())
+                    .spawn()
+                    .context("spawning clipboard copy command")?;
+                let mut stdin = child.stdin.take().ok_or(anyhow!("no stdin for copy command"))?;
+                // The copy commands can take their time, and the output
+                // should keep flowing meanwhile.
+                thread::spawn(move || {
+                    if let Err(e) = stdin.write_all(&data) {
+                        warn!("writing to clipboard copy command: {:?}", e);
+                    }
+                    drop(stdin);
+                    match child.wait() {
+                        Ok(status) if !status.success() => {
+                            warn!("clipboard copy command failed: {:?}", status)
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("waiting for clipboard copy command: {:?}", e),
+                    }
+                });
+                Ok(None)
+            }
+            Request::Paste { selection } => {
+                info!("answering a clipboard paste request");
+                let out = self
+                    .paste_cmd(is_primary(&selection))
+                    .stdin(process::Stdio::null())
+                    .stderr(process::Stdio::null())
+                    .output()
+                    .context("running clipboard paste command")?;
+                if !out.status.success() {
+                    return Err(anyhow!("clipboard paste command failed: {:?}", out.status));
+                }
+                Ok(Some(paste_reply(&selection, &out.stdout)))
+            }
+        }
+    }
+}
+
+/// Whether an OSC 52 selection parameter only names the primary
+/// selection. Anything else goes to the clipboard.
+fn is_primary(selection: &[u8]) -> bool {
+    !selection.is_empty() && selection.iter().all(|b| *b == b'p')
+}
+
+/// The sequence a terminal answers an OSC 52 paste request with.
+fn paste_reply(selection: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut reply = b"\x1b]52;".to_vec();
+    reply.extend_from_slice(if selection.is_empty() { b"c" } else { selection });
+    reply.push(b';');
+    reply.extend_from_slice(STANDARD.encode(data).as_bytes());
+    reply.push(BEL);
+    reply
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(chunks: &[&[u8]]) -> (String, Vec<Request>) {
+        let mut filter = Osc52Filter::default();
+        let mut out = vec![];
+        let mut reqs = vec![];
+        for chunk in chunks.iter() {
+            reqs.extend(filter.filter(chunk, &mut out));
+        }
+        (String::from_utf8_lossy(&out).into_owned(), reqs)
+    }
+
+    fn copy(selection: &str, data: &str) -> Request {
+        Request::Copy { selection: selection.as_bytes().to_vec(), data: data.as_bytes().to_vec() }
+    }
+
+    #[test]
+    fn takes_out_copies() {
+        // "hi" is aGk=
+        assert_eq!(run(&[b"a\x1b]52;c;aGk=\x07b"]), (String::from("ab"), vec![copy("c", "hi")]));
+        assert_eq!(run(&[b"\x1b]52;;aGk=\x1b\\"]), (String::new(), vec![copy("", "hi")]));
+        assert_eq!(
+            run(&[b"x\x1b]5", b"2;p;aG", b"k=\x1b", b"\\y"]),
+            (String::from("xy"), vec![copy("p", "hi")])
+        );
+    }
+
+    #[test]
+    fn takes_out_pastes() {
+        assert_eq!(
+            run(&[b"\x1b]52;c;?\x07"]),
+            (String::new(), vec![Request::Paste { selection: b"c".to_vec() }])
+        );
+    }
+
+    #[test]
+    fn leaves_everything_else() {
+        for out in [
+            "plain text\r\n",
+            "\x1b[1;31mred\x1b[0m",
+            "\x1b]0;title\x07",
+            "\x1b]7;file://host/tmp\x1b\\",
+            "\x1b]5;x\x07\x1b]522;x\x07",
+            "\x1b\x1b[A",
+        ] {
+            assert_eq!(run(&[out.as_bytes()]), (String::from(out), vec![]), "{:?}", out);
+        }
+        // an OSC 52 cut short by another escape sequence
+        assert_eq!(
+            run(&[b"\x1b]52;c;aGk=\x1b[0m"]),
+            (String::from("\x1b]52;c;aGk=\x1b[0m"), vec![])
+        );
+        // bad base64 is dropped
+        assert_eq!(run(&[b"\x1b]52;c;!!!\x07ok"]), (String::from("ok"), vec![]));
+    }
+
+    #[test]
+    fn replies() {
+        assert_eq!(paste_reply(b"", b"hi"), b"\x1b]52;c;aGk=\x07");
+        assert_eq!(paste_reply(b"p", b""), b"\x1b]52;p;\x07");
+        assert!(is_primary(b"p"));
+        assert!(!is_primary(b"c"));
+        assert!(!is_primary(b""));
+    }
+
+    #[test]
+    fn pastes_need_opting_in() {
+        let bridge = Bridge { backend: Backend::Wayland, paste: false };
+        let req = Request::Paste { selection: b"c".to_vec() };
+        assert_eq!(bridge.handle(req).unwrap(), None);
+    }
+}
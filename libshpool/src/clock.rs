@@ -0,0 +1,132 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The clock module lets code that deals in timeouts get the time from
+//! a source that tests can control, so that they can check timeout
+//! behavior without actually having to sleep.
+
+use std::{fmt, time::Instant};
+#[cfg(any(test, feature = "testing"))]
+use std::{sync::Mutex, time::Duration};
+
+/// A source of time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// A channel that delivers a single message once the clock reaches
+    /// the given time, for use in `crossbeam_channel::select!`.
+    fn at(&self, when: Instant) -> crossbeam_channel::Receiver<Instant>;
+}
+
+/// The real clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn at(&self, when: Instant) -> crossbeam_channel::Receiver<Instant> {
+        crossbeam_channel::at(when)
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct MockClock {
+    inner: Mutex<MockClockInner>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+struct MockClockInner {
+    now: Instant,
+    waiters: Vec<(Instant, crossbeam_channel::Sender<Instant>)>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock { inner: Mutex::new(MockClockInner { now: Instant::now(), waiters: vec![] }) }
+    }
+
+    /// Move the clock forward, waking up anyone waiting for a time that
+    /// has now passed.
+    pub fn advance(&self, by: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.now += by;
+        let now = inner.now;
+        inner.waiters.retain(|(when, tx)| {
+            if *when <= now {
+                // the waiter may have given up already, which is fine
+                let _ = tx.try_send(now);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    fn at(&self, when: Instant) -> crossbeam_channel::Receiver<Instant> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let mut inner = self.inner.lock().unwrap();
+        if when <= inner.now {
+            let _ = tx.try_send(inner.now);
+        } else {
+            inner.waiters.push((when, tx));
+        }
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mock_clock_fires_on_advance() {
+        let clock = MockClock::new();
+        let start = clock.now();
+
+        let past = clock.at(start);
+        assert_eq!(past.try_recv(), Ok(start));
+
+        let later = clock.at(start + Duration::from_secs(10));
+        assert!(later.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(5));
+        assert!(later.try_recv().is_err());
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(later.try_recv(), Ok(start + Duration::from_secs(10)));
+    }
+}
@@ -14,7 +14,7 @@
 
 //! The common module is a grab bag of shared utility functions.
 
-use std::env;
+use std::{any::Any, env};
 
 use anyhow::anyhow;
 
@@ -32,3 +32,14 @@ pub fn resolve_sessions(sessions: &mut Vec<String>, action: &str) -> anyhow::Res
 
     Ok(())
 }
+
+/// Pull the message out of a caught panic payload.
+pub fn panic_msg(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        String::from(*s)
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("<non-string panic payload>")
+    }
+}
@@ -12,19 +12,32 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+/*!
+  The config module holds shpool's configuration, as read from
+  config.toml and documented in CONFIG.md. Programs that embed shpool
+  can skip the file entirely by putting a `Config` together with
+  `Config::builder` and handing it to `run_with_config`.
+*/
+
 use std::{
     borrow::Cow,
     collections::HashMap,
-    env, fs,
+    env, fmt, fs,
+    net::IpAddr,
     path::{Path, PathBuf},
     sync::{Arc, RwLock, RwLockReadGuard},
 };
 
-use anyhow::{Context as _, Result};
+use anyhow::{anyhow, Context as _, Result};
 use serde_derive::Deserialize;
+use shpool_protocol::RestartPolicy;
 use tracing::{info, warn};
 
-use crate::{config_watcher::ConfigWatcher, daemon::keybindings, test_hooks, user};
+use crate::{
+    config_watcher::ConfigWatcher,
+    daemon::{cmd_policy, command, keybindings, output_filter, scheduling, threads},
+    duration, session_name, test_hooks, user,
+};
 
 /// Exposes the shpool config file, watching for file updates
 /// so that the user does not need to restart the daemon when
@@ -37,7 +50,9 @@ use crate::{config_watcher::ConfigWatcher, daemon::keybindings, test_hooks, user
 pub struct Manager {
     /// The config value.
     config: Arc<RwLock<Config>>,
-    _watcher: Arc<ConfigWatcher>,
+    /// Watches the config files for changes. Not present for a config
+    /// that was handed to us directly, since there is no file to watch.
+    _watcher: Option<Arc<ConfigWatcher>>,
 }
 
 impl Manager {
@@ -82,10 +97,16 @@ impl Manager {
                 let mut config = config.write().unwrap();
                 match Self::load(&config_files) {
                     Ok(c) => {
-                        info!("new config: {:?}", c);
+                        let changes = config.diff(&c);
+                        if changes.is_empty() {
+                            info!("config reloaded with no changes");
+                        }
+                        for change in changes.iter() {
+                            info!("config changed: {}", change);
+                        }
                         *config = c;
                     }
-                    Err(err) => warn!("error loading config file: {:?}", err),
+                    Err(err) => warn!("rejecting new config, keeping the old one: {:?}", err),
                 }
                 test_hooks::emit("daemon-reload-config");
             })
@@ -94,11 +115,20 @@ impl Manager {
         for path in config_files {
             watcher.watch(path).context("registering config file for watching")?;
         }
-        let manager = Manager { config, _watcher: Arc::new(watcher) };
+        let manager = Manager { config, _watcher: Some(Arc::new(watcher)) };
 
         Ok(manager)
     }
 
+    /// Create a config manager that always serves the given config,
+    /// rather than loading one from disk. Since there is no file to
+    /// watch, the config never changes.
+    pub fn from_config(config: Config) -> Result<Self> {
+        config.validate().context("validating config")?;
+        info!("starting with config: {:?}", config);
+        Ok(Manager { config: Arc::new(RwLock::new(config)), _watcher: None })
+    }
+
     /// Get the current config value.
     pub fn get(&self) -> RwLockReadGuard<'_, Config> {
         self.config.read().unwrap()
@@ -135,6 +165,7 @@ impl Manager {
             };
             config = new_config.merge(config);
         }
+        config.validate().context("validating config")?;
         Ok(config)
     }
 
@@ -176,7 +207,7 @@ impl std::fmt::Debug for Manager {
     }
 }
 
-#[derive(Deserialize, Default, Debug, Clone)]
+#[derive(Deserialize, Default, Debug, Clone, PartialEq)]
 pub struct Config {
     /// norc makes it so that new shells do not load rc files
     /// when they spawn. Only works with bash.
@@ -220,6 +251,23 @@ pub struct Config {
     /// shell overrides the user's default shell
     pub shell: Option<String>,
 
+    /// Launch new shells inside of a container or a set of linux
+    /// namespaces rather than directly on the host. This takes the
+    /// form `kind:target`, where kind is one of `docker`, `podman`,
+    /// `netns` or `mntns`. For example `docker:devbox` wraps the
+    /// shell in `docker exec -it devbox`, while `netns:/run/netns/vpn`
+    /// wraps it in `nsenter --net=/run/netns/vpn`. The `--container`
+    /// flag to `shpool attach` overrides this on a per-session basis.
+    pub container: Option<String>,
+
+    /// How the daemon starts the processes for new sessions, either
+    /// "posix_spawn" (the default) or "fork". posix_spawn avoids running
+    /// any code in a forked copy of the multithreaded daemon, but it can't
+    /// apply the scheduling, umask, supplementary group or pam settings,
+    /// so sessions that need those get forked no matter what this is set
+    /// to. posix_spawn is only used on linux with glibc.
+    pub spawn_method: Option<SpawnMethod>,
+
     /// a table of environment variables to inject into the
     /// initial shell
     pub env: Option<HashMap<String, String>>,
@@ -254,9 +302,184 @@ pub struct Config {
     /// the vt100 engine has been replaced.
     pub vt100_output_spool_width: Option<u16>,
 
+    /// The maximum rate, in bytes per second, at which a session may
+    /// produce output. Short bursts of up to a second's worth of output
+    /// are let through, but if a process keeps printing faster than this,
+    /// shpool stops reading its output for a bit, which eventually blocks
+    /// the process until the client catches up. By default, there is
+    /// no limit.
+    pub output_rate_limit: Option<u64>,
+
+    /// The stack size, in bytes, for the threads that the daemon spawns
+    /// to service each session and connection. The default is the
+    /// standard library's, which is 2 MiB. With a lot of sessions,
+    /// shrinking this can cut down on the daemon's memory footprint.
+    pub session_thread_stack_size: Option<usize>,
+
+    /// If true, `shpool list --long` estimates how much network traffic
+    /// the processes in each session are responsible for. This means
+    /// walking every process's open fds, so it is off by default.
+    pub network_accounting: Option<bool>,
+
+    /// How long before a session's ttl expires to warn the attached
+    /// client that the session is about to be killed. This is a
+    /// duration in the same format as `shpool attach --ttl`. Defaults
+    /// to 5 minutes. Set to "0s" to disable the warning.
+    pub ttl_warning: Option<String>,
+
+    /// Detach a client that has not sent any input for this long, in
+    /// the same format as `shpool attach --ttl`, so that a forgotten
+    /// terminal doesn't keep a session busy forever. Unset by default.
+    pub client_idle_detach: Option<String>,
+
+    /// How long the daemon waits on a client that has stopped reading
+    /// output, such as one on a laptop that went to sleep, before
+    /// detaching it so that the session stays healthy. This is a
+    /// duration in the same format as `shpool attach --ttl`. Defaults
+    /// to 30 seconds. Set to "0s" to wait forever.
+    pub client_write_timeout: Option<String>,
+
+    /// How long client commands like `shpool list` and `shpool attach`
+    /// wait on the daemon to accept a connection or answer a request
+    /// before giving up with a timeout error, in the same format as
+    /// `shpool attach --ttl`. The `--timeout` flag overrides it. Unset
+    /// by default, which means waiting forever.
+    pub client_timeout: Option<String>,
+
+    /// How long the daemon gives a new connection to send its request
+    /// before hanging up on it, so that a client which connects and
+    /// then stalls doesn't tie up a thread. In the same format as
+    /// `shpool attach --ttl`. Defaults to 2 seconds.
+    pub handshake_timeout: Option<String>,
+
+    /// How many connections can be waiting on their request at once.
+    /// The daemon closes new connections right away while it is at the
+    /// limit, so that a buggy script hammering the socket can't exhaust
+    /// it. Defaults to 64.
+    pub max_pending_connections: Option<usize>,
+
+    /// Named sets of session settings that can be applied to a new
+    /// session with `shpool attach --template <name>`.
+    pub templates: Option<HashMap<String, SessionTemplate>>,
+
+    /// Scheduling settings to apply to every shell the daemon spawns,
+    /// so that pooled sessions running in the background can be kept
+    /// from starving interactive work.
+    pub scheduling: Option<Scheduling>,
+
+    /// Scheduling settings for sessions whose names match a pattern.
+    /// Settings from the first matching entry take priority over the
+    /// top level `scheduling` table.
+    pub session_scheduling: Option<Vec<SessionScheduling>>,
+
+    /// The umask to give spawned shells, for example `umask = 0o022`.
+    /// By default, shells inherit the daemon's umask.
+    pub umask: Option<u32>,
+
+    /// Changes to make to the supplementary groups that spawned shells
+    /// inherit from the daemon. Changing groups requires privileges.
+    pub supplementary_groups: Option<SupplementaryGroups>,
+
+    /// The PAM service to open a session with around each spawned
+    /// shell, for example "login" or a dedicated "shpool" service.
+    /// Requires shpool to be built with the `pam` feature.
+    pub pam_service: Option<String>,
+
+    /// Record attached sessions in utmp and wtmp so that they show
+    /// up in `who` and `last`. Requires permission to write those
+    /// files. Defaults to false.
+    pub utmp: Option<bool>,
+
+    /// Sessions that the daemon should create as soon as it starts up,
+    /// without waiting for anyone to attach to them.
+    pub autostart_sessions: Option<Vec<AutostartSession>>,
+
+    /// A file to append a json line to for every request made on the
+    /// control socket, recording who made it and how it turned out.
+    /// Unset by default, which disables the audit log.
+    pub audit_log: Option<String>,
+
+    /// Where to put the control socket when the runtime directory is on
+    /// a filesystem that can't hold unix sockets, like some NFS mounts.
+    /// Must be a directory that only the current user can access.
+    /// Defaults to /tmp/shpool-$UID.
+    pub socket_fallback_dir: Option<String>,
+
+    /// Limits on the history of session lifecycle events kept for
+    /// `shpool last`.
+    pub lastlog: Option<LastLog>,
+
+    /// Overrides for the messages shpool shows users, for example to
+    /// point them at a help channel when an attach gets refused, or to
+    /// translate them.
+    pub messages: Option<Messages>,
+
+    /// Restrictions on the commands that clients may ask the daemon
+    /// to run with `shpool attach --cmd` or `shpool import`.
+    pub cmd_policy: Option<CmdPolicy>,
+
+    /// Rules for scrubbing things like secrets out of session output
+    /// before it reaches clients or the output spool.
+    pub filters: Option<OutputFilters>,
+
+    /// Helper processes that the daemon launches when it starts, and
+    /// which get told about session events and consulted on requests,
+    /// so that shpool can be extended without writing any rust.
+    pub plugins: Option<Vec<PluginConfig>>,
+
+    /// If true, the daemon offers a service on the user's D-Bus session
+    /// bus so that desktop widgets can list and manage sessions.
+    /// Requires shpool to be built with the `dbus` feature.
+    pub dbus: Option<bool>,
+
+    /// If true, the daemon publishes session events as json lines on
+    /// events.socket in its runtime dir, for tools that want to follow
+    /// along. Only checked when the daemon starts.
+    pub event_socket: Option<bool>,
+
+    /// What `shpool serve-ssh <key>` gives access to for each key name,
+    /// for use as the forced command of ssh keys in authorized_keys.
+    pub ssh_keys: Option<HashMap<String, SshKey>>,
+
     /// The user supplied keybindings.
     pub keybinding: Option<Vec<Keybinding>>,
 
+    /// A keybinding that `shpool attach` watches for itself and which
+    /// detaches the client without involving the daemon, so it works
+    /// even when the daemon is unresponsive. Uses the same syntax as
+    /// the `keybinding` table. Defaults to "Ctrl-Space Ctrl-q". Set
+    /// to the empty string to disable it.
+    pub client_detach_keybinding: Option<String>,
+
+    /// A keybinding that `shpool attach` watches for itself and which
+    /// asks the daemon to redraw the screen, for when the display gets
+    /// garbled. Uses the same syntax as the `keybinding` table. Defaults
+    /// to "Ctrl-Space Ctrl-l". Set to the empty string to disable it.
+    pub client_redraw_keybinding: Option<String>,
+
+    /// A command for `shpool attach` to run through `sh -c` before it
+    /// connects to the daemon, with $SHPOOL_SESSION_NAME set to the
+    /// session being attached to. If it exits non-zero, the attach is
+    /// called off.
+    pub on_attach_local: Option<String>,
+
+    /// A command for `shpool attach` to run through `sh -c` once the
+    /// attach is over, however it ended, with $SHPOOL_SESSION_NAME set
+    /// the same way as for `on_attach_local`.
+    pub on_detach_local: Option<String>,
+
+    /// If true, `shpool attach` handles OSC 52 clipboard sequences from
+    /// the session itself, copying to and pasting from the local
+    /// clipboard with wl-copy and wl-paste or xclip, rather than leaving
+    /// them to the terminal. Defaults to false.
+    pub clipboard_bridge: Option<bool>,
+
+    /// If true, the clipboard bridge also answers OSC 52 paste requests
+    /// with what is on the local clipboard. Off by default, since any
+    /// program running in the session could read the clipboard this
+    /// way. Does nothing unless clipboard_bridge is on.
+    pub clipboard_bridge_paste: Option<bool>,
+
     /// A prefix to inject into the prompt of freshly spawned shells.
     /// The prefix will get included in the shell's prompt variable
     /// verbatim except that the string '$SHPOOL_SESSION_NAME' will
@@ -268,6 +491,56 @@ pub struct Config {
     /// environment variable.
     pub prompt_prefix: Option<String>,
 
+    /// By default, the daemon lets clients with an incompatible protocol
+    /// version attach, and the client just prints a warning. If this is
+    /// set, the daemon refuses attaches from such clients instead, which
+    /// can be useful for managed installs where version skew might
+    /// otherwise cause subtle breakage.
+    pub strict_version_check: Option<bool>,
+
+    /// When reattaching to a session, have the shell cd to the directory
+    /// that `shpool attach` was run from. To avoid typing into whatever
+    /// program is running in the session, this is done with a hook that
+    /// runs right before the shell next draws its prompt, so it only
+    /// works for bash, zsh and fish, and not for sessions running a
+    /// custom command.
+    pub follow_client_cwd: Option<bool>,
+
+    /// Give each session its own shell history file by setting
+    /// HISTFILE to a file in the session's runtime directory, so
+    /// that history from different sessions does not get mixed
+    /// together in the user's global history file. The history for
+    /// a session can be printed with `shpool history`.
+    pub per_session_history: Option<bool>,
+
+    /// Record the output of every session to a file in the session's
+    /// runtime directory, so that it can be played back later with
+    /// `shpool replay`. Off by default.
+    pub recording: Option<Recording>,
+
+    /// Don't query the client terminal for its capabilities when
+    /// attaching. `shpool attach` normally asks the terminal what it
+    /// supports and passes that on to the daemon, which waits on a
+    /// reply from the terminal for a moment.
+    pub noprobe_terminal: Option<bool>,
+
+    /// Print a short summary of the session when `shpool attach` exits,
+    /// saying how long it was attached, how much data went back and
+    /// forth, and how to reattach. Defaults to false.
+    pub exit_banner: Option<bool>,
+
+    /// Which signal characters typed at the client terminal get handled
+    /// by `shpool attach` itself rather than passed on to the session.
+    /// By default they all go to the session.
+    pub tty_signals: Option<TtySignals>,
+
+    /// The IP address the daemon binds a udp socket to when a client
+    /// asks for the experimental udp transport with `shpool attach
+    /// --udp`. Each attach gets its own socket on a free port of this
+    /// address, and the address is handed to the client as is, so it
+    /// must be one the client can reach. Defaults to 127.0.0.1.
+    pub udp_bind_addr: Option<String>,
+
     /// Control when and how shpool will display the message of the day.
     pub motd: Option<MotdDisplayMode>,
 
@@ -285,6 +558,368 @@ pub struct Config {
 }
 
 impl Config {
+    /// Start building up a config in code, for programs that embed
+    /// shpool and don't want to go through a toml file.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Check the settings that can't be fully checked just by parsing
+    /// the toml, so that a bad value gets caught when the config is
+    /// loaded rather than when a session trips over it.
+    pub fn validate(&self) -> Result<()> {
+        let check_duration = |what: &str, src: &Option<String>| -> Result<()> {
+            if let Some(src) = src {
+                duration::parse(src).with_context(|| format!("parsing {} '{}'", what, src))?;
+            }
+            Ok(())
+        };
+        let check_container = |what: &str, src: &Option<String>| -> Result<()> {
+            if let Some(src) = src {
+                src.parse::<command::ContainerTarget>()
+                    .with_context(|| format!("parsing {} '{}'", what, src))?;
+            }
+            Ok(())
+        };
+
+        check_container("container", &self.container)?;
+        check_duration("ttl_warning", &self.ttl_warning)?;
+        check_duration("client_idle_detach", &self.client_idle_detach)?;
+        check_duration("client_write_timeout", &self.client_write_timeout)?;
+        check_duration("client_timeout", &self.client_timeout)?;
+        check_duration("handshake_timeout", &self.handshake_timeout)?;
+        if let Some(lastlog) = &self.lastlog {
+            check_duration("lastlog max_age", &lastlog.max_age)?;
+        }
+        if let Some(MotdDisplayMode::Pager { show_every, .. }) = &self.motd {
+            check_duration("motd show_every", show_every)?;
+        }
+        let check_recording = |what: &str, recording: &Recording| -> Result<()> {
+            check_duration(&format!("{} fsync_interval", what), &recording.fsync_interval)?;
+            if cfg!(not(feature = "s3")) && recording.upload.is_some() {
+                return Err(anyhow!(
+                    "{} upload is set, but shpool was built without the s3 feature",
+                    what
+                ));
+            }
+            Ok(())
+        };
+        if let Some(recording) = &self.recording {
+            check_recording("recording", recording)?;
+        }
+        if let Some(templates) = &self.templates {
+            for (name, template) in templates.iter() {
+                check_duration(&format!("ttl for template {}", name), &template.ttl)?;
+                check_container(&format!("container for template {}", name), &template.container)?;
+                if let Some(recording) = &template.recording {
+                    let what = format!("recording for template {}", name);
+                    check_recording(&what, recording)?;
+                    if recording.key_file.is_some() {
+                        return Err(anyhow!(
+                            "{} sets key_file, which only goes in the top level recording table",
+                            what
+                        ));
+                    }
+                }
+            }
+        }
+        if let Some(sched) = &self.scheduling {
+            scheduling::validate(sched).context("parsing scheduling")?;
+        }
+        if let Some(session_scheds) = &self.session_scheduling {
+            for sched in session_scheds.iter() {
+                scheduling::validate_session(sched)
+                    .with_context(|| format!("parsing session_scheduling '{}'", sched.pattern))?;
+            }
+        }
+        if let Some(size) = self.session_thread_stack_size {
+            if size < threads::MIN_STACK_SIZE {
+                return Err(anyhow!(
+                    "session_thread_stack_size {} is below the minimum of {}",
+                    size,
+                    threads::MIN_STACK_SIZE
+                ));
+            }
+        }
+        if self.max_pending_connections == Some(0) {
+            return Err(anyhow!("max_pending_connections must be at least 1"));
+        }
+        if let Some(umask) = self.umask {
+            if umask > 0o777 {
+                return Err(anyhow!("umask {:#o} is out of range", umask));
+            }
+        }
+        if cfg!(not(feature = "pam")) && self.pam_service.is_some() {
+            return Err(anyhow!(
+                "pam_service is set, but shpool was built without the pam feature"
+            ));
+        }
+        if let Some(addr) = &self.udp_bind_addr {
+            addr.parse::<IpAddr>().with_context(|| format!("parsing udp_bind_addr '{}'", addr))?;
+        }
+        if cfg!(not(feature = "dbus")) && self.dbus.unwrap_or(false) {
+            return Err(anyhow!("dbus is set, but shpool was built without the dbus feature"));
+        }
+        if let Some(autostart) = &self.autostart_sessions {
+            for session in autostart.iter() {
+                session_name::validate(&session.name).map_err(|reason| {
+                    anyhow!("autostart session '{}': {}", session.name, reason)
+                })?;
+            }
+        }
+        if let Some(keys) = &self.ssh_keys {
+            for (key, ssh_key) in keys.iter() {
+                session_name::validate(&ssh_key.session).map_err(|reason| {
+                    anyhow!("session '{}' for ssh key '{}': {}", ssh_key.session, key, reason)
+                })?;
+            }
+        }
+        if let Some(policy) = &self.cmd_policy {
+            cmd_policy::compile(policy).context("parsing cmd_policy")?;
+        }
+        if let Some(filters) = &self.filters {
+            output_filter::compile(filters).context("parsing filters")?;
+        }
+        if let Some(plugins) = &self.plugins {
+            let mut names = std::collections::HashSet::new();
+            for plugin in plugins.iter() {
+                if plugin.name.is_empty() {
+                    return Err(anyhow!("plugins need a name"));
+                }
+                if !names.insert(&plugin.name) {
+                    return Err(anyhow!("more than one plugin named '{}'", plugin.name));
+                }
+                let parts = shell_words::split(&plugin.cmd)
+                    .with_context(|| format!("parsing cmd for plugin '{}'", plugin.name))?;
+                if parts.is_empty() {
+                    return Err(anyhow!("plugin '{}' has an empty cmd", plugin.name));
+                }
+            }
+        }
+        if let Some(bindings) = &self.keybinding {
+            keybindings::Bindings::new(bindings.iter().map(|b| (b.binding.as_str(), b.action)))
+                .context("parsing keybindings")?;
+        }
+        if let Some(binding) = &self.client_detach_keybinding {
+            if !binding.is_empty() {
+                keybindings::Bindings::new([(binding.as_str(), keybindings::Action::Detach)])
+                    .context("parsing client_detach_keybinding")?;
+            }
+        }
+        if let Some(binding) = &self.client_redraw_keybinding {
+            if !binding.is_empty() {
+                keybindings::Bindings::new([(binding.as_str(), keybindings::Action::Redraw)])
+                    .context("parsing client_redraw_keybinding")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Describe each top level setting that differs between `self` and
+    /// `other`, for logging what changed when the config gets reloaded.
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        fn field<T: PartialEq + fmt::Debug>(
+            changes: &mut Vec<String>,
+            name: &str,
+            old: &T,
+            new: &T,
+        ) {
+            if old != new {
+                changes.push(format!("{}: {:?} -> {:?}", name, old, new));
+            }
+        }
+
+        let mut changes = vec![];
+
+        // Destructure rather than using field access so that adding a new
+        // option without updating this is a compile error.
+        let Config {
+            norc,
+            noecho,
+            nosymlink_ssh_auth_sock,
+            noread_etc_environment,
+            nodaemonize,
+            nodaemonize_timeout,
+            shell,
+            container,
+            spawn_method,
+            env,
+            forward_env,
+            initial_path,
+            session_restore_mode,
+            output_spool_lines,
+            vt100_output_spool_width,
+            output_rate_limit,
+            session_thread_stack_size,
+            network_accounting,
+            ttl_warning,
+            client_idle_detach,
+            client_write_timeout,
+            client_timeout,
+            handshake_timeout,
+            max_pending_connections,
+            templates,
+            autostart_sessions,
+            audit_log,
+            socket_fallback_dir,
+            lastlog,
+            messages,
+            scheduling,
+            session_scheduling,
+            umask,
+            supplementary_groups,
+            pam_service,
+            utmp,
+            cmd_policy,
+            filters,
+            plugins,
+            dbus,
+            event_socket,
+            ssh_keys,
+            keybinding,
+            client_detach_keybinding,
+            client_redraw_keybinding,
+            on_attach_local,
+            on_detach_local,
+            clipboard_bridge,
+            clipboard_bridge_paste,
+            prompt_prefix,
+            strict_version_check,
+            follow_client_cwd,
+            per_session_history,
+            recording,
+            noprobe_terminal,
+            exit_banner,
+            tty_signals,
+            udp_bind_addr,
+            motd,
+            motd_args,
+        } = self;
+        field(&mut changes, "norc", norc, &other.norc);
+        field(&mut changes, "noecho", noecho, &other.noecho);
+        field(
+            &mut changes,
+            "nosymlink_ssh_auth_sock",
+            nosymlink_ssh_auth_sock,
+            &other.nosymlink_ssh_auth_sock,
+        );
+        field(
+            &mut changes,
+            "noread_etc_environment",
+            noread_etc_environment,
+            &other.noread_etc_environment,
+        );
+        field(&mut changes, "nodaemonize", nodaemonize, &other.nodaemonize);
+        field(&mut changes, "nodaemonize_timeout", nodaemonize_timeout, &other.nodaemonize_timeout);
+        field(&mut changes, "shell", shell, &other.shell);
+        field(&mut changes, "container", container, &other.container);
+        field(&mut changes, "spawn_method", spawn_method, &other.spawn_method);
+        field(&mut changes, "env", env, &other.env);
+        field(&mut changes, "forward_env", forward_env, &other.forward_env);
+        field(&mut changes, "initial_path", initial_path, &other.initial_path);
+        field(
+            &mut changes,
+            "session_restore_mode",
+            session_restore_mode,
+            &other.session_restore_mode,
+        );
+        field(&mut changes, "output_spool_lines", output_spool_lines, &other.output_spool_lines);
+        field(
+            &mut changes,
+            "vt100_output_spool_width",
+            vt100_output_spool_width,
+            &other.vt100_output_spool_width,
+        );
+        field(&mut changes, "output_rate_limit", output_rate_limit, &other.output_rate_limit);
+        field(
+            &mut changes,
+            "session_thread_stack_size",
+            session_thread_stack_size,
+            &other.session_thread_stack_size,
+        );
+        field(&mut changes, "network_accounting", network_accounting, &other.network_accounting);
+        field(&mut changes, "ttl_warning", ttl_warning, &other.ttl_warning);
+        field(&mut changes, "client_idle_detach", client_idle_detach, &other.client_idle_detach);
+        field(
+            &mut changes,
+            "client_write_timeout",
+            client_write_timeout,
+            &other.client_write_timeout,
+        );
+        field(&mut changes, "client_timeout", client_timeout, &other.client_timeout);
+        field(&mut changes, "handshake_timeout", handshake_timeout, &other.handshake_timeout);
+        field(
+            &mut changes,
+            "max_pending_connections",
+            max_pending_connections,
+            &other.max_pending_connections,
+        );
+        field(&mut changes, "templates", templates, &other.templates);
+        field(&mut changes, "autostart_sessions", autostart_sessions, &other.autostart_sessions);
+        field(&mut changes, "audit_log", audit_log, &other.audit_log);
+        field(&mut changes, "socket_fallback_dir", socket_fallback_dir, &other.socket_fallback_dir);
+        field(&mut changes, "lastlog", lastlog, &other.lastlog);
+        field(&mut changes, "messages", messages, &other.messages);
+        field(&mut changes, "scheduling", scheduling, &other.scheduling);
+        field(&mut changes, "session_scheduling", session_scheduling, &other.session_scheduling);
+        field(&mut changes, "umask", umask, &other.umask);
+        field(
+            &mut changes,
+            "supplementary_groups",
+            supplementary_groups,
+            &other.supplementary_groups,
+        );
+        field(&mut changes, "pam_service", pam_service, &other.pam_service);
+        field(&mut changes, "utmp", utmp, &other.utmp);
+        field(&mut changes, "cmd_policy", cmd_policy, &other.cmd_policy);
+        field(&mut changes, "filters", filters, &other.filters);
+        field(&mut changes, "plugins", plugins, &other.plugins);
+        field(&mut changes, "dbus", dbus, &other.dbus);
+        field(&mut changes, "event_socket", event_socket, &other.event_socket);
+        field(&mut changes, "ssh_keys", ssh_keys, &other.ssh_keys);
+        field(&mut changes, "keybinding", keybinding, &other.keybinding);
+        field(
+            &mut changes,
+            "client_detach_keybinding",
+            client_detach_keybinding,
+            &other.client_detach_keybinding,
+        );
+        field(
+            &mut changes,
+            "client_redraw_keybinding",
+            client_redraw_keybinding,
+            &other.client_redraw_keybinding,
+        );
+        field(&mut changes, "on_attach_local", on_attach_local, &other.on_attach_local);
+        field(&mut changes, "on_detach_local", on_detach_local, &other.on_detach_local);
+        field(&mut changes, "clipboard_bridge", clipboard_bridge, &other.clipboard_bridge);
+        field(
+            &mut changes,
+            "clipboard_bridge_paste",
+            clipboard_bridge_paste,
+            &other.clipboard_bridge_paste,
+        );
+        field(&mut changes, "prompt_prefix", prompt_prefix, &other.prompt_prefix);
+        field(
+            &mut changes,
+            "strict_version_check",
+            strict_version_check,
+            &other.strict_version_check,
+        );
+        field(&mut changes, "follow_client_cwd", follow_client_cwd, &other.follow_client_cwd);
+        field(&mut changes, "per_session_history", per_session_history, &other.per_session_history);
+        field(&mut changes, "recording", recording, &other.recording);
+        field(&mut changes, "noprobe_terminal", noprobe_terminal, &other.noprobe_terminal);
+        field(&mut changes, "exit_banner", exit_banner, &other.exit_banner);
+        field(&mut changes, "tty_signals", tty_signals, &other.tty_signals);
+        field(&mut changes, "udp_bind_addr", udp_bind_addr, &other.udp_bind_addr);
+        field(&mut changes, "motd", motd, &other.motd);
+        field(&mut changes, "motd_args", motd_args, &other.motd_args);
+
+        changes
+    }
+
     /// Merge with `another` Config instance, with `self` taking higher
     /// priority, i.e. it is not commutative.
     ///
@@ -302,6 +937,8 @@ impl Config {
             nodaemonize: self.nodaemonize.or(another.nodaemonize),
             nodaemonize_timeout: self.nodaemonize_timeout.or(another.nodaemonize_timeout),
             shell: self.shell.or(another.shell),
+            container: self.container.or(another.container),
+            spawn_method: self.spawn_method.or(another.spawn_method),
             env: self.env.or(another.env),
             forward_env: self.forward_env.or(another.forward_env),
             initial_path: self.initial_path.or(another.initial_path),
@@ -310,15 +947,420 @@ impl Config {
             vt100_output_spool_width: self
                 .vt100_output_spool_width
                 .or(another.vt100_output_spool_width),
+            output_rate_limit: self.output_rate_limit.or(another.output_rate_limit),
+            session_thread_stack_size: self
+                .session_thread_stack_size
+                .or(another.session_thread_stack_size),
+            network_accounting: self.network_accounting.or(another.network_accounting),
+            ttl_warning: self.ttl_warning.or(another.ttl_warning),
+            client_idle_detach: self.client_idle_detach.or(another.client_idle_detach),
+            client_write_timeout: self.client_write_timeout.or(another.client_write_timeout),
+            client_timeout: self.client_timeout.or(another.client_timeout),
+            handshake_timeout: self.handshake_timeout.or(another.handshake_timeout),
+            max_pending_connections: self
+                .max_pending_connections
+                .or(another.max_pending_connections),
+            templates: self.templates.or(another.templates),
+            autostart_sessions: self.autostart_sessions.or(another.autostart_sessions),
+            audit_log: self.audit_log.or(another.audit_log),
+            socket_fallback_dir: self.socket_fallback_dir.or(another.socket_fallback_dir),
+            lastlog: self.lastlog.or(another.lastlog),
+            messages: self.messages.or(another.messages),
+            scheduling: self.scheduling.or(another.scheduling),
+            session_scheduling: self.session_scheduling.or(another.session_scheduling),
+            umask: self.umask.or(another.umask),
+            supplementary_groups: self.supplementary_groups.or(another.supplementary_groups),
+            pam_service: self.pam_service.or(another.pam_service),
+            utmp: self.utmp.or(another.utmp),
+            cmd_policy: self.cmd_policy.or(another.cmd_policy),
+            filters: self.filters.or(another.filters),
+            plugins: self.plugins.or(another.plugins),
+            dbus: self.dbus.or(another.dbus),
+            event_socket: self.event_socket.or(another.event_socket),
+            ssh_keys: self.ssh_keys.or(another.ssh_keys),
             keybinding: self.keybinding.or(another.keybinding),
+            client_detach_keybinding: self
+                .client_detach_keybinding
+                .or(another.client_detach_keybinding),
+            client_redraw_keybinding: self
+                .client_redraw_keybinding
+                .or(another.client_redraw_keybinding),
+            on_attach_local: self.on_attach_local.or(another.on_attach_local),
+            on_detach_local: self.on_detach_local.or(another.on_detach_local),
+            clipboard_bridge: self.clipboard_bridge.or(another.clipboard_bridge),
+            clipboard_bridge_paste: self.clipboard_bridge_paste.or(another.clipboard_bridge_paste),
             prompt_prefix: self.prompt_prefix.or(another.prompt_prefix),
+            strict_version_check: self.strict_version_check.or(another.strict_version_check),
+            follow_client_cwd: self.follow_client_cwd.or(another.follow_client_cwd),
+            per_session_history: self.per_session_history.or(another.per_session_history),
+            recording: self.recording.or(another.recording),
+            noprobe_terminal: self.noprobe_terminal.or(another.noprobe_terminal),
+            exit_banner: self.exit_banner.or(another.exit_banner),
+            tty_signals: self.tty_signals.or(another.tty_signals),
+            udp_bind_addr: self.udp_bind_addr.or(another.udp_bind_addr),
             motd: self.motd.or(another.motd),
             motd_args: self.motd_args.or(another.motd_args),
         }
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Builds a `Config` one option at a time. Each method sets the config
+/// option of the same name, and any option that is never set keeps its
+/// default, just like an option left out of the toml file.
+///
+/// ```
+/// let config = libshpool::config::Config::builder()
+///     .shell("/bin/zsh")
+///     .prompt_prefix("")
+///     .output_spool_lines(5000usize)
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.shell.as_deref(), Some("/bin/zsh"));
+/// ```
+#[derive(Default, Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            pub fn $field(mut self, $field: impl Into<$ty>) -> Self {
+                self.config.$field = Some($field.into());
+                self
+            }
+        )*
+    };
+}
+
+impl ConfigBuilder {
+    setters! {
+        norc: bool,
+        noecho: bool,
+        nosymlink_ssh_auth_sock: bool,
+        noread_etc_environment: bool,
+        nodaemonize: bool,
+        nodaemonize_timeout: bool,
+        shell: String,
+        container: String,
+        spawn_method: SpawnMethod,
+        env: HashMap<String, String>,
+        forward_env: Vec<String>,
+        initial_path: String,
+        session_restore_mode: SessionRestoreMode,
+        output_spool_lines: usize,
+        vt100_output_spool_width: u16,
+        output_rate_limit: u64,
+        session_thread_stack_size: usize,
+        network_accounting: bool,
+        ttl_warning: String,
+        client_idle_detach: String,
+        client_write_timeout: String,
+        client_timeout: String,
+        handshake_timeout: String,
+        max_pending_connections: usize,
+        templates: HashMap<String, SessionTemplate>,
+        scheduling: Scheduling,
+        session_scheduling: Vec<SessionScheduling>,
+        umask: u32,
+        supplementary_groups: SupplementaryGroups,
+        pam_service: String,
+        utmp: bool,
+        autostart_sessions: Vec<AutostartSession>,
+        audit_log: String,
+        socket_fallback_dir: String,
+        lastlog: LastLog,
+        messages: Messages,
+        cmd_policy: CmdPolicy,
+        filters: OutputFilters,
+        plugins: Vec<PluginConfig>,
+        dbus: bool,
+        event_socket: bool,
+        ssh_keys: HashMap<String, SshKey>,
+        keybinding: Vec<Keybinding>,
+        client_detach_keybinding: String,
+        client_redraw_keybinding: String,
+        on_attach_local: String,
+        on_detach_local: String,
+        clipboard_bridge: bool,
+        clipboard_bridge_paste: bool,
+        prompt_prefix: String,
+        strict_version_check: bool,
+        follow_client_cwd: bool,
+        per_session_history: bool,
+        recording: Recording,
+        noprobe_terminal: bool,
+        exit_banner: bool,
+        tty_signals: TtySignals,
+        udp_bind_addr: String,
+        motd: MotdDisplayMode,
+        motd_args: Vec<String>,
+    }
+
+    /// Check the config over the same way as one loaded from a file,
+    /// and hand it back.
+    pub fn build(self) -> Result<Config> {
+        self.config.validate().context("validating config")?;
+        Ok(self.config)
+    }
+}
+
+/// Settings that get applied to a session created with a template.
+/// Each of these takes priority over the corresponding top level
+/// config option, but explicit flags passed to `shpool attach` take
+/// priority over the template.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionTemplate {
+    /// The shell to use for the session.
+    pub shell: Option<String>,
+    /// A command to run instead of the shell, like `shpool attach --cmd`.
+    pub cmd: Option<String>,
+    /// Extra environment variables to inject, merged on top of the
+    /// top level `env` table.
+    pub env: Option<HashMap<String, String>>,
+    /// A ttl for the session, like `shpool attach --ttl`.
+    pub ttl: Option<String>,
+    /// A container target, like `shpool attach --container`.
+    pub container: Option<String>,
+    /// The session restore mode to use for the session.
+    pub session_restore_mode: Option<SessionRestoreMode>,
+    /// Recording settings for the session, each of which overrides
+    /// the same setting in the top level `recording` table.
+    pub recording: Option<Recording>,
+    /// What to do when the session's shell exits, like
+    /// `shpool attach --restart`.
+    pub restart: Option<RestartPolicy>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Scheduling {
+    /// The niceness to run the shell at, from -20 to 19. Going below
+    /// the daemon's own niceness requires privileges.
+    pub nice: Option<i32>,
+    /// The io scheduling class and priority, as with ionice(1). One of
+    /// "idle", "best-effort" or "realtime", optionally followed by a
+    /// colon and a priority from 0 (highest) to 7 (lowest).
+    pub ionice: Option<String>,
+    /// The value to write to the shell's oom_score_adj, from -1000 to
+    /// 1000. Higher values make the kernel more willing to kill the
+    /// session when memory runs out.
+    pub oom_score_adj: Option<i32>,
+    /// The cpus the session is allowed to run on, as with taskset(1).
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SupplementaryGroups {
+    /// Groups to add, by name or numeric id.
+    pub add: Option<Vec<String>>,
+    /// Groups to drop, by name or numeric id.
+    pub drop: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SessionScheduling {
+    /// A regular expression which must match the whole session name.
+    pub pattern: String,
+    #[serde(flatten)]
+    pub scheduling: Scheduling,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct LastLog {
+    /// The number of events to keep. Defaults to 1000. Setting this
+    /// to 0 turns off recording events.
+    pub max_entries: Option<usize>,
+    /// Events older than this get dropped, in the same format as
+    /// `shpool attach --ttl`. Unset by default.
+    pub max_age: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    /// Turns on recording. Defaults to false.
+    pub enabled: Option<bool>,
+    /// How often to sync recordings to disk, in the same format as
+    /// `shpool attach --ttl`. Output recorded since the last sync can
+    /// get lost if the machine goes down, but not if just the daemon
+    /// does. Defaults to 1s.
+    pub fsync_interval: Option<String>,
+    /// Start each line of recorded output with the time it was printed
+    /// at. The timestamps only go in the recording, not to the
+    /// terminal. Defaults to false.
+    pub timestamps: Option<bool>,
+    /// Also copy recordings to an s3 compatible bucket as they get
+    /// synced. Requires the s3 feature. Unset by default.
+    pub upload: Option<RecordingUpload>,
+    /// Encrypt recorded output with the key in this file, which must
+    /// hold exactly 32 bytes. Only allowed in the top level recording
+    /// table, since `shpool replay` uses it to decrypt every recording.
+    /// Unset by default.
+    pub key_file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RecordingUpload {
+    /// The base url of the bucket's s3 api, like
+    /// `https://s3.us-west-2.amazonaws.com`, or
+    /// `https://storage.googleapis.com` for gcs.
+    pub endpoint: String,
+    pub bucket: String,
+    /// The region to sign requests for. Defaults to us-east-1. Gcs
+    /// takes `auto`.
+    pub region: Option<String>,
+    /// Put in front of every object key, which is otherwise
+    /// `<session>/<unix ms of the first output>-<offset>`. Unset by
+    /// default.
+    pub prefix: Option<String>,
+}
+
+impl Recording {
+    /// Fill in whatever this leaves unset from `another`.
+    pub fn merge(self, another: Recording) -> Recording {
+        Recording {
+            enabled: self.enabled.or(another.enabled),
+            fsync_interval: self.fsync_interval.or(another.fsync_interval),
+            timestamps: self.timestamps.or(another.timestamps),
+            upload: self.upload.or(another.upload),
+            key_file: self.key_file.or(another.key_file),
+        }
+    }
+}
+
+/// Templates for user facing messages. `$SHPOOL_SESSION_NAME` gets
+/// replaced with the name of the session in all of them, and some
+/// messages have more variables, listed on each field. See
+/// `messages.rs` for the defaults.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Messages {
+    /// Shown when attaching to a session that already has a terminal.
+    pub busy: Option<String>,
+    /// Shown when `shpool attach -f` can't get the other terminal to
+    /// let go of the session.
+    pub force_attach_failed: Option<String>,
+    /// Shown when the daemon refuses an attach. `$SHPOOL_REASON` is
+    /// why, for example the cmd_policy rule that matched.
+    pub forbidden: Option<String>,
+    /// Shown when the session name is not allowed. `$SHPOOL_REASON`
+    /// says what is wrong with it.
+    pub invalid_name: Option<String>,
+    /// Shown in a session shortly before its ttl runs out.
+    /// `$SHPOOL_TTL_REMAINING` is the time left, like `5m`.
+    pub ttl_warning: Option<String>,
+}
+
+/// What to do with each of the signal characters typed at the client
+/// terminal while attached.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TtySignals {
+    /// The suspend character, usually Ctrl-Z. Handling it locally
+    /// suspends `shpool attach` instead of the foreground job in the
+    /// session.
+    pub suspend: Option<TtySignalHandling>,
+    /// The quit character, usually Ctrl-\. Handling it locally makes
+    /// `shpool attach` exit, leaving the session running.
+    pub quit: Option<TtySignalHandling>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TtySignalHandling {
+    /// Pass the character on to the session, whose pty turns it into a
+    /// signal for its foreground process group.
+    #[default]
+    Forward,
+    /// Raise the signal in `shpool attach` itself.
+    Local,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AutostartSession {
+    /// The name of the session to create.
+    pub name: String,
+    /// A command to run instead of the user's default shell. Parsed
+    /// the same way as the `--cmd` flag to `shpool attach`.
+    pub cmd: Option<String>,
+    /// Whether the daemon should launch the session again when it
+    /// exits, unless it was explicitly killed with `shpool kill`. For
+    /// backwards compatibility, `true` means "always" and `false` means
+    /// "never".
+    #[serde(default, deserialize_with = "deserialize_restart")]
+    pub restart: RestartPolicy,
+}
+
+fn deserialize_restart<'de, D>(deserializer: D) -> std::result::Result<RestartPolicy, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Restart {
+        Flag(bool),
+        Policy(RestartPolicy),
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Restart::Flag(true) => RestartPolicy::Always,
+        Restart::Flag(false) => RestartPolicy::Never,
+        Restart::Policy(policy) => policy,
+    })
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CmdPolicy {
+    /// Regular expressions, one of which a command must match in full
+    /// for the daemon to run it. If unset, any command which is not
+    /// denied is allowed.
+    pub allow: Option<Vec<String>>,
+    /// Regular expressions for commands the daemon should refuse
+    /// to run, even if they are also allowed.
+    pub deny: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct OutputFilters {
+    /// The rules to apply, in order of priority.
+    pub rules: Option<Vec<OutputFilterRule>>,
+    /// The longest match the rules are guaranteed to catch when it
+    /// gets split between two reads of the session's output. Defaults
+    /// to 256 bytes.
+    pub max_match_len: Option<usize>,
+}
+
+/// The session that an ssh key configured with `shpool serve-ssh`
+/// gets, and what it may do with it.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct SshKey {
+    /// The session to attach to, created if it does not exist yet
+    /// unless the key is read only.
+    pub session: String,
+    /// Only show the session's output, ignoring anything typed.
+    /// Defaults to false.
+    pub read_only: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginConfig {
+    /// A name for the plugin, for logging.
+    pub name: String,
+    /// The command to launch the plugin with, split up like a shell
+    /// would, but without going through one.
+    pub cmd: String,
+    /// How long to wait for the plugin to answer a request before
+    /// giving up on it, in milliseconds. Defaults to 500.
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputFilterRule {
+    /// A regular expression for the output to replace.
+    pub pattern: String,
+    /// The text to put in place of anything the pattern matches, taken
+    /// literally. Defaults to "[redacted]".
+    pub replacement: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Keybinding {
     /// The keybinding to map to an action. The syntax for these keybindings
     /// is described in src/daemon/keybindings.rs.
@@ -327,7 +1369,19 @@ pub struct Keybinding {
     pub action: keybindings::Action,
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpawnMethod {
+    /// Start shells with posix_spawn, leaving it to libc to fork and
+    /// exec in one go.
+    #[default]
+    PosixSpawn,
+    /// Fork the daemon and set the shell up in the child before
+    /// exec'ing it.
+    Fork,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum SessionRestoreMode {
     /// Just reattach to the pty and issue SIGWINCH to force apps like
@@ -346,7 +1400,7 @@ pub enum SessionRestoreMode {
     Lines(u16),
 }
 
-#[derive(Deserialize, Debug, Clone, Default)]
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum MotdDisplayMode {
     /// Never display the message of the day.
@@ -389,6 +1443,29 @@ mod test {
     use super::*;
     use ntest::timeout;
 
+    #[test]
+    fn builder() -> Result<()> {
+        let config = Config::builder()
+            .shell("/bin/zsh")
+            .env(HashMap::from([(String::from("EDITOR"), String::from("vim"))]))
+            .ttl_warning("10m")
+            .build()?;
+        let from_toml: Config = toml::from_str(
+            r#"
+            shell = "/bin/zsh"
+            env = { EDITOR = "vim" }
+            ttl_warning = "10m"
+            "#,
+        )?;
+        assert_eq!(config, from_toml);
+
+        assert!(Config::builder().ttl_warning("not a duration").build().is_err());
+        assert!(Config::builder().client_write_timeout("forever").build().is_err());
+        assert!(Config::builder().client_timeout("soon").build().is_err());
+        assert!(Config::builder().max_pending_connections(0usize).build().is_err());
+        Ok(())
+    }
+
     #[test]
     #[timeout(30000)]
     fn parse() -> Result<()> {
@@ -403,10 +1480,38 @@ mod test {
             session_restore_mode = "screen"
             "#,
             r#"
+            spawn_method = "posix_spawn"
+            "#,
+            r#"
+            spawn_method = "fork"
+            "#,
+            r#"
             [[keybinding]]
             binding = "Ctrl-q a"
             action = "detach"
             "#,
+            r#"
+            [[autostart_sessions]]
+            name = "repl"
+            cmd = "python3"
+            restart = true
+
+            [[autostart_sessions]]
+            name = "scratch"
+
+            [[autostart_sessions]]
+            name = "db"
+            cmd = "psql"
+            restart = "on-failure"
+            "#,
+            r#"
+            [templates.work]
+            shell = "/bin/zsh"
+            ttl = "8h"
+            session_restore_mode = { lines = 100 }
+            env = { EDITOR = "vim" }
+            restart = "always"
+            "#,
         ];
 
         for case in cases.into_iter() {
@@ -416,6 +1521,154 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    #[timeout(30000)]
+    fn autostart_restart() -> Result<()> {
+        let cases = vec![
+            ("", RestartPolicy::Never),
+            ("restart = false", RestartPolicy::Never),
+            ("restart = true", RestartPolicy::Always),
+            (r#"restart = "on-failure""#, RestartPolicy::OnFailure),
+            (r#"restart = "always""#, RestartPolicy::Always),
+        ];
+        for (restart, want) in cases.into_iter() {
+            let config: Config =
+                toml::from_str(&format!("[[autostart_sessions]]\nname = \"x\"\n{}", restart))?;
+            let autostart = config.autostart_sessions.unwrap_or_default();
+            assert_eq!(autostart[0].restart, want, "restart={}", restart);
+        }
+
+        let res: std::result::Result<Config, _> =
+            toml::from_str("[[autostart_sessions]]\nname = \"x\"\nrestart = \"sometimes\"");
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    #[cfg(not(feature = "pam"))]
+    fn pam_service_needs_feature() -> Result<()> {
+        let config: Config = toml::from_str(r#"pam_service = "login""#)?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    #[cfg(not(feature = "dbus"))]
+    fn dbus_needs_feature() -> Result<()> {
+        let config: Config = toml::from_str("dbus = true")?;
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("dbus = false")?;
+        config.validate()?;
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn validate() -> Result<()> {
+        let valid = vec![
+            r#"
+            container = "docker:devbox"
+            ttl_warning = "10m"
+            "#,
+            r#"
+            [templates.work]
+            ttl = "1h"
+            "#,
+            r#"
+            session_thread_stack_size = 262144
+            "#,
+            r#"
+            [ssh_keys.laptop]
+            session = "main"
+            read_only = true
+            "#,
+            r#"
+            udp_bind_addr = "::1"
+            "#,
+        ];
+        for case in valid.into_iter() {
+            let config: Config = toml::from_str(case)?;
+            config.validate()?;
+        }
+
+        let invalid = vec![
+            r#"
+            container = "lxc:devbox"
+            "#,
+            r#"
+            ttl_warning = "soon"
+            "#,
+            r#"
+            [templates.work]
+            ttl = "forever"
+            "#,
+            r#"
+            session_thread_stack_size = 4096
+            "#,
+            r#"
+            [ssh_keys.laptop]
+            session = "has space"
+            "#,
+            r#"
+            [[plugins]]
+            name = "notify"
+            cmd = "notify-plugin"
+            [[plugins]]
+            name = "notify"
+            cmd = "other-plugin"
+            "#,
+            r#"
+            [[keybinding]]
+            binding = "Ctrl-q Ctrl-q Ctrl-"
+            action = "detach"
+            "#,
+            r#"
+            [templates.work.recording]
+            key_file = "/etc/shpool/recording.key"
+            "#,
+            r#"
+            udp_bind_addr = "localhost:1234"
+            "#,
+        ];
+        for case in invalid.into_iter() {
+            let config: Config = toml::from_str(case)?;
+            assert!(config.validate().is_err(), "expected invalid: {}", case);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[timeout(30000)]
+    fn diff() -> Result<()> {
+        let old: Config = toml::from_str(
+            r#"
+            shell = "/bin/bash"
+            env = { A = "1", B = "2" }
+            "#,
+        )?;
+        let new: Config = toml::from_str(
+            r#"
+            shell = "/bin/zsh"
+            env = { B = "2", A = "1" }
+            noecho = true
+            "#,
+        )?;
+
+        assert!(old.diff(&old).is_empty());
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                String::from("noecho: None -> Some(true)"),
+                String::from(r#"shell: Some("/bin/bash") -> Some("/bin/zsh")"#),
+            ]
+        );
+
+        Ok(())
+    }
+
     mod merge {
         use super::*;
         use assert_matches::assert_matches;
@@ -19,9 +19,23 @@ pub const JOIN_POLL_DURATION: time::Duration = time::Duration::from_millis(100);
 
 pub const BUF_SIZE: usize = 1024 * 16;
 
+// The most data that can go in a single chunk. Anything bigger gets split
+// across several chunks, and a length prefix over this is treated as a
+// protocol violation rather than trusted to size a read.
+pub const MAX_CHUNK_SIZE: usize = BUF_SIZE;
+
+// The most bytes a single header or reply can take up on the wire, so that
+// a bogus length inside of one can't make us allocate gigabytes.
+pub const MAX_MESSAGE_SIZE: u64 = 1024 * 1024 * 16;
+
 pub const HEARTBEAT_DURATION: time::Duration = time::Duration::from_millis(500);
 
+// The exit status that attached clients get when the daemon shuts down
+// out from under them. Matches what a shell reports for death by SIGTERM.
+pub const DAEMON_SHUTDOWN_EXIT_STATUS: i32 = 128 + 15;
+
 pub const STDIN_FD: i32 = 0;
+pub const STDOUT_FD: i32 = 1;
 pub const STDERR_FD: i32 = 2;
 
 // Used to determine when the shell has started up so we can attempt to sniff
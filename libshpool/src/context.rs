@@ -0,0 +1,247 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp, io,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    thread, time,
+};
+
+use anyhow::Context;
+use shpool_protocol::ConnectHeader;
+use tracing::info;
+
+use crate::{config, duration, output, protocol, protocol::ClientResult, Error};
+
+/// How long to wait before the first retry of a connection that the
+/// daemon refused. The wait doubles with each retry, up to
+/// MAX_RETRY_BACKOFF.
+const RETRY_BACKOFF: time::Duration = time::Duration::from_millis(50);
+const MAX_RETRY_BACKOFF: time::Duration = time::Duration::from_secs(1);
+
+/// What the client subcommands need in order to talk to the daemon.
+/// It gets built once in `run`, so that every subcommand finds the
+/// daemon, and deals with one that is down or speaks a different
+/// version of the protocol, the same way.
+#[derive(Clone)]
+pub struct ClientContext {
+    pub config: config::Manager,
+    pub socket: PathBuf,
+    /// How long to wait on the daemon, see `timeout`.
+    pub timeout: Option<time::Duration>,
+    /// The warning about the daemon speaking a different version of
+    /// the protocol, once a connection has turned one up. Clones share
+    /// it, so a command that makes lots of connections only brings the
+    /// mismatch up once.
+    version_mismatch: Arc<OnceLock<String>>,
+}
+
+impl ClientContext {
+    pub fn new(config: config::Manager, socket: PathBuf, timeout: Option<time::Duration>) -> Self {
+        ClientContext { config, socket, timeout, version_mismatch: Arc::new(OnceLock::new()) }
+    }
+
+    /// Connect to the daemon, leaving a protocol version mismatch up to
+    /// the caller. Most callers want `connect` instead.
+    ///
+    /// With a timeout, connections that the daemon refuses, say because
+    /// it is in the middle of restarting, get retried until the timeout
+    /// is up.
+    pub fn dial(&self) -> anyhow::Result<ClientResult> {
+        let deadline = self.timeout.map(|t| time::Instant::now() + t);
+        let mut backoff = RETRY_BACKOFF;
+        loop {
+            match protocol::Client::new(&self.socket, self.timeout) {
+                Err(err)
+                    if refused(&err)
+                        && deadline
+                            .map(|d| time::Instant::now() + backoff < d)
+                            .unwrap_or(false) =>
+                {
+                    info!("daemon refused connection, retrying in {:?}", backoff);
+                    thread::sleep(backoff);
+                    backoff = cmp::min(backoff * 2, MAX_RETRY_BACKOFF);
+                }
+                res => return res.map_err(unreachable),
+            }
+        }
+    }
+
+    /// Connect to the daemon, warning about a version mismatch the
+    /// first time one comes up.
+    pub fn connect(&self) -> anyhow::Result<protocol::Client> {
+        match self.dial()? {
+            ClientResult::JustClient(client) => Ok(client),
+            ClientResult::VersionMismatch { warning, client } => {
+                if self.note_version_mismatch(warning) {
+                    if let Some(msg) = self.version_warning() {
+                        output::warning(msg);
+                    }
+                }
+                Ok(client)
+            }
+        }
+    }
+
+    /// What to tell the user about a version mismatch, if a connection
+    /// has turned one up. Commands that redraw the screen use this to
+    /// keep the warning in view.
+    pub fn version_warning(&self) -> Option<String> {
+        self.version_mismatch
+            .get()
+            .map(|warning| format!("warning: {}, try restarting your daemon", warning))
+    }
+
+    /// Remember a version mismatch. Returns true if it is the first
+    /// one, meaning the user has not heard about it yet.
+    pub fn note_version_mismatch(&self, warning: String) -> bool {
+        self.version_mismatch.set(warning).is_ok()
+    }
+
+    /// Make a one off request to the daemon and read its reply.
+    pub fn request<R>(&self, header: ConnectHeader) -> anyhow::Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        self.connect()?.request(header)
+    }
+}
+
+/// How long client commands should wait on the daemon, from the
+/// --timeout flag if it was given, or else the client_timeout config.
+/// A timeout of zero means waiting forever, same as no timeout at all.
+pub fn timeout(
+    flag: Option<&str>,
+    config: &config::Config,
+) -> anyhow::Result<Option<time::Duration>> {
+    let Some(src) = flag.or(config.client_timeout.as_deref()) else {
+        return Ok(None);
+    };
+    let timeout = duration::parse(src).context("parsing client timeout")?;
+    Ok(if timeout.is_zero() { None } else { Some(timeout) })
+}
+
+fn refused(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .map(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+        .unwrap_or(false)
+}
+
+/// Turn a failure to connect into a DaemonUnreachable error, telling the
+/// user if it is because there is no daemon listening.
+fn unreachable(err: anyhow::Error) -> anyhow::Error {
+    match err.downcast::<io::Error>() {
+        Ok(io_err) => {
+            if io_err.kind() == io::ErrorKind::NotFound {
+                output::error("could not connect to daemon");
+            }
+            Error::DaemonUnreachable(io_err).into()
+        }
+        Err(err) => err,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn no_daemon() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let ctx = ClientContext::new(
+            config::Manager::from_config(config::Config::default())?,
+            tmp_dir.path().join("shpool.socket"),
+            None,
+        );
+
+        let err = ctx.connect().err().expect("connecting with no daemon");
+        assert!(matches!(err.downcast::<Error>()?, Error::DaemonUnreachable(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn mismatch_noted_once() -> anyhow::Result<()> {
+        let ctx = ClientContext::new(
+            config::Manager::from_config(config::Config::default())?,
+            PathBuf::from("shpool.socket"),
+            None,
+        );
+        let clone = ctx.clone();
+        assert_eq!(ctx.version_warning(), None);
+
+        assert!(ctx.note_version_mismatch(String::from("too old")));
+        assert!(!clone.note_version_mismatch(String::from("too old")));
+        assert_eq!(
+            clone.version_warning().as_deref(),
+            Some("warning: too old, try restarting your daemon")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn timeouts() -> anyhow::Result<()> {
+        let config =
+            config::Config { client_timeout: Some(String::from("5s")), ..Default::default() };
+
+        assert_eq!(timeout(None, &config::Config::default())?, None);
+        assert_eq!(timeout(None, &config)?, Some(time::Duration::from_secs(5)));
+        assert_eq!(timeout(Some("500ms"), &config)?, Some(time::Duration::from_millis(500)));
+        assert_eq!(timeout(Some("0s"), &config)?, None);
+        assert!(timeout(Some("soon"), &config).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn wedged_daemon() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let socket = tmp_dir.path().join("shpool.socket");
+        // listening without ever accepting or writing the version header
+        let _listener = std::os::unix::net::UnixListener::bind(&socket)?;
+        let ctx = ClientContext::new(
+            config::Manager::from_config(config::Config::default())?,
+            socket,
+            Some(time::Duration::from_millis(100)),
+        );
+
+        let err = ctx.connect().err().expect("connecting to a wedged daemon");
+        assert!(matches!(err.downcast::<Error>()?, Error::Timeout(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn refused_gets_retried() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let socket = tmp_dir.path().join("shpool.socket");
+        // a socket file with nobody listening on it, like the one a
+        // crashed daemon leaves behind
+        drop(std::os::unix::net::UnixListener::bind(&socket)?);
+        let ctx = ClientContext::new(
+            config::Manager::from_config(config::Config::default())?,
+            socket,
+            Some(time::Duration::from_millis(300)),
+        );
+
+        let start = time::Instant::now();
+        let err = ctx.connect().err().expect("connecting with nobody listening");
+        assert!(start.elapsed() >= time::Duration::from_millis(100));
+        assert!(matches!(err.downcast::<Error>()?, Error::DaemonUnreachable(_)));
+
+        Ok(())
+    }
+}
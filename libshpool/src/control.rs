@@ -0,0 +1,384 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Control mode for `shpool attach --control`, which is to shpool what
+  `tmux -CC` is to tmux. Rather than hooking the session straight up to
+  a terminal, the attach process speaks a line based protocol over its
+  stdin and stdout, so that terminal emulators can drive sessions with
+  their own native tabs and windows.
+
+  Every line shpool writes starts with a `%` tag:
+
+  - `%attached <name> <created|existing>` comes first, once the attach
+    goes through.
+  - `%warning <text>` passes on warnings from the daemon.
+  - `%output <base64>` carries a chunk of output from the session.
+  - `%notice <base64>` carries a message from the daemon meant for the
+    user, like a TTL warning.
+  - `%exit <status>` says that the shell exited.
+  - `%detached` says that the session got detached without exiting.
+  - `%list <name> <attached|disconnected> <started_at_unix_ms>`
+    describes one session in reply to `list`.
+  - `%ok` and `%error <text>` end the reply to a command.
+
+  The front-end sends one command per line:
+
+  - `send <base64>` sends input to the session.
+  - `resize <rows> <cols>` resizes the session.
+  - `list` lists all of the daemon's sessions.
+  - `detach` detaches from the session, as does closing stdin.
+
+  Each command gets exactly one `%ok` or `%error` back, in order, though
+  output from the session can show up at any point.
+*/
+
+use std::{
+    io,
+    io::{BufRead, Write},
+    net,
+    os::unix::net::UnixStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{anyhow, Context};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use byteorder::{LittleEndian, ReadBytesExt};
+use shpool_protocol::{ChunkKind, ConnectHeader, ListReply, TtySize};
+use tracing::{error, info, instrument, warn};
+
+use crate::{attach, consts, context::ClientContext, protocol};
+
+/// A command from the front-end.
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Send(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    List,
+    Detach,
+}
+
+fn parse_command(line: &str) -> anyhow::Result<Command> {
+    let mut words = line.split_whitespace();
+    let cmd = words.next().ok_or(anyhow!("empty command"))?;
+    let parsed = match cmd {
+        "send" => {
+            let data = words.next().ok_or(anyhow!("send needs some base64 data"))?;
+            Command::Send(STANDARD.decode(data).context("decoding base64")?)
+        }
+        "resize" => {
+            let mut size = || -> anyhow::Result<u16> {
+                words
+                    .next()
+                    .ok_or(anyhow!("resize needs rows and cols"))?
+                    .parse()
+                    .context("parsing size")
+            };
+            let rows = size()?;
+            let cols = size()?;
+            Command::Resize { rows, cols }
+        }
+        "list" => Command::List,
+        "detach" => Command::Detach,
+        _ => return Err(anyhow!("unknown command '{}'", cmd)),
+    };
+    if words.next().is_some() {
+        return Err(anyhow!("too many arguments to {}", cmd));
+    }
+    Ok(parsed)
+}
+
+/// Flatten free form text onto a single line so it can't break up the
+/// protocol.
+fn one_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+/// The writing half of the protocol. Output from the session and replies
+/// to commands come from different threads, so every line gets written
+/// in one go under a lock.
+#[derive(Clone)]
+pub struct Emitter {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl Emitter {
+    pub fn stdout() -> Self {
+        Emitter { out: Arc::new(Mutex::new(Box::new(io::stdout()))) }
+    }
+
+    fn line(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        // There is nobody left to tell if the front-end has gone away, we
+        // will notice when stdin closes.
+        if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+            warn!("writing control line: {:?}", e);
+        }
+    }
+
+    pub fn attached(&self, name: &str, created: bool) {
+        self.line(&format!("%attached {} {}", name, if created { "created" } else { "existing" }));
+    }
+
+    pub fn warning(&self, warning: &str) {
+        self.line(&format!("%warning {}", one_line(warning)));
+    }
+
+    fn error(&self, err: &anyhow::Error) {
+        self.line(&format!("%error {}", one_line(&format!("{:#}", err))));
+    }
+}
+
+/// Shuffle data between the session and the front-end until the shell
+/// exits or we detach.
+///
+/// Return value: the exit status that `shpool attach` should exit with.
+#[instrument(skip_all, fields(s = session_name))]
+pub fn run(
+    stream: UnixStream,
+    frames: protocol::FrameReader,
+    emitter: Emitter,
+    session_name: String,
+    ctx: ClientContext,
+) -> anyhow::Result<i32> {
+    let write_stream = stream.try_clone().context("cloning session stream")?;
+    let detached_locally = Arc::new(AtomicBool::new(false));
+
+    {
+        let emitter = emitter.clone();
+        let detached_locally = Arc::clone(&detached_locally);
+        // Not scoped, since this will be sitting in a read on stdin when
+        // the session ends, and we don't want to wait around for it.
+        thread::Builder::new()
+            .name(String::from("control-stdin"))
+            .spawn(move || {
+                let stdin = io::stdin().lock();
+                let res = serve_commands(
+                    stdin,
+                    write_stream,
+                    &emitter,
+                    &session_name,
+                    &ctx,
+                    &detached_locally,
+                );
+                if let Err(e) = res {
+                    error!("serving control commands: {:?}", e);
+                }
+            })
+            .context("spawning control command thread")?;
+    }
+
+    forward_output(stream, frames, &emitter, &detached_locally)
+}
+
+fn serve_commands<R: BufRead>(
+    input: R,
+    mut session: UnixStream,
+    emitter: &Emitter,
+    session_name: &str,
+    ctx: &ClientContext,
+    detached_locally: &AtomicBool,
+) -> anyhow::Result<()> {
+    for line in input.lines() {
+        let line = line.context("reading control command")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let cmd = match parse_command(&line) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                emitter.error(&e);
+                continue;
+            }
+        };
+
+        let res = match cmd {
+            Command::Send(data) => session
+                .write_all(&data)
+                .and_then(|_| session.flush())
+                .context("sending input to session"),
+            Command::Resize { rows, cols } => {
+                let tty_size = TtySize { rows, cols, xpixel: 0, ypixel: 0 };
+                attach::resize_session(ctx, session_name, tty_size)
+            }
+            Command::List => list_sessions(ctx, emitter),
+            Command::Detach => break,
+        };
+        match res {
+            Ok(()) => emitter.line("%ok"),
+            Err(e) => emitter.error(&e),
+        }
+    }
+
+    info!("detaching");
+    detached_locally.store(true, Ordering::Release);
+    // Hanging up is all it takes to detach, and it kicks the output
+    // thread out of its read.
+    session.shutdown(net::Shutdown::Both).context("shutting down session stream")?;
+    emitter.line("%ok");
+    Ok(())
+}
+
+fn list_sessions(ctx: &ClientContext, emitter: &Emitter) -> anyhow::Result<()> {
+    // any version mismatch was already reported when we attached
+    let reply: ListReply = ctx.request(ConnectHeader::List).context("listing sessions")?;
+    for session in reply.sessions.iter() {
+        emitter.line(&format!(
+            "%list {} {} {}",
+            session.name, session.status, session.started_at_unix_ms
+        ));
+    }
+    Ok(())
+}
+
+fn forward_output(
+    mut session: UnixStream,
+    mut frames: protocol::FrameReader,
+    emitter: &Emitter,
+    detached_locally: &AtomicBool,
+) -> anyhow::Result<i32> {
+    let mut exit_status = 1;
+    let mut exited = false;
+    let mut buf = vec![0; consts::BUF_SIZE];
+    loop {
+        let chunk = match frames.read(&mut session, &mut buf) {
+            Ok(c) => c,
+            Err(_) if exited => return Ok(exit_status),
+            Err(_) if detached_locally.load(Ordering::Acquire) => {
+                emitter.line("%detached");
+                return Ok(0);
+            }
+            Err(err) => {
+                let eof = err
+                    .downcast_ref::<io::Error>()
+                    .map(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+                    .unwrap_or(false);
+                if eof {
+                    // someone else detached us
+                    emitter.line("%detached");
+                    return Ok(exit_status);
+                }
+                emitter.error(&err);
+                return Err(err).context("reading chunk");
+            }
+        };
+
+        match chunk.kind {
+            ChunkKind::Heartbeat => {}
+            ChunkKind::Data => emitter.line(&format!("%output {}", STANDARD.encode(chunk.buf))),
+            ChunkKind::Notice => emitter.line(&format!("%notice {}", STANDARD.encode(chunk.buf))),
+            ChunkKind::ExitStatus => {
+                let stat = io::Cursor::new(chunk.buf)
+                    .read_i32::<LittleEndian>()
+                    .context("reading exit status from exit status chunk")?;
+                info!("got exit status frame (status={})", stat);
+                exit_status = stat;
+                exited = true;
+                emitter.line(&format!("%exit {}", stat));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::Chunk;
+
+    use super::*;
+    use crate::protocol::ChunkExt as _;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(parse_command("send aGkK").unwrap(), Command::Send(b"hi\n".to_vec()));
+        assert_eq!(
+            parse_command("  resize 40 120 ").unwrap(),
+            Command::Resize { rows: 40, cols: 120 }
+        );
+        assert_eq!(parse_command("list").unwrap(), Command::List);
+        assert_eq!(parse_command("detach").unwrap(), Command::Detach);
+
+        for bad in ["", "send", "send !!!", "resize 40", "resize a b", "list now", "attach x"] {
+            assert!(parse_command(bad).is_err(), "'{}' should not parse", bad);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_lines() {
+        let (mut daemon, client) = UnixStream::pair().unwrap();
+        let capture = Capture::default();
+        let emitter = Emitter { out: Arc::new(Mutex::new(Box::new(capture.clone()))) };
+
+        emitter.attached("main", true);
+        emitter.warning("two\nlines");
+        for (kind, buf) in [
+            (ChunkKind::Data, &b"$ ls\r\n"[..]),
+            (ChunkKind::Heartbeat, &b""[..]),
+            (ChunkKind::Notice, &b"ttl"[..]),
+            (ChunkKind::ExitStatus, &3i32.to_le_bytes()[..]),
+        ] {
+            Chunk { kind, buf }.write_to(&mut daemon).unwrap();
+        }
+        drop(daemon);
+
+        let status = forward_output(
+            client,
+            protocol::FrameReader::new(false),
+            &emitter,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert_eq!(status, 3);
+        assert_eq!(
+            String::from_utf8(capture.0.lock().unwrap().clone()).unwrap(),
+            "%attached main created\n\
+             %warning two lines\n\
+             %output JCBscw0K\n\
+             %notice dHRs\n\
+             %exit 3\n"
+        );
+    }
+
+    #[test]
+    fn detached_remotely() {
+        let (daemon, client) = UnixStream::pair().unwrap();
+        let capture = Capture::default();
+        let emitter = Emitter { out: Arc::new(Mutex::new(Box::new(capture.clone()))) };
+        drop(daemon);
+
+        forward_output(
+            client,
+            protocol::FrameReader::new(false),
+            &emitter,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(capture.0.lock().unwrap().clone()).unwrap(), "%detached\n");
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for dialing and binding the control socket.
+//!
+//! The control socket is normally a unix socket on the filesystem,
+//! but on linux a path starting with '@' is interpreted as a name
+//! in the abstract socket namespace. Abstract sockets have no
+//! filesystem entry, so they work on read-only filesystems and
+//! never leave stale socket files behind.
+//!
+//! Some filesystems, notably some NFS mounts, can't hold unix sockets
+//! at all. When the runtime directory lives on one of those, the
+//! default socket goes in a private fallback directory instead, see
+//! `socket_dir`.
+
+use std::{
+    fs, io,
+    os::{
+        fd::{AsFd as _, AsRawFd as _},
+        unix::{
+            fs::{DirBuilderExt as _, MetadataExt as _},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    process, thread, time,
+};
+
+use anyhow::{anyhow, bail, Context};
+use nix::{errno::Errno, fcntl, poll, sys::socket};
+use tracing::warn;
+
+/// The prefix that marks a socket path as an abstract socket name.
+const ABSTRACT_PREFIX: &str = "@";
+
+/// The name of the default control socket within its directory.
+pub const SOCKET_NAME: &str = "shpool.socket";
+
+/// How often `connect_timeout` tries again while the daemon's listen
+/// backlog is full.
+const CONNECT_RETRY_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+/// If the given socket path names an abstract socket, return
+/// the name with the leading '@' stripped off.
+pub fn abstract_name(sock: &Path) -> Option<&str> {
+    sock.to_str().and_then(|s| s.strip_prefix(ABSTRACT_PREFIX))
+}
+
+/// Returns true if the given socket path refers to an abstract socket
+/// rather than a filesystem entry.
+pub fn is_abstract(sock: &Path) -> bool {
+    abstract_name(sock).is_some()
+}
+
+/// Dial the control socket.
+pub fn connect<P: AsRef<Path>>(sock: P) -> io::Result<UnixStream> {
+    let sock = sock.as_ref();
+    match abstract_name(sock) {
+        Some(name) => connect_abstract(name),
+        None => UnixStream::connect(sock),
+    }
+}
+
+/// Dial the control socket, giving up after `timeout`. Once a daemon
+/// stops accepting connections and its listen backlog fills up, a
+/// blocking connect would hang, so this dials with a non-blocking
+/// socket instead. Linux refuses such a connect with EAGAIN rather
+/// than finishing it in the background, in which case we try again
+/// every CONNECT_RETRY_INTERVAL until the deadline. Elsewhere it can
+/// come back with EINPROGRESS, and we poll for it to finish.
+pub fn connect_timeout<P: AsRef<Path>>(sock: P, timeout: time::Duration) -> io::Result<UnixStream> {
+    let deadline = time::Instant::now() + timeout;
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "connecting to the control socket");
+    let addr = unix_addr(sock.as_ref())?;
+    let fd = socket::socket(
+        socket::AddressFamily::Unix,
+        socket::SockType::Stream,
+        socket::SockFlag::empty(),
+        None,
+    )?;
+    fcntl::fcntl(fd.as_raw_fd(), fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::FD_CLOEXEC))?;
+    let stream = UnixStream::from(fd);
+    stream.set_nonblocking(true)?;
+
+    loop {
+        match socket::connect(stream.as_raw_fd(), &addr) {
+            Ok(()) => break,
+            Err(Errno::EINTR) => continue,
+            Err(Errno::EAGAIN) => {
+                let remaining = deadline.saturating_duration_since(time::Instant::now());
+                if remaining.is_zero() {
+                    return Err(timed_out());
+                }
+                thread::sleep(remaining.min(CONNECT_RETRY_INTERVAL));
+            }
+            Err(Errno::EINPROGRESS) => {
+                loop {
+                    let remaining = deadline.saturating_duration_since(time::Instant::now());
+                    if remaining.is_zero() {
+                        return Err(timed_out());
+                    }
+                    let mut poll_fds =
+                        [poll::PollFd::new(stream.as_fd(), poll::PollFlags::POLLOUT)];
+                    let ms = remaining.as_millis().clamp(1, u16::MAX as u128) as u16;
+                    match poll::poll(&mut poll_fds, ms) {
+                        Ok(0) | Err(Errno::EINTR) => continue,
+                        Ok(_) => break,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                match socket::getsockopt(&stream, socket::sockopt::SocketError)? {
+                    0 => break,
+                    errno => return Err(io::Error::from_raw_os_error(errno)),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// The address to dial for the given socket path.
+fn unix_addr(sock: &Path) -> io::Result<socket::UnixAddr> {
+    match abstract_name(sock) {
+        #[cfg(target_os = "linux")]
+        Some(name) => Ok(socket::UnixAddr::new_abstract(name.as_bytes())?),
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "abstract sockets are only supported on linux",
+        )),
+        None => Ok(socket::UnixAddr::new(sock)?),
+    }
+}
+
+/// Bind the control socket.
+pub fn bind<P: AsRef<Path>>(sock: P) -> io::Result<UnixListener> {
+    let sock = sock.as_ref();
+    match abstract_name(sock) {
+        Some(name) => bind_abstract(name),
+        None => UnixListener::bind(sock),
+    }
+}
+
+/// Pick the directory that the default control socket lives in. This
+/// is normally `runtime_dir`, but if the filesystem there can't hold
+/// unix sockets we use `fallback`, or /tmp/shpool-$UID if that is not
+/// set. The daemon and its clients all run this, so they agree on where
+/// the socket is without having to write that down anywhere.
+pub fn socket_dir(runtime_dir: &Path, fallback: Option<&Path>) -> anyhow::Result<PathBuf> {
+    // a socket that is already there is proof enough, and saves a bind
+    if runtime_dir.join(SOCKET_NAME).exists() {
+        return Ok(runtime_dir.to_path_buf());
+    }
+    match probe(runtime_dir) {
+        Err(e) if is_unsupported_fs(&e) => {
+            let fallback = match fallback {
+                Some(f) => f.to_path_buf(),
+                None => default_fallback_dir(),
+            };
+            warn!(
+                "{:?} can't hold unix sockets ({}), putting the socket in {:?} instead",
+                runtime_dir, e, fallback
+            );
+            ensure_private_dir(&fallback)?;
+            probe(&fallback).map_err(|fallback_err| {
+                anyhow!(
+                    "neither the runtime dir {:?} ({}) nor the fallback dir {:?} ({}) can hold \
+                     unix sockets, set socket_fallback_dir in the config or pass --socket",
+                    runtime_dir,
+                    e,
+                    fallback,
+                    fallback_err
+                )
+            })?;
+            Ok(fallback)
+        }
+        // Anything else is not about the filesystem, so let the real
+        // bind report it.
+        _ => Ok(runtime_dir.to_path_buf()),
+    }
+}
+
+fn default_fallback_dir() -> PathBuf {
+    PathBuf::from(format!("/tmp/shpool-{}", nix::unistd::getuid()))
+}
+
+/// Check that unix sockets can be bound in the given directory by
+/// binding a throwaway one.
+fn probe(dir: &Path) -> io::Result<()> {
+    let probe_path = dir.join(format!(".probe-{}.socket", process::id()));
+    let _ = fs::remove_file(&probe_path);
+    let res = UnixListener::bind(&probe_path);
+    let _ = fs::remove_file(&probe_path);
+    res.map(|_| ())
+}
+
+/// Returns true if an error from binding a unix socket means that the
+/// filesystem doesn't support them, rather than something like the
+/// directory being missing.
+fn is_unsupported_fs(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EPERM) | Some(libc::EOPNOTSUPP))
+}
+
+/// Create the given directory if need be, and make sure that nobody
+/// but us can get at it, since the socket in it hands out shells.
+fn ensure_private_dir(dir: &Path) -> anyhow::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("creating socket dir {:?}", dir)),
+    }
+
+    // symlink_metadata so that someone else can't point us somewhere
+    // with a symlink planted in /tmp
+    let meta = fs::symlink_metadata(dir).with_context(|| format!("checking {:?}", dir))?;
+    if !meta.is_dir() {
+        bail!("socket dir {:?} is not a directory", dir);
+    }
+    if meta.uid() != nix::unistd::getuid().as_raw() {
+        bail!("socket dir {:?} is owned by another user", dir);
+    }
+    if meta.mode() & 0o077 != 0 {
+        bail!("socket dir {:?} is accessible to other users, it should have mode 0700", dir);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> io::Result<UnixStream> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    UnixStream::connect_addr(&addr)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    UnixListener::bind_addr(&addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_name: &str) -> io::Result<UnixStream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> io::Result<UnixListener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "abstract sockets are only supported on linux"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt as _;
+
+    #[test]
+    fn abstract_names() {
+        assert_eq!(abstract_name(&PathBuf::from("@shpool-foo")), Some("shpool-foo"));
+        assert_eq!(abstract_name(&PathBuf::from("/run/shpool/shpool.socket")), None);
+        assert_eq!(abstract_name(&PathBuf::from("./@shpool-foo")), None);
+    }
+
+    #[test]
+    fn socket_dir_prefers_runtime_dir() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        assert_eq!(socket_dir(tmp.path(), None)?, tmp.path());
+        // the probe cleans up after itself
+        assert_eq!(fs::read_dir(tmp.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn private_dir() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+
+        let fresh = tmp.path().join("fresh");
+        ensure_private_dir(&fresh)?;
+        assert_eq!(fs::metadata(&fresh)?.mode() & 0o777, 0o700);
+        // fine to call again once it exists
+        ensure_private_dir(&fresh)?;
+
+        let open = tmp.path().join("open");
+        fs::create_dir(&open)?;
+        fs::set_permissions(&open, fs::Permissions::from_mode(0o755))?;
+        assert!(ensure_private_dir(&open).is_err());
+
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink(&fresh, &link)?;
+        assert!(ensure_private_dir(&link).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn unsupported_fs_errors() {
+        assert!(is_unsupported_fs(&io::Error::from_raw_os_error(libc::EOPNOTSUPP)));
+        assert!(is_unsupported_fs(&io::Error::from_raw_os_error(libc::EPERM)));
+        assert!(!is_unsupported_fs(&io::Error::from_raw_os_error(libc::ENOENT)));
+    }
+
+    #[test]
+    fn connect_gives_up() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let sock = tmp.path().join("shpool.socket");
+        let timeout = time::Duration::from_millis(100);
+        assert!(connect_timeout(&sock, timeout).is_err());
+
+        // nobody accepts, so eventually the backlog fills up and dialing
+        // times out rather than hanging
+        let _listener = UnixListener::bind(&sock)?;
+        let mut clients = vec![];
+        let err = loop {
+            match connect_timeout(&sock, timeout) {
+                Ok(client) => clients.push(client),
+                Err(e) => break e,
+            }
+            assert!(clients.len() < 10_000, "the backlog never filled up");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn abstract_round_trip() -> anyhow::Result<()> {
+        let sock = PathBuf::from(format!("@shpool-test-{}", process::id()));
+        let listener = bind(&sock)?;
+        let _client = connect(&sock)?;
+        let (_server, _) = listener.accept()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Experimental reptyr style adoption of a process that was started
+  outside of shpool, for `shpool adopt-pid`.
+
+  The daemon creates a session whose pty just runs a placeholder that
+  waits for the adopted process to exit, then uses ptrace to make the
+  process itself open the session's pty and dup it over whichever of
+  its stdin, stdout and stderr were pointing at a terminal. From then
+  on, the process reads from and writes to the shpool session.
+
+  Unlike reptyr, this does not move the process into the pty's unix
+  session, so the pty is not its controlling terminal. That means that
+  keys like Ctrl-c, which the pty turns into signals for its foreground
+  process group, and window size changes don't reach the process.
+
+  Tracing another process needs the kernel's blessing, which usually
+  means setting kernel.yama.ptrace_scope to 0 or giving the daemon
+  CAP_SYS_PTRACE. This is only compiled in with the `adopt_pid`
+  feature, and only works on x86_64 linux.
+*/
+
+/// Whether this build of the daemon is able to adopt processes.
+pub const SUPPORTED: bool =
+    cfg!(all(feature = "adopt_pid", target_os = "linux", target_arch = "x86_64"));
+
+/// The command that stands in for the shell in an adopted session.
+/// It keeps the session alive until the adopted process exits.
+pub fn placeholder_cmd(pid: i32) -> String {
+    format!("tail --pid={} -f /dev/null", pid)
+}
+
+#[cfg(all(feature = "adopt_pid", target_os = "linux", target_arch = "x86_64"))]
+pub use imp::adopt;
+
+#[cfg(not(all(feature = "adopt_pid", target_os = "linux", target_arch = "x86_64")))]
+pub fn adopt(_pid: i32, _pty_path: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("this shpool was built without support for adopting processes"))
+}
+
+#[cfg(all(feature = "adopt_pid", target_os = "linux", target_arch = "x86_64"))]
+mod imp {
+    use std::{ffi::CString, fs, io, mem, thread, time};
+
+    use anyhow::{anyhow, Context};
+    use nix::{
+        sys::{
+            ptrace, termios,
+            wait::{waitpid, WaitPidFlag, WaitStatus},
+        },
+        unistd::Pid,
+    };
+    use tracing::{info, warn};
+
+    const STOP_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+    // The instruction we poke into the tracee to make syscalls.
+    const SYSCALL_INSN: [u8; 2] = [0x0f, 0x05];
+
+    // Skip past the area below the stack pointer that leaf functions
+    // are allowed to use without moving it.
+    const RED_ZONE: u64 = 128;
+
+    // The errors that mean the tracee was stopped in the middle of a
+    // syscall that the kernel means to restart.
+    const ERESTARTSYS: i64 = 512;
+    const ERESTARTNOINTR: i64 = 513;
+    const ERESTARTNOHAND: i64 = 514;
+    const ERESTART_RESTARTBLOCK: i64 = 516;
+
+    pub fn adopt(pid: i32, pty_path: &str) -> anyhow::Result<()> {
+        let fds = tty_fds(pid);
+        if fds.is_empty() {
+            return Err(anyhow!("process {} does not have a terminal to move", pid));
+        }
+        copy_termios(pid, fds[0], pty_path);
+
+        let mut tracee = Tracee::attach(pid)?;
+        let res = tracee.move_fds(pty_path, &fds);
+        tracee.release();
+        res?;
+
+        info!("moved fds {:?} of pid {} onto {}", fds, pid, pty_path);
+        Ok(())
+    }
+
+    /// The fds among stdin, stdout and stderr that the given process has
+    /// pointed at a terminal. Those are the ones that get moved over.
+    fn tty_fds(pid: i32) -> Vec<i32> {
+        (0..3)
+            .filter(|fd| {
+                fs::read_link(format!("/proc/{}/fd/{}", pid, fd))
+                    .map(|target| {
+                        let target = target.to_string_lossy();
+                        target.starts_with("/dev/pts/") || target.starts_with("/dev/tty")
+                    })
+                    .unwrap_or(false)
+            })
+            .collect()
+    }
+
+    /// Give the new pty the same settings as the terminal the process is
+    /// leaving, so that a program that put its terminal in raw mode does
+    /// not suddenly find itself in cooked mode.
+    fn copy_termios(pid: i32, from_fd: i32, pty_path: &str) {
+        let res = (|| -> anyhow::Result<()> {
+            let old_tty = fs::File::open(format!("/proc/{}/fd/{}", pid, from_fd))?;
+            let settings = termios::tcgetattr(&old_tty)?;
+            let new_tty = fs::OpenOptions::new().read(true).write(true).open(pty_path)?;
+            termios::tcsetattr(&new_tty, termios::SetArg::TCSANOW, &settings)?;
+            Ok(())
+        })();
+        if let Err(e) = res {
+            warn!("copying terminal settings from pid {}: {:?}", pid, e);
+        }
+    }
+
+    /// A process we are attached to with ptrace, along with everything
+    /// we need to put it back the way we found it.
+    struct Tracee {
+        pid: Pid,
+        regs: libc::user_regs_struct,
+        /// The word at the instruction pointer, which we overwrite with
+        /// a syscall instruction.
+        saved_text: libc::c_long,
+        /// Words of stack that we overwrite, by address.
+        saved_stack: Vec<(u64, libc::c_long)>,
+    }
+
+    impl Tracee {
+        fn attach(pid: i32) -> anyhow::Result<Self> {
+            let pid = Pid::from_raw(pid);
+            ptrace::attach(pid).with_context(|| format!("attaching to pid {}", pid))?;
+            let detach = |e: anyhow::Error| {
+                let _ = ptrace::detach(pid, None);
+                e
+            };
+            wait_for_stop(pid).map_err(detach)?;
+
+            let mut regs = ptrace::getregs(pid).context("getting registers").map_err(detach)?;
+            // If the process was blocked in a syscall, the kernel would
+            // restart it once we let go, but our own syscalls are about
+            // to get in the way of that, so rewind to the syscall
+            // instruction ourselves.
+            match (regs.rax as i64).wrapping_neg() {
+                ERESTARTSYS | ERESTARTNOINTR | ERESTARTNOHAND => {
+                    regs.rax = regs.orig_rax;
+                    regs.rip -= SYSCALL_INSN.len() as u64;
+                }
+                ERESTART_RESTARTBLOCK => {
+                    regs.rax = libc::SYS_restart_syscall as u64;
+                    regs.rip -= SYSCALL_INSN.len() as u64;
+                }
+                _ => {}
+            }
+            // stop the kernel from trying to restart anything itself
+            regs.orig_rax = u64::MAX;
+
+            let saved_text = ptrace::read(pid, regs.rip as ptrace::AddressType)
+                .context("reading text")
+                .map_err(detach)?;
+            let tracee = Tracee { pid, regs, saved_text, saved_stack: vec![] };
+
+            let mut text = saved_text.to_ne_bytes();
+            text[..SYSCALL_INSN.len()].copy_from_slice(&SYSCALL_INSN);
+            if let Err(e) = tracee.poke(regs.rip, libc::c_long::from_ne_bytes(text)) {
+                tracee.release();
+                return Err(e).context("writing syscall instruction");
+            }
+
+            Ok(tracee)
+        }
+
+        /// Make the process open the pty and dup it over the given fds.
+        fn move_fds(&mut self, pty_path: &str, fds: &[i32]) -> anyhow::Result<()> {
+            let path = CString::new(pty_path).context("pty path")?;
+            let path_addr = self.push(path.as_bytes_with_nul())?;
+
+            let fd = self
+                .syscall(
+                    libc::SYS_openat,
+                    [libc::AT_FDCWD as u64, path_addr, (libc::O_RDWR | libc::O_NOCTTY) as u64, 0],
+                )
+                .context("opening the pty in the tracee")?;
+            for target in fds.iter() {
+                self.syscall(libc::SYS_dup2, [fd, *target as u64, 0, 0])
+                    .with_context(|| format!("moving fd {} in the tracee", target))?;
+            }
+            if !fds.contains(&(fd as i32)) {
+                self.syscall(libc::SYS_close, [fd, 0, 0, 0]).context("closing spare pty fd")?;
+            }
+
+            Ok(())
+        }
+
+        /// Copy some bytes onto the tracee's stack, below anything it
+        /// might be using, and return their address.
+        fn push(&mut self, bytes: &[u8]) -> anyhow::Result<u64> {
+            let word = mem::size_of::<libc::c_long>();
+            let len = bytes.len().div_ceil(word) * word;
+            let addr = (self.regs.rsp - RED_ZONE - len as u64) & !0xf;
+            for (i, chunk) in bytes.chunks(word).enumerate() {
+                let word_addr = addr + (i * word) as u64;
+                let old = ptrace::read(self.pid, word_addr as ptrace::AddressType)
+                    .context("reading stack")?;
+                self.saved_stack.push((word_addr, old));
+                let mut new = old.to_ne_bytes();
+                new[..chunk.len()].copy_from_slice(chunk);
+                self.poke(word_addr, libc::c_long::from_ne_bytes(new))?;
+            }
+            Ok(addr)
+        }
+
+        /// Run a syscall in the tracee, returning its result.
+        fn syscall(&self, nr: libc::c_long, args: [u64; 4]) -> anyhow::Result<u64> {
+            // rip already points at our syscall instruction
+            let mut regs = self.regs;
+            regs.rax = nr as u64;
+            regs.rdi = args[0];
+            regs.rsi = args[1];
+            regs.rdx = args[2];
+            regs.r10 = args[3];
+            ptrace::setregs(self.pid, regs).context("setting registers")?;
+            ptrace::step(self.pid, None).context("stepping over syscall")?;
+            wait_for_stop(self.pid)?;
+
+            let ret = ptrace::getregs(self.pid).context("getting registers")?.rax as i64;
+            if (-4095..0).contains(&ret) {
+                return Err(io::Error::from_raw_os_error(-ret as i32).into());
+            }
+            Ok(ret as u64)
+        }
+
+        /// Put back everything we changed and let the process go.
+        fn release(&self) {
+            if let Err(e) = self.poke(self.regs.rip, self.saved_text) {
+                warn!("restoring text of pid {}: {:?}", self.pid, e);
+            }
+            for (addr, word) in self.saved_stack.iter() {
+                if let Err(e) = self.poke(*addr, *word) {
+                    warn!("restoring stack of pid {}: {:?}", self.pid, e);
+                }
+            }
+            if let Err(e) = ptrace::setregs(self.pid, self.regs) {
+                warn!("restoring registers of pid {}: {:?}", self.pid, e);
+            }
+            if let Err(e) = ptrace::detach(self.pid, None) {
+                warn!("detaching from pid {}: {:?}", self.pid, e);
+            }
+        }
+
+        fn poke(&self, addr: u64, word: libc::c_long) -> anyhow::Result<()> {
+            // Safety: the tracee is stopped, and we only ever write over
+            //         words that we have saved to put back later.
+            unsafe {
+                ptrace::write(self.pid, addr as ptrace::AddressType, word as *mut libc::c_void)
+            }
+            .context("poking tracee memory")
+        }
+    }
+
+    fn wait_for_stop(pid: Pid) -> anyhow::Result<()> {
+        let deadline = time::Instant::now() + STOP_TIMEOUT;
+        loop {
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG | WaitPidFlag::__WALL))
+                .context("waiting for tracee")?
+            {
+                WaitStatus::Stopped(_, _) => return Ok(()),
+                WaitStatus::StillAlive => {}
+                status => return Err(anyhow!("tracee did not stop: {:?}", status)),
+            }
+            if time::Instant::now() > deadline {
+                return Err(anyhow!("timed out waiting for pid {} to stop", pid));
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The audit log is an append-only record of the requests clients make
+  on the control socket, written as json lines to the file named by the
+  audit_log config option. Unlike the regular log, it is meant to be
+  kept around and read by people other than shpool developers, so the
+  format should only ever grow new fields.
+
+  Each request gets an entry once the daemon knows how it turned out.
+  Attaches get two, one when the daemon replies to the attach and one
+  when the client disconnects.
+
+  `shpool serve-ssh` also writes entries of its own, since only it
+  knows which ssh key a connection came in with. Those have no conn_id,
+  and carry the key name and the address of the ssh client instead.
+*/
+
+use std::{
+    fs,
+    io::Write,
+    os::unix::{fs::OpenOptionsExt, net::UnixStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time,
+};
+
+use anyhow::Context;
+use serde_derive::Serialize;
+use shpool_protocol::ConnectHeader;
+use tracing::warn;
+
+use super::flight_recorder;
+use crate::{config, platform};
+
+/// The process on the other end of a control socket connection.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Peer {
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+}
+
+impl Peer {
+    pub fn of(sock: &UnixStream) -> Self {
+        match platform::peer_creds(sock) {
+            Ok(creds) => Peer { uid: Some(creds.uid), pid: creds.pid },
+            Err(e) => {
+                warn!("getting peer creds for audit log: {:?}", e);
+                Peer::default()
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Entry<'a> {
+    pub at_unix_ms: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conn_id: Option<usize>,
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    pub action: &'a str,
+    pub sessions: &'a [String],
+    pub outcome: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssh_client: Option<&'a str>,
+}
+
+impl Entry<'_> {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut line = serde_json::to_vec(self).context("serializing entry")?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+pub struct Log {
+    config: config::Manager,
+    /// The currently open audit file along with its path, so that
+    /// we can notice when the config points somewhere new.
+    file: Mutex<Option<(PathBuf, fs::File)>>,
+}
+
+impl Log {
+    pub fn new(config: config::Manager) -> Self {
+        Log { config, file: Mutex::new(None) }
+    }
+
+    /// Append an entry to the audit log, if there is one. Failing to
+    /// write the audit log does not fail the request, but it is logged.
+    pub fn record(
+        &self,
+        conn_id: usize,
+        peer: Peer,
+        action: &str,
+        sessions: &[String],
+        outcome: &str,
+    ) {
+        let entry = Entry {
+            at_unix_ms: flight_recorder::unix_ms(time::SystemTime::now()),
+            conn_id: Some(conn_id),
+            uid: peer.uid,
+            pid: peer.pid,
+            action,
+            sessions,
+            outcome,
+            ssh_key: None,
+            ssh_client: None,
+        };
+        if let Err(e) = self.write(&entry) {
+            warn!("writing audit log entry {:?}: {:?}", entry, e);
+        }
+    }
+
+    fn write(&self, entry: &Entry) -> anyhow::Result<()> {
+        let path = match &self.config.get().audit_log {
+            Some(p) => PathBuf::from(p),
+            None => return Ok(()),
+        };
+
+        let line = entry.encode()?;
+
+        let mut file = self.file.lock().unwrap();
+        if file.as_ref().map(|(p, _)| p != &path).unwrap_or(true) {
+            let f = open(&path)?;
+            *file = Some((path, f));
+        }
+        if let Some((_, f)) = file.as_mut() {
+            // a single write_all to an O_APPEND file keeps lines whole
+            f.write_all(&line).context("appending entry")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Append a single entry to the audit log at `path`, for processes
+/// other than the daemon, which don't keep the file open.
+pub fn append(path: &Path, entry: &Entry) -> anyhow::Result<()> {
+    let line = entry.encode()?;
+    // a single write_all to an O_APPEND file keeps lines whole, even
+    // with the daemon writing to the same file
+    open(path)?.write_all(&line).context("appending entry")
+}
+
+fn open(path: &Path) -> anyhow::Result<fs::File> {
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("opening audit log {:?}", path))
+}
+
+/// The name of the action a connect header asks for and the
+/// sessions it targets, for the audit log.
+pub fn describe(header: &ConnectHeader) -> (&'static str, Vec<String>) {
+    match header {
+        ConnectHeader::Attach(h) => ("attach", vec![h.name.clone()]),
+        ConnectHeader::List => ("list", vec![]),
+        ConnectHeader::SessionMessage(r) => ("session-message", vec![r.session_name.clone()]),
+        ConnectHeader::Detach(r) => ("detach", r.sessions.clone()),
+        ConnectHeader::Kill(r) => ("kill", r.sessions.clone()),
+        ConnectHeader::ExtendTtl(r) => ("extend-ttl", vec![r.session.clone()]),
+        ConnectHeader::Export => ("export", vec![]),
+        ConnectHeader::Import(r) => ("import", r.sessions.iter().map(|s| s.name.clone()).collect()),
+        ConnectHeader::DumpState => ("dump-state", vec![]),
+        ConnectHeader::Stats => ("stats", vec![]),
+        ConnectHeader::AdoptPid(r) => ("adopt-pid", vec![r.name.clone()]),
+        ConnectHeader::Broadcast(r) => ("broadcast", r.sessions.clone()),
+        ConnectHeader::LogLevel(_) => ("log-level", vec![]),
+        ConnectHeader::Profile(_) => ("profile", vec![]),
+        ConnectHeader::Copy(_) => ("copy", vec![]),
+        ConnectHeader::Paste(r) => ("paste", r.session.iter().cloned().collect()),
+        ConnectHeader::Pipe(r) => ("pipe", vec![r.session.clone()]),
+        ConnectHeader::Env(r) => ("env", vec![r.session.clone()]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn appends_json_lines() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let audit_path = tmp_dir.path().join("audit.jsonl");
+        let config_path = tmp_dir.path().join("config.toml");
+        fs::write(&config_path, format!("audit_log = {:?}\n", audit_path))?;
+        let config = config::Manager::new(config_path.to_str())?;
+
+        let log = Log::new(config);
+        let peer = Peer { uid: Some(1000), pid: Some(42) };
+        log.record(1, peer, "kill", &[String::from("a"), String::from("b")], "ok");
+        log.record(2, Peer::default(), "list", &[], "error: oops");
+
+        let lines = fs::read_to_string(&audit_path)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["action"], "kill");
+        assert_eq!(lines[0]["sessions"], serde_json::json!(["a", "b"]));
+        assert_eq!(lines[0]["uid"], 1000);
+        assert_eq!(lines[0]["pid"], 42);
+        assert_eq!(lines[1]["conn_id"], 2);
+        assert_eq!(lines[1]["uid"], serde_json::Value::Null);
+        assert_eq!(lines[1]["outcome"], "error: oops");
+        assert!(lines[1].get("ssh_key").is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cancellation for the threads that pump data for an attached client.
+//!
+//! Checking a stop flag between reads is not enough on its own, since a
+//! thread blocked in read(2) won't get around to checking it until the
+//! next byte shows up, which could be never if the client has gone
+//! quiet. Threads that block on an fd instead wait on both that fd and
+//! a self-pipe, and cancelling writes to the self-pipe to wake them up.
+
+use std::{
+    io,
+    io::Write,
+    os::{fd::AsFd, unix::net::UnixStream},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+use nix::poll;
+use tracing::warn;
+
+pub struct Cancel {
+    cancelled: AtomicBool,
+    wake_tx: UnixStream,
+    wake_rx: UnixStream,
+}
+
+impl Cancel {
+    pub fn new() -> anyhow::Result<Self> {
+        let (wake_tx, wake_rx) = UnixStream::pair().context("creating cancel self-pipe")?;
+        // one byte is enough to wake everyone up, so there is no
+        // point in ever blocking on a full pipe
+        wake_tx.set_nonblocking(true).context("making cancel self-pipe nonblocking")?;
+        Ok(Cancel { cancelled: AtomicBool::new(false), wake_tx, wake_rx })
+    }
+
+    /// Cancel the operation, waking up any threads waiting in
+    /// `wait_readable`. Cancelling more than once is harmless.
+    pub fn cancel(&self) {
+        if self.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        // The byte is never read back out, so the self-pipe stays
+        // readable and every later wait returns right away.
+        if let Err(e) = (&self.wake_tx).write(&[0]) {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                warn!("waking cancelled threads: {:?}", e);
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Block until `fd` is readable or the operation is cancelled.
+    /// Returns true if `fd` is ready to read, and false if the caller
+    /// should bail out instead.
+    pub fn wait_readable<F: AsFd>(&self, fd: F) -> anyhow::Result<bool> {
+        loop {
+            if self.is_cancelled() {
+                return Ok(false);
+            }
+            let mut poll_fds = [
+                poll::PollFd::new(fd.as_fd(), poll::PollFlags::POLLIN),
+                poll::PollFd::new(self.wake_rx.as_fd(), poll::PollFlags::POLLIN),
+            ];
+            match poll::poll(&mut poll_fds, poll::PollTimeout::NONE) {
+                Ok(_) => {}
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e).context("polling for readability"),
+            }
+            if self.is_cancelled() {
+                return Ok(false);
+            }
+            // A hangup or error counts as readable, so that the read
+            // itself gets to report what went wrong.
+            if poll_fds[0].any().unwrap_or(true) {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time};
+
+    use super::*;
+
+    #[test]
+    fn cancel_wakes_blocked_reader() {
+        let cancel = Cancel::new().unwrap();
+        // hold on to the other end so the stream never reports a hangup
+        let (_peer, quiet) = UnixStream::pair().unwrap();
+
+        thread::scope(|s| {
+            let waiter = s.spawn(|| cancel.wait_readable(&quiet).unwrap());
+            thread::sleep(time::Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            cancel.cancel();
+            assert!(!waiter.join().unwrap());
+        });
+
+        // stays cancelled
+        assert!(cancel.is_cancelled());
+        assert!(!cancel.wait_readable(&quiet).unwrap());
+    }
+
+    #[test]
+    fn readable_before_cancel() {
+        let cancel = Cancel::new().unwrap();
+        let (mut peer, stream) = UnixStream::pair().unwrap();
+        peer.write_all(b"x").unwrap();
+        assert!(cancel.wait_readable(&stream).unwrap());
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A single clipboard register held by the daemon and shared by all of
+//! its sessions, so that text copied in one session can be pasted into
+//! another without going through the terminal's selection, which can
+//! be painful over a laggy link.
+
+use std::sync::Mutex;
+
+/// The most the clipboard will hold. It lives in the daemon's memory for
+/// as long as the daemon runs, so it is meant for snippets, not files.
+pub const MAX_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Clipboard {
+    contents: Mutex<Option<Vec<u8>>>,
+}
+
+impl Clipboard {
+    /// Replace the contents of the clipboard. Refuses anything over
+    /// MAX_BYTES, leaving the old contents in place.
+    pub fn copy(&self, data: Vec<u8>) -> Result<(), usize> {
+        if data.len() > MAX_BYTES {
+            return Err(MAX_BYTES);
+        }
+        *self.contents.lock().unwrap() = Some(data);
+        Ok(())
+    }
+
+    /// The contents of the clipboard, if anything has been copied.
+    pub fn contents(&self) -> Option<Vec<u8>> {
+        self.contents.lock().unwrap().clone()
+    }
+
+    /// The contents of the clipboard as they should be typed into a
+    /// pty. Terminals send a carriage return for each newline in pasted
+    /// text, since that is what the enter key sends, so we do the same.
+    pub fn contents_as_input(&self) -> Option<Vec<u8>> {
+        self.contents().map(|data| as_input(&data))
+    }
+}
+
+fn as_input(data: &[u8]) -> Vec<u8> {
+    let mut input = Vec::with_capacity(data.len());
+    let mut prev = None;
+    for byte in data.iter() {
+        match (prev, byte) {
+            // a \r\n pair already has its carriage return
+            (Some(b'\r'), b'\n') => {}
+            (_, b'\n') => input.push(b'\r'),
+            (_, b) => input.push(*b),
+        }
+        prev = Some(*byte);
+    }
+    input
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn copy_and_paste() {
+        let clipboard = Clipboard::default();
+        assert_eq!(clipboard.contents(), None);
+
+        clipboard.copy(b"ls -l\ncd /tmp\r\n".to_vec()).unwrap();
+        assert_eq!(clipboard.contents().unwrap(), b"ls -l\ncd /tmp\r\n");
+        assert_eq!(clipboard.contents_as_input().unwrap(), b"ls -l\rcd /tmp\r");
+
+        assert_eq!(clipboard.copy(vec![b'x'; MAX_BYTES + 1]), Err(MAX_BYTES));
+        assert_eq!(clipboard.contents().unwrap(), b"ls -l\ncd /tmp\r\n");
+    }
+}
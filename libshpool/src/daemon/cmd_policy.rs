@@ -0,0 +1,98 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checking the commands that clients ask the daemon to run against
+//! the `cmd_policy` from the config.
+//!
+//! Patterns must match the whole command, so an allow pattern of
+//! `htop` does not let through `htop; rm -rf ~`.
+
+use anyhow::Context;
+use regex::Regex;
+
+use crate::config::CmdPolicy;
+
+/// A CmdPolicy with all of its patterns compiled.
+pub struct Compiled {
+    allow: Option<Vec<Regex>>,
+    deny: Vec<Regex>,
+}
+
+pub fn compile(policy: &CmdPolicy) -> anyhow::Result<Compiled> {
+    let compile_all = |pats: &[String]| -> anyhow::Result<Vec<Regex>> {
+        pats.iter()
+            .map(|p| {
+                Regex::new(&format!("^(?:{})$", p)).with_context(|| format!("compiling '{}'", p))
+            })
+            .collect()
+    };
+
+    Ok(Compiled {
+        allow: policy.allow.as_deref().map(compile_all).transpose()?,
+        deny: compile_all(policy.deny.as_deref().unwrap_or(&[]))?,
+    })
+}
+
+impl Compiled {
+    /// Check a command, returning the reason to give the client
+    /// if it is not allowed.
+    pub fn check(&self, cmd: &str) -> Result<(), String> {
+        if let Some(re) = self.deny.iter().find(|re| re.is_match(cmd)) {
+            return Err(format!("cmd '{}' is denied by the cmd_policy pattern '{}'", cmd, re));
+        }
+        if let Some(allow) = &self.allow {
+            if !allow.iter().any(|re| re.is_match(cmd)) {
+                return Err(format!("cmd '{}' is not in the cmd_policy allowlist", cmd));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn policy(allow: Option<&[&str]>, deny: &[&str]) -> Compiled {
+        let to_strings = |pats: &[&str]| pats.iter().map(|p| String::from(*p)).collect();
+        compile(&CmdPolicy { allow: allow.map(to_strings), deny: Some(to_strings(deny)) }).unwrap()
+    }
+
+    #[test]
+    fn check() {
+        let cases = vec![
+            (policy(None, &[]), "anything goes", true),
+            (policy(Some(&[]), &[]), "htop", false),
+            (policy(Some(&["htop", "tail -f /var/log/.*"]), &[]), "htop", true),
+            (policy(Some(&["htop"]), &[]), "htop; rm -rf ~", false),
+            (policy(Some(&["tail -f /var/log/.*"]), &[]), "tail -f /var/log/syslog", true),
+            (
+                policy(Some(&["tail -f /var/log/.*"]), &[".*\\.\\..*"]),
+                "tail -f /var/log/../x",
+                false,
+            ),
+            (policy(None, &["sudo .*"]), "sudo su", false),
+            (policy(None, &["sudo .*"]), "vim", true),
+        ];
+        for (policy, cmd, want_ok) in cases.into_iter() {
+            assert_eq!(policy.check(cmd).is_ok(), want_ok, "cmd={}", cmd);
+        }
+    }
+
+    #[test]
+    fn bad_pattern() {
+        let res = compile(&CmdPolicy { allow: Some(vec![String::from("(")]), deny: None });
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Building the command that gets exec'd in the child side of the pty fork.
+//!
+//! Normally this is just the user's shell, but sessions can ask to have
+//! the shell launched inside of a container or a set of linux namespaces,
+//! in which case we wrap the shell in whatever tool knows how to enter
+//! the target (`docker exec`, `podman exec` or `nsenter`).
+
+use std::{fmt, os::unix::process::CommandExt as _, path::Path, process, str::FromStr};
+
+use anyhow::{anyhow, bail};
+
+/// A place to run a session's shell other than directly on the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerTarget {
+    /// `docker:<container>`, run via `docker exec -it`.
+    Docker(String),
+    /// `podman:<container>`, run via `podman exec -it`.
+    Podman(String),
+    /// `netns:<pid or path>`, enter the network namespace of the given
+    /// pid or the namespace file at the given path (i.e. /run/netns/foo)
+    /// using `nsenter`.
+    NetNs(NsTarget),
+    /// `mntns:<pid or path>`, enter the mount namespace of the given
+    /// pid or the namespace file at the given path using `nsenter`.
+    MntNs(NsTarget),
+}
+
+/// The namespace to enter for the `netns` and `mntns` targets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NsTarget {
+    Pid(i32),
+    Path(String),
+}
+
+impl FromStr for ContainerTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(src: &str) -> anyhow::Result<Self> {
+        let (kind, target) = src
+            .split_once(':')
+            .ok_or(anyhow!("container target must be of the form kind:target"))?;
+        if target.is_empty() {
+            bail!("container target '{}' is missing a target after the ':'", src);
+        }
+
+        let ns_target = || match target.parse::<i32>() {
+            Ok(pid) => NsTarget::Pid(pid),
+            Err(_) => NsTarget::Path(String::from(target)),
+        };
+        Ok(match kind {
+            "docker" => ContainerTarget::Docker(String::from(target)),
+            "podman" => ContainerTarget::Podman(String::from(target)),
+            "netns" => ContainerTarget::NetNs(ns_target()),
+            "mntns" => ContainerTarget::MntNs(ns_target()),
+            _ => {
+                bail!("unknown container kind '{}' (expected docker, podman, netns or mntns)", kind)
+            }
+        })
+    }
+}
+
+impl fmt::Display for ContainerTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ns = |f: &mut fmt::Formatter<'_>, kind: &str, t: &NsTarget| match t {
+            NsTarget::Pid(pid) => write!(f, "{}:{}", kind, pid),
+            NsTarget::Path(path) => write!(f, "{}:{}", kind, path),
+        };
+        match self {
+            ContainerTarget::Docker(c) => write!(f, "docker:{}", c),
+            ContainerTarget::Podman(c) => write!(f, "podman:{}", c),
+            ContainerTarget::NetNs(t) => ns(f, "netns", t),
+            ContainerTarget::MntNs(t) => ns(f, "mntns", t),
+        }
+    }
+}
+
+/// A description of the command to launch for a session, independent
+/// of where it will be launched. Call `build` to get a
+/// `process::Command` ready to exec.
+#[derive(Debug, Clone)]
+pub struct ShellCommand {
+    /// The binary to invoke.
+    pub program: String,
+    /// The arguments to pass to the binary.
+    pub args: Vec<String>,
+    /// An override for argv[0], used to indicate a login shell.
+    pub arg0: Option<String>,
+    /// The full environment for the command.
+    pub env: Vec<(String, String)>,
+    /// The directory to launch the command in.
+    pub cwd: String,
+}
+
+impl ShellCommand {
+    pub fn new(program: String) -> Self {
+        ShellCommand { program, args: vec![], arg0: None, env: vec![], cwd: String::from("/") }
+    }
+
+    /// Build the command to exec, wrapping it to run inside the given
+    /// container target if there is one. The resulting command inherits
+    /// stdin, stdout and stderr, since it gets exec'd after the pty fork
+    /// has already `dup2`ed the pty into place.
+    pub fn build(&self, container: Option<&ContainerTarget>) -> anyhow::Result<process::Command> {
+        let mut cmd = match container {
+            None => {
+                let mut cmd = process::Command::new(&self.program);
+                cmd.args(&self.args).current_dir(&self.cwd);
+                if let Some(arg0) = &self.arg0 {
+                    cmd.arg0(arg0);
+                }
+                cmd
+            }
+            Some(ContainerTarget::Docker(container)) => self.container_exec("docker", container)?,
+            Some(ContainerTarget::Podman(container)) => self.container_exec("podman", container)?,
+            Some(ContainerTarget::NetNs(target)) => self.nsenter("--net", target)?,
+            Some(ContainerTarget::MntNs(target)) => self.nsenter("--mount", target)?,
+        };
+
+        cmd.stdin(process::Stdio::inherit())
+            .stdout(process::Stdio::inherit())
+            .stderr(process::Stdio::inherit());
+        match container {
+            // The runtime client needs its own environment to find the
+            // container engine (DOCKER_HOST, XDG_RUNTIME_DIR and so on),
+            // and the shell inside only gets the vars named with `-e`.
+            Some(ContainerTarget::Docker(_)) | Some(ContainerTarget::Podman(_)) => {}
+            // The env should mostly be set up by the shell sourcing
+            // rc files and whatnot, so we will start things off with
+            // an environment that is blank except for a few vars we inject
+            // to avoid breakage and vars the user has asked us to inject.
+            _ => {
+                cmd.env_clear();
+            }
+        }
+        cmd.envs(self.env.iter().cloned());
+        Ok(cmd)
+    }
+
+    /// The arguments for a program that gets launched by a wrapper
+    /// which can't set its argv[0]. A login shell gets asked for with
+    /// `-l` instead, which all the common shells understand, and any
+    /// other argv[0] override is an error rather than getting dropped.
+    fn wrapped_args(&self) -> anyhow::Result<Vec<String>> {
+        let mut args = self.args.clone();
+        if let Some(arg0) = &self.arg0 {
+            let basename = Path::new(&self.program).file_name().and_then(|n| n.to_str());
+            let login = basename.map(|b| *arg0 == format!("-{}", b)).unwrap_or(false);
+            if !login {
+                bail!("can't set argv[0] to '{}' for a shell in a container", arg0);
+            }
+            // bash wants its long options first, so this goes last
+            args.push(String::from("-l"));
+        }
+        Ok(args)
+    }
+
+    /// Wrap the command in `<runtime> exec -it`. The container runtime
+    /// won't pass our environment through, so each var gets named with
+    /// `-e KEY`, which has the runtime copy the value out of its own
+    /// environment rather than putting it on the command line for
+    /// anyone to see in `ps`. We don't set a working directory since
+    /// the host home directory most likely does not exist inside the
+    /// container.
+    fn container_exec(&self, runtime: &str, container: &str) -> anyhow::Result<process::Command> {
+        let mut cmd = process::Command::new(runtime);
+        cmd.arg("exec").arg("-it");
+        for (key, _) in self.env.iter() {
+            cmd.arg("-e").arg(key);
+        }
+        cmd.arg(container).arg(&self.program).args(self.wrapped_args()?);
+        Ok(cmd)
+    }
+
+    /// Wrap the command in `nsenter`. Since only the requested namespace
+    /// is entered, the command otherwise runs as a normal child of the
+    /// daemon, so we can still launch it in the usual working dir.
+    fn nsenter(&self, ns_flag: &str, target: &NsTarget) -> anyhow::Result<process::Command> {
+        let mut cmd = process::Command::new("nsenter");
+        match target {
+            NsTarget::Pid(pid) => {
+                cmd.arg("--target").arg(pid.to_string()).arg(ns_flag);
+            }
+            NsTarget::Path(path) => {
+                cmd.arg(format!("{}={}", ns_flag, path));
+            }
+        }
+        cmd.arg("--").arg(&self.program).args(self.wrapped_args()?).current_dir(&self.cwd);
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ffi::OsStr;
+
+    #[test]
+    fn parse_targets() {
+        let cases = vec![
+            ("docker:devbox", ContainerTarget::Docker(String::from("devbox"))),
+            ("podman:dev:latest", ContainerTarget::Podman(String::from("dev:latest"))),
+            ("netns:1234", ContainerTarget::NetNs(NsTarget::Pid(1234))),
+            (
+                "netns:/run/netns/vpn",
+                ContainerTarget::NetNs(NsTarget::Path(String::from("/run/netns/vpn"))),
+            ),
+            ("mntns:42", ContainerTarget::MntNs(NsTarget::Pid(42))),
+        ];
+        for (src, want) in cases.into_iter() {
+            let got: ContainerTarget = src.parse().expect("parse to succeed");
+            assert_eq!(got, want);
+            assert_eq!(got.to_string(), src);
+        }
+
+        for bad in ["docker", "docker:", "lxc:foo", ""] {
+            assert!(bad.parse::<ContainerTarget>().is_err(), "expected '{}' to fail", bad);
+        }
+    }
+
+    #[test]
+    fn wrap_docker() {
+        let mut shell = ShellCommand::new(String::from("/bin/bash"));
+        shell.args = vec![String::from("--norc")];
+        shell.env = vec![(String::from("TERM"), String::from("xterm"))];
+
+        let cmd = shell.build(Some(&ContainerTarget::Docker(String::from("devbox")))).unwrap();
+        assert_eq!(cmd.get_program(), "docker");
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(args, vec!["exec", "-it", "-e", "TERM", "devbox", "/bin/bash", "--norc"]);
+        let envs: Vec<_> = cmd.get_envs().collect();
+        assert_eq!(envs, vec![(OsStr::new("TERM"), Some(OsStr::new("xterm")))]);
+    }
+
+    #[test]
+    fn wrapped_login_shell() {
+        let mut shell = ShellCommand::new(String::from("/bin/bash"));
+        shell.args = vec![String::from("--norc")];
+        shell.arg0 = Some(String::from("-bash"));
+
+        let cmd = shell.build(Some(&ContainerTarget::Podman(String::from("dev")))).unwrap();
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(args, vec!["exec", "-it", "dev", "/bin/bash", "--norc", "-l"]);
+
+        let cmd = shell.build(Some(&ContainerTarget::NetNs(NsTarget::Pid(7)))).unwrap();
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(args, vec!["--target", "7", "--net", "--", "/bin/bash", "--norc", "-l"]);
+
+        shell.arg0 = Some(String::from("not-bash"));
+        assert!(shell.build(Some(&ContainerTarget::Podman(String::from("dev")))).is_err());
+        assert!(shell.build(None).is_ok());
+    }
+
+    #[test]
+    fn wrap_nsenter() {
+        let shell = ShellCommand::new(String::from("/bin/zsh"));
+
+        let cmd = shell.build(Some(&ContainerTarget::NetNs(NsTarget::Pid(7)))).unwrap();
+        assert_eq!(cmd.get_program(), "nsenter");
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(args, vec!["--target", "7", "--net", "--", "/bin/zsh"]);
+
+        let cmd =
+            shell.build(Some(&ContainerTarget::MntNs(NsTarget::Path(String::from("/x"))))).unwrap();
+        let args: Vec<&OsStr> = cmd.get_args().collect();
+        assert_eq!(args, vec!["--mount=/x", "--", "/bin/zsh"]);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  An optional service on the user's D-Bus session bus, so that desktop
+  widgets and shell extensions can show and manage sessions without
+  speaking the shpool protocol.
+
+  The daemon takes the `org.shpool.Daemon` name and serves the
+  `org.shpool.Daemon1` interface at `/org/shpool/Daemon`, with
+
+  - `List() -> a(sxs)`, the name, start time in unix milliseconds and
+    status of each session.
+  - `AttachInfo(s name) -> (s status, x started_at_unix_ms, as argv)`,
+    where `argv` is a command that attaches to the session, for
+    widgets that want to open it in a terminal.
+  - `Kill(as names) -> as not_found`, which takes names or globs like
+    `shpool kill`.
+  - A `SessionEvent(s event, s session)` signal, with the same events
+    as the `Hooks` trait: `new_session`, `reattach`, `busy`,
+    `client_disconnect` and `shell_disconnect`.
+
+  Kills made over D-Bus show up in the audit log with an action of
+  `dbus-kill`. This is only compiled in with the `dbus` feature, since
+  it needs to link against libdbus, and is only turned on when the
+  `dbus` config option is set.
+*/
+
+use std::sync::Arc;
+
+use super::server::Server;
+use crate::hooks::{CmdDecision, Hooks, StreamTransform};
+
+// How many events can be waiting on the D-Bus thread before we start
+// dropping them.
+const EVENT_QUEUE_LEN: usize = 256;
+
+/// A session event to broadcast as a signal.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct Event {
+    pub event: &'static str,
+    pub session: String,
+}
+
+/// Wrap the given hooks so that session events also get queued up for
+/// the D-Bus service, if it is enabled.
+pub fn wrap(
+    inner: Box<dyn Hooks + Send + Sync>,
+    enabled: bool,
+) -> (Box<dyn Hooks + Send + Sync>, Option<crossbeam_channel::Receiver<Event>>) {
+    if !enabled || cfg!(not(feature = "dbus")) {
+        return (inner, None);
+    }
+    let (tx, rx) = crossbeam_channel::bounded(EVENT_QUEUE_LEN);
+    (Box::new(EventHooks { inner, tx }), Some(rx))
+}
+
+/// Start serving on the session bus, if the D-Bus service is enabled.
+pub fn start(server: &Arc<Server>) {
+    let Some(events) = server.dbus_events.lock().unwrap().take() else {
+        return;
+    };
+    imp::start(Arc::clone(server), events);
+}
+
+struct EventHooks {
+    inner: Box<dyn Hooks + Send + Sync>,
+    tx: crossbeam_channel::Sender<Event>,
+}
+
+impl EventHooks {
+    fn event(&self, event: &'static str, session_name: &str) {
+        // never block the daemon on the bus, just drop events instead
+        let _ = self.tx.try_send(Event { event, session: String::from(session_name) });
+    }
+}
+
+impl Hooks for EventHooks {
+    fn on_new_session(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("new_session", session_name);
+        self.inner.on_new_session(session_name)
+    }
+
+    fn on_reattach(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("reattach", session_name);
+        self.inner.on_reattach(session_name)
+    }
+
+    fn on_busy(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("busy", session_name);
+        self.inner.on_busy(session_name)
+    }
+
+    fn on_client_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("client_disconnect", session_name);
+        self.inner.on_client_disconnect(session_name)
+    }
+
+    fn on_shell_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("shell_disconnect", session_name);
+        self.inner.on_shell_disconnect(session_name)
+    }
+
+    fn check_cmd(&self, session_name: &str, cmd: &str) -> CmdDecision {
+        self.inner.check_cmd(session_name, cmd)
+    }
+
+    fn output_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.output_transform(session_name)
+    }
+
+    fn input_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.input_transform(session_name)
+    }
+}
+
+#[cfg(feature = "dbus")]
+mod imp {
+    use std::{sync::Arc, thread, time};
+
+    use anyhow::{anyhow, Context};
+    use dbus::{
+        blocking::{stdintf::org_freedesktop_dbus::RequestNameReply, Connection},
+        channel::{MatchingReceiver, Sender},
+        message::MatchRule,
+        Message,
+    };
+    use dbus_crossroads::{Crossroads, MethodErr};
+    use shpool_protocol::KillRequest;
+    use tracing::{error, info, warn};
+
+    use super::Event;
+    use crate::daemon::server::Server;
+
+    const BUS_NAME: &str = "org.shpool.Daemon";
+    const OBJECT_PATH: &str = "/org/shpool/Daemon";
+    const INTERFACE: &str = "org.shpool.Daemon1";
+
+    // How often to check for events to broadcast when the bus is quiet.
+    const EVENT_POLL_INTERVAL: time::Duration = time::Duration::from_millis(200);
+
+    /// The command line that attaches to the given session through the
+    /// daemon's socket.
+    fn attach_argv(server: &Server, session_name: &str) -> Vec<String> {
+        let exe = std::env::current_exe()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| String::from("shpool"));
+        vec![
+            exe,
+            String::from("--socket"),
+            server.socket().to_string_lossy().into_owned(),
+            String::from("attach"),
+            String::from(session_name),
+        ]
+    }
+
+    /// Kill sessions, recording it in the audit log like a kill made with
+    /// `shpool kill` would be.
+    fn kill(server: &Server, names: Vec<String>) -> anyhow::Result<Vec<String>> {
+        let request = KillRequest { sessions: names, panes: false, dry_run: false };
+        let res = server.kill_sessions(&request);
+        let outcome = match &res {
+            Ok(_) => String::from("ok"),
+            Err(e) => format!("error: {:#}", e),
+        };
+        server.audit().record(0, Default::default(), "dbus-kill", &request.sessions, &outcome);
+        Ok(res?.not_found_sessions)
+    }
+
+    pub fn start(server: Arc<Server>, events: crossbeam_channel::Receiver<Event>) {
+        let res = thread::Builder::new().name(String::from("dbus")).spawn(move || {
+            if let Err(e) = serve(server, events) {
+                error!("dbus service: {:?}", e);
+            }
+        });
+        if let Err(e) = res {
+            error!("spawning dbus thread: {:?}", e);
+        }
+    }
+
+    fn serve(
+        server: Arc<Server>,
+        events: crossbeam_channel::Receiver<Event>,
+    ) -> anyhow::Result<()> {
+        let conn = Connection::new_session().context("connecting to session bus")?;
+        match conn.request_name(BUS_NAME, false, false, true).context("requesting bus name")? {
+            RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {}
+            reply => {
+                return Err(anyhow!(
+                    "could not take {}, is another daemon running? ({:?})",
+                    BUS_NAME,
+                    reply
+                ))
+            }
+        }
+        info!("serving {} on the session bus", BUS_NAME);
+
+        let mut cr = Crossroads::new();
+        let iface = cr.register(INTERFACE, |b| {
+            b.signal::<(String, String), _>("SessionEvent", ("event", "session"));
+            b.method("List", (), ("sessions",), |_, server: &mut Arc<Server>, (): ()| {
+                let reply = server.list_sessions().map_err(|e| MethodErr::failed(&e))?;
+                let sessions = reply
+                    .sessions
+                    .into_iter()
+                    .map(|s| (s.name, s.started_at_unix_ms, s.status.to_string()))
+                    .collect::<Vec<_>>();
+                Ok((sessions,))
+            });
+            b.method(
+                "AttachInfo",
+                ("name",),
+                ("status", "started_at_unix_ms", "argv"),
+                |_, server: &mut Arc<Server>, (name,): (String,)| {
+                    let reply = server.list_sessions().map_err(|e| MethodErr::failed(&e))?;
+                    let session =
+                        reply.sessions.into_iter().find(|s| s.name == name).ok_or_else(|| {
+                            MethodErr::failed(&format!("no session named {}", name))
+                        })?;
+                    Ok((
+                        session.status.to_string(),
+                        session.started_at_unix_ms,
+                        attach_argv(server, &name),
+                    ))
+                },
+            );
+            b.method(
+                "Kill",
+                ("names",),
+                ("not_found",),
+                |_, server: &mut Arc<Server>, (names,): (Vec<String>,)| {
+                    let not_found = kill(server, names).map_err(|e| MethodErr::failed(&e))?;
+                    Ok((not_found,))
+                },
+            );
+        });
+        cr.insert(OBJECT_PATH, &[iface], server);
+
+        conn.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |msg, conn| {
+                if cr.handle_message(msg, conn).is_err() {
+                    warn!("dbus: could not handle message");
+                }
+                true
+            }),
+        );
+
+        loop {
+            conn.process(EVENT_POLL_INTERVAL).context("processing dbus messages")?;
+            loop {
+                let event = match events.try_recv() {
+                    Ok(e) => e,
+                    Err(crossbeam_channel::TryRecvError::Empty) => break,
+                    Err(crossbeam_channel::TryRecvError::Disconnected) => return Ok(()),
+                };
+                let signal = Message::new_signal(OBJECT_PATH, INTERFACE, "SessionEvent")
+                    .map_err(|e| anyhow!("building signal: {}", e))?
+                    .append2(event.event, &event.session);
+                if conn.send(signal).is_err() {
+                    warn!("dbus: could not send {:?}", event);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "dbus"))]
+mod imp {
+    use std::sync::Arc;
+
+    use super::Event;
+    use crate::daemon::server::Server;
+
+    // `wrap` never hands out an event queue without the feature, so
+    // there is never anything to start.
+    pub fn start(_server: Arc<Server>, _events: crossbeam_channel::Receiver<Event>) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct NoopHooks;
+    impl Hooks for NoopHooks {}
+
+    #[test]
+    fn events_queue_without_blocking() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let hooks = EventHooks { inner: Box::new(NoopHooks), tx };
+        hooks.on_new_session("a").unwrap();
+        // the queue is full, so this one is dropped rather than blocking
+        hooks.on_busy("a").unwrap();
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.event, "new_session");
+        assert_eq!(event.session, "a");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn disabled() {
+        let (_, events) = wrap(Box::new(NoopHooks), false);
+        assert!(events.is_none());
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The event socket lets external tools follow session lifecycle events
+  without linking against libshpool, writing a plugin, or leaning on the
+  test_hooks socket, which only exists in test builds.
+
+  When the `event_socket` config option is on, the daemon listens on
+  `events.socket` in its runtime dir. Anyone who connects gets a `hello`
+  line with the daemon's version, followed by a json line for each
+  session event, in the same shape as the messages sent to plugins:
+
+  ```text
+  {"type":"hello","version":"0.9.0"}
+  {"type":"event","event":"new_session","session":"main","at_unix_ms":1700000000000}
+  ```
+
+  The events are the ones from the `Hooks` trait: `new_session`,
+  `reattach`, `busy`, `client_disconnect` and `shell_disconnect`. The
+  socket is read only, anything a subscriber writes is ignored.
+
+  Events go out from a dedicated thread, so a slow subscriber can never
+  hold up the daemon. If the queue fills up, events are dropped, and a
+  subscriber that can't take a line within WRITE_TIMEOUT gets hung up on.
+*/
+
+use std::{
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use serde_derive::Serialize;
+use tracing::{error, info, warn};
+
+use super::{flight_recorder, perms};
+use crate::hooks::{CmdDecision, Hooks, StreamTransform};
+
+// How many events can be waiting to go out before we start dropping
+// them.
+const EVENT_QUEUE_LEN: usize = 256;
+
+// How long a subscriber gets to take a line before we give up on it.
+const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// A line written to event socket subscribers.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Line<'a> {
+    Hello { version: &'a str },
+    Event { event: &'a str, session: &'a str, at_unix_ms: i64 },
+}
+
+impl Line<'_> {
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = serde_json::to_vec(self).context("encoding event line")?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+}
+
+struct Event {
+    event: &'static str,
+    session: String,
+    at_unix_ms: i64,
+}
+
+type Subscribers = Arc<Mutex<Vec<UnixStream>>>;
+
+pub fn path(runtime_dir: &Path) -> PathBuf {
+    runtime_dir.join("events.socket")
+}
+
+/// Wrap the given hooks so that session events also get published on
+/// the event socket, if it is enabled, starting to listen on it.
+pub fn wrap(
+    inner: Box<dyn Hooks + Send + Sync>,
+    runtime_dir: &Path,
+    enabled: bool,
+) -> Box<dyn Hooks + Send + Sync> {
+    if !enabled {
+        return inner;
+    }
+    match listen(&path(runtime_dir)) {
+        Ok(tx) => Box::new(EventHooks { inner, tx }),
+        Err(e) => {
+            error!("starting event socket: {:?}", e);
+            inner
+        }
+    }
+}
+
+fn listen(sock_path: &Path) -> anyhow::Result<crossbeam_channel::Sender<Event>> {
+    // A daemon that didn't get to clean up leaves the file behind, but
+    // make sure nobody is still listening before clobbering it.
+    if sock_path.exists() {
+        if UnixStream::connect(sock_path).is_ok() {
+            return Err(anyhow!("another daemon is publishing events on {:?}", sock_path));
+        }
+        std::fs::remove_file(sock_path)
+            .with_context(|| format!("removing stale event socket {:?}", sock_path))?;
+    }
+    let listener = UnixListener::bind(sock_path)
+        .with_context(|| format!("binding to event socket {:?}", sock_path))?;
+    perms::restrict_socket(sock_path)?;
+    info!("publishing events on {:?}", sock_path);
+
+    let subscribers: Subscribers = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = crossbeam_channel::bounded(EVENT_QUEUE_LEN);
+
+    let accept_subscribers = Arc::clone(&subscribers);
+    thread::Builder::new()
+        .name(String::from("event-socket-accept"))
+        .spawn(move || accept_loop(listener, accept_subscribers))
+        .context("spawning event socket listener")?;
+    thread::Builder::new()
+        .name(String::from("event-socket-publish"))
+        .spawn(move || publish_loop(rx, subscribers))
+        .context("spawning event socket publisher")?;
+
+    Ok(tx)
+}
+
+fn accept_loop(listener: UnixListener, subscribers: Subscribers) {
+    let hello = match (Line::Hello { version: shpool_protocol::VERSION }).encode() {
+        Ok(h) => h,
+        Err(e) => {
+            error!("{:?}", e);
+            return;
+        }
+    };
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("accepting event socket subscriber: {:?}", e);
+                continue;
+            }
+        };
+        // Holding the lock across the hello means that once a
+        // subscriber has seen it, it won't miss any later events.
+        let mut subscribers = subscribers.lock().unwrap();
+        let res =
+            stream.set_write_timeout(Some(WRITE_TIMEOUT)).and_then(|_| stream.write_all(&hello));
+        if let Err(e) = res {
+            info!("dropping event socket subscriber: {:?}", e);
+            continue;
+        }
+        info!("new event socket subscriber");
+        subscribers.push(stream);
+    }
+}
+
+fn publish_loop(rx: crossbeam_channel::Receiver<Event>, subscribers: Subscribers) {
+    for event in rx.iter() {
+        let line = Line::Event {
+            event: event.event,
+            session: &event.session,
+            at_unix_ms: event.at_unix_ms,
+        };
+        let buf = match line.encode() {
+            Ok(b) => b,
+            Err(e) => {
+                warn!("{:?}", e);
+                continue;
+            }
+        };
+        subscribers.lock().unwrap().retain_mut(|stream| match stream.write_all(&buf) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("hanging up on event socket subscriber: {:?}", e);
+                false
+            }
+        });
+    }
+}
+
+struct EventHooks {
+    inner: Box<dyn Hooks + Send + Sync>,
+    tx: crossbeam_channel::Sender<Event>,
+}
+
+impl EventHooks {
+    fn event(&self, event: &'static str, session_name: &str) {
+        let event = Event {
+            event,
+            session: String::from(session_name),
+            at_unix_ms: flight_recorder::unix_ms(time::SystemTime::now()),
+        };
+        // never block the daemon on subscribers, just drop events instead
+        if self.tx.try_send(event).is_err() {
+            warn!("event socket queue full, dropping event");
+        }
+    }
+}
+
+impl Hooks for EventHooks {
+    fn on_new_session(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("new_session", session_name);
+        self.inner.on_new_session(session_name)
+    }
+
+    fn on_reattach(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("reattach", session_name);
+        self.inner.on_reattach(session_name)
+    }
+
+    fn on_busy(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("busy", session_name);
+        self.inner.on_busy(session_name)
+    }
+
+    fn on_client_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("client_disconnect", session_name);
+        self.inner.on_client_disconnect(session_name)
+    }
+
+    fn on_shell_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("shell_disconnect", session_name);
+        self.inner.on_shell_disconnect(session_name)
+    }
+
+    fn check_cmd(&self, session_name: &str, cmd: &str) -> CmdDecision {
+        self.inner.check_cmd(session_name, cmd)
+    }
+
+    fn output_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.output_transform(session_name)
+    }
+
+    fn input_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.input_transform(session_name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufRead, BufReader};
+
+    use super::*;
+
+    struct NoopHooks;
+    impl Hooks for NoopHooks {}
+
+    #[test]
+    fn publishes_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let hooks = wrap(Box::new(NoopHooks), dir.path(), true);
+
+        let stream = UnixStream::connect(path(dir.path())).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let hello: serde_json::Value =
+            serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["version"], shpool_protocol::VERSION);
+
+        hooks.on_new_session("main").unwrap();
+        hooks.on_client_disconnect("main").unwrap();
+
+        for want in ["new_session", "client_disconnect"] {
+            let event: serde_json::Value =
+                serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+            assert_eq!(event["type"], "event");
+            assert_eq!(event["event"], want);
+            assert_eq!(event["session"], "main");
+            assert!(event["at_unix_ms"].as_i64().unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let _hooks = wrap(Box::new(NoopHooks), dir.path(), false);
+        assert!(!path(dir.path()).exists());
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A short in-memory history of how the shells of each session name
+//! exited, for `shpool list --long`. The history is keyed on the name
+//! rather than the session, so it survives the session being restarted
+//! or recreated, which makes it easy to spot a command that keeps
+//! falling over.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use shpool_protocol::{ExitRecord, SessionExits};
+
+/// How many exits to remember for each session name.
+const EXITS_PER_NAME: usize = 10;
+
+/// How many session names to remember exits for. Once we are full,
+/// the name that has gone the longest without an exit is forgotten.
+const MAX_NAMES: usize = 256;
+
+#[derive(Debug, Default)]
+pub struct ExitHistory {
+    exits: Mutex<HashMap<String, VecDeque<ExitRecord>>>,
+}
+
+impl ExitHistory {
+    /// Remember an exit of the named session's shell.
+    pub fn record(&self, name: &str, exit: ExitRecord) {
+        let mut exits = self.exits.lock().unwrap();
+        if !exits.contains_key(name) && exits.len() == MAX_NAMES {
+            let stalest = exits
+                .iter()
+                .min_by_key(|(_, e)| e.back().map(|e| e.exited_at_unix_ms).unwrap_or(0))
+                .map(|(n, _)| n.clone());
+            if let Some(stalest) = stalest {
+                exits.remove(&stalest);
+            }
+        }
+
+        let name_exits = exits.entry(String::from(name)).or_default();
+        if name_exits.len() == EXITS_PER_NAME {
+            name_exits.pop_front();
+        }
+        name_exits.push_back(exit);
+    }
+
+    /// The recorded exits, sorted by session name.
+    pub fn snapshot(&self) -> Vec<SessionExits> {
+        let exits = self.exits.lock().unwrap();
+        let mut snapshot: Vec<SessionExits> = exits
+            .iter()
+            .map(|(name, e)| SessionExits {
+                name: name.clone(),
+                exits: e.iter().cloned().collect(),
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exit(status: i32, at: i64) -> ExitRecord {
+        ExitRecord { status, signal: None, started_at_unix_ms: at - 10, exited_at_unix_ms: at }
+    }
+
+    #[test]
+    fn bounded_per_name() {
+        let history = ExitHistory::default();
+        for i in 0..(EXITS_PER_NAME + 3) {
+            history.record("flappy", exit(i as i32, i as i64));
+        }
+        history.record("calm", exit(0, 100));
+
+        let snapshot = history.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].name, "calm");
+        assert_eq!(snapshot[1].name, "flappy");
+        assert_eq!(snapshot[1].exits.len(), EXITS_PER_NAME);
+        assert_eq!(snapshot[1].exits[0].status, 3);
+        assert_eq!(snapshot[1].exits[EXITS_PER_NAME - 1].status, EXITS_PER_NAME as i32 + 2);
+    }
+
+    #[test]
+    fn forgets_stalest_name() {
+        let history = ExitHistory::default();
+        for i in 0..MAX_NAMES {
+            history.record(&format!("s{}", i), exit(0, 1000 + i as i64));
+        }
+        // s0 exits again, so s1 is now the stalest
+        history.record("s0", exit(0, 5000));
+        history.record("new", exit(0, 6000));
+
+        let names: Vec<String> = history.snapshot().into_iter().map(|e| e.name).collect();
+        assert_eq!(names.len(), MAX_NAMES);
+        assert!(names.contains(&String::from("s0")));
+        assert!(names.contains(&String::from("new")));
+        assert!(!names.contains(&String::from("s1")));
+    }
+}
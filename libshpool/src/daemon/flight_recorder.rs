@@ -0,0 +1,121 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The flight recorder keeps track of a few bits of daemon state that
+  are not otherwise visible from the outside so that `shpool dump-state`
+  can report on them. This is meant to help with debugging hangs and
+  other misbehavior in a running daemon without having to attach a
+  debugger to it.
+*/
+
+use std::{collections::VecDeque, sync::Mutex, time};
+
+use serde_derive::Serialize;
+use shpool_protocol::MemoryUsage;
+
+/// How many errors the daemon remembers.
+const RECENT_ERRORS_CAPACITY: usize = 32;
+
+/// A bounded log of the most recent errors the daemon ran into.
+#[derive(Debug, Default)]
+pub struct ErrorRing {
+    errors: Mutex<VecDeque<RecordedError>>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecordedError {
+    pub at_unix_ms: i64,
+    pub context: String,
+    pub error: String,
+}
+
+impl ErrorRing {
+    /// Remember an error, forgetting the oldest one if we are full.
+    pub fn record(&self, context: &str, err: &anyhow::Error) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() == RECENT_ERRORS_CAPACITY {
+            errors.pop_front();
+        }
+        errors.push_back(RecordedError {
+            at_unix_ms: unix_ms(time::SystemTime::now()),
+            context: String::from(context),
+            error: format!("{:#}", err),
+        });
+    }
+
+    /// The recorded errors, oldest first.
+    pub fn snapshot(&self) -> Vec<RecordedError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The state dumped by `shpool dump-state`.
+#[derive(Serialize, Debug)]
+pub struct DaemonState {
+    pub version: String,
+    pub pid: u32,
+    pub dumped_at_unix_ms: i64,
+    pub total_connections: usize,
+    pub active_connections: usize,
+    /// Connections that have not sent their connect header yet.
+    pub pending_connections: usize,
+    /// Connections hung up on for not finishing the handshake in time.
+    pub handshake_timeouts: usize,
+    /// Connections closed on arrival because too many were pending.
+    pub rejected_connections: usize,
+    pub sessions: Vec<SessionState>,
+    pub recent_errors: Vec<RecordedError>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionState {
+    pub name: String,
+    pub status: String,
+    pub child_pid: i32,
+    pub started_at_unix_ms: i64,
+    pub reap_in_secs: Option<u64>,
+    pub restart_on_exit: bool,
+    pub output_bytes: u64,
+    pub shell_to_client_running: bool,
+    pub pager_active: bool,
+    pub memory: MemoryUsage,
+}
+
+pub fn unix_ms(t: time::SystemTime) -> i64 {
+    t.duration_since(time::UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn error_ring_is_bounded() {
+        let ring = ErrorRing::default();
+        for i in 0..(RECENT_ERRORS_CAPACITY + 5) {
+            ring.record("test", &anyhow!("error {}", i));
+        }
+
+        let errors = ring.snapshot();
+        assert_eq!(errors.len(), RECENT_ERRORS_CAPACITY);
+        assert_eq!(errors[0].error, "error 5");
+        assert_eq!(
+            errors[RECENT_ERRORS_CAPACITY - 1].error,
+            format!("error {}", RECENT_ERRORS_CAPACITY + 4)
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The umask and supplementary groups of spawned shells.
+//!
+//! Without any config, shells inherit these from the daemon, which
+//! inherits them from whatever launched it (often systemd), so they can
+//! differ from what the user would get from a direct login shell. Like
+//! the scheduling settings, these get worked out in the daemon and then
+//! applied in the child right before it execs the shell.
+
+use anyhow::{anyhow, Context};
+use nix::unistd::{self, Gid, Group};
+
+use crate::config::Config;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Resolved {
+    umask: Option<libc::mode_t>,
+    groups: Option<Vec<Gid>>,
+}
+
+pub fn resolve(config: &Config) -> anyhow::Result<Resolved> {
+    let groups = match &config.supplementary_groups {
+        Some(change) => {
+            let current = unistd::getgroups().context("getting current groups")?;
+            let to_gids = |names: &Option<Vec<String>>| -> anyhow::Result<Vec<Gid>> {
+                names.iter().flatten().map(|n| lookup_group(n)).collect()
+            };
+            Some(apply_change(current, &to_gids(&change.add)?, &to_gids(&change.drop)?))
+        }
+        None => None,
+    };
+
+    Ok(Resolved { umask: config.umask.map(|m| m as libc::mode_t), groups })
+}
+
+/// Work out a new group list, keeping the order of the existing groups.
+fn apply_change(mut groups: Vec<Gid>, add: &[Gid], drop: &[Gid]) -> Vec<Gid> {
+    for gid in add.iter() {
+        if !groups.contains(gid) {
+            groups.push(*gid);
+        }
+    }
+    groups.retain(|gid| !drop.contains(gid));
+    groups
+}
+
+fn lookup_group(name: &str) -> anyhow::Result<Gid> {
+    if let Ok(gid) = name.parse::<libc::gid_t>() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(name).with_context(|| format!("looking up group '{}'", name))? {
+        Some(group) => Ok(group.gid),
+        None => Err(anyhow!("no group named '{}'", name)),
+    }
+}
+
+impl Resolved {
+    /// Apply the settings to the current process. Meant to be called in
+    /// the child after the fork, so failures are printed to the pty.
+    pub fn apply(&self) {
+        if let Some(umask) = self.umask {
+            // Safety: basic ffi, umask can't fail.
+            unsafe {
+                libc::umask(umask);
+            }
+        }
+        if let Some(groups) = &self.groups {
+            if let Err(e) = unistd::setgroups(groups) {
+                eprintln!("shpool: setting supplementary groups: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_changes() {
+        let gids = |ids: &[u32]| ids.iter().map(|id| Gid::from_raw(*id)).collect::<Vec<_>>();
+        let cases = vec![
+            (gids(&[1, 2, 3]), gids(&[]), gids(&[]), gids(&[1, 2, 3])),
+            (gids(&[1, 2, 3]), gids(&[4, 2]), gids(&[]), gids(&[1, 2, 3, 4])),
+            (gids(&[1, 2, 3]), gids(&[]), gids(&[2]), gids(&[1, 3])),
+            (gids(&[1, 2, 3]), gids(&[5]), gids(&[5, 1]), gids(&[2, 3])),
+        ];
+        for (current, add, drop, want) in cases.into_iter() {
+            assert_eq!(apply_change(current, &add, &drop), want);
+        }
+    }
+
+    #[test]
+    fn lookup() -> anyhow::Result<()> {
+        assert_eq!(lookup_group("1234")?, Gid::from_raw(1234));
+        assert_eq!(lookup_group("root")?, Gid::from_raw(0));
+        assert!(lookup_group("shpool-no-such-group").is_err());
+        Ok(())
+    }
+}
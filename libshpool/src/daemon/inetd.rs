@@ -0,0 +1,75 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for `shpool daemon --inetd`, where whoever launched us has
+//! already accepted a connection and handed it over on stdin, the way
+//! inetd style superservers and systemd's Accept=yes sockets do.
+
+use std::{
+    fs,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::net::UnixStream,
+    },
+};
+
+use anyhow::{anyhow, Context};
+use nix::{
+    fcntl,
+    sys::{socket, socket::SockaddrLike as _, stat},
+    unistd,
+};
+
+use crate::consts::{STDERR_FD, STDIN_FD, STDOUT_FD};
+
+/// Take the connection passed on stdin. Superservers usually pass the
+/// same socket on stdout and stderr as well, so those get pointed at
+/// /dev/null to keep stray output, like logs, out of the protocol
+/// stream.
+pub fn take_stdin_conn() -> anyhow::Result<UnixStream> {
+    let stdin_stat = stat::fstat(STDIN_FD).context("stating stdin")?;
+    if !stat::SFlag::from_bits_truncate(stdin_stat.st_mode).contains(stat::SFlag::S_IFSOCK) {
+        return Err(anyhow!("--inetd needs a unix socket on stdin"));
+    }
+    let addr: socket::SockaddrStorage =
+        socket::getsockname(STDIN_FD).context("getting stdin socket address")?;
+    if addr.family() != Some(socket::AddressFamily::Unix) {
+        return Err(anyhow!("--inetd needs a unix socket on stdin, got {:?}", addr.family()));
+    }
+
+    // Move the connection off of stdin so that the shells we spawn
+    // can't inherit it.
+    let conn_fd = fcntl::fcntl(STDIN_FD, fcntl::FcntlArg::F_DUPFD_CLOEXEC(STDERR_FD + 1))
+        .context("duplicating stdin")?;
+    // Safety: we just made this fd, and checked that it is a unix socket.
+    let conn = unsafe { UnixStream::from_raw_fd(conn_fd) };
+
+    let stderr_is_conn = stat::fstat(STDERR_FD)
+        .map(|s| s.st_dev == stdin_stat.st_dev && s.st_ino == stdin_stat.st_ino)
+        .unwrap_or(false);
+    let devnull = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("opening /dev/null")?;
+    let mut fds = vec![STDIN_FD, STDOUT_FD];
+    if stderr_is_conn {
+        fds.push(STDERR_FD);
+    }
+    for fd in fds.into_iter() {
+        unistd::dup2(devnull.as_raw_fd(), fd).context("pointing std stream at /dev/null")?;
+    }
+
+    Ok(conn)
+}
@@ -202,6 +202,10 @@ impl Bindings {
 pub enum Action {
     /// detaches the current shpool session
     Detach,
+    /// redraws the screen of the attached client
+    Redraw,
+    /// types the daemon's clipboard into the session
+    Paste,
     /// does nothing, useful for testing the keybinding engine and not much else
     NoOp,
 }
@@ -509,6 +513,11 @@ mod test {
             ),
             (vec![("Ctrl-Space Ctrl-d", Action::Detach)], vec![0, 20, 4], BindingResult::NoMatch),
             (vec![("Ctrl-Space Ctrl-d", Action::Detach)], vec![0, 4, 20], BindingResult::NoMatch),
+            (
+                vec![("Ctrl-Space Ctrl-d", Action::Detach)],
+                vec![0xff, 0, 0xff, 0, 4],
+                BindingResult::Match(Action::Detach),
+            ),
             (
                 vec![("a b c", Action::Detach)],
                 ['a', 'b'].iter().map(|c| *c as u32 as u8).collect::<Vec<_>>(),
@@ -12,25 +12,59 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{env, os::unix::net::UnixListener, path::PathBuf};
+use std::{env, fs, os::unix::net::UnixStream, path::PathBuf, sync::Arc};
 
 use anyhow::Context;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-use crate::{config, consts, hooks};
+use crate::{clock::SystemClock, config, consts, control_sock, hooks};
 
+mod adopt_pid;
+pub mod audit;
+pub mod cancel;
+mod clipboard;
+pub mod cmd_policy;
+pub mod command;
+mod dbus;
 mod etc_environment;
+mod event_socket;
+mod exit_history;
 mod exit_notify;
+mod flight_recorder;
+mod identity;
+pub mod inetd;
 pub mod keybindings;
+// sock_diag is Linux only, see platform.rs
+#[cfg_attr(not(any(target_os = "linux", target_os = "android")), path = "net_stat_unsupported.rs")]
+mod net_stat;
+pub mod output_filter;
+mod output_pipe;
 mod pager;
-mod prompt;
+mod pam;
+mod perms;
+mod plugins;
+mod proc_stat;
+mod profile;
+pub mod prompt;
+mod pty_io;
+mod rate_limit;
+pub mod scheduling;
+mod selector;
 mod server;
 mod shell;
+mod shell_integration;
 mod show_motd;
 mod signals;
+mod spawn;
+mod state_file;
 mod systemd;
+pub mod threads;
 mod trie;
 mod ttl_reaper;
+mod utmp;
+
+#[cfg(feature = "testing")]
+pub use server::Server;
 
 #[instrument(skip_all)]
 pub fn run(
@@ -38,12 +72,20 @@ pub fn run(
     runtime_dir: PathBuf,
     hooks: Box<dyn hooks::Hooks + Send + Sync>,
     socket: PathBuf,
+    resurrect: bool,
+    fix_perms: bool,
+    inetd_conn: Option<UnixStream>,
 ) -> anyhow::Result<()> {
     if let Ok(daemonize) = env::var(consts::AUTODAEMONIZE_VAR) {
         if daemonize == "true" {
             env::remove_var(consts::AUTODAEMONIZE_VAR); // avoid looping
 
-            let pid_file = socket.with_file_name("daemonized-shpool.pid");
+            let pid_file = if control_sock::is_abstract(&socket) {
+                fs::create_dir_all(&runtime_dir).context("ensuring runtime dir exists")?;
+                runtime_dir.join("daemonized-shpool.pid")
+            } else {
+                socket.with_file_name("daemonized-shpool.pid")
+            };
 
             info!("daemonizing with pid_file={:?}", pid_file);
             daemonize::Daemonize::new().pid_file(pid_file).start().context("daemonizing")?;
@@ -52,7 +94,59 @@ pub fn run(
 
     info!("\n\n======================== STARTING DAEMON ============================\n\n");
 
-    let server = server::Server::new(config_manager, hooks, runtime_dir)?;
+    // A single connection daemon never listens on the socket, so
+    // guarding it is up to whoever accepted the connection.
+    let listen_socket = if inetd_conn.is_some() { None } else { Some(socket.as_path()) };
+    perms::check_at_startup(&runtime_dir, listen_socket, fix_perms)?;
+
+    // Read the state file before the server gets a chance to overwrite it.
+    let resurrectable = if resurrect {
+        state_file::read(&state_file::path(&runtime_dir)).context("reading state file")?
+    } else {
+        vec![]
+    };
+
+    let server = server::Server::new(
+        config_manager,
+        hooks,
+        runtime_dir.clone(),
+        socket.clone(),
+        Arc::new(SystemClock),
+    )?;
+    if !resurrectable.is_empty() {
+        info!("resurrecting {} sessions", resurrectable.len());
+        let reply = server.import_sessions(resurrectable).context("resurrecting sessions")?;
+        if !reply.already_exists.is_empty() {
+            warn!("skipped resurrecting existing sessions: {:?}", reply.already_exists);
+        }
+        if !reply.forbidden.is_empty() {
+            warn!("skipped resurrecting sessions forbidden by cmd_policy: {:?}", reply.forbidden);
+        }
+        for failure in reply.failed.iter() {
+            warn!("failed to resurrect '{}': {}", failure.name, failure.error);
+        }
+        if !reply.failed.is_empty() {
+            // The state file writer is about to replace the file with
+            // just the sessions that made it, so hang on to the old one.
+            let state_file_path = state_file::path(&runtime_dir);
+            match state_file::keep_backup(&state_file_path) {
+                Ok(backup) => warn!("kept the old state file at {:?}", backup),
+                Err(e) => warn!("keeping the old state file: {:?}", e),
+            }
+        }
+    }
+    server.start_state_file_writer()?;
+
+    // Nothing outlives a single connection daemon, so there is no point
+    // in sessions or services that are meant to be long lived.
+    if let Some(conn) = inetd_conn {
+        signals::Handler::new(None, Arc::clone(&server)).spawn()?;
+        server::Server::serve_one(server, conn);
+        return Ok(());
+    }
+
+    server::Server::start_autostart_sessions(&server);
+    dbus::start(&server);
 
     let (cleanup_socket, listener) = match systemd::activation_socket() {
         Ok(l) => {
@@ -61,19 +155,57 @@ pub fn run(
         }
         Err(e) => {
             info!("no systemd activation socket: {:?}", e);
-            (Some(socket.clone()), UnixListener::bind(&socket).context("binding to socket")?)
+            let listener = control_sock::bind(&socket).context("binding to socket")?;
+            perms::restrict_socket(&socket)?;
+            // Abstract sockets vanish along with the last fd referring
+            // to them, so there is no file to clean up.
+            let cleanup_socket =
+                if control_sock::is_abstract(&socket) { None } else { Some(socket.clone()) };
+            (cleanup_socket, listener)
         }
     };
+    perms::spawn_rechecker(runtime_dir, socket.clone(), fix_perms)?;
     // spawn the signal handler thread in the background
-    signals::Handler::new(cleanup_socket.clone()).spawn()?;
+    signals::Handler::new(cleanup_socket.clone(), Arc::clone(&server)).spawn()?;
 
     server::Server::serve(server, listener)?;
 
     if let Some(sock) = cleanup_socket {
         std::fs::remove_file(sock).context("cleaning up socket on exit")?;
     } else {
-        info!("systemd manages the socket, so not cleaning it up");
+        info!("socket is managed by systemd or abstract, so not cleaning it up");
     }
 
     Ok(())
 }
+
+/// Start a daemon serving on the given listener from a background
+/// thread, without daemonizing or installing signal handlers, so that
+/// it can live inside of a test binary. Used by the harness in the
+/// testing module, which gets the server back so that it can stand in
+/// for the signal handler.
+#[cfg(feature = "testing")]
+pub fn start_in_process(
+    config_manager: config::Manager,
+    runtime_dir: PathBuf,
+    hooks: Box<dyn hooks::Hooks + Send + Sync>,
+    socket: PathBuf,
+    listener: std::os::unix::net::UnixListener,
+    clock: Arc<dyn crate::clock::Clock>,
+) -> anyhow::Result<Arc<server::Server>> {
+    let server = server::Server::new(config_manager, hooks, runtime_dir, socket, clock)?;
+    server.start_state_file_writer()?;
+    server::Server::start_autostart_sessions(&server);
+    std::thread::Builder::new()
+        .name(String::from("in-process-daemon"))
+        .spawn({
+            let server = Arc::clone(&server);
+            move || {
+                if let Err(e) = server::Server::serve(server, listener) {
+                    warn!("in-process daemon: {:?}", e);
+                }
+            }
+        })
+        .context("spawning in-process daemon")?;
+    Ok(server)
+}
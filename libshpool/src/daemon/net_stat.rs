@@ -0,0 +1,335 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Rough network accounting for sessions, for `shpool list --long`.
+
+  The kernel doesn't keep network counters per process, and the ones
+  under /proc/<pid>/net are for the whole network namespace, so we
+  piece together an estimate instead. We ask the kernel for every TCP
+  socket along with the byte counts it keeps for each one using the
+  sock_diag netlink interface, then match the sockets up with the
+  processes that have them open by looking through /proc/<pid>/fd,
+  and group the processes by unix session id like `proc_stat` does.
+
+  This only covers TCP sockets that are still open in the daemon's
+  network namespace, so traffic from connections that have already
+  closed, UDP traffic, and sessions running inside a container don't
+  show up. That is still enough to point at the session whose rsync is
+  hogging the VPN.
+*/
+
+use std::{
+    collections::HashMap,
+    fs, io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use anyhow::{anyhow, Context};
+use tracing::warn;
+
+use crate::daemon::proc_stat;
+
+/// Bytes sent and received over a set of sockets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetBytes {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+// From linux/sock_diag.h and linux/inet_diag.h, which the libc crate
+// doesn't cover.
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_INFO: u16 = 2;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HDR_LEN: usize = 16;
+const INET_DIAG_REQ_LEN: usize = 56;
+const INET_DIAG_MSG_LEN: usize = 72;
+const INET_DIAG_MSG_INODE_OFFSET: usize = 68;
+const RTATTR_HDR_LEN: usize = 4;
+// Offsets of tcpi_bytes_acked and tcpi_bytes_received in struct
+// tcp_info. Kernels older than 4.2 don't have them.
+const TCPI_BYTES_ACKED_OFFSET: usize = 120;
+const TCPI_BYTES_RECEIVED_OFFSET: usize = 128;
+
+/// Estimate how much each unix session has sent and received over the
+/// network, keyed by session id.
+pub fn net_bytes_by_session() -> HashMap<i32, NetBytes> {
+    let mut by_inode = HashMap::new();
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        if let Err(e) = dump_tcp(family as u8, &mut by_inode) {
+            warn!("dumping tcp sockets for family {}: {:?}", family, e);
+            return HashMap::new();
+        }
+    }
+
+    let mut by_session: HashMap<i32, NetBytes> = HashMap::new();
+    for (inode, sid) in socket_sessions().into_iter() {
+        if let Some(bytes) = by_inode.get(&inode) {
+            let total = by_session.entry(sid).or_default();
+            total.rx += bytes.rx;
+            total.tx += bytes.tx;
+        }
+    }
+    by_session
+}
+
+/// Map the inode of every socket that a process has open to the
+/// session id of the process. A socket shared between processes in
+/// different sessions gets counted for just one of them.
+fn socket_sessions() -> HashMap<u32, i32> {
+    let mut sessions = HashMap::new();
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("listing /proc: {:?}", e);
+            return sessions;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if !name.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        // processes can exit out from under us, and we can't look at
+        // the fds of other users' processes, so errors are expected
+        let Some(sid) = fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| proc_stat::parse_stat(&stat))
+            .map(|(sid, _)| sid)
+        else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(entry.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if let Some(inode) =
+                fs::read_link(fd.path()).ok().and_then(|t| parse_socket_link(&t.to_string_lossy()))
+            {
+                sessions.insert(inode, sid);
+            }
+        }
+    }
+    sessions
+}
+
+/// Pull the inode out of a "socket:[1234]" fd link.
+fn parse_socket_link(target: &str) -> Option<u32> {
+    target.strip_prefix("socket:[")?.strip_suffix(']')?.parse().ok()
+}
+
+/// Ask the kernel for all the TCP sockets of the given address family,
+/// adding their byte counts to `by_inode`.
+fn dump_tcp(family: u8, by_inode: &mut HashMap<u32, NetBytes>) -> anyhow::Result<()> {
+    // Safety: plain ffi, and we check the result before using it.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("opening sock_diag socket");
+    }
+    // Safety: we just opened the fd and nothing else owns it.
+    let sock = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let req = dump_request(family);
+    // Safety: the kernel address is all zeros apart from the family,
+    //         and the buffers outlive the calls.
+    let sent = unsafe {
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        libc::sendto(
+            sock.as_raw_fd(),
+            req.as_ptr() as *const libc::c_void,
+            req.len(),
+            0,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error()).context("sending sock_diag request");
+    }
+
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        // Safety: the buffer is valid for its whole length.
+        let len = unsafe {
+            libc::recv(sock.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error()).context("reading sock_diag reply");
+        }
+        if parse_reply(&buf[..len as usize], by_inode)? {
+            return Ok(());
+        }
+    }
+}
+
+/// An nlmsghdr followed by an inet_diag_req_v2 asking for every TCP
+/// socket in any state, along with its tcp_info.
+fn dump_request(family: u8) -> Vec<u8> {
+    let mut req = Vec::with_capacity(NLMSG_HDR_LEN + INET_DIAG_REQ_LEN);
+    req.extend_from_slice(&((NLMSG_HDR_LEN + INET_DIAG_REQ_LEN) as u32).to_ne_bytes());
+    req.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    req.extend_from_slice(&((libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16).to_ne_bytes());
+    req.extend_from_slice(&1u32.to_ne_bytes()); // seq
+    req.extend_from_slice(&0u32.to_ne_bytes()); // pid
+
+    req.push(family);
+    req.push(libc::IPPROTO_TCP as u8);
+    req.push(1 << (INET_DIAG_INFO - 1)); // ext
+    req.push(0); // pad
+    req.extend_from_slice(&u32::MAX.to_ne_bytes()); // states
+    req.resize(NLMSG_HDR_LEN + INET_DIAG_REQ_LEN, 0); // the socket id, unused for dumps
+    req
+}
+
+/// Parse one datagram of a sock_diag dump, returning true once the
+/// end of the dump shows up.
+fn parse_reply(mut buf: &[u8], by_inode: &mut HashMap<u32, NetBytes>) -> anyhow::Result<bool> {
+    while buf.len() >= NLMSG_HDR_LEN {
+        let msg_len = u32_at(buf, 0) as usize;
+        let msg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+        if msg_len < NLMSG_HDR_LEN || msg_len > buf.len() {
+            return Err(anyhow!("bad netlink message length {}", msg_len));
+        }
+        let payload = &buf[NLMSG_HDR_LEN..msg_len];
+
+        match msg_type {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = payload.get(..4).map(|e| i32::from_ne_bytes([e[0], e[1], e[2], e[3]]));
+                return Err(io::Error::from_raw_os_error(-errno.unwrap_or(-libc::EIO)).into());
+            }
+            SOCK_DIAG_BY_FAMILY if payload.len() >= INET_DIAG_MSG_LEN => {
+                let inode = u32_at(payload, INET_DIAG_MSG_INODE_OFFSET);
+                if let Some(bytes) = tcp_info_bytes(&payload[INET_DIAG_MSG_LEN..]) {
+                    by_inode.insert(inode, bytes);
+                }
+            }
+            _ => {}
+        }
+
+        buf = &buf[align(msg_len).min(buf.len())..];
+    }
+    Ok(false)
+}
+
+/// Find the INET_DIAG_INFO attribute and pull the byte counts out of
+/// the tcp_info inside it.
+fn tcp_info_bytes(mut attrs: &[u8]) -> Option<NetBytes> {
+    while attrs.len() >= RTATTR_HDR_LEN {
+        let attr_len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if attr_len < RTATTR_HDR_LEN || attr_len > attrs.len() {
+            return None;
+        }
+        let data = &attrs[RTATTR_HDR_LEN..attr_len];
+        if attr_type == INET_DIAG_INFO && data.len() >= TCPI_BYTES_RECEIVED_OFFSET + 8 {
+            return Some(NetBytes {
+                rx: u64_at(data, TCPI_BYTES_RECEIVED_OFFSET),
+                tx: u64_at(data, TCPI_BYTES_ACKED_OFFSET),
+            });
+        }
+        attrs = &attrs[align(attr_len).min(attrs.len())..];
+    }
+    None
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nlmsg(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let mut msg = vec![];
+        msg.extend_from_slice(&((NLMSG_HDR_LEN + payload.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&msg_type.to_ne_bytes());
+        msg.extend_from_slice(&[0; 10]);
+        msg.extend_from_slice(payload);
+        msg.resize(align(msg.len()), 0);
+        msg
+    }
+
+    fn diag_msg(inode: u32, rx: u64, tx: u64) -> Vec<u8> {
+        let mut msg = vec![0; INET_DIAG_MSG_LEN];
+        msg[INET_DIAG_MSG_INODE_OFFSET..].copy_from_slice(&inode.to_ne_bytes());
+        let mut info = vec![0; TCPI_BYTES_RECEIVED_OFFSET + 8];
+        info[TCPI_BYTES_ACKED_OFFSET..TCPI_BYTES_ACKED_OFFSET + 8]
+            .copy_from_slice(&tx.to_ne_bytes());
+        info[TCPI_BYTES_RECEIVED_OFFSET..].copy_from_slice(&rx.to_ne_bytes());
+        // some other attribute first
+        msg.extend_from_slice(&8u16.to_ne_bytes());
+        msg.extend_from_slice(&5u16.to_ne_bytes());
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(&((RTATTR_HDR_LEN + info.len()) as u16).to_ne_bytes());
+        msg.extend_from_slice(&INET_DIAG_INFO.to_ne_bytes());
+        msg.extend_from_slice(&info);
+        msg
+    }
+
+    #[test]
+    fn parsing() -> anyhow::Result<()> {
+        let mut by_inode = HashMap::new();
+
+        let mut buf = nlmsg(SOCK_DIAG_BY_FAMILY, &diag_msg(11, 100, 200));
+        buf.extend(nlmsg(SOCK_DIAG_BY_FAMILY, &diag_msg(12, 5, 6)));
+        assert!(!parse_reply(&buf, &mut by_inode)?);
+
+        assert!(parse_reply(&nlmsg(NLMSG_DONE, &[0; 4]), &mut by_inode)?);
+        assert_eq!(by_inode.get(&11), Some(&NetBytes { rx: 100, tx: 200 }));
+        assert_eq!(by_inode.get(&12), Some(&NetBytes { rx: 5, tx: 6 }));
+
+        let err = parse_reply(&nlmsg(NLMSG_ERROR, &(-libc::EPERM).to_ne_bytes()), &mut by_inode);
+        assert!(err.is_err());
+
+        // an old kernel with a short tcp_info
+        let mut old = diag_msg(13, 1, 1);
+        old.truncate(INET_DIAG_MSG_LEN + 8 + RTATTR_HDR_LEN + 100);
+        old[INET_DIAG_MSG_LEN + 8..INET_DIAG_MSG_LEN + 10]
+            .copy_from_slice(&((RTATTR_HDR_LEN + 100) as u16).to_ne_bytes());
+        assert!(!parse_reply(&nlmsg(SOCK_DIAG_BY_FAMILY, &old), &mut by_inode)?);
+        assert_eq!(by_inode.get(&13), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn socket_links() {
+        assert_eq!(parse_socket_link("socket:[123456]"), Some(123456));
+        assert_eq!(parse_socket_link("pipe:[123456]"), None);
+        assert_eq!(parse_socket_link("/dev/pts/3"), None);
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Network accounting is built on Linux's sock_diag netlink interface,
+//! so on other platforms sessions just report no network traffic. See
+//! `net_stat.rs` for the real thing.
+
+use std::collections::HashMap;
+
+use tracing::warn;
+
+/// Bytes sent and received over a set of sockets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NetBytes {
+    pub rx: u64,
+    pub tx: u64,
+}
+
+pub fn net_bytes_by_session() -> HashMap<i32, NetBytes> {
+    warn!("network accounting is not supported on {}", std::env::consts::OS);
+    HashMap::new()
+}
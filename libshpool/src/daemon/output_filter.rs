@@ -0,0 +1,211 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scrubbing session output with the regex rules from the `filters`
+//! config, for things like redacting tokens that CI tools print.
+//!
+//! Output arrives in whatever chunks the pty hands us, so a match can
+//! get split between two reads. To catch those, when a read fills our
+//! whole buffer (meaning the shell is in the middle of a burst of
+//! output), we hold back the last `max_match_len` bytes and scan them
+//! again along with the next chunk. Short reads mean the shell has
+//! paused, so we let everything out then, which keeps interactive
+//! output like keystroke echo from lagging.
+
+use anyhow::{anyhow, Context};
+use regex::bytes::Regex;
+
+use crate::config::OutputFilters;
+
+const DEFAULT_MAX_MATCH_LEN: usize = 256;
+const DEFAULT_REPLACEMENT: &str = "[redacted]";
+
+/// The filter rules for a session, along with any output being held
+/// back while we wait to see if it is the start of a match.
+pub struct OutputFilter {
+    /// All of the rules, combined into a single regex with one
+    /// capture group wrapped around each rule.
+    re: Regex,
+    /// The capture group index and replacement text for each rule.
+    replacements: Vec<(usize, Vec<u8>)>,
+    max_match_len: usize,
+    held: Vec<u8>,
+}
+
+/// Compile the filter rules, returning None if there are none.
+pub fn compile(filters: &OutputFilters) -> anyhow::Result<Option<OutputFilter>> {
+    let rules = filters.rules.as_deref().unwrap_or(&[]);
+    if rules.is_empty() {
+        return Ok(None);
+    }
+
+    let mut combined = vec![];
+    let mut replacements = vec![];
+    // group 0 is the whole match
+    let mut group = 1;
+    for rule in rules.iter() {
+        let re =
+            Regex::new(&rule.pattern).with_context(|| format!("compiling '{}'", rule.pattern))?;
+        if re.is_match(b"") {
+            return Err(anyhow!("filter pattern '{}' matches the empty string", rule.pattern));
+        }
+        combined.push(format!("({})", rule.pattern));
+        replacements.push((
+            group,
+            rule.replacement.as_deref().unwrap_or(DEFAULT_REPLACEMENT).as_bytes().to_vec(),
+        ));
+        // skip over our group and any groups in the rule's own pattern
+        group += re.captures_len();
+    }
+
+    Ok(Some(OutputFilter {
+        re: Regex::new(&combined.join("|")).context("combining filter patterns")?,
+        replacements,
+        max_match_len: filters.max_match_len.unwrap_or(DEFAULT_MAX_MATCH_LEN),
+        held: vec![],
+    }))
+}
+
+impl OutputFilter {
+    /// Filter a chunk of output, returning what is ready to pass on.
+    /// If `more_coming` is set, the tail of the output may be held back
+    /// to be scanned along with the next chunk.
+    pub fn process(&mut self, chunk: &[u8], more_coming: bool) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.held);
+        buf.extend_from_slice(chunk);
+
+        // A match that starts before this point either fits in what we
+        // have or is longer than we promise to catch.
+        let safe_end =
+            if more_coming { buf.len().saturating_sub(self.max_match_len) } else { buf.len() };
+
+        let mut out = Vec::with_capacity(buf.len());
+        let mut cursor = 0;
+        for caps in self.re.captures_iter(&buf) {
+            let m = caps.get(0).expect("group 0 is always the whole match");
+            if m.start() >= safe_end {
+                break;
+            }
+            out.extend_from_slice(&buf[cursor..m.start()]);
+            if let Some((_, replacement)) =
+                self.replacements.iter().find(|(group, _)| caps.get(*group).is_some())
+            {
+                out.extend_from_slice(replacement);
+            }
+            cursor = m.end();
+        }
+
+        let held_from = cursor.max(safe_end);
+        out.extend_from_slice(&buf[cursor..held_from]);
+        self.held = buf.split_off(held_from);
+        out
+    }
+
+    /// How much output is being held back.
+    pub fn held_len(&self) -> usize {
+        self.held.len()
+    }
+
+    /// Let out anything being held back.
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.process(&[], false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::OutputFilterRule;
+
+    fn filter(rules: &[(&str, Option<&str>)], max_match_len: usize) -> OutputFilter {
+        compile(&OutputFilters {
+            rules: Some(
+                rules
+                    .iter()
+                    .map(|(p, r)| OutputFilterRule {
+                        pattern: String::from(*p),
+                        replacement: r.map(String::from),
+                    })
+                    .collect(),
+            ),
+            max_match_len: Some(max_match_len),
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    #[test]
+    fn compiling() {
+        assert!(compile(&OutputFilters::default()).unwrap().is_none());
+        let bad = |pattern: &str| {
+            compile(&OutputFilters {
+                rules: Some(vec![OutputFilterRule {
+                    pattern: String::from(pattern),
+                    replacement: None,
+                }]),
+                max_match_len: None,
+            })
+            .is_err()
+        };
+        assert!(bad("(unclosed"));
+        assert!(bad("x*"));
+        assert!(!bad("x+"));
+    }
+
+    #[test]
+    fn replacing() {
+        let mut f = filter(
+            &[
+                (r"ghp_[A-Za-z0-9]{8}", None),
+                (r"(password|passwd)=(\S+)", Some("password=***")),
+                (r"secret", Some("$1 literal")),
+            ],
+            64,
+        );
+        assert_eq!(
+            f.process(b"token ghp_abcd1234 and password=hunter2 and secret\r\n", false),
+            b"token [redacted] and password=*** and $1 literal\r\n"
+        );
+        assert_eq!(f.process(b"nothing to see here", false), b"nothing to see here");
+    }
+
+    #[test]
+    fn split_matches() {
+        let mut f = filter(&[(r"ghp_[A-Za-z0-9]{8}", None)], 16);
+
+        let mut out = f.process(b"0123456789abcdef0123456789 ghp_ab", true);
+        // the start of the token is held back
+        assert_eq!(out, b"0123456789abcdef0");
+        out.extend(f.process(b"cd1234 done", false));
+        assert_eq!(out, b"0123456789abcdef0123456789 [redacted] done");
+
+        // held output comes out on a flush
+        let out = f.process(b"short ghp_", true);
+        assert!(out.is_empty());
+        assert_eq!(f.flush(), b"short ghp_");
+        assert!(f.flush().is_empty());
+    }
+
+    #[test]
+    fn byte_at_a_time() {
+        let mut f = filter(&[(r"ghp_[A-Za-z0-9]{8}", Some("X"))], 16);
+        let input = b"a ghp_abcd1234 b ghp_efgh5678 c";
+        let mut out = vec![];
+        for byte in input.iter() {
+            out.extend(f.process(&[*byte], true));
+        }
+        out.extend(f.flush());
+        assert_eq!(out, b"a X b X c");
+    }
+}
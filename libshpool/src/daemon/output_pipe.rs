@@ -0,0 +1,164 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read only copies of a session's output for `shpool pipe`.
+//!
+//! The shell->client thread hands each chunk of output to every pipe
+//! on the session, and each pipe has its own thread to write it out.
+//! A pipe that can't keep up must never slow down the shell or the
+//! attached client, so rather than blocking, the shell->client thread
+//! drops output for a pipe once it has too much queued up, and the
+//! pipe gets a notice saying how much it missed.
+
+use std::{io, io::Write, os::unix::net::UnixStream};
+
+use anyhow::Context;
+use shpool_protocol::{Chunk, ChunkKind};
+use tracing::{info, instrument, span, warn, Level};
+
+use crate::{config, daemon::threads, protocol};
+
+/// How many chunks of output may be waiting to go out to a pipe
+/// before we start dropping output for it.
+const QUEUE_CHUNKS: usize = 256;
+
+/// A request to start piping a session's output to a client.
+pub struct PipeConn {
+    pub stream: UnixStream,
+    /// Start the pipe off with the session's scrollback.
+    pub from_start: bool,
+}
+
+enum Msg {
+    Data(Vec<u8>),
+    Notice(String),
+    Exit(i32),
+}
+
+/// The shell->client thread's handle on a pipe.
+pub struct OutputPipe {
+    tx: crossbeam_channel::Sender<Msg>,
+    /// How many bytes have been dropped since the pipe last kept up.
+    dropped_bytes: usize,
+}
+
+impl OutputPipe {
+    /// Spawn the thread that writes to the pipe's client.
+    #[instrument(skip_all, fields(s = session))]
+    pub fn spawn(
+        session: &str,
+        stream: UnixStream,
+        config: &config::Manager,
+    ) -> anyhow::Result<Self> {
+        let (tx, rx) = crossbeam_channel::bounded(QUEUE_CHUNKS);
+        let name = String::from(session);
+        threads::for_session("pipe", session, config)
+            .spawn(move || {
+                let _s = span!(Level::INFO, "pipe", s = name).entered();
+                match write_loop(rx, stream) {
+                    Ok(()) => info!("pipe done"),
+                    Err(e) => info!("pipe hung up: {:?}", e),
+                }
+            })
+            .context("spawning pipe writer")?;
+        Ok(OutputPipe { tx, dropped_bytes: 0 })
+    }
+
+    /// Queue a chunk of output for the pipe. Returns false once the
+    /// pipe's client has gone away, after which the pipe should be
+    /// dropped.
+    pub fn send(&mut self, buf: &[u8]) -> bool {
+        if self.dropped_bytes > 0 {
+            let notice =
+                format!("pipe fell behind, dropped {} bytes of output", self.dropped_bytes);
+            match self.tx.try_send(Msg::Notice(notice)) {
+                Ok(()) => self.dropped_bytes = 0,
+                Err(crossbeam_channel::TrySendError::Full(_)) => {
+                    self.dropped_bytes += buf.len();
+                    return true;
+                }
+                Err(crossbeam_channel::TrySendError::Disconnected(_)) => return false,
+            }
+        }
+
+        match self.tx.try_send(Msg::Data(buf.to_vec())) {
+            Ok(()) => true,
+            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                self.dropped_bytes += buf.len();
+                true
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Let the pipe's client know that the shell exited. Blocks until
+    /// there is room in the queue, since the shell is gone and there
+    /// is nothing left to slow down.
+    pub fn exit(self, status: i32) {
+        if self.tx.send(Msg::Exit(status)).is_err() {
+            info!("pipe already gone, not sending exit status");
+        }
+    }
+}
+
+fn write_loop(rx: crossbeam_channel::Receiver<Msg>, stream: UnixStream) -> anyhow::Result<()> {
+    let frames = protocol::FrameWriter::new(false);
+    let mut sink = io::BufWriter::new(stream.try_clone().context("cloning pipe stream")?);
+    // The queue disconnects when the session goes away without its
+    // shell exiting, such as when the daemon shuts down.
+    for msg in rx.iter() {
+        let res = match msg {
+            Msg::Data(buf) => frames.write(&mut sink, &Chunk { kind: ChunkKind::Data, buf: &buf }),
+            Msg::Notice(notice) => {
+                warn!("{}", notice);
+                frames.write(&mut sink, &Chunk { kind: ChunkKind::Notice, buf: notice.as_bytes() })
+            }
+            Msg::Exit(status) => {
+                let status_buf = status.to_le_bytes();
+                frames.write(&mut sink, &Chunk { kind: ChunkKind::ExitStatus, buf: &status_buf })
+            }
+        };
+        res.and_then(|_| sink.flush()).context("writing to pipe")?;
+    }
+    stream.shutdown(std::net::Shutdown::Both).context("shutting down pipe stream")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drops_output_when_full() {
+        let (tx, rx) = crossbeam_channel::bounded(2);
+        let mut pipe = OutputPipe { tx, dropped_bytes: 0 };
+
+        assert!(pipe.send(b"a"));
+        assert!(pipe.send(b"bb"));
+        assert!(pipe.send(b"ccc"));
+        assert_eq!(pipe.dropped_bytes, 3);
+
+        // once there is room again, the reader hears about the gap
+        // before the next chunk of output
+        assert!(matches!(rx.recv(), Ok(Msg::Data(d)) if d == b"a"));
+        assert!(matches!(rx.recv(), Ok(Msg::Data(d)) if d == b"bb"));
+        assert!(pipe.send(b"dddd"));
+        assert_eq!(pipe.dropped_bytes, 0);
+        assert!(matches!(rx.recv(), Ok(Msg::Notice(n)) if n.contains("dropped 3 bytes")));
+        assert!(matches!(rx.recv(), Ok(Msg::Data(d)) if d == b"dddd"));
+
+        drop(rx);
+        assert!(!pipe.send(b"e"));
+    }
+}
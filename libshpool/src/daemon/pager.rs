@@ -49,7 +49,7 @@ use nix::{poll, sys::signal, unistd};
 use shpool_protocol::{Chunk, ChunkKind, TtySize};
 use tracing::{error, info, instrument, span, trace, warn, Level};
 
-use crate::{consts, protocol::ChunkExt as _, tty::TtySizeExt as _};
+use crate::{consts, daemon::pty_io, platform, protocol, tty::TtySizeExt as _};
 
 // poll relatively quickly to pick up pager exits reasonably fast,
 // but still slow enough to spend most of the time parked.
@@ -98,6 +98,8 @@ impl Pager {
         &self,
         // The client connection on which to display the pager.
         client_stream: &mut UnixStream,
+        // How chunks are framed on the client connection.
+        frames: &protocol::FrameWriter,
         // The slot to install the control handle in
         ctl_slot: Arc<Mutex<Option<PagerCtl>>>,
         // The size of the tty to start off with
@@ -137,7 +139,10 @@ impl Pager {
         // and execing the pty wrapped pager in the child.
         info!("forking pager pty proc");
         let fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
-        if fork.is_child().is_ok() {
+        if let Ok(slave) = fork.is_child() {
+            if let Some(fd) = slave.borrow_fd() {
+                let _ = platform::acquire_controlling_tty(fd);
+            }
             for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
                 let _ = nix::unistd::close(fd);
             }
@@ -151,24 +156,27 @@ impl Pager {
 
         let pager_exited_ref = Arc::clone(&pager_exited);
         let waitable_child = fork.clone();
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "pager_exit_monitor").entered();
-            match waitable_child.wait_for_exit() {
-                Ok((_, Some(exit_status))) => {
-                    info!("child pager exited with status {}", exit_status);
-                    pager_exited_ref.store(true, Ordering::Relaxed);
-                }
-                Ok((_, None)) => {
-                    info!("child pager exited without status");
-                    pager_exited_ref.store(true, Ordering::Relaxed);
-                }
-                Err(e) => {
-                    info!("error waiting on pager child: {:?}", e);
-                    pager_exited_ref.store(true, Ordering::Relaxed);
+        thread::Builder::new()
+            .name(String::from("pager-wait"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "pager_exit_monitor").entered();
+                match waitable_child.wait_for_exit() {
+                    Ok((_, Some(exit_status))) => {
+                        info!("child pager exited with status {}", exit_status);
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
+                    Ok((_, None)) => {
+                        info!("child pager exited without status");
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        info!("error waiting on pager child: {:?}", e);
+                        pager_exited_ref.store(true, Ordering::Relaxed);
+                    }
                 }
-            }
-            info!("reaped child pager: {:?}", waitable_child);
-        });
+                info!("reaped child pager: {:?}", waitable_child);
+            })
+            .context("spawning pager exit monitor")?;
 
         let mut pty_master = fork.is_parent().context("getting pty_master handle")?;
 
@@ -180,35 +188,39 @@ impl Pager {
         let tty_size = Arc::new(Mutex::new(init_tty_size.clone()));
         let tty_size_ref = Arc::clone(&tty_size);
         info!("spawning pager size change listener");
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "pager_size_change").entered();
-
-            // We could also set things up to handle detach commands, but
-            // since pagers don't stick around when the client hangs up
-            // it is not really that importaint. Let's KISS.
-            while let Ok(size) = tty_size_change_rx.recv() {
-                info!("recvd new size: {:?}", size);
-                if let Err(e) = size.set_fd(pty_master_fd) {
-                    warn!("setting pager size: {:?}", e);
-                }
+        thread::Builder::new()
+            .name(String::from("pager-resize"))
+            .spawn(move || {
+                let _s = span!(Level::INFO, "pager_size_change").entered();
+
+                // We could also set things up to handle detach commands, but
+                // since pagers don't stick around when the client hangs up
+                // it is not really that importaint. Let's KISS.
+                while let Ok(size) = tty_size_change_rx.recv() {
+                    info!("recvd new size: {:?}", size);
+                    if let Err(e) = size.set_fd(pty_master_fd) {
+                        warn!("setting pager size: {:?}", e);
+                    }
 
-                {
-                    // register the new size so it will get returned
-                    let mut tty_size = tty_size_ref.lock().unwrap();
-                    *tty_size = size;
-                }
+                    {
+                        // register the new size so it will get returned
+                        let mut tty_size = tty_size_ref.lock().unwrap();
+                        *tty_size = size;
+                    }
 
-                if let Err(e) = tty_size_change_ack_tx.send(()) {
-                    error!("could not send size change ack: {:?}", e);
-                    break;
+                    if let Err(e) = tty_size_change_ack_tx.send(()) {
+                        error!("could not send size change ack: {:?}", e);
+                        break;
+                    }
                 }
-            }
-            info!("pager size change loop done");
-        });
+                info!("pager size change loop done");
+            })
+            .context("spawning pager size change listener")?;
 
         let mut last_heartbeat_at = Instant::now();
         let mut buf = vec![0; consts::BUF_SIZE];
         let watchable_master = pty_master;
+        let mut pty_reader = pty_io::Master::new(&pty_master)?;
         let watchable_client_stream =
             client_stream.try_clone().context("could not clone client stream")?;
         loop {
@@ -239,7 +251,7 @@ impl Pager {
                     last_heartbeat_at = now;
 
                     let chunk = Chunk { kind: ChunkKind::Heartbeat, buf: &[] };
-                    match chunk.write_to(client_stream).and_then(|_| client_stream.flush()) {
+                    match frames.write(client_stream, &chunk).and_then(|_| client_stream.flush()) {
                         Ok(_) => {
                             trace!("wrote heartbeat");
                         }
@@ -260,9 +272,17 @@ impl Pager {
 
                 if pty_master_poll_fd.any().unwrap_or(false) {
                     // the pager process has some data for us
-                    let len = pty_master.read(&mut buf).context("reading chunk from pty master")?;
+                    let len = pty_reader.read(&mut buf).context("reading chunk from pty master")?;
+                    if len == 0 {
+                        if pty_reader.is_eof() {
+                            info!("pager hung up the pty");
+                            let tty_size = tty_size.lock().unwrap();
+                            return Ok(tty_size.clone());
+                        }
+                        continue;
+                    }
                     let chunk = Chunk { kind: ChunkKind::Data, buf: &buf[..len] };
-                    match chunk.write_to(client_stream).and_then(|_| client_stream.flush()) {
+                    match frames.write(client_stream, &chunk).and_then(|_| client_stream.flush()) {
                         Ok(_) => {}
                         Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
                             trace!("client hangup writing data chunk: {:?}", e);
@@ -0,0 +1,456 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Optional PAM sessions around spawned shells, so that things like
+  limits.conf, loginuid and logind accounting apply to pooled shells
+  the same way they do to real logins.
+
+  The daemon opens the session right before it spawns the shell and
+  holds on to the PAM handle until the shell exits, then closes the
+  session with that same handle, so modules that carry state from open
+  to close through their handle see it. Nothing PAM related runs in
+  the child. The daemon has a lot of threads, so the child of a fork
+  may only make async-signal-safe calls before it execs, which libpam
+  and the modules it loads certainly don't stick to.
+
+  Most session modules act on the process that opens the session
+  (pam_limits sets rlimits on it, pam_loginuid writes its loginuid,
+  pam_systemd moves it into a new scope), which is the daemon. The
+  shell gets spawned while the daemon still holds `spawn_lock`, so it
+  inherits what its own session set up rather than what some other
+  session did in the meantime. Those settings do stick to the daemon
+  afterwards though.
+
+  Shells started with posix_spawn get their pty opened ahead of time,
+  so it gets passed along as `PAM_TTY`. Forked shells don't have a pty
+  until the fork, so their sessions get opened without one.
+
+  This is only compiled in with the `pam` feature, since it needs to
+  link against libpam.
+*/
+
+use std::sync::{Mutex, MutexGuard};
+
+use tracing::warn;
+
+static SPAWN_LOCK: Mutex<()> = Mutex::new(());
+
+/// Held from opening a session until its shell has been spawned.
+pub fn spawn_lock() -> MutexGuard<'static, ()> {
+    SPAWN_LOCK.lock().unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    service: String,
+    user: String,
+}
+
+impl Session {
+    pub fn new(service: &str, user: &str) -> Self {
+        Session { service: String::from(service), user: String::from(user) }
+    }
+
+    /// Open the session. The modules see `tty` as the terminal the
+    /// session is on, if there is one.
+    pub fn open(&self, tty: Option<&str>) -> anyhow::Result<Open> {
+        self.open_with(&imp::Libpam, tty)
+    }
+
+    fn open_with(&self, backend: &dyn Backend, tty: Option<&str>) -> anyhow::Result<Open> {
+        let mut handle = backend.start(self, tty)?;
+        // If this fails the handle gets ended without closing anything.
+        let env = handle.open()?;
+        Ok(Open { handle: Some(handle), env })
+    }
+}
+
+/// An open session, which closes when dropped. Call `close` instead
+/// to find out whether that worked.
+pub struct Open {
+    handle: Option<Box<dyn Handle>>,
+    env: Vec<(String, String)>,
+}
+
+impl Open {
+    /// Environment variables the session modules want the shell to have.
+    pub fn env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    pub fn close(mut self) -> anyhow::Result<()> {
+        match self.handle.take() {
+            Some(mut handle) => handle.close(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        if let Some(mut handle) = self.handle.take() {
+            if let Err(e) = handle.close() {
+                warn!("closing pam session: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The libpam calls we make, behind a trait so that the bookkeeping
+/// around them can be tested without libpam.
+trait Backend {
+    fn start(&self, session: &Session, tty: Option<&str>) -> anyhow::Result<Box<dyn Handle>>;
+}
+
+/// A started pam transaction, which gets ended when dropped.
+trait Handle: Send {
+    fn open(&mut self) -> anyhow::Result<Vec<(String, String)>>;
+    fn close(&mut self) -> anyhow::Result<()>;
+}
+
+#[cfg(feature = "pam")]
+mod imp {
+    use std::{
+        ffi::{CStr, CString},
+        ptr,
+    };
+
+    use anyhow::{anyhow, Context};
+
+    use super::{ffi, Backend, Handle, Session};
+
+    pub struct Libpam;
+
+    impl Backend for Libpam {
+        fn start(&self, session: &Session, tty: Option<&str>) -> anyhow::Result<Box<dyn Handle>> {
+            let service = CString::new(session.service.as_str()).context("pam service")?;
+            let user = CString::new(session.user.as_str()).context("pam user")?;
+            let tty = tty.map(CString::new).transpose().context("pam tty")?;
+            let conv = ffi::PamConv { conv: Some(converse), appdata_ptr: ptr::null_mut() };
+
+            let mut pamh = ptr::null_mut();
+            // Safety: all the pointers are valid for the duration of the
+            //         call, and pam_start copies the conv struct.
+            let rc = unsafe { ffi::pam_start(service.as_ptr(), user.as_ptr(), &conv, &mut pamh) };
+            if rc != ffi::PAM_SUCCESS || pamh.is_null() {
+                return Err(anyhow!("pam_start for service '{}' failed ({})", session.service, rc));
+            }
+            let handle = PamHandle(pamh);
+
+            if let Some(tty) = tty {
+                // Safety: the handle is live and pam_set_item copies the string.
+                let rc = unsafe { ffi::pam_set_item(handle.0, ffi::PAM_TTY, tty.as_ptr().cast()) };
+                handle.check("pam_set_item(PAM_TTY)", rc)?;
+            }
+
+            Ok(Box::new(handle))
+        }
+    }
+
+    struct PamHandle(*mut ffi::PamHandle);
+
+    // Safety: libpam handles aren't tied to the thread that started
+    //         them, they just must not be used from two threads at
+    //         once, which `&mut self` takes care of.
+    unsafe impl Send for PamHandle {}
+
+    impl Handle for PamHandle {
+        fn open(&mut self) -> anyhow::Result<Vec<(String, String)>> {
+            // Safety: the handle is live.
+            unsafe {
+                self.check("pam_setcred", ffi::pam_setcred(self.0, ffi::PAM_ESTABLISH_CRED))?;
+                self.check("pam_open_session", ffi::pam_open_session(self.0, 0))?;
+            }
+            Ok(self.env())
+        }
+
+        fn close(&mut self) -> anyhow::Result<()> {
+            // Safety: the handle is live.
+            unsafe {
+                self.check("pam_close_session", ffi::pam_close_session(self.0, 0))?;
+                self.check("pam_setcred", ffi::pam_setcred(self.0, ffi::PAM_DELETE_CRED))?;
+            }
+            Ok(())
+        }
+    }
+
+    impl PamHandle {
+        /// The environment the session modules set up, which gets
+        /// handed to us as a malloced array of "NAME=value" strings
+        /// that we have to free.
+        fn env(&self) -> Vec<(String, String)> {
+            let mut env = vec![];
+            // Safety: the handle is live, and pam_getenvlist returns either
+            //         null or a null terminated array of c strings, all of
+            //         which we own.
+            unsafe {
+                let list = ffi::pam_getenvlist(self.0);
+                if list.is_null() {
+                    return env;
+                }
+                let mut entry = list;
+                while !(*entry).is_null() {
+                    let var = CStr::from_ptr(*entry).to_string_lossy();
+                    if let Some((k, v)) = var.split_once('=') {
+                        env.push((String::from(k), String::from(v)));
+                    }
+                    libc::free((*entry).cast());
+                    entry = entry.add(1);
+                }
+                libc::free(list.cast());
+            }
+            env
+        }
+
+        fn check(&self, what: &str, rc: libc::c_int) -> anyhow::Result<()> {
+            if rc == ffi::PAM_SUCCESS {
+                return Ok(());
+            }
+            // Safety: the handle is live and pam_strerror returns a static string.
+            let msg = unsafe { CStr::from_ptr(ffi::pam_strerror(self.0, rc)) };
+            Err(anyhow!("{}: {}", what, msg.to_string_lossy()))
+        }
+    }
+
+    impl Drop for PamHandle {
+        fn drop(&mut self) {
+            // Safety: the handle is live, and this is the last use of it.
+            unsafe {
+                ffi::pam_end(self.0, ffi::PAM_SUCCESS);
+            }
+        }
+    }
+
+    /// The conversation function. There is nobody around to answer
+    /// prompts, so those fail, and informational messages go to the
+    /// daemon log.
+    extern "C" fn converse(
+        num_msg: libc::c_int,
+        msg: *mut *const ffi::PamMessage,
+        _resp: *mut *mut ffi::PamResponse,
+        _appdata_ptr: *mut libc::c_void,
+    ) -> libc::c_int {
+        for i in 0..num_msg.max(0) as usize {
+            // Safety: linux-pam passes an array of num_msg message pointers.
+            let msg = unsafe { &**msg.add(i) };
+            match msg.msg_style {
+                ffi::PAM_ERROR_MSG | ffi::PAM_TEXT_INFO if !msg.msg.is_null() => {
+                    // Safety: messages are c strings.
+                    let text = unsafe { CStr::from_ptr(msg.msg) };
+                    tracing::info!("pam: {}", text.to_string_lossy());
+                }
+                ffi::PAM_ERROR_MSG | ffi::PAM_TEXT_INFO => {}
+                _ => return ffi::PAM_CONV_ERR,
+            }
+        }
+        ffi::PAM_SUCCESS
+    }
+}
+
+#[cfg(not(feature = "pam"))]
+mod imp {
+    use super::{Backend, Handle, Session};
+
+    pub struct Libpam;
+
+    impl Backend for Libpam {
+        fn start(&self, session: &Session, _tty: Option<&str>) -> anyhow::Result<Box<dyn Handle>> {
+            Err(anyhow::anyhow!(
+                "can't use pam service '{}' for {}, shpool was built without the pam feature",
+                session.service,
+                session.user
+            ))
+        }
+    }
+}
+
+/// The bits of the libpam api that we need. See pam_start(3) and friends.
+#[cfg(feature = "pam")]
+mod ffi {
+    use libc::{c_char, c_int, c_void};
+
+    pub const PAM_SUCCESS: c_int = 0;
+    pub const PAM_CONV_ERR: c_int = 19;
+
+    pub const PAM_TTY: c_int = 3;
+
+    pub const PAM_ESTABLISH_CRED: c_int = 0x0002;
+    pub const PAM_DELETE_CRED: c_int = 0x0004;
+
+    pub const PAM_ERROR_MSG: c_int = 3;
+    pub const PAM_TEXT_INFO: c_int = 4;
+
+    #[repr(C)]
+    pub struct PamHandle {
+        _private: [u8; 0],
+    }
+
+    #[repr(C)]
+    pub struct PamMessage {
+        pub msg_style: c_int,
+        pub msg: *const c_char,
+    }
+
+    #[repr(C)]
+    pub struct PamResponse {
+        pub resp: *mut c_char,
+        pub resp_retcode: c_int,
+    }
+
+    #[repr(C)]
+    pub struct PamConv {
+        pub conv: Option<
+            extern "C" fn(
+                c_int,
+                *mut *const PamMessage,
+                *mut *mut PamResponse,
+                *mut c_void,
+            ) -> c_int,
+        >,
+        pub appdata_ptr: *mut c_void,
+    }
+
+    #[link(name = "pam")]
+    extern "C" {
+        pub fn pam_start(
+            service_name: *const c_char,
+            user: *const c_char,
+            pam_conversation: *const PamConv,
+            pamh: *mut *mut PamHandle,
+        ) -> c_int;
+        pub fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+        pub fn pam_set_item(pamh: *mut PamHandle, item_type: c_int, item: *const c_void) -> c_int;
+        pub fn pam_setcred(pamh: *mut PamHandle, flags: c_int) -> c_int;
+        pub fn pam_open_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+        pub fn pam_close_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+        pub fn pam_getenvlist(pamh: *mut PamHandle) -> *mut *mut c_char;
+        pub fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Logs every call, tagged with which handle it was made on.
+    #[derive(Default)]
+    struct Fake {
+        calls: Arc<Mutex<Vec<String>>>,
+        fail_open: bool,
+        fail_close: bool,
+    }
+
+    struct FakeHandle {
+        id: usize,
+        calls: Arc<Mutex<Vec<String>>>,
+        fail_open: bool,
+        fail_close: bool,
+    }
+
+    impl Backend for Fake {
+        fn start(&self, session: &Session, tty: Option<&str>) -> anyhow::Result<Box<dyn Handle>> {
+            let mut calls = self.calls.lock().unwrap();
+            let id = calls.len();
+            calls.push(format!("start {} {} {} {:?}", id, session.service, session.user, tty));
+            Ok(Box::new(FakeHandle {
+                id,
+                calls: Arc::clone(&self.calls),
+                fail_open: self.fail_open,
+                fail_close: self.fail_close,
+            }))
+        }
+    }
+
+    impl Handle for FakeHandle {
+        fn open(&mut self) -> anyhow::Result<Vec<(String, String)>> {
+            self.calls.lock().unwrap().push(format!("open {}", self.id));
+            if self.fail_open {
+                return Err(anyhow::anyhow!("open failed"));
+            }
+            Ok(vec![(String::from("XDG_SESSION_ID"), String::from("7"))])
+        }
+
+        fn close(&mut self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(format!("close {}", self.id));
+            if self.fail_close {
+                return Err(anyhow::anyhow!("close failed"));
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for FakeHandle {
+        fn drop(&mut self) {
+            self.calls.lock().unwrap().push(format!("end {}", self.id));
+        }
+    }
+
+    fn calls(fake: &Fake) -> Vec<String> {
+        fake.calls.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn opens_and_closes_with_one_handle() -> anyhow::Result<()> {
+        let fake = Fake::default();
+        let session = Session::new("shpool", "someone");
+        let open = session.open_with(&fake, Some("/dev/pts/3"))?;
+        assert_eq!(open.env(), &[(String::from("XDG_SESSION_ID"), String::from("7"))]);
+        assert_eq!(calls(&fake), vec!["start 0 shpool someone Some(\"/dev/pts/3\")", "open 0"]);
+
+        open.close()?;
+        assert_eq!(
+            calls(&fake),
+            vec!["start 0 shpool someone Some(\"/dev/pts/3\")", "open 0", "close 0", "end 0"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn failed_open_ends_without_closing() {
+        let fake = Fake { fail_open: true, ..Fake::default() };
+        let session = Session::new("shpool", "someone");
+        assert!(session.open_with(&fake, None).is_err());
+        assert_eq!(calls(&fake), vec!["start 0 shpool someone None", "open 0", "end 0"]);
+    }
+
+    #[test]
+    fn failed_close_still_ends() -> anyhow::Result<()> {
+        let fake = Fake { fail_close: true, ..Fake::default() };
+        let session = Session::new("shpool", "someone");
+        let open = session.open_with(&fake, None)?;
+        assert!(open.close().is_err());
+        assert_eq!(calls(&fake), vec!["start 0 shpool someone None", "open 0", "close 0", "end 0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn drop_closes() -> anyhow::Result<()> {
+        let fake = Fake::default();
+        let session = Session::new("shpool", "someone");
+        drop(session.open_with(&fake, None)?);
+        assert_eq!(calls(&fake), vec!["start 0 shpool someone None", "open 0", "close 0", "end 0"]);
+        Ok(())
+    }
+
+    #[cfg(not(feature = "pam"))]
+    #[test]
+    fn unsupported_without_feature() {
+        let session = Session::new("shpool", "someone");
+        let err = session.open(None).err().expect("open to fail");
+        assert!(format!("{}", err).contains("without the pam feature"), "err={}", err);
+    }
+}
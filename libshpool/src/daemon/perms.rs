@@ -0,0 +1,349 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Checks on the ownership and modes of the files the daemon keeps.
+//!
+//! Anyone who can connect to the control socket can get a shell as the
+//! user running the daemon, and anyone who can write to the state file
+//! gets to pick the commands that `shpool daemon --resurrect` runs. So
+//! the runtime dir must be private to the user, the socket must only be
+//! usable by the user, and nothing under the runtime dir may be owned or
+//! writable by anybody else.
+//!
+//! The daemon refuses to start when any of that doesn't hold, unless it
+//! was started with `--fix-perms`, in which case it tightens up whatever
+//! it can. It also re-checks every so often while running, since files
+//! can get loosened behind its back, and either warns or fixes.
+
+use std::{
+    fs, io,
+    os::unix::fs::{FileTypeExt as _, MetadataExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{error, info, warn};
+
+use crate::control_sock;
+
+/// How often the running daemon re-checks permissions.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How far below the runtime dir to look. Session files live in
+/// `sessions/<name>/`, so this leaves a little room to spare.
+const MAX_DEPTH: usize = 4;
+
+/// Something that is more open than it should be.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub what: String,
+    /// The mode that would fix the problem, if it can be fixed by
+    /// changing the mode at all.
+    pub fix_mode: Option<u32>,
+}
+
+/// Look for problems with the runtime dir, everything under it, and
+/// the control socket, if we are listening on one.
+pub fn audit(runtime_dir: &Path, socket: Option<&Path>) -> Vec<Problem> {
+    let uid = nix::unistd::getuid().as_raw();
+    let mut problems = vec![];
+
+    match fs::symlink_metadata(runtime_dir) {
+        Ok(meta) => {
+            if !meta.is_dir() {
+                problems.push(Problem {
+                    path: runtime_dir.to_path_buf(),
+                    what: String::from("is not a directory"),
+                    fix_mode: None,
+                });
+            } else {
+                check_private(runtime_dir, &meta, uid, 0o700, &mut problems);
+                walk(runtime_dir, uid, 1, &mut problems);
+            }
+        }
+        Err(e) => problems.push(Problem {
+            path: runtime_dir.to_path_buf(),
+            what: format!("can't be inspected: {}", e),
+            fix_mode: None,
+        }),
+    }
+
+    if let Some(socket) = socket {
+        if !control_sock::is_abstract(socket) && !socket.starts_with(runtime_dir) {
+            check_socket(socket, uid, &mut problems);
+        }
+    }
+
+    problems
+}
+
+/// Check that a path which should be private to us is owned by us and
+/// has no group or other bits set.
+fn check_private(path: &Path, meta: &fs::Metadata, uid: u32, mode: u32, out: &mut Vec<Problem>) {
+    if meta.uid() != uid {
+        out.push(Problem {
+            path: path.to_path_buf(),
+            what: format!("is owned by uid {} rather than {}", meta.uid(), uid),
+            fix_mode: None,
+        });
+    } else if meta.mode() & 0o077 != 0 {
+        out.push(Problem {
+            path: path.to_path_buf(),
+            what: format!("has mode {:o}, it should be {:o}", meta.mode() & 0o7777, mode),
+            fix_mode: Some(mode),
+        });
+    }
+}
+
+/// Check everything below `dir`. Files only have to be owned by us
+/// and not writable by anyone else, since the runtime dir being private
+/// already keeps other users from reading them. Symlinks are skipped,
+/// their targets are not ours to police.
+fn walk(dir: &Path, uid: u32, depth: usize, out: &mut Vec<Problem>) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("listing {:?} to check permissions: {:?}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            // raced with a delete
+            continue;
+        };
+        let file_type = meta.file_type();
+        if file_type.is_symlink() {
+            continue;
+        }
+        if file_type.is_socket() {
+            check_private(&path, &meta, uid, 0o600, out);
+            continue;
+        }
+
+        if meta.uid() != uid {
+            out.push(Problem {
+                path: path.clone(),
+                what: format!("is owned by uid {} rather than {}", meta.uid(), uid),
+                fix_mode: None,
+            });
+        } else if meta.mode() & 0o022 != 0 {
+            out.push(Problem {
+                path: path.clone(),
+                what: format!("is writable by other users (mode {:o})", meta.mode() & 0o7777),
+                fix_mode: Some(meta.mode() & 0o7777 & !0o022),
+            });
+        }
+        if file_type.is_dir() {
+            walk(&path, uid, depth + 1, out);
+        }
+    }
+}
+
+/// Check a control socket that lives outside of the runtime dir, along
+/// with the directory it is in. A shared dir like /tmp is fine as long
+/// as it is sticky so nobody can swap the socket out from under us.
+fn check_socket(socket: &Path, uid: u32, out: &mut Vec<Problem>) {
+    if let Ok(meta) = fs::symlink_metadata(socket) {
+        check_private(socket, &meta, uid, 0o600, out);
+    }
+
+    let Some(parent) = socket.parent().filter(|p| !p.as_os_str().is_empty()) else {
+        return;
+    };
+    let Ok(meta) = fs::metadata(parent) else {
+        return;
+    };
+    let owner_ok = meta.uid() == uid || meta.uid() == 0;
+    let sticky = meta.mode() & 0o1000 != 0;
+    if !owner_ok {
+        out.push(Problem {
+            path: parent.to_path_buf(),
+            what: format!("holds the socket but is owned by uid {}", meta.uid()),
+            fix_mode: None,
+        });
+    } else if meta.mode() & 0o022 != 0 && !sticky {
+        out.push(Problem {
+            path: parent.to_path_buf(),
+            what: format!(
+                "holds the socket but is writable by other users (mode {:o})",
+                meta.mode() & 0o7777
+            ),
+            fix_mode: if meta.uid() == uid { Some(meta.mode() & 0o7777 & !0o022) } else { None },
+        });
+    }
+}
+
+/// Fix what can be fixed by changing modes, returning the problems that
+/// are left over.
+pub fn fix(problems: Vec<Problem>) -> Vec<Problem> {
+    let mut left = vec![];
+    for problem in problems.into_iter() {
+        let Some(mode) = problem.fix_mode else {
+            left.push(problem);
+            continue;
+        };
+        match fs::set_permissions(&problem.path, fs::Permissions::from_mode(mode)) {
+            Ok(()) => info!("set mode {:o} on {:?}, it {}", mode, problem.path, problem.what),
+            // it is gone, so it isn't a problem anymore
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!("fixing perms of {:?}: {:?}", problem.path, e);
+                left.push(problem);
+            }
+        }
+    }
+    left
+}
+
+/// Check permissions before the daemon starts serving, refusing to go
+/// on if anything is too open. With `fix_perms`, tighten things up
+/// first and only refuse over what couldn't be fixed.
+pub fn check_at_startup(
+    runtime_dir: &Path,
+    socket: Option<&Path>,
+    fix_perms: bool,
+) -> anyhow::Result<()> {
+    let mut problems = audit(runtime_dir, socket);
+    if fix_perms {
+        problems = fix(problems);
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let mut msg = String::from("refusing to start, since the control socket grants shell access:");
+    for problem in problems.iter() {
+        msg.push_str(&format!("\n  {} {}", problem.path.display(), problem.what));
+    }
+    if !fix_perms && problems.iter().any(|p| p.fix_mode.is_some()) {
+        msg.push_str("\nrun `shpool daemon --fix-perms` to tighten the permissions");
+    }
+    Err(anyhow!(msg))
+}
+
+/// Make the freshly bound control socket usable only by us, whatever
+/// the umask happened to be.
+pub fn restrict_socket(socket: &Path) -> anyhow::Result<()> {
+    if control_sock::is_abstract(socket) {
+        return Ok(());
+    }
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("restricting permissions on {:?}", socket))
+}
+
+/// Re-check permissions in the background for as long as the daemon
+/// runs, warning about anything that has gotten too open, or fixing it
+/// with `fix_perms`.
+pub fn spawn_rechecker(
+    runtime_dir: PathBuf,
+    socket: PathBuf,
+    fix_perms: bool,
+) -> anyhow::Result<()> {
+    thread::Builder::new()
+        .name(String::from("perms"))
+        .spawn(move || loop {
+            thread::sleep(RECHECK_INTERVAL);
+            let mut problems = audit(&runtime_dir, Some(&socket));
+            if fix_perms {
+                problems = fix(problems);
+            }
+            for problem in problems.iter() {
+                warn!("{:?} {}", problem.path, problem.what);
+            }
+        })
+        .context("spawning perms rechecker")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set_mode(path: &Path, mode: u32) -> anyhow::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    #[test]
+    fn private_runtime_dir_is_fine() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let runtime_dir = tmp.path().join("runtime");
+        fs::create_dir(&runtime_dir)?;
+        set_mode(&runtime_dir, 0o700)?;
+        let session_dir = runtime_dir.join("sessions").join("main");
+        fs::create_dir_all(&session_dir)?;
+        // readable by others is fine, the runtime dir keeps them out
+        fs::write(session_dir.join("history"), "ls\n")?;
+        set_mode(&session_dir.join("history"), 0o644)?;
+
+        let socket = runtime_dir.join("shpool.socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket)?;
+        restrict_socket(&socket)?;
+
+        assert_eq!(audit(&runtime_dir, Some(&socket)), vec![]);
+        check_at_startup(&runtime_dir, Some(&socket), false)?;
+        Ok(())
+    }
+
+    #[test]
+    fn open_perms_are_refused_then_fixed() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let runtime_dir = tmp.path().join("runtime");
+        fs::create_dir(&runtime_dir)?;
+        set_mode(&runtime_dir, 0o755)?;
+        let state_file = runtime_dir.join("state.toml");
+        fs::write(&state_file, "")?;
+        set_mode(&state_file, 0o666)?;
+        let socket = runtime_dir.join("shpool.socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket)?;
+        set_mode(&socket, 0o755)?;
+
+        let problems = audit(&runtime_dir, Some(&socket));
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        let err = check_at_startup(&runtime_dir, Some(&socket), false).unwrap_err();
+        assert!(format!("{}", err).contains("--fix-perms"));
+
+        check_at_startup(&runtime_dir, Some(&socket), true)?;
+        assert_eq!(fs::metadata(&runtime_dir)?.mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(&state_file)?.mode() & 0o777, 0o644);
+        assert_eq!(audit(&runtime_dir, Some(&socket)), vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn socket_in_sticky_shared_dir() -> anyhow::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let shared = tmp.path().join("shared");
+        fs::create_dir(&shared)?;
+        set_mode(&shared, 0o1777)?;
+        let socket = shared.join("shpool.socket");
+        let mut problems = vec![];
+        check_socket(&socket, nix::unistd::getuid().as_raw(), &mut problems);
+        assert_eq!(problems, vec![]);
+
+        set_mode(&shared, 0o777)?;
+        check_socket(&socket, nix::unistd::getuid().as_raw(), &mut problems);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].fix_mode, Some(0o755));
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Plugins are helper processes, listed in the `plugins` config option,
+  that extend the daemon without having to write any rust. They let
+  users do the same sorts of things that the `Hooks` trait lets a
+  wrapping binary do.
+
+  The daemon launches each plugin when it starts and talks to it with
+  json lines, writing messages to the plugin's stdin and reading replies
+  from its stdout. Anything the plugin writes to stderr ends up in the
+  daemon's log. There are three kinds of messages:
+
+  - A `hello` message, sent whenever the plugin is launched, with the
+    daemon's version.
+  - `event` messages, sent as sessions are created, attached to and so
+    on. Plugins don't reply to these.
+  - `request` messages, which carry an `id` and expect a reply with the
+    same `id`. If no reply shows up within the plugin's timeout, the
+    daemon carries on as if the plugin had no opinion.
+
+  Each plugin gets a thread that feeds it messages through a bounded
+  queue, so a plugin that stops reading can't wedge the daemon. Events
+  that don't fit in the queue get dropped. If a plugin exits, it gets
+  launched again with a backoff.
+*/
+
+use std::{
+    collections::HashMap,
+    io,
+    io::{BufRead, Write},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use serde_derive::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use super::flight_recorder;
+use crate::{
+    config::PluginConfig,
+    hooks::{CmdDecision, Hooks, StreamTransform},
+};
+
+const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+
+// How many messages can be waiting for a plugin before we start
+// dropping events.
+const QUEUE_LEN: usize = 256;
+
+const INITIAL_RESTART_DELAY: time::Duration = time::Duration::from_secs(1);
+const MAX_RESTART_DELAY: time::Duration = time::Duration::from_secs(60);
+// A plugin that stays up this long is considered healthy again, so
+// the next crash gets the initial delay.
+const HEALTHY_RUN: time::Duration = time::Duration::from_secs(60);
+
+/// A message from the daemon to a plugin.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message<'a> {
+    Hello { version: &'a str },
+    Event { event: &'a str, session: &'a str, at_unix_ms: i64 },
+    Request { id: u64, request: &'a str, session: &'a str, cmd: &'a str },
+}
+
+/// A reply from a plugin to a request.
+#[derive(Deserialize, Debug)]
+struct Reply {
+    id: u64,
+    #[serde(default)]
+    decision: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// A line for the plugin's thread to write to the plugin, along with
+/// somewhere to send the reply if it is a request.
+struct Outgoing {
+    line: String,
+    reply: Option<(u64, crossbeam_channel::Sender<Reply>)>,
+}
+
+type Pending = Arc<Mutex<HashMap<u64, crossbeam_channel::Sender<Reply>>>>;
+
+/// Wrap the given hooks so that the configured plugins also get to
+/// hear about everything, launching the plugins in the process.
+pub fn wrap(
+    inner: Box<dyn Hooks + Send + Sync>,
+    configs: &[PluginConfig],
+) -> Box<dyn Hooks + Send + Sync> {
+    if configs.is_empty() {
+        return inner;
+    }
+    let plugins = configs
+        .iter()
+        .filter_map(|config| match Plugin::launch(config.clone()) {
+            Ok(p) => Some(p),
+            Err(e) => {
+                error!("launching plugin '{}': {:?}", config.name, e);
+                None
+            }
+        })
+        .collect();
+    Box::new(PluginHooks { inner, plugins })
+}
+
+struct PluginHooks {
+    inner: Box<dyn Hooks + Send + Sync>,
+    plugins: Vec<Plugin>,
+}
+
+impl PluginHooks {
+    fn event(&self, event: &str, session_name: &str) {
+        for plugin in self.plugins.iter() {
+            plugin.event(event, session_name);
+        }
+    }
+}
+
+impl Hooks for PluginHooks {
+    fn on_new_session(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("new_session", session_name);
+        self.inner.on_new_session(session_name)
+    }
+
+    fn on_reattach(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("reattach", session_name);
+        self.inner.on_reattach(session_name)
+    }
+
+    fn on_busy(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("busy", session_name);
+        self.inner.on_busy(session_name)
+    }
+
+    fn on_client_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("client_disconnect", session_name);
+        self.inner.on_client_disconnect(session_name)
+    }
+
+    fn on_shell_disconnect(&self, session_name: &str) -> anyhow::Result<()> {
+        self.event("shell_disconnect", session_name);
+        self.inner.on_shell_disconnect(session_name)
+    }
+
+    /// The wrapping binary gets the first say, then each plugin in the
+    /// order they are configured.
+    fn check_cmd(&self, session_name: &str, cmd: &str) -> CmdDecision {
+        let decision = self.inner.check_cmd(session_name, cmd);
+        if decision != CmdDecision::UseConfig {
+            return decision;
+        }
+        for plugin in self.plugins.iter() {
+            let decision = plugin.check_cmd(session_name, cmd);
+            if decision != CmdDecision::UseConfig {
+                return decision;
+            }
+        }
+        CmdDecision::UseConfig
+    }
+
+    fn output_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.output_transform(session_name)
+    }
+
+    fn input_transform(&self, session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        self.inner.input_transform(session_name)
+    }
+}
+
+/// The daemon's handle on a plugin. Dropping it shuts the plugin down.
+struct Plugin {
+    name: String,
+    timeout: time::Duration,
+    next_id: AtomicU64,
+    tx: crossbeam_channel::Sender<Outgoing>,
+}
+
+impl Plugin {
+    fn launch(config: PluginConfig) -> anyhow::Result<Self> {
+        let (tx, rx) = crossbeam_channel::bounded(QUEUE_LEN);
+        let plugin = Plugin {
+            name: config.name.clone(),
+            timeout: config.timeout_ms.map(time::Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT),
+            next_id: AtomicU64::new(1),
+            tx,
+        };
+        thread::Builder::new()
+            .name(format!("plugin:{}", config.name))
+            .spawn(move || supervise(config, rx))
+            .context("spawning plugin thread")?;
+        Ok(plugin)
+    }
+
+    fn event(&self, event: &str, session_name: &str) {
+        let msg = Message::Event {
+            event,
+            session: session_name,
+            at_unix_ms: flight_recorder::unix_ms(time::SystemTime::now()),
+        };
+        if let Err(e) = self.send(&msg, None) {
+            warn!("plugin '{}': dropping {} event: {:?}", self.name, event, e);
+        }
+    }
+
+    fn check_cmd(&self, session_name: &str, cmd: &str) -> CmdDecision {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let msg = Message::Request { id, request: "check_cmd", session: session_name, cmd };
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        if let Err(e) = self.send(&msg, Some((id, reply_tx))) {
+            warn!("plugin '{}': sending check_cmd: {:?}", self.name, e);
+            return CmdDecision::UseConfig;
+        }
+
+        let reply = match reply_rx.recv_timeout(self.timeout) {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("plugin '{}': no reply to check_cmd: {:?}", self.name, e);
+                return CmdDecision::UseConfig;
+            }
+        };
+        match reply.decision.as_deref() {
+            Some("allow") => CmdDecision::Allow,
+            Some("deny") => CmdDecision::Deny(
+                reply.reason.unwrap_or_else(|| format!("denied by plugin '{}'", self.name)),
+            ),
+            Some("use_config") | None => CmdDecision::UseConfig,
+            Some(other) => {
+                warn!("plugin '{}': unknown check_cmd decision '{}'", self.name, other);
+                CmdDecision::UseConfig
+            }
+        }
+    }
+
+    fn send(
+        &self,
+        msg: &Message,
+        reply: Option<(u64, crossbeam_channel::Sender<Reply>)>,
+    ) -> anyhow::Result<()> {
+        let line = serde_json::to_string(msg).context("serializing message")?;
+        self.tx.try_send(Outgoing { line, reply }).map_err(|e| anyhow!("{}", e))
+    }
+}
+
+/// Keep the plugin running until the daemon hangs up on it.
+fn supervise(config: PluginConfig, rx: crossbeam_channel::Receiver<Outgoing>) {
+    let mut delay = INITIAL_RESTART_DELAY;
+    loop {
+        let started = time::Instant::now();
+        match run(&config, &rx) {
+            Ok(()) => return,
+            Err(e) => warn!("plugin '{}' stopped: {:?}", config.name, e),
+        }
+
+        if started.elapsed() > HEALTHY_RUN {
+            delay = INITIAL_RESTART_DELAY;
+        }
+        info!("restarting plugin '{}' in {:?}", config.name, delay);
+        // Drop anything sent while the plugin is down, rather than
+        // letting stale requests pile up.
+        let deadline = time::Instant::now() + delay;
+        loop {
+            match rx.recv_deadline(deadline) {
+                Ok(_) => {}
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => break,
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Launch the plugin and feed it messages until either it exits,
+/// which is an error, or the daemon hangs up on it.
+fn run(config: &PluginConfig, rx: &crossbeam_channel::Receiver<Outgoing>) -> anyhow::Result<()> {
+    let parts = shell_words::split(&config.cmd).context("parsing cmd")?;
+    let (prog, args) = parts.split_first().ok_or(anyhow!("empty cmd"))?;
+    let mut child = process::Command::new(prog)
+        .args(args)
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("spawning '{}'", config.cmd))?;
+    info!("launched plugin '{}' as pid {}", config.name, child.id());
+
+    let res = feed(config, rx, &mut child);
+    if let Err(e) = child.kill() {
+        if e.kind() != io::ErrorKind::InvalidInput {
+            warn!("killing plugin '{}': {:?}", config.name, e);
+        }
+    }
+    let status = child.wait().context("waiting for plugin")?;
+    info!("plugin '{}' exited with {}", config.name, status);
+    res
+}
+
+fn feed(
+    config: &PluginConfig,
+    rx: &crossbeam_channel::Receiver<Outgoing>,
+    child: &mut process::Child,
+) -> anyhow::Result<()> {
+    let mut stdin = child.stdin.take().ok_or(anyhow!("no stdin"))?;
+    let stdout = child.stdout.take().ok_or(anyhow!("no stdout"))?;
+
+    let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+    let (exited_tx, exited_rx) = crossbeam_channel::bounded::<()>(0);
+    {
+        let pending = Arc::clone(&pending);
+        let name = config.name.clone();
+        thread::Builder::new()
+            .name(format!("plugin-rd:{}", config.name))
+            .spawn(move || {
+                read_replies(&name, stdout, &pending);
+                // dropping the sender lets the feeder know that we are done
+                drop(exited_tx);
+            })
+            .context("spawning plugin reader")?;
+    }
+
+    let hello = Message::Hello { version: shpool_protocol::VERSION };
+    writeln!(stdin, "{}", serde_json::to_string(&hello)?).context("writing hello")?;
+
+    loop {
+        crossbeam_channel::select! {
+            recv(rx) -> msg => {
+                let Ok(msg) = msg else {
+                    return Ok(());
+                };
+                if let Some((id, reply)) = msg.reply {
+                    pending.lock().unwrap().insert(id, reply);
+                }
+                writeln!(stdin, "{}", msg.line).context("writing to plugin")?;
+            }
+            recv(exited_rx) -> _ => {
+                return Err(anyhow!("plugin closed its stdout"));
+            }
+        }
+    }
+}
+
+/// Route replies from the plugin's stdout to whoever is waiting on them.
+fn read_replies(name: &str, stdout: process::ChildStdout, pending: &Pending) {
+    for line in io::BufReader::new(stdout).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("plugin '{}': reading stdout: {:?}", name, e);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply: Reply = match serde_json::from_str(&line) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("plugin '{}': bad reply '{}': {:?}", name, line, e);
+                continue;
+            }
+        };
+        // Replies to requests that timed out have nobody left to send to.
+        if let Some(reply_tx) = pending.lock().unwrap().remove(&reply.id) {
+            let _ = reply_tx.try_send(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct NoopHooks;
+    impl Hooks for NoopHooks {}
+
+    fn plugin_hooks(cmd: &str) -> Box<dyn Hooks + Send + Sync> {
+        wrap(
+            Box::new(NoopHooks),
+            &[PluginConfig {
+                name: String::from("test"),
+                cmd: String::from(cmd),
+                timeout_ms: Some(5000),
+            }],
+        )
+    }
+
+    #[test]
+    fn messages() -> anyhow::Result<()> {
+        let event = Message::Event { event: "new_session", session: "main", at_unix_ms: 7 };
+        assert_eq!(
+            serde_json::to_string(&event)?,
+            r#"{"type":"event","event":"new_session","session":"main","at_unix_ms":7}"#
+        );
+        let req = Message::Request { id: 3, request: "check_cmd", session: "main", cmd: "ls" };
+        assert_eq!(
+            serde_json::to_string(&req)?,
+            r#"{"type":"request","id":3,"request":"check_cmd","session":"main","cmd":"ls"}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_cmd() {
+        // A plugin that denies every command it gets asked about.
+        let hooks = plugin_hooks(
+            r#"sh -c 'while read -r line; do
+                id=$(echo "$line" | sed -n "s/.*\"id\":\([0-9]*\).*/\1/p")
+                [ -n "$id" ] && echo "{\"id\":$id,\"decision\":\"deny\",\"reason\":\"nope\"}"
+            done'"#,
+        );
+        hooks.on_new_session("main").unwrap();
+        assert_eq!(hooks.check_cmd("main", "htop"), CmdDecision::Deny(String::from("nope")));
+    }
+
+    #[test]
+    fn silent_plugin() {
+        let hooks = wrap(
+            Box::new(NoopHooks),
+            &[PluginConfig {
+                name: String::from("quiet"),
+                cmd: String::from("sleep 30"),
+                timeout_ms: Some(50),
+            }],
+        );
+        assert_eq!(hooks.check_cmd("main", "htop"), CmdDecision::UseConfig);
+    }
+}
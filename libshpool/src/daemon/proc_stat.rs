@@ -0,0 +1,105 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cpu accounting for sessions, based on /proc.
+//!
+//! Each shell is the leader of its own unix session (the pty fork calls
+//! setsid), so we can find everything a shpool session is running by
+//! looking for processes with a matching session id, even if they have
+//! been reparented or put into a different process group by job control.
+
+use std::{collections::HashMap, fs, time::Duration};
+
+use tracing::warn;
+
+/// Sum up the cpu time used by every process on the system, grouped
+/// by unix session id. This includes the time of any children that have
+/// already exited and been waited on.
+pub fn cpu_time_by_session() -> HashMap<i32, Duration> {
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_sec <= 0 {
+        warn!("could not determine clock ticks per second");
+        return HashMap::new();
+    }
+
+    let entries = match fs::read_dir("/proc") {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("listing /proc: {:?}", e);
+            return HashMap::new();
+        }
+    };
+
+    let mut ticks: HashMap<i32, u64> = HashMap::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        if !name.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        // processes can exit out from under us, so errors here are expected
+        let stat = match fs::read_to_string(entry.path().join("stat")) {
+            Ok(s) => s,
+            Err(_) => continue,
+        };
+        if let Some((sid, t)) = parse_stat(&stat) {
+            *ticks.entry(sid).or_default() += t;
+        }
+    }
+
+    ticks
+        .into_iter()
+        .map(|(sid, t)| (sid, Duration::from_millis(t * 1000 / ticks_per_sec as u64)))
+        .collect()
+}
+
+/// Pull the session id and total cpu ticks out of the contents
+/// of a /proc/<pid>/stat file.
+pub fn parse_stat(stat: &str) -> Option<(i32, u64)> {
+    // The command name is wrapped in parens and can contain anything,
+    // including spaces and parens, so skip past the last paren before
+    // splitting the rest of the fields.
+    let (_, rest) = stat.rsplit_once(')')?;
+    let fields = rest.split_whitespace().collect::<Vec<_>>();
+
+    // Indexes are offset from the field numbers in proc(5) by 3, since
+    // we skipped the pid and comm and the numbering starts at 1.
+    let sid = fields.get(3)?.parse().ok()?;
+    let mut ticks = 0;
+    for field in fields.get(11..15)? {
+        ticks += field.parse::<i64>().ok()?.max(0) as u64;
+    }
+
+    Some((sid, ticks))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let cases = vec![
+            (
+                "1234 (bash) S 1 1234 1234 34816 1234 4194560 1 2 3 4 10 20 30 40 20 0 1 0",
+                Some((1234, 100)),
+            ),
+            ("99 (weird) name)) R 1 99 42 0 -1 0 0 0 0 0 1 2 0 0 20 0 1 0", Some((42, 3))),
+            ("99 (short) R 1 99", None),
+            ("garbage", None),
+        ];
+        for (stat, want) in cases.into_iter() {
+            assert_eq!(parse_stat(stat), want, "stat={}", stat);
+        }
+    }
+}
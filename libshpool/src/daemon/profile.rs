@@ -0,0 +1,165 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  On-demand cpu profiling for `shpool profile`, so that a cpu spike in a
+  long running daemon can be looked into without reaching for perf or a
+  debugger. A sampling profiler runs for the requested amount of time,
+  then the result gets written to the `profiles` directory under the
+  runtime dir as either a flamegraph svg or a pprof protobuf.
+
+  This is only compiled in with the `profiling` feature, since it pulls
+  in pprof-rs.
+*/
+
+use std::{path::Path, time::Duration};
+
+use shpool_protocol::{ProfileRequest, ProfileStatus};
+
+// Profiling is meant for catching a problem in the act, so there is no
+// reason to let a typo keep the profiler running for days.
+const MAX_DURATION: Duration = Duration::from_secs(10 * 60);
+
+/// Profile the daemon as the request asks, blocking until the profile
+/// has been written out.
+pub fn capture(runtime_dir: &Path, request: &ProfileRequest) -> ProfileStatus {
+    let duration = Duration::from_secs(request.duration_secs);
+    if duration.is_zero() || duration > MAX_DURATION {
+        return ProfileStatus::Failed {
+            reason: format!(
+                "the duration must be between 1 second and {} seconds",
+                MAX_DURATION.as_secs()
+            ),
+        };
+    }
+
+    imp::capture(runtime_dir, duration, request.format)
+}
+
+#[cfg(feature = "profiling")]
+mod imp {
+    use std::{
+        fs,
+        io::Write,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::Context;
+    use pprof::protos::Message;
+    use shpool_protocol::{ProfileFormat, ProfileStatus};
+    use tracing::{info, warn};
+
+    // Just off of 100 so that we don't sample in lockstep with
+    // anything that runs on a 10ms timer.
+    const SAMPLE_HZ: i32 = 99;
+
+    // The profiler hooks SIGPROF for the whole process, so only one
+    // profile can be collected at a time.
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    pub fn capture(runtime_dir: &Path, duration: Duration, format: ProfileFormat) -> ProfileStatus {
+        if RUNNING.swap(true, Ordering::AcqRel) {
+            return ProfileStatus::Busy;
+        }
+        let res = profile(runtime_dir, duration, format);
+        RUNNING.store(false, Ordering::Release);
+
+        match res {
+            Ok(path) => {
+                info!("wrote profile to {:?}", path);
+                ProfileStatus::Written { path: path.to_string_lossy().into_owned() }
+            }
+            Err(e) => {
+                warn!("profiling: {:?}", e);
+                ProfileStatus::Failed { reason: format!("{:#}", e) }
+            }
+        }
+    }
+
+    fn profile(
+        runtime_dir: &Path,
+        duration: Duration,
+        format: ProfileFormat,
+    ) -> anyhow::Result<PathBuf> {
+        info!("profiling for {:?}", duration);
+        let guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(SAMPLE_HZ)
+            // unwinding through these can deadlock
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .context("starting profiler")?;
+        thread::sleep(duration);
+        let report = guard.report().build().context("building profile report")?;
+        drop(guard);
+
+        let dir = runtime_dir.join("profiles");
+        fs::create_dir_all(&dir).context("creating profiles dir")?;
+        let ext = match format {
+            ProfileFormat::Flamegraph => "svg",
+            ProfileFormat::Pprof => "pb",
+        };
+        let path =
+            dir.join(format!("shpool-{}.{}", chrono::Local::now().format("%Y%m%d-%H%M%S"), ext));
+        let mut file = fs::File::create(&path).context("creating profile file")?;
+        match format {
+            ProfileFormat::Flamegraph => {
+                report.flamegraph(&mut file).context("writing flamegraph")?
+            }
+            ProfileFormat::Pprof => {
+                let profile = report.pprof().context("converting to pprof")?;
+                let mut buf = Vec::new();
+                profile.encode(&mut buf).context("encoding pprof")?;
+                file.write_all(&buf).context("writing pprof")?;
+            }
+        }
+
+        Ok(path)
+    }
+}
+
+#[cfg(not(feature = "profiling"))]
+mod imp {
+    use std::{path::Path, time::Duration};
+
+    use shpool_protocol::{ProfileFormat, ProfileStatus};
+
+    pub fn capture(
+        _runtime_dir: &Path,
+        _duration: Duration,
+        _format: ProfileFormat,
+    ) -> ProfileStatus {
+        ProfileStatus::Unsupported
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::ProfileFormat;
+
+    use super::*;
+
+    #[test]
+    fn rejects_bad_durations() {
+        for duration_secs in [0, 24 * 60 * 60] {
+            let request = ProfileRequest { duration_secs, format: ProfileFormat::Flamegraph };
+            assert!(matches!(
+                capture(Path::new("/nonexistent"), &request),
+                ProfileStatus::Failed { .. }
+            ));
+        }
+    }
+}
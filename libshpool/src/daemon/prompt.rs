@@ -15,14 +15,21 @@
 // This file contains the logic for injecting the `prompt_annotation`
 // config option into a user's prompt for known shells.
 
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
 
 use anyhow::{anyhow, Context};
 use tracing::{debug, info, instrument, warn};
 
 use crate::{
     consts::{SENTINEL_FLAG_VAR, STARTUP_SENTINEL},
-    daemon::trie::{Trie, TrieCursor},
+    daemon::{
+        pty_io,
+        trie::{Trie, TrieCursor},
+    },
+    platform,
 };
 
 #[derive(Debug, Clone)]
@@ -36,14 +43,26 @@ enum KnownShell {
 /// the shell path in `shell` to decide the right way to go about
 /// injecting the prefix.
 ///
-/// If the prefix is blank, this is a noop.
+/// If `cwd_file` is provided, also install a hook that runs right before
+/// each prompt and cds to the directory written in that file (if it
+/// exists), then removes the file. This is how we move the shell to the
+/// client's directory on reattach without typing into whatever program
+/// happens to be running in the foreground.
+///
+/// Whenever we inject anything, we also install a hook that picks up
+/// a new value for SHPOOL_ATTACH_COUNT from `attach_count_file` when
+/// the daemon writes one on reattach.
+///
+/// If the prefix is blank and there is no cwd file, this is a noop.
 #[instrument(skip_all)]
 pub fn maybe_inject_prefix(
     pty_master: &mut shpool_pty::fork::Fork,
     prompt_prefix: &str,
     session_name: &str,
+    cwd_file: Option<&Path>,
+    attach_count_file: &Path,
 ) -> anyhow::Result<()> {
-    if prompt_prefix.is_empty() {
+    if prompt_prefix.is_empty() && cwd_file.is_none() {
         return Ok(());
     }
 
@@ -58,7 +77,8 @@ pub fn maybe_inject_prefix(
     // now actually inject the prompt
     let prompt_prefix = prompt_prefix.replace("$SHPOOL_SESSION_NAME", session_name);
 
-    let mut script = match (prompt_prefix.as_str(), shell_type) {
+    let mut script = match (prompt_prefix.as_str(), &shell_type) {
+        ("", _) => String::new(),
         (_, Ok(KnownShell::Bash)) => format!(
             r#"
             if [[ -z "${{PROMPT_COMMAND+x}}" ]]; then
@@ -106,14 +126,19 @@ pub fn maybe_inject_prefix(
         }
     };
 
+    if let (Some(cwd_file), Ok(shell_type)) = (cwd_file, &shell_type) {
+        script.push_str(&cd_hook_script(shell_type, cwd_file)?);
+    }
+    if let Ok(shell_type) = &shell_type {
+        script.push_str(&attach_count_hook_script(shell_type, attach_count_file)?);
+    }
+
     // With this magic env var set, `shpool daemon` will just
     // print the prompt sentinel and immediately exit. We do
     // this rather than `echo $PROMPT_SENTINEL` because different
     // shells have subtly different echo behavior which makes it
     // hard to make the scanner work right.
-    // TODO(julien): this will probably not work on mac
-    let sentinel_cmd =
-        format!("\n {}=prompt /proc/{}/exe daemon\n", SENTINEL_FLAG_VAR, std::process::id());
+    let sentinel_cmd = sentinel_cmd("prompt")?;
     script.push_str(sentinel_cmd.as_str());
 
     debug!("injecting prefix script '{}'", script);
@@ -122,20 +147,107 @@ pub fn maybe_inject_prefix(
     Ok(())
 }
 
+/// The script to install a pre-prompt hook that cds to the directory
+/// named in `cwd_file` whenever it shows up.
+fn cd_hook_script(shell_type: &KnownShell, cwd_file: &Path) -> anyhow::Result<String> {
+    let cwd_file = cwd_file.to_str().ok_or(anyhow!("cwd file path is not utf8"))?;
+    let cwd_file = shell_words::quote(cwd_file);
+    Ok(match shell_type {
+        KnownShell::Bash => format!(
+            r#"
+            function __shpool__cd_hook() {{
+               if [[ -f {cwd_file} ]]; then
+                  builtin cd -- "$(< {cwd_file})"
+                  command rm -f {cwd_file}
+               fi
+            }}
+            PROMPT_COMMAND="__shpool__cd_hook${{PROMPT_COMMAND:+;${{PROMPT_COMMAND}}}}"
+        "#
+        ),
+        KnownShell::Zsh => format!(
+            r#"
+            typeset -a precmd_functions
+            function __shpool__cd_hook() {{
+               if [[ -f {cwd_file} ]]; then
+                  builtin cd -- "$(< {cwd_file})"
+                  command rm -f {cwd_file}
+               fi
+            }}
+            precmd_functions[1,0]=(__shpool__cd_hook)
+        "#
+        ),
+        KnownShell::Fish => format!(
+            r#"
+            function __shpool__cd_hook --on-event fish_prompt
+                if test -f {cwd_file}
+                    builtin cd -- (cat {cwd_file})
+                    command rm -f {cwd_file}
+                end
+            end
+        "#
+        ),
+    })
+}
+
+/// The script to install a pre-prompt hook that updates
+/// SHPOOL_ATTACH_COUNT whenever `count_file` shows up.
+fn attach_count_hook_script(shell_type: &KnownShell, count_file: &Path) -> anyhow::Result<String> {
+    let count_file = count_file.to_str().ok_or(anyhow!("attach count file path is not utf8"))?;
+    let count_file = shell_words::quote(count_file);
+    Ok(match shell_type {
+        KnownShell::Bash => format!(
+            r#"
+            function __shpool__attach_count_hook() {{
+               if [[ -f {count_file} ]]; then
+                  export SHPOOL_ATTACH_COUNT="$(< {count_file})"
+                  command rm -f {count_file}
+               fi
+            }}
+            PROMPT_COMMAND="__shpool__attach_count_hook${{PROMPT_COMMAND:+;${{PROMPT_COMMAND}}}}"
+        "#
+        ),
+        KnownShell::Zsh => format!(
+            r#"
+            typeset -a precmd_functions
+            function __shpool__attach_count_hook() {{
+               if [[ -f {count_file} ]]; then
+                  export SHPOOL_ATTACH_COUNT="$(< {count_file})"
+                  command rm -f {count_file}
+               fi
+            }}
+            precmd_functions[1,0]=(__shpool__attach_count_hook)
+        "#
+        ),
+        KnownShell::Fish => format!(
+            r#"
+            function __shpool__attach_count_hook --on-event fish_prompt
+                if test -f {count_file}
+                    set -gx SHPOOL_ATTACH_COUNT (cat {count_file})
+                    command rm -f {count_file}
+                end
+            end
+        "#
+        ),
+    })
+}
+
 #[instrument(skip_all)]
 fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result<()> {
     let mut startup_sentinel_scanner = SentinelScanner::new(STARTUP_SENTINEL);
-    let startup_sentinel_cmd =
-        format!("\n {}=startup /proc/{}/exe daemon\n", SENTINEL_FLAG_VAR, std::process::id());
+    let startup_sentinel_cmd = sentinel_cmd("startup")?;
 
     pty_master
         .write_all(startup_sentinel_cmd.as_bytes())
         .context("running startup sentinel script")?;
 
+    let mut pty_reader = pty_io::Master::new(pty_master)?;
     let mut buf: [u8; 2048] = [0; 2048];
     loop {
-        let len = pty_master.read(&mut buf).context("reading chunk to scan for startup")?;
+        let len = pty_reader.read(&mut buf).context("reading chunk to scan for startup")?;
         if len == 0 {
+            if pty_reader.is_eof() {
+                return Err(anyhow!("shell exited before it finished starting up"));
+            }
             continue;
         }
         let buf = &buf[..len];
@@ -152,12 +264,20 @@ fn wait_for_startup(pty_master: &mut shpool_pty::fork::Master) -> anyhow::Result
     }
 }
 
+/// The command line that makes a shell run the daemon binary with the
+/// sentinel flag set to `kind`.
+fn sentinel_cmd(kind: &str) -> anyhow::Result<String> {
+    let exe = platform::daemon_exe().context("finding the daemon exe")?;
+    let exe = exe.to_str().ok_or(anyhow!("daemon exe path is not utf8"))?;
+    Ok(format!("\n {}={} {} daemon\n", SENTINEL_FLAG_VAR, kind, shell_words::quote(exe)))
+}
+
 /// Determine the shell process running under the given pid by examining
-/// `/proc/<pid>/exe`.
+/// the name of the program it is running.
 #[instrument(skip_all)]
 fn sniff_shell(pid: libc::pid_t) -> anyhow::Result<KnownShell> {
-    let shell_proc_name =
-        libproc::proc_pid::name(pid).map_err(|e| anyhow!("determining subproc name: {:?}", e))?;
+    let shell_proc_name = platform::process_name(nix::unistd::Pid::from_raw(pid))
+        .context("determining subproc name")?;
     info!("shell_proc_name: {}", shell_proc_name);
 
     if shell_proc_name.ends_with("bash") {
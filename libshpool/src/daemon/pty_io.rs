@@ -0,0 +1,142 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! IO on a pty master that behaves the same way on every platform.
+//!
+//! Once every process has closed the slave side of a pty, reads from the
+//! master fail with EIO on Linux, while the BSDs and macOS just return 0.
+//! shpool_pty's `Master` papers over this by turning any failed read into
+//! a 0 byte read, which also swallows EINTR and real errors and leaves
+//! callers with no way to tell a quiet shell from a dead one. This wraps
+//! the raw fd so that the end of the session is always a clean EOF and
+//! everything else is reported as it is.
+
+use std::{io, os::fd::RawFd};
+
+use anyhow::anyhow;
+
+/// A pty master fd. Like the shpool_pty `Master` it wraps, this does not
+/// own the fd, so it is cheap to copy around between threads.
+#[derive(Debug, Copy, Clone)]
+pub struct Master {
+    fd: RawFd,
+    eof: bool,
+}
+
+impl Master {
+    pub fn new(master: &shpool_pty::fork::Master) -> anyhow::Result<Self> {
+        let fd = master.raw_fd().ok_or(anyhow!("no fd for pty master"))?;
+        Ok(Master { fd, eof: false })
+    }
+
+    /// True once a read has seen the end of the session, meaning that
+    /// nothing has the slave side of the pty open anymore.
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+}
+
+impl io::Read for Master {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.eof || buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            // Safety: buf is valid for writes of buf.len() bytes for the
+            // duration of the call.
+            let ret =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if ret > 0 {
+                return Ok(ret as usize);
+            }
+            if ret == 0 {
+                // how the BSDs report a hangup
+                self.eof = true;
+                return Ok(0);
+            }
+
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // how Linux reports a hangup
+                Some(libc::EIO) => {
+                    self.eof = true;
+                    return Ok(0);
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+impl io::Write for Master {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            // Safety: buf is valid for reads of buf.len() bytes for the
+            // duration of the call.
+            let ret =
+                unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+            if ret >= 0 {
+                return Ok(ret as usize);
+            }
+
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(libc::EIO) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "the pty has hung up, the shell has exited",
+                    ));
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        os::fd::AsRawFd,
+    };
+
+    use super::*;
+
+    #[test]
+    fn hangup_is_eof() {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let mut master = Master { fd: pty.master.as_raw_fd(), eof: false };
+
+        let mut slave = std::fs::File::from(pty.slave);
+        slave.write_all(b"hi").unwrap();
+        let mut buf = [0; 16];
+        let len = master.read(&mut buf).unwrap();
+        assert!(buf[..len].starts_with(b"h"));
+        assert!(!master.is_eof());
+
+        drop(slave);
+        // drain anything left over from the slave
+        while master.read(&mut buf).unwrap() > 0 {}
+        assert!(master.is_eof());
+        assert_eq!(master.read(&mut buf).unwrap(), 0);
+
+        drop(pty.master);
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, Instant};
+
+/// A token bucket for limiting the rate at which a session's output
+/// gets read from the pty. The bucket holds up to one second's worth
+/// of bytes, so short bursts go through untouched and only sustained
+/// floods get throttled.
+///
+/// The bucket is allowed to go negative, since we don't know how much
+/// the shell is going to hand us until after we have read it. A negative
+/// balance means the caller must wait for it to refill before reading
+/// again.
+#[derive(Debug)]
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, now: Instant) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        TokenBucket { bytes_per_sec, tokens: bytes_per_sec, last_refill: now }
+    }
+
+    /// Record that `n` bytes have been read.
+    pub fn consume(&mut self, n: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= n as f64;
+    }
+
+    /// How long the caller must wait before it is allowed to read more
+    /// output. Zero if it can read right away.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bursts_allowed() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        bucket.consume(1000, start);
+        assert_eq!(bucket.wait_time(start), Duration::ZERO);
+    }
+
+    #[test]
+    fn throttles_and_recovers() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        bucket.consume(1500, start);
+        assert_eq!(bucket.wait_time(start), Duration::from_millis(500));
+
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.wait_time(later), Duration::from_millis(250));
+
+        let done = start + Duration::from_millis(500);
+        assert_eq!(bucket.wait_time(done), Duration::ZERO);
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        // sitting idle for a long time should not bank more than
+        // a second's worth of output
+        let later = start + Duration::from_secs(60);
+        bucket.consume(2000, later);
+        assert_eq!(bucket.wait_time(later), Duration::from_secs(1));
+    }
+}
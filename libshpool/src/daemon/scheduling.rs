@@ -0,0 +1,233 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling settings for spawned shells.
+//!
+//! The settings get resolved from the config in the daemon, then applied
+//! by the child side of the pty fork right before it execs the shell, so
+//! that everything the shell goes on to run inherits them.
+
+use std::io;
+
+use anyhow::{anyhow, bail, Context};
+use regex::Regex;
+
+use crate::{
+    config::{Config, Scheduling, SessionScheduling},
+    platform,
+};
+
+// See ioprio_set(2). These are not exposed by the libc crate.
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+const IOPRIO_CLASS_RT: libc::c_int = 1;
+const IOPRIO_CLASS_BE: libc::c_int = 2;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+
+/// The scheduling settings for a particular session, ready to apply.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Resolved {
+    nice: Option<i32>,
+    ioprio: Option<libc::c_int>,
+    oom_score_adj: Option<i32>,
+    cpu_affinity: Option<Vec<usize>>,
+}
+
+/// Work out the scheduling settings for the named session.
+pub fn resolve(config: &Config, session_name: &str) -> anyhow::Result<Resolved> {
+    let global = config.scheduling.clone().unwrap_or_default();
+    let mut session = Scheduling::default();
+    for sched in config.session_scheduling.iter().flatten() {
+        if pattern(&sched.pattern)?.is_match(session_name) {
+            session = sched.scheduling.clone();
+            break;
+        }
+    }
+
+    Ok(Resolved {
+        nice: session.nice.or(global.nice),
+        ioprio: match session.ionice.as_ref().or(global.ionice.as_ref()) {
+            Some(ionice) => Some(parse_ionice(ionice)?),
+            None => None,
+        },
+        oom_score_adj: session.oom_score_adj.or(global.oom_score_adj),
+        cpu_affinity: session.cpu_affinity.or(global.cpu_affinity),
+    })
+}
+
+pub fn validate(sched: &Scheduling) -> anyhow::Result<()> {
+    if let Some(nice) = sched.nice {
+        if !(-20..=19).contains(&nice) {
+            bail!("nice must be between -20 and 19, got {}", nice);
+        }
+    }
+    if let Some(ionice) = &sched.ionice {
+        parse_ionice(ionice)?;
+    }
+    if let Some(adj) = sched.oom_score_adj {
+        if !(-1000..=1000).contains(&adj) {
+            bail!("oom_score_adj must be between -1000 and 1000, got {}", adj);
+        }
+    }
+    if let Some(cpus) = &sched.cpu_affinity {
+        if cpus.is_empty() {
+            bail!("cpu_affinity must list at least one cpu");
+        }
+        if let Some(cpu) = cpus.iter().find(|cpu| **cpu >= libc::CPU_SETSIZE as usize) {
+            bail!("cpu {} is out of range for cpu_affinity", cpu);
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_session(sched: &SessionScheduling) -> anyhow::Result<()> {
+    pattern(&sched.pattern)?;
+    validate(&sched.scheduling)
+}
+
+fn pattern(src: &str) -> anyhow::Result<Regex> {
+    Regex::new(&format!("^(?:{})$", src)).with_context(|| format!("compiling pattern '{}'", src))
+}
+
+/// Parse an ionice setting like "best-effort:4" into the value
+/// that ioprio_set expects.
+fn parse_ionice(src: &str) -> anyhow::Result<libc::c_int> {
+    let (class, level) = match src.split_once(':') {
+        Some((class, level)) => (
+            class,
+            Some(
+                level
+                    .parse::<libc::c_int>()
+                    .with_context(|| format!("parsing ionice '{}'", src))?,
+            ),
+        ),
+        None => (src, None),
+    };
+    if let Some(level) = level {
+        if !(0..=7).contains(&level) {
+            bail!("ionice priority must be between 0 and 7, got {}", level);
+        }
+    }
+
+    // 4 is the kernel's default priority within a class
+    let (class, level) = match class {
+        "realtime" => (IOPRIO_CLASS_RT, level.unwrap_or(4)),
+        "best-effort" => (IOPRIO_CLASS_BE, level.unwrap_or(4)),
+        "idle" if level.is_some() => bail!("the idle ionice class does not take a priority"),
+        "idle" => (IOPRIO_CLASS_IDLE, 0),
+        _ => return Err(anyhow!("unknown ionice class '{}'", class)),
+    };
+    Ok((class << IOPRIO_CLASS_SHIFT) | level)
+}
+
+impl Resolved {
+    /// Apply the settings to the current process. This is meant to be
+    /// called in the child after the fork, where there is nowhere to
+    /// report errors except the pty, so failures are printed there for
+    /// the user to see rather than stopping the shell from launching.
+    pub fn apply(&self) {
+        if let Some(nice) = self.nice {
+            // Safety: basic ffi, 0 means the calling process.
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                eprintln!("shpool: setting nice to {}: {}", nice, io::Error::last_os_error());
+            }
+        }
+        if let Some(ioprio) = self.ioprio {
+            if let Err(e) = platform::set_io_priority(ioprio) {
+                eprintln!("shpool: setting io priority: {}", e);
+            }
+        }
+        if let Some(adj) = self.oom_score_adj {
+            if let Err(e) = platform::set_oom_score_adj(adj) {
+                eprintln!("shpool: setting oom_score_adj to {}: {}", adj, e);
+            }
+        }
+        if let Some(cpus) = &self.cpu_affinity {
+            // the cpus were range checked when the config got validated
+            if let Err(e) = platform::set_cpu_affinity(cpus) {
+                eprintln!("shpool: setting cpu affinity to {:?}: {}", cpus, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ionice() {
+        let cases = vec![
+            ("idle", Some(3 << 13)),
+            ("best-effort", Some(2 << 13 | 4)),
+            ("best-effort:7", Some(2 << 13 | 7)),
+            ("realtime:0", Some(1 << 13)),
+            ("realtime:8", None),
+            ("idle:1", None),
+            ("fast", None),
+            ("best-effort:x", None),
+        ];
+        for (src, want) in cases.into_iter() {
+            assert_eq!(parse_ionice(src).ok(), want, "src={}", src);
+        }
+    }
+
+    #[test]
+    fn cpu_affinity() {
+        let sched =
+            |cpus: Vec<usize>| Scheduling { cpu_affinity: Some(cpus), ..Scheduling::default() };
+        assert!(validate(&sched(vec![0, 3])).is_ok());
+        assert!(validate(&sched(vec![])).is_err());
+        assert!(validate(&sched(vec![0, 100_000])).is_err());
+    }
+
+    #[test]
+    fn resolve_patterns() -> anyhow::Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [scheduling]
+            nice = 5
+            oom_score_adj = 100
+            cpu_affinity = [0]
+
+            [[session_scheduling]]
+            pattern = "build-.*"
+            nice = 19
+            ionice = "idle"
+
+            [[session_scheduling]]
+            pattern = "build-.*|bg"
+            nice = 10
+            cpu_affinity = [1, 2]
+            "#,
+        )?;
+
+        let resolved = |nice, ioprio, cpu_affinity: Option<Vec<usize>>| Resolved {
+            nice: Some(nice),
+            ioprio,
+            oom_score_adj: Some(100),
+            cpu_affinity,
+        };
+        let cases = vec![
+            ("main", resolved(5, None, Some(vec![0]))),
+            ("build-1", resolved(19, Some(3 << 13), Some(vec![0]))),
+            ("bg", resolved(10, None, Some(vec![1, 2]))),
+            ("xbg", resolved(5, None, Some(vec![0]))),
+        ];
+        for (session, want) in cases.into_iter() {
+            assert_eq!(resolve(&config, session)?, want, "session={}", session);
+        }
+
+        Ok(())
+    }
+}
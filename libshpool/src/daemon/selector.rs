@@ -0,0 +1,303 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Session selectors for requests like kill and detach that act on a
+//! set of sessions. A selector is either a plain session name or a
+//! shell style glob pattern (`*`, `?` and `[...]` classes), and gets
+//! resolved against the session table in the daemon so that the set
+//! of matching sessions is never stale.
+
+use crate::session_name;
+
+/// A session picked out by a selector.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Selected {
+    pub name: String,
+    /// Whether the session was named outright rather than matched by
+    /// a pattern. Requests tend to be more forgiving about sessions
+    /// that were only swept up by a pattern.
+    pub explicit: bool,
+}
+
+/// Resolve the given selectors against the names in the session table,
+/// returning the selected sessions in selector order without duplicates,
+/// along with the selectors that didn't match anything.
+///
+/// A selector that is exactly the name of a session always selects just
+/// that session, even if it has glob characters in it.
+pub fn resolve<'a, I>(selectors: &[String], names: I) -> (Vec<Selected>, Vec<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut names: Vec<&String> = names.into_iter().collect();
+    names.sort();
+
+    let mut selected: Vec<Selected> = vec![];
+    let mut not_found = vec![];
+    for selector in selectors.iter() {
+        let matches: Vec<&String> = if names.contains(&selector) {
+            vec![selector]
+        } else if is_pattern(selector) {
+            names.iter().copied().filter(|n| matches(selector, n)).collect()
+        } else {
+            vec![]
+        };
+
+        if matches.is_empty() {
+            not_found.push(selector.clone());
+        }
+        for name in matches.into_iter() {
+            let explicit = name == selector;
+            match selected.iter_mut().find(|s| &s.name == name) {
+                Some(s) => s.explicit |= explicit,
+                None => selected.push(Selected { name: name.clone(), explicit }),
+            }
+        }
+    }
+
+    (selected, not_found)
+}
+
+/// Like `resolve`, but selecting a session also selects all of its
+/// panes. A session can be selected by name even if it has no shell of
+/// its own any more and is only the owner of some panes.
+pub fn resolve_with_panes<'a, I>(selectors: &[String], names: I) -> (Vec<Selected>, Vec<String>)
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let names: Vec<&String> = names.into_iter().collect();
+    let mut owners: Vec<String> = names
+        .iter()
+        .filter_map(|n| match session_name::split_pane(n) {
+            (owner, Some(_)) if !names.iter().any(|n| n.as_str() == owner) => {
+                Some(String::from(owner))
+            }
+            _ => None,
+        })
+        .collect();
+    owners.sort();
+    owners.dedup();
+
+    let (selected, not_found) = resolve(selectors, names.iter().copied().chain(owners.iter()));
+
+    let mut expanded: Vec<Selected> = vec![];
+    let mut add =
+        |name: &String, explicit: bool| match expanded.iter_mut().find(|s| &s.name == name) {
+            Some(s) => s.explicit |= explicit,
+            None => expanded.push(Selected { name: name.clone(), explicit }),
+        };
+    for session in selected.iter() {
+        if names.contains(&&session.name) {
+            add(&session.name, session.explicit);
+        }
+        let mut panes: Vec<&String> = names
+            .iter()
+            .copied()
+            .filter(|n| match session_name::split_pane(n) {
+                (owner, Some(_)) => owner == session.name,
+                _ => false,
+            })
+            .collect();
+        panes.sort();
+        for pane in panes.into_iter() {
+            // swept up along with their session, like a pattern match
+            add(pane, false);
+        }
+    }
+
+    (expanded, not_found)
+}
+
+fn is_pattern(selector: &str) -> bool {
+    selector.contains(['*', '?', '['])
+}
+
+/// Check if the whole of `name` matches the glob `pattern`.
+fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // The usual backtracking glob match, where we only ever need to
+    // remember the most recent star.
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            Some('[') => {
+                if let Some((matched, len)) = match_class(&pattern[p..], name[n]) {
+                    if matched {
+                        p += len;
+                        n += 1;
+                        continue;
+                    }
+                } else if name[n] == '[' {
+                    // an unclosed class is just a literal '['
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        // no match here, so let the last star eat one more char
+        match star {
+            Some((star_p, star_n)) => {
+                star = Some((star_p, star_n + 1));
+                p = star_p + 1;
+                n = star_n + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match a char against the `[...]` class at the start of `pattern`,
+/// returning whether it matched and how long the class is, or None if
+/// the class is never closed.
+fn match_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *pattern.get(i)?;
+        // a ']' right at the start is part of the class
+        if lo == ']' && !first {
+            break;
+        }
+        first = false;
+
+        if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|hi| *hi != ']') {
+            let hi = pattern[i + 2];
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        let cases = vec![
+            ("work-*", "work-1", true),
+            ("work-*", "work-", true),
+            ("work-*", "play-1", false),
+            ("*", "anything", true),
+            ("*-2", "work-2", true),
+            ("*-2", "work-2-3", false),
+            ("w*k*2", "work-2", true),
+            ("w?rk", "work", true),
+            ("w?rk", "wrk", false),
+            ("s[0-9]", "s7", true),
+            ("s[0-9]", "sx", false),
+            ("s[!0-9]", "sx", true),
+            ("s[!0-9]", "s7", false),
+            ("s[abc]", "sb", true),
+            ("s[]]", "s]", true),
+            ("s[a-]", "s-", true),
+            ("s[", "s[", true),
+            ("s[", "sx", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXbYbZ", false),
+        ];
+        for (pattern, name, want) in cases.into_iter() {
+            assert_eq!(matches(pattern, name), want, "pattern={} name={}", pattern, name);
+        }
+    }
+
+    #[test]
+    fn resolution() {
+        let table: Vec<String> =
+            vec!["work-1", "work-2", "play", "odd*name"].into_iter().map(String::from).collect();
+        let selectors: Vec<String> =
+            vec!["work-*", "work-1", "nope-*", "odd*name", "play", "missing"]
+                .into_iter()
+                .map(String::from)
+                .collect();
+
+        let (selected, not_found) = resolve(&selectors, table.iter());
+        assert_eq!(
+            selected,
+            vec![
+                Selected { name: String::from("work-1"), explicit: true },
+                Selected { name: String::from("work-2"), explicit: false },
+                Selected { name: String::from("odd*name"), explicit: true },
+                Selected { name: String::from("play"), explicit: true },
+            ]
+        );
+        assert_eq!(not_found, vec![String::from("nope-*"), String::from("missing")]);
+    }
+
+    #[test]
+    fn panes() {
+        let table: Vec<String> = vec!["work", "work:build", "work:logs", "play:a", "other"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let sel = |selectors: &[&str]| {
+            let selectors: Vec<String> = selectors.iter().map(|s| String::from(*s)).collect();
+            let (selected, not_found) = resolve_with_panes(&selectors, table.iter());
+            (selected.into_iter().map(|s| (s.name, s.explicit)).collect::<Vec<_>>(), not_found)
+        };
+        let owned = |name: &str, explicit: bool| (String::from(name), explicit);
+
+        assert_eq!(
+            sel(&["work"]),
+            (
+                vec![owned("work", true), owned("work:build", false), owned("work:logs", false)],
+                vec![]
+            )
+        );
+        assert_eq!(sel(&["work:logs"]), (vec![owned("work:logs", true)], vec![]));
+        // the owner of a pane can be selected even without a shell of its own
+        assert_eq!(sel(&["play"]), (vec![owned("play:a", false)], vec![]));
+        assert_eq!(
+            sel(&["work:*", "nope"]),
+            (
+                vec![owned("work:build", false), owned("work:logs", false)],
+                vec![String::from("nope")]
+            )
+        );
+    }
+}
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    cmp,
     collections::HashMap,
     env, fs, io, net,
     ops::Add,
@@ -22,9 +23,12 @@ use std::{
         net::{UnixListener, UnixStream},
         process::CommandExt as _,
     },
+    panic,
     path::{Path, PathBuf},
-    process,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
     thread, time,
     time::{Duration, Instant},
 };
@@ -32,35 +36,78 @@ use std::{
 use anyhow::{anyhow, Context};
 use nix::unistd;
 use shpool_protocol::{
-    AttachHeader, AttachReplyHeader, AttachStatus, ConnectHeader, DetachReply, DetachRequest,
-    KillReply, KillRequest, ListReply, ResizeReply, Session, SessionMessageDetachReply,
-    SessionMessageReply, SessionMessageRequest, SessionMessageRequestPayload, SessionStatus,
+    AdoptPidReply, AdoptPidRequest, AdoptPidStatus, AttachHeader, AttachReplyHeader, AttachStatus,
+    BroadcastReply, BroadcastRequest, ConnectHeader, CopyReply, CopyRequest, CopyStatus,
+    DetachReply, DetachRequest, DumpStateReply, EnvReply, EnvRequest, EnvStatus, ExitRecord,
+    ExportReply, ExtendTtlReply, ExtendTtlRequest, ExtendTtlStatus, ImportFailure, ImportReply,
+    ImportRequest, KillReply, KillRequest, ListReply, LogLevelReply, LogLevelRequest, NetUsage,
+    PasteReply, PasteRequest, PasteStatus, PipeReply, PipeRequest, PipeStatus, ProfileReply,
+    ProfileRequest, RedrawReply, ResizeReply, RestartPolicy, Session, SessionDefinition,
+    SessionMessageDetachReply, SessionMessageReply, SessionMessageRequest,
+    SessionMessageRequestPayload, SessionStats, SessionStatus, StatsReply, SuspendReply, TtySize,
     VersionHeader,
 };
 use tracing::{error, info, instrument, span, warn, Level};
 
 use crate::{
+    clock::Clock,
+    common::panic_msg,
     config,
     config::MotdDisplayMode,
     consts,
     daemon::{
-        etc_environment, exit_notify::ExitNotifier, hooks, pager::PagerError, prompt, shell,
-        show_motd, ttl_reaper,
+        adopt_pid, audit, clipboard, cmd_policy, command, dbus, etc_environment, event_socket,
+        exit_history, exit_notify::ExitNotifier, flight_recorder, hooks, hooks::CmdDecision,
+        identity, net_stat, output_pipe, pager::PagerError, pam, plugins, proc_stat, profile,
+        prompt, scheduling, selector, shell, show_motd, spawn, state_file, threads, ttl_reaper,
+        utmp,
     },
-    protocol, test_hooks, tty, user,
+    duration, history, lastlog, log_level, platform, protocol, recording, session_name, test_hooks,
+    tty, user,
 };
 
 const DEFAULT_INITIAL_SHELL_PATH: &str = "/usr/bin:/bin:/usr/sbin:/sbin";
 const DEFAULT_OUTPUT_SPOOL_LINES: usize = 500;
 const DEFAULT_PROMPT_PREFIX: &str = "shpool:$SHPOOL_SESSION_NAME ";
 
+// Sessions created without a client (autostarted or imported) have no
+// client to tell us what sort of terminal they will be displayed in, so
+// we assume something reasonable.
+const DETACHED_TERM: &str = "xterm";
+const DETACHED_TTY_SIZE: TtySize = TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 };
+
+// Sessions with a restart policy back off between restarts if they
+// keep exiting right away.
+const INITIAL_RESTART_DELAY: time::Duration = time::Duration::from_secs(1);
+const MAX_RESTART_DELAY: time::Duration = time::Duration::from_secs(60);
+
 // Half a second should be more than enough time to handle any resize or
 // or detach. If things are taking longer, we can't afford to keep waiting
 // for the shell->client thread since session message calls are made with the
 // global session table lock held.
 const SESSION_MSG_TIMEOUT: time::Duration = time::Duration::from_millis(500);
 
+// How long an attach will wait on another client that is in the middle
+// of creating the same session before giving up and reporting it busy.
+// The creator normally finishes claiming the session within
+// milliseconds.
+const PENDING_ATTACH_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+// How long a new connection gets to send its connect header, and how
+// many connections can be doing that at once, unless the config says
+// otherwise.
+const DEFAULT_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+const DEFAULT_MAX_PENDING_CONNECTIONS: usize = 64;
+// How long shutdown waits on each session's recording to get synced.
+const RECORDING_SYNC_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+// How long shutdown waits on all the sessions together, since they get
+// synced and disconnected in parallel.
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(3);
+
 pub struct Server {
+    /// A handle on ourselves, so that threads spawned while handling
+    /// a connection can outlive it.
+    this: Weak<Server>,
     config: config::Manager,
     /// A map from shell session names to session descriptors.
     /// We wrap this in Arc<Mutex<_>> so that we can get at the
@@ -68,11 +115,38 @@ pub struct Server {
     /// that is spawned during the attach process, and so that
     /// handle_conn can delegate to worker threads and quickly allow
     /// the main thread to become available to accept new connections.
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    shells: Arc<shell::SessionTable>,
     runtime_dir: PathBuf,
+    /// The control socket, for SHPOOL_SOCKET.
+    socket: PathBuf,
     register_new_reapable_session: crossbeam_channel::Sender<(String, Instant)>,
+    clock: Arc<dyn Clock>,
     hooks: Box<dyn hooks::Hooks + Send + Sync>,
     daily_messenger: Arc<show_motd::DailyMessenger>,
+    /// Recent errors, for `shpool dump-state`.
+    recent_errors: flight_recorder::ErrorRing,
+    /// How the shells of recent sessions exited, for `shpool list --long`.
+    exit_history: Arc<exit_history::ExitHistory>,
+    audit: audit::Log,
+    /// Session lifecycle events, for `shpool last`.
+    lastlog: lastlog::Writer,
+    total_connections: AtomicUsize,
+    active_connections: AtomicUsize,
+    /// Connections that have not sent their connect header yet.
+    pending_connections: AtomicUsize,
+    /// Connections hung up on for taking too long over the handshake.
+    handshake_timeouts: AtomicUsize,
+    /// Connections closed right away because too many were pending.
+    rejected_connections: AtomicUsize,
+    /// The buffer for `shpool copy` and `shpool paste`, shared by all
+    /// sessions.
+    clipboard: Arc<clipboard::Clipboard>,
+    /// Session events for the D-Bus service to pass along, until it
+    /// starts up and takes them.
+    pub dbus_events: Mutex<Option<crossbeam_channel::Receiver<dbus::Event>>>,
+    /// Set once the state file writer is running. Until then the state
+    /// file belongs to resurrect, so shutdown leaves it alone too.
+    state_file_writer: AtomicBool,
 }
 
 impl Server {
@@ -81,45 +155,185 @@ impl Server {
         config: config::Manager,
         hooks: Box<dyn hooks::Hooks + Send + Sync>,
         runtime_dir: PathBuf,
+        socket: PathBuf,
+        clock: Arc<dyn Clock>,
     ) -> anyhow::Result<Arc<Self>> {
-        let shells = Arc::new(Mutex::new(HashMap::new()));
+        // Plugins only get launched at startup, so changes to them need
+        // a daemon restart.
+        let hooks = plugins::wrap(hooks, config.get().plugins.as_deref().unwrap_or(&[]));
+        let (hooks, dbus_events) = dbus::wrap(hooks, config.get().dbus.unwrap_or(false));
+        let hooks =
+            event_socket::wrap(hooks, &runtime_dir, config.get().event_socket.unwrap_or(false));
+
+        let shells = Arc::new(RwLock::new(HashMap::new()));
         // buffered so that we are unlikely to block when setting up a
         // new session
         let (new_sess_tx, new_sess_rx) = crossbeam_channel::bounded(10);
         let shells_tab = Arc::clone(&shells);
-        thread::spawn(move || {
-            if let Err(e) = ttl_reaper::run(new_sess_rx, shells_tab) {
-                warn!("ttl reaper exited with error: {:?}", e);
-            }
-        });
+        let reaper_config = config.clone();
+        let reaper_clock = Arc::clone(&clock);
+        thread::Builder::new()
+            .name(String::from("ttl-reaper"))
+            .spawn(move || {
+                if let Err(e) =
+                    ttl_reaper::run(new_sess_rx, shells_tab, reaper_config, reaper_clock)
+                {
+                    warn!("ttl reaper exited with error: {:?}", e);
+                }
+            })
+            .context("spawning ttl reaper")?;
 
         let daily_messenger = Arc::new(show_motd::DailyMessenger::new(config.clone())?);
-        Ok(Arc::new(Server {
+        let audit = audit::Log::new(config.clone());
+        let lastlog = lastlog::Writer::new(&runtime_dir, config.clone());
+        // No sessions exist yet, so nothing can be appending to the
+        // recordings while we patch them up after a crash.
+        recording::recover_all(&runtime_dir);
+        Ok(Arc::new_cyclic(|this| Server {
+            this: Weak::clone(this),
             config,
             shells,
             runtime_dir,
+            socket,
             register_new_reapable_session: new_sess_tx,
+            clock,
             hooks,
             daily_messenger,
+            recent_errors: flight_recorder::ErrorRing::default(),
+            exit_history: Arc::new(exit_history::ExitHistory::default()),
+            audit,
+            lastlog,
+            total_connections: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            pending_connections: AtomicUsize::new(0),
+            handshake_timeouts: AtomicUsize::new(0),
+            rejected_connections: AtomicUsize::new(0),
+            clipboard: Arc::new(clipboard::Clipboard::default()),
+            dbus_events: Mutex::new(dbus_events),
+            state_file_writer: AtomicBool::new(false),
         }))
     }
 
+    /// Start keeping the state file up to date with the session table.
+    /// This has to wait until any resurrect is done, since it would
+    /// overwrite the file resurrect is reading sessions from.
+    pub fn start_state_file_writer(&self) -> anyhow::Result<()> {
+        let state_file_path = state_file::path(&self.runtime_dir);
+        let shells_tab = Arc::clone(&self.shells);
+        thread::Builder::new()
+            .name(String::from("state-file"))
+            .spawn(move || {
+                if let Err(e) = state_file::run(state_file_path, shells_tab) {
+                    warn!("state file writer exited with error: {:?}", e);
+                }
+            })
+            .context("spawning state file writer")?;
+        self.state_file_writer.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Launch the sessions from the autostart_sessions config option,
+    /// each with a dedicated thread that restarts it if need be.
+    #[instrument(skip_all)]
+    pub fn start_autostart_sessions(server: &Arc<Self>) {
+        let sessions = server.config.get().autostart_sessions.clone().unwrap_or_default();
+        for autostart in sessions.into_iter() {
+            let builder = threads::for_session("auto", &autostart.name, &server.config);
+            let name = autostart.name.clone();
+            let server = Arc::clone(server);
+            let res = builder.spawn(move || {
+                let _s = span!(Level::INFO, "autostart", s = autostart.name).entered();
+                if let Err(e) = server.supervise_autostart_session(&autostart) {
+                    error!("autostart session '{}': {:?}", autostart.name, e);
+                    server.recent_errors.record("autostart session", &e);
+                }
+            });
+            if let Err(e) = res {
+                error!("spawning autostart thread for '{}': {:?}", name, e);
+            }
+        }
+    }
+
+    /// Get ready for the daemon to exit. We save the session table so
+    /// that `shpool daemon --resurrect` can bring the sessions back, and
+    /// tell attached clients what is going on rather than leaving them
+    /// to find out from a broken pipe.
+    #[instrument(skip_all)]
+    pub fn shutdown(&self) {
+        if self.state_file_writer.load(Ordering::Acquire) {
+            let state_file_path = state_file::path(&self.runtime_dir);
+            if let Err(e) = state_file::flush(&state_file_path, &self.shells) {
+                error!("saving sessions on shutdown: {:?}", e);
+            }
+        }
+
+        // Each session gets a thread, so that one wedged session can't
+        // hold up the rest, and a stuck one gets left behind once the
+        // deadline passes since the process is about to exit anyway.
+        let deadline = time::Instant::now() + SHUTDOWN_TIMEOUT;
+        let (done_tx, done_rx) = crossbeam_channel::unbounded();
+        let mut pending = 0;
+        for (name, session) in shell::snapshot(&self.shells).into_iter() {
+            let done_tx = done_tx.clone();
+            let res = threads::for_session("shutdown", &name, &self.config).spawn(move || {
+                if let Some(recording) = &session.recording {
+                    if let Err(e) = recording.sync(RECORDING_SYNC_TIMEOUT) {
+                        warn!("syncing recording of '{}' for shutdown: {:?}", name, e);
+                    }
+                }
+                if let Err(e) = session.disconnect_for_shutdown() {
+                    warn!("disconnecting '{}' for shutdown: {:?}", name, e);
+                }
+                let _ = done_tx.send(());
+            });
+            match res {
+                Ok(_) => pending += 1,
+                Err(e) => warn!("spawning shutdown thread: {:?}", e),
+            }
+        }
+        while pending > 0 {
+            if done_rx.recv_deadline(deadline).is_err() {
+                warn!("gave up on {} sessions after {:?}", pending, SHUTDOWN_TIMEOUT);
+                break;
+            }
+            pending -= 1;
+        }
+        test_hooks::emit("daemon-shutdown-done");
+    }
+
     #[instrument(skip_all)]
     pub fn serve(server: Arc<Self>, listener: UnixListener) -> anyhow::Result<()> {
         test_hooks::emit("daemon-about-to-listen");
-        let mut conn_counter = 0;
         for stream in listener.incoming() {
             info!("socket got a new connection");
             match stream {
                 Ok(stream) => {
-                    conn_counter += 1;
-                    let conn_id = conn_counter;
-                    let server = Arc::clone(&server);
-                    thread::spawn(move || {
-                        if let Err(err) = server.handle_conn(stream, conn_id) {
-                            error!("handling new connection: {:?}", err)
-                        }
+                    // Checked here on the accept thread, so that a flood
+                    // of connections can't get a thread each first.
+                    let max_pending = server
+                        .config
+                        .get()
+                        .max_pending_connections
+                        .unwrap_or(DEFAULT_MAX_PENDING_CONNECTIONS);
+                    let pending = server.pending_connections.load(Ordering::Relaxed);
+                    if pending >= max_pending {
+                        warn!("{} connections already pending, closing new connection", pending);
+                        server.rejected_connections.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    let conn_id = server.next_conn_id();
+                    let builder =
+                        threads::for_session("conn", &conn_id.to_string(), &server.config);
+                    server.pending_connections.fetch_add(1, Ordering::Relaxed);
+                    let res = builder.spawn({
+                        let server = Arc::clone(&server);
+                        move || server.serve_conn(stream, conn_id)
                     });
+                    if let Err(err) = res {
+                        error!("spawning connection handler: {:?}", err);
+                        server.pending_connections.fetch_sub(1, Ordering::Relaxed);
+                    }
                 }
                 Err(err) => {
                     error!("accepting stream: {:?}", err);
@@ -130,12 +344,55 @@ impl Server {
         Ok(())
     }
 
+    /// Serve a single connection on the calling thread, returning once
+    /// the client is done, for `shpool daemon --inetd`.
+    pub fn serve_one(server: Arc<Self>, stream: UnixStream) {
+        info!("serving a single connection");
+        let conn_id = server.next_conn_id();
+        server.pending_connections.fetch_add(1, Ordering::Relaxed);
+        server.serve_conn(stream, conn_id);
+    }
+
+    fn next_conn_id(&self) -> usize {
+        self.total_connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Handle a connection, keeping a bug in the handling of one client
+    /// from taking down the whole daemon. The caller has already counted
+    /// the connection as pending.
+    fn serve_conn(&self, stream: UnixStream, conn_id: usize) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        let pending = PendingConn(&self.pending_connections);
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.handle_conn(stream, conn_id, pending)
+        }))
+        .unwrap_or_else(|payload| {
+            Err(anyhow!("connection handler panicked: {}", panic_msg(&*payload)))
+        });
+        if let Err(err) = res {
+            error!("handling new connection: {:?}", err);
+            self.recent_errors.record("handling connection", &err);
+        }
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
     #[instrument(skip_all, fields(cid = conn_id))]
-    fn handle_conn(&self, mut stream: UnixStream, conn_id: usize) -> anyhow::Result<()> {
-        // We want to avoid timing out while blocking the main thread.
+    fn handle_conn(
+        &self,
+        mut stream: UnixStream,
+        conn_id: usize,
+        pending: PendingConn,
+    ) -> anyhow::Result<()> {
+        // A client that connects and then never gets around to sending
+        // its request would otherwise tie up this thread forever.
+        let handshake_timeout = match &self.config.get().handshake_timeout {
+            Some(src) => duration::parse(src).context("parsing handshake_timeout")?,
+            None => DEFAULT_HANDSHAKE_TIMEOUT,
+        };
+        let deadline = Instant::now() + handshake_timeout;
         stream
-            .set_read_timeout(Some(consts::SOCK_STREAM_TIMEOUT))
-            .context("setting read timout on inbound session")?;
+            .set_write_timeout(Some(handshake_timeout))
+            .context("setting write timout on inbound session")?;
 
         // advertize our protocol version to the client so that it can
         // warn about mismatches
@@ -144,10 +401,7 @@ impl Server {
                 // We allow fake version to be injected for ease of testing.
                 // Otherwise we would have to resort to some heinous build
                 // contortions.
-                version: match env::var("SHPOOL_TEST__OVERRIDE_VERSION") {
-                    Ok(fake_version) => fake_version,
-                    Err(_) => String::from(shpool_protocol::VERSION),
-                },
+                version: daemon_version(),
             },
             &mut stream,
         ) {
@@ -164,57 +418,215 @@ impl Server {
             Err(e) => return Err(e).context("while writing version"),
         }
 
-        let header = parse_connect_header(&mut stream).context("parsing connect header")?;
+        let header = match parse_connect_header(&stream, deadline) {
+            Ok(header) => header,
+            Err(e) if protocol::is_timeout(&e) => {
+                warn!("no connect header after {:?}, hanging up", handshake_timeout);
+                self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("parsing connect header"),
+        };
+        drop(pending);
+        let peer = audit::Peer::of(&stream);
+        let (action, sessions) = audit::describe(&header);
 
         if let Err(err) = check_peer(&stream) {
+            self.audit.record(conn_id, peer, action, &sessions, &format!("denied: {:#}", err));
             if let ConnectHeader::Attach(_) = header {
                 write_reply(
                     &mut stream,
-                    AttachReplyHeader { status: AttachStatus::Forbidden(format!("{:?}", err)) },
+                    AttachReplyHeader {
+                        status: AttachStatus::Forbidden(format!("{:?}", err)),
+                        name: None,
+                        checked_frames: false,
+                        ttl_applied: false,
+                    },
                 )?;
             }
             stream.shutdown(net::Shutdown::Both).context("closing stream")?;
             return Err(err);
         };
 
-        // Unset the read timeout before we pass things off to a
+        // Unset the timeouts before we pass things off to a
         // worker thread because it is perfectly fine for there to
         // be no new data for long periods of time when the users
         // is connected to a shell session.
         stream.set_read_timeout(None).context("unsetting read timout on inbound session")?;
-
-        match header {
-            ConnectHeader::Attach(h) => self.handle_attach(stream, conn_id, h),
+        stream.set_write_timeout(None).context("unsetting write timout on inbound session")?;
+
+        let is_attach = matches!(header, ConnectHeader::Attach(_));
+        let res = match header {
+            ConnectHeader::Attach(h) => {
+                let name = h.name.clone();
+                match panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    self.handle_attach(stream, conn_id, peer, h)
+                })) {
+                    Ok(res) => res,
+                    Err(payload) => {
+                        self.tear_down_panicked_session(&name);
+                        Err(anyhow!(
+                            "attach to session '{}' panicked: {}",
+                            name,
+                            panic_msg(&*payload)
+                        ))
+                    }
+                }
+            }
             ConnectHeader::Detach(r) => self.handle_detach(stream, r),
             ConnectHeader::Kill(r) => self.handle_kill(stream, r),
+            ConnectHeader::ExtendTtl(r) => self.handle_extend_ttl(stream, r),
             ConnectHeader::List => self.handle_list(stream),
+            ConnectHeader::Export => self.handle_export(stream),
+            ConnectHeader::Import(r) => self.handle_import(stream, r),
+            ConnectHeader::DumpState => self.handle_dump_state(stream),
+            ConnectHeader::Stats => self.handle_stats(stream),
+            ConnectHeader::AdoptPid(r) => self.handle_adopt_pid(stream, r),
+            ConnectHeader::Broadcast(r) => self.handle_broadcast(stream, r),
+            ConnectHeader::LogLevel(r) => self.handle_log_level(stream, r),
+            ConnectHeader::Profile(r) => self.handle_profile(stream, r),
+            ConnectHeader::Copy(r) => self.handle_copy(stream, r),
+            ConnectHeader::Paste(r) => self.handle_paste(stream, r),
+            ConnectHeader::Pipe(r) => self.handle_pipe(stream, r),
+            ConnectHeader::Env(r) => self.handle_env(stream, r),
             ConnectHeader::SessionMessage(header) => self.handle_session_message(stream, header),
-        }
+        };
+
+        // attaches already got an entry when we replied to them, so
+        // this one marks the end of the connection
+        let action = if is_attach { "disconnect" } else { action };
+        let outcome = match &res {
+            Ok(_) => String::from("ok"),
+            Err(e) => format!("error: {:#}", e),
+        };
+        self.audit.record(conn_id, peer, action, &sessions, &outcome);
+
+        res
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(s = header.name, cid = conn_id))]
     fn handle_attach(
         &self,
         mut stream: UnixStream,
         conn_id: usize,
-        header: AttachHeader,
+        peer: audit::Peer,
+        mut header: AttachHeader,
     ) -> anyhow::Result<()> {
+        if header.auto_name {
+            // N.B. two clients asking for auto names at once could in
+            // theory both pick the same free name, in which case the
+            // second sees the session as busy.
+            let shells = shell::read_table(&self.shells);
+            header.name = session_name::generate(|name| shells.contains_key(name));
+            info!("picked name '{}' for new session", header.name);
+        }
+
+        // Panes live in the table under their full address, so check
+        // the parts on their own and then put them together.
+        let name_check = session_name::validate(&header.name).and_then(|()| match &header.pane {
+            Some(pane) => session_name::validate_pane(pane),
+            None => Ok(()),
+        });
+        if let Some(pane) = header.pane.take() {
+            header.name = session_name::pane_address(&header.name, &pane);
+        }
+
+        let audit_attach = |outcome: &str| {
+            self.audit.record(conn_id, peer, "attach", std::slice::from_ref(&header.name), outcome)
+        };
+
+        if header.udp_transport {
+            let bind_addr = self.config.get().udp_bind_addr.clone();
+            stream = negotiate_udp(stream, bind_addr).context("negotiating udp transport")?;
+        }
+
+        if self.config.get().strict_version_check.unwrap_or(false) {
+            if let Err(reason) = check_client_version(&header.client_version) {
+                info!("refusing attach: {}", reason);
+                audit_attach(&format!("forbidden: {}", reason));
+                write_reply(
+                    &mut stream,
+                    AttachReplyHeader {
+                        status: AttachStatus::Forbidden(reason),
+                        name: None,
+                        checked_frames: false,
+                        ttl_applied: false,
+                    },
+                )?;
+                stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                return Ok(());
+            }
+        }
+
+        if let Err(reason) = name_check {
+            info!("refusing attach: {}", reason);
+            audit_attach(&format!("invalid name: {}", reason));
+            write_reply(
+                &mut stream,
+                AttachReplyHeader {
+                    status: AttachStatus::InvalidName(reason),
+                    name: None,
+                    checked_frames: false,
+                    ttl_applied: false,
+                },
+            )?;
+            stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+            return Ok(());
+        }
+
+        if let Some(cmd) = &header.cmd {
+            if let Err(reason) = self.check_cmd(&header.name, cmd) {
+                info!("refusing attach: {}", reason);
+                audit_attach(&format!("forbidden: {}", reason));
+                write_reply(
+                    &mut stream,
+                    AttachReplyHeader {
+                        status: AttachStatus::Forbidden(reason),
+                        name: None,
+                        checked_frames: false,
+                        ttl_applied: false,
+                    },
+                )?;
+                stream.shutdown(net::Shutdown::Both).context("closing stream")?;
+                return Ok(());
+            }
+        }
+
         // We don't currently populate any warnings, but we used to and we might
         // want to in the future, so it is not worth breaking the protocol over.
         let warnings = vec![];
 
         let user_info = user::info().context("resolving user info")?;
-        let shell_env = self.build_shell_env(&user_info, &header).context("building shell env")?;
+        let shell_env =
+            self.build_shell_env(&user_info, &header, 1).context("building shell env")?;
 
-        let (child_exit_notifier, inner_to_stream, pager_ctl_slot, status) = {
-            // we unwrap to propagate the poison as an unwind
+        self.wait_for_pending_attach(&header.name);
+
+        let (
+            child_exit_notifier,
+            inner_to_stream,
+            pager_ctl_slot,
+            attach_pending,
+            attachment,
+            status,
+        ) = {
             let _s = span!(Level::INFO, "1_lock(shells)").entered();
-            let mut shells = self.shells.lock().unwrap();
+            let mut shells = shell::write_table(&self.shells);
 
             let mut status = AttachStatus::Attached { warnings: warnings.clone() };
             if let Some(session) = shells.get(&header.name) {
                 info!("found entry for '{}'", header.name);
-                if let Ok(mut inner) = session.inner.try_lock() {
+                // If the session is still pending after we waited on it,
+                // the creator is wedged somehow, so we tell the client
+                // rather than stealing the session.
+                let creating = session.attach_pending.is_pending();
+                let claimed = if creating {
+                    warn!("session is still being created by another client");
+                    None
+                } else {
+                    session.inner.try_lock().ok()
+                };
+                if let Some(mut inner) = claimed {
                     let _s = span!(Level::INFO, "aquired_lock(session.inner)", s = header.name)
                         .entered();
                     // We have an existing session in our table, but the subshell
@@ -232,6 +644,7 @@ impl Server {
                             // the channel is still open so the subshell is still running
                             info!("taking over existing session inner");
                             inner.client_stream = Some(stream.try_clone()?);
+                            inner.frames = protocol::FrameWriter::for_client(&header);
 
                             if inner
                                 .shell_to_client_join_h
@@ -274,12 +687,30 @@ impl Server {
 
                     // fallthrough to bidi streaming
                 } else {
-                    info!("busy shell session, doing nothing");
+                    let status = if creating {
+                        info!("shell session still being created, doing nothing");
+                        audit_attach("being created");
+                        AttachStatus::BeingCreated
+                    } else {
+                        info!("busy shell session, doing nothing");
+                        audit_attach("busy");
+                        AttachStatus::Busy
+                    };
                     // The stream is busy, so we just inform the client and close the stream.
-                    write_reply(&mut stream, AttachReplyHeader { status: AttachStatus::Busy })?;
+                    write_reply(
+                        &mut stream,
+                        AttachReplyHeader {
+                            status,
+                            name: None,
+                            checked_frames: false,
+                            ttl_applied: false,
+                        },
+                    )?;
                     stream.shutdown(net::Shutdown::Both).context("closing stream")?;
-                    if let Err(err) = self.hooks.on_busy(&header.name) {
-                        warn!("busy hook: {:?}", err);
+                    if !creating {
+                        if let Err(err) = self.hooks.on_busy(&header.name) {
+                            warn!("busy hook: {:?}", err);
+                        }
                     }
                     return Ok(());
                 }
@@ -298,17 +729,50 @@ impl Server {
                 let motd = self.config.get().motd.clone().unwrap_or_default();
                 let session = self.spawn_subshell(
                     conn_id,
-                    stream,
+                    Some(stream),
                     &header,
                     &user_info,
                     &shell_env,
                     matches!(motd, MotdDisplayMode::Dump),
                 )?;
 
-                shells.insert(header.name.clone(), Box::new(session));
+                let restart = header
+                    .restart
+                    .or_else(|| self.template(&header.template).and_then(|t| t.restart))
+                    .unwrap_or_default();
+                if restart != RestartPolicy::Never {
+                    session.restart_on_exit.store(true, Ordering::Release);
+                    self.spawn_supervisor(&header, restart, Running::of(&session));
+                }
+
+                shells.insert(header.name.clone(), Arc::new(session));
                 // fallthrough to bidi streaming
-            } else if let Err(err) = self.hooks.on_reattach(&header.name) {
-                warn!("reattach hook: {:?}", err);
+            } else {
+                if let Err(err) = self.hooks.on_reattach(&header.name) {
+                    warn!("reattach hook: {:?}", err);
+                }
+                if let Some(session) = shells.get(&header.name) {
+                    if let Some(ttl_secs) = header.ttl_secs {
+                        let reap_at = self.clock.now().add(Duration::from_secs(ttl_secs));
+                        info!("resetting ttl for '{}' to {:?} on reattach", header.name, reap_at);
+                        // re-registering bumps the reaper's generation for the session,
+                        // so any old deadline gets ignored
+                        self.register_new_reapable_session
+                            .send((header.name.clone(), reap_at))
+                            .context("sending reapable session re-registration msg")?;
+                        *session.reap_at.lock().unwrap() = Some(reap_at);
+                    }
+                    *session.client_terminal.lock().unwrap() = header.terminal.clone();
+                    let attach_count = session.attach_count.fetch_add(1, Ordering::AcqRel) + 1;
+                    if let Err(err) = self.publish_attach_count(&header.name, attach_count) {
+                        warn!("publishing attach count: {:?}", err);
+                    }
+                }
+                if self.config.get().follow_client_cwd.unwrap_or(false) {
+                    if let Err(err) = self.request_client_cwd(&header) {
+                        warn!("requesting shell cd to client cwd: {:?}", err);
+                    }
+                }
             }
 
             // return a reference to the inner session so that
@@ -319,21 +783,29 @@ impl Server {
                     Some(Arc::clone(&session.child_exit_notifier)),
                     Some(Arc::clone(&session.inner)),
                     Some(Arc::clone(&session.pager_ctl)),
+                    Some(session.attach_pending.guard()),
+                    Some(Arc::clone(&session.attachment)),
                     status,
                 )
             } else {
-                (None, None, None, status)
+                (None, None, None, None, None, status)
             }
         };
         info!("released lock on shells table");
 
         self.link_ssh_auth_sock(&header).context("linking SSH_AUTH_SOCK")?;
 
-        if let (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot)) =
-            (child_exit_notifier, inner_to_stream, pager_ctl_slot)
+        if let (Some(child_exit_notifier), Some(inner), Some(pager_ctl_slot), Some(attachment)) =
+            (child_exit_notifier, inner_to_stream, pager_ctl_slot, attachment)
         {
             let mut child_done = false;
             let mut inner = inner.lock().unwrap();
+            let _attached = attachment.attach();
+            // now that we hold the inner lock, other clients will see the
+            // session as busy
+            drop(attach_pending);
+            test_hooks::maybe_panic("attach", &header.name);
+            let frames = inner.frames.clone();
             let client_stream = match inner.client_stream.as_mut() {
                 Some(s) => s,
                 None => {
@@ -341,8 +813,27 @@ impl Server {
                 }
             };
 
-            let reply_status =
-                write_reply(client_stream, AttachReplyHeader { status: status.clone() });
+            audit_attach(match status {
+                AttachStatus::Created { .. } => "created",
+                _ => "attached",
+            });
+            self.lastlog.record(
+                &header.name,
+                match status {
+                    AttachStatus::Created { .. } => lastlog::Event::Create,
+                    _ => lastlog::Event::Attach,
+                },
+                header.ssh_client.as_deref(),
+            );
+            let reply_status = write_reply(
+                client_stream,
+                AttachReplyHeader {
+                    status: status.clone(),
+                    name: Some(header.name.clone()),
+                    checked_frames: header.checked_frames,
+                    ttl_applied: header.ttl_secs.is_some(),
+                },
+            );
             if let Err(e) = reply_status {
                 error!("error writing reply status: {:?}", e);
             }
@@ -354,6 +845,7 @@ impl Server {
             let init_tty_size = if matches!(motd_mode, MotdDisplayMode::Pager { .. }) {
                 match self.daily_messenger.display_in_pager(
                     client_stream,
+                    &frames,
                     pager_ctl_slot,
                     header.local_tty_size.clone(),
                     &shell_env,
@@ -380,6 +872,23 @@ impl Server {
                 header.local_tty_size.clone()
             };
 
+            // Held until the attach finishes, so the session shows up in
+            // utmp until the client detaches or the shell exits.
+            let _utmp_login = if self.config.get().utmp.unwrap_or(false) {
+                match (pty_name(&inner.pty_master), inner.pty_master.child_pid()) {
+                    (Ok(pty), Some(pid)) => {
+                        Some(utmp::Login::new(&pty, pid, &user_info.user, &header.name))
+                    }
+                    (Err(e), _) => {
+                        warn!("not recording utmp login: {:?}", e);
+                        None
+                    }
+                    (_, None) => None,
+                }
+            } else {
+                None
+            };
+
             info!("starting bidi stream loop");
             match inner.bidi_stream(conn_id, init_tty_size, child_exit_notifier) {
                 Ok(done) => {
@@ -390,6 +899,11 @@ impl Server {
                 }
             }
             info!("bidi stream loop finished child_done={}", child_done);
+            self.lastlog.record(
+                &header.name,
+                if child_done { lastlog::Event::Exit } else { lastlog::Event::Detach },
+                header.ssh_client.as_deref(),
+            );
 
             if child_done {
                 info!("'{}' exited, removing from session table", header.name);
@@ -397,7 +911,7 @@ impl Server {
                     warn!("shell_disconnect hook: {:?}", err);
                 }
                 let _s = span!(Level::INFO, "2_lock(shells)").entered();
-                let mut shells = self.shells.lock().unwrap();
+                let mut shells = shell::write_table(&self.shells);
                 shells.remove(&header.name);
 
                 // The child shell has exited, so the shell->client thread should
@@ -422,7 +936,205 @@ impl Server {
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// If another client is in the middle of creating the named session,
+    /// give it a chance to finish claiming the session before we go
+    /// looking at it, so that we see a busy session rather than racing
+    /// the creator for it.
+    fn wait_for_pending_attach(&self, name: &str) {
+        let session = match shell::get_session(&self.shells, name) {
+            Some(s) if s.attach_pending.is_pending() => s,
+            _ => return,
+        };
+        info!("'{}' is being created by another client, waiting", name);
+        if session.attach_pending.wait_created(PENDING_ATTACH_TIMEOUT) {
+            warn!("gave up waiting for another client to finish creating '{}'", name);
+        }
+    }
+
+    /// Create the given autostart session and then keep it going
+    /// according to its restart policy.
+    fn supervise_autostart_session(
+        &self,
+        autostart: &config::AutostartSession,
+    ) -> anyhow::Result<()> {
+        let header = AttachHeader {
+            name: autostart.name.clone(),
+            cmd: autostart.cmd.clone(),
+            ..Default::default()
+        };
+        self.supervise_session(&header, autostart.restart, None)
+    }
+
+    /// Start a thread that keeps an attach created session going
+    /// according to its restart policy.
+    fn spawn_supervisor(&self, header: &AttachHeader, policy: RestartPolicy, running: Running) {
+        let Some(server) = self.this.upgrade() else {
+            return;
+        };
+        let builder = threads::for_session("restart", &header.name, &self.config);
+        let header = header.clone();
+        let res = builder.spawn(move || {
+            let _s = span!(Level::INFO, "supervise", s = header.name).entered();
+            if let Err(e) = server.supervise_session(&header, policy, Some(running)) {
+                error!("supervising session '{}': {:?}", header.name, e);
+                server.recent_errors.record("supervising session", &e);
+            }
+        });
+        if let Err(e) = res {
+            error!("spawning restart supervisor: {:?}", e);
+        }
+    }
+
+    /// Wait for the session's shell to exit, then launch it again as a
+    /// detached session with the same name for as long as the restart
+    /// policy says to. If the session is not already `running`, this
+    /// creates it first.
+    fn supervise_session(
+        &self,
+        header: &AttachHeader,
+        policy: RestartPolicy,
+        mut running: Option<Running>,
+    ) -> anyhow::Result<()> {
+        let mut restart_delay = INITIAL_RESTART_DELAY;
+        let mut notice = None;
+        loop {
+            let started_at = self.clock.now();
+            let Running { child_exit_notifier, restart_on_exit, child_pid } = match running.take() {
+                Some(running) => running,
+                None => {
+                    let _s = span!(Level::INFO, "lock(shells)").entered();
+                    let mut shells = shell::write_table(&self.shells);
+                    if shells.contains_key(&header.name) {
+                        info!("'{}' already exists, not starting it", header.name);
+                        return Ok(());
+                    }
+
+                    info!("starting '{}'", header.name);
+                    let session = self.create_detached_session(&mut shells, header)?;
+                    session
+                        .restart_on_exit
+                        .store(policy != RestartPolicy::Never, Ordering::Release);
+                    if let Some(notice) = notice.take() {
+                        session.inner.lock().unwrap().pending_notice = Some(notice);
+                    }
+                    Running::of(session)
+                }
+            };
+
+            let exit_status = child_exit_notifier.wait(None);
+            info!("supervised session exited with status {:?}", exit_status);
+
+            // If a client is attached, it will remove the session from the
+            // table once it notices the exit, otherwise the stale entry is
+            // ours to clean up. Either way, we wait for the client to be
+            // gone before starting the session again.
+            loop {
+                let attachment = {
+                    let _s = span!(Level::INFO, "lock(shells)").entered();
+                    let mut shells = shell::write_table(&self.shells);
+                    match shells.get(&header.name) {
+                        Some(s) if s.child_pid == child_pid => {
+                            if !s.attachment.is_attached() {
+                                shells.remove(&header.name);
+                                break;
+                            }
+                            Arc::clone(&s.attachment)
+                        }
+                        _ => break,
+                    }
+                };
+                attachment.wait_detached();
+            }
+
+            let exit_status = exit_status.unwrap_or(-1);
+            if !restart_on_exit.load(Ordering::Acquire) || !policy.should_restart(exit_status) {
+                return Ok(());
+            }
+            notice = Some(format!(
+                "shpool: session '{}' was restarted after its shell exited with status {}",
+                header.name, exit_status
+            ));
+
+            // If the session ran for a good while, this is a fresh failure
+            // rather than a crash loop.
+            if self.clock.now().saturating_duration_since(started_at) > MAX_RESTART_DELAY {
+                restart_delay = INITIAL_RESTART_DELAY;
+            }
+            info!("restarting '{}' in {:?}", header.name, restart_delay);
+            let _ = self.clock.at(self.clock.now() + restart_delay).recv();
+            restart_delay = cmp::min(restart_delay * 2, MAX_RESTART_DELAY);
+        }
+    }
+
+    /// Create a new session with no client attached and add it to the
+    /// session table. The caller must hold the shells lock and have
+    /// already checked that the name is free. Holding the lock also
+    /// ensures that an attach can't sneak in before the session has
+    /// been started detached and then get detached by us.
+    fn create_detached_session<'a>(
+        &self,
+        shells: &'a mut HashMap<String, Arc<shell::Session>>,
+        header: &AttachHeader,
+    ) -> anyhow::Result<&'a shell::Session> {
+        let mut header = AttachHeader {
+            name: header.name.clone(),
+            pane: None,
+            local_tty_size: DETACHED_TTY_SIZE,
+            local_env: header.local_env.clone(),
+            ttl_secs: header.ttl_secs,
+            cmd: header.cmd.clone(),
+            container: header.container.clone(),
+            udp_transport: false,
+            template: header.template.clone(),
+            cwd: header.cwd.clone(),
+            client_version: header.client_version.clone(),
+            ssh_client: header.ssh_client.clone(),
+            auto_name: false,
+            restart: header.restart,
+            terminal: None,
+            checked_frames: false,
+            notice_chunks: false,
+        };
+        if header.local_env_get("TERM").is_none() {
+            header.local_env.push((String::from("TERM"), String::from(DETACHED_TERM)));
+        }
+
+        let user_info = user::info().context("resolving user info")?;
+        let shell_env =
+            self.build_shell_env(&user_info, &header, 0).context("building shell env")?;
+
+        if let Err(err) = self.hooks.on_new_session(&header.name) {
+            warn!("new_session hook: {:?}", err);
+        }
+        let session = self.spawn_subshell(0, None, &header, &user_info, &shell_env, false)?;
+        session.start_detached().context("starting session detached")?;
+        self.lastlog.record(&header.name, lastlog::Event::Create, header.ssh_client.as_deref());
+
+        let session = shells.entry(header.name.clone()).or_insert(Arc::new(session));
+        Ok(session)
+    }
+
+    /// Clean up after a session whose attach handler panicked. The
+    /// session may have been left in an inconsistent state (for example
+    /// with its inner lock poisoned), so rather than trying to keep it
+    /// going we kill it and drop it from the table.
+    #[instrument(skip_all, fields(s = name))]
+    fn tear_down_panicked_session(&self, name: &str) {
+        error!("session handler panicked, tearing down session");
+        let session = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let mut shells = shell::write_table(&self.shells);
+            shells.remove(name)
+        };
+        if let Some(session) = session {
+            if let Err(e) = session.kill() {
+                error!("killing panicked session: {:?}", e);
+            }
+        }
+        test_hooks::emit("daemon-session-panicked");
+    }
+
+    #[instrument(skip_all, fields(s = header.name))]
     fn link_ssh_auth_sock(&self, header: &AttachHeader) -> anyhow::Result<()> {
         if self.config.get().nosymlink_ssh_auth_sock.unwrap_or(false) {
             return Ok(());
@@ -457,78 +1169,302 @@ impl Server {
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(s = ?request.sessions))]
     fn handle_detach(&self, mut stream: UnixStream, request: DetachRequest) -> anyhow::Result<()> {
-        let mut not_found_sessions = vec![];
         let mut not_attached_sessions = vec![];
-        {
-            let _s = span!(Level::INFO, "lock(shells)").entered();
-            let shells = self.shells.lock().unwrap();
-            for session in request.sessions.into_iter() {
-                if let Some(s) = shells.get(&session) {
-                    let _s = span!(Level::INFO, "lock(shell_to_client_ctl)", s = session).entered();
-                    let shell_to_client_ctl = s.shell_to_client_ctl.lock().unwrap();
-                    shell_to_client_ctl
-                        .client_connection
-                        .send(shell::ClientConnectionMsg::Disconnect)
-                        .context("sending client detach to shell->client")?;
-                    let status = shell_to_client_ctl
-                        .client_connection_ack
-                        .recv()
-                        .context("getting client conn ack")?;
-                    info!("detached session({}), status = {:?}", session, status);
-                    if let shell::ClientConnectionStatus::DetachNone = status {
-                        not_attached_sessions.push(session);
-                    }
-                } else {
-                    not_found_sessions.push(session);
+        let mut matched_sessions = vec![];
+        let (selected, not_found_sessions) = self.select_sessions(&request.sessions, request.panes);
+        for (selector::Selected { name: session, explicit }, s) in selected.into_iter() {
+            matched_sessions.push(session.clone());
+            if request.dry_run {
+                continue;
+            }
+            // Let whoever is attached know why they are getting
+            // kicked out, since this is most likely a forced attach
+            // from some other terminal.
+            if let Err(err) = s.notify("detached by another shpool client") {
+                warn!("notifying '{}' of detach: {:?}", session, err);
+            }
+
+            let _s = span!(Level::INFO, "lock(shell_to_client_ctl)", s = session).entered();
+            let shell_to_client_ctl = s.shell_to_client_ctl.lock().unwrap();
+            shell_to_client_ctl
+                .client_connection
+                .send(shell::ClientConnectionMsg::Disconnect)
+                .context("sending client detach to shell->client")?;
+            let status = shell_to_client_ctl
+                .client_connection_ack
+                .recv()
+                .context("getting client conn ack")?;
+            info!("detached session({}), status = {:?}", session, status);
+            if let shell::ClientConnectionStatus::DetachNone = status {
+                if explicit {
+                    not_attached_sessions.push(session);
                 }
             }
         }
 
-        write_reply(&mut stream, DetachReply { not_found_sessions, not_attached_sessions })
-            .context("writing detach reply")?;
+        write_reply(
+            &mut stream,
+            DetachReply { not_found_sessions, not_attached_sessions, matched_sessions },
+        )
+        .context("writing detach reply")?;
 
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// Resolve session selectors against the session table, returning
+    /// handles on the selected sessions along with the selectors that
+    /// matched nothing. With `panes`, selecting a session also selects
+    /// its panes. The table lock is only held for the lookup.
+    fn select_sessions(
+        &self,
+        selectors: &[String],
+        panes: bool,
+    ) -> (Vec<(selector::Selected, Arc<shell::Session>)>, Vec<String>) {
+        let _s = span!(Level::INFO, "select_lock(shells)").entered();
+        let shells = shell::read_table(&self.shells);
+        let (selected, not_found) = if panes {
+            selector::resolve_with_panes(selectors, shells.keys())
+        } else {
+            selector::resolve(selectors, shells.keys())
+        };
+        let selected = selected
+            .into_iter()
+            .filter_map(|sel| shells.get(&sel.name).map(|s| (sel, Arc::clone(s))))
+            .collect();
+        (selected, not_found)
+    }
+
+    #[instrument(skip_all, fields(s = ?request.sessions))]
     fn handle_kill(&self, mut stream: UnixStream, request: KillRequest) -> anyhow::Result<()> {
-        let mut not_found_sessions = vec![];
-        {
+        let reply = self.kill_sessions(&request)?;
+        write_reply(&mut stream, reply).context("writing kill reply")?;
+
+        Ok(())
+    }
+
+    /// The control socket that the daemon is serving on.
+    #[cfg(feature = "dbus")]
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    #[cfg(feature = "dbus")]
+    pub fn audit(&self) -> &audit::Log {
+        &self.audit
+    }
+
+    /// Kill the sessions that the request selects.
+    pub fn kill_sessions(&self, request: &KillRequest) -> anyhow::Result<KillReply> {
+        let mut matched_sessions = vec![];
+        let (selected, not_found_sessions) = self.select_sessions(&request.sessions, request.panes);
+        let mut to_remove = Vec::with_capacity(selected.len());
+        for (selector::Selected { name: session, .. }, s) in selected.into_iter() {
+            matched_sessions.push(session.clone());
+            if request.dry_run {
+                continue;
+            }
+            s.restart_on_exit.store(false, Ordering::Release);
+            s.kill().context("killing shell proc")?;
+            self.lastlog.record(&session, lastlog::Event::Kill, None);
+
+            // we don't need to wait since the dedicated reaping thread is active
+            // even when a tty is not attached
+            to_remove.push((session, s));
+        }
+
+        if !to_remove.is_empty() {
             let _s = span!(Level::INFO, "lock(shells)").entered();
-            let mut shells = self.shells.lock().unwrap();
+            let mut shells = shell::write_table(&self.shells);
+            for (name, killed) in to_remove.iter() {
+                // someone may have already replaced the session we killed
+                // with a fresh one while we weren't holding the lock
+                if shells.get(name).map(|s| Arc::ptr_eq(s, killed)).unwrap_or(false) {
+                    shells.remove(name);
+                }
+            }
+            test_hooks::emit("daemon-handle-kill-removed-shells");
+        }
 
-            let mut to_remove = Vec::with_capacity(request.sessions.len());
-            for session in request.sessions.into_iter() {
-                if let Some(s) = shells.get(&session) {
-                    s.kill().context("killing shell proc")?;
+        Ok(KillReply { not_found_sessions, matched_sessions })
+    }
 
-                    // we don't need to wait since the dedicated reaping thread is active
-                    // even when a tty is not attached
-                    to_remove.push(session);
-                } else {
-                    not_found_sessions.push(session);
+    #[instrument(skip_all, fields(s = ?request.sessions))]
+    fn handle_broadcast(
+        &self,
+        mut stream: UnixStream,
+        request: BroadcastRequest,
+    ) -> anyhow::Result<()> {
+        let mut matched_sessions = vec![];
+        let mut failed_sessions = vec![];
+        let (selected, not_found_sessions) = self.select_sessions(&request.sessions, request.panes);
+        for (selector::Selected { name: session, .. }, s) in selected.into_iter() {
+            if !request.dry_run {
+                if let Err(e) = s.write_input(&request.data) {
+                    warn!("broadcasting to '{}': {:?}", session, e);
+                    failed_sessions.push(session.clone());
                 }
             }
+            matched_sessions.push(session);
+        }
+
+        write_reply(
+            &mut stream,
+            BroadcastReply { not_found_sessions, matched_sessions, failed_sessions },
+        )
+        .context("writing broadcast reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_log_level(
+        &self,
+        mut stream: UnixStream,
+        request: LogLevelRequest,
+    ) -> anyhow::Result<()> {
+        let status = log_level::apply(&request.filter);
+        write_reply(&mut stream, LogLevelReply { status }).context("writing log level reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_profile(
+        &self,
+        mut stream: UnixStream,
+        request: ProfileRequest,
+    ) -> anyhow::Result<()> {
+        let status = profile::capture(&self.runtime_dir, &request);
+        write_reply(&mut stream, ProfileReply { status }).context("writing profile reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(len = request.data.len()))]
+    fn handle_copy(&self, mut stream: UnixStream, request: CopyRequest) -> anyhow::Result<()> {
+        let status = match self.clipboard.copy(request.data) {
+            Ok(()) => CopyStatus::Copied,
+            Err(max_bytes) => CopyStatus::TooBig { max_bytes: max_bytes as u64 },
+        };
+        write_reply(&mut stream, CopyReply { status }).context("writing copy reply")?;
+        Ok(())
+    }
 
-            for session in to_remove.iter() {
-                shells.remove(session);
+    #[instrument(skip_all, fields(s = ?request.session))]
+    fn handle_paste(&self, mut stream: UnixStream, request: PasteRequest) -> anyhow::Result<()> {
+        let status = match request.session {
+            Some(session) => {
+                match (
+                    shell::get_session(&self.shells, &session),
+                    self.clipboard.contents_as_input(),
+                ) {
+                    (None, _) => PasteStatus::NotFound,
+                    (Some(_), None) => PasteStatus::Empty,
+                    (Some(s), Some(input)) => {
+                        s.write_input(&input).context("typing clipboard into session")?;
+                        PasteStatus::Typed
+                    }
+                }
             }
-            if !to_remove.is_empty() {
-                test_hooks::emit("daemon-handle-kill-removed-shells");
+            None => match self.clipboard.contents() {
+                Some(data) => PasteStatus::Contents(data),
+                None => PasteStatus::Empty,
+            },
+        };
+        write_reply(&mut stream, PasteReply { status }).context("writing paste reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = request.session))]
+    fn handle_pipe(&self, mut stream: UnixStream, request: PipeRequest) -> anyhow::Result<()> {
+        let Some(session) = shell::get_session(&self.shells, &request.session) else {
+            write_reply(&mut stream, PipeReply { status: PipeStatus::NotFound })
+                .context("writing pipe reply")?;
+            return Ok(());
+        };
+
+        // The reply has to go out before the shell->client thread
+        // starts writing output to the stream.
+        write_reply(&mut stream, PipeReply { status: PipeStatus::Piping })
+            .context("writing pipe reply")?;
+        session
+            .pipe_output(output_pipe::PipeConn { stream, from_start: request.from_start })
+            .context("starting output pipe")?;
+        info!("piping output of '{}'", request.session);
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = request.session))]
+    fn handle_env(&self, mut stream: UnixStream, request: EnvRequest) -> anyhow::Result<()> {
+        let status = match shell::get_session(&self.shells, &request.session) {
+            Some(session) => EnvStatus::Env(session.env.clone()),
+            None => EnvStatus::NotFound,
+        };
+        write_reply(&mut stream, EnvReply { status }).context("writing env reply")?;
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = request.session))]
+    fn handle_extend_ttl(
+        &self,
+        mut stream: UnixStream,
+        request: ExtendTtlRequest,
+    ) -> anyhow::Result<()> {
+        let status = {
+            match shell::get_session(&self.shells, &request.session) {
+                Some(s) => {
+                    let mut reap_at = s.reap_at.lock().unwrap();
+                    match *reap_at {
+                        Some(old_reap_at) => {
+                            let now = self.clock.now();
+                            let new_reap_at = cmp::max(old_reap_at, now)
+                                .add(Duration::from_secs(request.extension_secs));
+                            info!(
+                                "extending ttl for '{}' from {:?} to {:?}",
+                                request.session, old_reap_at, new_reap_at
+                            );
+                            // re-registering bumps the reaper's generation for the session,
+                            // so the old deadline (and its warning) get ignored
+                            self.register_new_reapable_session
+                                .send((request.session.clone(), new_reap_at))
+                                .context("sending reapable session re-registration msg")?;
+                            *reap_at = Some(new_reap_at);
+                            ExtendTtlStatus::Extended {
+                                remaining_secs: new_reap_at.duration_since(now).as_secs(),
+                            }
+                        }
+                        None => ExtendTtlStatus::NoTtl,
+                    }
+                }
+                None => ExtendTtlStatus::NotFound,
             }
-        }
+        };
 
-        write_reply(&mut stream, KillReply { not_found_sessions }).context("writing kill reply")?;
+        write_reply(&mut stream, ExtendTtlReply { status }).context("writing extend ttl reply")?;
 
         Ok(())
     }
 
     #[instrument(skip_all)]
     fn handle_list(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let reply = self.list_sessions()?;
+        write_reply(&mut stream, reply)?;
+
+        Ok(())
+    }
+
+    /// Describe all the current sessions, along with recent exits.
+    pub fn list_sessions(&self) -> anyhow::Result<ListReply> {
+        // walk /proc before taking the lock, it can take a little while
+        let net_bytes = if self.config.get().network_accounting.unwrap_or(false) {
+            Some(net_stat::net_bytes_by_session())
+        } else {
+            None
+        };
+
+        let now = self.clock.now();
         let _s = span!(Level::INFO, "lock(shells)").entered();
-        let shells = self.shells.lock().unwrap();
+        let shells = shell::read_table(&self.shells);
 
         let sessions: anyhow::Result<Vec<Session>> = shells
             .iter()
@@ -537,22 +1473,296 @@ impl Server {
                     Ok(_) => SessionStatus::Disconnected,
                     Err(_) => SessionStatus::Attached,
                 };
+                let memory = v.stats.memory(matches!(status, SessionStatus::Attached));
 
                 Ok(Session {
                     name: k.to_string(),
                     started_at_unix_ms: v.started_at.duration_since(time::UNIX_EPOCH)?.as_millis()
                         as i64,
                     status,
+                    terminal: v.client_terminal.lock().unwrap().clone(),
+                    net: net_bytes.as_ref().map(|net_bytes| {
+                        let bytes = net_bytes.get(&v.child_pid).copied().unwrap_or_default();
+                        NetUsage { rx_bytes: bytes.rx, tx_bytes: bytes.tx }
+                    }),
+                    memory,
+                    reap_in_secs: v
+                        .reap_at
+                        .lock()
+                        .unwrap()
+                        .map(|reap_at| reap_at.saturating_duration_since(now).as_secs()),
+                    client_suspended: v.client_suspended.load(Ordering::Acquire),
                 })
             })
             .collect();
-        let sessions = sessions.context("collecting running session metadata")?;
+        let mut sessions = sessions.context("collecting running session metadata")?;
+        // keep panes next to the session they belong to
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(ListReply { sessions, exit_history: self.exit_history.snapshot() })
+    }
+
+    #[instrument(skip_all)]
+    fn handle_export(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let sessions = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let shells = shell::read_table(&self.shells);
+
+            shells.values().map(|sess| sess.current_definition()).collect::<Vec<_>>()
+        };
+
+        write_reply(&mut stream, ExportReply { sessions }).context("writing export reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_stats(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        // walk /proc before taking the lock, it can take a little while
+        let cpu_times = proc_stat::cpu_time_by_session();
+
+        let sessions = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let shells = shell::read_table(&self.shells);
+
+            shells
+                .iter()
+                .map(|(name, sess)| {
+                    let status = match sess.inner.try_lock() {
+                        Ok(_) => SessionStatus::Disconnected,
+                        Err(_) => SessionStatus::Attached,
+                    };
+                    SessionStats {
+                        name: name.clone(),
+                        status,
+                        output_bytes: sess.stats.output_bytes.load(Ordering::Relaxed),
+                        input_bytes: sess.stats.input_bytes.load(Ordering::Relaxed),
+                        cpu_time_ms: cpu_times
+                            .get(&sess.child_pid)
+                            .map(|t| t.as_millis() as u64)
+                            .unwrap_or(0),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
 
-        write_reply(&mut stream, ListReply { sessions })?;
+        write_reply(&mut stream, StatsReply { sessions }).context("writing stats reply")?;
 
         Ok(())
     }
 
+    #[instrument(skip_all)]
+    fn handle_dump_state(&self, mut stream: UnixStream) -> anyhow::Result<()> {
+        let sessions = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let shells = shell::read_table(&self.shells);
+
+            let now = self.clock.now();
+            let mut sessions = shells
+                .iter()
+                .map(|(name, sess)| {
+                    let status = match sess.inner.try_lock() {
+                        Ok(_) => SessionStatus::Disconnected,
+                        Err(_) => SessionStatus::Attached,
+                    };
+                    // try_lock so that a wedged pager can't wedge the dump too
+                    let pager_active = match sess.pager_ctl.try_lock() {
+                        Ok(pager_ctl) => pager_ctl.is_some(),
+                        Err(_) => true,
+                    };
+                    flight_recorder::SessionState {
+                        name: name.clone(),
+                        status: status.to_string(),
+                        child_pid: sess.child_pid,
+                        started_at_unix_ms: flight_recorder::unix_ms(sess.started_at),
+                        reap_in_secs: sess
+                            .reap_at
+                            .lock()
+                            .unwrap()
+                            .map(|reap_at| reap_at.saturating_duration_since(now).as_secs()),
+                        restart_on_exit: sess.restart_on_exit.load(Ordering::Acquire),
+                        output_bytes: sess.stats.output_bytes.load(Ordering::Relaxed),
+                        shell_to_client_running: sess
+                            .stats
+                            .shell_to_client_running
+                            .load(Ordering::Relaxed),
+                        pager_active,
+                        memory: sess.stats.memory(matches!(status, SessionStatus::Attached)),
+                    }
+                })
+                .collect::<Vec<_>>();
+            sessions.sort_by(|a, b| a.name.cmp(&b.name));
+            sessions
+        };
+
+        let state = flight_recorder::DaemonState {
+            version: daemon_version(),
+            pid: std::process::id(),
+            dumped_at_unix_ms: flight_recorder::unix_ms(time::SystemTime::now()),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            // don't count the connection asking for the dump
+            active_connections: self.active_connections.load(Ordering::Relaxed).saturating_sub(1),
+            pending_connections: self.pending_connections.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            sessions,
+            recent_errors: self.recent_errors.snapshot(),
+        };
+        let state_json = serde_json::to_string_pretty(&state).context("formatting state")?;
+
+        write_reply(&mut stream, DumpStateReply { state_json })
+            .context("writing dump state reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_import(&self, mut stream: UnixStream, request: ImportRequest) -> anyhow::Result<()> {
+        let reply = self.import_sessions(request.sessions)?;
+
+        write_reply(&mut stream, reply).context("writing import reply")?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(s = request.name, pid = request.pid))]
+    fn handle_adopt_pid(
+        &self,
+        mut stream: UnixStream,
+        request: AdoptPidRequest,
+    ) -> anyhow::Result<()> {
+        let status = self.adopt_pid(request.name, request.pid);
+        info!("adopt-pid status = {:?}", status);
+
+        write_reply(&mut stream, AdoptPidReply { status }).context("writing adopt-pid reply")?;
+
+        Ok(())
+    }
+
+    /// Create a session for an already running process and move the
+    /// process onto its pty. See the adopt_pid module for how.
+    fn adopt_pid(&self, name: String, pid: i32) -> AdoptPidStatus {
+        if !adopt_pid::SUPPORTED {
+            return AdoptPidStatus::Unsupported;
+        }
+        if let Err(reason) = session_name::validate(&name) {
+            return AdoptPidStatus::InvalidName(reason);
+        }
+
+        let pty_path = {
+            let _s = span!(Level::INFO, "lock(shells)").entered();
+            let mut shells = shell::write_table(&self.shells);
+            if shells.contains_key(&name) {
+                return AdoptPidStatus::AlreadyExists;
+            }
+
+            // The placeholder is our own command rather than the
+            // client's, so it doesn't go through the cmd_policy.
+            let header = AttachHeader {
+                name: name.clone(),
+                cmd: Some(adopt_pid::placeholder_cmd(pid)),
+                cwd: platform::cwd_for_pid(unistd::Pid::from_raw(pid))
+                    .ok()
+                    .map(|p| p.to_string_lossy().into_owned()),
+                ..Default::default()
+            };
+            let session = match self.create_detached_session(&mut shells, &header) {
+                Ok(s) => s,
+                Err(e) => return AdoptPidStatus::Failed(format!("creating session: {:#}", e)),
+            };
+            let inner = session.inner.lock().unwrap();
+            pty_name(&inner.pty_master)
+        };
+
+        match pty_path.and_then(|pty_path| adopt_pid::adopt(pid, &pty_path)) {
+            Ok(()) => AdoptPidStatus::Adopted,
+            Err(e) => {
+                warn!("adopting pid {}: {:?}", pid, e);
+                let session = {
+                    let _s = span!(Level::INFO, "lock(shells)").entered();
+                    shell::write_table(&self.shells).remove(&name)
+                };
+                if let Some(session) = session {
+                    if let Err(e) = session.kill() {
+                        warn!("killing placeholder session: {:?}", e);
+                    }
+                }
+                AdoptPidStatus::Failed(format!("{:#}", e))
+            }
+        }
+    }
+
+    /// Create detached sessions from the given definitions, returning
+    /// the names of any that were skipped because a session with the
+    /// same name already exists or because the cmd_policy forbids
+    /// their command.
+    pub fn import_sessions(&self, defs: Vec<SessionDefinition>) -> anyhow::Result<ImportReply> {
+        let mut already_exists = vec![];
+        let mut forbidden = vec![];
+        let mut failed = vec![];
+
+        let _s = span!(Level::INFO, "lock(shells)").entered();
+        let mut shells = shell::write_table(&self.shells);
+        for def in defs.into_iter() {
+            if shells.contains_key(&def.name) {
+                already_exists.push(def.name);
+                continue;
+            }
+
+            // exports include panes under their full address
+            if let Err(reason) = session_name::validate_address(&def.name) {
+                warn!("not importing '{}': {}", def.name, reason);
+                forbidden.push(def.name);
+                continue;
+            }
+
+            if let Some(cmd) = &def.cmd {
+                if let Err(reason) = self.check_cmd(&def.name, cmd) {
+                    warn!("not importing '{}': {}", def.name, reason);
+                    forbidden.push(def.name);
+                    continue;
+                }
+            }
+
+            info!("importing '{}'", def.name);
+            let header = AttachHeader {
+                name: def.name,
+                local_env: def.env,
+                cmd: def.cmd,
+                cwd: def.cwd,
+                ..Default::default()
+            };
+            // One session failing to start shouldn't keep the rest
+            // from getting imported.
+            if let Err(e) = self.create_detached_session(&mut shells, &header) {
+                warn!("importing '{}': {:?}", header.name, e);
+                failed.push(ImportFailure { name: header.name, error: format!("{:#}", e) });
+            }
+        }
+
+        Ok(ImportReply { already_exists, forbidden, failed })
+    }
+
+    /// Check a client supplied command against the check_cmd hook and
+    /// the cmd_policy, returning the reason if it is not allowed.
+    fn check_cmd(&self, session_name: &str, cmd: &str) -> Result<(), String> {
+        match self.hooks.check_cmd(session_name, cmd) {
+            CmdDecision::Allow => return Ok(()),
+            CmdDecision::Deny(reason) => return Err(reason),
+            CmdDecision::UseConfig => {}
+        }
+
+        match &self.config.get().cmd_policy {
+            Some(policy) => match cmd_policy::compile(policy) {
+                Ok(policy) => policy.check(cmd),
+                // The config gets validated on load, so this should not
+                // happen, but fail closed if it somehow does.
+                Err(e) => Err(format!("invalid cmd_policy: {:?}", e)),
+            },
+            None => Ok(()),
+        }
+    }
+
     #[instrument(skip_all, fields(s = &header.session_name))]
     fn handle_session_message(
         &self,
@@ -562,9 +1772,7 @@ impl Server {
         // create a slot to store our reply so we can do
         // our IO without the lock held.
         let reply = {
-            let _s = span!(Level::INFO, "lock(shells)").entered();
-            let shells = self.shells.lock().unwrap();
-            if let Some(session) = shells.get(&header.session_name) {
+            if let Some(session) = shell::get_session(&self.shells, &header.session_name) {
                 match header.payload {
                     SessionMessageRequestPayload::Resize(resize_request) => {
                         let _s = span!(Level::INFO, "lock(pager_ctl)").entered();
@@ -612,6 +1820,21 @@ impl Server {
                         info!("detached session({}), status = {:?}", header.session_name, status);
                         SessionMessageReply::Detach(SessionMessageDetachReply::Ok)
                     }
+                    SessionMessageRequestPayload::Suspend(suspend_request) => {
+                        info!(
+                            "client of session({}) suspended = {}",
+                            header.session_name, suspend_request.suspended
+                        );
+                        session
+                            .client_suspended
+                            .store(suspend_request.suspended, Ordering::Release);
+                        SessionMessageReply::Suspend(SuspendReply::Ok)
+                    }
+                    SessionMessageRequestPayload::Redraw => {
+                        info!("redrawing session({})", header.session_name);
+                        session.redraw()?;
+                        SessionMessageReply::Redraw(RedrawReply::Ok)
+                    }
                 }
             } else {
                 SessionMessageReply::NotFound
@@ -626,17 +1849,20 @@ impl Server {
     /// Spawn a subshell and return the sessession descriptor for it. The
     /// session is wrapped in an Arc so the inner session can hold a Weak
     /// back-reference to the session.
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(s = header.name, cid = conn_id))]
     fn spawn_subshell(
         &self,
         conn_id: usize,
-        client_stream: UnixStream,
+        client_stream: Option<UnixStream>,
         header: &AttachHeader,
         user_info: &user::Info,
         shell_env: &[(String, String)],
         dump_motd_on_new_session: bool,
     ) -> anyhow::Result<shell::Session> {
-        let shell = if let Some(s) = &self.config.get().shell {
+        let initial_attach_count = usize::from(client_stream.is_some());
+        let template = self.template(&header.template).unwrap_or_default();
+        let custom_cmd = header.cmd.clone().or(template.cmd);
+        let shell = if let Some(s) = template.shell.as_ref().or(self.config.get().shell.as_ref()) {
             s.clone()
         } else {
             user_info.default_shell.clone()
@@ -647,41 +1873,42 @@ impl Server {
         // We will exec this command after a fork, so we want to just inherit
         // stdout/stderr/stdin. The pty crate automatically `dup2`s the file
         // descriptors for us.
-        let mut cmd = if let Some(cmd_str) = &header.cmd {
+        let mut shell_cmd = if let Some(cmd_str) = &custom_cmd {
             let cmd_parts = shell_words::split(cmd_str).context("parsing cmd")?;
             info!("running cmd: {:?}", cmd_parts);
             if cmd_parts.is_empty() {
                 return Err(anyhow!("no command to run"));
             }
-            let mut cmd = process::Command::new(&cmd_parts[0]);
-            cmd.args(&cmd_parts[1..]);
-            cmd
+            let mut shell_cmd = command::ShellCommand::new(cmd_parts[0].clone());
+            shell_cmd.args = cmd_parts[1..].to_vec();
+            shell_cmd
         } else {
-            let mut cmd = process::Command::new(&shell);
+            let mut shell_cmd = command::ShellCommand::new(shell.clone());
             if self.config.get().norc.unwrap_or(false) {
                 if shell.ends_with("bash") {
-                    cmd.arg("--norc").arg("--noprofile");
+                    shell_cmd.args = vec![String::from("--norc"), String::from("--noprofile")];
                 } else if shell.ends_with("zsh") {
-                    cmd.arg("--no-rcs");
+                    shell_cmd.args = vec![String::from("--no-rcs")];
                 } else if shell.ends_with("fish") {
-                    cmd.arg("--no-config");
+                    shell_cmd.args = vec![String::from("--no-config")];
                 }
             }
-            cmd
+            shell_cmd
         };
-
-        cmd.current_dir(user_info.home_dir.clone())
-            .stdin(process::Stdio::inherit())
-            .stdout(process::Stdio::inherit())
-            .stderr(process::Stdio::inherit())
-            // The env should mostly be set up by the shell sourcing
-            // rc files and whatnot, so we will start things off with
-            // an environment that is blank except for a few vars we inject
-            // to avoid breakage and vars the user has asked us to inject.
-            .env_clear();
+        // Launch in the requested directory if there is one, falling back
+        // to the home dir if it does not exist here (for example, because
+        // the client is attaching from a different machine over ssh).
+        shell_cmd.cwd = match &header.cwd {
+            Some(cwd) if Path::new(cwd).is_absolute() && Path::new(cwd).is_dir() => cwd.clone(),
+            Some(cwd) => {
+                warn!("'{}' is not a directory, launching shell in home dir instead", cwd);
+                user_info.home_dir.clone()
+            }
+            None => user_info.home_dir.clone(),
+        };
+        shell_cmd.env = shell_env.to_vec();
 
         let term = shell_env.iter().filter(|(k, _)| k == "TERM").map(|(_, v)| v).next();
-        cmd.envs(shell_env.to_vec());
         let fallback_terminfo = || match termini::TermInfo::from_name("xterm") {
             Ok(db) => Ok(db),
             Err(err) => {
@@ -709,7 +1936,7 @@ impl Server {
             }
         });
 
-        if header.cmd.is_none() {
+        if custom_cmd.is_none() {
             // spawn the shell as a login shell by setting
             // arg0 to be the basename of the shell path
             // proceeded with a "-". You can see sshd doing the
@@ -720,24 +1947,99 @@ impl Server {
                 .ok_or(anyhow!("error building login shell indicator"))?
                 .to_str()
                 .ok_or(anyhow!("error parsing shell name as utf8"))?;
-            cmd.arg0(format!("-{}", shell_basename));
+            shell_cmd.arg0 = Some(format!("-{}", shell_basename));
         };
 
+        let config_container = self.config.get().container.clone();
+        let container = match header
+            .container
+            .as_ref()
+            .or(template.container.as_ref())
+            .or(config_container.as_ref())
+        {
+            Some(target) => {
+                Some(target.parse::<command::ContainerTarget>().context("parsing container")?)
+            }
+            None => None,
+        };
+        if let Some(target) = &container {
+            info!("wrapping shell to run in container target '{}'", target);
+        }
+        let mut cmd = shell_cmd.build(container.as_ref()).context("building shell command")?;
+
+        let sched = scheduling::resolve(&self.config.get(), &header.name)
+            .context("resolving scheduling settings")?;
+        let identity =
+            identity::resolve(&self.config.get()).context("resolving umask and groups")?;
+        let pam_session = self
+            .config
+            .get()
+            .pam_service
+            .as_ref()
+            .map(|service| pam::Session::new(service, &user_info.user));
         let noecho = self.config.get().noecho.unwrap_or(false);
-        info!("about to fork subshell noecho={}", noecho);
-        let mut fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
-        if let Ok(slave) = fork.is_child() {
-            if noecho {
+        // posix_spawn can't do any of the setup that has to happen in
+        // the child, so those sessions get forked regardless.
+        let needs_child_setup =
+            sched != scheduling::Resolved::default() || identity != identity::Resolved::default();
+        let spawn_method = self.config.get().spawn_method.unwrap_or_default();
+        let pty = if spawn_method == config::SpawnMethod::PosixSpawn
+            && spawn::supported()
+            && !needs_child_setup
+        {
+            Some(spawn::open_pty(noecho).context("opening pty")?)
+        } else {
+            None
+        };
+
+        // The shell has to be spawned before anyone else gets to open a
+        // session, see the pam module.
+        let pam_spawn_lock = pam_session.as_ref().map(|_| pam::spawn_lock());
+        let pam_open = match &pam_session {
+            Some(pam_session) => {
+                let open = pam_session
+                    .open(pty.as_ref().map(|pty| pty.name()))
+                    .context("opening pam session")?;
+                cmd.envs(open.env().iter().map(|(k, v)| (k, v)));
+                Some(open)
+            }
+            None => None,
+        };
+
+        let mut fork = if let Some(pty) = pty {
+            info!("about to posix_spawn subshell noecho={}", noecho);
+            let arg0 = if container.is_none() { shell_cmd.arg0.as_deref() } else { None };
+            spawn::posix_spawn(pty, &cmd, arg0).context("spawning shell")?
+        } else {
+            info!("about to fork subshell noecho={} sched={:?}", noecho, sched);
+            let fd_limit = spawn::fd_limit();
+            let fork = shpool_pty::fork::Fork::from_ptmx().context("forking pty")?;
+            if let Ok(slave) = fork.is_child() {
                 if let Some(fd) = slave.borrow_fd() {
-                    tty::disable_echo(fd).context("disabling echo on pty")?;
+                    if let Err(e) = platform::acquire_controlling_tty(fd) {
+                        eprintln!("shpool: acquiring controlling tty: {}", e);
+                    }
                 }
+                if noecho {
+                    if let Some(fd) = slave.borrow_fd() {
+                        tty::disable_echo(fd).context("disabling echo on pty")?;
+                    }
+                }
+                sched.apply();
+                identity.apply();
+                spawn::close_inherited_fds(fd_limit);
+                let err = cmd.exec();
+                eprintln!("shell exec err: {:?}", err);
+                std::process::exit(1);
             }
-            for fd in consts::STDERR_FD + 1..(nix::unistd::SysconfVar::OPEN_MAX as i32) {
-                let _ = nix::unistd::close(fd);
-            }
-            let err = cmd.exec();
-            eprintln!("shell exec err: {:?}", err);
-            std::process::exit(1);
+            fork
+        };
+        drop(pam_spawn_lock);
+        // The pty master gets opened without O_CLOEXEC, so without this
+        // every shell and hook spawned after this one would inherit it.
+        let master = fork.is_parent().context("internal error: executing in child fork")?;
+        if let Some(fd) = master.borrow_fd() {
+            spawn::set_cloexec(fd).context("making pty master close-on-exec")?;
         }
 
         // spawn a background thread to reap the shell when it exits
@@ -757,55 +2059,89 @@ impl Server {
         let waitable_child_pid = fork.child_pid().ok_or(anyhow!("missing child pid"))?;
         let session_name = header.name.clone();
         let notifiable_child_exit_notifier = Arc::clone(&child_exit_notifier);
-        thread::spawn(move || {
-            let _s = span!(Level::INFO, "child_watcher", s = session_name, cid = conn_id).entered();
-
-            let mut err = None;
-            let mut status = 0;
-            let mut unpacked_status = None;
-            loop {
-                // Saftey: all basic ffi, the pid is valid before this returns.
-                unsafe {
-                    match libc::waitpid(waitable_child_pid, &mut status, 0) {
-                        0 => continue,
-                        -1 => {
-                            err = Some("waitpid failed");
-                            break;
-                        }
-                        _ => {
-                            if libc::WIFEXITED(status) {
-                                unpacked_status = Some(libc::WEXITSTATUS(status));
+        let started_at = time::SystemTime::now();
+        let exit_history = Arc::clone(&self.exit_history);
+        threads::for_session("wait", &header.name, &self.config)
+            .spawn(move || {
+                let _s =
+                    span!(Level::INFO, "child_watcher", s = session_name, cid = conn_id).entered();
+
+                let mut err = None;
+                let mut status = 0;
+                let mut unpacked_status = None;
+                let mut signal = None;
+                loop {
+                    // Saftey: all basic ffi, the pid is valid before this returns.
+                    unsafe {
+                        match libc::waitpid(waitable_child_pid, &mut status, 0) {
+                            0 => continue,
+                            -1 => {
+                                err = Some("waitpid failed");
+                                break;
+                            }
+                            _ => {
+                                if libc::WIFEXITED(status) {
+                                    unpacked_status = Some(libc::WEXITSTATUS(status));
+                                } else if libc::WIFSIGNALED(status) {
+                                    signal = Some(libc::WTERMSIG(status));
+                                }
+                                break;
                             }
-                            break;
                         }
                     }
                 }
-            }
-            if let Some(status) = unpacked_status {
-                info!("child exited with status {}", status);
-                notifiable_child_exit_notifier.notify_exit(status);
-            } else {
-                if let Some(e) = err {
-                    info!("child exited without status, using 1: {:?}", e);
+                let exit_status = if let Some(status) = unpacked_status {
+                    info!("child exited with status {}", status);
+                    status
                 } else {
-                    info!("child exited without status, using 1");
+                    if let Some(e) = err {
+                        info!("child exited without status, using 1: {:?}", e);
+                    } else {
+                        info!("child exited without status, using 1 (signal={:?})", signal);
+                    }
+                    1
+                };
+                // record the exit before anyone waiting on it can restart
+                // the session
+                exit_history.record(
+                    &session_name,
+                    ExitRecord {
+                        status: exit_status,
+                        signal,
+                        started_at_unix_ms: flight_recorder::unix_ms(started_at),
+                        exited_at_unix_ms: flight_recorder::unix_ms(time::SystemTime::now()),
+                    },
+                );
+                notifiable_child_exit_notifier.notify_exit(exit_status);
+
+                if let Some(pam_open) = pam_open {
+                    if let Err(e) = pam_open.close() {
+                        warn!("closing pam session: {:?}", e);
+                    }
                 }
-                notifiable_child_exit_notifier.notify_exit(1);
-            }
-        });
+            })
+            .context("spawning child watcher")?;
 
         // Inject the prompt prefix, if any. For custom commands, avoid doing this
         // since we have no idea what the command is so the shell code probably won't
         // work.
-        if header.cmd.is_none() {
+        if custom_cmd.is_none() {
             info!("injecting prompt prefix");
-            let prompt_prefix = self
-                .config
-                .get()
-                .prompt_prefix
-                .clone()
-                .unwrap_or(String::from(DEFAULT_PROMPT_PREFIX));
-            if let Err(err) = prompt::maybe_inject_prefix(&mut fork, &prompt_prefix, &header.name) {
+            let (prompt_prefix, follow_client_cwd) = {
+                let config = self.config.get();
+                (
+                    config.prompt_prefix.clone().unwrap_or(String::from(DEFAULT_PROMPT_PREFIX)),
+                    config.follow_client_cwd.unwrap_or(false),
+                )
+            };
+            let cwd_file = if follow_client_cwd { Some(self.cwd_file(&header.name)) } else { None };
+            if let Err(err) = prompt::maybe_inject_prefix(
+                &mut fork,
+                &prompt_prefix,
+                &header.name,
+                cwd_file.as_deref(),
+                &self.attach_count_file(&header.name),
+            ) {
                 warn!("issue injecting prefix: {:?}", err);
             }
         }
@@ -817,6 +2153,9 @@ impl Server {
 
         let (heartbeat_tx, heartbeat_rx) = crossbeam_channel::bounded(0);
         let (heartbeat_ack_tx, heartbeat_ack_rx) = crossbeam_channel::bounded(0);
+        let (notice_tx, notice_rx) = crossbeam_channel::bounded(0);
+        let (redraw_tx, redraw_rx) = crossbeam_channel::bounded(0);
+        let (pipe_tx, pipe_rx) = crossbeam_channel::bounded(0);
 
         let shell_to_client_ctl = Arc::new(Mutex::new(shell::ReaderCtl {
             client_connection: client_connection_tx,
@@ -825,46 +2164,86 @@ impl Server {
             tty_size_change_ack: tty_size_change_ack_rx,
             heartbeat: heartbeat_tx,
             heartbeat_ack: heartbeat_ack_rx,
+            notice: notice_tx,
+            redraw: redraw_tx,
+            pipe: pipe_tx,
         }));
+        let stats = Arc::new(shell::SessionStats::default());
+        let client_suspended = Arc::new(AtomicBool::new(false));
         let mut session_inner = shell::SessionInner {
             name: header.name.clone(),
             shell_to_client_ctl: Arc::clone(&shell_to_client_ctl),
             pty_master: fork,
-            client_stream: Some(client_stream),
+            client_stream,
+            frames: protocol::FrameWriter::for_client(header),
             config: self.config.clone(),
             shell_to_client_join_h: None,
             term_db,
             daily_messenger: Arc::clone(&self.daily_messenger),
             needs_initial_motd_dump: dump_motd_on_new_session,
-            custom_cmd: header.cmd.is_some(),
+            custom_cmd: custom_cmd.is_some(),
+            clock: Arc::clone(&self.clock),
+            stats: Arc::clone(&stats),
+            clipboard: Arc::clone(&self.clipboard),
+            pending_notice: None,
+            input_transform: shell::InputTransform(Mutex::new(
+                self.hooks.input_transform(&header.name),
+            )),
         };
         let child_pid = session_inner.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
+        let pty_input = session_inner.pty_master.is_parent().context("getting pty master")?;
+        // Read out of the config up front, since a config guard living
+        // on in a temporary across spawn_shell_to_client would be held
+        // while it takes the config lock again.
+        let (session_restore_mode, output_spool_lines, recording_config) = {
+            let config = self.config.get();
+            (
+                template.session_restore_mode.or(config.session_restore_mode.clone()),
+                config.output_spool_lines,
+                config.recording.clone().unwrap_or_default(),
+            )
+        };
+        let recorder = recording::Recorder::for_session(
+            &self.runtime_dir,
+            &header.name,
+            &template.recording.clone().unwrap_or_default().merge(recording_config),
+        );
+        let recording = recorder.as_ref().map(|r| r.syncer());
         session_inner.shell_to_client_join_h =
             Some(session_inner.spawn_shell_to_client(shell::ReaderArgs {
                 conn_id,
                 tty_size: header.local_tty_size.clone(),
-                scrollback_lines: match (
-                    self.config.get().output_spool_lines,
-                    &self.config.get().session_restore_mode,
-                ) {
+                scrollback_lines: match (output_spool_lines, &session_restore_mode) {
                     (Some(l), _) => l,
                     (None, Some(config::SessionRestoreMode::Lines(l))) => *l as usize,
                     (None, _) => DEFAULT_OUTPUT_SPOOL_LINES,
                 },
-                session_restore_mode:
-                    self.config.get().session_restore_mode.clone().unwrap_or_default(),
+                session_restore_mode: session_restore_mode.clone().unwrap_or_default(),
                 client_connection: client_connection_rx,
                 client_connection_ack: client_connection_ack_tx,
                 tty_size_change: tty_size_change_rx,
                 tty_size_change_ack: tty_size_change_ack_tx,
                 heartbeat: heartbeat_rx,
                 heartbeat_ack: heartbeat_ack_tx,
+                notice: notice_rx,
+                redraw: redraw_rx,
+                pipe: pipe_rx,
+                stats: Arc::clone(&stats),
+                client_suspended: Arc::clone(&client_suspended),
+                output_transform: self.hooks.output_transform(&header.name),
+                recorder,
             })?);
 
-        if let Some(ttl_secs) = header.ttl_secs {
+        let ttl = match (header.ttl_secs, &template.ttl) {
+            (Some(ttl_secs), _) => Some(Duration::from_secs(ttl_secs)),
+            (None, Some(src)) => Some(duration::parse(src).context("parsing template ttl")?),
+            (None, None) => None,
+        };
+        let reap_at = ttl.map(|ttl| self.clock.now().add(ttl));
+        if let Some(reap_at) = reap_at {
             info!("registering session with ttl with the reaper");
             self.register_new_reapable_session
-                .send((header.name.clone(), Instant::now().add(Duration::from_secs(ttl_secs))))
+                .send((header.name.clone(), reap_at))
                 .context("sending reapable session registration msg")?;
         }
 
@@ -872,20 +2251,50 @@ impl Server {
             shell_to_client_ctl,
             pager_ctl: Arc::new(Mutex::new(None)),
             child_pid,
+            pty_input: Mutex::new(pty_input),
             child_exit_notifier,
-            started_at: time::SystemTime::now(),
+            started_at,
+            attach_count: AtomicUsize::new(initial_attach_count),
+            reap_at: Mutex::new(reap_at),
+            restart_on_exit: Arc::new(AtomicBool::new(false)),
+            attach_pending: Arc::new(shell::Creation::new(initial_attach_count > 0)),
+            attachment: Arc::new(shell::Attachment::default()),
+            client_suspended,
+            definition: SessionDefinition {
+                name: header.name.clone(),
+                cmd: custom_cmd,
+                cwd: None,
+                // The auth sock is specific to the client that created the
+                // session, so there is no point in remembering it.
+                env: header
+                    .local_env
+                    .iter()
+                    .filter(|(k, _)| k != "SSH_AUTH_SOCK")
+                    .cloned()
+                    .collect(),
+            },
             inner: Arc::new(Mutex::new(session_inner)),
+            stats,
+            client_terminal: Mutex::new(header.terminal.clone()),
+            env: shell_env.to_vec(),
+            recording,
         })
     }
 
     /// Set up the environment for the shell, returning the right TERM value.
-    #[instrument(skip_all)]
+    #[instrument(skip_all, fields(s = header.name))]
     fn build_shell_env(
         &self,
         user_info: &user::Info,
         header: &AttachHeader,
+        attach_count: usize,
     ) -> anyhow::Result<Vec<(String, String)>> {
         let s = String::from;
+        // Looked up before taking the config lock below, since it takes
+        // the lock itself. A second read lock while this one is held
+        // deadlocks as soon as a config reload queues up for the write
+        // lock in between.
+        let template_env = self.template(&header.template).and_then(|t| t.env);
         let config = self.config.get();
         let auth_sock = self.ssh_auth_sock_symlink(PathBuf::from(&header.name));
         let mut env = vec![
@@ -899,6 +2308,15 @@ impl Server {
                     .unwrap_or(DEFAULT_INITIAL_SHELL_PATH)),
             ),
             (s("SHPOOL_SESSION_NAME"), s(&header.name)),
+            (
+                s("SHPOOL_SOCKET"),
+                s(self.socket.to_str().ok_or(anyhow!("failed to convert socket path"))?),
+            ),
+            (
+                s("SHPOOL_SESSION_CREATED_AT"),
+                flight_recorder::unix_ms(time::SystemTime::now()).div_euclid(1000).to_string(),
+            ),
+            (s("SHPOOL_ATTACH_COUNT"), attach_count.to_string()),
             (s("SHELL"), s(&user_info.default_shell)),
             (s("USER"), s(&user_info.user)),
             (
@@ -907,10 +2325,26 @@ impl Server {
             ),
         ];
 
+        if let (_, Some(pane)) = session_name::split_pane(&header.name) {
+            env.push((s("SHPOOL_PANE_NAME"), s(pane)));
+        }
+
         if let Ok(xdg_runtime_dir) = env::var("XDG_RUNTIME_DIR") {
             env.push((s("XDG_RUNTIME_DIR"), xdg_runtime_dir));
         }
 
+        // This goes before the env from the config so that users can still
+        // point individual sessions somewhere else.
+        if config.per_session_history.unwrap_or(false) {
+            let histfile = history::histfile(&self.runtime_dir, &header.name);
+            fs::create_dir_all(histfile.parent().ok_or(anyhow!("no histfile parent dir"))?)
+                .context("creating session dir for histfile")?;
+            env.push((
+                s("HISTFILE"),
+                String::from(histfile.to_str().ok_or(anyhow!("failed to convert histfile path"))?),
+            ));
+        }
+
         // Most of the time, use the TERM that the user sent along in
         // the attach header. If they have an explicit TERM value set
         // in their config file, use that instead. If they have a blank
@@ -920,8 +2354,25 @@ impl Server {
         if let Some(t) = header.local_env_get("TERM") {
             term = Some(String::from(t));
         }
+        // Template env vars take priority over the ones from the
+        // top level env config.
+        let merged_env_pin;
+        let config_env = match (config.env.as_ref(), template_env) {
+            (Some(config_env), Some(template_env)) => {
+                let mut e = config_env.clone();
+                e.extend(template_env);
+                merged_env_pin = Some(e);
+                merged_env_pin.as_ref()
+            }
+            (Some(config_env), None) => Some(config_env),
+            (None, Some(template_env)) => {
+                merged_env_pin = Some(template_env);
+                merged_env_pin.as_ref()
+            }
+            (None, None) => None,
+        };
         let filtered_env_pin;
-        if let Some(extra_env) = config.env.as_ref() {
+        if let Some(extra_env) = config_env {
             term = match extra_env.get("TERM") {
                 None => term,
                 Some(t) if t.is_empty() => None,
@@ -961,7 +2412,7 @@ impl Server {
         }
 
         // parse and load /etc/environment unless we've been asked not to
-        if !self.config.get().noread_etc_environment.unwrap_or(false) {
+        if !config.noread_etc_environment.unwrap_or(false) {
             match fs::File::open("/etc/environment") {
                 Ok(f) => {
                     let pairs = etc_environment::parse_compat(io::BufReader::new(f))?;
@@ -978,17 +2429,125 @@ impl Server {
         Ok(env)
     }
 
+    /// Look up the session template with the given name, if any.
+    fn template(&self, name: &Option<String>) -> Option<config::SessionTemplate> {
+        let name = name.as_ref()?;
+        let template = self.config.get().templates.as_ref().and_then(|t| t.get(name)).cloned();
+        if template.is_none() {
+            warn!("no template named '{}', ignoring it", name);
+        }
+        template
+    }
+
+    /// The file that the shell's pre-prompt hook checks for a directory
+    /// to cd to when follow_client_cwd is on.
+    fn cwd_file(&self, session_name: &str) -> PathBuf {
+        self.runtime_dir.join("sessions").join(session_name).join("client-cwd")
+    }
+
+    /// Ask the session's shell to cd to the client's directory the next
+    /// time it draws a prompt.
+    fn request_client_cwd(&self, header: &AttachHeader) -> anyhow::Result<()> {
+        let cwd = match &header.cwd {
+            Some(cwd) if Path::new(cwd).is_absolute() && Path::new(cwd).is_dir() => cwd,
+            _ => return Ok(()),
+        };
+
+        let cwd_file = self.cwd_file(&header.name);
+        fs::create_dir_all(cwd_file.parent().ok_or(anyhow!("no cwd file parent dir"))?)
+            .context("creating session dir for cwd file")?;
+        fs::write(&cwd_file, cwd).context("writing cwd file")?;
+        Ok(())
+    }
+
+    /// The file that the shell's pre-prompt hook checks for a new
+    /// value of SHPOOL_ATTACH_COUNT.
+    fn attach_count_file(&self, session_name: &str) -> PathBuf {
+        self.runtime_dir.join("sessions").join(session_name).join("attach-count")
+    }
+
+    /// Let the session's shell know it has been attached to again. It
+    /// picks the new count up the next time it draws a prompt.
+    fn publish_attach_count(&self, session_name: &str, attach_count: usize) -> anyhow::Result<()> {
+        let count_file = self.attach_count_file(session_name);
+        fs::create_dir_all(count_file.parent().ok_or(anyhow!("no count file parent dir"))?)
+            .context("creating session dir for attach count file")?;
+        fs::write(&count_file, attach_count.to_string()).context("writing attach count file")?;
+        Ok(())
+    }
+
     fn ssh_auth_sock_symlink(&self, session_name: PathBuf) -> PathBuf {
         self.runtime_dir.join("sessions").join(session_name).join("ssh-auth-sock.socket")
     }
 }
 
+/// The protocol version the daemon advertizes to clients.
+fn daemon_version() -> String {
+    // We allow fake version to be injected for ease of testing.
+    // Otherwise we would have to resort to some heinous build
+    // contortions.
+    match env::var("SHPOOL_TEST__OVERRIDE_VERSION") {
+        Ok(fake_version) => fake_version,
+        Err(_) => String::from(shpool_protocol::VERSION),
+    }
+}
+
+/// Make sure the client speaks a compatible version of the protocol,
+/// returning the reason to give the client if it does not.
+fn check_client_version(client_version: &str) -> Result<(), String> {
+    if client_version.is_empty() {
+        return Err(String::from(
+            "client did not report its version and strict_version_check is enabled, try upgrading shpool",
+        ));
+    }
+
+    let daemon_version = daemon_version();
+    match protocol::Client::version_ord(client_version, &daemon_version) {
+        Ok(cmp::Ordering::Equal) => Ok(()),
+        Ok(_) => Err(format!(
+            "client protocol version {} is not compatible with daemon protocol version {} and strict_version_check is enabled",
+            client_version, daemon_version
+        )),
+        Err(e) => Err(format!("could not check client version: {:?}", e)),
+    }
+}
+
 #[instrument(skip_all)]
-fn parse_connect_header(stream: &mut UnixStream) -> anyhow::Result<ConnectHeader> {
-    let header: ConnectHeader = protocol::decode_from(stream).context("parsing header")?;
+fn parse_connect_header(stream: &UnixStream, deadline: Instant) -> anyhow::Result<ConnectHeader> {
+    let header: ConnectHeader =
+        protocol::decode_from(DeadlineReader { stream, deadline }).context("parsing header")?;
     Ok(header)
 }
 
+/// Reads from a stream with a timeout that shrinks as the deadline
+/// gets closer, so that a client dribbling out its request a byte at
+/// a time still runs out of time.
+struct DeadlineReader<'a> {
+    stream: &'a UnixStream,
+    deadline: Instant,
+}
+
+impl io::Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "handshake deadline passed"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        io::Read::read(&mut self.stream, buf)
+    }
+}
+
+/// Counts a connection as pending until it gets dropped, which happens
+/// once the connection has sent its connect header or gone away.
+struct PendingConn<'a>(&'a AtomicUsize);
+
+impl Drop for PendingConn<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 #[instrument(skip_all)]
 fn write_reply<H>(stream: &mut UnixStream, header: H) -> anyhow::Result<()>
 where
@@ -1005,32 +2564,76 @@ where
     Ok(())
 }
 
+/// Respond to a client's request for the experimental udp transport,
+/// returning the stream to use for the rest of the attach. If we were
+/// built without udp support, we decline and keep using the original
+/// stream.
+#[cfg(feature = "udp_transport")]
+fn negotiate_udp(mut stream: UnixStream, bind_addr: Option<String>) -> anyhow::Result<UnixStream> {
+    let bind_addr = match bind_addr {
+        Some(addr) => addr.parse().context("parsing udp_bind_addr")?,
+        None => std::net::IpAddr::from([127, 0, 0, 1]),
+    };
+    crate::udp::offer(&mut stream, bind_addr)
+}
+
+#[cfg(not(feature = "udp_transport"))]
+fn negotiate_udp(mut stream: UnixStream, _bind_addr: Option<String>) -> anyhow::Result<UnixStream> {
+    info!("declining udp transport, not built with udp_transport feature");
+    write_reply(&mut stream, shpool_protocol::UdpTransportOffer::default())?;
+    Ok(stream)
+}
+
+/// What a supervisor needs to keep an eye on a running session.
+struct Running {
+    child_exit_notifier: Arc<ExitNotifier>,
+    restart_on_exit: Arc<AtomicBool>,
+    child_pid: libc::pid_t,
+}
+
+impl Running {
+    fn of(session: &shell::Session) -> Self {
+        Running {
+            child_exit_notifier: Arc::clone(&session.child_exit_notifier),
+            restart_on_exit: Arc::clone(&session.restart_on_exit),
+            child_pid: session.child_pid,
+        }
+    }
+}
+
+/// The path of the pty slave that a forked shell is attached to.
+fn pty_name(fork: &shpool_pty::fork::Fork) -> anyhow::Result<String> {
+    let master = fork.is_parent().map_err(|e| anyhow!("getting pty master: {:?}", e))?;
+    let mut buf = vec![0; 1024];
+    master.ptsname_r(&mut buf).map_err(|e| anyhow!("ptsname: {:?}", e))?;
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
 /// check_peer makes sure that a process dialing in on the shpool
 /// control socket has the same UID as the current user and that
 /// both have the same executable path.
+///
+/// Not every platform reports the pid of the peer, so the exe check
+/// only happens where it does.
 fn check_peer(sock: &UnixStream) -> anyhow::Result<()> {
-    use nix::sys::socket;
-
-    let peer_creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)
-        .context("could not get peer creds from socket")?;
-    let peer_uid = unistd::Uid::from_raw(peer_creds.uid());
+    let peer_creds = platform::peer_creds(sock).context("could not get peer creds from socket")?;
+    let peer_uid = unistd::Uid::from_raw(peer_creds.uid);
     let self_uid = unistd::Uid::current();
     if peer_uid != self_uid {
         return Err(anyhow!("shpool prohibits connections across users"));
     }
 
-    let peer_pid = unistd::Pid::from_raw(peer_creds.pid());
+    let Some(peer_pid) = peer_creds.pid else {
+        return Ok(());
+    };
+    let peer_pid = unistd::Pid::from_raw(peer_pid);
     let self_pid = unistd::Pid::this();
-    let peer_exe = exe_for_pid(peer_pid).context("could not resolve exe from the pid")?;
-    let self_exe = exe_for_pid(self_pid).context("could not resolve our own exe")?;
+    let peer_exe = platform::exe_for_pid(peer_pid).context("could not resolve exe from the pid")?;
+    let self_exe = platform::exe_for_pid(self_pid).context("could not resolve our own exe")?;
     if peer_exe != self_exe {
         warn!("attach binary differs from daemon binary");
     }
 
     Ok(())
 }
-
-fn exe_for_pid(pid: unistd::Pid) -> anyhow::Result<PathBuf> {
-    let path = std::fs::read_link(format!("/proc/{}/exe", pid))?;
-    Ok(path)
-}
@@ -13,14 +13,16 @@
 // limitations under the License.
 
 use std::{
-    io,
+    collections::HashMap,
+    fmt, io,
     io::{Read, Write},
     net,
     ops::Add,
     os::unix::net::UnixStream,
+    panic,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
     thread, time,
     time::Duration,
@@ -28,14 +30,19 @@ use std::{
 
 use anyhow::{anyhow, Context};
 use nix::{sys::signal, unistd::Pid};
-use shpool_protocol::{Chunk, ChunkKind, TtySize};
+use shpool_protocol::{Chunk, ChunkKind, MemoryUsage, SessionDefinition, TerminalCaps, TtySize};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
 use crate::{
+    clock::Clock,
+    common::panic_msg,
     consts,
-    daemon::{config, exit_notify::ExitNotifier, keybindings, pager::PagerCtl, prompt, show_motd},
-    protocol::ChunkExt as _,
-    test_hooks,
+    daemon::{
+        cancel, clipboard, config, exit_notify::ExitNotifier, keybindings, output_filter,
+        output_pipe, pager::PagerCtl, prompt, pty_io, rate_limit::TokenBucket, shell_integration,
+        show_motd, threads,
+    },
+    duration, hooks, platform, protocol, recording, test_hooks,
     tty::TtySizeExt as _,
 };
 
@@ -64,11 +71,33 @@ const SHELL_TO_CLIENT_POLL_MS: u16 = 100;
 // shell->client thread.
 const SHELL_TO_CLIENT_CTL_TIMEOUT: time::Duration = time::Duration::from_millis(300);
 
+// The minimum amount of time between "output throttled" notices so that
+// a session bouncing in and out of its rate limit doesn't spam the user.
+const THROTTLE_NOTICE_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+// How long a write to a client may block before we give up on the
+// client, unless the client_write_timeout config option says otherwise.
+const DEFAULT_CLIENT_WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
 /// Session represent a shell session
 #[derive(Debug)]
 pub struct Session {
     pub started_at: time::SystemTime,
+    /// How many times a client has attached to the session, for
+    /// SHPOOL_ATTACH_COUNT.
+    pub attach_count: AtomicUsize,
+    /// When the ttl reaper will kill this session, if it has a ttl.
+    pub reap_at: Mutex<Option<time::Instant>>,
+    /// Set for sessions with a restart policy, which get launched again
+    /// when they exit. Cleared when the session gets explicitly killed.
+    pub restart_on_exit: Arc<AtomicBool>,
+    /// The parameters the session was created with, for `shpool export`.
+    pub definition: SessionDefinition,
     pub child_pid: libc::pid_t,
+    /// A handle on the pty master for writing input without going
+    /// through an attached client, as `shpool broadcast` does. The fd
+    /// is owned by `inner`, which outlives the session table entry.
+    pub pty_input: Mutex<shpool_pty::fork::Master>,
     pub child_exit_notifier: Arc<ExitNotifier>,
     pub shell_to_client_ctl: Arc<Mutex<ReaderCtl>>,
     pub pager_ctl: Arc<Mutex<Option<PagerCtl>>>,
@@ -76,9 +105,222 @@ pub struct Session {
     /// while a tty is attached to the session. Probing the mutex can be used
     /// to determine if someone is currently attached to the session.
     pub inner: Arc<Mutex<SessionInner>>,
+    /// Pending while the client that created the session has yet to
+    /// take the inner lock, so that nobody else can grab the session
+    /// out from under it in the meantime.
+    pub attach_pending: Arc<Creation>,
+    /// Whether a client is attached right now, for code that needs to
+    /// wait for it to go away rather than just probing `inner`.
+    pub attachment: Arc<Attachment>,
+    /// Set while the attached client is suspended, so that the
+    /// shell->client thread holds off on writing to it.
+    pub client_suspended: Arc<AtomicBool>,
+    /// Counters for `shpool dump-state`.
+    pub stats: Arc<SessionStats>,
+    /// What the terminal of the most recently attached client can do,
+    /// if `shpool attach` was able to probe it.
+    pub client_terminal: Mutex<Option<TerminalCaps>>,
+    /// The environment the shell was started with, for `shpool env`.
+    pub env: Vec<(String, String)>,
+    /// For syncing the session's recording on shutdown, if it has one.
+    pub recording: Option<recording::Syncer>,
+}
+
+/// The daemon's sessions, keyed by name.
+///
+/// Only adding and removing sessions takes the table lock for writing,
+/// so lookups from `shpool list` and friends don't contend with each
+/// other. Sessions are reference counted so that a handler can grab
+/// the sessions it needs with `get_session` or `snapshot` and then let
+/// go of the table before doing anything that might block, like
+/// talking to a session's shell->client thread.
+///
+/// To stay clear of deadlocks, the locks that make up the table and
+/// its sessions are always taken in this order, and none of them is
+/// ever waited on while holding a lock further down the list:
+///
+/// 1. the session table
+/// 2. `Session::inner`, which an attached client holds for the whole
+///    attach, so it may only be `try_lock`ed while holding the table,
+///    unless the session was just created and nobody else has seen it
+/// 3. `Session::pager_ctl`
+/// 4. `Session::shell_to_client_ctl`
+/// 5. the leaf locks: `reap_at`, `pty_input`, `client_terminal` and
+///    the one inside `attachment`
+pub type SessionTable = RwLock<HashMap<String, Arc<Session>>>;
+
+/// Tracks whether a client is attached to a session. The attach
+/// handler holds an `Attached` guard for as long as it holds the
+/// session's inner lock, and dropping the guard wakes up anyone
+/// waiting on the client to go away.
+#[derive(Debug, Default)]
+pub struct Attachment {
+    attached: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Attachment {
+    /// Mark the session attached until the returned guard is dropped.
+    pub fn attach(self: &Arc<Self>) -> Attached {
+        *self.attached.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        Attached(Arc::clone(self))
+    }
+
+    pub fn is_attached(&self) -> bool {
+        *self.attached.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Block until no client is attached.
+    pub fn wait_detached(&self) {
+        let attached = self.attached.lock().unwrap_or_else(PoisonError::into_inner);
+        let _unused =
+            self.cond.wait_while(attached, |a| *a).unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// A client being attached to a session, see `Attachment::attach`.
+#[derive(Debug)]
+pub struct Attached(Arc<Attachment>);
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        *self.0.attached.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        self.0.cond.notify_all();
+    }
+}
+
+/// Tracks whether the client that created a session has yet to claim
+/// it. Other clients wait on this rather than racing the creator, and
+/// dropping the creator's `Creating` guard wakes them up.
+#[derive(Debug, Default)]
+pub struct Creation {
+    pending: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Creation {
+    pub fn new(pending: bool) -> Self {
+        Creation { pending: Mutex::new(pending), cond: Condvar::new() }
+    }
+
+    /// Get the guard that ends the creation when dropped.
+    pub fn guard(self: &Arc<Self>) -> Creating {
+        Creating(Arc::clone(self))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        *self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Block until the creator has claimed the session or the timeout
+    /// runs out, returning whether it is still pending.
+    pub fn wait_created(&self, timeout: time::Duration) -> bool {
+        let pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        let (pending, _) = self
+            .cond
+            .wait_timeout_while(pending, timeout, |p| *p)
+            .unwrap_or_else(PoisonError::into_inner);
+        *pending
+    }
+}
+
+/// The creator's hold on a pending session, see `Creation::guard`. It
+/// is dropped once the creator has the inner lock, or if the creating
+/// attach bails out early.
+#[derive(Debug)]
+pub struct Creating(Arc<Creation>);
+
+impl Drop for Creating {
+    fn drop(&mut self) {
+        *self.0.pending.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        self.0.cond.notify_all();
+    }
+}
+
+/// Lock the session table for reading. A panic while the table was
+/// locked means that the handler for one session blew up, but the
+/// table itself is still intact, so we recover from the poisoning
+/// rather than letting one bad session wedge every other one.
+pub fn read_table(shells: &SessionTable) -> RwLockReadGuard<'_, HashMap<String, Arc<Session>>> {
+    shells.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock the session table for adding or removing sessions, recovering
+/// from poisoning the same way `read_table` does.
+pub fn write_table(shells: &SessionTable) -> RwLockWriteGuard<'_, HashMap<String, Arc<Session>>> {
+    shells.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A handle on the named session, holding the table lock just long
+/// enough to look it up.
+pub fn get_session(shells: &SessionTable, name: &str) -> Option<Arc<Session>> {
+    let _s = span!(Level::INFO, "get_lock(shells)").entered();
+    read_table(shells).get(name).map(Arc::clone)
+}
+
+/// Handles on every session, holding the table lock just long enough
+/// to copy them out.
+pub fn snapshot(shells: &SessionTable) -> Vec<(String, Arc<Session>)> {
+    let _s = span!(Level::INFO, "snapshot_lock(shells)").entered();
+    read_table(shells).iter().map(|(name, sess)| (name.clone(), Arc::clone(sess))).collect()
+}
+
+/// Counters that get updated by the session's threads so that the
+/// daemon can report on them without having to take any locks.
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    /// The total number of bytes read from the pty.
+    pub output_bytes: AtomicU64,
+    /// The total number of bytes written to the pty by clients.
+    pub input_bytes: AtomicU64,
+    /// Whether the shell->client thread is currently running.
+    pub shell_to_client_running: AtomicBool,
+    /// An estimate of the memory held by the output spool.
+    pub spool_bytes: AtomicU64,
+    /// Output held back by the output filters.
+    pub pending_output_bytes: AtomicU64,
+}
+
+impl SessionStats {
+    /// Account for the memory held on behalf of the session.
+    pub fn memory(&self, attached: bool) -> MemoryUsage {
+        // The shell->client thread has a read buffer for as long as it
+        // runs, and the client->shell thread has one while attached.
+        let mut io_buffers = 0;
+        if self.shell_to_client_running.load(Ordering::Relaxed) {
+            io_buffers += consts::BUF_SIZE;
+        }
+        if attached {
+            io_buffers += consts::BUF_SIZE;
+        }
+        MemoryUsage {
+            scrollback_bytes: self.spool_bytes.load(Ordering::Relaxed),
+            pending_output_bytes: self.pending_output_bytes.load(Ordering::Relaxed),
+            io_buffer_bytes: io_buffers as u64,
+        }
+    }
+}
+
+/// Estimate how much memory an output spool is using. The terminal
+/// emulator allocates every cell of every row up front, and we keep
+/// track of how many rows of scrollback have built up by counting
+/// lines as they go by.
+fn spool_bytes(spool: &shpool_vt100::Parser, scrollback_rows: usize) -> u64 {
+    let (rows, cols) = spool.screen().size();
+    ((rows as usize + scrollback_rows) * cols as usize * std::mem::size_of::<shpool_vt100::Cell>())
+        as u64
 }
 
 impl Session {
+    /// Write input to the shell as if a client had typed it.
+    pub fn write_input(&self, data: &[u8]) -> anyhow::Result<()> {
+        let mut pty_input = self.pty_input.lock().unwrap();
+        pty_input.write_all(data).context("writing input to pty")?;
+        pty_input.flush().context("flushing pty")?;
+        self.stats.input_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
     /// Kill the session, first sending a SIGHUP and then resorting to a
     /// SIGKILL if that doesn't work (SIGTERM doesn't really work on shells).
     #[instrument(skip_all)]
@@ -98,6 +340,90 @@ impl Session {
 
         Ok(())
     }
+
+    /// The parameters needed to recreate this session, with the working
+    /// directory set to wherever the shell currently is rather than where
+    /// it started out.
+    pub fn current_definition(&self) -> SessionDefinition {
+        let mut def = self.definition.clone();
+        def.cwd = platform::cwd_for_pid(Pid::from_raw(self.child_pid))
+            .ok()
+            .and_then(|p| p.to_str().map(String::from));
+        def
+    }
+
+    /// Let the shell->client thread of a freshly spawned session start
+    /// consuming output without waiting for a client to attach first.
+    pub fn start_detached(&self) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        shell_to_client_ctl
+            .client_connection
+            .send_timeout(ClientConnectionMsg::Disconnect, SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("sending initial detached state to shell->client thread")?;
+        shell_to_client_ctl
+            .client_connection_ack
+            .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("waiting for initial connection ack")?;
+        Ok(())
+    }
+
+    /// Let the attached client, if any, know that the daemon is going
+    /// away, then hang up on it.
+    pub fn disconnect_for_shutdown(&self) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        // The notice rides along with the disconnect rather than going
+        // over the notice channel, which the shell->client thread might
+        // get to after it has already hung up.
+        shell_to_client_ctl
+            .client_connection
+            .send_timeout(
+                ClientConnectionMsg::DisconnectExit(
+                    consts::DAEMON_SHUTDOWN_EXIT_STATUS,
+                    Some(String::from("the shpool daemon is shutting down")),
+                ),
+                SHELL_TO_CLIENT_CTL_TIMEOUT,
+            )
+            .context("sending shutdown to shell->client")?;
+        let status = shell_to_client_ctl
+            .client_connection_ack
+            .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("waiting for shutdown ack")?;
+        info!("disconnected for shutdown, status = {:?}", status);
+        Ok(())
+    }
+
+    /// Redraw the screen of the client attached to this session, if any.
+    pub fn redraw(&self) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        shell_to_client_ctl
+            .redraw
+            .send_timeout((), SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("sending redraw to shell->client")?;
+        Ok(())
+    }
+
+    /// Start streaming a copy of the session's output to a client, on
+    /// top of sending it to the attached client as usual.
+    pub fn pipe_output(&self, conn: output_pipe::PipeConn) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        shell_to_client_ctl
+            .pipe
+            .send_timeout(conn, SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .map_err(|_| anyhow!("timed out handing pipe to shell->client"))?;
+        Ok(())
+    }
+
+    /// Display a notice to the client attached to this session, if any.
+    /// The notice is guaranteed to be written before any output or control
+    /// message that gets handed to the shell->client thread afterwards.
+    pub fn notify(&self, notice: &str) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        shell_to_client_ctl
+            .notice
+            .send_timeout(String::from(notice), SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("sending notice to shell->client")?;
+        Ok(())
+    }
 }
 
 /// ShellSessionInner contains values that the pipe thread needs to be
@@ -108,11 +434,24 @@ pub struct SessionInner {
     pub shell_to_client_ctl: Arc<Mutex<ReaderCtl>>,
     pub pty_master: shpool_pty::fork::Fork,
     pub client_stream: Option<UnixStream>,
+    /// How to frame chunks for the client in `client_stream`.
+    pub frames: protocol::FrameWriter,
     pub config: config::Manager,
     pub term_db: Arc<termini::TermInfo>,
     pub daily_messenger: Arc<show_motd::DailyMessenger>,
     pub needs_initial_motd_dump: bool,
     pub custom_cmd: bool,
+    /// The source of time for resize delays, throttling and heartbeats.
+    pub clock: Arc<dyn Clock>,
+    /// Counters shared with the Session, for reporting.
+    pub stats: Arc<SessionStats>,
+    /// The daemon wide clipboard, for the paste action.
+    pub clipboard: Arc<clipboard::Clipboard>,
+    /// A notice to show the next client that attaches, such as word
+    /// that the session has been restarted.
+    pub pending_notice: Option<String>,
+    /// The embedder's transform for input headed to the shell.
+    pub input_transform: InputTransform,
 
     /// The join handle for the always-on background shell->client thread.
     /// Only wrapped in an option so we can spawn the thread after
@@ -120,18 +459,58 @@ pub struct SessionInner {
     pub shell_to_client_join_h: Option<thread::JoinHandle<anyhow::Result<()>>>,
 }
 
+/// An optional input transform from the `Hooks`. The client->shell
+/// thread only borrows the SessionInner, so the transform lives behind
+/// a mutex.
+#[derive(Default)]
+pub struct InputTransform(pub Mutex<Option<Box<dyn hooks::StreamTransform + Send>>>);
+
+impl fmt::Debug for InputTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let set = self.0.lock().map(|t| t.is_some()).unwrap_or(false);
+        f.debug_tuple("InputTransform").field(&if set { "set" } else { "unset" }).finish()
+    }
+}
+
 /// A notification that a new client has connected, sent to the
 /// shell->client thread.
 pub struct ClientConnection {
     /// All output data should be written to this sink rather than
     /// directly to the unix stream.
     sink: io::BufWriter<UnixStream>,
+    /// How to frame chunks written to the sink.
+    frames: protocol::FrameWriter,
     /// The size of the client tty.
     size: TtySize,
     /// The raw unix socket stream. The shell->client thread should
     /// never write to this directly, just use it for control
     /// operations like shutdown.
     stream: UnixStream,
+    /// How long a write may block before we give up on the client.
+    write_timeout: Option<time::Duration>,
+}
+
+impl ClientConnection {
+    fn write_chunk(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.frames.write(&mut self.sink, chunk)
+    }
+
+    /// Hang up on a client that has stopped reading, which is how the
+    /// client finds out, since it isn't reading anything we could tell
+    /// it. Returns true if the error means the client is too slow.
+    fn evict_if_stalled(&self, err: &io::Error) -> bool {
+        if !matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+            return false;
+        }
+        warn!(
+            "detaching client, it has not read any output for {:?}",
+            self.write_timeout.unwrap_or_default()
+        );
+        if let Err(e) = self.stream.shutdown(net::Shutdown::Both) {
+            warn!("shutting down stalled client stream: {:?}", e);
+        }
+        true
+    }
 }
 
 #[derive(Debug)]
@@ -171,8 +550,10 @@ pub enum ClientConnectionMsg {
     /// Accept a newly connected client
     New(ClientConnection),
     /// Disconnect the client and exit the shell->client loop since
-    /// the client shell has exited with the given exit status.
-    DisconnectExit(i32),
+    /// the client shell has exited with the given exit status. The
+    /// notice, if any, gets shown to the client right before the exit
+    /// status.
+    DisconnectExit(i32, Option<String>),
     /// Disconnect the client, but stay around and be ready for
     /// reconnects.
     Disconnect,
@@ -190,6 +571,16 @@ pub struct ReaderArgs {
     pub heartbeat: crossbeam_channel::Receiver<()>,
     // true if the client is still live, false if it has hung up on us
     pub heartbeat_ack: crossbeam_channel::Sender<bool>,
+    pub notice: crossbeam_channel::Receiver<String>,
+    pub redraw: crossbeam_channel::Receiver<()>,
+    pub pipe: crossbeam_channel::Receiver<output_pipe::PipeConn>,
+    pub stats: Arc<SessionStats>,
+    /// Shared with `Session::client_suspended`.
+    pub client_suspended: Arc<AtomicBool>,
+    /// The embedder's transform for output from the shell.
+    pub output_transform: Option<Box<dyn hooks::StreamTransform + Send>>,
+    /// Where to record the session's output, if anywhere.
+    pub recorder: Option<recording::Recorder>,
 }
 
 impl SessionInner {
@@ -207,21 +598,41 @@ impl SessionInner {
         let mut prompt_sentinel_scanner = prompt::SentinelScanner::new(consts::PROMPT_SENTINEL);
 
         // We only scan for the prompt sentinel if the user has not set up a
-        // custom command or blanked out the prompt_prefix config option.
-        let prompt_prefix_is_blank =
-            self.config.get().prompt_prefix.as_ref().map(|p| p.is_empty()).unwrap_or(false);
-        let mut has_seen_prompt_sentinel = self.custom_cmd || prompt_prefix_is_blank;
+        // custom command and we actually injected something into the shell,
+        // which we don't do if the prompt_prefix config option is blanked out
+        // and follow_client_cwd is off.
+        let nothing_injected = {
+            let config = self.config.get();
+            let prompt_prefix_is_blank =
+                config.prompt_prefix.as_ref().map(|p| p.is_empty()).unwrap_or(false);
+            prompt_prefix_is_blank && !config.follow_client_cwd.unwrap_or(false)
+        };
+        let mut has_seen_prompt_sentinel = self.custom_cmd || nothing_injected;
 
         let daily_messenger = Arc::clone(&self.daily_messenger);
         let mut needs_initial_motd_dump = self.needs_initial_motd_dump;
 
-        let vterm_width = {
+        let mut output_transform = args.output_transform;
+        let mut recorder = args.recorder;
+        let (vterm_width, output_rate_limit, mut output_filter) = {
             let config = self.config.get();
-            config.vt100_output_spool_width.unwrap_or(VTERM_WIDTH)
+            let output_filter = match &config.filters {
+                Some(filters) => output_filter::compile(filters).context("compiling filters")?,
+                None => None,
+            };
+            (
+                config.vt100_output_spool_width.unwrap_or(VTERM_WIDTH),
+                config.output_rate_limit,
+                output_filter,
+            )
         };
-        let mut pty_master = self.pty_master.is_parent()?;
+        let pty_master = self.pty_master.is_parent()?;
         let watchable_master = pty_master;
         let name = self.name.clone();
+        let child_pid = self.pty_master.child_pid().ok_or(anyhow!("no child pid"))?;
+        let stats = Arc::clone(&args.stats);
+        let clock = Arc::clone(&self.clock);
+        let pipe_config = self.config.clone();
         let mut closure = move || {
             let _s = span!(Level::INFO, "shell->client", s = name, cid = args.conn_id).entered();
 
@@ -235,11 +646,22 @@ impl SessionInner {
                         args.scrollback_lines,
                     ))
                 };
+            // Only needed to patch things up after a restore, so we leave
+            // it alone when there is no output spool to restore from.
+            let mut integration_markers = shell_integration::MarkerTracker::default();
+            let mut scrollback_rows = 0;
+            if let Some(s) = output_spool.as_ref() {
+                args.stats.spool_bytes.store(spool_bytes(s, scrollback_rows), Ordering::Relaxed);
+            }
+            let mut rate_limiter = output_rate_limit.map(|r| TokenBucket::new(r, clock.now()));
+            let mut throttled = false;
+            let mut last_throttle_notice: Option<time::Instant> = None;
             let mut buf: Vec<u8> = vec![0; consts::BUF_SIZE];
             let mut poll_fds = [poll::PollFd::new(
                 watchable_master.borrow_fd().ok_or(anyhow!("no master fd"))?,
                 poll::PollFlags::POLLIN,
             )];
+            let mut pty_reader = pty_io::Master::new(&pty_master)?;
 
             // block until we get the first connection attached so that we don't drop
             // the initial prompt on the floor
@@ -250,23 +672,30 @@ impl SessionInner {
                 .send(ClientConnectionStatus::New)
                 .context("sending initial client connection ack")?;
             info!("got initial client connection");
+            test_hooks::maybe_panic("shell->client", &name);
 
             let mut resize_cmd = if let ClientConnectionMsg::New(conn) = &client_conn {
-                Some(ResizeCmd { size: conn.size.clone(), when: time::Instant::now() })
+                Some(ResizeCmd { size: conn.size.clone(), when: clock.now() })
             } else {
                 None
             };
+            let mut was_suspended = false;
+            let mut pipes: Vec<output_pipe::OutputPipe> = vec![];
 
             loop {
                 let mut do_reattach = false;
+                // set if the client already has something on its screen
+                // that the restore needs to paint over
+                let mut clear_first = false;
                 crossbeam_channel::select! {
                     recv(args.client_connection) -> new_connection => {
                         match new_connection {
                             Ok(ClientConnectionMsg::New(conn)) => {
                                 info!("got new connection (rows={}, cols={})", conn.size.rows, conn.size.cols);
                                 do_reattach = true;
+                                args.client_suspended.store(false, Ordering::Release);
                                 let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
-                                    Self::write_exit_chunk(&mut old_conn.sink, 0);
+                                    Self::write_exit_chunk(&mut old_conn, 0);
                                     old_conn.stream.shutdown(net::Shutdown::Both)?;
                                     ClientConnectionStatus::Replaced
                                 } else {
@@ -288,10 +717,13 @@ impl SessionInner {
                                 // need to inject a delay into that.
                                 if let Some(s) = output_spool.as_mut() {
                                     s.screen_mut().set_size(conn.size.rows, u16::MAX);
+                                    args.stats
+                                        .spool_bytes
+                                        .store(spool_bytes(s, scrollback_rows), Ordering::Relaxed);
                                 }
                                 resize_cmd = Some(ResizeCmd {
                                     size: conn.size.clone(),
-                                    when: time::Instant::now().add(REATTACH_RESIZE_DELAY),
+                                    when: clock.now().add(REATTACH_RESIZE_DELAY),
                                 });
                                 client_conn = ClientConnectionMsg::New(conn);
 
@@ -299,9 +731,10 @@ impl SessionInner {
                                     .context("sending client connection ack")?;
                             }
                             Ok(ClientConnectionMsg::Disconnect) => {
+                                args.client_suspended.store(false, Ordering::Release);
                                 let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
                                     info!("disconnect, shutting down client stream");
-                                    Self::write_exit_chunk(&mut old_conn.sink, 0);
+                                    Self::write_exit_chunk(&mut old_conn, 0);
                                     old_conn.stream.shutdown(net::Shutdown::Both)?;
                                     ClientConnectionStatus::Detached
                                 } else {
@@ -313,14 +746,19 @@ impl SessionInner {
                                 args.client_connection_ack.send(ack)
                                     .context("sending client connection ack")?;
                             }
-                            Ok(ClientConnectionMsg::DisconnectExit(exit_status)) => {
+                            Ok(ClientConnectionMsg::DisconnectExit(exit_status, notice)) => {
                                 let ack = if let ClientConnectionMsg::New(mut old_conn) = client_conn {
                                     info!("disconnectexit({}), shutting down client stream",
                                            exit_status);
 
+                                    if let Some(notice) = notice {
+                                        info!("writing notice '{}'", notice);
+                                        Self::write_notice_chunk(&mut old_conn, &notice);
+                                    }
+
                                     // write an exit status frame so the attach process
                                     // can exit with the same exit code as the child shell
-                                    Self::write_exit_chunk(&mut old_conn.sink, exit_status);
+                                    Self::write_exit_chunk(&mut old_conn, exit_status);
                                     old_conn.stream.shutdown(net::Shutdown::Both)?;
 
                                     ClientConnectionStatus::Detached
@@ -332,6 +770,9 @@ impl SessionInner {
                                 };
                                 args.client_connection_ack.send(ack)
                                     .context("sending client connection ack")?;
+                                for pipe in pipes.drain(..) {
+                                    pipe.exit(exit_status);
+                                }
 
                                 return Ok(());
                             }
@@ -354,7 +795,7 @@ impl SessionInner {
                                     size,
                                     // No delay needed for ordinary resizes, just
                                     // for reconnects.
-                                    when: time::Instant::now(),
+                                    when: clock.now(),
                                 });
                                 args.tty_size_change_ack.send(())
                                     .context("sending size change ack")?;
@@ -366,9 +807,15 @@ impl SessionInner {
                         }
                     }
                     recv(args.heartbeat) -> _ => {
-                        let client_present = if let ClientConnectionMsg::New(conn) = &mut client_conn {
+                        let client_present = if args.client_suspended.load(Ordering::Acquire) {
+                            // a suspended client can't read the heartbeat, but if
+                            // it goes away for good the client->shell side will
+                            // see the hangup
+                            trace!("client suspended, skipping heartbeat");
+                            true
+                        } else if let ClientConnectionMsg::New(conn) = &mut client_conn {
                             let chunk = Chunk { kind: ChunkKind::Heartbeat, buf: &[] };
-                            match chunk.write_to(&mut conn.sink).and_then(|_| conn.sink.flush()) {
+                            match conn.write_chunk(&chunk).and_then(|_| conn.sink.flush()) {
                                 Ok(_) => {
                                     trace!("wrote heartbeat");
                                     true
@@ -377,6 +824,7 @@ impl SessionInner {
                                     trace!("client hangup: {:?}", e);
                                     false
                                 }
+                                Err(e) if conn.evict_if_stalled(&e) => false,
                                 Err(e) => {
                                     error!("unexpected IO error while writing heartbeat: {}", e);
                                     return Err(e).context("writing heartbeat")?;
@@ -389,6 +837,97 @@ impl SessionInner {
                         args.heartbeat_ack.send(client_present)
                             .context("sending heartbeat ack")?;
                     }
+                    recv(args.redraw) -> redraw => {
+                        match redraw {
+                            Ok(()) => {
+                                info!("redraw requested");
+                                // Jiggle the pty size like we do on reattach so
+                                // that full screen programs repaint themselves,
+                                // going back to any resize that is still pending.
+                                let size = match resize_cmd.as_ref() {
+                                    Some(cmd) => Ok(cmd.size.clone()),
+                                    None => pty_master
+                                        .raw_fd()
+                                        .ok_or(anyhow!("no master fd"))
+                                        .and_then(TtySize::from_fd),
+                                };
+                                match size {
+                                    Ok(size) => {
+                                        let oversize = TtySize {
+                                            rows: size.rows + 1,
+                                            cols: size.cols + 1,
+                                            xpixel: size.xpixel,
+                                            ypixel: size.ypixel,
+                                        };
+                                        let status = pty_master
+                                            .raw_fd()
+                                            .ok_or(anyhow!("no master fd"))
+                                            .and_then(|fd| oversize.set_fd(fd));
+                                        if let Err(e) = status {
+                                            warn!("error oversizing pty for redraw: {}", e);
+                                        }
+                                        resize_cmd = Some(ResizeCmd {
+                                            size,
+                                            when: clock.now().add(REATTACH_RESIZE_DELAY),
+                                        });
+                                    }
+                                    Err(e) => warn!("getting pty size for redraw: {:?}", e),
+                                }
+                                do_reattach = true;
+                                clear_first = true;
+                            }
+                            Err(err) => {
+                                warn!("redraw: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    recv(args.pipe) -> pipe => {
+                        match pipe {
+                            Ok(pipe) => {
+                                info!("new output pipe (from_start={})", pipe.from_start);
+                                match output_pipe::OutputPipe::spawn(&name, pipe.stream, &pipe_config) {
+                                    Ok(mut out) => {
+                                        let backlog = match (output_spool.as_ref(), pipe.from_start) {
+                                            (Some(spool), true) => {
+                                                let (rows, _) = spool.screen().size();
+                                                let nrows = (scrollback_rows + rows as usize)
+                                                    .min(u16::MAX as usize);
+                                                spool.screen().last_n_rows_contents_formatted(nrows as u16)
+                                            }
+                                            _ => vec![],
+                                        };
+                                        if backlog.is_empty() || out.send(&backlog) {
+                                            pipes.push(out);
+                                        }
+                                    }
+                                    Err(e) => warn!("spawning output pipe: {:?}", e),
+                                }
+                            }
+                            Err(err) => {
+                                warn!("pipe: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        }
+                    }
+                    recv(args.notice) -> notice => {
+                        match notice {
+                            Ok(notice) => {
+                                if args.client_suspended.load(Ordering::Acquire) {
+                                    info!("client suspended, dropping notice '{}'", notice);
+                                } else if let ClientConnectionMsg::New(conn) = &mut client_conn {
+                                    info!("writing notice '{}'", notice);
+                                    Self::write_notice_chunk(conn, &notice);
+                                } else {
+                                    info!("no client to show notice '{}' to", notice);
+                                }
+                            }
+                            Err(err) => {
+                                warn!("notice: bailing due to: {:?}", err);
+                                return Ok(());
+                            }
+                        }
+                    }
 
                     // make this select non-blocking so we spend most of our time parked
                     // in poll
@@ -397,7 +936,7 @@ impl SessionInner {
 
                 let mut executed_resize = false;
                 if let Some(resize_cmd) = resize_cmd.as_ref() {
-                    if resize_cmd.when.saturating_duration_since(time::Instant::now())
+                    if resize_cmd.when.saturating_duration_since(clock.now())
                         == time::Duration::ZERO
                     {
                         let status = pty_master
@@ -418,11 +957,23 @@ impl SessionInner {
                     resize_cmd = None;
                 }
 
+                // Once a suspended client comes back, it has missed
+                // whatever the shell printed in the meantime, so redraw
+                // the screen the same way we do for a reattach.
+                let suspended = args.client_suspended.load(Ordering::Acquire);
+                if was_suspended && !suspended {
+                    info!("client resumed");
+                    do_reattach = true;
+                    clear_first = true;
+                }
+                was_suspended = suspended;
+
                 if do_reattach {
                     use config::SessionRestoreMode::*;
 
                     info!("executing reattach protocol (mode={:?})", args.session_restore_mode);
-                    let restore_buf = match (output_spool.as_mut(), &args.session_restore_mode) {
+                    let mut restore_buf = match (output_spool.as_mut(), &args.session_restore_mode)
+                    {
                         (Some(spool), Screen) => {
                             let (rows, cols) = spool.screen().size();
                             info!(
@@ -437,10 +988,22 @@ impl SessionInner {
                                 "computing lines({}) restore buf with (rows={}, cols={})",
                                 nlines, rows, cols
                             );
-                            spool.screen().last_n_rows_contents_formatted(*nlines)
+                            let mut buf = if clear_first {
+                                // go home and clear the screen
+                                b"\x1b[H\x1b[2J".to_vec()
+                            } else {
+                                vec![]
+                            };
+                            buf.extend(spool.screen().last_n_rows_contents_formatted(*nlines));
+                            buf
                         }
                         (_, _) => vec![],
                     };
+                    // The redrawn screen has none of the shell integration
+                    // markers from the original output, so tack them on.
+                    if !restore_buf.is_empty() {
+                        restore_buf.extend(integration_markers.restore_sequence());
+                    }
                     if let (true, ClientConnectionMsg::New(conn)) =
                         (!restore_buf.is_empty(), &mut client_conn)
                     {
@@ -450,7 +1013,7 @@ impl SessionInner {
                         for block in restore_buf.as_slice().chunks(consts::BUF_SIZE) {
                             let chunk = Chunk { kind: ChunkKind::Data, buf: block };
 
-                            if let Err(err) = chunk.write_to(&mut conn.sink) {
+                            if let Err(err) = conn.write_chunk(&chunk) {
                                 warn!("err writing session-restore buf: {:?}", err);
                             }
                         }
@@ -460,6 +1023,45 @@ impl SessionInner {
                     }
                 }
 
+                // If the session has been producing output faster than its
+                // rate limit allows, stop reading from the pty for a bit. Once
+                // the pty buffer fills up, this blocks the writer, so a runaway
+                // process gets slowed down rather than flooding the client.
+                if let Some(limiter) = rate_limiter.as_mut() {
+                    let wait = limiter.wait_time(clock.now());
+                    if wait > time::Duration::ZERO {
+                        if !throttled {
+                            info!("throttling output for {:?}", wait);
+                            throttled = true;
+                            let notice_due = last_throttle_notice
+                                .map(|t| {
+                                    clock.now().saturating_duration_since(t)
+                                        > THROTTLE_NOTICE_INTERVAL
+                                })
+                                .unwrap_or(true);
+                            if let (true, ClientConnectionMsg::New(conn)) =
+                                (notice_due, &mut client_conn)
+                            {
+                                Self::write_notice_chunk(
+                                    conn,
+                                    "output throttled, session exceeded output_rate_limit",
+                                );
+                                last_throttle_notice = Some(clock.now());
+                            }
+                        }
+                        // Sleep in small increments so we stay responsive
+                        // to reattaches and resizes.
+                        thread::sleep(std::cmp::min(
+                            wait,
+                            time::Duration::from_millis(SHELL_TO_CLIENT_POLL_MS as u64),
+                        ));
+                        continue;
+                    } else if throttled {
+                        info!("done throttling output");
+                        throttled = false;
+                    }
+                }
+
                 // TODO(ethan): what if poll times out on a tick when we have just
                 // set up a restore chunk? It looks like we will just drop the
                 // data as things are now.
@@ -467,32 +1069,60 @@ impl SessionInner {
                 // Block until the shell has some data for us so we can be sure our reads
                 // always succeed. We don't want to end up blocked forever on a read while
                 // a client is trying to attach.
-                let nready = match poll::poll(&mut poll_fds, SHELL_TO_CLIENT_POLL_MS) {
-                    Ok(n) => n,
-                    Err(e) => {
-                        error!("polling pty master: {:?}", e);
-                        return Err(e)?;
+                let nready = if pty_reader.is_eof() {
+                    // The shell has hung up its end of the pty, so poll would
+                    // just keep reporting the hangup. Idle until the session
+                    // gets torn down instead.
+                    thread::sleep(time::Duration::from_millis(SHELL_TO_CLIENT_POLL_MS as u64));
+                    0
+                } else {
+                    match poll::poll(&mut poll_fds, SHELL_TO_CLIENT_POLL_MS) {
+                        Ok(n) => n,
+                        Err(e) => {
+                            error!("polling pty master: {:?}", e);
+                            return Err(e)?;
+                        }
                     }
                 };
-                if nready == 0 {
-                    // if timeout
-                    continue;
-                }
-                if nready != 1 {
-                    return Err(anyhow!("shell->client thread: expected exactly 1 ready fd"));
-                }
-                let len = match pty_master.read(&mut buf) {
-                    Ok(l) => l,
-                    Err(e) => {
-                        error!("reading chunk from pty master: {:?}", e);
-                        return Err(e).context("reading pty master chunk")?;
+                let held;
+                let (mut buf, more_coming, flushed) = if nready == 0 {
+                    // if timeout, the shell has gone quiet, so let out
+                    // anything the filters were holding on to
+                    match output_filter.as_mut().map(|f| f.flush()) {
+                        Some(flushed) if !flushed.is_empty() => {
+                            held = flushed;
+                            (&held[..], false, true)
+                        }
+                        _ => continue,
                     }
+                } else {
+                    if nready != 1 {
+                        return Err(anyhow!("shell->client thread: expected exactly 1 ready fd"));
+                    }
+                    let len = match pty_reader.read(&mut buf) {
+                        Ok(l) => l,
+                        Err(e) => {
+                            error!("reading chunk from pty master: {:?}", e);
+                            return Err(e).context("reading pty master chunk")?;
+                        }
+                    };
+                    if len == 0 {
+                        info!("shell hung up the pty");
+                        continue;
+                    }
+                    args.stats.output_bytes.fetch_add(len as u64, Ordering::Relaxed);
+                    if let Some(limiter) = rate_limiter.as_mut() {
+                        limiter.consume(len, clock.now());
+                    }
+                    trace!(
+                        "read pty master len={} '{}'",
+                        len,
+                        String::from_utf8_lossy(&buf[..len])
+                    );
+                    // a full read means the shell is probably in the middle
+                    // of a burst of output
+                    (&buf[..len], len == buf.len(), false)
                 };
-                if len == 0 {
-                    continue;
-                }
-                let mut buf = &buf[..len];
-                trace!("read pty master len={} '{}'", len, String::from_utf8_lossy(buf));
 
                 // scan for control codes we need to handle
                 let mut reset_client_conn = false;
@@ -510,14 +1140,54 @@ impl SessionInner {
                     }
                 }
 
+                // Filter before anything else sees the output. Flushed
+                // output has already been through the filters.
+                let filtered;
+                if let (Some(filter), true, true) =
+                    (output_filter.as_mut(), has_seen_prompt_sentinel, !flushed)
+                {
+                    filtered = filter.process(buf, more_coming);
+                    args.stats
+                        .pending_output_bytes
+                        .store(filter.held_len() as u64, Ordering::Relaxed);
+                    if filtered.is_empty() {
+                        continue;
+                    }
+                    buf = &filtered[..];
+                }
+                let transformed;
+                if let (Some(transform), true) =
+                    (output_transform.as_mut(), has_seen_prompt_sentinel)
+                {
+                    transformed = transform.transform(buf);
+                    if transformed.is_empty() {
+                        continue;
+                    }
+                    buf = &transformed[..];
+                }
+
                 if !matches!(args.session_restore_mode, config::SessionRestoreMode::Simple) {
                     if let (Some(s), true) = (output_spool.as_mut(), has_seen_prompt_sentinel) {
                         s.process(buf);
+                        integration_markers.process(buf);
+                        let newlines = buf.iter().filter(|b| **b == b'\n').count();
+                        scrollback_rows = (scrollback_rows + newlines).min(args.scrollback_lines);
+                        args.stats
+                            .spool_bytes
+                            .store(spool_bytes(s, scrollback_rows), Ordering::Relaxed);
                     }
                 }
 
-                if let (ClientConnectionMsg::New(conn), true) =
-                    (&mut client_conn, has_seen_prompt_sentinel)
+                if let (Some(recorder), true) = (recorder.as_mut(), has_seen_prompt_sentinel) {
+                    recorder.record(buf);
+                }
+
+                if has_seen_prompt_sentinel && !pipes.is_empty() {
+                    pipes.retain_mut(|pipe| pipe.send(buf));
+                }
+
+                if let (ClientConnectionMsg::New(conn), true, false) =
+                    (&mut client_conn, has_seen_prompt_sentinel, suspended)
                 {
                     let chunk = Chunk { kind: ChunkKind::Data, buf };
 
@@ -526,15 +1196,17 @@ impl SessionInner {
                     // write the first chunk.
                     if needs_initial_motd_dump {
                         needs_initial_motd_dump = false;
-                        if let Err(e) = daily_messenger.dump(&mut conn.sink, &term_db) {
+                        if let Err(e) = daily_messenger.dump(&mut conn.sink, &conn.frames, &term_db)
+                        {
                             warn!("Error handling clear: {:?}", e);
                         }
                     }
 
-                    let write_result =
-                        chunk.write_to(&mut conn.sink).and_then(|_| conn.sink.flush());
+                    let write_result = conn.write_chunk(&chunk).and_then(|_| conn.sink.flush());
                     if let Err(err) = write_result {
-                        info!("client_stream write err, assuming hangup: {:?}", err);
+                        if !conn.evict_if_stalled(&err) {
+                            info!("client_stream write err, assuming hangup: {:?}", err);
+                        }
                         reset_client_conn = true;
                     } else {
                         test_hooks::emit("daemon-wrote-s2c-chunk");
@@ -546,15 +1218,38 @@ impl SessionInner {
             }
         };
 
-        Ok(thread::Builder::new()
-            .name(format!("shell->client({})", self.name))
-            .spawn(move || log_if_error("error in shell->client", closure()))?)
+        Ok(threads::for_session("s2c", &self.name, &self.config).spawn(move || {
+            stats.shell_to_client_running.store(true, Ordering::Relaxed);
+            let res = match panic::catch_unwind(panic::AssertUnwindSafe(&mut closure)) {
+                Ok(res) => res,
+                Err(payload) => {
+                    // Without the shell->client thread, the session can't
+                    // do anything useful, so kill the shell. The next attach
+                    // will notice that it exited and start a fresh one.
+                    let msg = panic_msg(&*payload);
+                    error!("shell->client panicked, killing shell: {}", msg);
+                    if let Err(e) =
+                        signal::kill(Pid::from_raw(child_pid), Some(signal::Signal::SIGKILL))
+                    {
+                        error!("killing shell after shell->client panic: {:?}", e);
+                    }
+                    test_hooks::emit("daemon-session-panicked");
+                    Err(anyhow!("shell->client panicked: {}", msg))
+                }
+            };
+            let res = log_if_error("error in shell->client", res);
+            // the spool and any held back output went down with the thread
+            stats.spool_bytes.store(0, Ordering::Relaxed);
+            stats.pending_output_bytes.store(0, Ordering::Relaxed);
+            stats.shell_to_client_running.store(false, Ordering::Relaxed);
+            res
+        })?)
     }
 
-    fn write_exit_chunk<W: io::Write>(mut sink: W, status: i32) {
+    fn write_exit_chunk(conn: &mut ClientConnection, status: i32) {
         let status_buf: [u8; 4] = status.to_le_bytes();
         let chunk = Chunk { kind: ChunkKind::ExitStatus, buf: status_buf.as_slice() };
-        match chunk.write_to(&mut sink).and_then(|_| sink.flush()) {
+        match conn.write_chunk(&chunk).and_then(|_| conn.sink.flush()) {
             Ok(_) => {
                 trace!("wrote exit status chunk");
             }
@@ -567,6 +1262,21 @@ impl SessionInner {
         };
     }
 
+    fn write_notice_chunk(conn: &mut ClientConnection, notice: &str) {
+        let chunk = Chunk { kind: ChunkKind::Notice, buf: notice.as_bytes() };
+        match conn.write_chunk(&chunk).and_then(|_| conn.sink.flush()) {
+            Ok(_) => {
+                trace!("wrote notice chunk");
+            }
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                trace!("client hangup: {:?}", e);
+            }
+            Err(e) => {
+                error!("writing notice chunk: {:?}", e);
+            }
+        };
+    }
+
     /// bidi_stream shuffles bytes between the subprocess and
     /// the client connection. It returns true if the subprocess
     /// has exited, and false if it is still running.
@@ -594,6 +1304,15 @@ impl SessionInner {
             client_stream.try_clone().context("creating shell->client client stream handle")?;
         let output_sink =
             io::BufWriter::new(client_stream.try_clone().context("wrapping stream in bufwriter")?);
+        let write_timeout = match &self.config.get().client_write_timeout {
+            Some(src) => duration::parse(src).context("parsing client_write_timeout")?,
+            None => DEFAULT_CLIENT_WRITE_TIMEOUT,
+        };
+        // a zero timeout would be an error, so it means no timeout
+        let write_timeout = Some(write_timeout).filter(|t| !t.is_zero());
+        // This covers all the handles on the stream, but only the
+        // shell->client thread writes to it.
+        client_stream.set_write_timeout(write_timeout).context("setting client write timeout")?;
 
         {
             let _s = span!(Level::INFO, "initial_attach_lock(shell_to_client_ctl)").entered();
@@ -603,8 +1322,10 @@ impl SessionInner {
                 .send_timeout(
                     ClientConnectionMsg::New(ClientConnection {
                         sink: output_sink,
+                        frames: self.frames.clone(),
                         size: init_tty_size,
                         stream: shell_to_client_client_stream,
+                        write_timeout,
                     }),
                     SHELL_TO_CLIENT_CTL_TIMEOUT,
                 )
@@ -614,25 +1335,39 @@ impl SessionInner {
                 .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
                 .context("waiting for client connection ack")?;
             info!("client connection status={:?}", status);
+
+            if let Some(notice) = self.pending_notice.take() {
+                shell_to_client_ctl
+                    .notice
+                    .send_timeout(notice, SHELL_TO_CLIENT_CTL_TIMEOUT)
+                    .context("sending pending notice to shell->client")?;
+            }
         }
 
         let pty_master =
             self.pty_master.is_parent().context("internal error: executing in child fork")?;
 
-        // A flag to indicate that outstanding threads should stop
-        let stop = AtomicBool::new(false);
+        // Tells the outstanding threads to stop, waking up the
+        // client->shell thread if it is blocked waiting on the client.
+        let stop = cancel::Cancel::new()?;
         // A flag to indicate if the child shell has exited
         let child_done = AtomicBool::new(false);
+        // When the client last typed something, for client_idle_detach.
+        let last_input = Mutex::new(self.clock.now());
+        let idle_detach = match &self.config.get().client_idle_detach {
+            Some(src) => Some(duration::parse(src).context("parsing client_idle_detach")?),
+            None => None,
+        };
 
         thread::scope(|s| -> anyhow::Result<()> {
             // Spawn the main data transport threads
             let client_to_shell_h = self.spawn_client_to_shell(
-                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream)?;
+                s, conn_id, &stop, &pty_master, &mut client_to_shell_client_stream, &last_input)?;
 
             // Send a steady stream of heartbeats to the client
             // so that if the connection unexpectedly goes
             // down, we detect it immediately.
-            let heartbeat_h = self.spawn_heartbeat(s, conn_id, &stop)?;
+            let heartbeat_h = self.spawn_heartbeat(s, conn_id, &stop, write_timeout.is_some())?;
 
             // poll the pty master fd to see if the child
             // shell has exited.
@@ -650,16 +1385,32 @@ impl SessionInner {
                         supervisor_h.is_finished(),
                         c_done,
                     );
-                    stop.store(true, Ordering::Relaxed);
+                    stop.cancel();
                     break;
                 }
+                if let Some(idle_detach) = idle_detach {
+                    let idle_for = self.clock.now().saturating_duration_since(*last_input.lock().unwrap());
+                    if idle_for >= idle_detach {
+                        info!("detaching client after {:?} without input", idle_for);
+                        let notice = format!(
+                            "shpool: detaching from session '{}' after {} without input",
+                            self.name, duration::format(idle_detach));
+                        let notice_res = self.shell_to_client_ctl.lock().unwrap()
+                            .notice.send_timeout(notice, SHELL_TO_CLIENT_CTL_TIMEOUT);
+                        if let Err(e) = notice_res {
+                            warn!("showing idle detach notice: {:?}", e);
+                        }
+                        stop.cancel();
+                        break;
+                    }
+                }
                 thread::sleep(consts::JOIN_POLL_DURATION);
             }
 
-            // disconnect the shell->client thread first since that will shutdown the
-            // client stream, and the client->shell thread hangs out blocked on
-            // that stream, so we need to close it in order to get all our
-            // cows to come home.
+            // disconnect the shell->client thread so that it shuts down the
+            // client stream and lets go of its handle on it. The client->shell
+            // thread has already been woken up by the stop signal, so it won't
+            // be left blocked on the stream holding the fd open.
             let c_done = child_done.load(Ordering::Acquire);
             {
                 let _s = span!(Level::INFO, "disconnect_lock(shell_to_client_ctl)").entered();
@@ -669,7 +1420,7 @@ impl SessionInner {
                         .wait(Some(Duration::from_secs(0)))
                         .unwrap_or(1);
                     info!("telling shell->client to disconnect with exit status {}", exit_status);
-                    ClientConnectionMsg::DisconnectExit(exit_status)
+                    ClientConnectionMsg::DisconnectExit(exit_status, None)
                 } else {
                     info!("telling shell->client to disconnect without reaping");
                     ClientConnectionMsg::Disconnect
@@ -727,9 +1478,10 @@ impl SessionInner {
         &'scope self,
         scope: &'scope thread::Scope<'scope, '_>,
         conn_id: usize,
-        stop: &'scope AtomicBool,
+        stop: &'scope cancel::Cancel,
         pty_master: &'scope shpool_pty::fork::Master,
         shell_to_client_client_stream: &'scope mut UnixStream,
+        last_input: &'scope Mutex<time::Instant>,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
         let empty_bindings = vec![config::Keybinding {
             binding: String::from("Ctrl-Space Ctrl-q"),
@@ -745,14 +1497,13 @@ impl SessionInner {
                 .map(|binding| (binding.binding.as_str(), binding.action)),
         );
 
-        thread::Builder::new()
-            .name(format!("client->shell({})", self.name))
+        threads::for_session("c2s", &self.name, &self.config)
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s =
                     span!(Level::INFO, "client->shell", s = self.name, cid = conn_id).entered();
                 let mut bindings = bindings.context("compiling keybindings engine")?;
 
-                let mut master_writer = *pty_master;
+                let mut master_writer = pty_io::Master::new(pty_master)?;
 
                 let mut snip_sections = vec![]; // (<len>, <end offset>)
                 let mut keep_sections = vec![]; // (<start offset>, <end offset>)
@@ -760,7 +1511,7 @@ impl SessionInner {
                 let mut partial_keybinding = vec![];
 
                 loop {
-                    if stop.load(Ordering::Relaxed) {
+                    if stop.is_cancelled() {
                         info!("recvd stop msg (1)");
                         return Ok(());
                     }
@@ -772,12 +1523,25 @@ impl SessionInner {
                     //
                     // Also, note that we don't access through the mutex because reads
                     // don't need to be excluded from trampling on writes.
+                    //
+                    // Wait for the client to say something before reading so that
+                    // a detach or kill during a quiet spell doesn't leave us stuck
+                    // in read(2) holding the stream open.
+                    if !stop.wait_readable(&*shell_to_client_client_stream)? {
+                        info!("recvd stop msg while waiting for client");
+                        return Ok(());
+                    }
                     let mut len = shell_to_client_client_stream
                         .read(&mut buf)
                         .context("reading client chunk")?;
                     if len == 0 {
-                        continue;
+                        // The stream only reports EOF once the client has hung up
+                        // or the shell->client thread has shut it down, so there
+                        // is nothing more coming.
+                        info!("client stream closed");
+                        return Ok(());
                     }
+                    *last_input.lock().unwrap() = self.clock.now();
                     test_hooks::emit("daemon-read-c2s-chunk");
                     trace!("read client len={}: '{}'", len, String::from_utf8_lossy(&buf[..len]),);
 
@@ -834,6 +1598,8 @@ impl SessionInner {
                                 use keybindings::Action::*;
                                 match action {
                                     Detach => self.action_detach()?,
+                                    Redraw => self.action_redraw()?,
+                                    Paste => self.action_paste(&mut master_writer)?,
                                     NoOp => {}
                                 }
                             }
@@ -857,7 +1623,21 @@ impl SessionInner {
                     }
                     len = snip_buf(&mut buf[..], len, &snip_sections[..], &mut keep_sections);
 
-                    master_writer.write_all(&buf[0..len]).context("writing client chunk")?;
+                    let transformed = self
+                        .input_transform
+                        .0
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .map(|transform| transform.transform(&buf[0..len]));
+                    let chunk = transformed.as_deref().unwrap_or(&buf[0..len]);
+                    let len = chunk.len();
+                    if len == 0 {
+                        continue;
+                    }
+
+                    master_writer.write_all(chunk).context("writing client chunk")?;
+                    self.stats.input_bytes.fetch_add(len as u64, Ordering::Relaxed);
 
                     master_writer.flush().context("flushing input from client to shell")?;
 
@@ -872,21 +1652,30 @@ impl SessionInner {
         &'scope self,
         scope: &'scope thread::Scope<'scope, '_>,
         conn_id: usize,
-        stop: &'scope AtomicBool,
+        stop: &'scope cancel::Cancel,
+        // Set if writes to the client time out, so that a shell->client
+        // thread stuck on a slow client is sure to come back eventually.
+        writes_bounded: bool,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("heartbeat({})", self.name))
+        threads::for_session("hb", &self.name, &self.config)
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s1 = span!(Level::INFO, "heartbeat", s = self.name, cid = conn_id).entered();
 
+                let mut next_beat = self.clock.at(self.clock.now() + consts::HEARTBEAT_DURATION);
                 loop {
                     trace!("checking stop_rx");
-                    if stop.load(Ordering::Relaxed) {
+                    if stop.is_cancelled() {
                         info!("recvd stop msg");
                         return Ok(());
                     }
 
-                    thread::sleep(consts::HEARTBEAT_DURATION);
+                    // Never block for longer than a heartbeat in real time,
+                    // so that we still notice the stop flag promptly when
+                    // the clock is mocked out and not moving.
+                    if next_beat.recv_timeout(consts::HEARTBEAT_DURATION).is_err() {
+                        continue;
+                    }
+                    next_beat = self.clock.at(self.clock.now() + consts::HEARTBEAT_DURATION);
                     {
                         let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
                         match shell_to_client_ctl
@@ -899,22 +1688,44 @@ impl SessionInner {
                             Err(crossbeam_channel::SendTimeoutError::Disconnected(_)) => {
                                 return Ok(())
                             }
+                            // The shell->client thread is busy writing to a
+                            // slow client, and will either finish or give up
+                            // on the client once the write times out.
+                            Err(crossbeam_channel::SendTimeoutError::Timeout(_))
+                                if writes_bounded =>
+                            {
+                                debug!("shell->client busy, skipping heartbeat");
+                                continue;
+                            }
                             Err(e) => {
                                 return Err(e)
                                     .context("requesting heartbeat from shell->client thread")
                             }
                             _ => {}
                         }
-                        let client_present = match shell_to_client_ctl
-                            .heartbeat_ack
-                            .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
-                        {
-                            // If the channel is disconnected, it means that the shell exited and
-                            // the shell->client process exited cleanly. We should not raise a
-                            // ruckus.
-                            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => return Ok(()),
-                            Err(e) => return Err(e).context("waiting for heartbeat ack"),
-                            Ok(client_present) => client_present,
+                        let client_present = loop {
+                            match shell_to_client_ctl
+                                .heartbeat_ack
+                                .recv_timeout(SHELL_TO_CLIENT_CTL_TIMEOUT)
+                            {
+                                // If the channel is disconnected, it means that the shell exited
+                                // and the shell->client process exited cleanly. We should not
+                                // raise a ruckus.
+                                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                                    return Ok(())
+                                }
+                                // Writing the heartbeat is taking a while, but it
+                                // can't take longer than the write timeout, and the
+                                // shell->client thread will block until it can hand
+                                // us the ack, so we have to stick around for it.
+                                Err(crossbeam_channel::RecvTimeoutError::Timeout)
+                                    if writes_bounded =>
+                                {
+                                    debug!("waiting on slow heartbeat write");
+                                }
+                                Err(e) => return Err(e).context("waiting for heartbeat ack"),
+                                Ok(client_present) => break client_present,
+                            }
                         };
                         if !client_present {
                             // Bail from the thread to get the rest of the
@@ -932,19 +1743,18 @@ impl SessionInner {
         &'scope self,
         scope: &'scope thread::Scope<'scope, '_>,
         conn_id: usize,
-        stop: &'scope AtomicBool,
+        stop: &'scope cancel::Cancel,
         child_done: &'scope AtomicBool,
         pty_master: &'scope shpool_pty::fork::Master,
         child_exit_notifier: Arc<ExitNotifier>,
     ) -> anyhow::Result<thread::ScopedJoinHandle<'scope, anyhow::Result<()>>> {
-        thread::Builder::new()
-            .name(format!("supervisor({})", self.name))
+        threads::for_session("sup", &self.name, &self.config)
             .spawn_scoped(scope, move || -> anyhow::Result<()> {
                 let _s1 = span!(Level::INFO, "supervisor", s = self.name, cid = conn_id).entered();
 
                 loop {
                     trace!("checking stop_rx (pty_master={:?})", pty_master.raw_fd());
-                    if stop.load(Ordering::Relaxed) {
+                    if stop.is_cancelled() {
                         info!("recvd stop msg");
                         return Ok(());
                     }
@@ -990,6 +1800,28 @@ impl SessionInner {
         info!("action detach, status={:?}", status);
         Ok(())
     }
+
+    fn action_redraw(&self) -> anyhow::Result<()> {
+        let shell_to_client_ctl = self.shell_to_client_ctl.lock().unwrap();
+        shell_to_client_ctl
+            .redraw
+            .send_timeout((), SHELL_TO_CLIENT_CTL_TIMEOUT)
+            .context("signaling redraw to shell->client thread")?;
+        info!("action redraw");
+        Ok(())
+    }
+
+    fn action_paste(&self, pty: &mut impl Write) -> anyhow::Result<()> {
+        let Some(input) = self.clipboard.contents_as_input() else {
+            info!("action paste, clipboard empty");
+            return Ok(());
+        };
+        pty.write_all(&input).context("pasting clipboard")?;
+        pty.flush().context("flushing paste")?;
+        self.stats.input_bytes.fetch_add(input.len() as u64, Ordering::Relaxed);
+        info!("action paste, len={}", input.len());
+        Ok(())
+    }
 }
 
 /// A handle for poking at the always-running shell->client thread.
@@ -1021,6 +1853,20 @@ pub struct ReaderCtl {
     // True if the client is still listening, false if it has hung up
     // on us.
     pub heartbeat_ack: crossbeam_channel::Receiver<bool>,
+
+    /// A control channel telling the shell->client thread to write
+    /// a notice chunk to the attached client, if there is one. It is
+    /// a rendezvous channel, so once a send completes, the notice will
+    /// be written before anything sent to the thread afterwards.
+    pub notice: crossbeam_channel::Sender<String>,
+
+    /// A control channel telling the shell->client thread to redraw the
+    /// attached client's screen.
+    pub redraw: crossbeam_channel::Sender<()>,
+
+    /// A control channel handing the shell->client thread a client
+    /// that wants a read only copy of the output.
+    pub pipe: crossbeam_channel::Sender<output_pipe::PipeConn>,
 }
 
 /// Given a buffer, a length after which the data is not valid, a list of
@@ -1082,6 +1928,58 @@ fn snip_buf(
 mod test {
     use super::*;
 
+    #[test]
+    fn memory_accounting() {
+        let stats = SessionStats::default();
+        assert_eq!(stats.memory(false).total(), 0);
+
+        stats.spool_bytes.store(1000, Ordering::Relaxed);
+        stats.pending_output_bytes.store(10, Ordering::Relaxed);
+        stats.shell_to_client_running.store(true, Ordering::Relaxed);
+        let memory = stats.memory(true);
+        assert_eq!(memory.io_buffer_bytes, 2 * consts::BUF_SIZE as u64);
+        assert_eq!(memory.total(), 1010 + 2 * consts::BUF_SIZE as u64);
+    }
+
+    #[test]
+    fn attachment() {
+        let attachment = Arc::new(Attachment::default());
+        assert!(!attachment.is_attached());
+        // returns right away with nobody attached
+        attachment.wait_detached();
+
+        let attached = attachment.attach();
+        assert!(attachment.is_attached());
+        let waiter = {
+            let attachment = Arc::clone(&attachment);
+            thread::spawn(move || attachment.wait_detached())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(attached);
+        waiter.join().unwrap();
+        assert!(!attachment.is_attached());
+    }
+
+    #[test]
+    fn creation() {
+        let creation = Arc::new(Creation::new(true));
+        assert!(creation.wait_created(Duration::from_millis(10)));
+
+        let creating = creation.guard();
+        let waiter = {
+            let creation = Arc::clone(&creation);
+            thread::spawn(move || creation.wait_created(Duration::from_secs(30)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+
+        drop(creating);
+        assert!(!waiter.join().unwrap());
+        assert!(!creation.is_pending());
+    }
+
     #[test]
     fn test_snip_buf() {
         let cases = vec![
@@ -0,0 +1,252 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Keeps shell integration features working across a reattach.
+
+  Shells set up for integration with terminals like kitty, iTerm2 or
+  wezterm mark up their output with OSC escape sequences: OSC 133
+  semantic prompt markers that say where prompts and command output
+  start, and OSC 7 or iTerm2's OSC 1337 sequences that tell the terminal
+  about the current directory and host. The terminal uses these for
+  things like jumping between prompts.
+
+  When we restore a session on reattach, we send the client a redrawn
+  screen rather than the raw output, so the terminal never sees any of
+  these sequences and loses track of where it is. To fix that up, we
+  watch the output for them, and after the restore we re-send the
+  latest directory and host sequences, plus a prompt start marker at
+  the start of the cursor line if the shell was sitting at a prompt.
+*/
+
+/// OSC payloads longer than this are not ones we care about, so we
+/// stop collecting them rather than buffering arbitrary amounts of
+/// output.
+const MAX_PAYLOAD_LEN: usize = 4096;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Ground,
+    Esc,
+    Osc,
+    /// Saw an ESC inside an OSC, which should be the start of the
+    /// string terminator.
+    OscEsc,
+    /// Inside an OSC that is too long to care about.
+    OscIgnored,
+    OscIgnoredEsc,
+}
+
+/// Tracks the shell integration sequences in a session's output.
+#[derive(Debug, Default)]
+pub struct MarkerTracker {
+    state: State,
+    payload: Vec<u8>,
+    /// The most recent OSC 133 marker kind, like b'A' for prompt start.
+    last_mark: Option<u8>,
+    /// The payload of the most recent prompt start marker, which can
+    /// carry options that we want to hand back to the terminal.
+    prompt_start: Option<Vec<u8>>,
+    /// The payload of the most recent OSC 7 working directory report.
+    cwd: Option<Vec<u8>>,
+    /// The payloads of the most recent iTerm2 CurrentDir and RemoteHost
+    /// sequences.
+    iterm_current_dir: Option<Vec<u8>>,
+    iterm_remote_host: Option<Vec<u8>>,
+}
+
+impl MarkerTracker {
+    /// Scan a chunk of output from the shell.
+    pub fn process(&mut self, buf: &[u8]) {
+        for byte in buf.iter() {
+            self.transition(*byte);
+        }
+    }
+
+    fn transition(&mut self, byte: u8) {
+        self.state = match (self.state, byte) {
+            (_, CAN) | (_, SUB) => State::Ground,
+
+            (State::Ground, ESC) => State::Esc,
+            (State::Ground, _) => State::Ground,
+
+            (State::Esc, b']') => {
+                self.payload.clear();
+                State::Osc
+            }
+            (State::Esc, ESC) => State::Esc,
+            (State::Esc, _) => State::Ground,
+
+            (State::Osc, BEL) => {
+                self.finish();
+                State::Ground
+            }
+            (State::Osc, ESC) => State::OscEsc,
+            (State::Osc, _) if self.payload.len() >= MAX_PAYLOAD_LEN => State::OscIgnored,
+            (State::Osc, _) => {
+                self.payload.push(byte);
+                State::Osc
+            }
+
+            (State::OscEsc, b'\\') => {
+                self.finish();
+                State::Ground
+            }
+            // Any other escape cuts the OSC short and starts something new.
+            (State::OscEsc, _) => {
+                self.state = State::Esc;
+                self.transition(byte);
+                return;
+            }
+
+            (State::OscIgnored, BEL) => State::Ground,
+            (State::OscIgnored, ESC) => State::OscIgnoredEsc,
+            (State::OscIgnored, _) => State::OscIgnored,
+            (State::OscIgnoredEsc, b'\\') => State::Ground,
+            (State::OscIgnoredEsc, _) => {
+                self.state = State::Esc;
+                self.transition(byte);
+                return;
+            }
+        };
+    }
+
+    /// Handle a complete OSC with the payload collected so far.
+    fn finish(&mut self) {
+        let payload = &self.payload[..];
+        let (code, rest) = match payload.iter().position(|b| *b == b';') {
+            Some(i) => (&payload[..i], &payload[i + 1..]),
+            None => (payload, &[][..]),
+        };
+
+        match code {
+            b"133" => {
+                if let Some(kind) = rest.first() {
+                    self.last_mark = Some(*kind);
+                    if *kind == b'A' {
+                        self.prompt_start = Some(payload.to_vec());
+                    }
+                }
+            }
+            b"7" => self.cwd = Some(payload.to_vec()),
+            b"1337" if rest.starts_with(b"CurrentDir=") => {
+                self.iterm_current_dir = Some(payload.to_vec())
+            }
+            b"1337" if rest.starts_with(b"RemoteHost=") => {
+                self.iterm_remote_host = Some(payload.to_vec())
+            }
+            _ => {}
+        }
+    }
+
+    /// The sequences to send the client right after a session restore
+    /// to bring its shell integration state back in line with the
+    /// session's.
+    pub fn restore_sequence(&self) -> Vec<u8> {
+        let mut seq = vec![];
+        for payload in
+            [&self.cwd, &self.iterm_remote_host, &self.iterm_current_dir].into_iter().flatten()
+        {
+            push_osc(&mut seq, payload);
+        }
+
+        // Between a prompt start and the command starting to run, the
+        // cursor is on the prompt line, so mark the start of that line.
+        if matches!(self.last_mark, Some(b'A') | Some(b'B')) {
+            // save the cursor, jump to the start of the line, mark it,
+            // then put the cursor back
+            seq.extend_from_slice(b"\x1b7\r");
+            push_osc(&mut seq, self.prompt_start.as_deref().unwrap_or(b"133;A"));
+            seq.extend_from_slice(b"\x1b8");
+        }
+
+        seq
+    }
+}
+
+fn push_osc(seq: &mut Vec<u8>, payload: &[u8]) {
+    seq.extend_from_slice(b"\x1b]");
+    seq.extend_from_slice(payload);
+    seq.extend_from_slice(b"\x1b\\");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn restore_after(chunks: &[&[u8]]) -> String {
+        let mut tracker = MarkerTracker::default();
+        for chunk in chunks.iter() {
+            tracker.process(chunk);
+        }
+        String::from_utf8_lossy(&tracker.restore_sequence()).into_owned()
+    }
+
+    #[test]
+    fn prompt_markers() {
+        // at a prompt, terminated with BEL or ST
+        assert_eq!(restore_after(&[b"\x1b]133;A\x07$ "]), "\x1b7\r\x1b]133;A\x1b\\\x1b8");
+        assert_eq!(
+            restore_after(&[b"\x1b]133;A;aid=12\x1b\\$ \x1b]133;B\x1b\\"]),
+            "\x1b7\r\x1b]133;A;aid=12\x1b\\\x1b8"
+        );
+
+        // a command is running, or finished without a new prompt yet
+        assert_eq!(restore_after(&[b"\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]133;C\x07"]), "");
+        assert_eq!(restore_after(&[b"\x1b]133;C\x07out\x1b]133;D;0\x07"]), "");
+
+        // no shell integration at all
+        assert_eq!(restore_after(&[b"$ ls\r\nfoo  bar\r\n"]), "");
+    }
+
+    #[test]
+    fn split_across_chunks() {
+        assert_eq!(
+            restore_after(&[b"out\x1b]13", b"3;A\x1b", b"\\$ "]),
+            "\x1b7\r\x1b]133;A\x1b\\\x1b8"
+        );
+    }
+
+    #[test]
+    fn directory_reports() {
+        assert_eq!(
+            restore_after(&[
+                b"\x1b]7;file://host/old\x07\x1b]7;file://host/tmp\x07",
+                b"\x1b]1337;RemoteHost=me@host\x07\x1b]1337;CurrentDir=/tmp\x07",
+                b"\x1b]1337;SetMark\x07",
+            ]),
+            "\x1b]7;file://host/tmp\x1b\\\x1b]1337;RemoteHost=me@host\x1b\\\
+             \x1b]1337;CurrentDir=/tmp\x1b\\"
+        );
+    }
+
+    #[test]
+    fn malformed_sequences() {
+        // an OSC cut short by another escape sequence
+        assert_eq!(restore_after(&[b"\x1b]133;A\x1b[0m$ "]), "");
+        // cancelled
+        assert_eq!(restore_after(&[b"\x1b]133;A\x18\x1b]7;x\x07"]), "\x1b]7;x\x1b\\");
+        // a huge OSC, like an inline image, gets skipped over
+        let mut big = b"\x1b]1337;File=".to_vec();
+        big.extend(std::iter::repeat(b'x').take(MAX_PAYLOAD_LEN * 2));
+        big.extend_from_slice(b"\x07\x1b]133;A\x07");
+        assert_eq!(restore_after(&[&big]), "\x1b7\r\x1b]133;A\x1b\\\x1b8");
+    }
+}
@@ -26,8 +26,7 @@ use tracing::{info, instrument};
 use crate::{
     config,
     daemon::pager::{Pager, PagerCtl},
-    duration,
-    protocol::ChunkExt as _,
+    duration, protocol,
 };
 
 /// Showers know how to show the message of the day.
@@ -62,6 +61,7 @@ impl DailyMessenger {
     pub fn dump<W: io::Write>(
         &self,
         mut stream: W,
+        frames: &protocol::FrameWriter,
         term_db: &termini::TermInfo,
     ) -> anyhow::Result<()> {
         assert!(matches!(
@@ -73,7 +73,7 @@ impl DailyMessenger {
 
         let chunk = Chunk { kind: ChunkKind::Data, buf: raw_motd_value.as_slice() };
 
-        chunk.write_to(&mut stream).context("dumping motd")
+        frames.write(&mut stream, &chunk).context("dumping motd")
     }
 
     /// Display the motd in a pager. Callers should do a downcast error
@@ -90,6 +90,8 @@ impl DailyMessenger {
         &self,
         // The client connection on which to display the pager.
         client_stream: &mut UnixStream,
+        // How chunks are framed on the client connection.
+        frames: &protocol::FrameWriter,
         // The session to associate this pager with for SIGWINCH purposes.
         ctl_slot: Arc<Mutex<Option<PagerCtl>>>,
         // The size of the tty to start off with
@@ -121,6 +123,7 @@ impl DailyMessenger {
 
         let final_size = pager.display(
             client_stream,
+            frames,
             ctl_slot,
             init_tty_size,
             motd_value.as_str(),
@@ -22,12 +22,15 @@ use anyhow::Context;
 use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
 use tracing::{error, info};
 
+use super::server::Server;
+
 pub struct Handler {
     sock: Option<PathBuf>,
+    server: Arc<Server>,
 }
 impl Handler {
-    pub fn new(sock: Option<PathBuf>) -> Self {
-        Handler { sock }
+    pub fn new(sock: Option<PathBuf>, server: Arc<Server>) -> Self {
+        Handler { sock, server }
     }
 
     pub fn spawn(self) -> anyhow::Result<()> {
@@ -48,26 +51,32 @@ impl Handler {
         }
 
         let mut signals = Signals::new(TERM_SIGNALS).context("creating signal iterator")?;
-        thread::spawn(move || {
-            // Signals are exposed via an iterator so this loop is just to consume
-            // that by blocking until the first value is emitted. Clippy thinks we
-            // are looping over a collection and is confused about why we always
-            // exit in the loop body.
-            #[allow(clippy::never_loop)]
-            for signal in &mut signals {
-                assert!(TERM_SIGNALS.contains(&signal));
+        thread::Builder::new()
+            .name(String::from("signals"))
+            .spawn(move || {
+                // Signals are exposed via an iterator so this loop is just to consume
+                // that by blocking until the first value is emitted. Clippy thinks we
+                // are looping over a collection and is confused about why we always
+                // exit in the loop body.
+                #[allow(clippy::never_loop)]
+                for signal in &mut signals {
+                    assert!(TERM_SIGNALS.contains(&signal));
+
+                    info!("term sig handler: shutting down sessions");
+                    self.server.shutdown();
 
-                info!("term sig handler: cleaning up socket");
-                if let Some(sock) = self.sock {
-                    if let Err(e) = std::fs::remove_file(sock).context("cleaning up socket") {
-                        error!("error cleaning up socket file: {}", e);
+                    info!("term sig handler: cleaning up socket");
+                    if let Some(sock) = self.sock {
+                        if let Err(e) = std::fs::remove_file(sock).context("cleaning up socket") {
+                            error!("error cleaning up socket file: {}", e);
+                        }
                     }
-                }
 
-                info!("term sig handler: exiting");
-                std::process::exit(0);
-            }
-        });
+                    info!("term sig handler: exiting");
+                    std::process::exit(0);
+                }
+            })
+            .context("spawning signal handler")?;
 
         Ok(())
     }
@@ -0,0 +1,503 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Spawning shells with posix_spawn rather than fork.
+//!
+//! The daemon runs a lot of threads, so the child of a fork only gets to
+//! call async-signal-safe functions before it execs, and anything else
+//! (allocating, logging, taking a lock some other thread held at the
+//! time of the fork) risks deadlocking the child. posix_spawn leaves the
+//! fork and exec to libc, which sets the child up from a list of file
+//! actions without running any of our code in between.
+//!
+//! The catch is that posix_spawn can only do a handful of things to the
+//! child, so sessions that need scheduling or identity setup still go
+//! through the fork path in the server. The pty gets opened ahead of the
+//! spawn, so the daemon can do things that need its name (like opening
+//! a pam session) before the shell starts.
+//!
+//! Whichever way a shell gets started, it should only ever see its pty
+//! on fds 0 through 2. Anything else the daemon has open (the listening
+//! socket, client streams, the pty masters of other sessions) would keep
+//! those alive for as long as the shell runs, even after the daemon is
+//! gone. The fds the daemon opens itself are close-on-exec, and both
+//! spawn paths close whatever else is left over in the child.
+
+use std::{
+    os::fd::{AsRawFd, BorrowedFd},
+    process,
+};
+
+use anyhow::Context;
+use nix::{fcntl, unistd};
+
+use crate::consts;
+
+/// Mark an fd that some library opened for us as close-on-exec, so that
+/// it doesn't leak into the shells and hooks the daemon spawns.
+pub fn set_cloexec(fd: BorrowedFd) -> anyhow::Result<()> {
+    let flags =
+        fcntl::fcntl(fd.as_raw_fd(), fcntl::FcntlArg::F_GETFD).context("getting fd flags")?;
+    let flags = fcntl::FdFlag::from_bits_truncate(flags) | fcntl::FdFlag::FD_CLOEXEC;
+    fcntl::fcntl(fd.as_raw_fd(), fcntl::FcntlArg::F_SETFD(flags)).context("setting fd flags")?;
+    Ok(())
+}
+
+/// One past the highest fd the process may have open. This needs to be
+/// looked up before forking, since the child of a fork should stick to
+/// async-signal-safe calls.
+pub fn fd_limit() -> libc::c_int {
+    match unistd::sysconf(unistd::SysconfVar::OPEN_MAX) {
+        Ok(Some(max)) if max > 0 => libc::c_int::try_from(max).unwrap_or(libc::c_int::MAX),
+        _ => 1024,
+    }
+}
+
+/// Close every fd above stderr in the child of a fork, right before it
+/// execs. `limit` should come from `fd_limit`, called before the fork.
+/// Only makes async-signal-safe calls.
+pub fn close_inherited_fds(limit: libc::c_int) {
+    let first = consts::STDERR_FD + 1;
+    #[cfg(target_os = "linux")]
+    {
+        // Safety: close_range only closes fds, and we are about to exec
+        // so nothing in the child is going to use them again.
+        let res = unsafe {
+            libc::syscall(libc::SYS_close_range, first as libc::c_uint, libc::c_uint::MAX, 0)
+        };
+        if res == 0 {
+            return;
+        }
+        // Older kernels don't have close_range, so fall back to
+        // closing them one by one.
+    }
+    for fd in first..limit {
+        let _ = unistd::close(fd);
+    }
+}
+
+/// Whether shells can be spawned with posix_spawn here. We need the
+/// glibc extensions to start a new session, set the working directory
+/// and close inherited fds, and we rely on linux handing out a
+/// controlling terminal to a session leader that opens a tty. The fd
+/// closing extension only arrived in glibc 2.34, so this gets checked
+/// at runtime.
+pub fn supported() -> bool {
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    {
+        imp::addclosefrom().is_some()
+    }
+    #[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+    {
+        false
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub use imp::{open_pty, Pty};
+
+/// Spawn `cmd` as the leader of a new session with `pty` as its
+/// controlling terminal, returning it in the same shape as a fork so
+/// that the rest of the daemon doesn't need to care how the shell got
+/// started. `arg0` overrides the name the program sees itself run as.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn posix_spawn(
+    pty: Pty,
+    cmd: &process::Command,
+    arg0: Option<&str>,
+) -> anyhow::Result<shpool_pty::fork::Fork> {
+    imp::posix_spawn(pty, cmd, arg0)
+}
+
+/// There is no way to spawn onto a pty on this platform, so there is
+/// never one to hand out.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub struct Pty(std::convert::Infallible);
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+impl Pty {
+    pub fn name(&self) -> &str {
+        match self.0 {}
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn open_pty(_noecho: bool) -> anyhow::Result<Pty> {
+    Err(anyhow::anyhow!("posix_spawn is not supported on this platform"))
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn posix_spawn(
+    pty: Pty,
+    _cmd: &process::Command,
+    _arg0: Option<&str>,
+) -> anyhow::Result<shpool_pty::fork::Fork> {
+    match pty.0 {}
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+mod imp {
+    use std::{
+        env,
+        ffi::{CStr, CString, OsStr},
+        fs, io, mem,
+        os::{
+            fd::{AsFd, AsRawFd},
+            unix::{ffi::OsStrExt, fs::OpenOptionsExt, fs::PermissionsExt},
+        },
+        path::{Path, PathBuf},
+        process, ptr,
+        sync::OnceLock,
+    };
+
+    use anyhow::{anyhow, Context};
+    use tracing::info;
+
+    use crate::{consts, tty};
+
+    const MAX_PTS_NAME: usize = 1024;
+
+    /// A fresh pty for a shell to get spawned onto. The master gets
+    /// closed if we bail out before handing it off to the `Fork`, which
+    /// takes care of closing it from then on.
+    pub struct Pty {
+        master: Option<shpool_pty::fork::Master>,
+        name: String,
+    }
+
+    impl Pty {
+        /// The path of the slave side, which becomes the shell's tty.
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    impl Drop for Pty {
+        fn drop(&mut self) {
+            if let Some(fd) =
+                self.master.take().and_then(|m| m.borrow_fd().map(|fd| fd.as_raw_fd()))
+            {
+                // Safety: we own the fd and nothing else has a copy of
+                // the master.
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        }
+    }
+
+    /// The posix_spawn file actions and attributes, which need to be
+    /// destroyed once we are done with them.
+    struct SpawnSetup {
+        actions: libc::posix_spawn_file_actions_t,
+        attrs: libc::posix_spawnattr_t,
+    }
+
+    impl SpawnSetup {
+        fn new() -> io::Result<Box<Self>> {
+            // Safety: these are plain C structs which get initialized by
+            // the init calls right away. They are boxed so that they stay
+            // put, since glibc is free to keep pointers into them.
+            unsafe {
+                let mut setup: Box<SpawnSetup> = Box::new(mem::zeroed());
+                check(libc::posix_spawn_file_actions_init(&mut setup.actions))?;
+                if let Err(e) = check(libc::posix_spawnattr_init(&mut setup.attrs)) {
+                    libc::posix_spawn_file_actions_destroy(&mut setup.actions);
+                    return Err(e);
+                }
+                Ok(setup)
+            }
+        }
+    }
+
+    impl Drop for SpawnSetup {
+        fn drop(&mut self) {
+            // Safety: both got initialized in new.
+            unsafe {
+                libc::posix_spawn_file_actions_destroy(&mut self.actions);
+                libc::posix_spawnattr_destroy(&mut self.attrs);
+            }
+        }
+    }
+
+    type AddCloseFrom =
+        unsafe extern "C" fn(*mut libc::posix_spawn_file_actions_t, libc::c_int) -> libc::c_int;
+
+    /// posix_spawn_file_actions_addclosefrom_np, if the glibc we are
+    /// running against has it. It gets looked up rather than linked to
+    /// so that the daemon still starts against an older glibc, and just
+    /// forks its shells there.
+    pub fn addclosefrom() -> Option<AddCloseFrom> {
+        static ADDCLOSEFROM: OnceLock<Option<AddCloseFrom>> = OnceLock::new();
+        *ADDCLOSEFROM.get_or_init(|| {
+            let name = b"posix_spawn_file_actions_addclosefrom_np\0";
+            // Safety: the name is nul terminated, and glibc declares
+            // the symbol with the signature of AddCloseFrom.
+            unsafe {
+                let sym = libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr() as *const libc::c_char);
+                if sym.is_null() {
+                    None
+                } else {
+                    Some(mem::transmute::<*mut libc::c_void, AddCloseFrom>(sym))
+                }
+            }
+        })
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        match ret {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+
+    /// Open a new pty, with echo turned off if `noecho` is set.
+    pub fn open_pty(noecho: bool) -> anyhow::Result<Pty> {
+        let ptmx = CString::new("/dev/ptmx")?;
+        let master = shpool_pty::fork::Master::new(&ptmx).context("opening pty master")?;
+        let mut pty = Pty { master: Some(master), name: String::new() };
+        master.grantpt().context("granting pty")?;
+        master.unlockpt().context("unlocking pty")?;
+        let mut pts_buf = vec![0; MAX_PTS_NAME];
+        master.ptsname_r(&mut pts_buf).context("getting pty name")?;
+        pty.name = CStr::from_bytes_until_nul(&pts_buf)
+            .context("parsing pty name")?
+            .to_str()
+            .context("parsing pty name")?
+            .to_string();
+
+        if noecho {
+            // Flags set on the slave stick around after we close it, so
+            // this is in place by the time the shell opens it.
+            let slave = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_NOCTTY | libc::O_CLOEXEC)
+                .open(&pty.name)
+                .context("opening pty slave")?;
+            tty::disable_echo(slave.as_fd()).context("disabling echo on pty")?;
+        }
+
+        Ok(pty)
+    }
+
+    pub fn posix_spawn(
+        mut pty: Pty,
+        cmd: &process::Command,
+        arg0: Option<&str>,
+    ) -> anyhow::Result<shpool_pty::fork::Fork> {
+        let addclosefrom = addclosefrom().ok_or(anyhow!(
+            "posix_spawn_file_actions_addclosefrom_np is missing, glibc is too old"
+        ))?;
+        let master = pty.master.ok_or(anyhow!("no pty master"))?;
+        let pts_name = CString::new(pty.name.as_str())?;
+
+        let env: Vec<(&OsStr, &OsStr)> =
+            cmd.get_envs().filter_map(|(k, v)| v.map(|v| (k, v))).collect();
+        let path_var = env.iter().find(|(k, _)| *k == "PATH").map(|(_, v)| v.to_os_string());
+        let program = resolve(cmd.get_program(), path_var.or_else(|| env::var_os("PATH")))?;
+        let program = CString::new(program.as_os_str().as_bytes())?;
+
+        let mut argv = vec![match arg0 {
+            Some(arg0) => CString::new(arg0)?,
+            None => CString::new(cmd.get_program().as_bytes())?,
+        }];
+        for arg in cmd.get_args() {
+            argv.push(CString::new(arg.as_bytes())?);
+        }
+        let mut envp = vec![];
+        for (k, v) in env.iter() {
+            let mut var = k.as_bytes().to_vec();
+            var.push(b'=');
+            var.extend_from_slice(v.as_bytes());
+            envp.push(CString::new(var)?);
+        }
+        let cwd = match cmd.get_current_dir() {
+            Some(dir) => Some(CString::new(dir.as_os_str().as_bytes())?),
+            None => None,
+        };
+        let argv_ptrs: Vec<*mut libc::c_char> = argv
+            .iter()
+            .map(|a| a.as_ptr() as *mut libc::c_char)
+            .chain(std::iter::once(ptr::null_mut()))
+            .collect();
+        let envp_ptrs: Vec<*mut libc::c_char> = envp
+            .iter()
+            .map(|e| e.as_ptr() as *mut libc::c_char)
+            .chain(std::iter::once(ptr::null_mut()))
+            .collect();
+
+        let mut setup = SpawnSetup::new().context("setting up posix_spawn")?;
+        let mut pid: libc::pid_t = 0;
+        // Safety: everything handed to posix_spawn is a valid, nul
+        // terminated string or array that outlives the call.
+        unsafe {
+            let actions = &mut setup.actions;
+            // Whatever fds the daemon has open that are not close-on-exec,
+            // including ones other threads open while we get set up here,
+            // would otherwise leak into the shell. This closes the pty
+            // master too. glibc uses close_range in the child, so it
+            // covers exactly the fds that are open at the time of the
+            // spawn.
+            check(addclosefrom(actions, consts::STDERR_FD + 1))?;
+            // The child is a session leader by the time the file actions
+            // run, so opening the slave makes it the controlling terminal.
+            check(libc::posix_spawn_file_actions_addopen(
+                actions,
+                consts::STDIN_FD,
+                pts_name.as_ptr(),
+                libc::O_RDWR,
+                0,
+            ))?;
+            check(libc::posix_spawn_file_actions_adddup2(
+                actions,
+                consts::STDIN_FD,
+                consts::STDOUT_FD,
+            ))?;
+            check(libc::posix_spawn_file_actions_adddup2(
+                actions,
+                consts::STDIN_FD,
+                consts::STDERR_FD,
+            ))?;
+            if let Some(cwd) = &cwd {
+                check(libc::posix_spawn_file_actions_addchdir_np(actions, cwd.as_ptr()))?;
+            }
+
+            // Start the shell off with a clean slate of signal handling,
+            // rather than whatever the daemon happens to have blocked or
+            // ignored (rust ignores SIGPIPE, for one).
+            let attrs = &mut setup.attrs;
+            let mut sigs: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut sigs);
+            check(libc::posix_spawnattr_setsigmask(attrs, &sigs))?;
+            libc::sigfillset(&mut sigs);
+            check(libc::posix_spawnattr_setsigdefault(attrs, &sigs))?;
+            check(libc::posix_spawnattr_setflags(
+                attrs,
+                // older versions of libc type SETSID differently
+                (libc::POSIX_SPAWN_SETSID as libc::c_int
+                    | libc::POSIX_SPAWN_SETSIGMASK
+                    | libc::POSIX_SPAWN_SETSIGDEF) as libc::c_short,
+            ))?;
+
+            check(libc::posix_spawn(
+                &mut pid,
+                program.as_ptr(),
+                &setup.actions,
+                &setup.attrs,
+                argv_ptrs.as_ptr(),
+                envp_ptrs.as_ptr(),
+            ))
+            .with_context(|| format!("spawning {:?}", program))?;
+        }
+        info!("spawned pid {} on {:?}", pid, pts_name);
+
+        pty.master.take();
+        Ok(shpool_pty::fork::Fork::Parent(pid, master))
+    }
+
+    /// Find the program to run the way exec would, but using the PATH
+    /// from the shell's environment rather than the daemon's.
+    fn resolve(program: &OsStr, path: Option<std::ffi::OsString>) -> anyhow::Result<PathBuf> {
+        if program.as_bytes().contains(&b'/') {
+            return Ok(PathBuf::from(program));
+        }
+        for dir in env::split_paths(&path.unwrap_or_default()) {
+            let candidate = dir.join(program);
+            if is_executable(&candidate) {
+                return Ok(candidate);
+            }
+        }
+        Err(anyhow!("could not find {:?} in PATH", program))
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        fs::metadata(path)
+            .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(all(test, target_os = "linux", target_env = "gnu"))]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+    use crate::daemon::pty_io;
+
+    fn run(cmd: &process::Command, arg0: Option<&str>) -> anyhow::Result<(String, i32)> {
+        let fork = posix_spawn(open_pty(false)?, cmd, arg0)?;
+        let mut reader = pty_io::Master::new(&fork.is_parent()?)?;
+        let mut out = vec![];
+        reader.read_to_end(&mut out)?;
+        let mut status = 0;
+        // Safety: basic ffi, the pid is our child.
+        unsafe {
+            libc::waitpid(fork.child_pid().unwrap(), &mut status, 0);
+        }
+        Ok((String::from_utf8_lossy(&out).into_owned(), libc::WEXITSTATUS(status)))
+    }
+
+    #[test]
+    fn spawns_in_pty() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c")
+            // /dev/tty can only be opened with a controlling terminal
+            .arg("echo \"$0 $FOO $(pwd)\" && exec 3</dev/tty && echo has-ctty; exit 7")
+            .current_dir(dir.path())
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin")
+            .env("FOO", "foo-val");
+
+        let (out, status) = run(&cmd, Some("-shpool-test"))?;
+        assert!(
+            out.contains(&format!("-shpool-test foo-val {}", dir.path().display())),
+            "out={}",
+            out
+        );
+        assert!(out.contains("has-ctty"), "out={}", out);
+        assert_eq!(status, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn closes_inherited_fds() -> anyhow::Result<()> {
+        // an fd that is not close-on-exec, which would leak without the
+        // close action
+        let (leaky, _other) = unistd::pipe()?;
+        let flags = fcntl::fcntl(leaky.as_raw_fd(), fcntl::FcntlArg::F_GETFD)?;
+        assert_eq!(flags & libc::FD_CLOEXEC, 0);
+
+        let mut cmd = process::Command::new("sh");
+        cmd.arg("-c")
+            .arg(format!(
+                "[ -e /proc/self/fd/{} ] && echo leaked || echo closed",
+                leaky.as_raw_fd()
+            ))
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin");
+        let (out, _) = run(&cmd, None)?;
+        assert!(out.contains("closed"), "out={}", out);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_program() -> anyhow::Result<()> {
+        let mut cmd = process::Command::new("shpool-no-such-program");
+        cmd.env_clear().env("PATH", "/usr/bin:/bin");
+        assert!(posix_spawn(open_pty(false)?, &cmd, None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The state file records the creation parameters and status of
+  every running session so that `shpool daemon --resurrect` can
+  recreate them after the daemon restarts.
+
+  Rather than having every code path that touches the session
+  table remember to update the file, a dedicated thread
+  periodically snapshots the table and rewrites the file whenever
+  the snapshot changes. This also lets us pick up changes that
+  never go through the daemon at all, like a shell changing
+  directories.
+*/
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread, time,
+};
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use shpool_protocol::{SessionDefinition, SessionStatus};
+use tracing::{info, span, warn, Level};
+
+use super::shell;
+
+const STATE_FILE_NAME: &str = "sessions.toml";
+const SNAPSHOT_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+struct State {
+    #[serde(default)]
+    sessions: Vec<PersistedSession>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct PersistedSession {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cmd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    started_at_unix_ms: i64,
+}
+
+/// The path of the state file within the given runtime dir.
+pub fn path(runtime_dir: &Path) -> PathBuf {
+    runtime_dir.join(STATE_FILE_NAME)
+}
+
+/// Read the session definitions out of the state file. A missing
+/// file just means there is nothing to resurrect.
+pub fn read(path: &Path) -> anyhow::Result<Vec<SessionDefinition>> {
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("reading state file"),
+    };
+    let state: State = toml::from_str(&src).context("parsing state file")?;
+    Ok(state
+        .sessions
+        .into_iter()
+        .map(|s| SessionDefinition {
+            name: s.name,
+            cmd: s.cmd,
+            cwd: s.cwd,
+            env: s.env.into_iter().collect(),
+        })
+        .collect())
+}
+
+/// Run the state file writer loop. Should be invoked in a dedicated
+/// thread.
+pub fn run(path: PathBuf, shells: Arc<shell::SessionTable>) -> anyhow::Result<()> {
+    let _s = span!(Level::INFO, "state_file").entered();
+
+    let mut last_written = None;
+    loop {
+        let state = snapshot(&shells);
+        if last_written.as_ref() != Some(&state) {
+            info!("session table changed, writing {:?}", path);
+            if let Err(e) = write(&path, &state) {
+                warn!("writing state file: {:?}", e);
+            } else {
+                last_written = Some(state);
+            }
+        }
+
+        thread::sleep(SNAPSHOT_INTERVAL);
+    }
+}
+
+/// Write out the session table right away rather than waiting for the
+/// next snapshot. Used on shutdown.
+pub fn flush(path: &Path, shells: &shell::SessionTable) -> anyhow::Result<()> {
+    write(path, &snapshot(shells))
+}
+
+fn snapshot(shells: &shell::SessionTable) -> State {
+    let _s = span!(Level::INFO, "lock(shells)").entered();
+    let shells = shell::read_table(shells);
+
+    let mut sessions = shells
+        .values()
+        .map(|sess| {
+            let def = sess.current_definition();
+            let status = match sess.inner.try_lock() {
+                Ok(_) => SessionStatus::Disconnected,
+                Err(_) => SessionStatus::Attached,
+            };
+            PersistedSession {
+                name: def.name,
+                cmd: def.cmd,
+                cwd: def.cwd,
+                env: def.env.into_iter().collect(),
+                status: status.to_string(),
+                started_at_unix_ms: sess
+                    .started_at
+                    .duration_since(time::UNIX_EPOCH)
+                    .map(|d| d.as_millis() as i64)
+                    .unwrap_or(0),
+            }
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    State { sessions }
+}
+
+/// Copy the state file to a backup next to it, returning the backup's
+/// path. Used when some sessions failed to resurrect, so that they
+/// don't just vanish once the writer rewrites the file.
+pub fn keep_backup(path: &Path) -> anyhow::Result<PathBuf> {
+    let backup_path = path.with_extension("toml.bak");
+    let src = fs::read(path).context("reading state file")?;
+    write_private(&backup_path, &src)?;
+    Ok(backup_path)
+}
+
+/// Write the state file by way of a temp file so that a crash midway
+/// through can't leave a truncated file behind.
+fn write(path: &Path, state: &State) -> anyhow::Result<()> {
+    let src = toml::to_string(state).context("formatting state")?;
+    write_private(path, src.as_bytes())
+}
+
+/// Atomically replace `path` with a file only the user can read, since
+/// the state has the sessions' environments in it.
+fn write_private(path: &Path, src: &[u8]) -> anyhow::Result<()> {
+    let tmp_path = path.with_extension("toml.tmp");
+    // A leftover temp file from a crash may have looser permissions,
+    // which opening it would keep.
+    match fs::remove_file(&tmp_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("removing stale tmp state file"),
+    }
+    let mut tmp = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .context("creating tmp state file")?;
+    tmp.write_all(src).context("writing tmp state file")?;
+    drop(tmp);
+    fs::rename(&tmp_path, path).context("moving state file into place")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path());
+
+        assert!(read(&path)?.is_empty());
+
+        let state = State {
+            sessions: vec![PersistedSession {
+                name: String::from("main"),
+                cmd: Some(String::from("python3")),
+                cwd: Some(String::from("/tmp")),
+                env: BTreeMap::from([(String::from("TERM"), String::from("xterm"))]),
+                status: String::from("disconnected"),
+                started_at_unix_ms: 42,
+            }],
+        };
+        write(&path, &state)?;
+
+        let defs = read(&path)?;
+        assert_eq!(defs.len(), 1);
+        assert_eq!(defs[0].name, "main");
+        assert_eq!(defs[0].cmd.as_deref(), Some("python3"));
+        assert_eq!(defs[0].cwd.as_deref(), Some("/tmp"));
+        assert_eq!(defs[0].env, vec![(String::from("TERM"), String::from("xterm"))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_and_backed_up() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path());
+        // a stale temp file that anyone could read
+        let tmp_path = path.with_extension("toml.tmp");
+        fs::write(&tmp_path, "")?;
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o644))?;
+
+        let state = State {
+            sessions: vec![PersistedSession {
+                name: String::from("main"),
+                cmd: None,
+                cwd: None,
+                env: BTreeMap::new(),
+                status: String::from("disconnected"),
+                started_at_unix_ms: 42,
+            }],
+        };
+        write(&path, &state)?;
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        let backup = keep_backup(&path)?;
+        write(&path, &State::default())?;
+        assert_eq!(fs::metadata(&backup)?.permissions().mode() & 0o777, 0o600);
+        assert_eq!(read(&backup)?.len(), 1);
+        assert!(read(&path)?.is_empty());
+
+        Ok(())
+    }
+}
@@ -14,12 +14,17 @@
 
 use std::{
     env,
-    os::unix::{io::FromRawFd, net::UnixListener},
+    os::{
+        fd::AsFd,
+        unix::{io::FromRawFd, net::UnixListener},
+    },
 };
 
 use anyhow::{anyhow, Context};
 use nix::sys::stat;
 
+use super::spawn;
+
 // the fd that systemd uses for the first activation socket
 // (0 through 2 are for the std streams)
 const FIRST_ACTIVATION_SOCKET_FD: i32 = 3;
@@ -27,13 +32,8 @@ const FIRST_ACTIVATION_SOCKET_FD: i32 = 3;
 /// activation_socket converts the systemd activation socket
 /// to a usable UnixStream.
 pub fn activation_socket() -> anyhow::Result<UnixListener> {
-    let num_activation_socks = env::var("LISTEN_FDS")
-        .context("fetching LISTEN_FDS env var")?
-        .parse::<isize>()
-        .context("parsing LISTEN_FDS as int")?;
-    if num_activation_socks != 1 {
-        return Err(anyhow!("expected exactly 1 activation fd, got {}", num_activation_socks));
-    }
+    let listen_fds = env::var("LISTEN_FDS").context("fetching LISTEN_FDS env var")?;
+    check_activation_env(&listen_fds, env::var("LISTEN_PID").ok().as_deref(), std::process::id())?;
 
     let fd = FIRST_ACTIVATION_SOCKET_FD;
     let sock_stat = stat::fstat(fd).context("stating activation sock")?;
@@ -42,5 +42,63 @@ pub fn activation_socket() -> anyhow::Result<UnixListener> {
     }
 
     // Safety: we have just verified that this is a unix socket.
-    unsafe { Ok(UnixListener::from_raw_fd(fd)) }
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    // systemd hands the socket over without close-on-exec set, and we
+    // don't want the shells we spawn holding on to it.
+    spawn::set_cloexec(listener.as_fd()).context("making activation socket close-on-exec")?;
+    Ok(listener)
+}
+
+/// Check the env vars that systemd passes activation sockets with, as
+/// described in sd_listen_fds(3).
+fn check_activation_env(
+    listen_fds: &str,
+    listen_pid: Option<&str>,
+    pid: u32,
+) -> anyhow::Result<()> {
+    // The vars may have been inherited from a parent that was socket
+    // activated itself, in which case the fds are not meant for us. We
+    // are lenient about a missing LISTEN_PID, since some activators
+    // other than systemd leave it out.
+    if let Some(listen_pid) = listen_pid {
+        let listen_pid = listen_pid.parse::<u32>().context("parsing LISTEN_PID as int")?;
+        if listen_pid != pid {
+            return Err(anyhow!("activation fds are for pid {}, not us ({})", listen_pid, pid));
+        }
+    }
+
+    let num_activation_socks = listen_fds.parse::<isize>().context("parsing LISTEN_FDS as int")?;
+    if num_activation_socks != 1 {
+        return Err(anyhow!("expected exactly 1 activation fd, got {}", num_activation_socks));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn activation_env() {
+        assert!(check_activation_env("1", Some("42"), 42).is_ok());
+        assert!(check_activation_env("1", None, 42).is_ok());
+
+        let cases = [
+            ("1", Some("41"), "not us"),
+            ("1", Some("pid"), "parsing LISTEN_PID"),
+            ("0", Some("42"), "got 0"),
+            ("2", Some("42"), "got 2"),
+            ("", None, "parsing LISTEN_FDS"),
+        ];
+        for (listen_fds, listen_pid, err_substr) in cases.into_iter() {
+            let err = check_activation_env(listen_fds, listen_pid, 42).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains(err_substr),
+                "LISTEN_FDS={:?} LISTEN_PID={:?}: {:#}",
+                listen_fds,
+                listen_pid,
+                err
+            );
+        }
+    }
 }
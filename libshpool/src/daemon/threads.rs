@@ -0,0 +1,40 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Names and stack sizes for the threads the daemon spawns.
+//!
+//! Linux only keeps the first 15 bytes of a thread's name, and that is
+//! all that `ps -T`, `top -H` and debuggers get to show. Thread names
+//! are a short role followed by whatever the thread is working for,
+//! like `s2c:main` for the shell->client thread of the `main` session,
+//! so that the interesting part survives the cut. The daemon's own
+//! long lived threads just get a short name, like `config-reload`.
+
+use std::thread;
+
+use crate::config;
+
+/// The smallest `session_thread_stack_size` we accept. Anything
+/// smaller is all but certain to overflow.
+pub const MIN_STACK_SIZE: usize = 64 * 1024;
+
+/// A builder for a thread doing the given job for a session or
+/// connection, sized according to the config.
+pub fn for_session(role: &str, owner: &str, config: &config::Manager) -> thread::Builder {
+    let builder = thread::Builder::new().name(format!("{}:{}", role, owner));
+    match config.get().session_thread_stack_size {
+        Some(size) => builder.stack_size(size),
+        None => builder,
+    }
+}
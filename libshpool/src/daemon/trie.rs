@@ -137,7 +137,7 @@ where
 
 impl TrieTab<u8> for Vec<Option<usize>> {
     fn new() -> Self {
-        vec![None; u8::MAX as usize]
+        vec![None; u8::MAX as usize + 1]
     }
 
     fn get(&self, index: u8) -> Option<&usize> {
@@ -18,24 +18,40 @@
   names to avoid clobbering fresh session with the same
   session name as a previous session, and uses a min heap
   to schedule wakeups in order to reap threads on time.
+
+  Shortly before reaping a session, the reaper also warns
+  any attached client that the session is about to go away,
+  so the user has a chance to extend the ttl.
 */
 
 use std::{
     cmp,
     collections::{BinaryHeap, HashMap},
-    sync::{Arc, Mutex},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use tracing::{info, span, warn, Level};
 
 use super::shell;
+use crate::{
+    clock::Clock,
+    config, duration,
+    messages::{self, Message},
+};
+
+/// How long before a session gets reaped to warn the user, unless
+/// overridden by the ttl_warning config option.
+const DEFAULT_TTL_WARNING: Duration = Duration::from_secs(5 * 60);
 
 /// Run the reaper thread loop. Should be invoked in a dedicated
-/// thread.
+/// thread. Sending a session that is already scheduled to be reaped
+/// reschedules it, which is how ttls get extended.
 pub fn run(
     new_sess: crossbeam_channel::Receiver<(String, Instant)>,
-    shells: Arc<Mutex<HashMap<String, Box<shell::Session>>>>,
+    shells: Arc<shell::SessionTable>,
+    config: config::Manager,
+    clock: Arc<dyn Clock>,
 ) -> anyhow::Result<()> {
     let _s = span!(Level::INFO, "ttl_reaper").entered();
 
@@ -47,13 +63,8 @@ pub fn run(
         while heap.is_empty() {
             match new_sess.recv() {
                 Ok((session_name, reap_at)) => {
-                    let gen_id = gen_ids.entry(session_name.clone()).or_insert(0);
-                    *gen_id += 1;
-                    info!(
-                        "scheduling first sess {}:{} to be reaped at {:?}",
-                        &session_name, *gen_id, reap_at
-                    );
-                    heap.push(Reapable { session_name, gen_id: *gen_id, reap_at });
+                    info!("scheduling first sess {} to be reaped at {:?}", &session_name, reap_at);
+                    schedule(&mut heap, &mut gen_ids, &config, &*clock, session_name, reap_at);
                 }
                 Err(crossbeam_channel::RecvError) => {
                     info!("bailing due to RecvError in empty heap loop");
@@ -64,7 +75,7 @@ pub fn run(
 
         while !heap.is_empty() {
             let wake_at = if let Some(reapable) = heap.peek() {
-                reapable.reap_at
+                reapable.at
             } else {
                 warn!("no reapable even with heap len {}, should be impossible", heap.len());
                 continue;
@@ -74,15 +85,8 @@ pub fn run(
                 recv(new_sess) -> new_sess_msg => {
                     match new_sess_msg {
                         Ok((session_name, reap_at)) => {
-                            let gen_id = gen_ids.entry(session_name.clone()).or_insert(0);
-                            *gen_id += 1;
-                            info!("scheduling {}:{} to be reaped at {:?}",
-                                  &session_name, *gen_id, reap_at);
-                            heap.push(Reapable {
-                                session_name,
-                                gen_id: *gen_id,
-                                reap_at,
-                            });
+                            info!("scheduling {} to be reaped at {:?}", &session_name, reap_at);
+                            schedule(&mut heap, &mut gen_ids, &config, &*clock, session_name, reap_at);
                         }
                         Err(crossbeam_channel::RecvError) => {
                             info!("bailing due to RecvError");
@@ -90,7 +94,7 @@ pub fn run(
                         },
                     }
                 }
-                recv(crossbeam_channel::at(wake_at)) -> _ => {
+                recv(clock.at(wake_at)) -> _ => {
                     let reapable = heap.pop()
                         .expect("there to be an entry in a non-empty heap");
                     info!("waking up to reap {:?}", reapable);
@@ -102,37 +106,107 @@ pub fn run(
                         continue;
                     }
 
-                    let _s = span!(Level::INFO, "lock(shells)").entered();
-                    let mut shells = shells.lock().unwrap();
-                    if let Some(sess) = shells.get(&reapable.session_name) {
-                        if let Err(e) = sess.kill() {
-                            warn!("error trying to kill '{}': {:?}",
+                    let Some(sess) = shell::get_session(&shells, &reapable.session_name) else {
+                        warn!("tried to reap '{}' but it wasn't in the shells tab",
+                              reapable.session_name);
+                        continue;
+                    };
+                    if let ReapableKind::Warn { reap_at } = reapable.kind {
+                        let remaining = reap_at.saturating_duration_since(clock.now());
+                        let remaining = duration::format(remaining);
+                        let notice = messages::render(
+                            &config.get(),
+                            Message::TtlWarning,
+                            &reapable.session_name,
+                            &[(messages::TTL_REMAINING_VAR, remaining.as_str())],
+                        );
+                        if let Err(e) = sess.notify(&notice) {
+                            warn!("error warning '{}' about its ttl: {:?}",
                                   reapable.session_name, e);
                         }
-                    } else {
-                        warn!("tried to kill '{}' but it wasn't in the shells tab",
-                              reapable.session_name);
                         continue;
                     }
-                    shells.remove(&reapable.session_name);
+
+                    if let Err(e) = sess.kill() {
+                        warn!("error trying to kill '{}': {:?}",
+                              reapable.session_name, e);
+                    }
+                    let _s = span!(Level::INFO, "lock(shells)").entered();
+                    let mut shells = shell::write_table(&shells);
+                    // the session may have been replaced while we were killing it
+                    if shells.get(&reapable.session_name).map(|s| Arc::ptr_eq(s, &sess)).unwrap_or(false) {
+                        shells.remove(&reapable.session_name);
+                    }
                 }
             }
         }
     }
 }
 
+/// Push the wakeups for a session onto the heap, invalidating any
+/// wakeups that were previously scheduled for it.
+fn schedule(
+    heap: &mut BinaryHeap<Reapable>,
+    gen_ids: &mut HashMap<String, usize>,
+    config: &config::Manager,
+    clock: &dyn Clock,
+    session_name: String,
+    reap_at: Instant,
+) {
+    let gen_id = gen_ids.entry(session_name.clone()).or_insert(0);
+    *gen_id += 1;
+
+    let warning = ttl_warning(config);
+    if let Some(warn_at) = reap_at.checked_sub(warning) {
+        // Only warn if there is actually some lead time, otherwise
+        // we would be warning right as the session gets created.
+        if !warning.is_zero() && warn_at > clock.now() {
+            heap.push(Reapable {
+                session_name: session_name.clone(),
+                gen_id: *gen_id,
+                at: warn_at,
+                kind: ReapableKind::Warn { reap_at },
+            });
+        }
+    }
+    heap.push(Reapable { session_name, gen_id: *gen_id, at: reap_at, kind: ReapableKind::Reap });
+}
+
+fn ttl_warning(config: &config::Manager) -> Duration {
+    match &config.get().ttl_warning {
+        Some(src) => match duration::parse(src) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("could not parse ttl_warning '{}', using default: {:?}", src, e);
+                DEFAULT_TTL_WARNING
+            }
+        },
+        None => DEFAULT_TTL_WARNING,
+    }
+}
+
 /// A record in the min heap that we use to track the
 /// sessions that need to be cleaned up.
 #[derive(Debug)]
 struct Reapable {
     session_name: String,
     gen_id: usize,
-    reap_at: Instant,
+    /// When to wake up for this record.
+    at: Instant,
+    kind: ReapableKind,
+}
+
+#[derive(Debug)]
+enum ReapableKind {
+    /// Warn the attached client that the session will be reaped soon.
+    Warn { reap_at: Instant },
+    /// Actually kill the session.
+    Reap,
 }
 
 impl cmp::PartialEq for Reapable {
     fn eq(&self, rhs: &Reapable) -> bool {
-        self.reap_at == rhs.reap_at
+        self.at == rhs.at
     }
 }
 impl cmp::Eq for Reapable {}
@@ -146,6 +220,6 @@ impl cmp::PartialOrd for Reapable {
 impl cmp::Ord for Reapable {
     fn cmp(&self, other: &Reapable) -> cmp::Ordering {
         // flip the ordering to make a min heap
-        other.reap_at.cmp(&self.reap_at)
+        other.at.cmp(&self.at)
     }
 }
@@ -0,0 +1,125 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! utmp and wtmp records for attached sessions, so that `who`, `w` and
+//! `last` show shpool sessions like any other login. A session counts
+//! as logged in while a client is attached to it.
+//!
+//! Writing these files usually requires being root or in the utmp
+//! group, so this is opt in with the `utmp` config option.
+
+use std::{io, mem, time};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use crate::platform;
+
+/// A utmp login record for an attached session. The record gets
+/// replaced with a logout when this is dropped.
+pub struct Login {
+    line: String,
+    pid: libc::pid_t,
+}
+
+impl Login {
+    /// Record a login on the given pty, which should be the full path
+    /// of the pty slave that the shell with the given pid is using.
+    pub fn new(pty_path: &str, pid: libc::pid_t, user: &str, session_name: &str) -> Self {
+        let line = String::from(pty_path.strip_prefix("/dev/").unwrap_or(pty_path));
+        let login = Login { line, pid };
+        let entry = login.entry(libc::USER_PROCESS, user, &format!("shpool:{}", session_name));
+        match write(&entry) {
+            Ok(()) => info!("recorded utmp login on {}", login.line),
+            Err(e) => warn!("recording utmp login on {}: {:?}", login.line, e),
+        }
+        login
+    }
+
+    fn entry(&self, ut_type: libc::c_short, user: &str, host: &str) -> libc::utmpx {
+        // Safety: utmpx is a plain c struct, so all zeros is valid.
+        let mut entry: libc::utmpx = unsafe { mem::zeroed() };
+        entry.ut_type = ut_type;
+        entry.ut_pid = self.pid;
+        fill(&mut entry.ut_line, &self.line);
+        // like login(1), identify the entry by the end of the line
+        let id_start = self.line.len().saturating_sub(entry.ut_id.len());
+        fill(&mut entry.ut_id, &self.line[id_start..]);
+        fill(&mut entry.ut_user, user);
+        fill(&mut entry.ut_host, host);
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        entry.ut_tv.tv_sec = now.as_secs() as _;
+        entry.ut_tv.tv_usec = now.subsec_micros() as _;
+        entry
+    }
+}
+
+impl Drop for Login {
+    fn drop(&mut self) {
+        let entry = self.entry(libc::DEAD_PROCESS, "", "");
+        match write(&entry) {
+            Ok(()) => info!("recorded utmp logout on {}", self.line),
+            Err(e) => warn!("recording utmp logout on {}: {:?}", self.line, e),
+        }
+    }
+}
+
+/// Copy a string into a fixed size utmp field, truncating if need be.
+/// These fields don't need to be nul terminated.
+fn fill(field: &mut [libc::c_char], s: &str) {
+    for (dst, src) in field.iter_mut().zip(s.bytes()) {
+        *dst = src as libc::c_char;
+    }
+}
+
+fn write(entry: &libc::utmpx) -> anyhow::Result<()> {
+    // Safety: entry is a valid utmpx, and the utmp functions copy
+    //         what they need out of it.
+    unsafe {
+        libc::setutxent();
+        let res = libc::pututxline(entry);
+        let err = io::Error::last_os_error();
+        libc::endutxent();
+        if res.is_null() {
+            return Err(anyhow!("writing utmp: {}", err));
+        }
+    }
+    platform::append_wtmp(entry).context("writing wtmp")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn field_str(field: &[libc::c_char]) -> String {
+        field.iter().take_while(|c| **c != 0).map(|c| *c as u8 as char).collect()
+    }
+
+    #[test]
+    fn entry() {
+        let login = Login { line: String::from("pts/12"), pid: 42 };
+        let entry = login.entry(libc::USER_PROCESS, "alice", &"x".repeat(300));
+        assert_eq!(entry.ut_type, libc::USER_PROCESS);
+        assert_eq!(entry.ut_pid, 42);
+        assert_eq!(field_str(&entry.ut_line), "pts/12");
+        let id_start = "pts/12".len().saturating_sub(entry.ut_id.len());
+        assert_eq!(field_str(&entry.ut_id), &"pts/12"[id_start..]);
+        assert_eq!(field_str(&entry.ut_user), "alice");
+        assert_eq!(entry.ut_host.len(), field_str(&entry.ut_host).len());
+
+        // avoid writing the test login record on drop
+        mem::forget(login);
+    }
+}
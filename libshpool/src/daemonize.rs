@@ -12,28 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{ffi::OsStr, os::unix::net::UnixStream, path::Path, process, thread, time::Duration};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process, thread,
+    time::Duration,
+};
 
-use crate::{config, consts, Args};
+use crate::{config, consts, control_sock, Args};
 
 use anyhow::{anyhow, Context};
+use nix::sys::stat::{umask, Mode};
 use tracing::info;
 
 /// Check if we can connect to the control socket, and if we
 /// can't, fork the daemon in the background.
-pub fn maybe_fork_daemon<B, P>(
+pub fn maybe_fork_daemon<B, P, R>(
     config_manager: &config::Manager,
     args: &Args,
     shpool_bin: B,
     control_sock: P,
+    runtime_dir: R,
 ) -> anyhow::Result<()>
 where
     B: AsRef<OsStr>,
     P: AsRef<Path>,
+    R: AsRef<Path>,
 {
     let control_sock = control_sock.as_ref();
 
-    if UnixStream::connect(control_sock).is_ok() {
+    if control_sock::connect(control_sock).is_ok() {
         info!("daemon already running on {:?}, no need to autodaemonize", control_sock);
         // There is already a daemon listening on the control socket, we
         // don't need to do anything.
@@ -41,7 +50,15 @@ where
     }
     info!("no daemon running on {:?}, autodaemonizing", control_sock);
 
-    let log_file = control_sock.with_file_name("daemonized-shpool.log");
+    // Abstract sockets have no directory to put the log next to, so
+    // we fall back to the runtime dir.
+    let log_file = if control_sock::is_abstract(control_sock) {
+        let runtime_dir = runtime_dir.as_ref();
+        fs::create_dir_all(runtime_dir).context("ensuring runtime dir exists")?;
+        runtime_dir.join("daemonized-shpool.log")
+    } else {
+        control_sock.with_file_name("daemonized-shpool.log")
+    };
 
     let mut cmd = process::Command::new(shpool_bin);
     if let Some(config_file) = &args.config_file {
@@ -65,7 +82,7 @@ where
         let mut sleep_ms = 10;
         let max_sleep_ms = 2000;
         loop {
-            if UnixStream::connect(control_sock).is_ok() {
+            if control_sock::connect(control_sock).is_ok() {
                 info!("connected to freshly launched background daemon");
                 return Ok(());
             }
@@ -81,7 +98,7 @@ where
         // `sum(10*(2**x) for x in range(9))` = 5110 ms = ~5 s
         let mut sleep_ms = 10;
         for _ in 0..9 {
-            if UnixStream::connect(control_sock).is_ok() {
+            if control_sock::connect(control_sock).is_ok() {
                 info!("connected to freshly launched background daemon");
                 return Ok(());
             }
@@ -93,3 +110,51 @@ where
 
     Err(anyhow!("daemonizing: launched daemon, but control socket never came up"))
 }
+
+/// Turn the current process into a background daemon for `shpool
+/// daemon --daemonize`: fork twice with a setsid in between so that we
+/// can never pick up a controlling terminal again, write our pid to
+/// the pid file, and point stdout and stderr at the log file. Only the
+/// daemon returns, the original process exits once the daemon is on
+/// its own.
+///
+/// This must be called before any threads get spawned.
+pub fn into_background(
+    runtime_dir: &Path,
+    pid_file: Option<&str>,
+    log_file: Option<fs::File>,
+) -> anyhow::Result<()> {
+    let pid_file =
+        pid_file.map(PathBuf::from).unwrap_or_else(|| runtime_dir.join("daemonized-shpool.pid"));
+    // Sharing the log file handle keeps anything written to stderr,
+    // like a panic, in order with the logs rather than on top of them.
+    let log_file = match log_file {
+        Some(f) => f,
+        None => fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(runtime_dir.join("daemonized-shpool.log"))
+            .context("opening daemon log file")?,
+    };
+    let stderr = log_file.try_clone().context("cloning log file handle")?;
+
+    // Shells inherit the daemon's umask, so keep the user's rather
+    // than taking the daemonize crate's default.
+    let mask = umask(Mode::empty());
+    umask(mask);
+
+    info!("daemonizing with pid_file={:?}", pid_file);
+    // Unlike most daemons, we stay put rather than moving to /, so that
+    // relative paths given on the command line, like --config-file,
+    // keep working.
+    ::daemonize::Daemonize::new()
+        .pid_file(&pid_file)
+        .working_directory(std::env::current_dir().context("getting cwd")?)
+        .umask(mask.bits())
+        .stdout(log_file)
+        .stderr(stderr)
+        .start()
+        .with_context(|| format!("daemonizing with pid file {:?}", pid_file))?;
+    info!("daemonized");
+    Ok(())
+}
@@ -12,48 +12,44 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::Path};
-
 use anyhow::{anyhow, Context};
 use shpool_protocol::{ConnectHeader, DetachReply, DetachRequest};
 
-use crate::{common, protocol, protocol::ClientResult};
-
-pub fn run<P>(mut sessions: Vec<String>, socket: P) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    let mut client = match protocol::Client::new(socket) {
-        Ok(ClientResult::JustClient(c)) => c,
-        Ok(ClientResult::VersionMismatch { warning, client }) => {
-            eprintln!("warning: {}, try restarting your daemon", warning);
-            client
-        }
-        Err(err) => {
-            let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
-                eprintln!("could not connect to daemon");
-            }
-            return Err(io_err).context("connecting to daemon");
-        }
-    };
+use crate::{common, context::ClientContext, output, Error};
+
+pub fn run(
+    mut sessions: Vec<String>,
+    panes: bool,
+    dry_run: bool,
+    ctx: &ClientContext,
+) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
 
     common::resolve_sessions(&mut sessions, "detach")?;
 
     client
-        .write_connect_header(ConnectHeader::Detach(DetachRequest { sessions }))
+        .write_connect_header(ConnectHeader::Detach(DetachRequest { sessions, panes, dry_run }))
         .context("writing detach request header")?;
 
     let reply: DetachReply = client.read_reply().context("reading reply")?;
 
+    if dry_run {
+        for session in reply.matched_sessions.iter() {
+            output::listing(session);
+        }
+    }
+
     if !reply.not_found_sessions.is_empty() {
-        eprintln!("not found: {}", reply.not_found_sessions.join(" "));
-        return Err(anyhow!("not found: {}", reply.not_found_sessions.join(" ")));
+        output::error(format!("not found: {}", reply.not_found_sessions.join(" ")));
+        output::result("detach", "not-found", &reply.not_found_sessions);
+        return Err(Error::SessionsNotFound(reply.not_found_sessions).into());
     }
     if !reply.not_attached_sessions.is_empty() {
-        eprintln!("not attached: {}", reply.not_attached_sessions.join(" "));
+        output::error(format!("not attached: {}", reply.not_attached_sessions.join(" ")));
+        output::result("detach", "not-attached", &reply.not_attached_sessions);
         return Err(anyhow!("not attached: {}", reply.not_attached_sessions.join(" ")));
     }
 
+    output::result("detach", if dry_run { "dry-run" } else { "ok" }, &reply.matched_sessions);
     Ok(())
 }
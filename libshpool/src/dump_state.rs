@@ -0,0 +1,37 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+use shpool_protocol::{ConnectHeader, DumpStateReply};
+
+use crate::context::ClientContext;
+
+pub fn run(output: Option<PathBuf>, ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::DumpState)
+        .context("sending dump state connect header")?;
+    let reply: DumpStateReply = client.read_reply().context("reading reply")?;
+
+    match output {
+        Some(path) => fs::write(&path, reply.state_json + "\n")
+            .with_context(|| format!("writing state to {:?}", path))?,
+        None => println!("{}", reply.state_json),
+    }
+
+    Ok(())
+}
@@ -13,12 +13,17 @@
 // limitations under the License.
 
 /*! A parser for the duration format supported by the
-  attach --ttl flag.
+  attach --ttl flag and all of the duration config options.
+
+  Durations can be given in colon form, as in 1:30:00, or with
+  unit suffixes, as in 90m, 1.5h or 1d2h30m. The units are ms,
+  s, m, h, d and w.
 */
 
 use anyhow::{anyhow, bail, Context};
 use std::time;
 
+/// Parse a duration in either the colon or the suffix form.
 pub fn parse(src: &str) -> anyhow::Result<time::Duration> {
     if src.contains(':') {
         parse_colon_duration(src)
@@ -37,22 +42,18 @@ fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
         bail!("'{}' must have at least one part", src);
     }
     let mut secs = parts[0].parse::<u64>().context("parsing seconds part")?;
-    dbg!(secs);
     if parts.len() == 1 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[1].parse::<u64>().context("parsing minutes part")? * 60;
-    dbg!(secs);
     if parts.len() == 2 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[2].parse::<u64>().context("parsing hours part")? * 60 * 60;
-    dbg!(secs);
     if parts.len() == 3 {
         return Ok(time::Duration::from_secs(secs));
     }
     secs += parts[3].parse::<u64>().context("parsing days part")? * 60 * 60 * 24;
-    dbg!(secs);
     if parts.len() != 4 {
         bail!("colon duration cannot have more than 4 parts");
     }
@@ -60,24 +61,63 @@ fn parse_colon_duration(src: &str) -> anyhow::Result<time::Duration> {
     Ok(time::Duration::from_secs(secs))
 }
 
-/// Parses 20d, 3h, 14m ect
+/// Parses 20d, 3h, 1.5h, 14m ect, as well as any number of them
+/// run together, like 1d2h30m, which get added up.
 fn parse_suffix_duration(src: &str) -> anyhow::Result<time::Duration> {
-    let num: String = src.chars().take_while(|c| c.is_numeric()).collect();
-    let c = src.chars().last().ok_or(anyhow!("internal error: no suffix"))?;
-    make_suffix_duration(num.parse::<u64>().context("parsing num part of duration")?, c)
-        .ok_or(anyhow!("unknown time unit '{}'", c))
+    let mut total = time::Duration::ZERO;
+    let mut rest = src;
+    while !rest.is_empty() {
+        let num_len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(rest.len());
+        let (num, after) = rest.split_at(num_len);
+        let unit_len = after.find(|c: char| !c.is_alphabetic()).unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        if num.is_empty() {
+            bail!("expected a number at '{}' in '{}'", rest, src);
+        }
+        if unit.is_empty() {
+            bail!("'{}' in '{}' is missing a time unit", num, src);
+        }
+
+        let n = num.parse::<f64>().with_context(|| format!("parsing '{}' in '{}'", num, src))?;
+        let unit_secs = unit_secs(unit).ok_or(anyhow!("unknown time unit '{}'", unit))?;
+        let part = time::Duration::try_from_secs_f64(n * unit_secs)
+            .with_context(|| format!("'{}{}' is out of range", num, unit))?;
+        total = total.checked_add(part).ok_or(anyhow!("'{}' is out of range", src))?;
+        rest = after;
+    }
+    Ok(total)
 }
 
-fn make_suffix_duration(n: u64, c: char) -> Option<time::Duration> {
-    match c {
-        's' => Some(time::Duration::from_secs(n)),
-        'm' => Some(time::Duration::from_secs(n * 60)),
-        'h' => Some(time::Duration::from_secs(n * 60 * 60)),
-        'd' => Some(time::Duration::from_secs(n * 60 * 60 * 24)),
+fn unit_secs(unit: &str) -> Option<f64> {
+    match unit {
+        "ms" => Some(0.001),
+        "s" => Some(1.0),
+        "m" => Some(60.0),
+        "h" => Some(60.0 * 60.0),
+        "d" => Some(60.0 * 60.0 * 24.0),
+        "w" => Some(60.0 * 60.0 * 24.0 * 7.0),
         _ => None,
     }
 }
 
+/// Format a duration in a compact human readable form, i.e. '1d3h'
+/// or '45s'. Sub-second precision is dropped.
+pub fn format(d: time::Duration) -> String {
+    let mut secs = d.as_secs();
+    if secs == 0 {
+        return String::from("0s");
+    }
+
+    let mut out = String::new();
+    for (unit_secs, suffix) in [(60 * 60 * 24, 'd'), (60 * 60, 'h'), (60, 'm'), (1, 's')] {
+        if secs >= unit_secs {
+            out.push_str(&format!("{}{}", secs / unit_secs, suffix));
+            secs %= unit_secs;
+        }
+    }
+    out
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -92,6 +132,13 @@ mod test {
             ("5m", time::Duration::from_secs(5 * 60)),
             ("5h", time::Duration::from_secs(5 * 60 * 60)),
             ("5d", time::Duration::from_secs(5 * 60 * 60 * 24)),
+            ("2w", time::Duration::from_secs(2 * 7 * 60 * 60 * 24)),
+            ("250ms", time::Duration::from_millis(250)),
+            ("1.5h", time::Duration::from_secs(90 * 60)),
+            ("0.5s", time::Duration::from_millis(500)),
+            ("1d2h30m", time::Duration::from_secs(60 * 60 * 24 + 2 * 60 * 60 + 30 * 60)),
+            ("1w1d", time::Duration::from_secs(8 * 60 * 60 * 24)),
+            ("1m30s500ms", time::Duration::from_millis(90_500)),
         ];
 
         for (src, dur) in cases.into_iter() {
@@ -106,11 +153,31 @@ mod test {
         }
     }
 
+    #[test]
+    fn formatting() {
+        let cases = vec![
+            (time::Duration::from_secs(0), "0s"),
+            (time::Duration::from_millis(1500), "1s"),
+            (time::Duration::from_secs(5 * 60), "5m"),
+            (time::Duration::from_secs(60 * 60 + 30), "1h30s"),
+            (time::Duration::from_secs(2 * 60 * 60 * 24 + 3 * 60 * 60 + 4 * 60 + 5), "2d3h4m5s"),
+        ];
+
+        for (dur, want) in cases.into_iter() {
+            assert_eq!(format(dur), want);
+        }
+    }
+
     #[test]
     fn errors() {
         let cases = vec![
             ("12", "could not parse"),
             ("12x", "unknown time unit"),
+            ("1h30", "could not parse"),
+            ("h", "expected a number"),
+            ("1.2.3s", "parsing '1.2.3'"),
+            ("1h 30m", "expected a number at ' 30m'"),
+            ("1.5 h", "missing a time unit"),
             (":1", "parsing minutes part"),
             ("1:1:1:1:1", "cannot have more than 4"),
         ];
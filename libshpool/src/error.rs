@@ -0,0 +1,204 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The errors that `run` hands back to embedders.
+//!
+//! Internally, everything is still plumbed around as an `anyhow::Error`.
+//! The failures that callers might want to react to get created as one
+//! of the variants here and wrapped up in the anyhow error, then pulled
+//! back out again at the edge of the library.
+
+use std::{fmt, io};
+
+/// An error from running a shpool command.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The daemon's socket could not be connected to, usually because
+    /// the daemon is not running.
+    DaemonUnreachable(io::Error),
+    /// The daemon refused the client because it speaks an incompatible
+    /// version of the protocol.
+    VersionSkew(String),
+    /// The session already has a terminal attached.
+    SessionBusy(String),
+    /// Another client is still in the middle of creating the session.
+    SessionBeingCreated(String),
+    /// The daemon refused the request, with the reason it gave.
+    Forbidden(String),
+    /// The daemon did not accept the connection or answer the request
+    /// within the client timeout, saying what was being waited on.
+    Timeout(String),
+    /// The session name was rejected, with the reason why.
+    InvalidSessionName(String),
+    /// None of the sessions matched these names or patterns.
+    SessionsNotFound(Vec<String>),
+    /// The config file could not be loaded.
+    Config(anyhow::Error),
+    /// Some other IO error.
+    Io(io::Error),
+    /// Anything else.
+    Other(anyhow::Error),
+}
+
+/// The exit statuses that the shpool binary uses for failures, along
+/// with what each one means. Scripts branch on these, so a status must
+/// never be reused for something else. The --help text is generated
+/// from this table, so it stays in sync with `Error::exit_status`.
+const EXIT_STATUSES: &[(u8, &str)] = &[
+    (EXIT_FAILURE, "any other failure"),
+    (EXIT_NOT_FOUND, "a session was not found"),
+    (EXIT_BUSY, "the session already has a terminal attached or is still being created"),
+    (EXIT_DAEMON_UNREACHABLE, "the daemon could not be reached"),
+    (EXIT_VERSION_SKEW, "the daemon refused an incompatible client version"),
+    (EXIT_FORBIDDEN, "the daemon refused the request"),
+    (EXIT_TIMEOUT, "the daemon did not respond within the timeout"),
+];
+
+const EXIT_FAILURE: u8 = 1;
+const EXIT_NOT_FOUND: u8 = 3;
+const EXIT_BUSY: u8 = 4;
+const EXIT_DAEMON_UNREACHABLE: u8 = 5;
+const EXIT_VERSION_SKEW: u8 = 6;
+const EXIT_FORBIDDEN: u8 = 7;
+const EXIT_TIMEOUT: u8 = 8;
+
+impl Error {
+    /// The status the shpool binary exits with for this error.
+    pub fn exit_status(&self) -> u8 {
+        match self {
+            Error::SessionsNotFound(_) => EXIT_NOT_FOUND,
+            Error::SessionBusy(_) | Error::SessionBeingCreated(_) => EXIT_BUSY,
+            Error::DaemonUnreachable(_) => EXIT_DAEMON_UNREACHABLE,
+            Error::VersionSkew(_) => EXIT_VERSION_SKEW,
+            Error::Forbidden(_) => EXIT_FORBIDDEN,
+            Error::Timeout(_) => EXIT_TIMEOUT,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/// The exit status section of the --help text.
+pub(crate) fn exit_status_help() -> String {
+    let mut help = String::from(
+        "Exit Status:
+  0  success, or for attach, the exit status of the session's shell
+",
+    );
+    for (status, meaning) in EXIT_STATUSES.iter() {
+        help.push_str(&format!("  {}  {}\n", status, meaning));
+    }
+    help
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::DaemonUnreachable(e) => write!(f, "connecting to daemon: {}", e),
+            Error::VersionSkew(reason) => write!(f, "version skew: {}", reason),
+            Error::SessionBusy(name) => {
+                write!(f, "session '{}' already has a terminal attached", name)
+            }
+            Error::SessionBeingCreated(name) => {
+                write!(f, "session '{}' is still being created by another client", name)
+            }
+            Error::Forbidden(reason) => write!(f, "forbidden: {}", reason),
+            Error::Timeout(what) => write!(f, "timed out {}", what),
+            Error::InvalidSessionName(reason) => write!(f, "invalid session name: {}", reason),
+            Error::SessionsNotFound(names) => write!(f, "not found: {}", names.join(" ")),
+            Error::Config(e) => write!(f, "loading config: {:#}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DaemonUnreachable(e) | Error::Io(e) => Some(e),
+            Error::Config(e) | Error::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        // anyhow can see through any context that got added on the way
+        // up to the error it started out as.
+        let err = match err.downcast::<Error>() {
+            Ok(e) => return e,
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(e) => Error::Io(e),
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn through_context() {
+        let err = Err::<(), _>(anyhow::Error::from(Error::SessionBusy(String::from("main"))))
+            .context("attaching")
+            .unwrap_err();
+        assert!(matches!(Error::from(err), Error::SessionBusy(name) if name == "main"));
+
+        let err = Err::<(), _>(io::Error::from(io::ErrorKind::PermissionDenied))
+            .context("opening log")
+            .unwrap_err();
+        assert!(
+            matches!(Error::from(err), Error::Io(e) if e.kind() == io::ErrorKind::PermissionDenied)
+        );
+
+        assert!(matches!(Error::from(anyhow::anyhow!("boom")), Error::Other(_)));
+    }
+
+    #[test]
+    fn exit_statuses() {
+        assert_eq!(Error::SessionsNotFound(vec![]).exit_status(), 3);
+        assert_eq!(Error::SessionBusy(String::new()).exit_status(), 4);
+        assert_eq!(Error::SessionBeingCreated(String::new()).exit_status(), 4);
+        assert_eq!(
+            Error::DaemonUnreachable(io::Error::from(io::ErrorKind::NotFound)).exit_status(),
+            5
+        );
+        assert_eq!(Error::VersionSkew(String::new()).exit_status(), 6);
+        assert_eq!(Error::Forbidden(String::new()).exit_status(), 7);
+        assert_eq!(Error::Timeout(String::new()).exit_status(), 8);
+        assert_eq!(Error::Other(anyhow::anyhow!("boom")).exit_status(), 1);
+
+        // every status is documented exactly once
+        for (status, _) in EXIT_STATUSES.iter() {
+            assert_eq!(EXIT_STATUSES.iter().filter(|(s, _)| s == status).count(), 1);
+        }
+        let help = exit_status_help();
+        assert!(help.contains(
+            "  4  the session already has a terminal attached or is still being created\n"
+        ));
+    }
+}
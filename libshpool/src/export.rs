@@ -0,0 +1,108 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use shpool_protocol::{ConnectHeader, ExportReply, SessionDefinition};
+
+use crate::context::ClientContext;
+
+/// The toml file format written by `shpool export` and read by
+/// `shpool import`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SessionsFile {
+    #[serde(default)]
+    pub sessions: Vec<SessionEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SessionEntry {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    // A BTreeMap so that the output is stable
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl From<SessionDefinition> for SessionEntry {
+    fn from(def: SessionDefinition) -> Self {
+        SessionEntry {
+            name: def.name,
+            cmd: def.cmd,
+            cwd: def.cwd,
+            env: def.env.into_iter().collect(),
+        }
+    }
+}
+
+impl From<SessionEntry> for SessionDefinition {
+    fn from(entry: SessionEntry) -> Self {
+        SessionDefinition {
+            name: entry.name,
+            cmd: entry.cmd,
+            cwd: entry.cwd,
+            env: entry.env.into_iter().collect(),
+        }
+    }
+}
+
+pub fn run(ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client.write_connect_header(ConnectHeader::Export).context("sending export connect header")?;
+    let reply: ExportReply = client.read_reply().context("reading reply")?;
+
+    let mut file =
+        SessionsFile { sessions: reply.sessions.into_iter().map(SessionEntry::from).collect() };
+    file.sessions.sort_by(|a, b| a.name.cmp(&b.name));
+    print!("{}", toml::to_string(&file).context("formatting sessions")?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let file = SessionsFile {
+            sessions: vec![
+                SessionEntry {
+                    name: String::from("main"),
+                    cmd: None,
+                    cwd: Some(String::from("/home/me/src")),
+                    env: BTreeMap::from([(String::from("TERM"), String::from("xterm"))]),
+                },
+                SessionEntry {
+                    name: String::from("repl"),
+                    cmd: Some(String::from("python3 -q")),
+                    cwd: None,
+                    env: BTreeMap::new(),
+                },
+            ],
+        };
+
+        let src = toml::to_string(&file)?;
+        let parsed: SessionsFile = toml::from_str(&src)?;
+        assert_eq!(parsed.sessions, file.sessions);
+
+        Ok(())
+    }
+}
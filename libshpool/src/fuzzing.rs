@@ -0,0 +1,100 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Entry points for the fuzz targets in the top level `fuzz` directory.
+//! These feed arbitrary bytes into the parsers that the daemon runs on
+//! input it gets from clients and shells. Errors are fine, but none of
+//! them should ever panic or hang.
+
+use std::io;
+
+use shpool_protocol::{Chunk, ConnectHeader};
+
+use crate::{
+    consts,
+    daemon::{
+        keybindings::{Action, Bindings},
+        prompt::SentinelScanner,
+    },
+    protocol,
+    protocol::ChunkExt as _,
+};
+
+/// Decode a stream of chunks, the framing used for the attach stream.
+pub fn chunks(data: &[u8]) {
+    let mut r = io::Cursor::new(data);
+    let mut buf = vec![0; consts::BUF_SIZE];
+    while Chunk::read_into(&mut r, &mut buf).is_ok() {}
+}
+
+/// Decode a connect header, the first thing a client sends the daemon.
+/// Anything that decodes must also encode again.
+pub fn connect_header(data: &[u8]) {
+    if let Ok(header) = protocol::decode_from::<ConnectHeader, _>(data) {
+        let mut out = vec![];
+        protocol::encode_to(&header, &mut out).expect("re-encoding decoded header");
+    }
+}
+
+/// Scan shell output for the prompt sentinel.
+pub fn sentinel_scanner(data: &[u8]) {
+    let mut scanner = SentinelScanner::new(consts::PROMPT_SENTINEL);
+    for byte in data {
+        scanner.transition(*byte);
+    }
+}
+
+/// Parse the first line as a keybinding spec, then use the resulting
+/// bindings (or the default ones if it was invalid) to scan the rest
+/// of the input as keystrokes.
+pub fn keybindings(data: &[u8]) {
+    let (spec, keys) = match data.iter().position(|b| *b == b'\n') {
+        Some(i) => (String::from_utf8_lossy(&data[..i]), &data[i + 1..]),
+        None => (String::from_utf8_lossy(data), &[][..]),
+    };
+
+    let mut bindings = match Bindings::new([(&*spec, Action::Detach)]) {
+        Ok(b) => b,
+        Err(_) => Bindings::new([("Ctrl-Space Ctrl-q", Action::Detach)])
+            .expect("default bindings to parse"),
+    };
+    for byte in keys {
+        bindings.transition(*byte);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    // Make sure the seed corpus at least runs cleanly so that the fuzz
+    // targets don't rot between fuzzing runs.
+    #[test]
+    fn seed_corpus() -> anyhow::Result<()> {
+        let corpus = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../fuzz/corpus");
+        let run_seeds = |target: &str, run: fn(&[u8])| -> anyhow::Result<()> {
+            for entry in fs::read_dir(corpus.join(target))? {
+                run(&fs::read(entry?.path())?);
+            }
+            Ok(())
+        };
+        run_seeds("chunks", chunks)?;
+        run_seeds("connect_header", connect_header)?;
+        run_seeds("sentinel_scanner", sentinel_scanner)?;
+        run_seeds("keybindings", keybindings)?;
+        Ok(())
+    }
+}
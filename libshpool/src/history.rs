@@ -0,0 +1,46 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+/// The HISTFILE the daemon gives a session when per_session_history
+/// is on. This lives in the runtime dir rather than going through the
+/// daemon so that the history is still around after the session exits.
+pub fn histfile(runtime_dir: &Path, session: &str) -> PathBuf {
+    runtime_dir.join("sessions").join(session).join("history")
+}
+
+pub fn run(session: String, runtime_dir: PathBuf) -> anyhow::Result<()> {
+    let path = histfile(&runtime_dir, &session);
+    let history = match fs::read(&path) {
+        Ok(h) => h,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "no history for session '{}', per_session_history might be off or the shell may not have written its history yet",
+                session
+            );
+            return Err(anyhow!("no history file at {:?}", path));
+        }
+        Err(e) => return Err(e).with_context(|| format!("reading {:?}", path)),
+    };
+
+    io::stdout().lock().write_all(&history).context("writing history")?;
+    Ok(())
+}
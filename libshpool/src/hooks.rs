@@ -60,4 +60,56 @@ pub trait Hooks {
     fn on_shell_disconnect(&self, _session_name: &str) -> anyhow::Result<()> {
         Ok(())
     }
+
+    /// Consulted before the daemon runs a custom command that a client
+    /// asked for with `shpool attach --cmd` or `shpool import`. Unlike
+    /// the other hooks, the return value is acted upon, allowing the
+    /// wrapping binary to override the `cmd_policy` from the config.
+    fn check_cmd(&self, _session_name: &str, _cmd: &str) -> CmdDecision {
+        CmdDecision::UseConfig
+    }
+
+    /// Consulted once when a session is created to get a transform
+    /// to run over everything the shell outputs for the life of the
+    /// session. The output is transformed before it is recorded for
+    /// session restore, so the transform sees each byte exactly once
+    /// no matter how many times the session gets reattached.
+    fn output_transform(&self, _session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        None
+    }
+
+    /// Consulted once when a session is created to get a transform
+    /// to run over the input that clients send to the shell, after
+    /// keybindings have been picked out of it.
+    fn input_transform(&self, _session_name: &str) -> Option<Box<dyn StreamTransform + Send>> {
+        None
+    }
+}
+
+/// A stateful rewrite of one of a session's byte streams, for things
+/// like redacting secrets or handling custom OSC sequences.
+///
+/// Chunks arrive in whatever sizes the pty or socket hand them over,
+/// so an escape sequence or a word can be split across calls. A
+/// transform may hold on to the tail of a chunk and return it along
+/// with a later one, but it should not hold bytes for long, since
+/// nothing more may come for a while.
+///
+/// Transforms are called inline in the threads that pump data between
+/// the shell and the client, so they MUST NOT block.
+pub trait StreamTransform {
+    /// Transform a chunk of the stream, returning the bytes to pass on
+    /// in its place.
+    fn transform(&mut self, chunk: &[u8]) -> Vec<u8>;
+}
+
+/// What the `check_cmd` hook wants done with a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CmdDecision {
+    /// Defer to the `cmd_policy` in the config.
+    UseConfig,
+    /// Run the command, regardless of the config.
+    Allow,
+    /// Refuse to run the command, with the given reason.
+    Deny(String),
 }
@@ -0,0 +1,51 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, ImportReply, ImportRequest};
+
+use crate::{context::ClientContext, export::SessionsFile};
+
+pub fn run(file: PathBuf, ctx: &ClientContext) -> anyhow::Result<()> {
+    let src = fs::read_to_string(&file).context(format!("reading {:?}", file))?;
+    let sessions_file: SessionsFile =
+        toml::from_str(&src).context(format!("parsing {:?}", file))?;
+
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::Import(ImportRequest {
+            sessions: sessions_file.sessions.into_iter().map(From::from).collect(),
+        }))
+        .context("writing import request header")?;
+
+    let reply: ImportReply = client.read_reply().context("reading reply")?;
+
+    if !reply.already_exists.is_empty() {
+        eprintln!("skipped existing sessions: {}", reply.already_exists.join(" "));
+    }
+    if !reply.forbidden.is_empty() {
+        eprintln!("skipped sessions with forbidden commands: {}", reply.forbidden.join(" "));
+    }
+    for failure in reply.failed.iter() {
+        eprintln!("failed to import {}: {}", failure.name, failure.error);
+    }
+    if !reply.failed.is_empty() {
+        return Err(anyhow!("failed to import {} sessions", reply.failed.len()));
+    }
+
+    Ok(())
+}
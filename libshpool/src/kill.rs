@@ -12,44 +12,156 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::Path};
+use std::{
+    env,
+    io::{self, BufRead, Write},
+    os::fd::AsRawFd,
+};
 
 use anyhow::{anyhow, Context};
-use shpool_protocol::{ConnectHeader, KillReply, KillRequest};
-
-use crate::{common, protocol, protocol::ClientResult};
-
-pub fn run<P>(mut sessions: Vec<String>, socket: P) -> anyhow::Result<()>
-where
-    P: AsRef<Path>,
-{
-    let mut client = match protocol::Client::new(socket) {
-        Ok(ClientResult::JustClient(c)) => c,
-        Ok(ClientResult::VersionMismatch { warning, client }) => {
-            eprintln!("warning: {}, try restarting your daemon", warning);
-            client
-        }
-        Err(err) => {
-            let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
-                eprintln!("could not connect to daemon");
-            }
-            return Err(io_err).context("connecting to daemon");
-        }
-    };
+use nix::unistd::isatty;
+use shpool_protocol::{ConnectHeader, KillReply, KillRequest, ListReply, Session, SessionStatus};
+
+use crate::{common, context::ClientContext, output, Error};
+
+pub fn run(
+    mut sessions: Vec<String>,
+    panes: bool,
+    dry_run: bool,
+    yes: bool,
+    ctx: &ClientContext,
+) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
 
     common::resolve_sessions(&mut sessions, "kill")?;
 
+    // Scripts have nobody to ask, so they keep killing whatever they
+    // are told to.
+    if !dry_run && !yes && output::chatty() && isatty(io::stdin().as_raw_fd()).unwrap_or(false) {
+        // don't leave the daemon waiting on us while the user thinks
+        drop(client);
+        let attached = attached_sessions(ctx, &sessions, panes)?;
+        if !attached.is_empty() && !confirm(&attached)? {
+            output::error("not killing anything");
+            return Err(anyhow!("kill cancelled"));
+        }
+        client = ctx.connect()?;
+    }
+
     client
-        .write_connect_header(ConnectHeader::Kill(KillRequest { sessions }))
+        .write_connect_header(ConnectHeader::Kill(KillRequest { sessions, panes, dry_run }))
         .context("writing detach request header")?;
 
     let reply: KillReply = client.read_reply().context("reading reply")?;
 
+    if dry_run {
+        for session in reply.matched_sessions.iter() {
+            output::listing(session);
+        }
+    }
+
     if !reply.not_found_sessions.is_empty() {
-        eprintln!("not found: {}", reply.not_found_sessions.join(" "));
-        return Err(anyhow!("not found: {}", reply.not_found_sessions.join(" ")));
+        output::error(format!("not found: {}", reply.not_found_sessions.join(" ")));
+        output::result("kill", "not-found", &reply.not_found_sessions);
+        return Err(Error::SessionsNotFound(reply.not_found_sessions).into());
     }
 
+    output::result("kill", if dry_run { "dry-run" } else { "ok" }, &reply.matched_sessions);
     Ok(())
 }
+
+/// The sessions the kill would hit that someone is attached to right
+/// now. It is fine if a session changes hands between here and the
+/// kill, the point is just to catch the user killing the wrong thing.
+fn attached_sessions(
+    ctx: &ClientContext,
+    sessions: &[String],
+    panes: bool,
+) -> anyhow::Result<Vec<String>> {
+    // A dry run resolves any patterns the same way the kill will.
+    let mut client = ctx.connect()?;
+    client
+        .write_connect_header(ConnectHeader::Kill(KillRequest {
+            sessions: sessions.to_vec(),
+            panes,
+            dry_run: true,
+        }))
+        .context("writing kill dry run header")?;
+    let matched: KillReply = client.read_reply().context("reading kill dry run reply")?;
+
+    let mut client = ctx.connect()?;
+    client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
+    let listed: ListReply = client.read_reply().context("reading list reply")?;
+
+    let own = env::var("SHPOOL_SESSION_NAME").ok();
+    Ok(needs_confirm(&matched.matched_sessions, &listed.sessions, own.as_deref()))
+}
+
+/// Pick out the matched sessions that are attached, leaving out the
+/// one we are running inside of, since that one is attached to us.
+fn needs_confirm(matched: &[String], listed: &[Session], own: Option<&str>) -> Vec<String> {
+    listed
+        .iter()
+        .filter(|s| matches!(s.status, SessionStatus::Attached))
+        .filter(|s| Some(s.name.as_str()) != own)
+        .filter(|s| matched.contains(&s.name))
+        .map(|s| s.name.clone())
+        .collect()
+}
+
+fn confirm(attached: &[String]) -> anyhow::Result<bool> {
+    eprint!(
+        "{} attached, kill anyway? [y/N] ",
+        attached.iter().map(|s| format!("'{}'", s)).collect::<Vec<_>>().join(", ")
+    );
+    io::stderr().flush().context("flushing prompt")?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer).context("reading answer")?;
+    Ok(is_yes(&answer))
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn session(name: &str, status: SessionStatus) -> Session {
+        Session {
+            name: String::from(name),
+            started_at_unix_ms: 0,
+            status,
+            terminal: None,
+            net: None,
+            memory: Default::default(),
+            reap_in_secs: None,
+            client_suspended: false,
+        }
+    }
+
+    #[test]
+    fn only_attached_matches_need_confirm() {
+        let listed = vec![
+            session("main", SessionStatus::Attached),
+            session("idle", SessionStatus::Disconnected),
+            session("other", SessionStatus::Attached),
+            session("here", SessionStatus::Attached),
+        ];
+        let matched: Vec<String> =
+            ["main", "idle", "here"].iter().map(|s| String::from(*s)).collect();
+        assert_eq!(needs_confirm(&matched, &listed, Some("here")), vec![String::from("main")]);
+        assert_eq!(needs_confirm(&matched, &listed, None), vec!["main", "here"]);
+    }
+
+    #[test]
+    fn answers() {
+        for yes in ["y", "Y\n", " yes \n"] {
+            assert!(is_yes(yes), "{:?}", yes);
+        }
+        for no in ["", "\n", "n", "nope", "yess"] {
+            assert!(!is_yes(no), "{:?}", no);
+        }
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The lastlog is a history of session lifecycle events (creates,
+  attaches, detaches and so on) kept as json lines in the runtime dir,
+  so that `shpool last` can answer questions like "when did I last
+  attach to work-2, and from where". Like the per-session history
+  files, `shpool last` reads the file directly rather than asking the
+  daemon, so it works even when the daemon is not running.
+
+  The daemon appends to the file as things happen, and rewrites it
+  to enforce the retention limits from the `lastlog` config once it
+  has grown a good bit past them, so appends stay cheap.
+*/
+
+use std::{
+    fmt, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time,
+};
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{config, duration};
+
+const LASTLOG_FILE_NAME: &str = "lastlog.jsonl";
+const DEFAULT_MAX_ENTRIES: usize = 1000;
+
+pub fn path(runtime_dir: &Path) -> PathBuf {
+    runtime_dir.join(LASTLOG_FILE_NAME)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Event {
+    Create,
+    Attach,
+    Detach,
+    Exit,
+    Kill,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Create => write!(f, "create"),
+            Event::Attach => write!(f, "attach"),
+            Event::Detach => write!(f, "detach"),
+            Event::Exit => write!(f, "exit"),
+            Event::Kill => write!(f, "kill"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub at_unix_ms: i64,
+    pub session: String,
+    pub event: Event,
+    /// Where the client came from, if it was attaching over ssh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+/// The daemon's handle on the lastlog.
+pub struct Writer {
+    path: PathBuf,
+    config: config::Manager,
+    /// The number of entries in the file, once we have counted them.
+    entries: Mutex<Option<usize>>,
+}
+
+impl Writer {
+    pub fn new(runtime_dir: &Path, config: config::Manager) -> Self {
+        Writer { path: path(runtime_dir), config, entries: Mutex::new(None) }
+    }
+
+    /// Record an event. Failures get logged rather than returned, since
+    /// they should never get in the way of the event itself.
+    pub fn record(&self, session: &str, event: Event, from: Option<&str>) {
+        let entry = Entry {
+            at_unix_ms: chrono::Utc::now().timestamp_millis(),
+            session: String::from(session),
+            event,
+            from: from.map(String::from),
+        };
+        if let Err(e) = self.append(&entry) {
+            warn!("recording {:?} in lastlog: {:?}", entry, e);
+        }
+    }
+
+    fn append(&self, entry: &Entry) -> anyhow::Result<()> {
+        let (max_entries, max_age) = {
+            let config = self.config.get();
+            let lastlog = config.lastlog.clone().unwrap_or_default();
+            let max_age = match &lastlog.max_age {
+                Some(src) => Some(duration::parse(src).context("parsing lastlog max_age")?),
+                None => None,
+            };
+            (lastlog.max_entries.unwrap_or(DEFAULT_MAX_ENTRIES), max_age)
+        };
+        if max_entries == 0 {
+            return Ok(());
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let mut line = serde_json::to_vec(entry).context("serializing entry")?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .context("opening lastlog")?
+            .write_all(&line)
+            .context("appending entry")?;
+
+        // Compact the first time we write, which picks up any age limit
+        // and config changes across daemon restarts, and then whenever
+        // we get a quarter past the limit.
+        let count = entries.map(|n| n + 1).unwrap_or(usize::MAX);
+        *entries = Some(if count > max_entries + max_entries / 4 {
+            compact(&self.path, max_entries, max_age).context("compacting lastlog")?
+        } else {
+            count
+        });
+
+        Ok(())
+    }
+}
+
+/// Rewrite the lastlog with only the entries that are within the
+/// retention limits, returning how many are left.
+fn compact(
+    path: &Path,
+    max_entries: usize,
+    max_age: Option<time::Duration>,
+) -> anyhow::Result<usize> {
+    let mut entries = read(path)?;
+    if let Some(max_age) = max_age {
+        let cutoff = chrono::Utc::now().timestamp_millis() - max_age.as_millis() as i64;
+        entries.retain(|e| e.at_unix_ms >= cutoff);
+    }
+    let excess = entries.len().saturating_sub(max_entries);
+    entries.drain(..excess);
+
+    let mut buf = vec![];
+    for entry in entries.iter() {
+        serde_json::to_writer(&mut buf, entry).context("serializing entry")?;
+        buf.push(b'\n');
+    }
+    let tmp_path = path.with_extension("jsonl.tmp");
+    fs::write(&tmp_path, buf).context("writing compacted lastlog")?;
+    fs::rename(&tmp_path, path).context("replacing lastlog")?;
+    info!("compacted lastlog to {} entries", entries.len());
+
+    Ok(entries.len())
+}
+
+/// Read all the entries in the lastlog, oldest first. Lines that don't
+/// parse, such as a line that got cut off by a crash, are skipped.
+fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("opening {:?}", path)),
+    };
+    let mut entries = vec![];
+    for line in io::BufReader::new(file).lines() {
+        let line = line.context("reading lastlog")?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("skipping bad lastlog line {:?}: {:?}", line, e),
+        }
+    }
+    Ok(entries)
+}
+
+pub fn run(session: Option<String>, limit: usize, runtime_dir: PathBuf) -> anyhow::Result<()> {
+    let entries = read(&path(&runtime_dir))?;
+
+    println!("SESSION\tEVENT\tFROM\tAT");
+    for entry in entries
+        .iter()
+        .rev()
+        .filter(|e| session.as_ref().map(|s| s == &e.session).unwrap_or(true))
+        .take(limit)
+    {
+        let at = time::UNIX_EPOCH + time::Duration::from_millis(entry.at_unix_ms as u64);
+        let at = chrono::DateTime::<chrono::Utc>::from(at);
+        println!(
+            "{}\t{}\t{}\t{}",
+            entry.session,
+            entry.event,
+            entry.from.as_deref().unwrap_or("local"),
+            at.to_rfc3339()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn writer(dir: &Path, config_src: &str) -> anyhow::Result<Writer> {
+        let config_path = dir.join("config.toml");
+        fs::write(&config_path, config_src)?;
+        Ok(Writer::new(dir, config::Manager::new(config_path.to_str())?))
+    }
+
+    #[test]
+    fn retention() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let writer = writer(tmp_dir.path(), "[lastlog]\nmax_entries = 4\n")?;
+
+        for i in 0..12 {
+            writer.record(&format!("s{}", i), Event::Attach, None);
+            // never more than a quarter past the limit
+            assert!(read(&path(tmp_dir.path()))?.len() <= 5);
+        }
+        let sessions =
+            read(&path(tmp_dir.path()))?.into_iter().map(|e| e.session).collect::<Vec<_>>();
+        assert_eq!(sessions.last().map(|s| s.as_str()), Some("s11"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_age_on_first_write() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let old = Entry {
+            at_unix_ms: 1000,
+            session: String::from("old"),
+            event: Event::Create,
+            from: Some(String::from("10.0.0.1")),
+        };
+        fs::write(path(tmp_dir.path()), format!("{}\nnot json\n", serde_json::to_string(&old)?))?;
+
+        let writer = writer(tmp_dir.path(), "[lastlog]\nmax_age = \"30d\"\n")?;
+        writer.record("new", Event::Create, None);
+
+        let entries = read(&path(tmp_dir.path()))?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].session, "new");
+        assert_eq!(entries[0].from, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn disabled() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let writer = writer(tmp_dir.path(), "[lastlog]\nmax_entries = 0\n")?;
+        writer.record("s", Event::Attach, None);
+        assert!(!path(tmp_dir.path()).exists());
+        Ok(())
+    }
+}
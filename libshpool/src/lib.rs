@@ -17,31 +17,76 @@ use std::{
     env, fs,
     hash::{Hash, Hasher},
     io,
+    os::unix::fs::DirBuilderExt as _,
     path::PathBuf,
+    process::ExitCode,
     sync::Mutex,
 };
 
 use anyhow::{anyhow, Context};
-use clap::{Parser, Subcommand};
-pub use hooks::Hooks;
+use clap::{Parser, Subcommand, ValueEnum};
+pub use error::Error;
+pub use hooks::{CmdDecision, Hooks, StreamTransform};
+use shpool_protocol::RestartPolicy;
 use tracing::error;
-use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::{
+    fmt::{format::FmtSpan, writer::BoxMakeWriter},
+    prelude::*,
+    reload, EnvFilter,
+};
 
+mod adopt;
+mod adopt_pid;
 mod attach;
+mod broadcast;
+mod clipboard;
+mod clipboard_bridge;
+mod clock;
 mod common;
-mod config;
+pub mod config;
 mod config_watcher;
 mod consts;
+mod context;
+mod control;
+mod control_sock;
 mod daemon;
 mod daemonize;
 mod detach;
-mod duration;
+mod dump_state;
+pub mod duration;
+mod error;
+mod export;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod history;
 mod hooks;
+mod import;
 mod kill;
+mod lastlog;
 mod list;
+mod log_level;
+mod messages;
+mod output;
+mod picker;
+mod pipe;
+mod platform;
+mod profile;
 mod protocol;
+mod recording;
+mod serve_ssh;
+mod session_env;
+mod session_name;
+mod ssh;
+mod terminal_probe;
 mod test_hooks;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod top;
+mod ttl;
 mod tty;
+#[cfg(feature = "udp_transport")]
+mod udp;
+mod update_check;
 mod user;
 
 /// The command line arguments that shpool expects.
@@ -53,7 +98,7 @@ mod user;
 /// if it is set. Clap won't do a good job with its
 /// automatic version support for a library.
 #[derive(Parser, Debug)]
-#[clap(author, about)]
+#[clap(author, about, after_long_help = error::exit_status_help())]
 pub struct Args {
     #[clap(
         short,
@@ -74,6 +119,19 @@ running in daemon mode, the logs will go to stderr by default."
     )]
     pub verbose: u8,
 
+    #[clap(
+        long,
+        action,
+        long_help = "A filter controlling which logs get emitted
+
+This uses the same syntax as RUST_LOG, so something like
+'info,libshpool::daemon::shell=trace' will turn on trace logs for
+just the session code. When set, it takes priority over both
+RUST_LOG and --verbose. The targets used by each log line are
+included in the output whenever a filter is in effect."
+    )]
+    pub trace_filter: Option<String>,
+
     #[clap(
         short,
         long,
@@ -81,7 +139,14 @@ running in daemon mode, the logs will go to stderr by default."
         long_help = "The path for the unix socket to listen on
 
 This defaults to $XDG_RUNTIME_DIR/shpool/shpool.socket or ~/.local/run/shpool/shpool.socket
-if XDG_RUNTIME_DIR is unset.
+if XDG_RUNTIME_DIR is unset. If that directory is on a filesystem
+that can't hold unix sockets, such as some NFS mounts, the socket goes
+in the socket_fallback_dir from the config, or /tmp/shpool-$UID.
+
+On linux, a socket starting with '@' (i.e. '@shpool-foo') names a socket
+in the abstract namespace, which has no filesystem entry. This can be
+useful in containers with a read-only filesystem. To use a filesystem
+socket whose name starts with '@', prefix it with './'.
 
 This flag gets overridden by systemd socket activation when
 the daemon is launched by systemd."
@@ -97,6 +162,43 @@ the daemon is launched by systemd."
     #[clap(short = 'D', long, action, help = "do not automatically launch a daemon")]
     pub no_daemonize: bool,
 
+    #[clap(
+        short,
+        long,
+        action,
+        global = true,
+        conflicts_with = "porcelain",
+        help = "only print errors and the output that was asked for"
+    )]
+    pub quiet: bool,
+
+    #[clap(
+        long,
+        action,
+        global = true,
+        long_help = "print stable, machine readable results
+
+attach, detach and kill report their outcome as a single line of the
+form '<command> <status> [<session>...]', and banners, notes and prompts
+are left out. See the README for the statuses each command can report."
+    )]
+    pub porcelain: bool,
+
+    #[clap(
+        long,
+        action,
+        global = true,
+        long_help = "how long to wait on the daemon before giving up
+
+Bounds connecting to the daemon and waiting on its reply to each
+request, so that a wedged daemon makes commands like list, kill,
+detach and attach fail with exit status 8 rather than hang. Once
+attached, a quiet session never times out. Takes a duration in the
+same format as attach --ttl, such as 5s or 500ms. Defaults to the
+client_timeout config, or waiting forever if that is not set."
+    )]
+    pub timeout: Option<String>,
+
     #[clap(subcommand)]
     pub command: Commands,
 }
@@ -108,7 +210,64 @@ pub enum Commands {
     Version,
 
     #[clap(about = "Starts running a daemon that holds a pool of shells")]
-    Daemon,
+    Daemon {
+        #[clap(
+            long,
+            long_help = "Recreate the sessions that were running when the last daemon exited
+
+Each session gets a fresh shell with the same name, command, working
+directory and environment as before. The sessions are created detached."
+        )]
+        resurrect: bool,
+        #[clap(
+            long,
+            help = "Check crates.io for a newer release of shpool and exit rather than starting a daemon"
+        )]
+        check_update: bool,
+        #[clap(
+            long,
+            long_help = "Tighten the permissions on the runtime dir and control socket
+
+The daemon refuses to start if its runtime dir is accessible to other
+users, or if anything in it is writable by other users, since the
+control socket grants shell access. With this flag it fixes the
+permissions instead, and keeps fixing them if they get loosened while
+it runs."
+        )]
+        fix_perms: bool,
+        #[clap(
+            long,
+            conflicts_with = "foreground",
+            long_help = "Detach from the terminal and run in the background
+
+For systems without systemd. The daemon forks twice, starts a new
+session with setsid, writes its pid to the pid file and sends its
+stdout and stderr to the log file, which is --log-file if given, or
+daemonized-shpool.log in the runtime dir. The command returns once the
+daemon is in the background."
+        )]
+        daemonize: bool,
+        #[clap(long, help = "Stay in the foreground, attached to the terminal (the default)")]
+        foreground: bool,
+        #[clap(
+            long,
+            requires = "daemonize",
+            help = "Where --daemonize writes the daemon's pid, instead of daemonized-shpool.pid in the runtime dir"
+        )]
+        pid_file: Option<String>,
+        #[clap(
+            long,
+            conflicts_with_all = ["daemonize", "resurrect"],
+            long_help = "Serve a single connection passed on stdin, then exit
+
+For inetd style superservers, systemd sockets with Accept=yes and ssh
+forced commands, which accept the connection themselves. stdin must be
+a unix socket. Sessions only live as long as the connection, and since
+the runtime dir is shared by every daemon using the same --socket, give
+single connection daemons a --socket name of their own."
+        )]
+        inetd: bool,
+    },
 
     #[clap(about = "Creates or attaches to an existing shell session")]
     Attach {
@@ -118,14 +277,14 @@ pub enum Commands {
             long,
             long_help = "Automatically kill the session after the given time
 
-This option only applies when first creating a session, it is ignored on
-reattach.
+When reattaching to an existing session, the session's deadline is reset
+to the given time from now, even if it was not created with a ttl.
 
 The duration can be specified either in a colon seperated format
 of the form dd:hh:mm:ss where any prefix may be left off (i.e. '01:00:30:00'
 for 1 day and 30 minutes or '10:45:00' for 10 hours and 45 minutes), or
-using a number with a trailing letter to indicate time unit
-(i.e. '3d', '19h', or '5s')."
+using numbers with trailing units, which are ms, s, m, h, d and w
+(i.e. '3d', '19h', '1.5h', or '1d2h30m')."
         )]
         ttl: Option<String>,
         #[clap(
@@ -137,33 +296,478 @@ The command is broken up into a binary to invoke and a list of arguments to
 pass to the binary using the shell-words crate."
         )]
         cmd: Option<String>,
-        #[clap(help = "The name of the shell session to create or attach to")]
-        name: String,
+        #[clap(
+            long,
+            long_help = "A container or namespace to launch the shell inside of
+
+This takes the form kind:target, where kind is one of docker, podman,
+netns or mntns (i.e. 'docker:devbox' or 'netns:/run/netns/vpn'). It
+overrides the 'container' config option and only applies when first
+creating a session."
+        )]
+        container: Option<String>,
+        #[clap(
+            long,
+            long_help = "Use the experimental udp transport for the attach stream
+
+This requires shpool to be built with the udp_transport feature. If
+the daemon does not support the udp transport, the attach will fall
+back to the normal transport."
+        )]
+        udp: bool,
+        #[clap(
+            long,
+            long_help = "A session template from the config file to create the session from
+
+The template supplies defaults for the shell, cmd, env, ttl, container,
+session restore mode and restart policy of the new session. Flags passed
+to attach take priority over the template."
+        )]
+        template: Option<String>,
+        #[clap(
+            long,
+            long_help = "The directory to start the shell in
+
+Defaults to the current directory. If the directory does not exist
+on the daemon's machine, the shell starts in your home directory. Only
+applies when first creating a session."
+        )]
+        cwd: Option<String>,
+        #[clap(
+            long,
+            long_help = "What to do when the session's shell exits
+
+One of 'never', 'on-failure' or 'always'. With 'on-failure', the daemon
+launches the shell again under the same session name if it exits with a
+non-zero status, and 'always' restarts it no matter how it exits. The
+next client to attach after a restart is told about it. A session that
+is killed with `shpool kill` is never restarted. Only applies when first
+creating a session, and overrides the template's restart policy."
+        )]
+        restart: Option<RestartPolicy>,
+        #[clap(
+            long,
+            conflicts_with = "name",
+            long_help = "Create a new session with a name picked by the daemon
+
+The daemon makes up a friendly name that is not already in use, like
+'brave-otter', and shpool prints it before attaching."
+        )]
+        auto_name: bool,
+        #[clap(
+            long,
+            requires = "name",
+            long_help = "Attach to a pane of the session instead of the session itself
+
+A pane is an extra shell that belongs to the session, like a tmux
+window. It shows up as 'session:pane' in 'shpool list', and can be
+attached to, detached or killed by that address. Pass --panes to kill,
+detach or broadcast to take all of a session's panes along."
+        )]
+        pane: Option<String>,
+        #[clap(
+            long,
+            long_help = "Speak a line based control protocol instead of attaching a terminal
+
+This is meant for terminal emulators and other front-ends that want to
+show shpool sessions in their own tabs and windows, like tmux's -CC
+mode. Output arrives as base64 encoded '%output' lines on stdout, and
+the front-end sends 'send', 'resize', 'list' and 'detach' commands on
+stdin. See the README for the details of the protocol."
+        )]
+        control: bool,
+        #[clap(
+            help = "The name of the shell session to create or attach to",
+            long_help = "The name of the shell session to create or attach to
+
+If no name is given and shpool is running on a terminal, it pops up
+a list of the existing sessions to pick from."
+        )]
+        name: Option<String>,
     },
 
     #[clap(about = "Make the given session detach from shpool
 
-This does not close the shell. If no session name is provided
-$SHPOOL_SESSION_NAME will be used if it is present in the
-environment.")]
+This does not close the shell. Sessions can be given as glob
+patterns like 'work-*', which the daemon matches against its
+sessions. If no session name is provided $SHPOOL_SESSION_NAME will
+be used if it is present in the environment.")]
     Detach {
+        #[clap(long, help = "Print the sessions that would be detached without detaching them")]
+        dry_run: bool,
+        #[clap(long, help = "Also detach the panes of each session")]
+        panes: bool,
         #[clap(help = "sessions to detach")]
         sessions: Vec<String>,
     },
 
+    #[clap(about = "Send the same line of input to several sessions
+
+The input is typed into each session followed by enter, whether or not
+a terminal is attached to it, for running the same command across a
+fleet of shells. Sessions can be given as glob patterns like 'work-*',
+and --panes sends the input to the panes of each session as well. Put
+the input after a '--', like 'shpool broadcast 'proj-*' -- git pull'.")]
+    Broadcast {
+        #[clap(long, help = "Print the sessions that would get the input without sending it")]
+        dry_run: bool,
+        #[clap(long, help = "Also send the input to the panes of each session")]
+        panes: bool,
+        #[clap(long, help = "Don't hit enter after the input")]
+        no_enter: bool,
+        #[clap(required = true, help = "sessions to send the input to")]
+        sessions: Vec<String>,
+        #[clap(last = true, required = true, help = "the input to send, joined by spaces")]
+        input: Vec<String>,
+    },
+
+    #[clap(about = "Copy text into the daemon's clipboard
+
+The clipboard is shared by every session, so text copied in one
+session can be pasted into another with 'shpool paste', without
+relying on the terminal's selection. With no arguments, the text is
+read from stdin, as in 'git rev-parse HEAD | shpool copy'.")]
+    Copy {
+        #[clap(help = "the text to copy, joined by spaces")]
+        input: Vec<String>,
+    },
+
+    #[clap(about = "Print the daemon's clipboard
+
+With --session, the clipboard is typed into the given session
+instead, as if it had been pasted into an attached terminal.")]
+    Paste {
+        #[clap(long, help = "Type the clipboard into this session rather than printing it")]
+        session: Option<String>,
+    },
+
+    #[clap(about = "Pipe the output of a session into a local command
+
+This streams everything the session prints into the stdin of the
+given command, like 'shpool pipe build -- grep -i error', without
+attaching to the session or bothering the terminal that is attached
+to it. With no command, the output goes to stdout. The output is raw
+terminal output, escape sequences and all. It keeps going until the
+session's shell exits or the command stops reading. If the command
+can't keep up, some output gets skipped rather than slowing the
+session down, with a warning on stderr.")]
+    Pipe {
+        #[clap(long, help = "Start with the session's scrollback rather than just new output")]
+        from_start: bool,
+        #[clap(help = "the session to pipe")]
+        session: String,
+        #[clap(last = true, help = "the command to pipe the output into")]
+        cmd: Vec<String>,
+    },
+
+    #[clap(about = "Show the environment a session's shell was started with
+
+This prints the variables the daemon handed to the shell when it
+started the session, such as the TERM and SSH_AUTH_SOCK it ended up
+with, which helps with working out why something behaves differently
+inside shpool. Anything the shell or its rc files exported since then
+is not included. If no session name is provided $SHPOOL_SESSION_NAME
+will be used if it is present in the environment.")]
+    Env {
+        #[clap(long = "var", help = "Only show this variable, can be given more than once")]
+        vars: Vec<String>,
+        #[clap(help = "the session to show the environment of")]
+        session: Option<String>,
+    },
+
     #[clap(about = "Kill the given sessions
 
 This detaches the session if it is attached and kills the underlying
 shell with a SIGHUP followed by a SIGKILL if the shell fails to exit
-quickly enough. If no session name is provided $SHPOOL_SESSION_NAME
-will be used if it is present in the environment.")]
+quickly enough. Sessions can be given as glob patterns like 'work-*',
+which the daemon matches against its sessions. If no session name is
+provided $SHPOOL_SESSION_NAME will be used if it is present in the
+environment.")]
     Kill {
+        #[clap(long, help = "Print the sessions that would be killed without killing them")]
+        dry_run: bool,
+        #[clap(long, help = "Also kill the panes of each session")]
+        panes: bool,
+        #[clap(
+            short,
+            long,
+            long_help = "Kill attached sessions without asking first
+
+When run from a terminal, kill asks before killing a session that
+someone is attached to, other than the one it is running inside of.
+It never asks when stdin is not a terminal or with --porcelain."
+        )]
+        yes: bool,
         #[clap(help = "sessions to kill")]
         sessions: Vec<String>,
     },
 
     #[clap(about = "lists all the running shell sessions")]
-    List,
+    List {
+        #[clap(
+            short,
+            long,
+            long_help = "Also show how the shells of each session name recently exited
+
+This includes exits of sessions that have since been restarted or
+recreated under the same name, as well as sessions that are gone,
+which helps with tracking down commands that keep falling over."
+        )]
+        long: bool,
+        #[clap(
+            long,
+            value_enum,
+            default_value_t = clap::ColorChoice::Auto,
+            long_help = "Whether to color the status of each session
+
+By default, the status is colored when printing to a terminal, unless
+the NO_COLOR environment variable is set."
+        )]
+        color: clap::ColorChoice,
+    },
+
+    #[clap(about = "Print the definitions of all the running sessions
+
+The output is a toml file which can be passed to `shpool import`
+to recreate the sessions, for example on another machine.")]
+    Export,
+
+    #[clap(about = "Create sessions from a file written by `shpool export`
+
+The sessions are created detached. Sessions which already exist
+are skipped.")]
+    Import {
+        #[clap(help = "The file to read session definitions from")]
+        file: PathBuf,
+    },
+
+    #[clap(about = "Recreate running tmux and screen sessions as shpool sessions
+
+Each tmux pane or screen window becomes a detached shpool session
+running the same command in the same directory. The tmux and screen
+sessions are left alone, so you can shut them down once you have
+moved over.")]
+    Adopt {
+        #[clap(long, value_enum, help = "Only adopt sessions from this multiplexer")]
+        from: Option<AdoptSource>,
+        #[clap(
+            long,
+            help = "Print the sessions that would be created, in the format `shpool import` reads"
+        )]
+        dry_run: bool,
+    },
+
+    #[clap(about = "Move an already running process into a new session (experimental)
+
+This uses ptrace to point the process's terminal fds at the pty of a
+new session, like reptyr does, so that a long running job started
+outside of shpool survives you disconnecting. Keys that send signals,
+like Ctrl-c, don't reach the adopted process. The daemon must be built
+with the adopt_pid feature, runs only on x86_64 linux, and needs to be
+allowed to ptrace the process.")]
+    AdoptPid {
+        #[clap(long, help = "The name of the session to create")]
+        name: String,
+        #[clap(help = "The process to adopt")]
+        pid: i32,
+    },
+
+    #[clap(about = "Dump the daemon's internal state as json
+
+This includes the session table, per-session thread status and
+output counters, and the most recent errors the daemon ran into.
+It is meant to help with debugging a misbehaving daemon, and the
+format is not stable.")]
+    DumpState {
+        #[clap(short, long, help = "A file to write the state to rather than stdout")]
+        output: Option<PathBuf>,
+    },
+
+    #[clap(about = "Show or change the running daemon's log filter
+
+With no argument, this prints the filter the daemon is currently
+logging with. Otherwise, the daemon starts using the given filter,
+which has the same syntax as RUST_LOG, like 'debug' or
+'info,libshpool::daemon::server=trace'. This lasts until the daemon
+restarts, and lets you capture debug logs of a live problem without
+losing your sessions.")]
+    LogLevel {
+        #[clap(help = "the new filter")]
+        filter: Option<String>,
+    },
+
+    #[clap(about = "Profile the running daemon's cpu usage
+
+A sampling profiler runs inside the daemon for the given amount of
+time, and the result gets written to the profiles directory under
+shpool's runtime dir. The path of the profile is printed once it has
+been written. This needs a daemon built with the profiling feature.")]
+    Profile {
+        #[clap(
+            long,
+            default_value = "30s",
+            help = "How long to profile for, in the same format as --ttl"
+        )]
+        duration: String,
+        #[clap(long, value_enum, default_value = "flamegraph", help = "The kind of file to write")]
+        format: ProfileFormat,
+    },
+
+    #[clap(about = "Print the shell history of a session
+
+Only works if the per_session_history config option was on when
+the session was created. Most shells only write out their history
+when they exit.")]
+    History {
+        #[clap(help = "The name of the session")]
+        session: String,
+    },
+
+    #[clap(about = "Play back the recorded output of a session
+
+Only works if the recording config option was on while the session
+was running. Pauses in the output are played back as they happened,
+but cut down to at most 2 seconds. Replaying from partway through
+with --since can leave the screen looking a bit off, since the output
+from before that point that set it up gets skipped.")]
+    Replay {
+        #[clap(help = "The name of the session")]
+        session: String,
+        #[clap(
+            long,
+            help = "Only play back output from this long ago onward, in the same format as --ttl"
+        )]
+        since: Option<String>,
+        #[clap(
+            long,
+            default_value = "1",
+            help = "How fast to play back the output, 0 to skip pauses entirely"
+        )]
+        speed: f64,
+    },
+
+    #[clap(about = "Show recent session events, newest first
+
+Lists when sessions were created, attached to, detached from, killed
+and exited, along with where attaching clients came from if they were
+connected over ssh. The history is kept even after sessions exit, with
+limits set by the lastlog config option.")]
+    Last {
+        #[clap(help = "Only show events for this session")]
+        session: Option<String>,
+        #[clap(short = 'n', long, default_value = "20", help = "The number of events to show")]
+        limit: usize,
+    },
+
+    #[clap(about = "Show a live table of sessions sorted by resource usage
+
+The table shows how many bytes per second each session is writing
+to and reading from its clients, and how much cpu the processes in
+each session are using.")]
+    Top {
+        #[clap(
+            long,
+            value_enum,
+            default_value = "output",
+            help = "The column to sort sessions by"
+        )]
+        sort: TopSort,
+        #[clap(
+            long,
+            default_value = "2s",
+            help = "How long to wait between refreshes, in the same format as --ttl"
+        )]
+        interval: String,
+        #[clap(short = 'n', long, help = "Exit after this many refreshes")]
+        iterations: Option<usize>,
+    },
+
+    #[clap(about = "Manage the ttl of a running session")]
+    Ttl {
+        #[clap(subcommand)]
+        command: TtlCommands,
+    },
+
+    #[clap(about = "Attach to a shell session on a remote host over ssh
+
+This runs `ssh -t host shpool attach session`, starting a daemon on
+the remote host if one is not already running. If the connection
+drops, it will automatically reconnect to the same session.")]
+    Ssh {
+        #[clap(
+            long,
+            default_value = "shpool",
+            help = "The shpool binary to invoke on the remote host"
+        )]
+        remote_shpool: String,
+        #[clap(long, help = "Exit rather than reconnecting when the connection drops")]
+        no_reconnect: bool,
+        #[clap(
+            long = "ssh-arg",
+            allow_hyphen_values = true,
+            help = "An extra argument to pass to ssh, may be provided multiple times"
+        )]
+        ssh_args: Vec<String>,
+        #[clap(help = "The host to connect to")]
+        host: String,
+        #[clap(help = "The name of the remote shell session to create or attach to")]
+        name: String,
+    },
+
+    #[clap(about = "Serve the session configured for an ssh key
+
+This is meant to be the forced command of a key in authorized_keys,
+like 'command=\"shpool serve-ssh laptop\",restrict,pty ssh-ed25519 ...',
+turning ssh logins with that key into attaches to the session the
+ssh_keys config table gives the key. Read only keys just see the
+session's output. Commands requested by the ssh client are refused,
+and connections are recorded in the audit log if there is one.")]
+    ServeSsh {
+        #[clap(help = "The name of the key in the ssh_keys config table")]
+        key: String,
+    },
+}
+
+/// The columns that `shpool top` can sort by.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum TopSort {
+    /// Bytes per second of shell output.
+    Output,
+    /// Bytes per second of client input.
+    Input,
+    /// Cpu usage of the processes in the session.
+    Cpu,
+}
+
+/// The kinds of file that `shpool profile` can write.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ProfileFormat {
+    /// A flamegraph svg, for looking at in a browser.
+    Flamegraph,
+    /// A pprof protobuf, for `go tool pprof` and similar tools.
+    Pprof,
+}
+
+/// The terminal multiplexers that `shpool adopt` knows about.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum AdoptSource {
+    Tmux,
+    Screen,
+}
+
+/// The subcommands of `shpool ttl`.
+#[derive(Subcommand, Debug)]
+pub enum TtlCommands {
+    #[clap(about = "Push back the time at which a session with a ttl gets killed
+
+The duration is added to the time the session has left, and is
+specified in the same format as the --ttl flag for attach.")]
+    Extend {
+        #[clap(help = "The session to extend")]
+        session: String,
+        #[clap(help = "How much longer to keep the session around")]
+        duration: String,
+    },
 }
 
 impl Args {
@@ -174,19 +778,17 @@ impl Args {
     }
 }
 
-/// Run the shpool tool with the given arguments. If hooks is provided,
-/// inject the callbacks into the daemon.
-pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> anyhow::Result<()> {
-    match (&args.command, env::var(consts::SENTINEL_FLAG_VAR).as_deref()) {
-        (Commands::Daemon, Ok("prompt")) => {
-            println!("{}", consts::PROMPT_SENTINEL);
-            std::process::exit(0);
-        }
-        (Commands::Daemon, Ok("startup")) => {
-            println!("{}", consts::STARTUP_SENTINEL);
-            std::process::exit(0);
+/// Figure out which logs to emit. An explicit --trace-filter wins,
+/// followed by RUST_LOG, and finally the coarse --verbose ramp. The
+/// second return value indicates if a per-module filter is in effect.
+fn trace_filter(args: &Args) -> anyhow::Result<(EnvFilter, bool)> {
+    if let Some(filter) = &args.trace_filter {
+        return Ok((EnvFilter::try_new(filter).context("parsing --trace-filter")?, true));
+    }
+    if let Ok(filter) = env::var(EnvFilter::DEFAULT_ENV) {
+        if !filter.is_empty() {
+            return Ok((EnvFilter::try_new(filter).context("parsing RUST_LOG")?, true));
         }
-        _ => {}
     }
 
     let trace_level = if args.verbose == 0 {
@@ -196,23 +798,103 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
     } else {
         tracing::Level::TRACE
     };
-    if let Some(log_file) = args.log_file.clone() {
-        let file = fs::File::create(log_file)?;
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(Mutex::new(file))
-            .init();
+    Ok((EnvFilter::default().add_directive(trace_level.into()), false))
+}
+
+/// Run the shpool tool with the given arguments and the process exit
+/// status the command wants, like the exit status of the shell for
+/// `shpool attach`. If hooks is provided, inject the callbacks into the
+/// daemon.
+///
+/// This never exits the process itself, so it is safe to call from a
+/// program that embeds shpool. See `run_cli` for the binary.
+pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> Result<i32, Error> {
+    run_with(args, None, hooks)
+}
+
+/// Like `run`, but with a config built in code rather than loaded from
+/// the config files, which also means that `--config-file` is ignored
+/// and the config never gets reloaded.
+///
+/// The daemon only sees this config if it runs in this process, either
+/// as `shpool daemon` or with `--no-daemonize`. A daemon that gets
+/// spawned automatically is a fresh process that reads the usual files.
+pub fn run_with_config(
+    args: Args,
+    config: config::Config,
+    hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>,
+) -> Result<i32, Error> {
+    run_with(args, Some(config), hooks)
+}
+
+fn run_with(
+    args: Args,
+    config: Option<config::Config>,
+    hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>,
+) -> Result<i32, Error> {
+    match (&args.command, env::var(consts::SENTINEL_FLAG_VAR).as_deref()) {
+        (Commands::Daemon { .. }, Ok("prompt")) => {
+            println!("{}", consts::PROMPT_SENTINEL);
+            return Ok(0);
+        }
+        (Commands::Daemon { .. }, Ok("startup")) => {
+            println!("{}", consts::STARTUP_SENTINEL);
+            return Ok(0);
+        }
+        _ => {}
+    }
+
+    // Superservers pass the connection on stderr too, so it has to be
+    // taken before anything gets logged there.
+    let inetd_conn = match &args.command {
+        Commands::Daemon { inetd: true, .. } => {
+            match daemon::inetd::take_stdin_conn() {
+                Ok(conn) => Some(conn),
+                Err(e) => {
+                    // nothing is set up to log the error yet
+                    output::error(format!("shpool: {:#}", e));
+                    return Err(e.into());
+                }
+            }
+        }
+        _ => None,
+    };
+
+    output::set_mode(if args.porcelain {
+        output::Mode::Porcelain
+    } else if args.quiet {
+        output::Mode::Quiet
+    } else {
+        output::Mode::Normal
+    });
+
+    let (filter, custom_filter) = trace_filter(&args)?;
+    let log_file = match &args.log_file {
+        Some(path) => Some(fs::File::create(path)?),
+        None => None,
+    };
+    let log_writer = if let Some(log_file) = &log_file {
+        Some(BoxMakeWriter::new(Mutex::new(log_file.try_clone()?)))
     } else if let Commands::Daemon { .. } = args.command {
-        tracing_subscriber::fmt()
-            .with_max_level(trace_level)
-            .with_thread_ids(true)
-            .with_target(false)
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_writer(io::stderr)
+        Some(BoxMakeWriter::new(io::stderr))
+    } else {
+        None
+    };
+    if let Some(log_writer) = log_writer {
+        // The filter is reloadable so that `shpool log-level` can
+        // change it in a running daemon.
+        let (filter, filter_handle) = reload::Layer::new(filter);
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_thread_ids(true)
+                    .with_target(custom_filter)
+                    .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+                    .with_writer(log_writer),
+            )
             .init();
+        log_level::install(filter_handle);
     }
 
     let mut runtime_dir = match env::var("XDG_RUNTIME_DIR") {
@@ -222,31 +904,55 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
             .join("run"),
     }
     .join("shpool");
-    fs::create_dir_all(&runtime_dir).context("ensuring runtime dir exists")?;
+
+    if let Some(s) = &args.socket {
+        // The user can reasonably expect that if they provide seperate
+        // sockets for differnt shpool instances to run on, they won't
+        // stomp on one another. To respect this expectation we need to
+        // namespace the rest of the runtime data if they provide a socket
+        // name. A short hash is probably good enough. Abstract socket
+        // names hash with their leading '@', so '@foo' and 'foo'
+        // get different runtime dirs.
+        let mut hasher = DefaultHasher::new();
+        s.hash(&mut hasher);
+        let hash = hasher.finish();
+        runtime_dir = runtime_dir.join(format!("{:x}", hash));
+    }
+
+    // private, since the socket in it hands out shells, see daemon/perms.rs
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&runtime_dir)
+        .context("ensuring runtime dir exists")?;
+
+    // This has to happen before anything spawns a thread, such as the
+    // config watcher, since only the forking thread survives a fork.
+    if let Commands::Daemon { daemonize: true, pid_file, .. } = &args.command {
+        daemonize::into_background(&runtime_dir, pid_file.as_deref(), log_file)?;
+    }
+
+    let config_manager = match config {
+        Some(config) => config::Manager::from_config(config),
+        None => config::Manager::new(args.config_file.as_deref()),
+    }
+    .map_err(Error::Config)?;
 
     let socket = match &args.socket {
-        Some(s) => {
-            // The user can reasonably expect that if they provide seperate
-            // sockets for differnt shpool instances to run on, they won't
-            // stomp on one another. To respect this expectation we need to
-            // namespace the rest of the runtime data if they provide a socket
-            // name. A short hash is probably good enough.
-            let mut hasher = DefaultHasher::new();
-            s.hash(&mut hasher);
-            let hash = hasher.finish();
-            runtime_dir = runtime_dir.join(format!("{:x}", hash));
-
-            PathBuf::from(s)
+        Some(s) => PathBuf::from(s),
+        None => {
+            let fallback = config_manager.get().socket_fallback_dir.clone().map(PathBuf::from);
+            control_sock::socket_dir(&runtime_dir, fallback.as_deref())?
+                .join(control_sock::SOCKET_NAME)
         }
-        None => runtime_dir.join("shpool.socket"),
     };
 
-    let config_manager = config::Manager::new(args.config_file.as_deref())?;
-
     if !config_manager.get().nodaemonize.unwrap_or(false) || args.daemonize {
         let arg0 = env::args().next().ok_or(anyhow!("arg0 missing"))?;
-        if !args.no_daemonize && !matches!(args.command, Commands::Daemon) {
-            daemonize::maybe_fork_daemon(&config_manager, &args, arg0, &socket)?;
+        if !args.no_daemonize
+            && !matches!(args.command, Commands::Daemon { .. } | Commands::Ssh { .. })
+        {
+            daemonize::maybe_fork_daemon(&config_manager, &args, arg0, &socket, &runtime_dir)?;
         }
     }
 
@@ -261,28 +967,93 @@ pub fn run(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> an
         test_hooks::TEST_HOOK_SERVER.wait_for_connect()?;
     }
 
-    let res: anyhow::Result<()> = match args.command {
-        Commands::Version => return Err(anyhow!("wrapper binary must handle version")),
-        Commands::Daemon => daemon::run(
-            config_manager,
+    let timeout = context::timeout(args.timeout.as_deref(), &config_manager.get())?;
+    let ctx = context::ClientContext::new(config_manager, socket, timeout);
+    let res: anyhow::Result<i32> = match args.command {
+        Commands::Version => {
+            return Err(Error::Other(anyhow!("wrapper binary must handle version")))
+        }
+        Commands::Daemon { check_update: true, .. } => update_check::run(),
+        Commands::Daemon { resurrect, fix_perms, .. } => daemon::run(
+            ctx.config,
             runtime_dir,
             hooks.unwrap_or(Box::new(NoopHooks {})),
-            socket,
+            ctx.socket,
+            resurrect,
+            fix_perms,
+            inetd_conn,
+        )
+        .map(|()| 0),
+        Commands::Attach {
+            force,
+            ttl,
+            cmd,
+            container,
+            udp,
+            template,
+            cwd,
+            restart,
+            auto_name,
+            pane,
+            control,
+            name,
+        } => attach::run(
+            ctx, name, pane, auto_name, force, ttl, cmd, container, udp, template, cwd, restart,
+            control,
         ),
-        Commands::Attach { force, ttl, cmd, name } => {
-            attach::run(config_manager, name, force, ttl, cmd, socket)
+        Commands::Detach { dry_run, panes, sessions } => {
+            detach::run(sessions, panes, dry_run, &ctx).map(|()| 0)
+        }
+        Commands::Kill { dry_run, panes, yes, sessions } => {
+            kill::run(sessions, panes, dry_run, yes, &ctx).map(|()| 0)
+        }
+        Commands::Broadcast { dry_run, panes, no_enter, sessions, input } => {
+            broadcast::run(sessions, input, panes, no_enter, dry_run, &ctx).map(|()| 0)
+        }
+        Commands::Copy { input } => clipboard::copy(input, &ctx).map(|()| 0),
+        Commands::Paste { session } => clipboard::paste(session, &ctx).map(|()| 0),
+        Commands::Pipe { from_start, session, cmd } => pipe::run(session, from_start, cmd, &ctx),
+        Commands::Env { vars, session } => session_env::run(session, vars, &ctx).map(|()| 0),
+        Commands::List { long, color } => list::run(long, color, &ctx).map(|()| 0),
+        Commands::Export => export::run(&ctx).map(|()| 0),
+        Commands::Import { file } => import::run(file, &ctx).map(|()| 0),
+        Commands::Adopt { from, dry_run } => adopt::run(from, dry_run, &ctx).map(|()| 0),
+        Commands::AdoptPid { name, pid } => adopt_pid::run(pid, name, &ctx).map(|()| 0),
+        Commands::DumpState { output } => dump_state::run(output, &ctx).map(|()| 0),
+        Commands::LogLevel { filter } => log_level::run(filter, &ctx).map(|()| 0),
+        Commands::Profile { duration, format } => profile::run(duration, format, &ctx).map(|()| 0),
+        Commands::History { session } => history::run(session, runtime_dir).map(|()| 0),
+        Commands::Replay { session, since, speed } => {
+            let key_file = ctx.config.get().recording.as_ref().and_then(|r| r.key_file.clone());
+            recording::replay(session, since, speed, runtime_dir, key_file).map(|()| 0)
         }
-        Commands::Detach { sessions } => detach::run(sessions, socket),
-        Commands::Kill { sessions } => kill::run(sessions, socket),
-        Commands::List => list::run(socket),
+        Commands::Last { session, limit } => lastlog::run(session, limit, runtime_dir).map(|()| 0),
+        Commands::Top { sort, interval, iterations } => {
+            top::run(sort, interval, iterations, &ctx).map(|()| 0)
+        }
+        Commands::Ttl { command: TtlCommands::Extend { session, duration } } => {
+            ttl::extend(session, duration, &ctx).map(|()| 0)
+        }
+        Commands::Ssh { remote_shpool, no_reconnect, ssh_args, host, name } => {
+            ssh::run(host, name, remote_shpool, no_reconnect, ssh_args)
+        }
+        Commands::ServeSsh { key } => serve_ssh::run(ctx, key),
     };
 
-    if let Err(err) = res {
-        error!("{:?}", err);
-        std::process::exit(1);
-    }
+    Ok(res?)
+}
 
-    Ok(())
+/// Run the shpool tool as the `shpool` binary does, logging any error
+/// and turning the outcome into an exit code for `main` to return.
+pub fn run_cli(args: Args, hooks: Option<Box<dyn hooks::Hooks + Send + Sync>>) -> ExitCode {
+    match run(args, hooks) {
+        // Exit statuses only have 8 bits, the same as process::exit gives.
+        Ok(status) => ExitCode::from(status as u8),
+        Err(err) => {
+            error!("{:?}", err);
+            ExitCode::from(err.exit_status())
+        }
+    }
 }
 
 struct NoopHooks {}
@@ -12,39 +12,440 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{io, path::PathBuf, time};
+use std::{env, time};
 
 use anyhow::Context;
-use shpool_protocol::{ConnectHeader, ListReply};
+use clap::ColorChoice;
+use nix::unistd::isatty;
+use shpool_protocol::{
+    ConnectHeader, ExitRecord, ListReply, NetUsage, Session, TerminalCaps, TtySize,
+};
 
-use crate::{protocol, protocol::ClientResult};
+use crate::{consts, context::ClientContext, duration, top, tty::TtySizeExt as _};
 
-pub fn run(socket: PathBuf) -> anyhow::Result<()> {
-    let mut client = match protocol::Client::new(socket) {
-        Ok(ClientResult::JustClient(c)) => c,
-        Ok(ClientResult::VersionMismatch { warning, client }) => {
-            eprintln!("warning: {}, try restarting your daemon", warning);
-            client
-        }
-        Err(err) => {
-            let io_err = err.downcast::<io::Error>()?;
-            if io_err.kind() == io::ErrorKind::NotFound {
-                eprintln!("could not connect to daemon");
+pub fn run(long: bool, color: ColorChoice, ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
+    let reply: ListReply = client.read_reply().context("reading reply")?;
+
+    for line in render(&Style::detect(color), long, &reply) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// How to lay out the list.
+struct Style {
+    /// When set, the list is going to a terminal this many columns
+    /// wide, so the columns get aligned and cut down to fit. Otherwise
+    /// they are separated by tabs, which is easier to script against.
+    width: Option<usize>,
+    /// Color the status of each session.
+    color: bool,
+    /// The current time, for showing times relative to it.
+    now: time::SystemTime,
+}
+
+impl Style {
+    fn detect(color: ColorChoice) -> Self {
+        let is_tty = isatty(consts::STDOUT_FD).unwrap_or(false);
+        let width = if is_tty {
+            TtySize::from_fd(consts::STDOUT_FD).ok().map(|s| s.cols as usize).filter(|c| *c > 0)
+        } else {
+            None
+        };
+        let color = match color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            // https://no-color.org
+            ColorChoice::Auto => {
+                is_tty && env::var_os("NO_COLOR").map(|v| v.is_empty()).unwrap_or(true)
             }
-            return Err(io_err).context("connecting to daemon");
+        };
+        Style { width, color, now: time::SystemTime::now() }
+    }
+
+    /// Show a time as a timestamp for scripts, or as how long ago it was
+    /// for people.
+    fn time(&self, unix_ms: i64) -> String {
+        if self.width.is_none() {
+            return format_unix_ms(unix_ms);
         }
+        let then = time::UNIX_EPOCH + time::Duration::from_millis(unix_ms.max(0) as u64);
+        describe_ago(self.now.duration_since(then).unwrap_or_default())
+    }
+
+    fn status(&self, status: &str) -> String {
+        let code = match status {
+            "attached" => "32",
+            "disconnected" => "33",
+            "exited" => "31",
+            "suspended" => "36",
+            _ => return String::from(status),
+        };
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, status)
+        } else {
+            String::from(status)
+        }
+    }
+}
+
+/// A session's row in the list, along with its recent exits.
+struct Row {
+    cells: Vec<String>,
+    exits: Vec<(String, String)>,
+}
+
+/// The column that gets colored in.
+const STATUS_COL: usize = 2;
+
+fn render(style: &Style, long: bool, reply: &ListReply) -> Vec<String> {
+    let exit_lines = |exits: &[ExitRecord]| -> Vec<(String, String)> {
+        // newest first
+        exits.iter().rev().map(|e| (style.time(e.exited_at_unix_ms), describe_exit(e))).collect()
     };
 
-    client.write_connect_header(ConnectHeader::List).context("sending list connect header")?;
-    let reply: ListReply = client.read_reply().context("reading reply")?;
+    let mut rows = vec![];
+    if !long {
+        let header = ["NAME", "STARTED_AT", "STATUS", "TTL"];
+        for session in reply.sessions.iter() {
+            rows.push(Row {
+                cells: vec![
+                    session.name.clone(),
+                    style.time(session.started_at_unix_ms),
+                    describe_status(session),
+                    describe_ttl(session.reap_in_secs),
+                ],
+                exits: vec![],
+            });
+        }
+        return layout(style, &header, &rows);
+    }
 
-    println!("NAME\tSTARTED_AT\tSTATUS");
+    let no_exits = vec![];
+    let exits_of = |name: &str| {
+        reply.exit_history.iter().find(|e| e.name == name).map(|e| &e.exits).unwrap_or(&no_exits)
+    };
+    let header = ["NAME", "STARTED_AT", "STATUS", "EXITS", "TERMINAL", "NET", "MEM", "TTL"];
     for session in reply.sessions.iter() {
-        let started_at =
-            time::UNIX_EPOCH + time::Duration::from_millis(session.started_at_unix_ms as u64);
-        let started_at = chrono::DateTime::<chrono::Utc>::from(started_at);
-        println!("{}\t{}\t{}", session.name, started_at.to_rfc3339(), session.status);
+        let exits = exits_of(&session.name);
+        rows.push(Row {
+            cells: vec![
+                session.name.clone(),
+                style.time(session.started_at_unix_ms),
+                describe_status(session),
+                exits.len().to_string(),
+                describe_terminal(&session.terminal),
+                describe_net(&session.net),
+                top::human_bytes(session.memory.total()),
+                describe_ttl(session.reap_in_secs),
+            ],
+            exits: exit_lines(exits),
+        });
     }
+    // sessions that are gone, but exited recently
+    for gone in reply.exit_history.iter() {
+        if reply.sessions.iter().any(|s| s.name == gone.name) {
+            continue;
+        }
+        let mut cells = vec![gone.name.clone(), String::from("-"), String::from("exited")];
+        cells.push(gone.exits.len().to_string());
+        cells.extend(std::iter::repeat(String::from("-")).take(4));
+        rows.push(Row { cells, exits: exit_lines(&gone.exits) });
+    }
+    layout(style, &header, &rows)
+}
 
-    Ok(())
+/// Turn the rows into lines of output, with the exits indented under
+/// the session they belong to.
+fn layout(style: &Style, header: &[&str], rows: &[Row]) -> Vec<String> {
+    let Some(width) = style.width else {
+        let mut lines = vec![header.join("\t")];
+        for row in rows.iter() {
+            let cells: Vec<String> = row
+                .cells
+                .iter()
+                .enumerate()
+                .map(|(i, c)| if i == STATUS_COL { style.status(c) } else { c.clone() })
+                .collect();
+            lines.push(cells.join("\t"));
+            for (at, desc) in row.exits.iter() {
+                lines.push(format!("\t{}\t{}", at, desc));
+            }
+        }
+        return lines;
+    };
+
+    let mut col_widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in rows.iter() {
+        for (w, cell) in col_widths.iter_mut().zip(row.cells.iter()) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+
+    let header: Vec<String> = header.iter().map(|h| String::from(*h)).collect();
+    let mut lines = vec![fit_row(style, &header, &col_widths, width, false)];
+    for row in rows.iter() {
+        lines.push(fit_row(style, &row.cells, &col_widths, width, true));
+        for (at, desc) in row.exits.iter() {
+            lines.push(truncate(&format!("  {}  {}", at, desc), width));
+        }
+    }
+    lines
+}
+
+/// Pad out the cells of a row into aligned columns, cutting the row
+/// off once it hits the edge of the terminal.
+fn fit_row(
+    style: &Style,
+    cells: &[String],
+    col_widths: &[usize],
+    width: usize,
+    color: bool,
+) -> String {
+    let mut line = String::new();
+    let mut remaining = width;
+    for (i, (cell, col_width)) in cells.iter().zip(col_widths.iter()).enumerate() {
+        let last = i + 1 == cells.len();
+        let padded = if last { cell.clone() } else { format!("{:<w$}  ", cell, w = col_width) };
+        let fits = padded.chars().count() <= remaining;
+        let text = if fits { padded } else { truncate(&padded, remaining) };
+        remaining = remaining.saturating_sub(text.chars().count());
+
+        if color && i == STATUS_COL {
+            // color just the status, not the padding after it
+            let visible = text.trim_end();
+            line.push_str(&style.status(visible));
+            line.push_str(&text[visible.len()..]);
+        } else {
+            line.push_str(&text);
+        }
+        if !fits {
+            break;
+        }
+    }
+    line.trim_end().to_string()
+}
+
+/// Cut `s` down to at most `width` chars, marking the cut.
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return String::from(s);
+    }
+    if width == 0 {
+        return String::new();
+    }
+    let mut cut: String = s.chars().take(width - 1).collect();
+    cut.push('…');
+    cut
+}
+
+/// Describe how long ago something happened in the single largest unit,
+/// as in '3h ago'.
+fn describe_ago(elapsed: time::Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs == 0 {
+        return String::from("just now");
+    }
+    for (unit_secs, suffix) in [(60 * 60 * 24, 'd'), (60 * 60, 'h'), (60, 'm'), (1, 's')] {
+        if secs >= unit_secs {
+            return format!("{}{} ago", secs / unit_secs, suffix);
+        }
+    }
+    format!("{}s ago", secs)
+}
+
+fn describe_exit(exit: &ExitRecord) -> String {
+    let ran_for = duration::format(time::Duration::from_millis(
+        exit.exited_at_unix_ms.saturating_sub(exit.started_at_unix_ms).max(0) as u64,
+    ));
+    match exit.signal {
+        Some(signal) => format!("killed by signal {} after {}", signal, ran_for),
+        None => format!("exited with status {} after {}", exit.status, ran_for),
+    }
+}
+
+/// Summarize what the last client terminal could do as a comma
+/// separated list of features.
+fn describe_terminal(caps: &Option<TerminalCaps>) -> String {
+    let Some(caps) = caps else {
+        return String::from("-");
+    };
+
+    let mut features = vec![];
+    if caps.truecolor {
+        features.push("truecolor");
+    }
+    if caps.kitty_keyboard {
+        features.push("kitty-keyboard");
+    }
+    // DA1 attributes as assigned by DEC and extended by xterm
+    for (attr, feature) in [(4, "sixel"), (52, "osc52")] {
+        if caps.primary_attributes.contains(&attr) {
+            features.push(feature);
+        }
+    }
+
+    if features.is_empty() {
+        String::from("basic")
+    } else {
+        features.join(",")
+    }
+}
+
+/// Show network usage as received and sent byte counts, or a dash if
+/// the daemon isn't keeping track.
+fn describe_net(net: &Option<NetUsage>) -> String {
+    match net {
+        Some(net) => {
+            format!("rx {} tx {}", top::human_bytes(net.rx_bytes), top::human_bytes(net.tx_bytes))
+        }
+        None => String::from("-"),
+    }
+}
+
+/// A session whose client is suspended is still attached, but it's
+/// worth calling out since nobody is looking at its output.
+fn describe_status(session: &Session) -> String {
+    if session.client_suspended {
+        String::from("suspended")
+    } else {
+        session.status.to_string()
+    }
+}
+
+/// Show how long a session has left before its ttl is up, or a dash
+/// if it doesn't have one.
+fn describe_ttl(reap_in_secs: Option<u64>) -> String {
+    match reap_in_secs {
+        Some(secs) => duration::format(time::Duration::from_secs(secs)),
+        None => String::from("-"),
+    }
+}
+
+fn format_unix_ms(unix_ms: i64) -> String {
+    let t = time::UNIX_EPOCH + time::Duration::from_millis(unix_ms as u64);
+    chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339()
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::SessionStatus;
+
+    use super::*;
+
+    #[test]
+    fn exit_descriptions() {
+        let exit = ExitRecord {
+            status: 3,
+            signal: None,
+            started_at_unix_ms: 1_000,
+            exited_at_unix_ms: 91_000,
+        };
+        assert_eq!(
+            describe_exit(&exit),
+            format!(
+                "exited with status 3 after {}",
+                duration::format(time::Duration::from_secs(90))
+            )
+        );
+
+        let killed = ExitRecord { status: 1, signal: Some(9), ..exit };
+        assert!(describe_exit(&killed).starts_with("killed by signal 9 after "));
+    }
+
+    #[test]
+    fn terminal_descriptions() {
+        assert_eq!(describe_terminal(&None), "-");
+        assert_eq!(describe_terminal(&Some(TerminalCaps::default())), "basic");
+        let caps = TerminalCaps {
+            primary_attributes: vec![62, 4, 22, 52],
+            secondary_attributes: vec![41, 390, 0],
+            truecolor: true,
+            kitty_keyboard: true,
+        };
+        assert_eq!(describe_terminal(&Some(caps)), "truecolor,kitty-keyboard,sixel,osc52");
+    }
+
+    #[test]
+    fn ttl_descriptions() {
+        assert_eq!(describe_ttl(None), "-");
+        assert_eq!(describe_ttl(Some(90)), duration::format(time::Duration::from_secs(90)));
+    }
+
+    fn listing() -> ListReply {
+        let session = |name: &str, status, started_at_unix_ms| Session {
+            name: String::from(name),
+            started_at_unix_ms,
+            status,
+            terminal: None,
+            net: None,
+            memory: Default::default(),
+            reap_in_secs: None,
+            client_suspended: false,
+        };
+        ListReply {
+            sessions: vec![
+                session("main", SessionStatus::Attached, 0),
+                session("a-much-longer-name", SessionStatus::Disconnected, 3_600_000),
+            ],
+            exit_history: vec![],
+        }
+    }
+
+    fn style(width: Option<usize>, color: bool) -> Style {
+        Style { width, color, now: time::UNIX_EPOCH + time::Duration::from_secs(2 * 60 * 60) }
+    }
+
+    #[test]
+    fn tab_separated_for_scripts() {
+        let lines = render(&style(None, false), false, &listing());
+        assert_eq!(
+            lines,
+            vec![
+                "NAME\tSTARTED_AT\tSTATUS\tTTL",
+                "main\t1970-01-01T00:00:00+00:00\tattached\t-",
+                "a-much-longer-name\t1970-01-01T01:00:00+00:00\tdisconnected\t-",
+            ]
+        );
+    }
+
+    #[test]
+    fn aligned_for_terminals() {
+        let lines = render(&style(Some(80), false), false, &listing());
+        assert_eq!(
+            lines,
+            vec![
+                "NAME                STARTED_AT  STATUS        TTL",
+                "main                2h ago      attached      -",
+                "a-much-longer-name  1h ago      disconnected  -",
+            ]
+        );
+
+        let lines = render(&style(Some(24), false), false, &listing());
+        assert_eq!(lines[1], "main                2h …");
+        assert!(lines.iter().all(|l| l.chars().count() <= 24));
+
+        let lines = render(&style(Some(80), true), false, &listing());
+        assert_eq!(lines[1], "main                2h ago      \x1b[32mattached\x1b[0m      -");
+    }
+
+    #[test]
+    fn ago_descriptions() {
+        assert_eq!(describe_ago(time::Duration::from_millis(300)), "just now");
+        assert_eq!(describe_ago(time::Duration::from_secs(45)), "45s ago");
+        assert_eq!(describe_ago(time::Duration::from_secs(2 * 60 * 60 + 5)), "2h ago");
+        assert_eq!(describe_ago(time::Duration::from_secs(3 * 24 * 60 * 60)), "3d ago");
+    }
+
+    #[test]
+    fn net_descriptions() {
+        assert_eq!(describe_net(&None), "-");
+        assert_eq!(
+            describe_net(&Some(NetUsage { rx_bytes: 3 * 1024 * 1024, tx_bytes: 12 })),
+            "rx 3.0MiB tx 12B"
+        );
+    }
 }
@@ -0,0 +1,108 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Changing the daemon's log filter while it is running, so that debug
+//! logs of a live problem can be captured without a restart that would
+//! take all of the sessions down with it.
+
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, LogLevelReply, LogLevelRequest, LogLevelStatus};
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::context::ClientContext;
+
+lazy_static::lazy_static! {
+    /// The handle for swapping out the filter, if logging was set up
+    /// by `run` in this process.
+    static ref FILTER_HANDLE: Mutex<Option<reload::Handle<EnvFilter, Registry>>> =
+        Mutex::new(None);
+}
+
+/// Remember the handle for the filter that `run` set up logging with.
+pub fn install(handle: reload::Handle<EnvFilter, Registry>) {
+    *FILTER_HANDLE.lock().unwrap() = Some(handle);
+}
+
+/// Swap in the given filter, or just report the current one if it
+/// is empty.
+pub fn apply(filter: &str) -> LogLevelStatus {
+    let handle = FILTER_HANDLE.lock().unwrap();
+    let Some(handle) = handle.as_ref() else {
+        return LogLevelStatus::Unsupported;
+    };
+
+    if !filter.is_empty() {
+        let new_filter = match EnvFilter::try_new(filter) {
+            Ok(f) => f,
+            Err(e) => return LogLevelStatus::Invalid { reason: e.to_string() },
+        };
+        if let Err(e) = handle.reload(new_filter) {
+            warn!("reloading log filter: {:?}", e);
+            return LogLevelStatus::Unsupported;
+        }
+        // tracing-log capped the log crate's max level based on the
+        // filter we started with, so open it back up and leave the
+        // filtering to the new filter.
+        log::set_max_level(log::LevelFilter::Trace);
+        info!("log filter changed to '{}'", filter);
+    }
+
+    match handle.with_current(|f| f.to_string()) {
+        Ok(current) => LogLevelStatus::Filter { current },
+        Err(_) => LogLevelStatus::Unsupported,
+    }
+}
+
+pub fn run(filter: Option<String>, ctx: &ClientContext) -> anyhow::Result<()> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::LogLevel(LogLevelRequest {
+            filter: filter.unwrap_or_default(),
+        }))
+        .context("writing log level request header")?;
+
+    let reply: LogLevelReply = client.read_reply().context("reading reply")?;
+
+    match reply.status {
+        LogLevelStatus::Filter { current } => {
+            println!("{}", current);
+            Ok(())
+        }
+        LogLevelStatus::Invalid { reason } => Err(anyhow!("invalid log filter: {}", reason)),
+        LogLevelStatus::Unsupported => {
+            Err(anyhow!("the daemon's log filter can't be changed while it is running"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn apply_filters() {
+        assert!(matches!(apply("debug"), LogLevelStatus::Unsupported));
+
+        let (_layer, handle) = reload::Layer::<_, Registry>::new(EnvFilter::new("info"));
+        install(handle);
+        assert!(matches!(apply(""), LogLevelStatus::Filter { current } if current == "info"));
+        assert!(matches!(apply("debug"), LogLevelStatus::Filter { current } if current == "debug"));
+        assert!(matches!(apply("=["), LogLevelStatus::Invalid { .. }));
+        assert!(matches!(apply(""), LogLevelStatus::Filter { current } if current == "debug"));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The catalog of messages that shpool shows users when something
+//! goes wrong with their session. Each one can be overridden with the
+//! `[messages]` config table, so that site admins can tell people where
+//! to go for help, or translate them.
+
+use crate::config;
+
+/// The variable that every message can use for the session name.
+pub const SESSION_NAME_VAR: &str = "SHPOOL_SESSION_NAME";
+/// The variable for why an attach was refused.
+pub const REASON_VAR: &str = "SHPOOL_REASON";
+/// The variable for how long a session has left before its ttl is up.
+pub const TTL_REMAINING_VAR: &str = "SHPOOL_TTL_REMAINING";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Busy,
+    ForceAttachFailed,
+    Forbidden,
+    InvalidName,
+    TtlWarning,
+}
+
+impl Message {
+    fn default_template(&self) -> &'static str {
+        match self {
+            Message::Busy => "session '$SHPOOL_SESSION_NAME' already has a terminal attached",
+            Message::ForceAttachFailed => {
+                "session '$SHPOOL_SESSION_NAME' already has a terminal which remains attached \
+                 even after attempting to detach it"
+            }
+            Message::Forbidden => "forbidden: $SHPOOL_REASON",
+            Message::InvalidName => "invalid session name: $SHPOOL_REASON",
+            Message::TtlWarning => {
+                "session '$SHPOOL_SESSION_NAME' will be killed in $SHPOOL_TTL_REMAINING when \
+                 its ttl expires, run 'shpool ttl extend $SHPOOL_SESSION_NAME <duration>' to \
+                 keep it around"
+            }
+        }
+    }
+
+    fn configured<'a>(&self, messages: &'a config::Messages) -> Option<&'a str> {
+        match self {
+            Message::Busy => messages.busy.as_deref(),
+            Message::ForceAttachFailed => messages.force_attach_failed.as_deref(),
+            Message::Forbidden => messages.forbidden.as_deref(),
+            Message::InvalidName => messages.invalid_name.as_deref(),
+            Message::TtlWarning => messages.ttl_warning.as_deref(),
+        }
+    }
+}
+
+/// Render the given message for a session, using the override from
+/// the config if there is one. `vars` fills in any variables beyond
+/// the session name.
+pub fn render(
+    config: &config::Config,
+    msg: Message,
+    session_name: &str,
+    vars: &[(&str, &str)],
+) -> String {
+    let template =
+        config.messages.as_ref().and_then(|m| msg.configured(m)).unwrap_or(msg.default_template());
+
+    let mut all_vars = vec![(SESSION_NAME_VAR, session_name)];
+    all_vars.extend_from_slice(vars);
+    substitute(template, &all_vars)
+}
+
+/// Replace every `$VAR` in the template in a single pass, so that
+/// values which happen to contain a `$VAR` of their own don't get
+/// expanded again. Unknown variables are left alone.
+fn substitute(template: &str, vars: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(idx) = rest.find('$') {
+        out.push_str(&rest[..idx]);
+        let after = &rest[idx + 1..];
+        // longest match, in case one name is a prefix of another
+        let var = vars
+            .iter()
+            .filter(|(name, _)| after.starts_with(name))
+            .max_by_key(|(name, _)| name.len());
+        match var {
+            Some((name, value)) => {
+                out.push_str(value);
+                rest = &after[name.len()..];
+            }
+            None => {
+                out.push('$');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = config::Config::default();
+        assert_eq!(
+            render(&config, Message::Busy, "main", &[]),
+            "session 'main' already has a terminal attached"
+        );
+        assert_eq!(
+            render(&config, Message::Forbidden, "main", &[(REASON_VAR, "no vim")]),
+            "forbidden: no vim"
+        );
+        assert_eq!(
+            render(&config, Message::TtlWarning, "build", &[(TTL_REMAINING_VAR, "5m")]),
+            "session 'build' will be killed in 5m when its ttl expires, run \
+             'shpool ttl extend build <duration>' to keep it around"
+        );
+    }
+
+    #[test]
+    fn overrides() -> anyhow::Result<()> {
+        let config: config::Config = toml::from_str(
+            r#"
+            [messages]
+            forbidden = "$SHPOOL_SESSION_NAME: $SHPOOL_REASON, ask in #help-infra"
+            "#,
+        )?;
+        assert_eq!(
+            render(&config, Message::Forbidden, "main", &[(REASON_VAR, "no vim")]),
+            "main: no vim, ask in #help-infra"
+        );
+        // the rest keep their defaults
+        assert_eq!(
+            render(&config, Message::InvalidName, "x", &[(REASON_VAR, "too long")]),
+            "invalid session name: too long"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn substitution() {
+        let vars = [("A", "1"), ("AB", "2"), ("C", "$A")];
+        let cases = vec![
+            ("$A-$AB", "1-2"),
+            ("$$A", "$1"),
+            ("$UNKNOWN $", "$UNKNOWN $"),
+            ("$C", "$A"),
+            ("no vars", "no vars"),
+        ];
+        for (template, want) in cases.into_iter() {
+            assert_eq!(substitute(template, &vars), want, "template={}", template);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! User facing output for the client subcommands.
+//!
+//! Subcommands print what they have to say through here rather than
+//! straight to stdout or stderr, so that the global --quiet and
+//! --porcelain flags apply the same way everywhere.
+//!
+//! In porcelain mode, a subcommand reports its outcome as a single
+//! line of the form `<command> <status> [<session>...]`, separated by
+//! single spaces. Session names can't contain whitespace, so the line
+//! splits cleanly. The statuses are part of shpool's interface, so
+//! existing ones must not change meaning.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Say what is going on, for people.
+    Normal = 0,
+    /// Only print errors and the output that was asked for.
+    Quiet = 1,
+    /// Only print errors on stderr and machine readable results.
+    Porcelain = 2,
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Normal as u8);
+
+/// Set the output mode for the rest of the process.
+pub fn set_mode(mode: Mode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Quiet,
+        2 => Mode::Porcelain,
+        _ => Mode::Normal,
+    }
+}
+
+/// Whether to print banners and other chatter.
+pub fn chatty() -> bool {
+    mode() == Mode::Normal
+}
+
+/// Chatter for people, like notes about what just happened. Dropped
+/// by both --quiet and --porcelain.
+pub fn note<D: fmt::Display>(msg: D) {
+    if chatty() {
+        eprintln!("{}", msg);
+    }
+}
+
+/// Something that might need the user's attention, but didn't stop
+/// the command. Dropped by --quiet.
+pub fn warning<D: fmt::Display>(msg: D) {
+    if mode() != Mode::Quiet {
+        eprintln!("{}", msg);
+    }
+}
+
+/// Why the command failed. Always printed.
+pub fn error<D: fmt::Display>(msg: D) {
+    eprintln!("{}", msg);
+}
+
+/// Output the user asked for, such as the sessions a dry run would
+/// touch. Porcelain mode reports it with `result` instead.
+pub fn listing<D: fmt::Display>(line: D) {
+    if mode() != Mode::Porcelain {
+        println!("{}", line);
+    }
+}
+
+/// Report the outcome of a command in porcelain mode.
+pub fn result(command: &str, status: &str, sessions: &[String]) {
+    if mode() == Mode::Porcelain {
+        println!("{}", porcelain_line(command, status, sessions));
+    }
+}
+
+/// Like `result`, but for commands whose stdout is spoken for, such
+/// as attach, where it carries the session's output.
+pub fn result_to_stderr(command: &str, status: &str, sessions: &[String]) {
+    if mode() == Mode::Porcelain {
+        eprintln!("{}", porcelain_line(command, status, sessions));
+    }
+}
+
+fn porcelain_line(command: &str, status: &str, sessions: &[String]) -> String {
+    let mut line = format!("{} {}", command, status);
+    for session in sessions.iter() {
+        line.push(' ');
+        line.push_str(session);
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn porcelain_lines() {
+        assert_eq!(porcelain_line("kill", "ok", &[]), "kill ok");
+        assert_eq!(
+            porcelain_line("detach", "not-found", &[String::from("a"), String::from("b")]),
+            "detach not-found a b"
+        );
+    }
+}
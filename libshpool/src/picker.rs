@@ -0,0 +1,315 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small fuzzy finder for picking a session to attach to, which is
+//! what `shpool attach` does when it is run on a terminal without a
+//! session name. Typing narrows down the list, the arrow keys (or
+//! Ctrl-p and Ctrl-n) move the selection, enter attaches and escape
+//! or Ctrl-c gives up.
+//!
+//! The picker draws itself on stderr below the cursor rather than
+//! taking over the whole screen, and cleans up after itself when done.
+
+use std::{
+    io,
+    io::{Read, Write},
+    os::unix::io::AsRawFd,
+};
+
+use anyhow::Context;
+use nix::unistd::isatty;
+use shpool_protocol::{ConnectHeader, ListReply, TtySize};
+
+use crate::{consts, context::ClientContext, tty, tty::TtySizeExt as _};
+
+/// The most sessions we show at once.
+const MAX_ROWS: usize = 10;
+
+/// Check if we are on a terminal that the user can drive the picker from.
+pub fn available() -> bool {
+    isatty(io::stdin().as_raw_fd()).unwrap_or(false)
+        && isatty(io::stderr().as_raw_fd()).unwrap_or(false)
+}
+
+/// Let the user pick one of the daemon's sessions, returning None if
+/// they back out or there is nothing to pick from.
+pub fn pick(ctx: &ClientContext) -> anyhow::Result<Option<String>> {
+    let sessions = list(ctx)?;
+    if sessions.is_empty() {
+        eprintln!("no sessions to attach to, pass a name to create one");
+        return Ok(None);
+    }
+
+    let _tty_guard = tty::set_attach_flags()?;
+    let mut picker = Picker::new(sessions);
+    let mut stderr = io::stderr().lock();
+    let mut stdin = io::stdin().lock();
+    let mut buf = [0; 64];
+    let choice = loop {
+        picker.draw(&mut stderr)?;
+        let len = stdin.read(&mut buf).context("reading keys")?;
+        if len == 0 {
+            break None;
+        }
+        if let Some(outcome) = keys(&buf[..len]).find_map(|k| picker.handle(k)) {
+            break outcome;
+        }
+    };
+    picker.clear(&mut stderr)?;
+
+    Ok(choice)
+}
+
+fn list(ctx: &ClientContext) -> anyhow::Result<Vec<(String, String)>> {
+    let reply: ListReply = ctx.request(ConnectHeader::List).context("listing sessions")?;
+    Ok(reply.sessions.into_iter().map(|s| (s.name, s.status.to_string())).collect())
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Key {
+    Char(char),
+    Backspace,
+    Up,
+    Down,
+    Enter,
+    Cancel,
+    Ignored,
+}
+
+/// Decode a chunk of raw terminal input into keys. An escape on its
+/// own is a cancel, but escape sequences we don't know get dropped.
+fn keys(buf: &[u8]) -> impl Iterator<Item = Key> + '_ {
+    let s = String::from_utf8_lossy(buf).into_owned();
+    let mut chars = s.chars().collect::<Vec<_>>().into_iter().peekable();
+    std::iter::from_fn(move || {
+        let c = chars.next()?;
+        Some(match c {
+            '\x1b' => match chars.peek() {
+                Some('[') | Some('O') => {
+                    chars.next();
+                    // skip any parameters up to the final byte
+                    let mut last = None;
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() || c == '~' {
+                            last = Some(c);
+                            break;
+                        }
+                    }
+                    match last {
+                        Some('A') => Key::Up,
+                        Some('B') => Key::Down,
+                        _ => Key::Ignored,
+                    }
+                }
+                _ => Key::Cancel,
+            },
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x10' => Key::Up,   // Ctrl-p
+            '\x0e' => Key::Down, // Ctrl-n
+            '\x03' | '\x04' => Key::Cancel,
+            c if c.is_control() => Key::Ignored,
+            c => Key::Char(c),
+        })
+    })
+}
+
+struct Picker {
+    /// Session names along with their status.
+    sessions: Vec<(String, String)>,
+    query: String,
+    /// The matching sessions, best first, as indexes into `sessions`.
+    matches: Vec<usize>,
+    selected: usize,
+}
+
+impl Picker {
+    fn new(sessions: Vec<(String, String)>) -> Self {
+        let mut picker = Picker { sessions, query: String::new(), matches: vec![], selected: 0 };
+        picker.refilter();
+        picker
+    }
+
+    /// Apply a key, returning the outcome once the user is done.
+    fn handle(&mut self, key: Key) -> Option<Option<String>> {
+        match key {
+            Key::Char(c) => {
+                self.query.push(c);
+                self.refilter();
+            }
+            Key::Backspace => {
+                self.query.pop();
+                self.refilter();
+            }
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => {
+                self.selected = (self.selected + 1).min(self.matches.len().saturating_sub(1))
+            }
+            Key::Enter => {
+                // with nothing matching, there is nothing to attach to
+                return self.matches.get(self.selected).map(|i| Some(self.sessions[*i].0.clone()));
+            }
+            Key::Cancel => return Some(None),
+            Key::Ignored => {}
+        }
+        None
+    }
+
+    fn refilter(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .sessions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (name, _))| score(&self.query, name).map(|s| (s, i)))
+            .collect();
+        // stable, so equally good matches stay in list order
+        scored.sort_by_key(|(s, _)| -s);
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = 0;
+    }
+
+    fn draw<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        let cols = TtySize::from_fd(consts::STDERR_FD).map(|s| s.cols as usize).unwrap_or(80);
+        let fit = |s: String| -> String { s.chars().take(cols.saturating_sub(1)).collect() };
+
+        self.rewind(w)?;
+        // the terminal is in raw mode, so we need explicit carriage returns
+        write!(w, "{}", fit(format!("attach to: {}", self.query)))?;
+        let rows = self.matches.len().min(MAX_ROWS);
+        // keep the selection in view
+        let first = (self.selected + 1).saturating_sub(rows);
+        for (row, i) in self.matches.iter().skip(first).take(rows).enumerate() {
+            let (name, status) = &self.sessions[*i];
+            let line = fit(format!("  {}  ({})", name, status));
+            if first + row == self.selected {
+                write!(w, "\r\n\x1b[7m{}\x1b[0m", line)?;
+            } else {
+                write!(w, "\r\n{}", line)?;
+            }
+        }
+        if rows == 0 {
+            write!(w, "\r\n  no matching sessions")?;
+        }
+
+        // put the cursor back at the end of the prompt
+        let prompt_len = fit(format!("attach to: {}", self.query)).chars().count();
+        write!(w, "\x1b[{}A\r\x1b[{}C", rows.max(1), prompt_len)?;
+        w.flush().context("flushing picker")?;
+        Ok(())
+    }
+
+    fn clear<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        self.rewind(w)?;
+        w.flush().context("flushing picker")?;
+        Ok(())
+    }
+
+    /// Move to the start of the prompt line and wipe everything below.
+    fn rewind<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        write!(w, "\r\x1b[J").context("clearing picker")?;
+        Ok(())
+    }
+}
+
+/// Fuzzy match `query` against `name`, returning None if the characters
+/// of the query don't all show up in order in the name, or a score that
+/// is higher for tighter matches nearer the start of the name.
+fn score(query: &str, name: &str) -> Option<i64> {
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut last_match: Option<usize> = None;
+    for q in query.chars().flat_map(char::to_lowercase) {
+        let found = pos + name[pos..].iter().position(|c| *c == q)?;
+        score += match last_match {
+            Some(last) if found == last + 1 => 10,
+            None if found == 0 => 10,
+            // matching at the start of a word is nearly as good
+            _ if found == 0 || !name[found - 1].is_alphanumeric() => 8,
+            Some(last) => -((found - last) as i64).min(5),
+            None => -(found as i64).min(5),
+        };
+        last_match = Some(found);
+        pos = found + 1;
+    }
+    Some(score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sessions(names: &[&str]) -> Vec<(String, String)> {
+        names.iter().map(|n| (String::from(*n), String::from("disconnected"))).collect()
+    }
+
+    fn matched_names(picker: &Picker) -> Vec<&str> {
+        picker.matches.iter().map(|i| picker.sessions[*i].0.as_str()).collect()
+    }
+
+    #[test]
+    fn scoring() {
+        assert_eq!(score("", "anything"), Some(0));
+        assert_eq!(score("wk", "work"), score("wk", "work"));
+        assert!(score("xyz", "work").is_none());
+        assert!(score("kw", "work").is_none());
+        assert!(score("WORK", "work").is_some());
+        assert!(score("wor", "work") > score("wor", "w-o-r"));
+        assert!(score("dev", "dev-box") > score("dev", "my-devbox"));
+        assert!(score("dev", "my-devbox") > score("dev", "mydevbox"));
+    }
+
+    #[test]
+    fn key_decoding() {
+        let decoded: Vec<Key> = keys(b"ab\x1b[A\x1b[B\x1bOA\x7f\r\x1b[1;5C\x03").collect();
+        assert_eq!(
+            decoded,
+            vec![
+                Key::Char('a'),
+                Key::Char('b'),
+                Key::Up,
+                Key::Down,
+                Key::Up,
+                Key::Backspace,
+                Key::Enter,
+                Key::Ignored,
+                Key::Cancel,
+            ]
+        );
+        assert_eq!(keys(b"\x1b").collect::<Vec<_>>(), vec![Key::Cancel]);
+    }
+
+    #[test]
+    fn picking() {
+        let mut picker = Picker::new(sessions(&["main", "work-1", "work-2", "scratch"]));
+        assert_eq!(matched_names(&picker), vec!["main", "work-1", "work-2", "scratch"]);
+
+        for c in "w2".chars() {
+            assert_eq!(picker.handle(Key::Char(c)), None);
+        }
+        assert_eq!(matched_names(&picker), vec!["work-2"]);
+
+        picker.handle(Key::Backspace);
+        assert_eq!(matched_names(&picker), vec!["work-1", "work-2"]);
+        picker.handle(Key::Down);
+        picker.handle(Key::Down);
+        assert_eq!(picker.handle(Key::Enter), Some(Some(String::from("work-2"))));
+        picker.handle(Key::Up);
+        assert_eq!(picker.handle(Key::Enter), Some(Some(String::from("work-1"))));
+
+        picker.handle(Key::Char('z'));
+        assert_eq!(picker.handle(Key::Enter), None);
+        assert_eq!(picker.handle(Key::Cancel), Some(None));
+    }
+}
@@ -0,0 +1,101 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    io, net,
+    process::{Command, Stdio},
+    thread,
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, PipeReply, PipeRequest, PipeStatus};
+use tracing::{info, warn};
+
+use crate::{context::ClientContext, protocol, Error};
+
+/// Stream the output of a session into the stdin of `cmd`, or to
+/// stdout if there is no command, until the session exits or the
+/// command stops reading.
+///
+/// Return value: the exit status of the command, or 0 if there is none.
+pub fn run(
+    session: String,
+    from_start: bool,
+    cmd: Vec<String>,
+    ctx: &ClientContext,
+) -> anyhow::Result<i32> {
+    let client = start(&session, from_start, ctx)?;
+
+    let Some((prog, args)) = cmd.split_first() else {
+        client.pipe_output(&mut io::stdout().lock())?;
+        return Ok(0);
+    };
+
+    let mut child = Command::new(prog)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("running '{}'", prog))?;
+    let mut stdin = child.stdin.take().ok_or(anyhow!("no stdin for command"))?;
+    let hangup = client.hangup_handle()?;
+
+    thread::scope(|s| {
+        // If the command exits on its own, there is no point waiting
+        // around for more output.
+        let waiter = s.spawn(|| {
+            let status = child.wait();
+            if let Err(e) = hangup.shutdown(net::Shutdown::Both) {
+                warn!("hanging up on daemon: {:?}", e);
+            }
+            status
+        });
+
+        let piped = client.pipe_output(&mut stdin);
+        // let the command see EOF
+        drop(stdin);
+
+        let status = waiter
+            .join()
+            .map_err(|_| anyhow!("joining command waiter"))?
+            .context("waiting for command")?;
+        info!("command exited with {:?}", status);
+        piped?;
+        Ok(status.code().unwrap_or(1))
+    })
+}
+
+/// Ask the daemon to stream the output of `session`, leaving the
+/// caller to read it off of the returned client.
+pub fn start(
+    session: &str,
+    from_start: bool,
+    ctx: &ClientContext,
+) -> anyhow::Result<protocol::Client> {
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::Pipe(PipeRequest {
+            session: String::from(session),
+            from_start,
+        }))
+        .context("writing pipe request header")?;
+
+    let reply: PipeReply = client.read_reply().context("reading reply")?;
+    if reply.status == PipeStatus::NotFound {
+        eprintln!("not found: {}", session);
+        return Err(Error::SessionsNotFound(vec![String::from(session)]).into());
+    }
+
+    Ok(client)
+}
@@ -0,0 +1,290 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The parts of shpool that work differently from one OS to the next.
+//!
+//! shpool grew up on Linux and leans on procfs and a handful of Linux
+//! only syscalls. Rather than scattering cfgs through the daemon, each
+//! platform dependent operation lives here with one implementation per
+//! platform. Anything a platform can't do returns an error with kind
+//! `io::ErrorKind::Unsupported` so that callers can decide how to
+//! degrade, which is usually to log and carry on. The one exception is
+//! network accounting, which is big enough to get its own pair of
+//! modules, `daemon/net_stat.rs` and `daemon/net_stat_unsupported.rs`.
+//!
+//! | operation              | Linux        | FreeBSD              | other BSDs, macOS |
+//! |------------------------|--------------|----------------------|-------------------|
+//! | peer creds             | uid and pid  | uid only             | uid only          |
+//! | exe / cwd of a pid     | procfs       | sysctl / unsupported | unsupported       |
+//! | controlling tty        | on open      | TIOCSCTTY            | TIOCSCTTY         |
+//! | wtmp                   | updwtmpx     | done by pututxline   | done by pututxline|
+//! | ionice, oom, cpu pins  | yes          | unsupported          | unsupported       |
+//! | network accounting     | sock_diag    | reports nothing      | reports nothing   |
+//!
+//! OpenBSD is not supported yet, since it has neither utmpx nor the
+//! `ptsname_r` that shpool_pty relies on.
+
+use std::{
+    io,
+    os::{fd::BorrowedFd, unix::net::UnixStream},
+    path::PathBuf,
+};
+
+use nix::unistd::Pid;
+
+#[cfg_attr(any(target_os = "linux", target_os = "android"), allow(dead_code))]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported on {}", what, std::env::consts::OS),
+    )
+}
+
+/// The credentials of the process on the other end of a unix socket.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCreds {
+    pub uid: u32,
+    /// Only Linux reports the pid of the peer.
+    pub pid: Option<i32>,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_creds(sock: &UnixStream) -> io::Result<PeerCreds> {
+    use nix::sys::socket;
+
+    let creds = socket::getsockopt(sock, socket::sockopt::PeerCredentials)?;
+    Ok(PeerCreds { uid: creds.uid(), pid: Some(creds.pid()) })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_creds(sock: &UnixStream) -> io::Result<PeerCreds> {
+    let (uid, _gid) = nix::unistd::getpeereid(sock)?;
+    Ok(PeerCreds { uid: uid.as_raw(), pid: None })
+}
+
+/// The path of the executable that the given process is running.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn exe_for_pid(pid: Pid) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid))
+}
+
+/// The path of the executable that the given process is running.
+#[cfg(target_os = "freebsd")]
+pub fn exe_for_pid(pid: Pid) -> io::Result<PathBuf> {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt as _, ptr};
+
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PATHNAME, pid.as_raw()];
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let mut len = buf.len();
+    // Safety: mib and buf are valid for the lengths we pass, and the
+    //         kernel writes at most len bytes into buf.
+    let res = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // len includes the trailing nul
+    buf.truncate(len.saturating_sub(1));
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+}
+
+/// The path of the executable that the given process is running.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub fn exe_for_pid(_pid: Pid) -> io::Result<PathBuf> {
+    Err(unsupported("finding the exe of another process"))
+}
+
+/// The current working directory of the given process.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn cwd_for_pid(pid: Pid) -> io::Result<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/cwd", pid))
+}
+
+/// The current working directory of the given process.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn cwd_for_pid(_pid: Pid) -> io::Result<PathBuf> {
+    Err(unsupported("finding the cwd of another process"))
+}
+
+/// The short name of the program the given process is running, as
+/// `ps` would show it.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub fn process_name(pid: Pid) -> io::Result<String> {
+    libproc::proc_pid::name(pid.as_raw()).map_err(io::Error::other)
+}
+
+/// The short name of the program the given process is running, as
+/// `ps` would show it.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub fn process_name(pid: Pid) -> io::Result<String> {
+    let exe = exe_for_pid(pid)?;
+    exe.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "exe has no file name"))
+}
+
+/// A path that a shell running under the daemon can exec to run the
+/// daemon's own binary. On Linux this goes through procfs so that it
+/// keeps working even if the binary gets replaced by an upgrade.
+pub fn daemon_exe() -> io::Result<PathBuf> {
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        Ok(PathBuf::from(format!("/proc/{}/exe", std::process::id())))
+    } else {
+        std::env::current_exe()
+    }
+}
+
+/// Make the given tty the controlling terminal of the calling process,
+/// which must be a session leader. Linux does this implicitly when a
+/// session leader without a controlling terminal opens a tty, but the
+/// BSDs and macOS need to be asked explicitly.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn acquire_controlling_tty(_fd: BorrowedFd) -> io::Result<()> {
+    Ok(())
+}
+
+/// Make the given tty the controlling terminal of the calling process,
+/// which must be a session leader. Linux does this implicitly when a
+/// session leader without a controlling terminal opens a tty, but the
+/// BSDs and macOS need to be asked explicitly.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn acquire_controlling_tty(fd: BorrowedFd) -> io::Result<()> {
+    use std::os::fd::AsRawFd as _;
+
+    // Safety: basic ffi, TIOCSCTTY takes no pointer argument.
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCSCTTY as _, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Append a record to the wtmp login history.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn append_wtmp(entry: &libc::utmpx) -> io::Result<()> {
+    use std::ffi::CString;
+
+    extern "C" {
+        // glibc has this, but the libc crate doesn't expose it.
+        fn updwtmpx(wtmpx_file: *const libc::c_char, utmpx: *const libc::utmpx);
+    }
+
+    let wtmp = CString::new("/var/log/wtmp")?;
+    // Safety: entry is a valid utmpx, and wtmp is a valid c string.
+    unsafe { updwtmpx(wtmp.as_ptr(), entry) };
+    Ok(())
+}
+
+/// Append a record to the wtmp login history. The BSDs and macOS
+/// already log every record written with `pututxline`, so there is
+/// nothing extra to do.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn append_wtmp(_entry: &libc::utmpx) -> io::Result<()> {
+    Ok(())
+}
+
+/// Set the io scheduling class and priority of the calling process.
+/// `ioprio` is already in the packed form the kernel expects.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_io_priority(ioprio: libc::c_int) -> io::Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+    // Safety: basic ffi, 0 means the calling process.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the io scheduling class and priority of the calling process.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_io_priority(_ioprio: libc::c_int) -> io::Result<()> {
+    Err(unsupported("setting io priority"))
+}
+
+/// Adjust how likely the OOM killer is to pick the calling process.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_oom_score_adj(adj: i32) -> io::Result<()> {
+    std::fs::write("/proc/self/oom_score_adj", adj.to_string())
+}
+
+/// Adjust how likely the OOM killer is to pick the calling process.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_oom_score_adj(_adj: i32) -> io::Result<()> {
+    Err(unsupported("setting oom_score_adj"))
+}
+
+/// Pin the calling process to the given cpus.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_cpu_affinity(cpus: &[usize]) -> io::Result<()> {
+    // Safety: cpu_set_t is a plain bitmask, so all zeros is a valid
+    //         empty set, and CPU_SET panics rather than writing out of
+    //         bounds. 0 means the calling process.
+    let res = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus.iter() {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pin the calling process to the given cpus.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_cpu_affinity(_cpus: &[usize]) -> io::Result<()> {
+    Err(unsupported("setting cpu affinity"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn peer_creds_of_pair() -> anyhow::Result<()> {
+        let (a, _b) = UnixStream::pair()?;
+        let creds = peer_creds(&a)?;
+        assert_eq!(creds.uid, nix::unistd::Uid::current().as_raw());
+        if let Some(pid) = creds.pid {
+            assert_eq!(pid as u32, std::process::id());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn own_process() -> anyhow::Result<()> {
+        let me = Pid::this();
+        match exe_for_pid(me) {
+            Ok(exe) => assert_eq!(exe, std::env::current_exe()?),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        match cwd_for_pid(me) {
+            Ok(cwd) => assert_eq!(cwd, std::env::current_dir()?),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        assert!(!process_name(me)?.is_empty());
+        assert!(daemon_exe()?.exists());
+        Ok(())
+    }
+}
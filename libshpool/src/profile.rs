@@ -0,0 +1,51 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, ProfileReply, ProfileRequest, ProfileStatus};
+
+use crate::{context::ClientContext, duration, ProfileFormat};
+
+pub fn run(duration: String, format: ProfileFormat, ctx: &ClientContext) -> anyhow::Result<()> {
+    let duration = duration::parse(&duration).context("parsing profile duration")?;
+
+    let mut client = ctx.connect()?;
+    // the daemon only replies once it is done profiling
+    client.set_timeout(client.timeout().map(|t| t + duration))?;
+
+    client
+        .write_connect_header(ConnectHeader::Profile(ProfileRequest {
+            duration_secs: duration.as_secs(),
+            format: match format {
+                ProfileFormat::Flamegraph => shpool_protocol::ProfileFormat::Flamegraph,
+                ProfileFormat::Pprof => shpool_protocol::ProfileFormat::Pprof,
+            },
+        }))
+        .context("writing profile request header")?;
+    eprintln!("profiling the daemon for {}", duration::format(duration));
+
+    let reply: ProfileReply = client.read_reply().context("reading reply")?;
+
+    match reply.status {
+        ProfileStatus::Written { path } => {
+            println!("{}", path);
+            Ok(())
+        }
+        ProfileStatus::Busy => Err(anyhow!("the daemon is already being profiled")),
+        ProfileStatus::Failed { reason } => Err(anyhow!("profiling failed: {}", reason)),
+        ProfileStatus::Unsupported => {
+            Err(anyhow!("the daemon was built without the profiling feature"))
+        }
+    }
+}
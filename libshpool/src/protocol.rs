@@ -15,19 +15,30 @@
 use std::{
     cmp,
     io::{self, Read, Write},
-    os::unix::net::UnixStream,
+    net,
+    os::{fd::AsFd as _, unix::net::UnixStream},
     path::Path,
-    sync::atomic::{AtomicI32, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     thread, time,
 };
 
 use anyhow::{anyhow, Context};
 use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
 use serde::{Deserialize, Serialize};
-use shpool_protocol::{Chunk, ChunkKind, ConnectHeader, VersionHeader};
+use shpool_protocol::{
+    AttachHeader, Chunk, ChunkKind, ConnectHeader, UdpTransportOffer, VersionHeader,
+};
 use tracing::{debug, error, info, instrument, span, trace, warn, Level};
 
-use super::{consts, tty};
+#[cfg(feature = "udp_transport")]
+use super::udp;
+use super::{
+    attach, clipboard_bridge, consts, context::ClientContext, control, control_sock,
+    daemon::cancel, output, tty, Error,
+};
 
 const JOIN_POLL_DUR: time::Duration = time::Duration::from_millis(100);
 const JOIN_HANGUP_DUR: time::Duration = time::Duration::from_millis(300);
@@ -57,9 +68,20 @@ where
     for<'de> T: Deserialize<'de>,
     R: Read,
 {
-    let mut deserializer = rmp_serde::Deserializer::new(r);
-    let d: T = Deserialize::deserialize(&mut deserializer).context("deserializing from reader")?;
-    Ok(d)
+    // msgpack strings and arrays carry their own length prefixes, so cap
+    // how much we are willing to read rather than trusting them.
+    let mut limited = r.take(consts::MAX_MESSAGE_SIZE);
+    let mut deserializer = rmp_serde::Deserializer::new(&mut limited);
+    let res: Result<T, _> = Deserialize::deserialize(&mut deserializer);
+    match res {
+        Ok(d) => Ok(d),
+        Err(_) if limited.limit() == 0 => Err(FrameError(format!(
+            "message exceeds size limit of {} bytes",
+            consts::MAX_MESSAGE_SIZE
+        ))
+        .into()),
+        Err(e) => Err(e).context("deserializing from reader"),
+    }
 }
 
 /// Methods for the Chunk protocol struct. Protocol structs
@@ -79,13 +101,20 @@ impl<'data> ChunkExt<'data> for Chunk<'data> {
     where
         W: std::io::Write,
     {
-        w.write_u8(self.kind as u8)?;
         if let ChunkKind::ExitStatus = self.kind {
-            assert!(self.buf.len() == 4);
             // the caller should have already little-endian encoded
             // the exit status and stuffed it into buf
+            if self.buf.len() != 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("exit status chunk must be 4 bytes, got {}", self.buf.len()),
+                ));
+            }
+            w.write_u8(self.kind as u8)?;
         } else {
-            w.write_u32::<LittleEndian>(self.buf.len() as u32)?;
+            let len = frame_len(self.buf.len())?;
+            w.write_u8(self.kind as u8)?;
+            w.write_u32::<LittleEndian>(len)?;
         }
         w.write_all(self.buf)?;
 
@@ -107,6 +136,7 @@ impl<'data> ChunkExt<'data> for Chunk<'data> {
             Ok(Chunk { kind, buf: &buf[..4] })
         } else {
             let len = r.read_u32::<LittleEndian>()? as usize;
+            check_chunk_len(len)?;
             if len > buf.len() {
                 return Err(anyhow!(
                     "chunk of size {} exceeds size limit of {} bytes",
@@ -120,8 +150,234 @@ impl<'data> ChunkExt<'data> for Chunk<'data> {
     }
 }
 
+/// The length prefix for a chunk with the given amount of data. Chunks
+/// bigger than `consts::MAX_CHUNK_SIZE` get rejected rather than
+/// silently truncated, which would desync the stream. `FrameWriter`
+/// splits up big payloads so they never get this far.
+fn frame_len(len: usize) -> io::Result<u32> {
+    if len > consts::MAX_CHUNK_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("chunk of {} bytes is over the limit of {} bytes", len, consts::MAX_CHUNK_SIZE),
+        ));
+    }
+    Ok(len as u32)
+}
+
+/// Reject a length prefix that no well behaved peer would ever send
+/// before it gets used to size a read.
+fn check_chunk_len(len: usize) -> Result<(), FrameError> {
+    if len > consts::MAX_CHUNK_SIZE {
+        return Err(FrameError(format!(
+            "chunk claims to be {} bytes, over the protocol limit of {} bytes, \
+             the stream is corrupted",
+            len,
+            consts::MAX_CHUNK_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Whether an error came from a read or write on the connection running
+/// into its timeout.
+pub(crate) fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<io::Error>()
+            .map(|e| matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+            .unwrap_or(false)
+    })
+}
+
+/// Turn an error from running into the client timeout into a Timeout
+/// error saying what we were waiting on, letting the user know.
+fn timed_out(err: anyhow::Error, what: &str, timeout: Option<time::Duration>) -> anyhow::Error {
+    match timeout {
+        Some(t) if is_timeout(&err) => {
+            let what = format!("{} after {:?}", what, t);
+            output::error(format!("timed out {}", what));
+            Error::Timeout(what).into()
+        }
+        _ => err,
+    }
+}
+
+/// A protocol violation on the wire, such as a checked frame failing
+/// its checks or a length prefix over the limit, which means that the
+/// stream has been corrupted or has lost its place. These get reported
+/// to the user, since without them the user would just see a garbled
+/// terminal.
+#[derive(Debug)]
+pub struct FrameError(String);
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Writes chunks onto an attach stream, as checked frames if the client
+/// asked for them (see `Chunk` for the format). Clones share a sequence
+/// number, so the pager and the shell->client thread can take turns
+/// writing to the same client.
+#[derive(Clone, Debug, Default)]
+pub struct FrameWriter {
+    checked: bool,
+    /// Whether the client can display notice chunks. If not, notices
+    /// get written as data the way they used to be.
+    notices: bool,
+    next_seq: Arc<AtomicU32>,
+}
+
+impl FrameWriter {
+    pub fn new(checked: bool) -> Self {
+        FrameWriter { checked, notices: true, next_seq: Arc::new(AtomicU32::new(0)) }
+    }
+
+    /// A writer for the client that sent `header`.
+    pub fn for_client(header: &AttachHeader) -> Self {
+        FrameWriter { notices: header.notice_chunks, ..FrameWriter::new(header.checked_frames) }
+    }
+
+    /// Write the chunk, splitting data chunks that are too big for a
+    /// single frame into several frames.
+    pub fn write<W>(&self, w: &mut W, chunk: &Chunk) -> io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        if chunk.kind == ChunkKind::Data && chunk.buf.len() > consts::MAX_CHUNK_SIZE {
+            for piece in chunk.buf.chunks(consts::MAX_CHUNK_SIZE) {
+                self.write_frame(w, &Chunk { kind: ChunkKind::Data, buf: piece })?;
+            }
+            return Ok(());
+        }
+        if chunk.kind == ChunkKind::Notice && !self.notices {
+            // The terminal is in raw mode, so we need explicit
+            // carriage returns.
+            let text = format!("\r\nshpool: {}\r\n", String::from_utf8_lossy(chunk.buf));
+            return self.write(w, &Chunk { kind: ChunkKind::Data, buf: text.as_bytes() });
+        }
+        self.write_frame(w, chunk)
+    }
+
+    fn write_frame<W>(&self, w: &mut W, chunk: &Chunk) -> io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        if !self.checked {
+            return chunk.write_to(w);
+        }
+        if let ChunkKind::ExitStatus = chunk.kind {
+            if chunk.buf.len() != 4 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("exit status chunk must be 4 bytes, got {}", chunk.buf.len()),
+                ));
+            }
+        }
+
+        let seq = self.next_seq.fetch_add(1, Ordering::AcqRel);
+        let header = frame_header(chunk.kind as u8, seq, frame_len(chunk.buf.len())?);
+        w.write_all(&header)?;
+        w.write_u32::<LittleEndian>(frame_crc(&header, chunk.buf))?;
+        w.write_all(chunk.buf)?;
+
+        Ok(())
+    }
+}
+
+/// Reads chunks off of an attach stream, checking each frame if the
+/// daemon agreed to send checked frames.
+pub struct FrameReader {
+    checked: bool,
+    next_seq: u32,
+}
+
+impl FrameReader {
+    pub fn new(checked: bool) -> Self {
+        FrameReader { checked, next_seq: 0 }
+    }
+
+    pub fn read<'data, R>(
+        &mut self,
+        r: &mut R,
+        buf: &'data mut [u8],
+    ) -> anyhow::Result<Chunk<'data>>
+    where
+        R: std::io::Read,
+    {
+        if !self.checked {
+            return Chunk::read_into(r, buf);
+        }
+
+        let tag = r.read_u8()?;
+        let seq = r.read_u32::<LittleEndian>()?;
+        let len = r.read_u32::<LittleEndian>()?;
+        let crc = r.read_u32::<LittleEndian>()?;
+        check_chunk_len(len as usize)?;
+        // A length that doesn't fit can't be checked against the crc,
+        // but it is just as much a sign of a broken stream.
+        if len as usize > buf.len() {
+            return Err(FrameError(format!(
+                "frame {} claims to be {} bytes, over the limit of {} bytes, \
+                 the stream is corrupted",
+                seq,
+                len,
+                buf.len()
+            ))
+            .into());
+        }
+        r.read_exact(&mut buf[..len as usize])?;
+        let data = &buf[..len as usize];
+
+        let want_crc = frame_crc(&frame_header(tag, seq, len), data);
+        if crc != want_crc {
+            return Err(FrameError(format!(
+                "frame {} failed its checksum (got {:08x}, want {:08x}), the stream is corrupted",
+                seq, crc, want_crc
+            ))
+            .into());
+        }
+        if seq != self.next_seq {
+            return Err(FrameError(format!(
+                "got frame {} when expecting frame {}, the stream has lost its place",
+                seq, self.next_seq
+            ))
+            .into());
+        }
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let kind = ChunkKind::try_from(tag)?;
+        if kind == ChunkKind::ExitStatus && data.len() != 4 {
+            return Err(anyhow!("exit status chunk must be 4 bytes, got {}", data.len()));
+        }
+        Ok(Chunk { kind, buf: data })
+    }
+}
+
+fn frame_header(tag: u8, seq: u32, len: u32) -> [u8; 9] {
+    let mut header = [0; 9];
+    header[0] = tag;
+    header[1..5].copy_from_slice(&seq.to_le_bytes());
+    header[5..9].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+fn frame_crc(header: &[u8], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(data);
+    hasher.finalize()
+}
+
 pub struct Client {
     stream: UnixStream,
+    /// Whether the daemon is sending checked frames.
+    checked_frames: bool,
+    /// How long to wait on the daemon for each read or write before
+    /// giving up, if at all.
+    timeout: Option<time::Duration>,
 }
 
 /// The result of creating a client, possibly with
@@ -143,18 +399,34 @@ pub enum ClientResult {
 }
 
 impl Client {
-    /// Create a new client
+    /// Create a new client. If there is a timeout, it bounds connecting,
+    /// reading the version header and each request and reply after that,
+    /// up until the connection gets handed over to one of the pipe
+    /// methods.
     #[allow(clippy::new_ret_no_self)]
-    pub fn new<P: AsRef<Path>>(sock: P) -> anyhow::Result<ClientResult> {
-        let stream = UnixStream::connect(sock).context("connecting to shpool")?;
+    pub fn new<P: AsRef<Path>>(
+        sock: P,
+        timeout: Option<time::Duration>,
+    ) -> anyhow::Result<ClientResult> {
+        let stream = match timeout {
+            Some(t) => control_sock::connect_timeout(sock, t),
+            None => control_sock::connect(sock),
+        }
+        .map_err(|e| timed_out(e.into(), "connecting to the daemon", timeout))
+        .context("connecting to shpool")?;
+        stream.set_read_timeout(timeout).context("setting read timeout")?;
+        stream.set_write_timeout(timeout).context("setting write timeout")?;
 
         let daemon_version: VersionHeader = match decode_from(&stream) {
             Ok(v) => v,
+            Err(e) if is_timeout(&e) => {
+                return Err(timed_out(e, "waiting for the daemon's version", timeout));
+            }
             Err(e) => {
                 warn!("error parsing VersionHeader: {:?}", e);
                 return Ok(ClientResult::VersionMismatch {
                     warning: String::from("could not get daemon version"),
-                    client: Client { stream },
+                    client: Client { stream, checked_frames: false, timeout },
                 });
             }
         };
@@ -163,14 +435,16 @@ impl Client {
         match Self::version_ord(shpool_protocol::VERSION, &daemon_version.version)
             .context("comparing versions")?
         {
-            cmp::Ordering::Equal => Ok(ClientResult::JustClient(Client { stream })),
+            cmp::Ordering::Equal => {
+                Ok(ClientResult::JustClient(Client { stream, checked_frames: false, timeout }))
+            }
             cmp::Ordering::Less => Ok(ClientResult::VersionMismatch {
                 warning: format!(
                     "client protocol (version {:?}) is older than daemon protocol (version {:?})",
                     shpool_protocol::VERSION,
                     daemon_version.version,
                 ),
-                client: Client { stream },
+                client: Client { stream, checked_frames: false, timeout },
             }),
             cmp::Ordering::Greater => Ok(ClientResult::VersionMismatch {
                 warning: format!(
@@ -178,28 +452,81 @@ impl Client {
                     shpool_protocol::VERSION,
                     daemon_version.version,
                 ),
-                client: Client { stream },
+                client: Client { stream, checked_frames: false, timeout },
             }),
         }
     }
 
     pub fn write_connect_header(&self, header: ConnectHeader) -> anyhow::Result<()> {
-        encode_to(&header, &self.stream).context("writing reply")?;
+        encode_to(&header, &self.stream)
+            .map_err(|e| timed_out(e, "sending a request to the daemon", self.timeout))
+            .context("writing reply")?;
         Ok(())
     }
 
+    /// The timeout for reads and writes on the connection.
+    pub fn timeout(&self) -> Option<time::Duration> {
+        self.timeout
+    }
+
+    /// Change the timeout, for requests that the daemon is expected to
+    /// take a while over.
+    pub fn set_timeout(&mut self, timeout: Option<time::Duration>) -> anyhow::Result<()> {
+        self.stream.set_read_timeout(timeout).context("setting read timeout")?;
+        self.stream.set_write_timeout(timeout).context("setting write timeout")?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Read the daemon's response to a request for the experimental
+    /// udp transport, switching over to it if the daemon supports it.
+    pub fn negotiate_udp(&mut self) -> anyhow::Result<()> {
+        let offer: UdpTransportOffer = self.read_reply().context("reading udp offer")?;
+        match &offer.addr {
+            #[cfg(feature = "udp_transport")]
+            Some(addr) => {
+                info!("switching to udp transport at {}", addr);
+                self.stream = udp::connect(&offer)?;
+            }
+            _ => {
+                output::warning("shpool: daemon does not support the udp transport, falling back");
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch to reading checked frames once the daemon has agreed to
+    /// send them.
+    pub fn set_checked_frames(&mut self, checked: bool) {
+        self.checked_frames = checked;
+    }
+
+    /// Send a request, then read the daemon's reply to it.
+    pub fn request<R>(&mut self, header: ConnectHeader) -> anyhow::Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        self.write_connect_header(header).context("writing request header")?;
+        self.read_reply().context("reading reply")
+    }
+
     pub fn read_reply<R>(&mut self) -> anyhow::Result<R>
     where
         R: for<'de> serde::Deserialize<'de>,
     {
-        let reply: R = decode_from(&mut self.stream).context("parsing header")?;
+        let reply: R = decode_from(&mut self.stream)
+            .map_err(|e| timed_out(e, "waiting for a reply from the daemon", self.timeout))
+            .context("parsing header")?;
         Ok(reply)
     }
 
     /// This is essentially just PartialOrd on client version strings
     /// with more descriptive errors (since PartialOrd gives an option)
     /// and without having to wrap in a newtype.
-    fn version_ord(client_version: &str, daemon_version: &str) -> anyhow::Result<cmp::Ordering> {
+    pub fn version_ord(
+        client_version: &str,
+        daemon_version: &str,
+    ) -> anyhow::Result<cmp::Ordering> {
         let client_parts = client_version
             .split('.')
             .map(|p| p.parse::<i64>())
@@ -233,39 +560,190 @@ impl Client {
         Ok(client_parts[0].cmp(&daemon_parts[0]))
     }
 
+    /// Speak the control mode protocol on std{in,out} instead of
+    /// piping raw bytes, see the control module.
+    pub fn pipe_control(
+        mut self,
+        emitter: control::Emitter,
+        session_name: String,
+        ctx: ClientContext,
+    ) -> anyhow::Result<i32> {
+        // the session can be quiet for as long as it likes
+        self.set_timeout(None)?;
+        control::run(self.stream, FrameReader::new(self.checked_frames), emitter, session_name, ctx)
+    }
+
+    /// A second handle on the connection to the daemon, so that
+    /// another thread can hang up on it.
+    pub fn hangup_handle(&self) -> anyhow::Result<UnixStream> {
+        self.stream.try_clone().context("cloning client stream")
+    }
+
+    /// Copy the output of a session to `out`, as streamed by the daemon
+    /// for `shpool pipe`. Notices from the daemon go to stderr. Stops
+    /// once the daemon hangs up or `out` can't take any more.
+    ///
+    /// Return value: the exit status of the session's shell, if it
+    /// exited while we were watching.
+    #[instrument(skip_all)]
+    pub fn pipe_output<W: Write>(mut self, out: &mut W) -> anyhow::Result<Option<i32>> {
+        self.set_timeout(None)?;
+        let mut buf = vec![0; consts::BUF_SIZE];
+        let mut frames = FrameReader::new(false);
+        loop {
+            let chunk = match frames.read(&mut self.stream, &mut buf) {
+                Ok(c) => c,
+                Err(err) => match err.downcast_ref::<io::Error>() {
+                    Some(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        info!("daemon hung up");
+                        return Ok(None);
+                    }
+                    _ => return Err(err).context("reading output chunk"),
+                },
+            };
+            match chunk.kind {
+                ChunkKind::Data => {
+                    let res = out.write_all(chunk.buf).and_then(|_| out.flush());
+                    if let Err(e) = res {
+                        if e.kind() == io::ErrorKind::BrokenPipe {
+                            info!("output closed, done piping");
+                            return Ok(None);
+                        }
+                        return Err(e).context("writing output");
+                    }
+                }
+                ChunkKind::Notice => {
+                    eprintln!("shpool: {}", String::from_utf8_lossy(chunk.buf));
+                }
+                ChunkKind::ExitStatus => {
+                    let status = io::Cursor::new(chunk.buf)
+                        .read_i32::<LittleEndian>()
+                        .context("reading exit status from exit status chunk")?;
+                    info!("shell exited (status={})", status);
+                    return Ok(Some(status));
+                }
+                ChunkKind::Heartbeat => {}
+            }
+        }
+    }
+
     /// pipe_bytes suffles bytes from std{in,out} to the unix
     /// socket and back again. It is the main loop of
     /// `shpool attach`.
     ///
+    /// If `escape` is given, it gets a look at all user input before it
+    /// is sent to the daemon, and pipe_bytes bails out as soon as it sees
+    /// the client detach keybinding without waiting on the daemon.
+    ///
+    /// If there is an exit banner, it gets printed once the tty is back
+    /// to normal, unless the attach failed with an error.
+    ///
+    /// `local_signals` says which signal characters the tty should keep
+    /// turning into signals for us, rather than passing them through.
+    ///
+    /// If `clipboard` is given, OSC 52 sequences in the output get
+    /// handled with the local clipboard rather than passed to the
+    /// terminal, see clipboard_bridge.rs.
+    ///
+    /// Cancelling `quit` hangs up on the daemon, the same as the client
+    /// detach keybinding, and makes `shpool attach` exit with
+    /// `attach::QUIT_EXIT_STATUS`.
+    ///
     /// Return value: the exit status that `shpool attach` should
     /// exit with.
     #[instrument(skip_all)]
-    pub fn pipe_bytes(self) -> anyhow::Result<i32> {
-        let tty_guard = tty::set_attach_flags()?;
+    pub fn pipe_bytes(
+        mut self,
+        mut escape: Option<attach::LocalEscape>,
+        banner: Option<attach::ExitBanner>,
+        local_signals: tty::LocalSignals,
+        clipboard: Option<clipboard_bridge::Bridge>,
+        quit: &cancel::Cancel,
+    ) -> anyhow::Result<i32> {
+        self.set_timeout(None)?;
+        let tty_guard = tty::set_attach_flags_with(local_signals)?;
 
         let mut read_client_stream = self.stream.try_clone().context("cloning read stream")?;
-        let mut write_client_stream = self.stream.try_clone().context("cloning read stream")?;
+        // Shared with the clipboard bridge, which answers paste requests
+        // by typing into the session, so that its replies don't get
+        // interleaved with user input.
+        let write_client_stream =
+            Mutex::new(self.stream.try_clone().context("cloning read stream")?);
 
+        let checked_frames = self.checked_frames;
         let exit_status = AtomicI32::new(1);
+        let detached_locally = AtomicBool::new(false);
+        let bytes_in = AtomicU64::new(0);
+        let bytes_out = AtomicU64::new(0);
+        let shell_exited = AtomicBool::new(false);
+        let stats = || attach::AttachStats {
+            bytes_in: bytes_in.load(Ordering::Acquire),
+            bytes_out: bytes_out.load(Ordering::Acquire),
+            exit_status: if shell_exited.load(Ordering::Acquire) {
+                Some(exit_status.load(Ordering::Acquire))
+            } else {
+                None
+            },
+        };
         thread::scope(|s| {
             // stdin -> sock
             let stdin_to_sock_h = s.spawn(|| -> anyhow::Result<()> {
                 let _s = span!(Level::INFO, "stdin->sock").entered();
                 let mut stdin = std::io::stdin().lock();
                 let mut buf = vec![0; consts::BUF_SIZE];
+                let mut filtered = Vec::with_capacity(consts::BUF_SIZE);
+                let hang_up = || {
+                    detached_locally.store(true, Ordering::Release);
+                    // Hanging up is all it takes to detach, the daemon
+                    // notices once it fails to send us a heartbeat. This
+                    // also kicks the sock->stdout thread out of its read.
+                    if let Err(e) =
+                        write_client_stream.lock().unwrap().shutdown(net::Shutdown::Both)
+                    {
+                        warn!("shutting down client stream: {:?}", e);
+                    }
+                };
 
                 loop {
+                    // Our reads are bigger than the stdin buffer, so it
+                    // never holds on to any input and polling the fd is
+                    // enough to tell whether there is more.
+                    if !quit.wait_readable(io::stdin().as_fd())? {
+                        info!("quit, detaching");
+                        exit_status.store(attach::QUIT_EXIT_STATUS, Ordering::Release);
+                        hang_up();
+                        return Ok(());
+                    }
                     let nread = stdin.read(&mut buf).context("reading stdin from user")?;
                     if nread == 0 {
                         continue;
                     }
                     debug!("read {} bytes", nread);
 
-                    let to_write = &buf[..nread];
+                    let to_write = match escape.as_mut() {
+                        Some(escape) => {
+                            filtered.clear();
+                            if escape.scan(&buf[..nread], &mut filtered) {
+                                info!("client detach keybinding fired, detaching");
+                                exit_status.store(0, Ordering::Release);
+                                hang_up();
+                                return Ok(());
+                            }
+                            &filtered[..]
+                        }
+                        None => &buf[..nread],
+                    };
+                    if to_write.is_empty() {
+                        continue;
+                    }
                     trace!("created to_write='{}'", String::from_utf8_lossy(to_write));
 
-                    write_client_stream.write_all(to_write)?;
-                    write_client_stream.flush().context("flushing client")?;
+                    {
+                        let mut write_client_stream = write_client_stream.lock().unwrap();
+                        write_client_stream.write_all(to_write)?;
+                        write_client_stream.flush().context("flushing client")?;
+                    }
+                    bytes_in.fetch_add(to_write.len() as u64, Ordering::AcqRel);
                 }
             });
 
@@ -275,12 +753,23 @@ impl Client {
 
                 let mut stdout = std::io::stdout().lock();
                 let mut buf = vec![0; consts::BUF_SIZE];
+                let mut frames = FrameReader::new(checked_frames);
+                let mut osc52_filter = clipboard_bridge::Osc52Filter::default();
+                let mut filtered = Vec::with_capacity(consts::BUF_SIZE);
 
                 loop {
-                    let chunk = match Chunk::read_into(&mut read_client_stream, &mut buf) {
+                    let chunk = match frames.read(&mut read_client_stream, &mut buf) {
                         Ok(c) => c,
+                        Err(_) if detached_locally.load(Ordering::Acquire) => {
+                            info!("stream shut down by client detach keybinding");
+                            return Ok(());
+                        }
                         Err(err) => {
                             error!("reading chunk: {:?}", err);
+                            if let Some(frame_err) = err.downcast_ref::<FrameError>() {
+                                // stdout is in raw mode, so supply our own carriage returns
+                                eprint!("\r\nshpool: {}\r\n", frame_err);
+                            }
                             return Err(err);
                         }
                     };
@@ -299,7 +788,43 @@ impl Client {
                             trace!("got heartbeat chunk");
                         }
                         ChunkKind::Data => {
-                            stdout.write_all(chunk.buf).context("writing chunk to stdout")?;
+                            let to_write = match &clipboard {
+                                Some(bridge) => {
+                                    filtered.clear();
+                                    let reqs = osc52_filter.filter(chunk.buf, &mut filtered);
+                                    for req in reqs.into_iter() {
+                                        // Once the user has detached, nobody is
+                                        // around to have their clipboard read.
+                                        if matches!(req, clipboard_bridge::Request::Paste { .. })
+                                            && detached_locally.load(Ordering::Acquire)
+                                        {
+                                            info!("detached, ignoring a clipboard paste request");
+                                            continue;
+                                        }
+                                        let reply = match bridge.handle(req) {
+                                            Ok(reply) => reply,
+                                            Err(e) => {
+                                                warn!("handling clipboard request: {:?}", e);
+                                                continue;
+                                            }
+                                        };
+                                        if let Some(reply) = reply {
+                                            let mut write_client_stream =
+                                                write_client_stream.lock().unwrap();
+                                            write_client_stream
+                                                .write_all(&reply)
+                                                .context("sending clipboard reply")?;
+                                            write_client_stream
+                                                .flush()
+                                                .context("flushing clipboard reply")?;
+                                        }
+                                    }
+                                    &filtered[..]
+                                }
+                                None => chunk.buf,
+                            };
+                            stdout.write_all(to_write).context("writing chunk to stdout")?;
+                            bytes_out.fetch_add(to_write.len() as u64, Ordering::AcqRel);
 
                             if let Err(e) = stdout.flush() {
                                 if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -320,6 +845,14 @@ impl Client {
                                 .context("reading exit status from exit status chunk")?;
                             info!("got exit status frame (status={})", stat);
                             exit_status.store(stat, Ordering::Release);
+                            shell_exited.store(true, Ordering::Release);
+                        }
+                        ChunkKind::Notice => {
+                            let notice = String::from_utf8_lossy(chunk.buf);
+                            info!("got notice chunk: {}", notice);
+                            if let Err(e) = tty::render_notice(&mut stdout, &notice) {
+                                warn!("rendering notice: {:?}", e);
+                            }
                         }
                     }
                 }
@@ -361,6 +894,10 @@ impl Client {
                             // make sure that we restore the tty flags on the input
                             // tty before exiting the process.
                             drop(tty_guard);
+                            if let Some(banner) = &banner {
+                                banner.print(&stats());
+                            }
+                            attach::run_detach_hook();
 
                             std::process::exit(exit_status.load(Ordering::Acquire));
                         }
@@ -379,6 +916,10 @@ impl Client {
                 Err(panic_err) => std::panic::resume_unwind(panic_err),
             }
 
+            drop(tty_guard);
+            if let Some(banner) = &banner {
+                banner.print(&stats());
+            }
             Ok(exit_status.load(Ordering::Acquire))
         })
     }
@@ -386,6 +927,9 @@ impl Client {
 
 #[cfg(test)]
 mod test {
+    use proptest::prelude::*;
+    use shpool_protocol::{AttachHeader, TtySize};
+
     use super::*;
 
     #[test]
@@ -395,6 +939,7 @@ mod test {
             Chunk { kind: ChunkKind::Data, buf: data.as_slice() },
             Chunk { kind: ChunkKind::Heartbeat, buf: &data[..0] },
             Chunk { kind: ChunkKind::ExitStatus, buf: &data[..4] },
+            Chunk { kind: ChunkKind::Notice, buf: data.as_slice() },
         ];
 
         let mut buf = vec![0; 256];
@@ -408,6 +953,304 @@ mod test {
         }
     }
 
+    fn chunk_kind() -> impl Strategy<Value = ChunkKind> {
+        prop_oneof![
+            Just(ChunkKind::Data),
+            Just(ChunkKind::Heartbeat),
+            Just(ChunkKind::ExitStatus),
+            Just(ChunkKind::Notice),
+        ]
+    }
+
+    /// A chunk kind along with a payload that is valid for it.
+    fn chunk_parts() -> impl Strategy<Value = (ChunkKind, Vec<u8>)> {
+        chunk_kind().prop_flat_map(|kind| match kind {
+            ChunkKind::ExitStatus => {
+                (Just(kind), proptest::collection::vec(any::<u8>(), 4)).boxed()
+            }
+            // Mostly small random payloads, along with some big ones right
+            // up against the chunk size limit. Generating big random payloads
+            // is slow, so we just fill those with a single byte.
+            _ => (
+                Just(kind),
+                prop_oneof![
+                    4 => proptest::collection::vec(any::<u8>(), 0..256),
+                    1 => ((consts::MAX_CHUNK_SIZE - 1)..=consts::MAX_CHUNK_SIZE, any::<u8>())
+                        .prop_map(|(len, byte)| vec![byte; len]),
+                ],
+            )
+                .boxed(),
+        })
+    }
+
+    fn encode(kind: ChunkKind, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        Chunk { kind, buf: data }.write_to(&mut out).expect("write to succeed");
+        out
+    }
+
+    proptest! {
+        #[test]
+        fn chunk_round_trip_prop((kind, data) in chunk_parts()) {
+            let encoded = encode(kind, &data);
+            let mut buf = vec![0; data.len().max(4)];
+            let mut r = io::Cursor::new(&encoded);
+            let chunk = Chunk::read_into(&mut r, &mut buf).expect("parse to succeed");
+            prop_assert_eq!(chunk, Chunk { kind, buf: &data });
+            prop_assert_eq!(r.position() as usize, encoded.len());
+        }
+
+        #[test]
+        fn chunk_stream_round_trip(chunks in proptest::collection::vec(chunk_parts(), 0..6)) {
+            let mut encoded = vec![];
+            for (kind, data) in chunks.iter() {
+                encoded.extend(encode(*kind, data));
+            }
+
+            let mut r = io::Cursor::new(&encoded);
+            let mut buf = vec![0; consts::BUF_SIZE + 1];
+            for (kind, data) in chunks.iter() {
+                let chunk = Chunk::read_into(&mut r, &mut buf).expect("parse to succeed");
+                prop_assert_eq!(chunk, Chunk { kind: *kind, buf: data });
+            }
+            prop_assert!(Chunk::read_into(&mut r, &mut buf).is_err());
+        }
+
+        #[test]
+        fn truncated_chunk_errors((kind, data) in chunk_parts(), cut in any::<prop::sample::Index>()) {
+            let encoded = encode(kind, &data);
+            let truncated = &encoded[..cut.index(encoded.len())];
+            let mut buf = vec![0; data.len().max(4)];
+            prop_assert!(Chunk::read_into(&mut io::Cursor::new(truncated), &mut buf).is_err());
+        }
+
+        #[test]
+        fn oversized_chunk_errors(data in proptest::collection::vec(any::<u8>(), 1..1024)) {
+            let encoded = encode(ChunkKind::Data, &data);
+            let mut buf = vec![0; data.len() - 1];
+            prop_assert!(Chunk::read_into(&mut io::Cursor::new(&encoded), &mut buf).is_err());
+        }
+
+        #[test]
+        fn garbage_never_panics(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let mut r = io::Cursor::new(&data);
+            let mut buf = vec![0; 64];
+            while Chunk::read_into(&mut r, &mut buf).is_ok() {}
+        }
+
+        #[test]
+        fn frame_len_guard(len in prop_oneof![
+            0..=consts::MAX_CHUNK_SIZE,
+            (consts::MAX_CHUNK_SIZE + 1)..=(u32::MAX as usize),
+            (u32::MAX as usize + 1)..=usize::MAX,
+        ]) {
+            match frame_len(len) {
+                Ok(framed) => prop_assert_eq!(framed as usize, len),
+                Err(_) => prop_assert!(len > consts::MAX_CHUNK_SIZE),
+            }
+        }
+
+        #[test]
+        fn huge_len_prefix_is_a_protocol_error(
+            checked in any::<bool>(),
+            len in (consts::MAX_CHUNK_SIZE as u32 + 1)..=u32::MAX,
+        ) {
+            // Just the header, with no data behind it. The reader has to
+            // bail before it goes looking for the data, no matter how big
+            // a buffer it was handed.
+            let mut encoded = vec![ChunkKind::Data as u8];
+            if checked {
+                encoded.extend(0u32.to_le_bytes());
+            }
+            encoded.extend(len.to_le_bytes());
+            if checked {
+                encoded.extend(0u32.to_le_bytes());
+            }
+
+            let mut buf = vec![0; consts::MAX_CHUNK_SIZE * 2];
+            let err = FrameReader::new(checked)
+                .read(&mut io::Cursor::new(&encoded), &mut buf)
+                .unwrap_err();
+            prop_assert!(err.downcast_ref::<FrameError>().is_some(), "err={:?}", err);
+        }
+
+        #[test]
+        fn big_paste_is_split_into_chunks(
+            checked in any::<bool>(),
+            len in 0..(consts::MAX_CHUNK_SIZE * 5),
+            seed in any::<u8>(),
+        ) {
+            // Every byte value shows up, so this also checks that nothing
+            // along the way mangles binary data.
+            let data: Vec<u8> = (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect();
+            let mut encoded = vec![];
+            FrameWriter::new(checked)
+                .write(&mut encoded, &Chunk { kind: ChunkKind::Data, buf: &data })
+                .expect("write to succeed");
+
+            let mut reader = FrameReader::new(checked);
+            let mut r = io::Cursor::new(&encoded);
+            let mut buf = vec![0; consts::BUF_SIZE];
+            let mut got = vec![];
+            while (r.position() as usize) < encoded.len() {
+                let chunk = reader.read(&mut r, &mut buf).expect("parse to succeed");
+                prop_assert_eq!(chunk.kind, ChunkKind::Data);
+                prop_assert!(chunk.buf.len() <= consts::MAX_CHUNK_SIZE);
+                got.extend_from_slice(chunk.buf);
+            }
+            prop_assert_eq!(got, data);
+        }
+
+        #[test]
+        fn bad_exit_status_len_errors(data in proptest::collection::vec(any::<u8>(), 0..16)) {
+            prop_assume!(data.len() != 4);
+            let mut out = vec![];
+            let chunk = Chunk { kind: ChunkKind::ExitStatus, buf: &data };
+            prop_assert!(chunk.write_to(&mut out).is_err());
+            prop_assert!(out.is_empty());
+        }
+
+        #[test]
+        fn checked_frame_stream_round_trip(chunks in proptest::collection::vec(chunk_parts(), 0..6)) {
+            let writer = FrameWriter::new(true);
+            let mut encoded = vec![];
+            for (kind, data) in chunks.iter() {
+                writer.write(&mut encoded, &Chunk { kind: *kind, buf: data }).expect("write to succeed");
+            }
+
+            let mut reader = FrameReader::new(true);
+            let mut r = io::Cursor::new(&encoded);
+            let mut buf = vec![0; consts::BUF_SIZE + 1];
+            for (kind, data) in chunks.iter() {
+                let chunk = reader.read(&mut r, &mut buf).expect("parse to succeed");
+                prop_assert_eq!(chunk, Chunk { kind: *kind, buf: data });
+            }
+            prop_assert!(reader.read(&mut r, &mut buf).is_err());
+        }
+
+        #[test]
+        fn corrupted_frame_errors(
+            data in proptest::collection::vec(any::<u8>(), 0..256),
+            at in any::<prop::sample::Index>(),
+            flip in 1..=u8::MAX,
+        ) {
+            let mut encoded = vec![];
+            FrameWriter::new(true)
+                .write(&mut encoded, &Chunk { kind: ChunkKind::Data, buf: &data })
+                .expect("write to succeed");
+            let at = at.index(encoded.len());
+            encoded[at] ^= flip;
+
+            let mut buf = vec![0; 256];
+            let res = FrameReader::new(true).read(&mut io::Cursor::new(&encoded), &mut buf);
+            prop_assert!(res.is_err());
+        }
+
+        #[test]
+        fn dropped_frame_errors(skip in 0..4usize) {
+            let writer = FrameWriter::new(true);
+            let mut frames = vec![];
+            for i in 0..4u8 {
+                let mut frame = vec![];
+                writer.write(&mut frame, &Chunk { kind: ChunkKind::Data, buf: &[i] }).expect("write to succeed");
+                frames.push(frame);
+            }
+            frames.remove(skip);
+            let encoded = frames.concat();
+
+            let mut reader = FrameReader::new(true);
+            let mut r = io::Cursor::new(&encoded);
+            let mut buf = vec![0; 16];
+            for _ in 0..skip {
+                prop_assert!(reader.read(&mut r, &mut buf).is_ok());
+            }
+            if skip < 3 {
+                let err = reader.read(&mut r, &mut buf).unwrap_err();
+                prop_assert!(err.downcast_ref::<FrameError>().is_some());
+            }
+        }
+
+        #[test]
+        fn attach_header_round_trip(
+            name in ".{0,32}",
+            rows in any::<u16>(),
+            cols in any::<u16>(),
+            local_env in proptest::collection::vec((".{0,16}", ".{0,16}"), 0..8),
+            ttl_secs in any::<Option<u64>>(),
+            cmd in any::<Option<String>>(),
+        ) {
+            let header = ConnectHeader::Attach(AttachHeader {
+                name,
+                local_tty_size: TtySize { rows, cols, xpixel: 0, ypixel: 0 },
+                local_env,
+                ttl_secs,
+                cmd,
+                ..AttachHeader::default()
+            });
+            let mut encoded = vec![];
+            encode_to(&header, &mut encoded).expect("encode to succeed");
+
+            let decoded: ConnectHeader = decode_from(&encoded[..]).expect("decode to succeed");
+            prop_assert_eq!(format!("{:?}", decoded), format!("{:?}", header));
+
+            // chopping off any amount of the header should give a clean error
+            for cut in 0..encoded.len() {
+                prop_assert!(decode_from::<ConnectHeader, _>(&encoded[..cut]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_notice_errors() {
+        let notice = vec![b'x'; consts::MAX_CHUNK_SIZE + 1];
+        for checked in [false, true] {
+            let mut out = vec![];
+            let res = FrameWriter::new(checked)
+                .write(&mut out, &Chunk { kind: ChunkKind::Notice, buf: &notice });
+            assert!(res.is_err(), "checked={}", checked);
+        }
+    }
+
+    #[test]
+    fn notices_become_data_for_old_clients() -> anyhow::Result<()> {
+        for (notice_chunks, kind) in [(true, ChunkKind::Notice), (false, ChunkKind::Data)] {
+            let header = AttachHeader { checked_frames: true, notice_chunks, ..Default::default() };
+            let mut out = vec![];
+            FrameWriter::for_client(&header)
+                .write(&mut out, &Chunk { kind: ChunkKind::Notice, buf: b"throttled" })?;
+
+            let mut buf = vec![0; 256];
+            let chunk = FrameReader::new(true).read(&mut io::Cursor::new(&out), &mut buf)?;
+            assert_eq!(chunk.kind, kind);
+            let text = String::from_utf8_lossy(chunk.buf);
+            if notice_chunks {
+                assert_eq!(text, "throttled");
+            } else {
+                assert_eq!(text, "\r\nshpool: throttled\r\n");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn huge_message_len_is_a_protocol_error() {
+        // A msgpack str32 claiming to be 4 GiB, with nothing behind it.
+        let mut encoded = vec![0xdb];
+        encoded.extend(u32::MAX.to_be_bytes());
+        let res = decode_from::<String, _>(io::Cursor::new(&encoded));
+        assert!(res.is_err());
+
+        // A message that really is too big.
+        let mut encoded = vec![];
+        encode_to(&"x".repeat(consts::MAX_MESSAGE_SIZE as usize), &mut encoded).unwrap();
+        let err = decode_from::<String, _>(io::Cursor::new(&encoded)).unwrap_err();
+        assert!(err.downcast_ref::<FrameError>().is_some(), "err={:?}", err);
+
+        let mut encoded = vec![];
+        encode_to(&"x".repeat(1024), &mut encoded).unwrap();
+        assert_eq!(decode_from::<String, _>(io::Cursor::new(&encoded)).unwrap().len(), 1024);
+    }
+
     #[test]
     fn version_ordering_noerr() {
         use std::cmp::Ordering;
@@ -0,0 +1,123 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encrypting recordings at rest.
+//!
+//! With a `key_file` in the recording config, the output in every frame
+//! gets sealed with chacha20-poly1305 under a random nonce, which goes
+//! in front of the ciphertext. The frame's timestamp stays in the clear
+//! so that `shpool replay` can seek and pace without the key, but it is
+//! bound to the ciphertext as associated data, so it can't be changed
+//! without the frame failing to open.
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use anyhow::{anyhow, Context};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use tracing::warn;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// How much longer sealing makes a chunk of output.
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Load the key from a file holding exactly KEY_LEN bytes.
+    pub fn load(path: &Path) -> anyhow::Result<Cipher> {
+        let key = fs::read(path).with_context(|| format!("reading recording key {:?}", path))?;
+        if key.len() != KEY_LEN {
+            return Err(anyhow!(
+                "recording key {:?} is {} bytes, it must be exactly {}",
+                path,
+                key.len(),
+                KEY_LEN
+            ));
+        }
+        if let Ok(meta) = fs::metadata(path) {
+            if meta.permissions().mode() & 0o077 != 0 {
+                warn!("recording key {:?} can be read by other users", path);
+            }
+        }
+        Ok(Cipher::new(&key))
+    }
+
+    fn new(key: &[u8]) -> Cipher {
+        Cipher { aead: ChaCha20Poly1305::new_from_slice(key).expect("key has the right length") }
+    }
+
+    pub fn seal(&self, at_unix_ms: u64, output: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = at_unix_ms.to_le_bytes();
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, Payload { msg: output, aad: &aad })
+            .map_err(|_| anyhow!("encrypting output"))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn open(&self, at_unix_ms: u64, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if sealed.len() < OVERHEAD {
+            return Err(anyhow!("sealed output is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let aad = at_unix_ms.to_le_bytes();
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| anyhow!("decrypting output, the key might be wrong"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let cipher = Cipher::new(&[7; KEY_LEN]);
+        let sealed = cipher.seal(100, b"hunter2\r\n")?;
+        assert_eq!(sealed.len(), b"hunter2\r\n".len() + OVERHEAD);
+        assert!(!sealed.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(cipher.open(100, &sealed)?, b"hunter2\r\n".to_vec());
+
+        // the same output never seals the same way twice
+        assert_ne!(cipher.seal(100, b"hunter2\r\n")?, sealed);
+        // a moved timestamp or the wrong key gets caught
+        assert!(cipher.open(101, &sealed).is_err());
+        assert!(Cipher::new(&[8; KEY_LEN]).open(100, &sealed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn load() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("key");
+        fs::write(&path, [1; KEY_LEN])?;
+        Cipher::load(&path)?;
+        fs::write(&path, b"too short")?;
+        assert!(Cipher::load(&path).is_err());
+        assert!(Cipher::load(&tmp_dir.path().join("missing")).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,839 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Session recordings, for `shpool replay`.
+
+  When the `recording` config option is on, the daemon appends
+  everything a session writes to its clients to a file in the session's
+  runtime directory. The file is a header followed by frames, each of
+  which is a unix timestamp in milliseconds, a length, the output itself
+  and a crc32 of all of that. The daemon only ever appends, and syncs
+  the file to disk every `fsync_interval`, so a crash can lose at most
+  that much output, plus a frame that was halfway written when it
+  happened. The writes and syncs happen on a writer thread per
+  recording, and the daemon syncs every recording when it shuts down.
+  With the `upload` option, synced output also gets copied to an s3
+  compatible bucket, see `storage`.
+  With a `key_file`, the output in each frame is encrypted, see
+  `crypt`, and the recording starts with a different header.
+  Recordings are only readable by the user, since they have everything
+  the shell printed in them.
+
+  With the `timestamps` option, each line of the recorded output starts
+  with the time it was printed at, which makes recordings of long
+  builds a lot more useful after the fact. The timestamps only go in
+  the recording, never to the client.
+
+  Next to the recording is an index of (timestamp, offset) pairs, one
+  for the first frame after every sync, which lets `shpool replay
+  --since` skip straight to the right part of a long recording. Index
+  entries only get written once the frame they point at is on disk.
+
+  When the daemon starts up, it runs `recover` on every recording to
+  cut off any torn frame at the end, along with index entries pointing
+  past what is left, so that new output gets appended after the last
+  intact frame. Like `shpool history` and `shpool last`, `shpool replay`
+  reads the files directly, so it works even when the daemon is down.
+*/
+
+use std::{
+    fs,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use crate::{config, duration};
+
+mod crypt;
+#[cfg(feature = "s3")]
+mod s3;
+mod storage;
+
+use crypt::Cipher;
+use storage::{FileStorage, Storage, Upload, Uploading};
+
+const RECORDING_FILE_NAME: &str = "recording";
+/// Every recording starts with this, so that recovery never goes
+/// truncating some file that just happens to have the right name.
+const MAGIC: &[u8] = b"shpool-recording-v1\n";
+/// Or this, if the output in its frames is encrypted. Both are the same
+/// length so that frames start at the same offset either way.
+const ENCRYPTED_MAGIC: &[u8] = b"shpool-recording-e1\n";
+const _: () = assert!(MAGIC.len() == ENCRYPTED_MAGIC.len());
+/// The timestamp and the length.
+const FRAME_HEADER_LEN: usize = 12;
+/// The crc32.
+const FRAME_TRAILER_LEN: usize = 4;
+/// A corrupt length should not be able to make us allocate a huge
+/// buffer. Output gets split into frames well under this.
+const MAX_FRAME_LEN: usize = 1 << 20;
+const INDEX_ENTRY_LEN: usize = 16;
+const DEFAULT_FSYNC_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// How many chunks of output may be waiting for the writer thread
+/// before we start dropping output.
+const QUEUE_CHUNKS: usize = 1024;
+/// `shpool replay` cuts pauses in the output down to this, since
+/// nobody wants to sit through a recording of a session that was
+/// left alone over the weekend.
+const MAX_REPLAY_PAUSE: time::Duration = time::Duration::from_secs(2);
+
+/// The recording for the given session.
+pub fn path(runtime_dir: &Path, session: &str) -> PathBuf {
+    runtime_dir.join("sessions").join(session).join(RECORDING_FILE_NAME)
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    path.with_extension("idx")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub at_unix_ms: u64,
+    pub data: Vec<u8>,
+}
+
+fn encode_frame(at_unix_ms: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAME_HEADER_LEN + data.len() + FRAME_TRAILER_LEN);
+    buf.extend(at_unix_ms.to_le_bytes());
+    buf.extend((data.len() as u32).to_le_bytes());
+    buf.extend(data);
+    let crc = crc32fast::hash(&buf);
+    buf.extend(crc.to_le_bytes());
+    buf
+}
+
+/// Read the frame at the reader's position. Ok(None) means there is no
+/// intact frame there, which is what both the end of a recording that is
+/// still being written and a frame torn by a crash look like.
+fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    if !read_full(r, &mut header)? {
+        return Ok(None);
+    }
+    let at_unix_ms = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_LEN {
+        return Ok(None);
+    }
+    let mut data = vec![0; len];
+    let mut crc = [0; FRAME_TRAILER_LEN];
+    if !read_full(r, &mut data)? || !read_full(r, &mut crc)? {
+        return Ok(None);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(&data);
+    if hasher.finalize() != u32::from_le_bytes(crc) {
+        return Ok(None);
+    }
+    Ok(Some(Frame { at_unix_ms, data }))
+}
+
+fn frame_len(frame: &Frame) -> u64 {
+    (FRAME_HEADER_LEN + frame.data.len() + FRAME_TRAILER_LEN) as u64
+}
+
+/// Like read_exact, except that running out of input is Ok(false)
+/// rather than an error.
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Header {
+    Intact {
+        encrypted: bool,
+    },
+    /// Shorter than the magic, but what is there matches it, so the
+    /// daemon went down while starting the recording.
+    Torn,
+    Invalid,
+}
+
+fn magic(encrypted: bool) -> &'static [u8] {
+    if encrypted {
+        ENCRYPTED_MAGIC
+    } else {
+        MAGIC
+    }
+}
+
+fn read_header<R: Read>(r: &mut R) -> io::Result<Header> {
+    let mut buf = vec![];
+    r.take(MAGIC.len() as u64).read_to_end(&mut buf)?;
+    Ok(if buf == MAGIC {
+        Header::Intact { encrypted: false }
+    } else if buf == ENCRYPTED_MAGIC {
+        Header::Intact { encrypted: true }
+    } else if MAGIC.starts_with(&buf) || ENCRYPTED_MAGIC.starts_with(&buf) {
+        Header::Torn
+    } else {
+        Header::Invalid
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IndexEntry {
+    at_unix_ms: u64,
+    offset: u64,
+}
+
+impl IndexEntry {
+    fn encode(&self) -> [u8; INDEX_ENTRY_LEN] {
+        let mut buf = [0; INDEX_ENTRY_LEN];
+        buf[..8].copy_from_slice(&self.at_unix_ms.to_le_bytes());
+        buf[8..].copy_from_slice(&self.offset.to_le_bytes());
+        buf
+    }
+}
+
+/// Read an index, ignoring a torn entry at the end.
+fn read_index(path: &Path) -> io::Result<Vec<IndexEntry>> {
+    Ok(fs::read(path)?
+        .chunks_exact(INDEX_ENTRY_LEN)
+        .map(|entry| IndexEntry {
+            at_unix_ms: u64::from_le_bytes(entry[..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(entry[8..].try_into().unwrap()),
+        })
+        .collect())
+}
+
+/// Where to start reading to find the first frame at or after
+/// `at_unix_ms`. Frames before the returned offset are all older.
+fn seek_offset(index: &[IndexEntry], at_unix_ms: u64) -> u64 {
+    match index.partition_point(|e| e.at_unix_ms <= at_unix_ms) {
+        0 => MAGIC.len() as u64,
+        i => index[i - 1].offset,
+    }
+}
+
+fn now_unix_ms() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Appends a session's output to its recording. The file work happens
+/// on a thread of its own, so that a slow disk or bucket never holds up
+/// the shell->client thread.
+pub struct Recorder {
+    path: PathBuf,
+    tx: crossbeam_channel::Sender<Msg>,
+    /// Set when lines should get timestamps.
+    stamper: Option<LineStamper>,
+    /// How many bytes have been dropped since the writer last kept up.
+    dropped_bytes: usize,
+}
+
+/// A handle for asking a recording's writer thread to sync, which
+/// outlives the `Recorder` itself.
+#[derive(Clone, Debug)]
+pub struct Syncer {
+    tx: crossbeam_channel::Sender<Msg>,
+}
+
+enum Msg {
+    Output { at_unix_ms: u64, data: Vec<u8> },
+    Sync(crossbeam_channel::Sender<()>),
+}
+
+impl Recorder {
+    /// Start recording the given session if the config asks for it.
+    /// Problems get logged rather than returned, since a session is
+    /// still worth having without a recording.
+    pub fn for_session(
+        runtime_dir: &Path,
+        session: &str,
+        recording: &config::Recording,
+    ) -> Option<Recorder> {
+        if !recording.enabled.unwrap_or(false) {
+            return None;
+        }
+        let fsync_interval = match &recording.fsync_interval {
+            Some(src) => match duration::parse(src) {
+                Ok(d) => d,
+                Err(e) => {
+                    warn!("parsing recording fsync_interval, using the default: {:?}", e);
+                    DEFAULT_FSYNC_INTERVAL
+                }
+            },
+            None => DEFAULT_FSYNC_INTERVAL,
+        };
+
+        // Better no recording than a plaintext one when the config
+        // asked for encryption.
+        let cipher = match &recording.key_file {
+            Some(key_file) => match Cipher::load(Path::new(key_file)) {
+                Ok(cipher) => Some(cipher),
+                Err(e) => {
+                    warn!("not recording session '{}': {:?}", session, e);
+                    return None;
+                }
+            },
+            None => None,
+        };
+        let upload = match &recording.upload {
+            Some(upload) => match storage::destination(upload) {
+                Ok(dest) => Some((upload.prefix.as_deref().unwrap_or(""), dest)),
+                Err(e) => {
+                    warn!("setting up recording uploads, only recording locally: {:?}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        match Recorder::open(path(runtime_dir, session), session, fsync_interval, cipher, upload) {
+            Ok(mut recorder) => {
+                if recording.timestamps.unwrap_or(false) {
+                    recorder.stamper = Some(LineStamper::default());
+                }
+                Some(recorder)
+            }
+            Err(e) => {
+                warn!("starting recording for session '{}': {:?}", session, e);
+                None
+            }
+        }
+    }
+
+    fn open(
+        path: PathBuf,
+        session: &str,
+        fsync_interval: time::Duration,
+        cipher: Option<Cipher>,
+        upload: Option<(&str, Box<dyn Upload>)>,
+    ) -> anyhow::Result<Recorder> {
+        let local = FileStorage::open(path.clone(), cipher.is_some())?;
+        let storage: Box<dyn Storage> = match upload {
+            Some((prefix, dest)) => Box::new(Uploading::open(local, session, prefix, dest)?),
+            None => Box::new(local),
+        };
+        let mut writer = Writer {
+            path: path.clone(),
+            storage,
+            cipher,
+            fsync_interval,
+            last_sync: time::Instant::now(),
+            dirty: false,
+        };
+        let (tx, rx) = crossbeam_channel::bounded(QUEUE_CHUNKS);
+        thread::Builder::new()
+            .name(format!("rec:{}", session))
+            .spawn(move || writer.run(rx))
+            .context("spawning recording writer")?;
+        Ok(Recorder { path, tx, stamper: None, dropped_bytes: 0 })
+    }
+
+    pub fn syncer(&self) -> Syncer {
+        Syncer { tx: self.tx.clone() }
+    }
+
+    /// Record a chunk of output. If the writer has fallen too far
+    /// behind, the output gets dropped rather than making the caller
+    /// wait.
+    pub fn record(&mut self, output: &[u8]) {
+        let at_unix_ms = now_unix_ms();
+        let data = match self.stamper.as_mut() {
+            Some(stamper) => {
+                let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+                stamper.stamp(output, &now)
+            }
+            None => output.to_vec(),
+        };
+        let len = data.len();
+        match self.tx.try_send(Msg::Output { at_unix_ms, data }) {
+            Ok(()) if self.dropped_bytes > 0 => {
+                warn!("recording {:?} caught up, dropped {} bytes", self.path, self.dropped_bytes);
+                self.dropped_bytes = 0;
+            }
+            Ok(()) => {}
+            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                if self.dropped_bytes == 0 {
+                    warn!("recording {:?} can't keep up, dropping output", self.path);
+                }
+                self.dropped_bytes += len;
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
+                self.dropped_bytes += len;
+            }
+        }
+    }
+}
+
+impl Syncer {
+    /// Get everything recorded so far onto disk, waiting at most
+    /// `timeout` for the writer to get to it.
+    pub fn sync(&self, timeout: time::Duration) -> anyhow::Result<()> {
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        self.tx
+            .send_timeout(Msg::Sync(done_tx), timeout)
+            .map_err(|_| anyhow!("recording writer is stuck or gone"))?;
+        done_rx.recv_timeout(timeout).context("waiting for recording sync")?;
+        Ok(())
+    }
+}
+
+/// The writer thread's side of a recording.
+struct Writer {
+    path: PathBuf,
+    storage: Box<dyn Storage>,
+    /// Set when output gets encrypted before it is stored.
+    cipher: Option<Cipher>,
+    fsync_interval: time::Duration,
+    last_sync: time::Instant,
+    /// Output has been appended since the last sync.
+    dirty: bool,
+}
+
+impl Writer {
+    /// Write out output as it comes in, syncing every fsync_interval
+    /// while there is unsynced output, until the recorder and all of
+    /// its syncers are gone.
+    fn run(&mut self, rx: crossbeam_channel::Receiver<Msg>) {
+        loop {
+            let msg = if self.dirty {
+                let due = self.last_sync + self.fsync_interval;
+                match rx.recv_deadline(due) {
+                    Ok(msg) => Some(msg),
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => None,
+                    Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(msg) => Some(msg),
+                    Err(_) => break,
+                }
+            };
+            match msg {
+                Some(Msg::Output { at_unix_ms, data }) => {
+                    let chunk_len = match self.cipher {
+                        Some(_) => MAX_FRAME_LEN - crypt::OVERHEAD,
+                        None => MAX_FRAME_LEN,
+                    };
+                    for chunk in data.chunks(chunk_len) {
+                        if let Err(e) = self.append(at_unix_ms, chunk) {
+                            warn!("recording output to {:?}: {:?}", self.path, e);
+                            break;
+                        }
+                    }
+                    if self.last_sync.elapsed() >= self.fsync_interval {
+                        self.sync_logged();
+                    }
+                }
+                Some(Msg::Sync(done)) => {
+                    self.sync_logged();
+                    let _ = done.send(());
+                }
+                None => self.sync_logged(),
+            }
+        }
+        self.sync_logged();
+    }
+
+    fn append(&mut self, at_unix_ms: u64, output: &[u8]) -> anyhow::Result<()> {
+        let frame = match &self.cipher {
+            Some(cipher) => encode_frame(at_unix_ms, &cipher.seal(at_unix_ms, output)?),
+            None => encode_frame(at_unix_ms, output),
+        };
+        self.storage.append(at_unix_ms, &frame)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn sync_logged(&mut self) {
+        self.last_sync = time::Instant::now();
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+        if let Err(e) = self.storage.sync() {
+            warn!("syncing recording {:?}: {:?}", self.path, e);
+        }
+    }
+}
+
+/// Puts a timestamp at the start of every line. Output shows up in
+/// whatever chunks the pty hands us, so lines can span several calls.
+struct LineStamper {
+    /// The last byte we saw ended a line, so the next one starts a
+    /// new one. The stamp waits for that byte rather than going out
+    /// with the newline, so that it says when the line was printed.
+    at_line_start: bool,
+}
+
+impl Default for LineStamper {
+    fn default() -> Self {
+        LineStamper { at_line_start: true }
+    }
+}
+
+impl LineStamper {
+    fn stamp(&mut self, output: &[u8], now: &str) -> Vec<u8> {
+        let mut stamped = Vec::with_capacity(output.len() + now.len() + 3);
+        for line in output.split_inclusive(|b| *b == b'\n') {
+            if self.at_line_start {
+                stamped.push(b'[');
+                stamped.extend(now.as_bytes());
+                stamped.extend(b"] ");
+            }
+            stamped.extend(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        stamped
+    }
+}
+
+/// Recover every recording in the runtime dir. Run once at daemon
+/// startup, before any session can be writing to them.
+pub fn recover_all(runtime_dir: &Path) {
+    let sessions = match fs::read_dir(runtime_dir.join("sessions")) {
+        Ok(sessions) => sessions,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!("listing session dirs to recover recordings: {:?}", e);
+            return;
+        }
+    };
+    for session_dir in sessions.flatten() {
+        let path = session_dir.path().join(RECORDING_FILE_NAME);
+        if !path.exists() {
+            continue;
+        }
+        match recover(&path) {
+            Ok(0) => {}
+            Ok(trimmed) => info!("trimmed {} bytes of torn output from {:?}", trimmed, path),
+            Err(e) => warn!("recovering recording {:?}: {:?}", path, e),
+        }
+    }
+}
+
+/// Truncate the recording at `path` after its last intact frame, and
+/// its index to the entries that still point into the recording.
+/// Returns the number of bytes cut off the recording.
+pub fn recover(path: &Path) -> anyhow::Result<u64> {
+    let data =
+        fs::OpenOptions::new().read(true).write(true).open(path).context("opening recording")?;
+    let len = data.metadata().context("stating recording")?.len();
+
+    let mut reader = BufReader::new(&data);
+    let intact_len = match read_header(&mut reader).context("reading header")? {
+        Header::Intact { .. } => {
+            let mut intact_len = MAGIC.len() as u64;
+            while let Some(frame) = read_frame(&mut reader).context("reading frame")? {
+                intact_len += frame_len(&frame);
+            }
+            intact_len
+        }
+        Header::Torn => 0,
+        Header::Invalid => return Err(anyhow!("not a shpool recording")),
+    };
+    if intact_len < len {
+        data.set_len(intact_len).context("truncating recording")?;
+        data.sync_all().context("syncing recording")?;
+    }
+
+    let index_path = index_path(path);
+    let index = match read_index(&index_path) {
+        Ok(index) => index,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(len - intact_len),
+        Err(e) => return Err(e).context("reading index"),
+    };
+    let keep = index.iter().take_while(|e| e.offset < intact_len).count();
+    let index_len = (keep * INDEX_ENTRY_LEN) as u64;
+    let index_file =
+        fs::OpenOptions::new().write(true).open(&index_path).context("opening index")?;
+    if index_file.metadata().context("stating index")?.len() != index_len {
+        index_file.set_len(index_len).context("truncating index")?;
+        index_file.sync_all().context("syncing index")?;
+    }
+
+    Ok(len - intact_len)
+}
+
+pub fn replay(
+    session: String,
+    since: Option<String>,
+    speed: f64,
+    runtime_dir: PathBuf,
+    key_file: Option<String>,
+) -> anyhow::Result<()> {
+    if !(speed >= 0.0 && speed.is_finite()) {
+        return Err(anyhow!("--speed must be a positive number, or 0 for no pauses"));
+    }
+    let since_unix_ms = match since {
+        Some(src) => {
+            let ago = duration::parse(&src).context("parsing --since")?;
+            Some(now_unix_ms().saturating_sub(ago.as_millis() as u64))
+        }
+        None => None,
+    };
+
+    let path = path(&runtime_dir, &session);
+    let mut reader = match fs::File::open(&path) {
+        Ok(f) => BufReader::new(f),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "no recording for session '{}', the recording config option might be off",
+                session
+            );
+            return Err(anyhow!("no recording at {:?}", path));
+        }
+        Err(e) => return Err(e).with_context(|| format!("opening {:?}", path)),
+    };
+    let cipher = match read_header(&mut reader).context("reading recording header")? {
+        Header::Intact { encrypted: false } => None,
+        Header::Intact { encrypted: true } => match key_file {
+            Some(key_file) => Some(Cipher::load(Path::new(&key_file))?),
+            None => {
+                return Err(anyhow!(
+                    "{:?} is encrypted, but there is no key_file in the recording config",
+                    path
+                ))
+            }
+        },
+        // nothing got recorded before the daemon went down
+        Header::Torn => return Ok(()),
+        Header::Invalid => return Err(anyhow!("{:?} is not a shpool recording", path)),
+    };
+    if let Some(since_unix_ms) = since_unix_ms {
+        // Without an index we can still get there, just more slowly.
+        let index = read_index(&index_path(&path)).unwrap_or_default();
+        reader
+            .seek(SeekFrom::Start(seek_offset(&index, since_unix_ms)))
+            .context("seeking recording")?;
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut last_at = None;
+    while let Some(frame) = read_frame(&mut reader).context("reading recording")? {
+        if since_unix_ms.map(|since| frame.at_unix_ms < since).unwrap_or(false) {
+            continue;
+        }
+        if let (Some(last_at), true) = (last_at, speed > 0.0) {
+            let pause = time::Duration::from_millis(frame.at_unix_ms.saturating_sub(last_at));
+            stdout.flush().context("flushing output")?;
+            thread::sleep(pause.min(MAX_REPLAY_PAUSE).div_f64(speed));
+        }
+        last_at = Some(frame.at_unix_ms);
+        match &cipher {
+            Some(cipher) => stdout.write_all(&cipher.open(frame.at_unix_ms, &frame.data)?),
+            None => stdout.write_all(&frame.data),
+        }
+        .context("writing output")?;
+    }
+    stdout.flush().context("flushing output")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(path: &Path, chunks: &[&[u8]]) -> anyhow::Result<()> {
+        // a zero interval syncs, and so indexes, every frame
+        let mut recorder =
+            Recorder::open(path.to_path_buf(), "test", time::Duration::ZERO, None, None)?;
+        for chunk in chunks.iter() {
+            recorder.record(chunk);
+        }
+        recorder.syncer().sync(time::Duration::from_secs(10))
+    }
+
+    fn frames(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut reader = BufReader::new(fs::File::open(path)?);
+        assert_eq!(read_header(&mut reader)?, Header::Intact { encrypted: false });
+        let mut frames = vec![];
+        while let Some(frame) = read_frame(&mut reader)? {
+            frames.push(frame.data);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path(), "main");
+        record(&path, &[b"echo hi\r\n", b"hi\r\n"])?;
+        // reopening appends rather than starting over
+        record(&path, &[b"$ "])?;
+
+        assert_eq!(
+            frames(&path)?,
+            vec![b"echo hi\r\n".to_vec(), b"hi\r\n".to_vec(), b"$ ".to_vec()]
+        );
+        let index = read_index(&index_path(&path))?;
+        assert_eq!(index.len(), 3);
+        assert_eq!(index[0].offset, MAGIC.len() as u64);
+        assert!(index.windows(2).all(|w| w[0].offset < w[1].offset));
+
+        Ok(())
+    }
+
+    #[test]
+    fn encrypted_round_trip() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let key_path = tmp_dir.path().join("key");
+        fs::write(&key_path, [3; crypt::KEY_LEN])?;
+        let path = path(tmp_dir.path(), "main");
+
+        let cipher = Cipher::load(&key_path)?;
+        let mut recorder =
+            Recorder::open(path.clone(), "test", time::Duration::ZERO, Some(cipher), None)?;
+        recorder.record(b"export TOKEN=hunter2\r\n");
+        recorder.syncer().sync(time::Duration::from_secs(10))?;
+        drop(recorder);
+
+        let contents = fs::read(&path)?;
+        assert!(contents.starts_with(ENCRYPTED_MAGIC));
+        assert!(!contents.windows(7).any(|w| w == b"hunter2"));
+        assert_eq!(recover(&path)?, 0);
+
+        let mut reader = BufReader::new(fs::File::open(&path)?);
+        assert_eq!(read_header(&mut reader)?, Header::Intact { encrypted: true });
+        let frame = read_frame(&mut reader)?.expect("a frame");
+        let cipher = Cipher::load(&key_path)?;
+        assert_eq!(cipher.open(frame.at_unix_ms, &frame.data)?, b"export TOKEN=hunter2\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn recover_torn_tail() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path(), "main");
+        record(&path, &[b"one", b"two"])?;
+        let intact_len = fs::metadata(&path)?.len();
+
+        // a crash partway through the third frame, after its index
+        // entry somehow made it out, plus half of a fourth entry
+        let torn = encode_frame(now_unix_ms(), b"three");
+        fs::OpenOptions::new().append(true).open(&path)?.write_all(&torn[..torn.len() - 3])?;
+        let entry = IndexEntry { at_unix_ms: now_unix_ms(), offset: intact_len };
+        let mut index = fs::OpenOptions::new().append(true).open(index_path(&path))?;
+        index.write_all(&entry.encode())?;
+        index.write_all(&entry.encode()[..5])?;
+
+        assert_eq!(recover(&path)?, torn.len() as u64 - 3);
+        assert_eq!(fs::metadata(&path)?.len(), intact_len);
+        assert_eq!(read_index(&index_path(&path))?.len(), 2);
+        assert_eq!(fs::metadata(index_path(&path))?.len(), 2 * INDEX_ENTRY_LEN as u64);
+        // already intact, so nothing to do
+        assert_eq!(recover(&path)?, 0);
+
+        record(&path, &[b"four"])?;
+        assert_eq!(frames(&path)?, vec![b"one".to_vec(), b"two".to_vec(), b"four".to_vec()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_bad_crc() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path(), "main");
+        record(&path, &[b"one", b"two"])?;
+        let mut contents = fs::read(&path)?;
+        let last = contents.len() - FRAME_TRAILER_LEN - 1;
+        contents[last] ^= 0xff;
+        fs::write(&path, &contents)?;
+
+        recover(&path)?;
+        assert_eq!(frames(&path)?, vec![b"one".to_vec()]);
+        assert_eq!(read_index(&index_path(&path))?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn recover_header() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("recording");
+
+        fs::write(&path, &MAGIC[..4])?;
+        assert_eq!(recover(&path)?, 4);
+        assert_eq!(fs::metadata(&path)?.len(), 0);
+
+        fs::write(&path, b"important stuff")?;
+        assert!(recover(&path).is_err());
+        assert_eq!(fs::read(&path)?, b"important stuff");
+        assert!(Recorder::open(path.clone(), "test", DEFAULT_FSYNC_INTERVAL, None, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn private_files() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path(), "main");
+        record(&path, &[b"secret\r\n"])?;
+        for p in [&path, &index_path(&path)] {
+            assert_eq!(fs::metadata(p)?.permissions().mode() & 0o777, 0o600, "{:?}", p);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sync_waits_for_queued_output() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = path(tmp_dir.path(), "main");
+        // a long interval means only an explicit sync indexes anything
+        let mut recorder =
+            Recorder::open(path.clone(), "test", time::Duration::from_secs(3600), None, None)?;
+        recorder.record(b"one");
+        recorder.record(b"two");
+        let syncer = recorder.syncer();
+        drop(recorder);
+        assert!(read_index(&index_path(&path))?.is_empty());
+
+        syncer.sync(time::Duration::from_secs(10))?;
+        assert_eq!(frames(&path)?, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(read_index(&index_path(&path))?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn line_stamps() {
+        let mut stamper = LineStamper::default();
+        assert_eq!(stamper.stamp(b"make\r\nbuild", "t1"), b"[t1] make\r\n[t1] build".to_vec());
+        assert_eq!(stamper.stamp(b"ing...\r\n", "t2"), b"ing...\r\n".to_vec());
+        assert_eq!(stamper.stamp(b"", "t3"), b"".to_vec());
+        assert_eq!(stamper.stamp(b"done\r\n\r\n", "t4"), b"[t4] done\r\n[t4] \r\n".to_vec());
+    }
+
+    #[test]
+    fn seeking() {
+        let index = [
+            IndexEntry { at_unix_ms: 100, offset: 50 },
+            IndexEntry { at_unix_ms: 200, offset: 90 },
+            IndexEntry { at_unix_ms: 300, offset: 150 },
+        ];
+        assert_eq!(seek_offset(&index, 50), MAGIC.len() as u64);
+        assert_eq!(seek_offset(&index, 100), 50);
+        assert_eq!(seek_offset(&index, 250), 90);
+        assert_eq!(seek_offset(&index, 1000), 150);
+        assert_eq!(seek_offset(&[], 1000), MAGIC.len() as u64);
+    }
+}
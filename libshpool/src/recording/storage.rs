@@ -0,0 +1,508 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  Where recordings get stored.
+
+  A recording's writer thread hands its frames to a `Storage`. The
+  default is `FileStorage`, which appends them to the recording file in
+  the session's runtime directory. `Uploading` does the same, and then
+  copies everything that has been synced to some other `Upload`
+  destination, like an s3 bucket, from a thread of its own.
+
+  The local file is always the source of truth. Uploads work from it in
+  segments of whole frames, each of which is stored as a recording in
+  its own right, so a segment can be played back with `shpool replay`
+  once it is downloaded. How far the uploads have gotten is kept in a
+  file next to the recording, so a daemon restart picks up where the
+  last one left off. If the destination is unreachable, the output is
+  still on local disk and goes up once the destination comes back.
+*/
+
+use std::{
+    fs,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    thread, time,
+};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use super::{index_path, magic, now_unix_ms, read_frame, read_header, Header, IndexEntry, MAGIC};
+use crate::config;
+
+/// The most output that goes in a single uploaded segment.
+const MAX_SEGMENT_LEN: u64 = 8 << 20;
+const INITIAL_UPLOAD_RETRY: time::Duration = time::Duration::from_secs(1);
+const MAX_UPLOAD_RETRY: time::Duration = time::Duration::from_secs(300);
+
+/// Somewhere to put a recording's frames.
+pub trait Storage: Send {
+    /// Append an encoded frame, which was recorded at `at_unix_ms`.
+    fn append(&mut self, at_unix_ms: u64, frame: &[u8]) -> anyhow::Result<()>;
+
+    /// Make everything appended so far durable.
+    fn sync(&mut self) -> anyhow::Result<()>;
+}
+
+/// Somewhere to copy segments of recordings to, once they are safely
+/// on local disk.
+pub trait Upload: Send {
+    /// Store `body` under `key`, replacing anything already there.
+    fn put(&self, key: &str, body: &[u8]) -> anyhow::Result<()>;
+}
+
+/// The destination for uploads that the config asks for.
+pub fn destination(upload: &config::RecordingUpload) -> anyhow::Result<Box<dyn Upload>> {
+    #[cfg(feature = "s3")]
+    {
+        Ok(Box::new(super::s3::Bucket::new(upload)?))
+    }
+    #[cfg(not(feature = "s3"))]
+    {
+        let _ = upload;
+        Err(anyhow!("recording upload is set, but shpool was built without the s3 feature"))
+    }
+}
+
+/// The recording file and its index in the session's runtime dir.
+pub struct FileStorage {
+    path: PathBuf,
+    data: fs::File,
+    index: fs::File,
+    /// The length of the recording, which is where the next frame goes.
+    len: u64,
+    /// The first frame written since the last sync, which gets added to
+    /// the index once it is safely on disk.
+    unindexed: Option<IndexEntry>,
+}
+
+impl FileStorage {
+    /// Open the recording at `path` for appending. If it exists but
+    /// `encrypted` does not match how it was written, it gets moved
+    /// aside and a fresh one started, since the two kinds of frame
+    /// can't be mixed in one file.
+    pub fn open(path: PathBuf, encrypted: bool) -> anyhow::Result<FileStorage> {
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .context("creating session dir")?;
+        }
+        let open = || {
+            fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(&path)
+                .context("opening recording")
+        };
+        let mut data = open()?;
+        let mut header = read_header(&mut data).context("reading recording header")?;
+        if let Header::Intact { encrypted: was_encrypted } = header {
+            if was_encrypted != encrypted {
+                move_aside(&path)?;
+                data = open()?;
+                header = Header::Torn;
+            }
+        }
+        match header {
+            Header::Intact { .. } => {}
+            Header::Torn => {
+                data.set_len(0).context("truncating recording")?;
+                data.write_all(magic(encrypted)).context("writing recording header")?;
+            }
+            Header::Invalid => return Err(anyhow!("{:?} is not a shpool recording", path)),
+        }
+        let len = data.metadata().context("stating recording")?.len();
+        let index = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(index_path(&path))
+            .context("opening recording index")?;
+
+        info!("recording to {:?}", path);
+        Ok(FileStorage { path, data, index, len, unindexed: None })
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&mut self, at_unix_ms: u64, frame: &[u8]) -> anyhow::Result<()> {
+        if let Err(e) = self.data.write_all(frame) {
+            // Don't leave half a frame for everything after it to get
+            // stuck behind.
+            if let Err(e) = self.data.set_len(self.len) {
+                warn!("trimming partial frame from {:?}: {:?}", self.path, e);
+            }
+            return Err(e).context("writing frame");
+        }
+        if self.unindexed.is_none() {
+            self.unindexed = Some(IndexEntry { at_unix_ms, offset: self.len });
+        }
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        let Some(entry) = self.unindexed.take() else {
+            return Ok(());
+        };
+        self.data.sync_data().context("syncing recording")?;
+        self.index.write_all(&entry.encode()).context("writing index entry")?;
+        self.index.sync_data().context("syncing index")?;
+        Ok(())
+    }
+}
+
+/// Rename the recording at `path`, along with the files that go with
+/// it, out of the way of a new one.
+fn move_aside(path: &Path) -> anyhow::Result<()> {
+    let aside = path.with_file_name(format!(
+        "{}-{}",
+        path.file_name().and_then(|n| n.to_str()).unwrap_or("recording"),
+        now_unix_ms()
+    ));
+    info!("encryption setting changed, moving {:?} to {:?}", path, aside);
+    fs::rename(path, &aside).context("moving old recording aside")?;
+    for (from, to) in
+        [(index_path(path), index_path(&aside)), (progress_path(path), progress_path(&aside))]
+    {
+        match fs::rename(&from, &to) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("moving {:?} aside", from)),
+        }
+    }
+    Ok(())
+}
+
+/// Records to a local file, and uploads it as it gets synced.
+pub struct Uploading {
+    local: FileStorage,
+    /// Tells the uploader how much of the local file is on disk.
+    synced_tx: crossbeam_channel::Sender<u64>,
+}
+
+impl Uploading {
+    pub fn open(
+        local: FileStorage,
+        session: &str,
+        prefix: &str,
+        dest: Box<dyn Upload>,
+    ) -> anyhow::Result<Uploading> {
+        let progress_path = progress_path(&local.path);
+        // Whatever was in the file when we opened it made it to disk
+        // on the last daemon's watch, or got cut off by recovery.
+        let synced = local.len;
+        let uploaded = match fs::read_to_string(&progress_path) {
+            Ok(src) => src.trim().parse::<u64>().unwrap_or(MAGIC.len() as u64).min(synced),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => MAGIC.len() as u64,
+            Err(e) => return Err(e).context("reading upload progress"),
+        };
+        let mut uploader = Uploader {
+            path: local.path.clone(),
+            progress_path,
+            key_prefix: format!("{}{}/", prefix, session),
+            dest,
+            uploaded,
+        };
+
+        let (synced_tx, synced_rx) = crossbeam_channel::unbounded();
+        thread::Builder::new()
+            .name(format!("rec-up:{}", session))
+            .spawn(move || uploader.run(synced, synced_rx))
+            .context("spawning recording uploader")?;
+        Ok(Uploading { local, synced_tx })
+    }
+}
+
+impl Storage for Uploading {
+    fn append(&mut self, at_unix_ms: u64, frame: &[u8]) -> anyhow::Result<()> {
+        self.local.append(at_unix_ms, frame)
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.local.sync()?;
+        // The uploader only goes away along with us.
+        let _ = self.synced_tx.send(self.local.len);
+        Ok(())
+    }
+}
+
+/// Where the upload progress for the recording at `path` is kept.
+fn progress_path(path: &Path) -> PathBuf {
+    path.with_extension("uploaded")
+}
+
+/// Why the uploader woke up.
+enum Wake {
+    /// The local file has been synced up to this offset.
+    Synced(u64),
+    /// There is output to upload, and nothing stopping us trying.
+    Retry,
+    /// The recording is done.
+    Closed,
+}
+
+struct Uploader {
+    path: PathBuf,
+    progress_path: PathBuf,
+    key_prefix: String,
+    dest: Box<dyn Upload>,
+    /// The offset in the local file that everything before has been
+    /// uploaded.
+    uploaded: u64,
+}
+
+impl Uploader {
+    /// Upload output as it gets synced, backing off when the
+    /// destination is having trouble, until the recording is closed.
+    fn run(&mut self, mut synced: u64, synced_rx: crossbeam_channel::Receiver<u64>) {
+        let mut retry: Option<(time::Instant, time::Duration)> = None;
+        loop {
+            let wake = if self.uploaded >= synced {
+                match synced_rx.recv() {
+                    Ok(len) => Wake::Synced(len),
+                    Err(_) => Wake::Closed,
+                }
+            } else if let Some((at, _)) = retry {
+                match synced_rx.recv_deadline(at) {
+                    Ok(len) => Wake::Synced(len),
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => Wake::Retry,
+                    Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Wake::Closed,
+                }
+            } else {
+                Wake::Retry
+            };
+            match wake {
+                Wake::Synced(len) => {
+                    synced = synced_rx.try_iter().fold(len, u64::max).max(synced);
+                    if retry.is_some() {
+                        // wait out the backoff before trying again
+                        continue;
+                    }
+                }
+                Wake::Retry => {}
+                // Give up on a destination that is already failing
+                // rather than holding up the session's exit.
+                Wake::Closed if retry.is_some() || self.uploaded >= synced => break,
+                Wake::Closed => {}
+            }
+
+            match self.upload(synced) {
+                Ok(()) => {
+                    if retry.take().is_some() {
+                        info!("uploads of {:?} caught up", self.path);
+                    }
+                }
+                Err(e) => {
+                    let wait = match retry {
+                        Some((_, wait)) => std::cmp::min(wait * 2, MAX_UPLOAD_RETRY),
+                        None => INITIAL_UPLOAD_RETRY,
+                    };
+                    warn!("uploading {:?}, retrying in {:?}: {:?}", self.path, wait, e);
+                    retry = Some((time::Instant::now() + wait, wait));
+                }
+            }
+            if let Wake::Closed = wake {
+                break;
+            }
+        }
+        if self.uploaded < synced {
+            warn!(
+                "recording {:?} closed with {} bytes not uploaded yet, they will go up \
+                 the next time the session is recorded",
+                self.path,
+                synced - self.uploaded
+            );
+        }
+    }
+
+    /// Upload everything up to `synced`, a segment at a time.
+    fn upload(&mut self, synced: u64) -> anyhow::Result<()> {
+        let mut data = fs::File::open(&self.path).context("opening recording")?;
+        // Segments start with the same header as the recording, so they
+        // say whether they are encrypted too.
+        let mut header = vec![0; MAGIC.len()];
+        data.read_exact(&mut header).context("reading recording header")?;
+        while self.uploaded < synced {
+            data.seek(SeekFrom::Start(self.uploaded)).context("seeking recording")?;
+            let mut reader = BufReader::new(&mut data);
+            let mut first_at = None;
+            let mut end = self.uploaded;
+            while end < synced && end - self.uploaded < MAX_SEGMENT_LEN {
+                let Some(frame) = read_frame(&mut reader).context("reading frame")? else {
+                    break;
+                };
+                first_at.get_or_insert(frame.at_unix_ms);
+                end += super::frame_len(&frame);
+            }
+            let Some(first_at) = first_at else {
+                return Err(anyhow!("no intact frame at offset {}", self.uploaded));
+            };
+
+            let mut body = header.clone();
+            data.seek(SeekFrom::Start(self.uploaded)).context("seeking recording")?;
+            (&mut data)
+                .take(end - self.uploaded)
+                .read_to_end(&mut body)
+                .context("reading segment")?;
+            let key = format!("{}{}-{}", self.key_prefix, first_at, self.uploaded);
+            self.dest.put(&key, &body).with_context(|| format!("putting {}", key))?;
+
+            self.uploaded = end;
+            fs::write(&self.progress_path, end.to_string()).context("saving upload progress")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::recording::{encode_frame, ENCRYPTED_MAGIC};
+
+    type Puts = Vec<(String, Vec<u8>)>;
+
+    #[derive(Clone, Default)]
+    struct Fake {
+        puts: Arc<Mutex<Puts>>,
+        failures: Arc<Mutex<usize>>,
+    }
+
+    impl Upload for Fake {
+        fn put(&self, key: &str, body: &[u8]) -> anyhow::Result<()> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(anyhow!("bucket is having a bad day"));
+            }
+            self.puts.lock().unwrap().push((String::from(key), body.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn wait_for_puts(fake: &Fake, n: usize) -> Puts {
+        let deadline = time::Instant::now() + time::Duration::from_secs(20);
+        loop {
+            let puts = fake.puts.lock().unwrap().clone();
+            if puts.len() >= n || time::Instant::now() > deadline {
+                return puts;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+    }
+
+    fn segment(frames: &[(u64, &[u8])]) -> Vec<u8> {
+        let mut body = MAGIC.to_vec();
+        for (at, data) in frames.iter() {
+            body.extend(encode_frame(*at, data));
+        }
+        body
+    }
+
+    #[test]
+    #[ntest::timeout(30000)]
+    fn uploads_synced_segments() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("main").join("recording");
+        let fake = Fake::default();
+
+        let local = FileStorage::open(path.clone(), false)?;
+        let mut storage = Uploading::open(local, "main", "host1/", Box::new(fake.clone()))?;
+        storage.append(100, &encode_frame(100, b"one"))?;
+        storage.append(200, &encode_frame(200, b"two"))?;
+        // nothing goes up until it is on disk
+        thread::sleep(time::Duration::from_millis(50));
+        assert!(fake.puts.lock().unwrap().is_empty());
+        storage.sync()?;
+        assert_eq!(
+            wait_for_puts(&fake, 1),
+            vec![(
+                format!("host1/main/100-{}", MAGIC.len()),
+                segment(&[(100, b"one"), (200, b"two")])
+            )]
+        );
+
+        let offset = fs::metadata(&path)?.len();
+        storage.append(300, &encode_frame(300, b"three"))?;
+        storage.sync()?;
+        let puts = wait_for_puts(&fake, 2);
+        assert_eq!(puts[1], (format!("host1/main/300-{}", offset), segment(&[(300, b"three")])));
+        assert_eq!(
+            fs::read_to_string(progress_path(&path))?,
+            fs::metadata(&path)?.len().to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn moves_aside_when_encryption_changes() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("main").join("recording");
+        let mut plain = FileStorage::open(path.clone(), false)?;
+        plain.append(100, &encode_frame(100, b"one"))?;
+        plain.sync()?;
+        let plain_contents = fs::read(&path)?;
+        drop(plain);
+
+        let encrypted = FileStorage::open(path.clone(), true)?;
+        assert_eq!(encrypted.len, MAGIC.len() as u64);
+        assert_eq!(fs::read(&path)?, ENCRYPTED_MAGIC);
+        let mut aside = fs::read_dir(path.parent().unwrap())?
+            .map(|e| e.map(|e| e.file_name().into_string().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        aside.sort();
+        assert_eq!(aside.len(), 4, "{:?}", aside);
+        assert!(aside[1].starts_with("recording-") && aside[2].ends_with(".idx"), "{:?}", aside);
+        assert_eq!(fs::read(path.with_file_name(&aside[1]))?, plain_contents);
+        Ok(())
+    }
+
+    #[test]
+    #[ntest::timeout(30000)]
+    fn retries_and_resumes() -> anyhow::Result<()> {
+        let tmp_dir = tempfile::tempdir()?;
+        let path = tmp_dir.path().join("main").join("recording");
+        let fake = Fake::default();
+        *fake.failures.lock().unwrap() = 1;
+
+        let local = FileStorage::open(path.clone(), false)?;
+        let mut storage = Uploading::open(local, "main", "", Box::new(fake.clone()))?;
+        storage.append(100, &encode_frame(100, b"one"))?;
+        storage.sync()?;
+        assert_eq!(
+            wait_for_puts(&fake, 1),
+            vec![(format!("main/100-{}", MAGIC.len()), segment(&[(100, b"one")]))]
+        );
+        drop(storage);
+
+        // output that never got uploaded before the daemon went down
+        // goes up when the recording is next opened
+        let local_len = fs::metadata(&path)?.len();
+        fs::OpenOptions::new().append(true).open(&path)?.write_all(&encode_frame(200, b"two"))?;
+        let local = FileStorage::open(path.clone(), false)?;
+        let _storage = Uploading::open(local, "main", "", Box::new(fake.clone()))?;
+        let puts = wait_for_puts(&fake, 2);
+        assert_eq!(puts[1], (format!("main/200-{}", local_len), segment(&[(200, b"two")])));
+        Ok(())
+    }
+}
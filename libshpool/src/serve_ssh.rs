@@ -0,0 +1,269 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `shpool serve-ssh` is meant to be the forced command of a key in
+//! authorized_keys, like
+//!
+//! ```text
+//! command="shpool serve-ssh laptop",restrict,pty ssh-ed25519 AAAA...
+//! ```
+//!
+//! so that logging in with that key lands straight in the session that
+//! the `ssh_keys` config table gives the key, either attached as usual
+//! or watching its output read only. Commands asked for by the ssh
+//! client are refused rather than run, and every connection is recorded
+//! in the audit log, if there is one.
+
+use std::{
+    env,
+    io::{self, Read},
+    net,
+    path::PathBuf,
+    process, thread, time,
+};
+
+use anyhow::anyhow;
+use nix::unistd::{self, isatty};
+use tracing::{info, warn};
+
+use crate::{
+    attach, config, consts, context::ClientContext, daemon::audit, output, pipe, tty, Error,
+};
+
+/// The bytes that make a read only viewer leave when typed at a
+/// terminal, Ctrl-c and Ctrl-d.
+const QUIT_BYTES: &[u8] = &[0x03, 0x04];
+
+pub fn run(ctx: ClientContext, key: String) -> anyhow::Result<i32> {
+    info!("\n\n======================== STARTING SERVE-SSH ============================\n\n");
+
+    let audit = Audit {
+        log: ctx.config.get().audit_log.clone().map(PathBuf::from),
+        key: &key,
+        ssh_client: env::var("SSH_CONNECTION")
+            .ok()
+            .and_then(|conn| conn.split_whitespace().next().map(String::from)),
+    };
+
+    let original_cmd = env::var("SSH_ORIGINAL_COMMAND").ok();
+    let ssh_key = match lookup(&ctx.config.get(), &key, original_cmd.as_deref()) {
+        Ok(ssh_key) => ssh_key,
+        Err(reason) => {
+            audit.record(&[], &format!("forbidden: {}", reason));
+            output::error(format!("shpool: {}", reason));
+            return Err(Error::Forbidden(reason).into());
+        }
+    };
+    let sessions = [ssh_key.session.clone()];
+    let read_only = ssh_key.read_only.unwrap_or(false);
+    info!("serving session '{}' to ssh key '{}', read_only={}", ssh_key.session, key, read_only);
+
+    // Without a pty, the attach would have nowhere to take raw input
+    // from, and the session's output would get mangled on the way out.
+    let has_tty =
+        isatty(consts::STDIN_FD).unwrap_or(false) && isatty(consts::STDOUT_FD).unwrap_or(false);
+    if !read_only && !has_tty {
+        audit.record(&sessions, "forbidden: no tty");
+        output::error("shpool: attaching needs a terminal, connect with 'ssh -t'");
+        return Err(anyhow!("no tty to attach with"));
+    }
+
+    audit.record(&sessions, if read_only { "connected: read-only" } else { "connected" });
+    let res = if read_only {
+        view(&ssh_key.session, &ctx)
+    } else {
+        attach::run(
+            ctx,
+            Some(ssh_key.session.clone()),
+            None,
+            false,
+            false,
+            None,
+            None,
+            None,
+            false,
+            None,
+            None,
+            None,
+            false,
+        )
+    };
+    match &res {
+        Ok(_) => audit.record(&sessions, "disconnect"),
+        Err(e) => audit.record(&sessions, &format!("error: {:#}", e)),
+    }
+    res
+}
+
+/// Work out what `key` gives access to, or why the connection should
+/// be turned away.
+fn lookup(
+    config: &config::Config,
+    key: &str,
+    original_cmd: Option<&str>,
+) -> Result<config::SshKey, String> {
+    let ssh_key = config
+        .ssh_keys
+        .as_ref()
+        .and_then(|keys| keys.get(key))
+        .ok_or_else(|| format!("ssh key '{}' is not in the ssh_keys config", key))?;
+    match original_cmd {
+        Some(cmd) if !cmd.trim().is_empty() => Err(format!(
+            "ssh key '{}' only gives access to session '{}', not to running '{}'",
+            key, ssh_key.session, cmd
+        )),
+        _ => Ok(ssh_key.clone()),
+    }
+}
+
+/// Stream the session's output to stdout, starting with its
+/// scrollback, until the viewer goes away.
+fn view(session: &str, ctx: &ClientContext) -> anyhow::Result<i32> {
+    let client = pipe::start(session, true, ctx)?;
+    let hangup = client.hangup_handle()?;
+
+    output::note(format!("shpool: watching session '{}' read only, Ctrl-c to leave", session));
+    // Raw mode keeps whatever the viewer types from being echoed over
+    // the session's output, and hands us Ctrl-c and Ctrl-d as bytes.
+    let _tty_guard = tty::set_attach_flags()?;
+
+    // When there is no pty, stdin hitting EOF is the only way to find
+    // out that the ssh client went away while the session is quiet.
+    thread::spawn(move || {
+        wait_for_quit(io::stdin().lock());
+        info!("viewer left, hanging up");
+        if let Err(e) = hangup.shutdown(net::Shutdown::Both) {
+            warn!("hanging up on daemon: {:?}", e);
+        }
+    });
+
+    client.pipe_output(&mut io::stdout().lock())?;
+    Ok(0)
+}
+
+/// Throw away input until it ends or contains one of the QUIT_BYTES.
+fn wait_for_quit<R: Read>(mut input: R) {
+    let mut buf = [0; consts::BUF_SIZE];
+    loop {
+        match input.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) if buf[..n].iter().any(|b| QUIT_BYTES.contains(b)) => return,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                warn!("reading viewer input: {:?}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Records connections in the audit log, tagged with the ssh key and
+/// where the connection came from.
+struct Audit<'a> {
+    log: Option<PathBuf>,
+    key: &'a str,
+    ssh_client: Option<String>,
+}
+
+impl Audit<'_> {
+    fn record(&self, sessions: &[String], outcome: &str) {
+        let Some(log) = &self.log else {
+            return;
+        };
+        let entry = audit::Entry {
+            at_unix_ms: time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as i64)
+                .unwrap_or(0),
+            conn_id: None,
+            uid: Some(unistd::getuid().as_raw()),
+            pid: Some(process::id() as i32),
+            action: "serve-ssh",
+            sessions,
+            outcome,
+            ssh_key: Some(self.key),
+            ssh_client: self.ssh_client.as_deref(),
+        };
+        if let Err(e) = audit::append(log, &entry) {
+            // Turning the user away over this would be worse than
+            // missing an entry, same as in the daemon.
+            warn!("writing audit log entry {:?}: {:?}", entry, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> config::Config {
+        toml::from_str(
+            r#"
+            [ssh_keys.laptop]
+            session = "main"
+            [ssh_keys.phone]
+            session = "main"
+            read_only = true
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn lookup_keys() {
+        let config = config();
+        let laptop = lookup(&config, "laptop", None).unwrap();
+        assert_eq!(laptop.session, "main");
+        assert_eq!(laptop.read_only, None);
+        assert_eq!(lookup(&config, "phone", Some("")).unwrap().read_only, Some(true));
+
+        assert!(lookup(&config, "tablet", None).unwrap_err().contains("not in the ssh_keys"));
+        assert!(lookup(&config::Config::default(), "laptop", None).is_err());
+    }
+
+    #[test]
+    fn refuses_commands() {
+        let err = lookup(&config(), "laptop", Some("rm -rf ~")).unwrap_err();
+        assert!(err.contains("not to running 'rm -rf ~'"), "{}", err);
+    }
+
+    #[test]
+    fn quit_on_ctrl_c_or_eof() {
+        // returning at all is the test here
+        wait_for_quit(&b"ls\x03more"[..]);
+        wait_for_quit(&b"just typing"[..]);
+    }
+
+    #[test]
+    fn audit_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("audit.jsonl");
+        let audit = Audit {
+            log: Some(log.clone()),
+            key: "laptop",
+            ssh_client: Some(String::from("10.0.0.1")),
+        };
+        audit.record(&[String::from("main")], "connected");
+
+        let line: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&log).unwrap()).unwrap();
+        assert_eq!(line["action"], "serve-ssh");
+        assert_eq!(line["ssh_key"], "laptop");
+        assert_eq!(line["ssh_client"], "10.0.0.1");
+        assert_eq!(line["sessions"], serde_json::json!(["main"]));
+        assert_eq!(line["outcome"], "connected");
+        assert!(line.get("conn_id").is_none());
+    }
+}
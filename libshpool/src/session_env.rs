@@ -0,0 +1,82 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, EnvReply, EnvRequest, EnvStatus};
+
+use crate::{context::ClientContext, Error};
+
+/// Print the environment that a session's shell was started with, one
+/// `KEY=VALUE` per line. With no session, falls back to the session
+/// this is running in.
+pub fn run(session: Option<String>, vars: Vec<String>, ctx: &ClientContext) -> anyhow::Result<()> {
+    let session = match session.or_else(|| env::var("SHPOOL_SESSION_NAME").ok()) {
+        Some(s) => s,
+        None => {
+            eprintln!("no session given and not running inside a shpool session");
+            return Err(anyhow!("no session to show the environment of"));
+        }
+    };
+
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::Env(EnvRequest { session: session.clone() }))
+        .context("writing env request header")?;
+
+    let reply: EnvReply = client.read_reply().context("reading reply")?;
+    match reply.status {
+        EnvStatus::Env(shell_env) => {
+            for line in format_env(shell_env, &vars) {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        EnvStatus::NotFound => {
+            eprintln!("not found: {}", session);
+            Err(Error::SessionsNotFound(vec![session]).into())
+        }
+    }
+}
+
+/// Sort the environment by name so it is easy to scan, keeping only
+/// the requested vars if any were given.
+fn format_env(mut shell_env: Vec<(String, String)>, vars: &[String]) -> Vec<String> {
+    shell_env.sort_by(|(a, _), (b, _)| a.cmp(b));
+    shell_env
+        .into_iter()
+        .filter(|(k, _)| vars.is_empty() || vars.contains(k))
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_env() {
+        let s = String::from;
+        let shell_env =
+            vec![(s("TERM"), s("xterm")), (s("HOME"), s("/home/me")), (s("A"), s("b=c"))];
+
+        assert_eq!(
+            format_env(shell_env.clone(), &[]),
+            vec![s("A=b=c"), s("HOME=/home/me"), s("TERM=xterm")]
+        );
+        assert_eq!(format_env(shell_env, &[s("TERM"), s("NOPE")]), vec![s("TERM=xterm")]);
+    }
+}
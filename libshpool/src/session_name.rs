@@ -0,0 +1,224 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The rules for session names, shared between the client, which checks
+//! them up front to give a friendly error, and the daemon, which has the
+//! final say. Session names end up as directory names in the runtime dir,
+//! so anything that would be trouble in a path is out.
+//!
+//! A pane is an extra shell that belongs to a session, made with
+//! `shpool attach --pane`. Panes live in the session table under an
+//! address of the form `session:pane`, which is why ':' is not allowed
+//! in names themselves. Kill, detach and broadcast only take a
+//! session's panes along when asked to with `--panes`.
+//!
+//! This also generates the friendly adjective-noun names that the daemon
+//! hands out for `shpool attach --auto-name`.
+
+use std::{process, time};
+
+/// The longest session name we accept, in characters.
+pub const MAX_LEN: usize = 128;
+
+/// What splits a pane address into the session and pane names.
+pub const PANE_SEP: char = ':';
+
+/// The address of a pane in the session table.
+pub fn pane_address(session: &str, pane: &str) -> String {
+    format!("{}{}{}", session, PANE_SEP, pane)
+}
+
+/// Split a name into the session it belongs to and its pane name, if
+/// it addresses a pane.
+pub fn split_pane(name: &str) -> (&str, Option<&str>) {
+    match name.split_once(PANE_SEP) {
+        Some((session, pane)) => (session, Some(pane)),
+        None => (name, None),
+    }
+}
+
+/// Check that a session name is acceptable, returning the reason
+/// it is not if it isn't.
+pub fn validate(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(String::from("blank session names are not allowed"));
+    }
+    if name.chars().count() > MAX_LEN {
+        return Err(format!("session names can't be longer than {} characters", MAX_LEN));
+    }
+    if name.contains(char::is_whitespace) {
+        return Err(String::from("whitespace is not allowed in session names"));
+    }
+    if name.contains(char::is_control) {
+        return Err(String::from("control characters are not allowed in session names"));
+    }
+    if name.contains('/') {
+        return Err(String::from("'/' is not allowed in session names"));
+    }
+    if name == "." || name == ".." {
+        return Err(format!("'{}' is not allowed as a session name", name));
+    }
+    if name.contains(PANE_SEP) {
+        return Err(format!(
+            "'{}' is not allowed in session names, use --pane to make a pane",
+            PANE_SEP
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a pane name is acceptable. Pane names follow the same
+/// rules as session names.
+pub fn validate_pane(pane: &str) -> Result<(), String> {
+    validate(pane).map_err(|reason| format!("bad pane name: {}", reason))
+}
+
+/// Check a name as it appears in the session table, where panes show
+/// up as `session:pane`.
+pub fn validate_address(address: &str) -> Result<(), String> {
+    match split_pane(address) {
+        (session, Some(pane)) => validate(session).and_then(|()| validate_pane(pane)),
+        (session, None) => validate(session),
+    }
+}
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "brave", "calm", "clever", "cozy", "crisp", "dapper", "eager", "fancy", "fuzzy",
+    "gentle", "happy", "jolly", "keen", "lively", "lucky", "mellow", "merry", "nimble", "plucky",
+    "proud", "quick", "quiet", "rapid", "shiny", "snappy", "sunny", "swift", "tidy", "witty",
+];
+
+const NOUNS: &[&str] = &[
+    "badger", "beaver", "bison", "crane", "falcon", "ferret", "gecko", "heron", "ibis", "koala",
+    "lemur", "lynx", "marmot", "marten", "moose", "newt", "ocelot", "otter", "panda", "puffin",
+    "quokka", "raven", "salmon", "stoat", "tapir", "toucan", "walrus", "weasel", "wombat", "yak",
+];
+
+/// How many random names to try before falling back to numbering them.
+const RANDOM_ATTEMPTS: usize = 64;
+
+/// Make up a friendly session name that `taken` says is not in use.
+pub fn generate<F>(taken: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    let nanos = time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64 ^ d.as_secs())
+        .unwrap_or(0);
+    generate_from_seed(nanos ^ ((process::id() as u64) << 32), taken)
+}
+
+fn generate_from_seed<F>(seed: u64, taken: F) -> String
+where
+    F: Fn(&str) -> bool,
+{
+    // xorshift64, which is plenty random for picking names
+    let mut state = seed | 1;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let mut pick = || {
+        let r = next();
+        format!(
+            "{}-{}",
+            ADJECTIVES[(r % ADJECTIVES.len() as u64) as usize],
+            NOUNS[((r >> 32) % NOUNS.len() as u64) as usize]
+        )
+    };
+
+    let mut name = pick();
+    for _ in 0..RANDOM_ATTEMPTS {
+        if !taken(&name) {
+            return name;
+        }
+        name = pick();
+    }
+
+    // Almost every name must be in use, so number the last one we tried.
+    (2..).map(|i| format!("{}-{}", name, i)).find(|n| !taken(n)).unwrap_or(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let longest = "x".repeat(MAX_LEN);
+        let too_long = "x".repeat(MAX_LEN + 1);
+        let cases = vec![
+            ("main", true),
+            ("work-2", true),
+            ("dev.box-3@host", true),
+            ("dev.box:3@host", false),
+            ("ünïcödé", true),
+            (longest.as_str(), true),
+            (too_long.as_str(), false),
+            ("", false),
+            ("has space", false),
+            ("tab\there", false),
+            ("bell\x07", false),
+            ("a/b", false),
+            (".", false),
+            ("..", false),
+            ("...", true),
+            ("work:build", false),
+        ];
+        for (name, valid) in cases.into_iter() {
+            assert_eq!(validate(name).is_ok(), valid, "name={:?}", name);
+        }
+    }
+
+    #[test]
+    fn address_validation() {
+        let cases = vec![
+            ("work", true),
+            ("work:build", true),
+            (":build", false),
+            ("work:", false),
+            ("work:build:1", false),
+            ("work:a b", false),
+        ];
+        for (address, valid) in cases.into_iter() {
+            assert_eq!(validate_address(address).is_ok(), valid, "address={:?}", address);
+        }
+    }
+
+    #[test]
+    fn panes() {
+        assert_eq!(split_pane("work"), ("work", None));
+        assert_eq!(split_pane("work:build"), ("work", Some("build")));
+        assert_eq!(pane_address("work", "build"), "work:build");
+    }
+
+    #[test]
+    fn generated_names_are_valid_and_free() {
+        for seed in 0..100 {
+            let name = generate_from_seed(seed, |n| n.starts_with("amber"));
+            assert!(validate(&name).is_ok(), "name={}", name);
+            assert!(!name.starts_with("amber"), "name={}", name);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_numbers() {
+        let name = generate_from_seed(7, |n| n.split('-').count() == 2);
+        assert_eq!(name.split('-').count(), 3, "name={}", name);
+        assert!(name.ends_with("-2"), "name={}", name);
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp, io, process, thread, time};
+
+use anyhow::{anyhow, Context};
+use tracing::{info, warn};
+
+use crate::protocol;
+
+/// The version of shpool we were built as part of. The shpool binary
+/// and libshpool are always released in lockstep.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The exit status ssh uses to indicate that something went wrong
+/// with the connection itself rather than with the remote command.
+const SSH_CONNECTION_ERROR_STATUS: i32 = 255;
+
+const INITIAL_RECONNECT_DELAY: time::Duration = time::Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: time::Duration = time::Duration::from_secs(30);
+
+pub fn run(
+    host: String,
+    name: String,
+    remote_shpool: String,
+    no_reconnect: bool,
+    ssh_args: Vec<String>,
+) -> anyhow::Result<i32> {
+    info!("\n\n======================== STARTING SSH ============================\n\n");
+
+    check_remote_version(&host, &remote_shpool, &ssh_args)?;
+
+    let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+    let mut force = false;
+    loop {
+        let started_at = time::Instant::now();
+        let status = ssh_cmd(&host, &ssh_args)
+            .arg("-t")
+            .arg(remote_attach_cmd(&remote_shpool, &name, force))
+            .status()
+            .context("running ssh")?;
+        let code = status.code().unwrap_or(1);
+        info!("ssh exited with status {:?}", status);
+
+        if code != SSH_CONNECTION_ERROR_STATUS || no_reconnect {
+            return Ok(code);
+        }
+
+        // If we were attached for a good while before the connection dropped,
+        // this is a fresh failure rather than a string of them.
+        if started_at.elapsed() > MAX_RECONNECT_DELAY {
+            reconnect_delay = INITIAL_RECONNECT_DELAY;
+        }
+        eprintln!(
+            "shpool: lost connection to {}, reconnecting in {:.1}s (^C to give up)",
+            host,
+            reconnect_delay.as_secs_f64()
+        );
+        thread::sleep(reconnect_delay);
+        reconnect_delay = cmp::min(reconnect_delay * 2, MAX_RECONNECT_DELAY);
+
+        // The remote daemon may not have noticed that the old connection
+        // is gone yet, so steal the session back from it.
+        force = true;
+    }
+}
+
+/// Make sure that the remote shpool exists and speaks a compatible
+/// version. As with a version mismatch against a local daemon, we
+/// let the user decide whether to continue.
+fn check_remote_version(
+    host: &str,
+    remote_shpool: &str,
+    ssh_args: &[String],
+) -> anyhow::Result<()> {
+    let out = ssh_cmd(host, ssh_args)
+        .arg(format!("{} version", shell_words::quote(remote_shpool)))
+        .stderr(process::Stdio::inherit())
+        .output()
+        .context("checking remote shpool version")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "could not run '{} version' on {} (is shpool installed there?)",
+            remote_shpool,
+            host
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let remote_version = parse_version_output(&stdout)
+        .ok_or(anyhow!("could not parse remote version output '{}'", stdout.trim()))?;
+    info!("remote shpool version: {}", remote_version);
+
+    match protocol::Client::version_ord(VERSION, remote_version) {
+        Ok(cmp::Ordering::Equal) => return Ok(()),
+        Ok(_) => {
+            eprintln!(
+                "warning: local shpool (version {}) is not compatible with shpool on {} (version {})",
+                VERSION, host, remote_version
+            );
+        }
+        Err(e) => {
+            warn!("comparing versions: {:?}", e);
+            eprintln!("warning: could not compare versions with shpool on {}", host);
+        }
+    }
+    eprintln!("hit enter to continue anyway or ^C to exit");
+    let _ =
+        io::stdin().lines().next().context("waiting for a continue through a version mismatch")?;
+
+    Ok(())
+}
+
+fn ssh_cmd(host: &str, ssh_args: &[String]) -> process::Command {
+    let mut cmd = process::Command::new("ssh");
+    cmd.args(ssh_args).arg(host);
+    cmd
+}
+
+/// The command line to run on the remote host. We pass --daemonize so
+/// the remote daemon gets started if it is not already running, even if
+/// the remote config disables autodaemonization.
+fn remote_attach_cmd(remote_shpool: &str, name: &str, force: bool) -> String {
+    let mut parts = vec![remote_shpool, "--daemonize", "attach"];
+    if force {
+        parts.push("--force");
+    }
+    parts.push(name);
+    shell_words::join(parts)
+}
+
+/// Pull the version number out of `shpool version` output, which looks
+/// like 'shpool 0.8.1'.
+fn parse_version_output(out: &str) -> Option<&str> {
+    out.trim().strip_prefix("shpool ").map(|v| v.trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn attach_cmd() {
+        assert_eq!(remote_attach_cmd("shpool", "main", false), "shpool --daemonize attach main");
+        assert_eq!(
+            remote_attach_cmd("/opt/bin/shpool", "my session", true),
+            "/opt/bin/shpool --daemonize attach --force 'my session'"
+        );
+    }
+
+    #[test]
+    fn version_output() {
+        assert_eq!(parse_version_output("shpool 0.8.1\n"), Some("0.8.1"));
+        assert_eq!(parse_version_output("bash: shpool: command not found"), None);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Asks the client's terminal what it can do before attaching, so that
+//! the daemon knows what sort of terminal the session is being shown
+//! in.
+//!
+//! We send queries for the kitty keyboard protocol and the secondary
+//! device attributes followed by one for the primary device attributes
+//! (DA1). Pretty much every terminal answers DA1, and terminals answer
+//! queries in order, so once the DA1 reply shows up we know that any
+//! other replies have already arrived. Terminals that don't know a
+//! query just ignore it. If nothing shows up in time, we give up and
+//! report the terminal as unprobed.
+//!
+//! Anything typed while the probe is waiting on the terminal gets
+//! dropped, but the wait is short.
+
+use std::{
+    env, io,
+    io::Write,
+    os::fd::{AsFd, AsRawFd},
+    time,
+};
+
+use anyhow::Context;
+use nix::{poll, unistd};
+use shpool_protocol::TerminalCaps;
+use tracing::{info, warn};
+
+use crate::tty;
+
+/// How long to wait for the terminal to answer. This needs to cover a
+/// round trip over ssh.
+const PROBE_TIMEOUT: time::Duration = time::Duration::from_millis(500);
+
+// kitty keyboard protocol flags, then DA2, then DA1
+const QUERIES: &[u8] = b"\x1b[?u\x1b[>c\x1b[c";
+
+/// Probe the terminal on stdin and stdout, returning None if we are
+/// not on a terminal or it didn't answer.
+pub fn probe() -> Option<TerminalCaps> {
+    if !unistd::isatty(io::stdin().as_raw_fd()).unwrap_or(false)
+        || !unistd::isatty(io::stdout().as_raw_fd()).unwrap_or(false)
+    {
+        return None;
+    }
+
+    match query() {
+        Ok(Some(mut caps)) => {
+            caps.truecolor =
+                env::var("COLORTERM").map(|c| c == "truecolor" || c == "24bit").unwrap_or(false);
+            info!("probed terminal: {:?}", caps);
+            Some(caps)
+        }
+        Ok(None) => {
+            info!("terminal did not answer probe");
+            None
+        }
+        Err(e) => {
+            warn!("probing terminal: {:?}", e);
+            None
+        }
+    }
+}
+
+fn query() -> anyhow::Result<Option<TerminalCaps>> {
+    // raw mode, so the replies don't get echoed or line buffered
+    let _tty_guard = tty::set_attach_flags()?;
+
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(QUERIES).context("writing queries")?;
+    stdout.flush().context("flushing queries")?;
+
+    let stdin = io::stdin();
+    let deadline = time::Instant::now() + PROBE_TIMEOUT;
+    let mut replies = vec![];
+    let mut buf = [0; 256];
+    loop {
+        let remaining = deadline.saturating_duration_since(time::Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+        let mut poll_fds = [poll::PollFd::new(stdin.as_fd(), poll::PollFlags::POLLIN)];
+        let nready = poll::poll(&mut poll_fds, remaining.as_millis().min(u16::MAX as u128) as u16)
+            .context("polling for terminal replies")?;
+        if nready == 0 {
+            return Ok(None);
+        }
+        let len = unistd::read(stdin.as_raw_fd(), &mut buf).context("reading terminal replies")?;
+        if len == 0 {
+            return Ok(None);
+        }
+        replies.extend_from_slice(&buf[..len]);
+        if let Some(caps) = parse(&replies) {
+            return Ok(Some(caps));
+        }
+    }
+}
+
+/// Pick the capabilities out of the terminal's replies, returning None
+/// until the DA1 reply that marks the end has shown up.
+fn parse(replies: &[u8]) -> Option<TerminalCaps> {
+    let mut caps = TerminalCaps::default();
+    let mut i = 0;
+    while i < replies.len() {
+        if !replies[i..].starts_with(b"\x1b[") {
+            i += 1;
+            continue;
+        }
+        i += 2;
+
+        let marker = match replies.get(i) {
+            Some(m @ (b'?' | b'>')) => {
+                i += 1;
+                Some(*m)
+            }
+            _ => None,
+        };
+        let params_start = i;
+        while i < replies.len() && (replies[i].is_ascii_digit() || replies[i] == b';') {
+            i += 1;
+        }
+        let params = parse_params(&replies[params_start..i]);
+        let Some(final_byte) = replies.get(i) else {
+            // the rest of the reply has yet to arrive
+            return None;
+        };
+        i += 1;
+
+        match (marker, *final_byte) {
+            (Some(b'?'), b'u') => caps.kitty_keyboard = true,
+            (Some(b'>'), b'c') => caps.secondary_attributes = params,
+            (Some(b'?'), b'c') => {
+                caps.primary_attributes = params;
+                return Some(caps);
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn parse_params(params: &[u8]) -> Vec<u32> {
+    String::from_utf8_lossy(params).split(';').filter_map(|p| p.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        // kitty answers all three
+        let caps = parse(b"\x1b[?0u\x1b[>1;4000;29c\x1b[?62;22;52c").expect("complete reply");
+        assert!(caps.kitty_keyboard);
+        assert_eq!(caps.secondary_attributes, vec![1, 4000, 29]);
+        assert_eq!(caps.primary_attributes, vec![62, 22, 52]);
+
+        // a terminal without the kitty keyboard protocol
+        let caps = parse(b"\x1b[>41;390;0c\x1b[?64;1;2;6;9;15;16;17;18;21;22;28c")
+            .expect("complete reply");
+        assert!(!caps.kitty_keyboard);
+        assert_eq!(caps.secondary_attributes, vec![41, 390, 0]);
+        assert_eq!(caps.primary_attributes.len(), 12);
+
+        // typeahead mixed in gets skipped
+        let caps = parse(b"ls\x1b[?1;2c").expect("complete reply");
+        assert_eq!(caps.primary_attributes, vec![1, 2]);
+
+        // no DA1 reply yet
+        assert_eq!(parse(b""), None);
+        assert_eq!(parse(b"\x1b[?0u\x1b[>1;4000;29c"), None);
+        assert_eq!(parse(b"\x1b[?0u\x1b[>1;4000;29c\x1b[?62;2"), None);
+    }
+}
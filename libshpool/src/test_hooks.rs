@@ -20,6 +20,11 @@
 // sleeps in order to test various scenarios. The basic idea is that
 // we publish a unix socket and then clients can listen for specific
 // named events in order to block until they have occurred.
+//
+// These events are internal and change whenever the tests need them
+// to, so they are only available in test builds. Tools outside of
+// shpool should subscribe to the event socket instead (see
+// daemon/event_socket.rs).
 use std::{
     io::Write,
     os::unix::net::{UnixListener, UnixStream},
@@ -43,6 +48,22 @@ pub fn emit(_event: &str) {
     // a no-op normally
 }
 
+/// Panic if the test harness asked for one at the given site for the
+/// given session by setting SHPOOL_TEST__PANIC_AT to "<site>:<session>".
+/// This lets tests make sure that a bug in the handling of one session
+/// can't take down the whole daemon.
+#[cfg(feature = "test_hooks")]
+pub fn maybe_panic(site: &str, session: &str) {
+    if std::env::var("SHPOOL_TEST__PANIC_AT").ok() == Some(format!("{}:{}", site, session)) {
+        panic!("injected panic at {} for session '{}'", site, session);
+    }
+}
+
+#[cfg(not(feature = "test_hooks"))]
+pub fn maybe_panic(_site: &str, _session: &str) {
+    // a no-op normally
+}
+
 #[cfg(feature = "test_hooks")]
 pub fn scoped(event: &str) -> ScopedEvent {
     ScopedEvent::new(event)
@@ -0,0 +1,365 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/*!
+  The testing module contains a harness for running a shpool daemon
+  inside of the test process. Unlike the integration tests in the
+  shpool crate, which drive a real `shpool` binary through a pty,
+  tests written against this harness talk to the daemon through fake
+  clients that speak the wire protocol directly, and control the
+  daemon's notion of time through a `MockClock`. This makes it possible
+  to test things like ttls without sleeping.
+
+  Note that only the daemon's timers run off of the mock clock.
+  Reads from the fake clients still use real timeouts so that a
+  misbehaving daemon can't hang a test forever.
+*/
+
+use std::{
+    fs, io,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use byteorder::{LittleEndian, ReadBytesExt};
+use shpool_protocol::{
+    AttachHeader, AttachReplyHeader, AttachStatus, Chunk, ChunkKind, ConnectHeader, DetachReply,
+    DetachRequest, ImportReply, ImportRequest, KillReply, KillRequest, ListReply,
+    SessionDefinition, StatsReply, TtySize, VersionHeader,
+};
+use tracing::error;
+
+use crate::{clock::Clock, config, consts, daemon, hooks, protocol};
+pub use crate::{clock::MockClock, protocol::ChunkExt};
+
+/// A config that gives a quiet, predictable shell, suitable for
+/// most tests.
+pub const DEFAULT_CONFIG: &str = r#"
+norc = true
+noecho = true
+shell = "/bin/bash"
+session_restore_mode = "simple"
+prompt_prefix = ""
+
+[env]
+PS1 = "prompt> "
+TERM = ""
+"#;
+
+/// How long a fake client will wait on the daemon before giving up.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A daemon running on a background thread of the test process.
+pub struct Daemon {
+    // Keeps the config file and socket alive until the daemon
+    // gets dropped.
+    _tmp_dir: tempfile::TempDir,
+    socket: PathBuf,
+    clock: Arc<MockClock>,
+    server: Arc<daemon::Server>,
+}
+
+impl Daemon {
+    /// Start a new daemon with the given toml config.
+    pub fn start(config_toml: &str) -> anyhow::Result<Self> {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-harness")
+            .tempdir()
+            .context("creating tmp dir")?;
+
+        let config_file = tmp_dir.path().join("config.toml");
+        fs::write(&config_file, config_toml).context("writing config")?;
+        let config_manager = config::Manager::new(Some(
+            config_file.to_str().ok_or(anyhow!("non-utf8 config path"))?,
+        ))
+        .context("loading config")?;
+
+        let runtime_dir = tmp_dir.path().join("runtime");
+        fs::create_dir_all(&runtime_dir).context("creating runtime dir")?;
+        let socket = runtime_dir.join("shpool.socket");
+        let listener = UnixListener::bind(&socket).context("binding to socket")?;
+
+        let clock = Arc::new(MockClock::new());
+        let daemon_clock: Arc<dyn Clock> = clock.clone();
+        let server = daemon::start_in_process(
+            config_manager,
+            runtime_dir,
+            Box::new(NoopHooks {}),
+            socket.clone(),
+            listener,
+            daemon_clock,
+        )
+        .context("starting in-process daemon")?;
+
+        Ok(Daemon { _tmp_dir: tmp_dir, socket, clock, server })
+    }
+
+    /// Do what the daemon does on SIGTERM, short of exiting.
+    pub fn shutdown(&self) {
+        self.server.shutdown();
+    }
+
+    /// The path to the daemon's control socket.
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// The clock driving the daemon's timers.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// Attach to the given session, creating it if needed.
+    pub fn attach(&self, name: &str) -> anyhow::Result<Client> {
+        self.attach_with(AttachHeader {
+            name: String::from(name),
+            local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+            client_version: String::from(shpool_protocol::VERSION),
+            notice_chunks: true,
+            ..AttachHeader::default()
+        })
+    }
+
+    /// Attach with a fully custom header.
+    pub fn attach_with(&self, header: AttachHeader) -> anyhow::Result<Client> {
+        let mut stream = self.connect()?;
+        protocol::encode_to(&ConnectHeader::Attach(header), &mut stream)
+            .context("writing attach header")?;
+        let reply: AttachReplyHeader =
+            protocol::decode_from(&mut stream).context("reading attach reply")?;
+        Ok(Client {
+            stream,
+            status: reply.status,
+            output: vec![],
+            notices: vec![],
+            heartbeats: 0,
+            exit_status: None,
+        })
+    }
+
+    pub fn list(&self) -> anyhow::Result<ListReply> {
+        self.request(ConnectHeader::List)
+    }
+
+    pub fn detach(&self, sessions: Vec<String>) -> anyhow::Result<DetachReply> {
+        self.request(ConnectHeader::Detach(DetachRequest {
+            sessions,
+            panes: false,
+            dry_run: false,
+        }))
+    }
+
+    pub fn kill(&self, sessions: Vec<String>) -> anyhow::Result<KillReply> {
+        self.request(ConnectHeader::Kill(KillRequest { sessions, panes: false, dry_run: false }))
+    }
+
+    pub fn kill_with_panes(&self, sessions: Vec<String>) -> anyhow::Result<KillReply> {
+        self.request(ConnectHeader::Kill(KillRequest { sessions, panes: true, dry_run: false }))
+    }
+
+    pub fn kill_dry_run(&self, sessions: Vec<String>) -> anyhow::Result<KillReply> {
+        self.request(ConnectHeader::Kill(KillRequest { sessions, panes: false, dry_run: true }))
+    }
+
+    pub fn import(&self, sessions: Vec<SessionDefinition>) -> anyhow::Result<ImportReply> {
+        self.request(ConnectHeader::Import(ImportRequest { sessions }))
+    }
+
+    pub fn stats(&self) -> anyhow::Result<StatsReply> {
+        self.request(ConnectHeader::Stats)
+    }
+
+    /// Poll the session list until `pred` holds, failing if it does
+    /// not within the client timeout.
+    pub fn wait_for_list<F>(&self, pred: F) -> anyhow::Result<ListReply>
+    where
+        F: Fn(&ListReply) -> bool,
+    {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            let reply = self.list()?;
+            if pred(&reply) {
+                return Ok(reply);
+            }
+            if Instant::now() > deadline {
+                return Err(anyhow!("timed out waiting on session list, last saw {:?}", reply));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn request<R>(&self, header: ConnectHeader) -> anyhow::Result<R>
+    where
+        R: for<'de> serde::Deserialize<'de>,
+    {
+        let mut stream = self.connect()?;
+        protocol::encode_to(&header, &mut stream).context("writing connect header")?;
+        protocol::decode_from(&mut stream).context("reading reply")
+    }
+
+    fn connect(&self) -> anyhow::Result<UnixStream> {
+        let mut stream = UnixStream::connect(&self.socket).context("connecting to daemon")?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT)).context("setting read timeout")?;
+        let _: VersionHeader =
+            protocol::decode_from(&mut stream).context("reading version header")?;
+        Ok(stream)
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        // The daemon thread can't be stopped, but we can at least
+        // make sure that we don't leak shells.
+        if let Ok(reply) = self.list() {
+            let sessions = reply.sessions.into_iter().map(|s| s.name).collect::<Vec<_>>();
+            if !sessions.is_empty() {
+                if let Err(e) = self.kill(sessions) {
+                    error!("killing sessions on drop: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+/// A fake `shpool attach` that speaks the chunk protocol directly.
+pub struct Client {
+    stream: UnixStream,
+    status: AttachStatus,
+    output: Vec<u8>,
+    notices: Vec<String>,
+    heartbeats: usize,
+    exit_status: Option<i32>,
+}
+
+impl Client {
+    /// The status the daemon replied to the attach with.
+    pub fn status(&self) -> &AttachStatus {
+        &self.status
+    }
+
+    /// Send raw input to the shell.
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        use std::io::Write;
+        self.stream.write_all(data).context("writing to daemon")?;
+        self.stream.flush().context("flushing")?;
+        Ok(())
+    }
+
+    /// Send a line of input to the shell.
+    pub fn run_cmd(&mut self, cmd: &str) -> anyhow::Result<()> {
+        self.write(format!("{}\n", cmd).as_bytes())
+    }
+
+    /// Read output until `needle` shows up, consuming everything
+    /// up to and including it.
+    pub fn expect(&mut self, needle: &str) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            if let Some(pos) =
+                self.output.windows(needle.len()).position(|w| w == needle.as_bytes())
+            {
+                self.output.drain(..pos + needle.len());
+                return Ok(());
+            }
+            self.read_chunk(deadline).with_context(|| {
+                format!("waiting for {:?}, got {:?}", needle, String::from_utf8_lossy(&self.output))
+            })?;
+        }
+    }
+
+    /// Read and throw away at least `nbytes` bytes of output.
+    pub fn discard_output(&mut self, nbytes: usize) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        let mut seen = self.output.len();
+        self.output.clear();
+        while seen < nbytes {
+            self.read_chunk(deadline).context("discarding output")?;
+            seen += self.output.len();
+            self.output.clear();
+        }
+        Ok(())
+    }
+
+    /// Read output until the shell exits, returning its exit status.
+    pub fn wait_for_exit(&mut self) -> anyhow::Result<i32> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            if let Some(status) = self.exit_status {
+                return Ok(status);
+            }
+            self.read_chunk(deadline).context("waiting for exit status")?;
+        }
+    }
+
+    /// Read output until the daemon hangs up on us.
+    pub fn wait_for_disconnect(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        loop {
+            match self.read_chunk(deadline) {
+                Ok(()) => {}
+                Err(e) => {
+                    let hung_up = e.chain().any(|cause| {
+                        cause.downcast_ref::<io::Error>().map(|e| e.kind())
+                            == Some(io::ErrorKind::UnexpectedEof)
+                    });
+                    return if hung_up { Ok(()) } else { Err(e).context("waiting for disconnect") };
+                }
+            }
+        }
+    }
+
+    /// Read output until the daemon sends a heartbeat.
+    pub fn expect_heartbeat(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + CLIENT_TIMEOUT;
+        let seen = self.heartbeats;
+        while self.heartbeats == seen {
+            self.read_chunk(deadline).context("waiting for heartbeat")?;
+        }
+        Ok(())
+    }
+
+    /// The notices the daemon has sent so far.
+    pub fn notices(&self) -> &[String] {
+        &self.notices
+    }
+
+    fn read_chunk(&mut self, deadline: Instant) -> anyhow::Result<()> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("timed out"));
+        }
+        self.stream.set_read_timeout(Some(remaining)).context("setting read timeout")?;
+
+        let mut buf = vec![0; consts::BUF_SIZE];
+        let chunk = Chunk::read_into(&mut self.stream, &mut buf).context("reading chunk")?;
+        match chunk.kind {
+            ChunkKind::Data => self.output.extend_from_slice(chunk.buf),
+            ChunkKind::Notice => self.notices.push(String::from_utf8_lossy(chunk.buf).into()),
+            ChunkKind::ExitStatus => {
+                let mut status = chunk.buf;
+                self.exit_status =
+                    Some(status.read_i32::<LittleEndian>().context("reading exit status")?);
+            }
+            ChunkKind::Heartbeat => self.heartbeats += 1,
+        }
+        Ok(())
+    }
+}
+
+struct NoopHooks {}
+impl hooks::Hooks for NoopHooks {}
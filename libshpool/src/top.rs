@@ -0,0 +1,219 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    io,
+    io::Write,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, SessionStats, StatsReply};
+
+use crate::{context::ClientContext, duration, output, TopSort};
+
+/// One row of the table, with the counters turned into rates.
+#[derive(Debug, PartialEq)]
+struct Row {
+    name: String,
+    status: String,
+    output_bytes_per_sec: u64,
+    input_bytes_per_sec: u64,
+    cpu_percent: f64,
+}
+
+pub fn run(
+    sort: TopSort,
+    interval: String,
+    iterations: Option<usize>,
+    ctx: &ClientContext,
+) -> anyhow::Result<()> {
+    let interval = duration::parse(&interval).context("parsing interval")?;
+    if interval.is_zero() {
+        return Err(anyhow!("the interval must be positive"));
+    }
+
+    let mut last = poll(ctx)?;
+    let mut last_at = Instant::now();
+    let mut stdout = io::stdout().lock();
+    let mut i = 0;
+    while iterations.map(|n| i < n).unwrap_or(true) {
+        thread::sleep(interval);
+        let stats = poll(ctx)?;
+        let now = Instant::now();
+
+        let rows = rates(&last, &stats, now.duration_since(last_at), sort);
+        // Clear the screen and home the cursor before redrawing. That
+        // would wipe out a version mismatch warning printed when we
+        // connected, so it goes back at the top of every redraw.
+        write!(stdout, "\x1b[H\x1b[2J").context("clearing screen")?;
+        let warning = ctx.version_warning().filter(|_| output::mode() != output::Mode::Quiet);
+        render(&mut stdout, warning.as_deref(), &rows).context("writing table")?;
+        stdout.flush().context("flushing stdout")?;
+
+        last = stats;
+        last_at = now;
+        i += 1;
+    }
+
+    Ok(())
+}
+
+fn poll(ctx: &ClientContext) -> anyhow::Result<Vec<SessionStats>> {
+    let reply: StatsReply = ctx.request(ConnectHeader::Stats).context("polling stats")?;
+    Ok(reply.sessions)
+}
+
+/// Diff two sets of counters taken `elapsed` apart. Sessions that are
+/// new since the last poll are measured from zero.
+fn rates(
+    last: &[SessionStats],
+    current: &[SessionStats],
+    elapsed: Duration,
+    sort: TopSort,
+) -> Vec<Row> {
+    let last = last.iter().map(|s| (s.name.as_str(), s)).collect::<HashMap<_, _>>();
+    let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+
+    let mut rows = current
+        .iter()
+        .map(|s| {
+            let (output_bytes, input_bytes, cpu_time_ms) = match last.get(s.name.as_str()) {
+                Some(l) => (l.output_bytes, l.input_bytes, l.cpu_time_ms),
+                None => (0, 0, 0),
+            };
+            Row {
+                name: s.name.clone(),
+                status: s.status.to_string(),
+                output_bytes_per_sec: (s.output_bytes.saturating_sub(output_bytes) as f64 / secs)
+                    as u64,
+                input_bytes_per_sec: (s.input_bytes.saturating_sub(input_bytes) as f64 / secs)
+                    as u64,
+                cpu_percent: s.cpu_time_ms.saturating_sub(cpu_time_ms) as f64 / 10.0 / secs,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    rows.sort_by(|a, b| {
+        let ord = match sort {
+            TopSort::Output => b.output_bytes_per_sec.cmp(&a.output_bytes_per_sec),
+            TopSort::Input => b.input_bytes_per_sec.cmp(&a.input_bytes_per_sec),
+            TopSort::Cpu => b.cpu_percent.total_cmp(&a.cpu_percent),
+        };
+        ord.then_with(|| a.name.cmp(&b.name))
+    });
+    rows
+}
+
+fn render<W: Write>(w: &mut W, warning: Option<&str>, rows: &[Row]) -> io::Result<()> {
+    if let Some(warning) = warning {
+        writeln!(w, "{}", warning)?;
+    }
+    let name_width = rows.iter().map(|r| r.name.len()).max().unwrap_or(0).max("NAME".len());
+    writeln!(
+        w,
+        "{:<name_width$}  {:<12}  {:>10}  {:>10}  {:>6}",
+        "NAME", "STATUS", "OUT/S", "IN/S", "CPU%"
+    )?;
+    for row in rows.iter() {
+        writeln!(
+            w,
+            "{:<name_width$}  {:<12}  {:>10}  {:>10}  {:>6.1}",
+            row.name,
+            row.status,
+            human_bytes(row.output_bytes_per_sec),
+            human_bytes(row.input_bytes_per_sec),
+            row.cpu_percent,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut n = n as f64;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < UNITS.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", n, UNITS[unit])
+    } else {
+        format!("{:.1}{}", n, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use shpool_protocol::SessionStatus;
+
+    use super::*;
+
+    fn stats(name: &str, output_bytes: u64, input_bytes: u64, cpu_time_ms: u64) -> SessionStats {
+        SessionStats {
+            name: String::from(name),
+            status: SessionStatus::Disconnected,
+            output_bytes,
+            input_bytes,
+            cpu_time_ms,
+        }
+    }
+
+    #[test]
+    fn rates_sorting() {
+        let last = vec![stats("a", 100, 0, 0), stats("b", 0, 0, 0)];
+        let current = vec![stats("a", 300, 10, 500), stats("b", 1000, 0, 0), stats("c", 50, 0, 0)];
+
+        let rows = rates(&last, &current, Duration::from_secs(2), TopSort::Output);
+        let got = rows
+            .iter()
+            .map(|r| (r.name.as_str(), r.output_bytes_per_sec, r.input_bytes_per_sec))
+            .collect::<Vec<_>>();
+        assert_eq!(got, vec![("b", 500, 0), ("a", 100, 5), ("c", 25, 0)]);
+        assert_eq!(rows[1].cpu_percent, 25.0);
+
+        let rows = rates(&last, &current, Duration::from_secs(2), TopSort::Cpu);
+        let got = rows.iter().map(|r| r.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(got, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn render_keeps_warning() -> anyhow::Result<()> {
+        let rows = rates(&[], &[stats("a", 2048, 0, 0)], Duration::from_secs(1), TopSort::Output);
+
+        let mut out = vec![];
+        render(&mut out, None, &rows)?;
+        let out = String::from_utf8(out)?;
+        assert!(out.starts_with("NAME"));
+        assert!(out.contains("2.0KiB"));
+
+        let mut out = vec![];
+        render(&mut out, Some("warning: version mismatch"), &rows)?;
+        let out = String::from_utf8(out)?;
+        assert_eq!(out.lines().next(), Some("warning: version mismatch"));
+        assert!(out.lines().nth(1).unwrap_or("").starts_with("NAME"));
+        Ok(())
+    }
+
+    #[test]
+    fn human() {
+        let cases = vec![(0, "0B"), (1023, "1023B"), (1024, "1.0KiB"), (3 * 1024 * 1024, "3.0MiB")];
+        for (n, want) in cases.into_iter() {
+            assert_eq!(human_bytes(n), want);
+        }
+    }
+}
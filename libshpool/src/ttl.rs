@@ -0,0 +1,54 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time;
+
+use anyhow::{anyhow, Context};
+use shpool_protocol::{ConnectHeader, ExtendTtlReply, ExtendTtlRequest, ExtendTtlStatus};
+
+use crate::{context::ClientContext, duration, Error};
+
+pub fn extend(session: String, extension: String, ctx: &ClientContext) -> anyhow::Result<()> {
+    let extension = duration::parse(&extension).context("parsing ttl extension")?;
+
+    let mut client = ctx.connect()?;
+
+    client
+        .write_connect_header(ConnectHeader::ExtendTtl(ExtendTtlRequest {
+            session: session.clone(),
+            extension_secs: extension.as_secs(),
+        }))
+        .context("writing extend ttl request header")?;
+
+    let reply: ExtendTtlReply = client.read_reply().context("reading reply")?;
+
+    match reply.status {
+        ExtendTtlStatus::Extended { remaining_secs } => {
+            println!(
+                "session '{}' will now be killed in {}",
+                session,
+                duration::format(time::Duration::from_secs(remaining_secs))
+            );
+            Ok(())
+        }
+        ExtendTtlStatus::NotFound => {
+            eprintln!("not found: {}", session);
+            Err(Error::SessionsNotFound(vec![session]).into())
+        }
+        ExtendTtlStatus::NoTtl => {
+            eprintln!("session '{}' does not have a ttl", session);
+            Err(anyhow!("session '{}' does not have a ttl", session))
+        }
+    }
+}
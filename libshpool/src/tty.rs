@@ -18,13 +18,17 @@ use std::{
         fd::BorrowedFd,
         unix::io::{AsRawFd, RawFd},
     },
+    sync::Mutex,
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use lazy_static::lazy_static;
 use nix::{
     sys::{
         termios,
-        termios::{ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg},
+        termios::{
+            ControlFlags, InputFlags, LocalFlags, OutputFlags, SetArg, SpecialCharacterIndices,
+        },
     },
     unistd::isatty,
 };
@@ -82,6 +86,40 @@ impl TtySizeExt for TtySize {
     }
 }
 
+/// Render a notice from the daemon on the bottom line of the terminal,
+/// saving and restoring the cursor so the notice does not get mixed up
+/// with the shell's output. The next redraw of that line will clear
+/// the notice away. If stdout is not a terminal, the notice just goes
+/// to stderr on its own line.
+pub fn render_notice<W: io::Write>(stdout: &mut W, notice: &str) -> io::Result<()> {
+    // Don't let the daemon smuggle escape codes into the user's terminal.
+    let notice: String = notice.chars().filter(|c| !c.is_control()).collect();
+    let prefix = "shpool: ";
+
+    let size = if isatty(consts::STDOUT_FD).unwrap_or(false) {
+        TtySize::from_fd(consts::STDOUT_FD).ok()
+    } else {
+        None
+    };
+    match size {
+        Some(size) if size.rows > 0 && size.cols as usize > prefix.len() => {
+            let notice: String = notice.chars().take(size.cols as usize - prefix.len()).collect();
+            // save cursor, jump to the bottom row, clear it, write the notice
+            // in reverse video, then restore the cursor
+            write!(
+                stdout,
+                "\x1b7\x1b[{};1H\x1b[2K\x1b[7m{}{}\x1b[0m\x1b8",
+                size.rows, prefix, notice
+            )?;
+            stdout.flush()
+        }
+        _ => {
+            eprintln!("{}{}", prefix, notice);
+            Ok(())
+        }
+    }
+}
+
 pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
     let mut term = termios::tcgetattr(fd).context("grabbing term flags")?;
     term.local_flags &= !LocalFlags::ECHO;
@@ -91,7 +129,34 @@ pub fn disable_echo(fd: BorrowedFd<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Which of the signal generating characters on the client tty should
+/// raise signals for `shpool attach` itself, rather than getting passed
+/// along to the session like any other input. By default, all of them
+/// go to the session, where the session's own pty turns them into
+/// signals for whatever is running in the foreground there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LocalSignals {
+    /// The suspend character (usually Ctrl-Z) raises SIGTSTP.
+    pub suspend: bool,
+    /// The quit character (usually Ctrl-\) raises SIGQUIT.
+    pub quit: bool,
+}
+
+lazy_static! {
+    /// The flags from before the attach and the raw attach flags, while
+    /// an attach has the tty, so that the signal handlers can flip back
+    /// and forth between them.
+    static ref ATTACH_FLAGS: Mutex<Option<(termios::Termios, termios::Termios)>> =
+        Mutex::new(None);
+}
+
 pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
+    set_attach_flags_with(LocalSignals::default())
+}
+
+/// Put the tty into raw mode for an attach, leaving signal generation
+/// on for just the characters in `local`.
+pub fn set_attach_flags_with(local: LocalSignals) -> anyhow::Result<AttachFlagsGuard<'static>> {
     // Safety: stdin is live for the whole program duration
     let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
 
@@ -125,11 +190,58 @@ pub fn set_attach_flags() -> anyhow::Result<AttachFlagsGuard<'static>> {
         | LocalFlags::IEXTEN);
     new.control_flags &= !(ControlFlags::CSIZE | ControlFlags::PARENB);
     new.control_flags |= ControlFlags::CS8;
+    if local != LocalSignals::default() {
+        // The line discipline only looks at the special characters when
+        // ISIG is on, so turn it back on and disable every signal
+        // character that should go to the session instead.
+        new.local_flags |= LocalFlags::ISIG;
+        let mut disabled = vec![SpecialCharacterIndices::VINTR];
+        #[cfg(any(
+            target_os = "freebsd",
+            target_os = "dragonfly",
+            target_os = "netbsd",
+            target_os = "openbsd",
+            target_os = "macos"
+        ))]
+        disabled.push(SpecialCharacterIndices::VDSUSP);
+        if !local.suspend {
+            disabled.push(SpecialCharacterIndices::VSUSP);
+        }
+        if !local.quit {
+            disabled.push(SpecialCharacterIndices::VQUIT);
+        }
+        for idx in disabled.into_iter() {
+            new.control_chars[idx as usize] = termios::_POSIX_VDISABLE;
+        }
+    }
     termios::tcsetattr(fd, SetArg::TCSANOW, &new)?;
 
+    *ATTACH_FLAGS.lock().unwrap() = Some((old.clone(), new));
     Ok(AttachFlagsGuard { fd, old: Some(old) })
 }
 
+/// Put the tty back the way it was before the attach, for example right
+/// before `shpool attach` gets suspended. Only works while an attach
+/// has the tty.
+pub fn restore_pre_attach_flags() -> anyhow::Result<()> {
+    let flags = ATTACH_FLAGS.lock().unwrap();
+    let (old, _) = flags.as_ref().ok_or(anyhow!("tty is not in attach mode"))?;
+    // Safety: stdin is live for the whole program duration
+    let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
+    termios::tcsetattr(fd, SetArg::TCSANOW, old).context("restoring term flags")?;
+    Ok(())
+}
+
+/// Undo `restore_pre_attach_flags`, putting the tty back in raw mode.
+pub fn reapply_attach_flags() -> anyhow::Result<()> {
+    let flags = ATTACH_FLAGS.lock().unwrap();
+    let (_, raw) = flags.as_ref().ok_or(anyhow!("tty is not in attach mode"))?;
+    // Safety: stdin is live for the whole program duration
+    let fd = unsafe { BorrowedFd::borrow_raw(consts::STDIN_FD) };
+    termios::tcsetattr(fd, SetArg::TCSANOW, raw).context("setting term flags")?;
+    Ok(())
+}
+
 pub struct AttachFlagsGuard<'fd> {
     fd: BorrowedFd<'fd>,
     old: Option<termios::Termios>,
@@ -138,6 +250,7 @@ pub struct AttachFlagsGuard<'fd> {
 impl std::ops::Drop for AttachFlagsGuard<'_> {
     fn drop(&mut self) {
         if let Some(old) = &self.old {
+            *ATTACH_FLAGS.lock().unwrap() = None;
             if let Err(e) = termios::tcsetattr(self.fd, SetArg::TCSANOW, old) {
                 error!("error restoring terminal settings: {:?}", e);
             }
@@ -0,0 +1,682 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An experimental datagram transport for the attach stream.
+//!
+//! The client asks for the udp transport in its AttachHeader, and the
+//! daemon responds with a UdpTransportOffer over the control socket
+//! containing the address of a freshly bound udp socket, a one-time
+//! key and the version of the datagram format the daemon speaks. If
+//! the client speaks the same version, it sends a hello datagram with
+//! the version, and from then on both sides bridge the attach stream
+//! over udp.
+//!
+//! Every datagram gets sealed with chacha20-poly1305 under the key from
+//! the offer, so the key never goes over the network, and datagrams
+//! from anyone who doesn't have it get dropped. The nonce is made up of
+//! the side that sent the packet, its kind and its sequence number, so
+//! the header is authenticated along with the payload, and no two
+//! different packets ever share a nonce. A resent packet is sealed the
+//! same way as the first copy, which is fine since it is the same
+//! packet.
+//!
+//! To keep the rest of shpool blissfully unaware of all this, each side
+//! hands back one end of a unix socket pair and runs a bridge thread that
+//! shuffles bytes between the other end and the udp socket. The bridge
+//! takes care of sequence numbers, acks and retransmission so that the
+//! byte stream it presents is reliable and in order.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    net::{self, SocketAddr, UdpSocket},
+    os::unix::net::UnixStream,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use shpool_protocol::UdpTransportOffer;
+use tracing::{error, info, instrument, warn};
+
+use crate::protocol;
+
+/// The version of the datagram format below. Bump this whenever the
+/// packet layout or the meaning of a packet changes, so that mismatched
+/// clients and daemons refuse each other up front rather than trading
+/// garbage.
+pub const VERSION: u32 = 2;
+
+/// The largest payload we put in a single datagram. This is small
+/// enough to avoid fragmentation on just about any link.
+const MAX_PAYLOAD: usize = 1200;
+/// The size of the packet header: a 1 byte kind followed by a
+/// little endian u64 sequence number.
+const HEADER_LEN: usize = 9;
+/// The size of the poly1305 tag that follows the sealed payload.
+const TAG_LEN: usize = 16;
+/// The largest datagram either side sends.
+const MAX_PACKET: usize = HEADER_LEN + MAX_PAYLOAD + TAG_LEN;
+/// The max number of unacked data packets in flight.
+const WINDOW: u64 = 128;
+/// The max number of in-order payloads waiting to be written to the
+/// local stream. Past this, data packets go unacked, which makes the
+/// peer hold off and retransmit them later.
+const DELIVER_QUEUE: usize = WINDOW as usize;
+/// How long to wait for an ack before first resending a packet. Each
+/// resend of the same packet doubles its wait, up to MAX_RETRANSMIT_TIMEOUT,
+/// so a congested or lossy link does not get flooded with copies.
+const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+const MAX_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(5);
+const TICK: Duration = Duration::from_millis(50);
+/// How often to send an ack even if nothing new has arrived, so the
+/// peer knows we are still around.
+const KEEPALIVE: Duration = Duration::from_secs(1);
+/// How long to go without hearing from the peer before giving up.
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PacketKind {
+    /// Sent by the client to establish the session. The payload is
+    /// the little endian u32 VERSION.
+    Hello = 0,
+    /// A chunk of the byte stream. An empty payload marks the end of
+    /// the stream.
+    Data = 1,
+    /// A cumulative ack, the sequence number is the next one the
+    /// sender expects to receive.
+    Ack = 2,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Packet<'a> {
+    kind: PacketKind,
+    seq: u64,
+    payload: &'a [u8],
+}
+
+impl<'a> Packet<'a> {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.seq.to_le_bytes());
+        buf.extend_from_slice(self.payload);
+        buf
+    }
+
+    fn decode(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let kind = match buf[0] {
+            0 => PacketKind::Hello,
+            1 => PacketKind::Data,
+            2 => PacketKind::Ack,
+            _ => return None,
+        };
+        let seq = u64::from_le_bytes(buf[1..HEADER_LEN].try_into().ok()?);
+        Some(Packet { kind, seq, payload: &buf[HEADER_LEN..] })
+    }
+}
+
+/// Which end of the transport a packet came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client = 0,
+    Daemon = 1,
+}
+
+impl Side {
+    fn peer(self) -> Side {
+        match self {
+            Side::Client => Side::Daemon,
+            Side::Daemon => Side::Client,
+        }
+    }
+}
+
+/// Seals the packets one side sends and opens the ones its peer sends.
+struct Sealer {
+    aead: ChaCha20Poly1305,
+    side: Side,
+}
+
+impl Sealer {
+    fn new(key: &[u8], side: Side) -> anyhow::Result<Sealer> {
+        let aead = ChaCha20Poly1305::new_from_slice(key)
+            .map_err(|_| anyhow!("udp key is {} bytes, it must be {}", key.len(), KEY_LEN))?;
+        Ok(Sealer { aead, side })
+    }
+
+    fn nonce(side: Side, kind: PacketKind, seq: u64) -> Nonce {
+        let mut nonce = [0; 12];
+        nonce[0] = side as u8;
+        nonce[1] = kind as u8;
+        nonce[4..].copy_from_slice(&seq.to_le_bytes());
+        Nonce::from(nonce)
+    }
+
+    fn seal(&self, pkt: &Packet) -> Vec<u8> {
+        let mut buf = Packet { payload: &[], ..*pkt }.encode();
+        let sealed = self
+            .aead
+            .encrypt(
+                &Self::nonce(self.side, pkt.kind, pkt.seq),
+                Payload { msg: pkt.payload, aad: &buf },
+            )
+            .expect("a packet is never too long to seal");
+        buf.extend(sealed);
+        buf
+    }
+
+    /// Open a packet from the peer, returning None if it is garbage or
+    /// was not sealed with our key. The payload goes in `plain`.
+    fn open<'a>(&self, buf: &[u8], plain: &'a mut Vec<u8>) -> Option<Packet<'a>> {
+        let Packet { kind, seq, payload } = Packet::decode(buf)?;
+        *plain = self
+            .aead
+            .decrypt(
+                &Self::nonce(self.side.peer(), kind, seq),
+                Payload { msg: payload, aad: &buf[..HEADER_LEN] },
+            )
+            .ok()?;
+        Some(Packet { kind, seq, payload: plain })
+    }
+}
+
+/// Daemon side of the handshake. Write an offer to the client over the
+/// control socket, wait for the client to say hello, then return a stream
+/// which is bridged to the client over udp. The udp socket is bound to
+/// a free port on `bind_addr`.
+#[instrument(skip_all)]
+pub fn offer(stream: &mut UnixStream, bind_addr: net::IpAddr) -> anyhow::Result<UnixStream> {
+    let sock = UdpSocket::bind(SocketAddr::new(bind_addr, 0)).context("binding udp socket")?;
+    let key = gen_key()?;
+    let sealer = Sealer::new(&key, Side::Daemon)?;
+    let addr = sock.local_addr().context("getting udp addr")?;
+    info!("offering udp transport version {} on {}", VERSION, addr);
+    protocol::encode_to(
+        &UdpTransportOffer { addr: Some(addr.to_string()), token: key, version: VERSION },
+        &mut *stream,
+    )
+    .context("writing udp offer")?;
+
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut buf = vec![0; MAX_PACKET];
+    let mut plain = vec![];
+    let peer = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(anyhow!("timed out waiting for udp hello"));
+        }
+        sock.set_read_timeout(Some(remaining)).context("setting handshake timeout")?;
+        let (len, peer) = sock.recv_from(&mut buf).context("waiting for udp hello")?;
+        match sealer.open(&buf[..len], &mut plain) {
+            Some(Packet { kind: PacketKind::Hello, payload, .. })
+                if payload == VERSION.to_le_bytes() =>
+            {
+                break peer;
+            }
+            _ => warn!("dropping unexpected datagram from {} during handshake", peer),
+        }
+    };
+    info!("got udp hello from {}", peer);
+
+    sock.connect(peer).context("connecting udp socket to client")?;
+    sock.send(&sealer.seal(&Packet { kind: PacketKind::Ack, seq: 0, payload: &[] }))
+        .context("acking hello")?;
+    spawn_bridge(sock, sealer)
+}
+
+/// Client side of the handshake. Check that the daemon speaks our
+/// version of the datagram format, say hello to it at the offered
+/// address, then return a stream which is bridged to the daemon over udp.
+#[instrument(skip_all)]
+pub fn connect(offer: &UdpTransportOffer) -> anyhow::Result<UnixStream> {
+    if offer.version != VERSION {
+        return Err(anyhow!(
+            "daemon speaks udp transport version {}, but this client speaks version {}",
+            offer.version,
+            VERSION
+        ));
+    }
+    let addr = offer.addr.as_deref().ok_or(anyhow!("udp offer has no address"))?;
+    let addr: SocketAddr = addr.parse().context("parsing udp addr")?;
+    let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let sealer = Sealer::new(&offer.token, Side::Client)?;
+    let sock = UdpSocket::bind(bind_addr).context("binding udp socket")?;
+    sock.connect(addr).context("connecting udp socket to daemon")?;
+
+    let hello =
+        sealer.seal(&Packet { kind: PacketKind::Hello, seq: 0, payload: &VERSION.to_le_bytes() });
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    let mut rto = INITIAL_RETRANSMIT_TIMEOUT;
+    let mut buf = vec![0; MAX_PACKET];
+    let mut plain = vec![];
+    loop {
+        if Instant::now() > deadline {
+            return Err(anyhow!("timed out waiting for daemon to ack udp hello"));
+        }
+        sock.set_read_timeout(Some(rto)).context("setting handshake timeout")?;
+        rto = backoff(rto);
+        sock.send(&hello).context("sending udp hello")?;
+        match sock.recv(&mut buf) {
+            Ok(len) => {
+                if let Some(Packet { kind: PacketKind::Ack, .. }) =
+                    sealer.open(&buf[..len], &mut plain)
+                {
+                    break;
+                }
+            }
+            Err(e) if is_timeout(&e) => continue,
+            Err(e) => return Err(e).context("waiting for hello ack"),
+        }
+    }
+    info!("udp transport established with {}", addr);
+
+    spawn_bridge(sock, sealer)
+}
+
+fn spawn_bridge(sock: UdpSocket, sealer: Sealer) -> anyhow::Result<UnixStream> {
+    let (local, remote) = UnixStream::pair().context("creating bridge socket pair")?;
+    thread::Builder::new()
+        .name(String::from("udp-bridge"))
+        .spawn(move || {
+            if let Err(e) = bridge(sock, sealer, remote) {
+                error!("udp bridge: {:?}", e);
+            }
+        })
+        .context("spawning udp bridge")?;
+    Ok(local)
+}
+
+/// A data packet that has been sent but not acked yet.
+struct InFlight {
+    seq: u64,
+    pkt: Vec<u8>,
+    /// When the packet was last sent.
+    sent_at: Instant,
+    /// How long to wait after `sent_at` before sending it again.
+    rto: Duration,
+}
+
+struct SendState {
+    next_seq: u64,
+    unacked: VecDeque<InFlight>,
+    sent_eof: bool,
+    closed: bool,
+}
+
+/// Shuffle bytes between `local` and the peer on the other end of `sock`
+/// until both directions have hit EOF or the peer goes quiet.
+fn bridge(sock: UdpSocket, sealer: Sealer, local: UnixStream) -> anyhow::Result<()> {
+    let sock = Arc::new(sock);
+    let sealer = Arc::new(sealer);
+    let send_state = Arc::new((
+        Mutex::new(SendState {
+            next_seq: 0,
+            unacked: VecDeque::new(),
+            sent_eof: false,
+            closed: false,
+        }),
+        Condvar::new(),
+    ));
+
+    let sender_h = {
+        let sock = Arc::clone(&sock);
+        let sealer = Arc::clone(&sealer);
+        let send_state = Arc::clone(&send_state);
+        let mut local_reader = local.try_clone().context("cloning local stream")?;
+        thread::Builder::new()
+            .name(String::from("udp-send"))
+            .spawn(move || -> anyhow::Result<()> {
+                let mut buf = vec![0; MAX_PAYLOAD];
+                loop {
+                    // An empty read, whether from EOF or an error, gets sent as
+                    // an empty data packet to tell the peer we are done.
+                    let len = local_reader.read(&mut buf).unwrap_or(0);
+
+                    let (lock, cvar) = &*send_state;
+                    let mut state = lock.lock().unwrap();
+                    while state.unacked.len() as u64 >= WINDOW && !state.closed {
+                        state = cvar.wait(state).unwrap();
+                    }
+                    if state.closed {
+                        return Ok(());
+                    }
+
+                    let seq = state.next_seq;
+                    state.next_seq += 1;
+                    let pkt =
+                        sealer.seal(&Packet { kind: PacketKind::Data, seq, payload: &buf[..len] });
+                    // A failed send is just a lost packet as far as we are
+                    // concerned, it will get retransmitted.
+                    let _ = sock.send(&pkt);
+                    state.unacked.push_back(InFlight {
+                        seq,
+                        pkt,
+                        sent_at: Instant::now(),
+                        rto: INITIAL_RETRANSMIT_TIMEOUT,
+                    });
+                    if len == 0 {
+                        state.sent_eof = true;
+                        return Ok(());
+                    }
+                }
+            })
+            .context("spawning udp sender")?
+    };
+
+    // Writing to the local stream can block for as long as whoever is
+    // on the other end of it feels like, so it happens on its own
+    // thread rather than holding up acks and retransmits.
+    let (deliver_tx, deliver_rx) = crossbeam_channel::bounded(DELIVER_QUEUE);
+    let writer_h = {
+        let local_writer = local.try_clone().context("cloning local stream")?;
+        thread::Builder::new()
+            .name(String::from("udp-deliver"))
+            .spawn(move || deliver_loop(deliver_rx, local_writer))
+            .context("spawning udp deliverer")?
+    };
+
+    sock.set_read_timeout(Some(TICK)).context("setting bridge read timeout")?;
+    let mut buf = vec![0; MAX_PACKET];
+    let mut plain = vec![];
+    let mut next_expected: u64 = 0;
+    // Payloads that have arrived but not been handed to the writer yet,
+    // either because they arrived out of order or because it is backed
+    // up.
+    let mut pending: BTreeMap<u64, Vec<u8>> = BTreeMap::new();
+    let mut got_eof = false;
+    let mut last_recv = Instant::now();
+    let mut last_ack = Instant::now();
+    let res = loop {
+        match sock.recv(&mut buf) {
+            Ok(len) => match sealer.open(&buf[..len], &mut plain) {
+                Some(Packet { kind: PacketKind::Data, seq, payload }) => {
+                    last_recv = Instant::now();
+                    if seq >= next_expected && seq < next_expected + 2 * WINDOW {
+                        pending.entry(seq).or_insert_with(|| payload.to_vec());
+                    }
+                    got_eof |= deliver(&deliver_tx, &mut pending, &mut next_expected);
+                    // Always ack, even duplicates, since a duplicate
+                    // means our last ack probably got lost.
+                    let _ = sock.send(&sealer.seal(&Packet {
+                        kind: PacketKind::Ack,
+                        seq: next_expected,
+                        payload: &[],
+                    }));
+                    last_ack = Instant::now();
+                }
+                Some(Packet { kind: PacketKind::Ack, seq, .. }) => {
+                    last_recv = Instant::now();
+                    let (lock, cvar) = &*send_state;
+                    let mut state = lock.lock().unwrap();
+                    while state.unacked.front().map(|f| f.seq < seq).unwrap_or(false) {
+                        state.unacked.pop_front();
+                    }
+                    cvar.notify_all();
+                }
+                // Retransmitted hellos from the handshake, or datagrams
+                // that were not sealed with our key.
+                _ => {}
+            },
+            Err(e) if is_timeout(&e) => {}
+            // The peer's port is unreachable. It might come back (i.e. if
+            // this is a transient network issue), so rely on PEER_TIMEOUT
+            // to decide when to give up.
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(e) => break Err(e).context("receiving datagram"),
+        }
+
+        // The writer might have made room for payloads that were held
+        // back.
+        got_eof |= deliver(&deliver_tx, &mut pending, &mut next_expected);
+
+        let now = Instant::now();
+        let done_sending = {
+            let (lock, _) = &*send_state;
+            let mut state = lock.lock().unwrap();
+            for inflight in state.unacked.iter_mut() {
+                if now.duration_since(inflight.sent_at) > inflight.rto {
+                    let _ = sock.send(&inflight.pkt);
+                    inflight.sent_at = now;
+                    inflight.rto = backoff(inflight.rto);
+                }
+            }
+            state.sent_eof && state.unacked.is_empty()
+        };
+        if got_eof && done_sending {
+            info!("both directions finished, closing udp bridge");
+            break Ok(());
+        }
+        if now.duration_since(last_recv) > PEER_TIMEOUT {
+            break Err(anyhow!("no word from udp peer in {:?}", PEER_TIMEOUT));
+        }
+        if now.duration_since(last_ack) > KEEPALIVE {
+            let _ = sock.send(&sealer.seal(&Packet {
+                kind: PacketKind::Ack,
+                seq: next_expected,
+                payload: &[],
+            }));
+            last_ack = now;
+        }
+    };
+
+    // Let the writer finish up with what it has queued unless we are
+    // bailing, in which case it gets cut off.
+    drop(deliver_tx);
+    if res.is_err() {
+        let _ = local.shutdown(net::Shutdown::Both);
+    }
+    if let Err(e) = writer_h.join() {
+        return Err(anyhow!("joining udp bridge deliverer: {:?}", e));
+    }
+
+    // Unblock the sender thread, whether it is waiting on the window
+    // or on reading from the local stream.
+    {
+        let (lock, cvar) = &*send_state;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_all();
+    }
+    let _ = local.shutdown(net::Shutdown::Both);
+    match sender_h.join() {
+        Ok(sender_res) => sender_res.context("udp bridge sender")?,
+        Err(e) => return Err(anyhow!("joining udp bridge sender: {:?}", e)),
+    }
+
+    res
+}
+
+/// Hand the payloads that are next in line over to the writer thread,
+/// stopping early if it is backed up, in which case the rest stay
+/// pending. Returns true once the end of the stream has been handed
+/// over.
+fn deliver(
+    tx: &crossbeam_channel::Sender<Vec<u8>>,
+    pending: &mut BTreeMap<u64, Vec<u8>>,
+    next_expected: &mut u64,
+) -> bool {
+    let mut eof = false;
+    while let Some(payload) = pending.remove(next_expected) {
+        let is_eof = payload.is_empty();
+        // A disconnected writer has given up on the local stream, so
+        // there is nothing to do but drop the payload.
+        if let Err(crossbeam_channel::TrySendError::Full(payload)) = tx.try_send(payload) {
+            pending.insert(*next_expected, payload);
+            break;
+        }
+        *next_expected += 1;
+        eof |= is_eof;
+    }
+    eof
+}
+
+/// Write payloads to the local stream in order. An empty payload marks
+/// the end of the stream.
+fn deliver_loop(rx: crossbeam_channel::Receiver<Vec<u8>>, mut local: UnixStream) {
+    for payload in rx.iter() {
+        if payload.is_empty() {
+            let _ = local.shutdown(net::Shutdown::Write);
+            return;
+        }
+        if let Err(e) = local.write_all(&payload) {
+            warn!("writing to local stream: {:?}", e);
+            return;
+        }
+    }
+}
+
+/// The next retransmit timeout after one has expired.
+fn backoff(rto: Duration) -> Duration {
+    std::cmp::min(rto * 2, MAX_RETRANSMIT_TIMEOUT)
+}
+
+fn gen_key() -> anyhow::Result<Vec<u8>> {
+    let mut key = vec![0; KEY_LEN];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut key))
+        .context("generating udp key")?;
+    Ok(key)
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn packet_round_trip() {
+        let cases = vec![
+            Packet { kind: PacketKind::Hello, seq: 0, payload: b"token" },
+            Packet { kind: PacketKind::Data, seq: 42, payload: b"some data" },
+            Packet { kind: PacketKind::Data, seq: u64::MAX, payload: b"" },
+            Packet { kind: PacketKind::Ack, seq: 7, payload: b"" },
+        ];
+        for pkt in cases.into_iter() {
+            let buf = pkt.encode();
+            assert_eq!(Packet::decode(&buf), Some(pkt));
+        }
+
+        assert_eq!(Packet::decode(&[1, 2, 3]), None);
+        assert_eq!(Packet::decode(&[9; HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn sealing() -> anyhow::Result<()> {
+        let client = Sealer::new(&[7; KEY_LEN], Side::Client)?;
+        let daemon = Sealer::new(&[7; KEY_LEN], Side::Daemon)?;
+        let pkt = Packet { kind: PacketKind::Data, seq: 3, payload: b"hunter2" };
+        let sealed = client.seal(&pkt);
+        assert!(!sealed.windows(7).any(|w| w == b"hunter2"));
+        let mut plain = vec![];
+        assert_eq!(daemon.open(&sealed, &mut plain), Some(pkt));
+
+        // packets can't be bounced back at their sender, or opened
+        // with another key
+        assert_eq!(client.open(&sealed, &mut plain), None);
+        assert_eq!(Sealer::new(&[8; KEY_LEN], Side::Daemon)?.open(&sealed, &mut plain), None);
+        // the header is covered too
+        let mut moved = sealed.clone();
+        moved[1] += 1;
+        assert_eq!(daemon.open(&moved, &mut plain), None);
+
+        assert!(Sealer::new(&[7; 16], Side::Client).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn deliver_holds_back_when_backed_up() {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let mut pending: BTreeMap<u64, Vec<u8>> =
+            [(0, b"a".to_vec()), (1, b"b".to_vec()), (3, vec![])].into_iter().collect();
+        let mut next_expected = 0;
+
+        assert!(!deliver(&tx, &mut pending, &mut next_expected));
+        assert_eq!(next_expected, 1);
+        assert_eq!(rx.try_recv(), Ok(b"a".to_vec()));
+
+        // 2 is still missing, so the end of the stream has to wait
+        assert!(!deliver(&tx, &mut pending, &mut next_expected));
+        assert_eq!(next_expected, 2);
+        assert_eq!(rx.try_recv(), Ok(b"b".to_vec()));
+        pending.insert(2, b"c".to_vec());
+        assert!(!deliver(&tx, &mut pending, &mut next_expected));
+        assert_eq!(rx.try_recv(), Ok(b"c".to_vec()));
+        assert!(deliver(&tx, &mut pending, &mut next_expected));
+        assert_eq!(next_expected, 4);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut rto = INITIAL_RETRANSMIT_TIMEOUT;
+        let mut waits = vec![];
+        for _ in 0..8 {
+            waits.push(rto);
+            rto = backoff(rto);
+        }
+        assert_eq!(waits[..3], [200, 400, 800].map(Duration::from_millis));
+        assert_eq!(*waits.last().unwrap(), MAX_RETRANSMIT_TIMEOUT);
+    }
+
+    #[test]
+    fn version_mismatch_refused() {
+        let offer = UdpTransportOffer {
+            addr: Some(String::from("127.0.0.1:9")),
+            token: vec![1; KEY_LEN],
+            version: VERSION + 1,
+        };
+        let err = connect(&offer).unwrap_err();
+        assert!(format!("{:?}", err).contains("udp transport version"), "{:?}", err);
+    }
+
+    #[test]
+    #[ntest::timeout(30000)]
+    fn bridge_round_trip() -> anyhow::Result<()> {
+        let a = UdpSocket::bind("127.0.0.1:0")?;
+        let b = UdpSocket::bind("127.0.0.1:0")?;
+        a.connect(b.local_addr()?)?;
+        b.connect(a.local_addr()?)?;
+        let mut a_local = spawn_bridge(a, Sealer::new(&[1; KEY_LEN], Side::Client)?)?;
+        let mut b_local = spawn_bridge(b, Sealer::new(&[1; KEY_LEN], Side::Daemon)?)?;
+
+        // big enough to need several packets and fill the window
+        let data: Vec<u8> = (0..(MAX_PAYLOAD * 300)).map(|i| (i % 251) as u8).collect();
+        let writer_data = data.clone();
+        let writer_h = thread::spawn(move || -> io::Result<()> {
+            a_local.write_all(&writer_data)?;
+            a_local.shutdown(net::Shutdown::Write)
+        });
+
+        let mut got = vec![];
+        b_local.read_to_end(&mut got)?;
+        writer_h.join().unwrap()?;
+        assert_eq!(got, data);
+
+        Ok(())
+    }
+}
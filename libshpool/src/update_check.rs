@@ -0,0 +1,111 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{cmp, process};
+
+use anyhow::{anyhow, Context};
+use serde_derive::Deserialize;
+use tracing::info;
+
+/// The version of shpool we were built as part of. The shpool binary
+/// and libshpool are always released in lockstep.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The sparse registry index entry for the shpool crate. Each line
+/// of the file is a json blob describing one published version.
+const INDEX_URL: &str = "https://index.crates.io/sh/po/shpool";
+
+#[derive(Deserialize, Debug)]
+struct IndexEntry {
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Compare the running version of shpool against the latest release
+/// on crates.io. Returns a non-zero exit status if there is a newer
+/// release so that this can easily be scripted.
+pub fn run() -> anyhow::Result<i32> {
+    // We shell out to curl rather than pulling in an http client since
+    // this is the only thing that needs to talk to the network.
+    let out = process::Command::new("curl")
+        .arg("--silent")
+        .arg("--show-error")
+        .arg("--fail")
+        .arg("--location")
+        .arg(INDEX_URL)
+        .stderr(process::Stdio::inherit())
+        .output()
+        .context("running curl to fetch crates.io metadata")?;
+    if !out.status.success() {
+        return Err(anyhow!("fetching {} failed ({})", INDEX_URL, out.status));
+    }
+    let index = String::from_utf8_lossy(&out.stdout);
+
+    let latest = latest_release(&index)?;
+    info!("running version {}, latest release {}", VERSION, latest);
+    if version_cmp(VERSION, &latest)? == cmp::Ordering::Less {
+        println!("shpool {} is available (running {})", latest, VERSION);
+        return Ok(1);
+    }
+
+    println!("shpool {} is up to date", VERSION);
+    Ok(0)
+}
+
+/// Pull the newest non-yanked, non-prerelease version out of an index file.
+fn latest_release(index: &str) -> anyhow::Result<String> {
+    let mut latest: Option<String> = None;
+    for line in index.lines().filter(|l| !l.trim().is_empty()) {
+        let entry: IndexEntry = serde_json::from_str(line).context("parsing index entry")?;
+        if entry.yanked || entry.vers.contains('-') {
+            continue;
+        }
+        latest = match latest {
+            Some(l) if version_cmp(&l, &entry.vers)? != cmp::Ordering::Less => Some(l),
+            _ => Some(entry.vers),
+        };
+    }
+    latest.ok_or(anyhow!("no releases found in crates.io index"))
+}
+
+/// Compare two plain x.y.z versions.
+fn version_cmp(lhs: &str, rhs: &str) -> anyhow::Result<cmp::Ordering> {
+    let parse = |v: &str| -> anyhow::Result<Vec<u64>> {
+        v.split('.')
+            .map(|p| p.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("parsing version '{}'", v))
+    };
+    Ok(parse(lhs)?.cmp(&parse(rhs)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latest() -> anyhow::Result<()> {
+        let index = r#"
+{"name":"shpool","vers":"0.6.0","deps":[],"cksum":"a","features":{},"yanked":false}
+{"name":"shpool","vers":"0.10.0","deps":[],"cksum":"b","features":{},"yanked":false}
+{"name":"shpool","vers":"0.9.2","deps":[],"cksum":"c","features":{},"yanked":false}
+{"name":"shpool","vers":"0.11.0","deps":[],"cksum":"d","features":{},"yanked":true}
+{"name":"shpool","vers":"0.12.0-rc1","deps":[],"cksum":"e","features":{},"yanked":false}
+"#;
+        assert_eq!(latest_release(index)?, "0.10.0");
+        assert!(latest_release("").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,489 @@
+use std::time::Duration;
+
+use assert_matches::assert_matches;
+use libshpool::testing::{Daemon, DEFAULT_CONFIG};
+use ntest::timeout;
+use shpool_protocol::{
+    AttachHeader, AttachStatus, RestartPolicy, SessionDefinition, SessionStatus, TtySize,
+};
+
+#[test]
+#[timeout(30000)]
+fn attach_and_run() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    assert_matches!(client.status(), AttachStatus::Created { .. });
+
+    client.run_cmd("echo hi-from-$((1 + 1))")?;
+    client.expect("hi-from-2")?;
+
+    client.run_cmd("exit 3")?;
+    assert_eq!(client.wait_for_exit()?, 3);
+
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn detach_and_reattach() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("export MARKER=still-here")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    let reply = daemon.detach(vec![String::from("sh1")])?;
+    assert!(reply.not_found_sessions.is_empty());
+    assert!(reply.not_attached_sessions.is_empty());
+    daemon.wait_for_list(|l| {
+        l.sessions.len() == 1 && matches!(l.sessions[0].status, SessionStatus::Disconnected)
+    })?;
+
+    let mut client = daemon.attach("sh1")?;
+    assert_matches!(client.status(), AttachStatus::Attached { .. });
+    client.run_cmd("echo $MARKER")?;
+    client.expect("still-here")?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn busy() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    let other = daemon.attach("sh1")?;
+    assert_eq!(other.status(), &AttachStatus::Busy);
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn kill() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    let reply = daemon.kill(vec![String::from("sh1"), String::from("nope")])?;
+    assert_eq!(reply.not_found_sessions, vec![String::from("nope")]);
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn kill_glob() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut clients = vec![];
+    for name in ["work-1", "work-2", "play"] {
+        let mut client = daemon.attach(name)?;
+        client.run_cmd("echo ready")?;
+        client.expect("ready")?;
+        clients.push(client);
+    }
+
+    let reply = daemon.kill_dry_run(vec![String::from("work-*"), String::from("nope-*")])?;
+    assert_eq!(reply.matched_sessions, vec![String::from("work-1"), String::from("work-2")]);
+    assert_eq!(reply.not_found_sessions, vec![String::from("nope-*")]);
+    assert_eq!(daemon.list()?.sessions.len(), 3);
+
+    let reply = daemon.kill(vec![String::from("work-*")])?;
+    assert_eq!(reply.matched_sessions, vec![String::from("work-1"), String::from("work-2")]);
+    assert!(reply.not_found_sessions.is_empty());
+    daemon.wait_for_list(|l| l.sessions.len() == 1 && l.sessions[0].name == "play")?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn panes_are_explicit() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let client = daemon.attach("work:build")?;
+    assert_matches!(client.status(), AttachStatus::InvalidName(reason) if reason.contains("--pane"));
+
+    let mut clients = vec![daemon.attach("work")?];
+    clients.push(daemon.attach_with(AttachHeader {
+        name: String::from("work"),
+        pane: Some(String::from("build")),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        client_version: String::from(shpool_protocol::VERSION),
+        ..AttachHeader::default()
+    })?);
+    for client in clients.iter_mut() {
+        client.run_cmd("echo ready")?;
+        client.expect("ready")?;
+    }
+
+    // killing a session leaves its panes alone unless asked
+    let reply = daemon.kill(vec![String::from("work")])?;
+    assert_eq!(reply.matched_sessions, vec![String::from("work")]);
+    daemon.wait_for_list(|l| l.sessions.len() == 1 && l.sessions[0].name == "work:build")?;
+
+    let reply = daemon.kill_with_panes(vec![String::from("work")])?;
+    assert_eq!(reply.matched_sessions, vec![String::from("work:build")]);
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn ttl_reaps_without_sleeping() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach_with(AttachHeader {
+        name: String::from("sh1"),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        ttl_secs: Some(60 * 60),
+        client_version: String::from(shpool_protocol::VERSION),
+        ..AttachHeader::default()
+    })?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    daemon.clock().advance(Duration::from_secs(30 * 60));
+    assert_eq!(daemon.list()?.sessions.len(), 1);
+
+    daemon.clock().advance(Duration::from_secs(31 * 60));
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn heartbeats_follow_the_clock() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    daemon.clock().advance(Duration::from_secs(1));
+    client.expect_heartbeat()?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn stats_count_bytes() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    let reply = daemon.stats()?;
+    assert_eq!(reply.sessions.len(), 1);
+    let stats = &reply.sessions[0];
+    assert_eq!(stats.name, "sh1");
+    assert_matches!(stats.status, SessionStatus::Attached);
+    assert_eq!(stats.input_bytes, "echo ready\n".len() as u64);
+    assert!(stats.output_bytes >= "ready".len() as u64);
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn cmd_policy_forbids() -> anyhow::Result<()> {
+    let daemon = Daemon::start(&format!(
+        "{}\n[cmd_policy]\nallow = [\"htop\", \"tail -f /var/log/.*\"]\n",
+        DEFAULT_CONFIG
+    ))?;
+
+    let client = daemon.attach_with(AttachHeader {
+        name: String::from("sh1"),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        cmd: Some(String::from("htop; rm -rf ~")),
+        client_version: String::from(shpool_protocol::VERSION),
+        ..AttachHeader::default()
+    })?;
+    assert_matches!(client.status(), AttachStatus::Forbidden(reason) if reason.contains("allowlist"));
+    assert!(daemon.list()?.sessions.is_empty());
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn audit_log() -> anyhow::Result<()> {
+    let tmp_dir = tempfile::tempdir()?;
+    let audit_path = tmp_dir.path().join("audit.jsonl");
+    let daemon = Daemon::start(&format!(
+        "audit_log = {:?}\n{}\n[cmd_policy]\nallow = []\n",
+        audit_path, DEFAULT_CONFIG
+    ))?;
+
+    daemon.list()?;
+    daemon.kill(vec![String::from("nope")])?;
+    let client = daemon.attach_with(AttachHeader {
+        name: String::from("sh1"),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        cmd: Some(String::from("htop")),
+        client_version: String::from(shpool_protocol::VERSION),
+        ..AttachHeader::default()
+    })?;
+    assert_matches!(client.status(), AttachStatus::Forbidden(_));
+
+    // entries get written after the reply goes out
+    let mut entries = {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            let entries = std::fs::read_to_string(&audit_path)
+                .unwrap_or_default()
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<Vec<serde_json::Value>, _>>()?;
+            if entries.len() >= 4 || std::time::Instant::now() > deadline {
+                break entries;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    };
+    // requests can finish out of order, but entries for the same
+    // connection are always in order
+    entries.sort_by_key(|e| e["conn_id"].as_u64());
+    let got = entries
+        .iter()
+        .map(|e| (e["action"].as_str().unwrap_or(""), e["outcome"].as_str().unwrap_or("")))
+        .collect::<Vec<_>>();
+    assert_eq!(got[0], ("list", "ok"));
+    assert_eq!(got[1], ("kill", "ok"));
+    assert_eq!(got[2].0, "attach");
+    assert!(got[2].1.starts_with("forbidden: "));
+    assert_eq!(got[3], ("disconnect", "ok"));
+    assert_eq!(entries[1]["sessions"], serde_json::json!(["nope"]));
+    assert_eq!(entries[0]["pid"], std::process::id());
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn import_keeps_going_after_failure() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let def = |name: &str, cmd: Option<&str>| SessionDefinition {
+        name: String::from(name),
+        cmd: cmd.map(String::from),
+        cwd: None,
+        env: vec![],
+    };
+    let reply =
+        daemon.import(vec![def("broken", Some("shpool-no-such-program")), def("fine", None)])?;
+    assert_eq!(reply.failed.len(), 1);
+    assert_eq!(reply.failed[0].name, "broken");
+    assert!(!reply.failed[0].error.is_empty());
+
+    let list = daemon.list()?;
+    let names: Vec<&str> = list.sessions.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, vec!["fine"]);
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn notices_in_band_for_old_clients() -> anyhow::Result<()> {
+    let daemon = Daemon::start(&format!("client_idle_detach = \"1h\"\n{}", DEFAULT_CONFIG))?;
+
+    // a client from before notice chunks existed doesn't set notice_chunks
+    let mut client = daemon.attach_with(AttachHeader {
+        name: String::from("sh1"),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        client_version: String::from(shpool_protocol::VERSION),
+        ..AttachHeader::default()
+    })?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    daemon.clock().advance(Duration::from_secs(61 * 60));
+    client.expect("shpool: ")?;
+    client.expect("after 1h without input")?;
+    client.wait_for_disconnect()?;
+    assert!(client.notices().is_empty());
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn client_idle_detach() -> anyhow::Result<()> {
+    let daemon = Daemon::start(&format!("client_idle_detach = \"1h\"\n{}", DEFAULT_CONFIG))?;
+
+    let mut client = daemon.attach("sh1")?;
+    client.run_cmd("echo ready")?;
+    client.expect("ready")?;
+
+    // heartbeats to the client don't count as activity
+    daemon.clock().advance(Duration::from_secs(50 * 60));
+    client.expect_heartbeat()?;
+    assert_eq!(daemon.list()?.sessions.len(), 1);
+    assert!(matches!(daemon.list()?.sessions[0].status, SessionStatus::Attached));
+
+    daemon.clock().advance(Duration::from_secs(11 * 60));
+    client.wait_for_disconnect()?;
+    assert_eq!(client.notices().len(), 1);
+    assert!(client.notices()[0].contains("after 1h without input"));
+    daemon.wait_for_list(|l| {
+        l.sessions.len() == 1 && matches!(l.sessions[0].status, SessionStatus::Disconnected)
+    })?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn restart_on_failure() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut client = daemon.attach_with(AttachHeader {
+        name: String::from("sh1"),
+        local_tty_size: TtySize { rows: 24, cols: 80, xpixel: 0, ypixel: 0 },
+        client_version: String::from(shpool_protocol::VERSION),
+        restart: Some(RestartPolicy::OnFailure),
+        ..AttachHeader::default()
+    })?;
+    client.run_cmd("exit 3")?;
+    assert_eq!(client.wait_for_exit()?, 3);
+    // the exited session is gone before the restart brings it back
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+
+    // the restart waits out a backoff on the daemon's clock
+    while daemon.list()?.sessions.is_empty() {
+        daemon.clock().advance(Duration::from_secs(1));
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let mut client = daemon.attach("sh1")?;
+    assert_matches!(client.status(), AttachStatus::Attached { .. });
+    client.run_cmd("echo back")?;
+    client.expect("back")?;
+    assert_eq!(client.notices().len(), 1);
+    assert!(client.notices()[0].contains("restarted after its shell exited with status 3"));
+    drop(client);
+
+    // killing the session stops it for good
+    daemon.kill(vec![String::from("sh1")])?;
+    daemon.wait_for_list(|l| l.sessions.is_empty())?;
+    daemon.clock().advance(Duration::from_secs(60));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(daemon.list()?.sessions.is_empty());
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn exit_history() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    for status in [2, 0] {
+        let mut client = daemon.attach("sh1")?;
+        client.run_cmd(&format!("exit {}", status))?;
+        assert_eq!(client.wait_for_exit()?, status);
+        daemon.wait_for_list(|l| l.sessions.is_empty())?;
+    }
+
+    let history = daemon.list()?.exit_history;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].name, "sh1");
+    let statuses: Vec<i32> = history[0].exits.iter().map(|e| e.status).collect();
+    assert_eq!(statuses, vec![2, 0]);
+    assert!(history[0].exits.iter().all(|e| e.exited_at_unix_ms >= e.started_at_unix_ms));
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn racing_creates() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let (a, b) = std::thread::scope(|s| {
+        let a = s.spawn(|| daemon.attach("sh1"));
+        let b = s.spawn(|| daemon.attach("sh1"));
+        (a.join().unwrap(), b.join().unwrap())
+    });
+    let mut clients = [a?, b?];
+    clients.sort_by_key(|c| matches!(c.status(), AttachStatus::Busy));
+    assert_matches!(clients[0].status(), AttachStatus::Created { .. });
+    assert_eq!(clients[1].status(), &AttachStatus::Busy);
+
+    // the winner must have ended up with its own connection
+    clients[0].run_cmd("echo mine")?;
+    clients[0].expect("mine")?;
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn shutdown_disconnects_everyone() -> anyhow::Result<()> {
+    let daemon = Daemon::start(DEFAULT_CONFIG)?;
+
+    let mut clients = vec![];
+    for name in ["sh1", "sh2", "sh3", "sh4"] {
+        let mut client = daemon.attach(name)?;
+        client.run_cmd("echo ready")?;
+        client.expect("ready")?;
+        clients.push(client);
+    }
+
+    let start = std::time::Instant::now();
+    daemon.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(3), "took {:?}", start.elapsed());
+    for client in clients.iter_mut() {
+        // 128 + SIGTERM, as if the daemon had been sent it
+        assert_eq!(client.wait_for_exit()?, 143);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn autostart_then_list() -> anyhow::Result<()> {
+    // Autostarted sessions come up right as the config watcher does its
+    // first reload, which used to deadlock the daemon.
+    let config = format!(
+        "{}\n{}",
+        DEFAULT_CONFIG,
+        r#"
+[recording]
+enabled = true
+
+[[autostart_sessions]]
+name = "auto1"
+
+[[autostart_sessions]]
+name = "auto2"
+
+[[autostart_sessions]]
+name = "auto3"
+"#
+    );
+    let daemon = Daemon::start(&config)?;
+
+    daemon.wait_for_list(|l| l.sessions.len() == 3)?;
+    // and it keeps answering once the reload has gone through
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(daemon.list()?.sessions.len(), 3);
+
+    Ok(())
+}
@@ -37,6 +37,9 @@ pub struct VersionHeader {
 /// It uses an enum to allow different connection types
 /// to be initiated on the same socket. The ConnectHeader is always prefixed
 /// with a 4 byte little endian unsigned word to indicate length.
+// There is only ever one of these per connection, so there is no
+// point in boxing the attach header to keep the size down.
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ConnectHeader {
     /// Attach to the named session indicated by the given header.
@@ -60,30 +63,464 @@ pub enum ConnectHeader {
     /// A message to request that a list of running
     /// sessions get killed.
     Kill(KillRequest),
+    /// A message to request that the ttl for a running
+    /// session be pushed back.
+    ExtendTtl(ExtendTtlRequest),
+    /// Dump the parameters needed to recreate all of the
+    /// currently running sessions.
+    ///
+    /// Responds with an ExportReply.
+    Export,
+    /// Create detached sessions from a list of previously
+    /// exported session definitions.
+    ///
+    /// Responds with an ImportReply.
+    Import(ImportRequest),
+    /// Dump the daemon's internal state for debugging.
+    ///
+    /// Responds with a DumpStateReply.
+    DumpState,
+    /// Get resource usage counters for all of the running sessions.
+    ///
+    /// Responds with a StatsReply.
+    Stats,
+    /// Move an already running process into a new session.
+    ///
+    /// Responds with an AdoptPidReply.
+    AdoptPid(AdoptPidRequest),
+    /// Send the same input to a set of sessions, as if it had been
+    /// typed into each of them.
+    ///
+    /// Responds with a BroadcastReply.
+    Broadcast(BroadcastRequest),
+    /// Look at or change the filter that decides which of the daemon's
+    /// log lines get written out.
+    ///
+    /// Responds with a LogLevelReply.
+    LogLevel(LogLevelRequest),
+    /// Run a sampling cpu profiler in the daemon for a while and write
+    /// out the result.
+    ///
+    /// Responds with a ProfileReply once the profile has been written.
+    Profile(ProfileRequest),
+    /// Store a buffer in the daemon's clipboard, which is shared by
+    /// all sessions.
+    ///
+    /// Responds with a CopyReply.
+    Copy(CopyRequest),
+    /// Fetch the daemon's clipboard, or type it into a session.
+    ///
+    /// Responds with a PasteReply.
+    Paste(PasteRequest),
+    /// Stream a copy of a session's output without attaching to it.
+    ///
+    /// Responds with a PipeReply. If the status is Piping, the reply
+    /// is followed by a stream of chunks, just like an attach, until
+    /// the session exits or the client hangs up.
+    Pipe(PipeRequest),
+    /// Fetch the environment a session's shell was started with.
+    ///
+    /// Responds with an EnvReply.
+    Env(EnvRequest),
+}
+
+/// SessionDefinition holds the parameters needed to recreate
+/// a session.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SessionDefinition {
+    #[serde(default)]
+    pub name: String,
+    /// The command the session runs, if it is not just a shell.
+    #[serde(default)]
+    pub cmd: Option<String>,
+    /// The working directory of the session's shell.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// The environment forwarded from the client that created
+    /// the session.
+    #[serde(default)]
+    pub env: Vec<(String, String)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportReply {
+    #[serde(default)]
+    pub sessions: Vec<SessionDefinition>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DumpStateReply {
+    /// The daemon state, formatted as json. The format is meant for
+    /// humans doing debugging and is not stable.
+    #[serde(default)]
+    pub state_json: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsReply {
+    #[serde(default)]
+    pub sessions: Vec<SessionStats>,
+}
+
+/// Resource usage for a single session. The counters are totals over
+/// the lifetime of the session, so clients that want rates should
+/// poll and take the difference.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SessionStats {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub status: SessionStatus,
+    /// The total number of bytes the shell has written to its pty.
+    #[serde(default)]
+    pub output_bytes: u64,
+    /// The total number of bytes clients have sent to the shell.
+    #[serde(default)]
+    pub input_bytes: u64,
+    /// The cpu time used by all of the processes in the session,
+    /// in milliseconds.
+    #[serde(default)]
+    pub cpu_time_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportRequest {
+    #[serde(default)]
+    pub sessions: Vec<SessionDefinition>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportReply {
+    /// sessions that were not imported because there is
+    /// already a session with the same name
+    #[serde(default)]
+    pub already_exists: Vec<String>,
+    /// sessions that were not imported because the daemon's
+    /// cmd_policy does not allow their command
+    #[serde(default)]
+    pub forbidden: Vec<String>,
+    /// sessions that the daemon tried to import but could not
+    /// start. The rest of the sessions still get imported.
+    #[serde(default)]
+    pub failed: Vec<ImportFailure>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImportFailure {
+    pub name: String,
+    /// what went wrong when starting the session
+    pub error: String,
 }
 
 /// KillRequest represents a request to kill
 /// the given named sessions.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KillRequest {
-    /// The sessions to detach
+    /// The sessions to kill. Each entry is a session name or a glob
+    /// pattern, which the daemon matches against its session table.
     #[serde(default)]
     pub sessions: Vec<String>,
+    /// Also select the panes of each selected session.
+    #[serde(default)]
+    pub panes: bool,
+    /// Just report which sessions would be killed.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KillReply {
     #[serde(default)]
     pub not_found_sessions: Vec<String>,
+    /// The sessions that the request selected, which were killed
+    /// unless it was a dry run.
+    #[serde(default)]
+    pub matched_sessions: Vec<String>,
+}
+
+/// ExtendTtlRequest represents a request to push back
+/// the time at which a session with a ttl gets reaped.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExtendTtlRequest {
+    /// The session to extend
+    #[serde(default)]
+    pub session: String,
+    /// How long to extend the ttl by. If the session is past its
+    /// deadline but not yet reaped, the extension starts from now.
+    #[serde(default)]
+    pub extension_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExtendTtlReply {
+    #[serde(default)]
+    pub status: ExtendTtlStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub enum ExtendTtlStatus {
+    /// The ttl was extended, and the session now has the
+    /// given number of seconds left to live.
+    Extended { remaining_secs: u64 },
+    /// There is no session with the given name.
+    #[default]
+    NotFound,
+    /// The session exists, but was not created with a ttl.
+    NoTtl,
+}
+
+/// AdoptPidRequest asks the daemon to create a session and move the
+/// given process's terminal over to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdoptPidRequest {
+    /// The name of the session to create.
+    #[serde(default)]
+    pub name: String,
+    /// The process to adopt.
+    #[serde(default)]
+    pub pid: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdoptPidReply {
+    #[serde(default)]
+    pub status: AdoptPidStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum AdoptPidStatus {
+    /// The process now has the new session's terminal.
+    Adopted,
+    /// The daemon was built without support for adopting processes,
+    /// or does not support it on this platform.
+    #[default]
+    Unsupported,
+    /// There is already a session with the requested name.
+    AlreadyExists,
+    /// The requested name is not a valid session name.
+    InvalidName(String),
+    /// Moving the process over failed.
+    Failed(String),
+}
+
+/// CopyRequest replaces the contents of the daemon's clipboard.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CopyRequest {
+    #[serde(default)]
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CopyReply {
+    #[serde(default)]
+    pub status: CopyStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum CopyStatus {
+    #[default]
+    Copied,
+    /// The buffer is bigger than the daemon is willing to hold on to,
+    /// so the clipboard was left alone.
+    TooBig { max_bytes: u64 },
+}
+
+/// PasteRequest asks for the contents of the daemon's clipboard.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasteRequest {
+    /// If set, the daemon types the clipboard into this session, as
+    /// if it had been pasted into an attached terminal, rather than
+    /// sending it back.
+    #[serde(default)]
+    pub session: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasteReply {
+    #[serde(default)]
+    pub status: PasteStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum PasteStatus {
+    /// The contents of the clipboard.
+    Contents(Vec<u8>),
+    /// The clipboard got typed into the requested session.
+    Typed,
+    /// Nothing has been copied yet.
+    #[default]
+    Empty,
+    /// There is no session with the requested name.
+    NotFound,
+}
+
+/// PipeRequest asks for a read only stream of a session's output,
+/// which does not disturb any client that is attached to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PipeRequest {
+    #[serde(default)]
+    pub session: String,
+    /// Start with the session's scrollback, rather than just the
+    /// output from here on out.
+    #[serde(default)]
+    pub from_start: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PipeReply {
+    #[serde(default)]
+    pub status: PipeStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub enum PipeStatus {
+    #[default]
+    Piping,
+    NotFound,
+}
+
+/// EnvRequest asks for the environment that a session's shell was
+/// started with.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnvRequest {
+    #[serde(default)]
+    pub session: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EnvReply {
+    #[serde(default)]
+    pub status: EnvStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub enum EnvStatus {
+    /// The shell's environment, in the order it was handed to the
+    /// shell. Anything the shell has exported since is not included.
+    Env(Vec<(String, String)>),
+    /// There is no session with the requested name.
+    #[default]
+    NotFound,
+}
+
+/// BroadcastRequest asks the daemon to write some input to the
+/// shells of the given sessions, whether or not they are attached.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BroadcastRequest {
+    /// The sessions to send the input to. Each entry is a session name
+    /// or a glob pattern, which the daemon matches against its session
+    /// table.
+    #[serde(default)]
+    pub sessions: Vec<String>,
+    /// Also select the panes of each selected session.
+    #[serde(default)]
+    pub panes: bool,
+    /// The raw input to send.
+    #[serde(default)]
+    pub data: Vec<u8>,
+    /// Just report which sessions would get the input.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BroadcastReply {
+    /// Selectors that didn't match any session.
+    #[serde(default)]
+    pub not_found_sessions: Vec<String>,
+    /// The sessions that the request selected, which got the input
+    /// unless it was a dry run.
+    #[serde(default)]
+    pub matched_sessions: Vec<String>,
+    /// Sessions that were selected, but could not be written to.
+    #[serde(default)]
+    pub failed_sessions: Vec<String>,
+}
+
+/// LogLevelRequest asks the daemon to swap out its log filter.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogLevelRequest {
+    /// The new filter, in the same syntax as RUST_LOG, like "debug" or
+    /// "info,libshpool::daemon::server=trace". If empty, the filter is
+    /// left alone and the reply just reports it.
+    #[serde(default)]
+    pub filter: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogLevelReply {
+    #[serde(default)]
+    pub status: LogLevelStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub enum LogLevelStatus {
+    /// The daemon is now logging with the given filter.
+    Filter { current: String },
+    /// The requested filter could not be parsed, so the old one
+    /// is still in place.
+    Invalid { reason: String },
+    /// The daemon's logging can't be changed at runtime, for
+    /// example because the daemon is embedded in another program
+    /// that set up logging itself.
+    #[default]
+    Unsupported,
+}
+
+/// ProfileRequest asks the daemon to profile itself.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileRequest {
+    /// How long to collect samples for.
+    #[serde(default)]
+    pub duration_secs: u64,
+    #[serde(default)]
+    pub format: ProfileFormat,
+}
+
+/// The kinds of file that a profile can be written out as.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    /// A flamegraph svg, for looking at in a browser.
+    #[default]
+    Flamegraph,
+    /// A pprof protobuf, for `go tool pprof` and similar tools.
+    Pprof,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileReply {
+    #[serde(default)]
+    pub status: ProfileStatus,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub enum ProfileStatus {
+    /// The profile was written to the given path on the daemon's
+    /// machine.
+    Written { path: String },
+    /// Another profile is already being collected.
+    Busy,
+    /// The profile could not be collected, for the given reason.
+    Failed { reason: String },
+    /// The daemon was built without the profiler.
+    #[default]
+    Unsupported,
 }
 
 /// DetachRequest represents a request to detach
 /// from the given named sessions.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DetachRequest {
-    /// The sessions to detach
+    /// The sessions to detach. Each entry is a session name or a glob
+    /// pattern, which the daemon matches against its session table.
     #[serde(default)]
     pub sessions: Vec<String>,
+    /// Also select the panes of each selected session.
+    #[serde(default)]
+    pub panes: bool,
+    /// Just report which sessions would be detached.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -92,9 +529,14 @@ pub struct DetachReply {
     #[serde(default)]
     pub not_found_sessions: Vec<String>,
     /// sessions that are in the session table, but have no
-    /// tty attached
+    /// tty attached. Sessions that were only matched by a
+    /// pattern don't show up here.
     #[serde(default)]
     pub not_attached_sessions: Vec<String>,
+    /// The sessions that the request selected, which were detached
+    /// unless it was a dry run.
+    #[serde(default)]
+    pub matched_sessions: Vec<String>,
 }
 
 /// SessionMessageRequest represents a request that
@@ -121,6 +563,14 @@ pub enum SessionMessageRequestPayload {
     /// by the server from a batch detach request.
     #[default]
     Detach,
+    /// Tell the daemon that the attached client is being suspended
+    /// or has been resumed. Generated when a `shpool attach` process
+    /// receives a SIGTSTP, and again once it gets continued.
+    Suspend(SuspendRequest),
+    /// Redraw the screen of the client attached to the given session,
+    /// for when its display has gotten garbled. Generated by the
+    /// client redraw keybinding.
+    Redraw,
 }
 
 /// ResizeRequest resizes the pty for a named session.
@@ -135,6 +585,15 @@ pub struct ResizeRequest {
     pub tty_size: TtySize,
 }
 
+/// SuspendRequest marks the client attached to a named session as
+/// suspended or not. While the client is suspended, the daemon holds
+/// off on sending it output, and redraws the screen once it resumes.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SuspendRequest {
+    #[serde(default)]
+    pub suspended: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum SessionMessageReply {
     /// The session was not found in the session table
@@ -146,6 +605,22 @@ pub enum SessionMessageReply {
     Resize(ResizeReply),
     /// The response to a detach message
     Detach(SessionMessageDetachReply),
+    /// The response to a suspend message
+    Suspend(SuspendReply),
+    /// The response to a redraw message
+    Redraw(RedrawReply),
+}
+
+/// A reply to a redraw message
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum RedrawReply {
+    Ok,
+}
+
+/// A reply to a suspend message
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum SuspendReply {
+    Ok,
 }
 
 /// A reply to a detach message
@@ -163,11 +638,15 @@ pub enum ResizeReply {
 /// AttachHeader is the blob of metadata that a client transmits when it
 /// first dials into the shpool daemon indicating which shell it wants
 /// to attach to.
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct AttachHeader {
     /// The name of the session to create or attach to.
     #[serde(default)]
     pub name: String,
+    /// Attach to this pane of the session rather than the session
+    /// itself. A pane is an extra shell that belongs to the session.
+    #[serde(default)]
+    pub pane: Option<String>,
     /// The size of the local tty. Passed along so that the remote
     /// pty can be kept in sync (important so curses applications look
     /// right).
@@ -179,15 +658,63 @@ pub struct AttachHeader {
     /// and `TERM`.
     #[serde(default)]
     pub local_env: Vec<(String, String)>,
-    /// If specified, sets a time limit on how long the shell will be open
-    /// when the shell is first created (does nothing in the case of a
-    /// reattach). The daemon is responsible for automatically killing the
-    /// session once the ttl is over.
+    /// If specified, sets a time limit on how long the shell will be open.
+    /// On a reattach, the session's deadline gets reset to this long from
+    /// now, whether or not it was created with a ttl. The daemon is
+    /// responsible for automatically killing the session once the ttl is
+    /// over.
     #[serde(default)]
     pub ttl_secs: Option<u64>,
     /// If specified, a command to run instead of the users default shell.
     #[serde(default)]
     pub cmd: Option<String>,
+    /// If specified, a container target (i.e. `docker:devbox`) to launch
+    /// the shell inside of. Overrides the `container` config option.
+    #[serde(default)]
+    pub container: Option<String>,
+    /// If true, the client would like to use the experimental udp
+    /// transport for the attach stream. The daemon will respond with
+    /// a UdpTransportOffer before the AttachReplyHeader.
+    #[serde(default)]
+    pub udp_transport: bool,
+    /// If specified, the name of a session template from the daemon's
+    /// config to take default settings from when creating the session.
+    #[serde(default)]
+    pub template: Option<String>,
+    /// If specified, the directory to launch the shell in when the
+    /// session is first created. If it does not exist on the daemon's
+    /// side, the shell is launched in the user's home directory.
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// The protocol version of the client, so that the daemon can
+    /// refuse incompatible clients if it has been configured to.
+    #[serde(default)]
+    pub client_version: String,
+    /// The address of the ssh client that `shpool attach` is running
+    /// under, if any, taken from $SSH_CONNECTION. Only used to record
+    /// where attaches come from.
+    #[serde(default)]
+    pub ssh_client: Option<String>,
+    /// If true, the daemon ignores `name` and makes up a fresh name
+    /// for a new session, which it reports back in the reply.
+    #[serde(default)]
+    pub auto_name: bool,
+    /// If specified, what the daemon should do when the session's
+    /// shell exits. Only applies when first creating a session.
+    #[serde(default)]
+    pub restart: Option<RestartPolicy>,
+    /// What the client's terminal said it could do when `shpool attach`
+    /// probed it, if it was probed.
+    #[serde(default)]
+    pub terminal: Option<TerminalCaps>,
+    /// Set if the client can read checked frames, see `Chunk`.
+    #[serde(default)]
+    pub checked_frames: bool,
+    /// Set if the client knows how to display `ChunkKind::Notice`
+    /// chunks. Older clients bail out on chunk kinds they don't know,
+    /// so they get notices mixed into the output as plain data instead.
+    #[serde(default)]
+    pub notice_chunks: bool,
 }
 
 impl AttachHeader {
@@ -196,6 +723,99 @@ impl AttachHeader {
     }
 }
 
+/// TerminalCaps describes the terminal that a client is attaching from,
+/// as learned by querying it when `shpool attach` starts up.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TerminalCaps {
+    /// The parameters of the terminal's primary device attributes (DA1)
+    /// reply, which list the features it supports.
+    #[serde(default)]
+    pub primary_attributes: Vec<u32>,
+    /// The parameters of the terminal's secondary device attributes
+    /// (DA2) reply, which identify the terminal type and version.
+    #[serde(default)]
+    pub secondary_attributes: Vec<u32>,
+    /// If true, the terminal supports 24 bit color, going by the
+    /// client's $COLORTERM.
+    #[serde(default)]
+    pub truecolor: bool,
+    /// If true, the terminal answered a query for the kitty keyboard
+    /// protocol.
+    #[serde(default)]
+    pub kitty_keyboard: bool,
+}
+
+/// RestartPolicy says whether the daemon should launch a session's
+/// shell again when it exits, keeping the session around under the
+/// same name.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Let the session end when the shell exits.
+    #[default]
+    Never,
+    /// Restart the shell only if it exits with a non-zero status.
+    OnFailure,
+    /// Restart the shell whenever it exits.
+    Always,
+}
+
+impl RestartPolicy {
+    /// Check if a shell that exited with the given status
+    /// should be restarted.
+    pub fn should_restart(&self, exit_status: i32) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => exit_status != 0,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+impl std::str::FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(anyhow!("unknown restart policy '{}', want never, on-failure or always", s)),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// UdpTransportOffer is the daemon's response to a request for the
+/// experimental udp transport. If `addr` is None, the daemon does not
+/// support the udp transport and the attach will continue over the
+/// original connection.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UdpTransportOffer {
+    /// The address of the udp socket the daemon is listening on.
+    #[serde(default)]
+    pub addr: Option<String>,
+    /// A one-time key that both sides seal their datagrams with, which
+    /// is how the daemon knows that datagrams come from the same client
+    /// that dialed the control socket.
+    #[serde(default)]
+    pub token: Vec<u8>,
+    /// The version of the udp datagram format the daemon speaks. The
+    /// client must not try to connect if it speaks a different one.
+    /// Zero if the daemon predates versioning.
+    #[serde(default)]
+    pub version: u32,
+}
+
 /// AttachReplyHeader is the blob of metadata that the shpool service prefixes
 /// the data stream with after an attach. In can be used to indicate a
 /// connection error.
@@ -203,6 +823,19 @@ impl AttachHeader {
 pub struct AttachReplyHeader {
     #[serde(default)]
     pub status: AttachStatus,
+    /// The name of the session the client ended up attached to. Only
+    /// set once the attach succeeds, and mostly useful with `auto_name`.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Set if the chunks that follow are checked frames, which the
+    /// daemon only sends if the client asked for them.
+    #[serde(default)]
+    pub checked_frames: bool,
+    /// Set if the client asked for a ttl and the daemon applied it.
+    /// Older daemons ignore the ttl on reattach, so they leave this
+    /// unset.
+    #[serde(default)]
+    pub ttl_applied: bool,
 }
 
 /// ListReply is contains a list of active sessions to be displayed to the user.
@@ -210,6 +843,37 @@ pub struct AttachReplyHeader {
 pub struct ListReply {
     #[serde(default)]
     pub sessions: Vec<Session>,
+    /// How the shells of recent sessions exited, by session name. This
+    /// covers sessions that have since been restarted or recreated under
+    /// the same name, as well as ones that are gone.
+    #[serde(default)]
+    pub exit_history: Vec<SessionExits>,
+}
+
+/// The recent exits of the shells of sessions with a given name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionExits {
+    #[serde(default)]
+    pub name: String,
+    /// The exits, oldest first.
+    #[serde(default)]
+    pub exits: Vec<ExitRecord>,
+}
+
+/// ExitRecord describes one exit of a session's shell.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExitRecord {
+    /// The exit status of the shell. Shells killed by a signal are
+    /// reported as exiting with status 1.
+    #[serde(default)]
+    pub status: i32,
+    /// The signal that killed the shell, if any.
+    #[serde(default)]
+    pub signal: Option<i32>,
+    #[serde(default)]
+    pub started_at_unix_ms: i64,
+    #[serde(default)]
+    pub exited_at_unix_ms: i64,
 }
 
 /// Session describes an active session.
@@ -221,6 +885,60 @@ pub struct Session {
     pub started_at_unix_ms: i64,
     #[serde(default)]
     pub status: SessionStatus,
+    /// What the terminal of the most recently attached client can do,
+    /// if it was probed.
+    #[serde(default)]
+    pub terminal: Option<TerminalCaps>,
+    /// An estimate of the network traffic of the processes in the
+    /// session, if the daemon has network accounting turned on.
+    #[serde(default)]
+    pub net: Option<NetUsage>,
+    /// An estimate of the memory the daemon is holding on to for the
+    /// session.
+    #[serde(default)]
+    pub memory: MemoryUsage,
+    /// How long until the ttl reaper kills the session, if it has a ttl.
+    #[serde(default)]
+    pub reap_in_secs: Option<u64>,
+    /// Set if the attached client has been suspended, for example with
+    /// Ctrl-Z, so it is not reading output right now.
+    #[serde(default)]
+    pub client_suspended: bool,
+}
+
+/// MemoryUsage breaks down the memory that the daemon holds for a
+/// session, in bytes. The scrollback figure is an estimate, since
+/// the terminal emulator doesn't report its own allocations.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The in-memory terminal that keeps the session's scrollback
+    /// for restoring it on reattach.
+    #[serde(default)]
+    pub scrollback_bytes: u64,
+    /// Output that has been read from the shell but not yet passed
+    /// on, like output held back by the filters.
+    #[serde(default)]
+    pub pending_output_bytes: u64,
+    /// The buffers used to shuffle data between the shell and the
+    /// client.
+    #[serde(default)]
+    pub io_buffer_bytes: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.scrollback_bytes + self.pending_output_bytes + self.io_buffer_bytes
+    }
+}
+
+/// NetUsage is the number of bytes that the processes in a session
+/// have sent and received over the TCP connections they have open.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetUsage {
+    #[serde(default)]
+    pub rx_bytes: u64,
+    #[serde(default)]
+    pub tx_bytes: u64,
 }
 
 /// Indicates if a shpool session currently has a client attached.
@@ -259,9 +977,16 @@ pub enum AttachStatus {
     /// name, but another shpool session is currently connected to
     /// it, so the connection attempt was rejected.
     Busy,
+    /// BeingCreated indicates that another client is still in the middle
+    /// of creating the session, and did not finish within the time the
+    /// daemon is willing to wait for it. Trying again later may work.
+    BeingCreated,
     /// Forbidden indicates that the daemon has rejected the connection
     /// attempt for security reasons.
     Forbidden(String),
+    /// InvalidName indicates that the daemon will not create a session
+    /// with the requested name, along with the reason.
+    InvalidName(String),
     /// Some unexpected error
     UnexpectedError(String),
 }
@@ -294,6 +1019,13 @@ pub enum ChunkKind {
     /// have exactly 4 bytes of data, which will contain a little endian
     /// code indicating the child's exit status.
     ExitStatus = 2,
+    /// A short utf-8 message from the daemon that should be displayed
+    /// to the user outside of the normal output stream, for example to
+    /// say that output is being throttled, that the session is about to
+    /// expire, or that another client took over the session. The framing
+    /// is the same as for Data chunks. Only sent to clients that set
+    /// `AttachHeader::notice_chunks`.
+    Notice = 3,
 }
 
 impl TryFrom<u8> for ChunkKind {
@@ -304,6 +1036,7 @@ impl TryFrom<u8> for ChunkKind {
             0 => Ok(ChunkKind::Data),
             1 => Ok(ChunkKind::Heartbeat),
             2 => Ok(ChunkKind::ExitStatus),
+            3 => Ok(ChunkKind::Notice),
             _ => Err(anyhow!("unknown ChunkKind {}", v)),
         }
     }
@@ -318,8 +1051,44 @@ impl TryFrom<u8> for ChunkKind {
 /// little endian 4 byte word: length prefix
 /// N bytes: data
 /// ```
+///
+/// If both sides agree to it in the attach headers, chunks get sent as
+/// checked frames instead, so that a corrupted or desynced stream can be
+/// reported as such rather than turning into a garbled terminal.
+///
+/// ```text
+/// 1 byte: kind tag
+/// little endian 4 byte word: sequence number, starting at 0
+/// little endian 4 byte word: length prefix
+/// little endian 4 byte word: crc32 of the tag, sequence number,
+///                            length prefix and data
+/// N bytes: data
+/// ```
+///
+/// Exit status chunks have a length prefix in checked frames.
+///
+/// Chunks never carry more than 16 KiB of data. Bigger payloads get
+/// split across several chunks, and readers reject a length prefix over
+/// the limit as a protocol violation.
 #[derive(Debug, PartialEq)]
 pub struct Chunk<'data> {
     pub kind: ChunkKind,
     pub buf: &'data [u8],
 }
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn chunk_kind_tag_round_trip(tag in any::<u8>()) {
+            match ChunkKind::try_from(tag) {
+                Ok(kind) => prop_assert_eq!(kind as u8, tag),
+                Err(_) => prop_assert!(tag > ChunkKind::Notice as u8),
+            }
+        }
+    }
+}
@@ -15,17 +15,19 @@
 /// aims to provide a simpler user experience. See [the
 /// README](https://github.com/shell-pool/shpool) for more
 /// info.
+use std::process::ExitCode;
+
 use clap::Parser;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     let args = libshpool::Args::parse();
 
     if args.version() {
         println!("shpool {}", VERSION);
-        return Ok(());
+        return ExitCode::SUCCESS;
     }
 
-    libshpool::run(args, None)
+    libshpool::run_cli(args, None)
 }
@@ -733,6 +733,44 @@ fn custom_keybinding_detach() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn client_keybinding_detach() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("client_detach_keybinding.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+        let mut waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-done"]);
+
+        let attach_args = || AttachArgs {
+            config: Some(String::from("client_detach_keybinding.toml")),
+            ..Default::default()
+        };
+        let mut a1 = daemon_proc.attach("sess", attach_args()).context("starting attach proc")?;
+        let mut lm1 = a1.line_matcher()?;
+
+        a1.run_cmd("export MYVAR=someval")?;
+        a1.run_cmd("echo $MYVAR")?;
+        lm1.scan_until_re("someval$")?;
+
+        // the daemon knows nothing about this binding, so it can only
+        // detach us if the client handles it
+        a1.run_raw(vec![22, 23, 7])?; // Ctrl-v Ctrl-w Ctrl-g
+        let exit_status = a1.proc.wait()?;
+        assert!(exit_status.success());
+
+        waiter.wait_event("daemon-bidi-stream-done")?;
+
+        let mut a2 = daemon_proc.attach("sess", attach_args()).context("starting attach proc 2")?;
+        let mut lm2 = a2.line_matcher()?;
+
+        a2.run_cmd("echo $MYVAR")?;
+        lm2.scan_until_re("someval$")?;
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn injects_term_even_with_env_config() -> anyhow::Result<()> {
@@ -1672,3 +1710,224 @@ fn up_arrow_no_crash() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+#[timeout(30000)]
+fn per_session_history() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "per_session_history.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo $HISTFILE")?;
+        line_matcher.scan_until_re("sessions/sh1/history$")?;
+
+        // bash only writes out its history on exit
+        attach_proc.run_cmd("echo only-in-sh1")?;
+        line_matcher.scan_until_re("only-in-sh1$")?;
+        attach_proc.run_cmd("exit")?;
+        attach_proc.proc.wait()?;
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--no-daemonize")
+            .arg("history")
+            .arg("sh1")
+            .output()
+            .context("spawning history proc")?;
+        assert!(out.status.success(), "history proc failed");
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        assert!(stdout.contains("echo only-in-sh1"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn session_env_vars() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "prompt_prefix_bash.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            attach_proc.run_cmd("echo count=$SHPOOL_ATTACH_COUNT")?;
+            line_matcher.scan_until_re("count=1$")?;
+            attach_proc.run_cmd("echo created=$SHPOOL_SESSION_CREATED_AT")?;
+            line_matcher.scan_until_re("created=[0-9]+$")?;
+            attach_proc.run_cmd("echo socket=$SHPOOL_SOCKET")?;
+            line_matcher.scan_until_re(&format!(
+                "socket={}$",
+                regex::escape(&daemon_proc.socket_path.to_string_lossy())
+            ))?;
+        }
+
+        daemon_proc.wait_until_list_matches(|listout| listout.contains("disconnected"))?;
+
+        {
+            let mut attach_proc =
+                daemon_proc.attach("sh1", Default::default()).context("reattaching")?;
+            let mut line_matcher = attach_proc.line_matcher()?;
+
+            // the count gets picked up right before the next prompt
+            attach_proc.run_cmd("")?;
+            attach_proc.run_cmd("echo count=$SHPOOL_ATTACH_COUNT")?;
+            line_matcher.scan_until_re("count=2$")?;
+        }
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn scheduling() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "scheduling.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+
+        // field 19 of /proc/<pid>/stat is the niceness
+        let check = "echo sched=$(cut -d' ' -f19 /proc/$$/stat),$(cat /proc/$$/oom_score_adj)";
+
+        let mut fg =
+            daemon_proc.attach("main", Default::default()).context("starting attach proc")?;
+        let mut fg_matcher = fg.line_matcher()?;
+        fg.run_cmd(check)?;
+        fg_matcher.scan_until_re("sched=5,100$")?;
+
+        let mut bg =
+            daemon_proc.attach("bg-1", Default::default()).context("starting attach proc")?;
+        let mut bg_matcher = bg.line_matcher()?;
+        bg.run_cmd(check)?;
+        bg_matcher.scan_until_re("sched=19,500$")?;
+        bg.run_cmd("grep Cpus_allowed_list /proc/$$/status")?;
+        bg_matcher.scan_until_re("Cpus_allowed_list:\\s+0$")?;
+
+        Ok(())
+    })
+}
+
+// Shells should only ever see their pty, not the daemon's socket or the
+// pty masters of other sessions. norc.toml spawns shells with posix_spawn
+// and scheduling.toml needs the fork path.
+#[test]
+#[timeout(30000)]
+fn only_pty_fds_posix_spawn() -> anyhow::Result<()> {
+    support::dump_err(|| assert_only_pty_fds("norc.toml"))
+}
+
+#[test]
+#[timeout(30000)]
+fn only_pty_fds_fork() -> anyhow::Result<()> {
+    support::dump_err(|| assert_only_pty_fds("scheduling.toml"))
+}
+
+fn assert_only_pty_fds(config: &str) -> anyhow::Result<()> {
+    let mut daemon_proc = support::daemon::Proc::new(
+        config,
+        DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+    )
+    .context("starting daemon proc")?;
+
+    // have another session around so there is a pty master to leak
+    let mut other =
+        daemon_proc.attach("other", Default::default()).context("starting attach proc")?;
+    let mut other_matcher = other.line_matcher()?;
+    other.run_cmd("echo ready")?;
+    other_matcher.scan_until_re("ready$")?;
+
+    let mut attach_proc =
+        daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+    let mut line_matcher = attach_proc.line_matcher()?;
+    attach_proc.run_cmd("echo shellpid=$$")?;
+    let shell_pid = loop {
+        if let Ok(caps) = line_matcher.capture_re("shellpid=([0-9]+)$") {
+            break caps[1].clone().ok_or(anyhow!("no pid"))?;
+        }
+    };
+
+    let mut fds = vec![];
+    for entry in fs::read_dir(format!("/proc/{}/fd", shell_pid)).context("listing shell fds")? {
+        let entry = entry?;
+        let target = fs::read_link(entry.path()).context("reading fd link")?;
+        fds.push((entry.file_name().to_string_lossy().into_owned(), target));
+    }
+    eprintln!("shell fds: {:?}", fds);
+    for std_fd in ["0", "1", "2"] {
+        assert!(fds.iter().any(|(fd, _)| fd == std_fd), "missing fd {}", std_fd);
+    }
+    for (fd, target) in fds.iter() {
+        assert!(target.starts_with("/dev/pts/"), "fd {} is {:?}, not the pty", fd, target);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[timeout(30000)]
+fn umask() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "umask.toml",
+            DaemonArgs { listen_events: false, ..DaemonArgs::default() },
+        )
+        .context("starting daemon proc")?;
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+
+        attach_proc.run_cmd("echo mask=$(umask)")?;
+        line_matcher.scan_until_re("mask=0027$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn on_attach_local_fails() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc =
+            support::daemon::Proc::new("on_attach_local_fail.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&daemon_proc.socket_path)
+            .arg("--config-file")
+            .arg(support::testdata_file("on_attach_local_fail.toml"))
+            .arg("--no-daemonize")
+            .arg("attach")
+            .arg("sh1")
+            .stdin(Stdio::null())
+            .output()
+            .context("running attach")?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no vpn for sh1"), "stderr: {}", stderr);
+        assert!(stderr.contains("not attaching"), "stderr: {}", stderr);
+        // the attach never happened, so there is nothing to detach from
+        assert!(!stderr.contains("detach hook ran"), "stderr: {}", stderr);
+
+        let listout = daemon_proc.list()?;
+        assert!(!String::from_utf8_lossy(&listout.stdout[..]).contains("sh1"));
+
+        Ok(())
+    })
+}
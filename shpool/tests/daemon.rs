@@ -3,7 +3,7 @@ use std::{
     io::Read,
     os::unix::{
         io::{AsRawFd, FromRawFd},
-        net::UnixListener,
+        net::{UnixListener, UnixStream},
         process::CommandExt as _,
     },
     path,
@@ -66,6 +66,113 @@ fn start() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn daemonize() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let sock_path = tmp_dir.path().join("shpool.socket");
+        let pid_file = tmp_dir.path().join("shpool.pid");
+        let log_file = tmp_dir.path().join("daemon.log");
+
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--log-file")
+            .arg(&log_file)
+            .arg("--socket")
+            .arg(&sock_path)
+            .arg("daemon")
+            .arg("--daemonize")
+            .arg("--pid-file")
+            .arg(&pid_file)
+            .output()
+            .context("running daemonizing proc")?;
+        assert!(out.status.success(), "daemonizing failed: {:?}", out);
+
+        // the daemon outlives the command that launched it
+        support::wait_until(|| Ok(std::os::unix::net::UnixStream::connect(&sock_path).is_ok()))?;
+        let pid: i32 = std::fs::read_to_string(&pid_file)?.trim().parse()?;
+        let sid = nix::unistd::getsid(Some(Pid::from_raw(pid)))?;
+        assert_ne!(sid, nix::unistd::getsid(None)?, "daemon should be in its own session");
+
+        signal::kill(Pid::from_raw(pid), Signal::SIGTERM)?;
+        support::wait_until(|| Ok(!sock_path.exists()))?;
+        assert!(std::fs::read_to_string(&log_file)?.contains("STARTING DAEMON"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn inetd() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("shpool-test")
+            .rand_bytes(20)
+            .tempdir()
+            .context("creating tmp dir")?;
+        let sock_path = tmp_dir.path().join("shpool.socket");
+        let listener = UnixListener::bind(&sock_path)?;
+
+        // Play superserver: accept the client's connection, then hand it
+        // to a daemon that only ever sees that one connection.
+        let list_proc = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&sock_path)
+            .arg("--no-daemonize")
+            .arg("list")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("spawning list proc")?;
+        let (conn, _) = listener.accept().context("accepting list conn")?;
+
+        let mut daemon_proc = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&sock_path)
+            .arg("daemon")
+            .arg("--inetd")
+            .stdin(std::os::fd::OwnedFd::from(conn))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("spawning daemon proc")?;
+
+        let out = list_proc.wait_with_output().context("waiting for list proc")?;
+        assert!(out.status.success(), "list failed: {:?}", out);
+        assert!(String::from_utf8_lossy(&out.stdout[..]).contains("NAME"));
+
+        // the daemon is done once its one client is
+        let status = daemon_proc.wait().context("waiting for daemon")?;
+        assert!(status.success());
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn inetd_needs_socket() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg("/fake/does/not/exist/shpool.socket")
+            .arg("daemon")
+            .arg("--inetd")
+            .stdin(Stdio::null())
+            .output()
+            .context("running daemon proc")?;
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr[..]).contains("needs a unix socket on stdin"));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn systemd_activation() -> anyhow::Result<()> {
@@ -302,3 +409,156 @@ fn echo_sentinel() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn session_panic_isolated() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new(
+            "norc.toml",
+            DaemonArgs {
+                extra_env: vec![(
+                    String::from("SHPOOL_TEST__PANIC_AT"),
+                    String::from("attach:crash"),
+                )],
+                ..DaemonArgs::default()
+            },
+        )
+        .context("starting daemon proc")?;
+
+        let mut sh1_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting sh1 proc")?;
+        let mut sh1_matcher = sh1_proc.line_matcher()?;
+        sh1_proc.run_cmd("echo before")?;
+        sh1_matcher.scan_until_re("before$")?;
+
+        let mut crash_proc =
+            daemon_proc.attach("crash", Default::default()).context("starting crash proc")?;
+        daemon_proc.await_event("daemon-session-panicked")?;
+        crash_proc.proc.wait()?;
+
+        // the session that blew up should be gone, but the daemon and
+        // the other session should keep working
+        daemon_proc.wait_until_list_matches(|listout| {
+            listout.contains("sh1") && !listout.contains("crash")
+        })?;
+        sh1_proc.run_cmd("echo after")?;
+        sh1_matcher.scan_until_re("after$")?;
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn sigterm_disconnects_clients() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut sh1_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting sh1 proc")?;
+        let mut sh1_matcher = sh1_proc.line_matcher()?;
+        // with no tty to draw it on, the notice goes to stderr
+        let mut sh1_stderr_matcher = sh1_proc.stderr_line_matcher()?;
+        sh1_proc.run_cmd("echo ready")?;
+        sh1_matcher.scan_until_re("ready$")?;
+
+        signal::kill(
+            Pid::from_raw(daemon_proc.proc.as_ref().unwrap().id() as i32),
+            Signal::SIGTERM,
+        )?;
+        daemon_proc.await_event("daemon-shutdown-done")?;
+
+        sh1_stderr_matcher.scan_until_re("shpool daemon is shutting down")?;
+        let exit_status = sh1_proc.proc.wait()?;
+        assert_eq!(exit_status.code(), Some(143));
+
+        daemon_proc.proc_wait()?;
+
+        Ok(())
+    })
+}
+
+/// Connect to the daemon and read (at least the start of) its version
+/// header, but never send a request.
+fn stall(daemon_proc: &support::daemon::Proc) -> anyhow::Result<UnixStream> {
+    let mut stream =
+        UnixStream::connect(&daemon_proc.socket_path).context("connecting to daemon")?;
+    stream.set_read_timeout(Some(time::Duration::from_secs(10)))?;
+    let mut buf = [0; 256];
+    let n = stream.read(&mut buf).context("reading version header")?;
+    assert!(n > 0, "daemon hung up without sending its version");
+    Ok(stream)
+}
+
+fn dump_state(daemon_proc: &support::daemon::Proc) -> anyhow::Result<serde_json::Value> {
+    let out = Command::new(support::shpool_bin()?)
+        .arg("--socket")
+        .arg(&daemon_proc.socket_path)
+        .arg("--no-daemonize")
+        .arg("dump-state")
+        .output()
+        .context("running dump-state")?;
+    if !out.status.success() {
+        return Err(anyhow!("dump-state failed: {}", String::from_utf8_lossy(&out.stderr)));
+    }
+    serde_json::from_slice(&out.stdout).context("parsing dump-state output")
+}
+
+#[test]
+#[timeout(30000)]
+fn handshake_timeout() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("handshake_timeout.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let mut stream = stall(&daemon_proc)?;
+        let start = time::Instant::now();
+        // the daemon hangs up once the handshake timeout is up
+        stream.read_to_end(&mut vec![]).context("waiting for daemon to hang up")?;
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+
+        let state = dump_state(&daemon_proc)?;
+        assert_eq!(state["handshake_timeouts"], 1, "state: {}", state);
+        assert_eq!(state["pending_connections"], 0, "state: {}", state);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn pending_limit() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("pending_limit.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let stalled = vec![stall(&daemon_proc)?, stall(&daemon_proc)?];
+
+        // the daemon is at its limit, so the next connection gets
+        // closed without a word
+        let mut stream =
+            UnixStream::connect(&daemon_proc.socket_path).context("connecting to daemon")?;
+        stream.set_read_timeout(Some(time::Duration::from_secs(10)))?;
+        let mut rest = vec![];
+        let _ = stream.read_to_end(&mut rest);
+        assert!(rest.is_empty(), "got {:?} from a rejected connection", rest);
+
+        // once the stalled connections go away, there is room again
+        drop(stalled);
+        let mut state = dump_state(&daemon_proc);
+        for _ in 0..20 {
+            if state.is_ok() {
+                break;
+            }
+            std::thread::sleep(time::Duration::from_millis(100));
+            state = dump_state(&daemon_proc);
+        }
+        let state = state?;
+        assert!(state["rejected_connections"].as_u64() >= Some(1), "state: {}", state);
+
+        Ok(())
+    })
+}
@@ -1,6 +1,6 @@
 #![allow(clippy::literal_string_with_formatting_args)]
 
-use std::process::Command;
+use std::{process::Command, thread, time::Duration};
 
 use anyhow::Context;
 use ntest::timeout;
@@ -301,3 +301,42 @@ fn double_tap() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn during_silence() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(["daemon-bidi-stream-enter", "daemon-bidi-stream-done"]);
+        let mut attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        waiter.wait_event("daemon-bidi-stream-enter")?;
+
+        // Let the client sit idle for a few heartbeats so the
+        // client->shell thread is parked waiting on it.
+        thread::sleep(Duration::from_secs(2));
+
+        let out = daemon_proc.detach(vec![String::from("sh1")])?;
+        assert!(out.status.success(), "not successful");
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-done")?);
+
+        let attach_exit_status = attach_proc.proc.wait()?;
+        assert!(attach_exit_status.success());
+
+        // All the pumps for the old client are gone, so the session
+        // is free to take right away.
+        let mut sess2 =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        let mut lm2 = sess2.line_matcher()?;
+        sess2.run_cmd("echo back")?;
+        lm2.scan_until_re("back$")?;
+
+        Ok(())
+    })
+}
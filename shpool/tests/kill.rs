@@ -1,6 +1,6 @@
 #![allow(clippy::literal_string_with_formatting_args)]
 
-use std::{env, process::Command};
+use std::{env, process::Command, thread};
 
 use anyhow::Context;
 use ntest::timeout;
@@ -22,6 +22,7 @@ fn no_daemon() -> anyhow::Result<()> {
             .context("spawning kill proc")?;
 
         assert!(!out.status.success(), "kill proc exited successfully");
+        assert_eq!(out.status.code(), Some(5), "daemon unreachable status");
 
         let stderr = String::from_utf8_lossy(&out.stderr[..]);
         assert!(stderr.contains("could not connect to daemon"));
@@ -343,6 +344,7 @@ fn missing() -> anyhow::Result<()> {
 
         let out = daemon_proc.kill(vec![String::from("missing")])?;
         assert!(!out.status.success());
+        assert_eq!(out.status.code(), Some(3), "not found status");
 
         let stderr = String::from_utf8_lossy(&out.stderr[..]);
         assert!(stderr.contains("not found: missing"));
@@ -350,3 +352,100 @@ fn missing() -> anyhow::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+#[timeout(30000)]
+fn porcelain() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let waiter = daemon_proc.events.take().unwrap().waiter(["daemon-bidi-stream-enter"]);
+        let _attach_proc =
+            daemon_proc.attach("sh1", Default::default()).context("starting attach proc")?;
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        let kill = |sessions: &[&str]| {
+            Command::new(support::shpool_bin()?)
+                .arg("--socket")
+                .arg(&daemon_proc.socket_path)
+                .arg("--porcelain")
+                .arg("kill")
+                .args(sessions)
+                .output()
+                .context("spawning kill proc")
+        };
+
+        let out = kill(&["missing"])?;
+        assert!(!out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "kill not-found missing\n");
+
+        let out = kill(&["sh1"])?;
+        assert!(out.status.success());
+        assert_eq!(String::from_utf8_lossy(&out.stdout[..]), "kill ok sh1\n");
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(60000)]
+fn concurrent_with_list() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("norc.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let names = ["sh1", "sh2", "sh3", "sh4"];
+        let mut waiter = daemon_proc
+            .events
+            .take()
+            .unwrap()
+            .waiter(names.iter().map(|_| "daemon-bidi-stream-enter"));
+        let mut attach_procs = vec![];
+        for name in names.iter() {
+            attach_procs.push(
+                daemon_proc.attach(name, Default::default()).context("starting attach proc")?,
+            );
+        }
+        for _ in 1..names.len() {
+            waiter.wait_event("daemon-bidi-stream-enter")?;
+        }
+        daemon_proc.events = Some(waiter.wait_final_event("daemon-bidi-stream-enter")?);
+
+        // Hammer the session table with readers while the sessions
+        // get killed out from under them. None of the lists should
+        // fail or hang.
+        let socket_path = daemon_proc.socket_path.clone();
+        let listers = (0..4)
+            .map(|_| {
+                let socket_path = socket_path.clone();
+                thread::spawn(move || -> anyhow::Result<()> {
+                    for _ in 0..10 {
+                        let out = Command::new(support::shpool_bin()?)
+                            .arg("--socket")
+                            .arg(&socket_path)
+                            .arg("list")
+                            .output()
+                            .context("spawning list proc")?;
+                        assert!(out.status.success(), "list failed: {:?}", out);
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for name in names.iter() {
+            let out = daemon_proc.kill(vec![String::from(*name)])?;
+            assert!(out.status.success(), "kill failed: {:?}", out);
+        }
+
+        for lister in listers.into_iter() {
+            lister.join().map_err(|e| anyhow::anyhow!("joining lister: {:?}", e))??;
+        }
+
+        daemon_proc
+            .wait_until_list_matches(|listout| !names.iter().any(|n| listout.contains(n)))?;
+
+        Ok(())
+    })
+}
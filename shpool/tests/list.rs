@@ -1,4 +1,4 @@
-use std::process::Command;
+use std::{os::unix::net::UnixListener, process::Command, time};
 
 use anyhow::Context;
 use ntest::timeout;
@@ -104,6 +104,35 @@ fn no_daemon() -> anyhow::Result<()> {
     })
 }
 
+#[test]
+#[timeout(30000)]
+fn wedged_daemon() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let tmp_dir = tempfile::tempdir().context("creating tmp dir")?;
+        let socket = tmp_dir.path().join("shpool.socket");
+        // accepts connections into the backlog, but never says anything
+        let _listener = UnixListener::bind(&socket).context("binding fake daemon socket")?;
+
+        let start = time::Instant::now();
+        let out = Command::new(support::shpool_bin()?)
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--no-daemonize")
+            .arg("--timeout")
+            .arg("500ms")
+            .arg("list")
+            .output()
+            .context("spawning list proc")?;
+
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert_eq!(out.status.code(), Some(8), "stderr: {}", stderr);
+        assert!(stderr.contains("timed out"), "stderr: {}", stderr);
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+
+        Ok(())
+    })
+}
+
 #[test]
 #[timeout(30000)]
 fn one_session() -> anyhow::Result<()> {
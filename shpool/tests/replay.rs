@@ -0,0 +1,88 @@
+use std::{
+    process::{Command, Output},
+    thread, time,
+};
+
+use anyhow::Context;
+use ntest::timeout;
+use regex::Regex;
+
+mod support;
+
+use crate::support::daemon::DaemonArgs;
+
+fn replay(daemon_proc: &support::daemon::Proc, args: &[&str]) -> anyhow::Result<Output> {
+    Command::new(support::shpool_bin()?)
+        .arg("--socket")
+        .arg(&daemon_proc.socket_path)
+        .arg("--config-file")
+        .arg(support::testdata_file("recording.toml"))
+        .arg("--no-daemonize")
+        .arg("replay")
+        .args(args)
+        .output()
+        .context("running replay")
+}
+
+/// Replay the autostarted session once its output has been recorded.
+fn wait_for_recording(daemon_proc: &support::daemon::Proc) -> anyhow::Result<String> {
+    // the session gets started in the background, so the recording
+    // might not be there right away
+    loop {
+        let out = replay(daemon_proc, &["rec", "--speed", "0"])?;
+        let stdout = String::from_utf8_lossy(&out.stdout[..]);
+        if stdout.contains("recorded-2") {
+            return Ok(stdout.into_owned());
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+#[test]
+#[timeout(30000)]
+fn autostarted_session() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("recording.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        wait_for_recording(&daemon_proc)?;
+        let out = replay(&daemon_proc, &["rec", "--since", "1h"])?;
+        assert!(out.status.success());
+        assert!(String::from_utf8_lossy(&out.stdout[..]).contains("recorded-2"));
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn timestamps() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc =
+            support::daemon::Proc::new("recording_timestamps.toml", DaemonArgs::default())
+                .context("starting daemon proc")?;
+
+        let stdout = wait_for_recording(&daemon_proc)?;
+        let stamped =
+            Regex::new(r"(?m)^\[\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z\] recorded-2")?;
+        assert!(stamped.is_match(&stdout), "stdout: {:?}", stdout);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn no_recording() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("recording.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = replay(&daemon_proc, &["nosuchsession"])?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("no recording for session 'nosuchsession'"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
@@ -0,0 +1,117 @@
+use std::{
+    io::{BufRead, BufReader},
+    process::{Command, Output, Stdio},
+};
+
+use anyhow::{anyhow, Context};
+use ntest::timeout;
+
+mod support;
+
+use crate::support::daemon::{AttachArgs, DaemonArgs};
+
+fn serve_ssh_cmd(daemon_proc: &support::daemon::Proc, key: &str) -> anyhow::Result<Command> {
+    let mut cmd = Command::new(support::shpool_bin()?);
+    cmd.arg("--socket")
+        .arg(&daemon_proc.socket_path)
+        .arg("--config-file")
+        .arg(support::testdata_file("serve_ssh.toml"))
+        .arg("--no-daemonize")
+        .arg("serve-ssh")
+        .arg(key)
+        .env_remove("SSH_ORIGINAL_COMMAND")
+        .env("SSH_CONNECTION", "10.0.0.1 51234 10.0.0.2 22");
+    Ok(cmd)
+}
+
+fn serve_ssh(daemon_proc: &support::daemon::Proc, key: &str) -> anyhow::Result<Output> {
+    serve_ssh_cmd(daemon_proc, key)?.stdin(Stdio::null()).output().context("running serve-ssh")
+}
+
+#[test]
+#[timeout(30000)]
+fn read_only() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let mut daemon_proc = support::daemon::Proc::new("serve_ssh.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+        let mut attach_proc = daemon_proc
+            .attach(
+                "sh1",
+                AttachArgs { config: Some(String::from("serve_ssh.toml")), ..Default::default() },
+            )
+            .context("starting attach proc")?;
+        let mut line_matcher = attach_proc.line_matcher()?;
+        attach_proc.run_cmd("echo before-$((1+1))")?;
+        line_matcher.scan_until_re("before-2$")?;
+
+        let mut viewer = serve_ssh_cmd(&daemon_proc, "viewer")?
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("spawning viewer")?;
+        let mut viewer_lines =
+            BufReader::new(viewer.stdout.take().ok_or(anyhow!("missing stdout"))?).lines();
+
+        // the scrollback comes first, then whatever happens next
+        for want in ["before-2", "after-2"] {
+            if want == "after-2" {
+                attach_proc.run_cmd("echo after-$((1+1))")?;
+            }
+            loop {
+                let line = viewer_lines.next().ok_or(anyhow!("viewer output ended"))??;
+                if line.trim_end().ends_with(want) {
+                    break;
+                }
+            }
+        }
+
+        // hanging up stdin is how the viewer notices ssh going away
+        drop(viewer.stdin.take());
+        let status = viewer.wait().context("waiting for viewer")?;
+        assert!(status.success(), "viewer failed: {:?}", status);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn attach_needs_tty() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("serve_ssh.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = serve_ssh(&daemon_proc, "worker")?;
+        assert!(!out.status.success());
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert!(stderr.contains("ssh -t"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
+
+#[test]
+#[timeout(30000)]
+fn forbidden() -> anyhow::Result<()> {
+    support::dump_err(|| {
+        let daemon_proc = support::daemon::Proc::new("serve_ssh.toml", DaemonArgs::default())
+            .context("starting daemon proc")?;
+
+        let out = serve_ssh(&daemon_proc, "stranger")?;
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert_eq!(out.status.code(), Some(7), "stderr: {}", stderr);
+        assert!(stderr.contains("not in the ssh_keys config"), "stderr: {}", stderr);
+
+        let out = serve_ssh_cmd(&daemon_proc, "viewer")?
+            .env("SSH_ORIGINAL_COMMAND", "cat /etc/passwd")
+            .stdin(Stdio::null())
+            .output()
+            .context("running serve-ssh")?;
+        let stderr = String::from_utf8_lossy(&out.stderr[..]);
+        assert_eq!(out.status.code(), Some(7), "stderr: {}", stderr);
+        assert!(stderr.contains("not to running 'cat /etc/passwd'"), "stderr: {}", stderr);
+
+        Ok(())
+    })
+}
@@ -192,6 +192,7 @@ impl Proc {
                     .map_err(|e| anyhow!("conversion error: {:?}", e))?,
             ),
             verbose: 2,
+            trace_filter: None,
             socket: Some(
                 socket_path
                     .clone()
@@ -207,7 +208,18 @@ impl Proc {
             ),
             daemonize: false,
             no_daemonize: true,
-            command: libshpool::Commands::Daemon,
+            quiet: false,
+            porcelain: false,
+            timeout: None,
+            command: libshpool::Commands::Daemon {
+                resurrect: false,
+                check_update: false,
+                fix_perms: false,
+                daemonize: false,
+                foreground: false,
+                pid_file: None,
+                inetd: false,
+            },
         };
         let hooks_recorder = Box::new(HooksRecorder {
             records: Arc::new(Mutex::new(HookRecords {